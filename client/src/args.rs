@@ -0,0 +1,136 @@
+// REPL tokenizer modelled on redis' sdssplitargs:
+// - "double quotes" accept \n \r \t \b \a \\ \" and \xHH escapes
+// - 'single quotes' only accept \'
+// - a closing quote must be followed by whitespace or the end of the line
+// Returns the arguments as raw bytes so binary values survive intact.
+pub fn split_args(line: &str) -> Result<Vec<Vec<u8>>, String> {
+    // Trailing-backslash continuations reach us as "\\\n"; join them like a shell would.
+    let joined = line.replace("\\\r\n", "").replace("\\\n", "");
+    let bytes = joined.as_bytes();
+    let mut args = Vec::new();
+    let mut i = 0usize;
+    loop {
+        while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i >= bytes.len() {
+            return Ok(args);
+        }
+        let mut current = Vec::new();
+        let mut in_double = false;
+        let mut in_single = false;
+        loop {
+            if in_double {
+                if i >= bytes.len() {
+                    return Err("unbalanced double quotes".to_string());
+                }
+                match bytes[i] {
+                    b'\\' if i + 3 < bytes.len()
+                        && bytes[i + 1] == b'x'
+                        && bytes[i + 2].is_ascii_hexdigit()
+                        && bytes[i + 3].is_ascii_hexdigit() =>
+                    {
+                        let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).unwrap_or("0");
+                        current.push(u8::from_str_radix(hex, 16).unwrap_or(0));
+                        i += 3;
+                    }
+                    b'\\' if i + 1 < bytes.len() => {
+                        i += 1;
+                        current.push(match bytes[i] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    b'"' => {
+                        if i + 1 < bytes.len() && !bytes[i + 1].is_ascii_whitespace() {
+                            return Err("closing quote must be followed by a space".to_string());
+                        }
+                        i += 1;
+                        break;
+                    }
+                    other => current.push(other),
+                }
+            } else if in_single {
+                if i >= bytes.len() {
+                    return Err("unbalanced single quotes".to_string());
+                }
+                match bytes[i] {
+                    b'\\' if i + 1 < bytes.len() && bytes[i + 1] == b'\'' => {
+                        i += 1;
+                        current.push(b'\'');
+                    }
+                    b'\'' => {
+                        if i + 1 < bytes.len() && !bytes[i + 1].is_ascii_whitespace() {
+                            return Err("closing quote must be followed by a space".to_string());
+                        }
+                        i += 1;
+                        break;
+                    }
+                    other => current.push(other),
+                }
+            } else {
+                if i >= bytes.len() || bytes[i].is_ascii_whitespace() {
+                    break;
+                }
+                match bytes[i] {
+                    b'"' => in_double = true,
+                    b'\'' => in_single = true,
+                    other => current.push(other),
+                }
+            }
+            i += 1;
+        }
+        args.push(current);
+    }
+}
+
+/// True when the line ends in an unescaped backslash and should continue on the next line.
+pub fn wants_continuation(line: &str) -> bool {
+    let trailing = line.bytes().rev().take_while(|b| *b == b'\\').count();
+    trailing % 2 == 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(line: &str) -> Vec<Vec<u8>> {
+        split_args(line).unwrap()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(split("  set  key\tvalue "), vec![b"set".to_vec(), b"key".to_vec(), b"value".to_vec()]);
+        assert!(split("   ").is_empty());
+    }
+
+    #[test]
+    fn double_quotes_take_escapes() {
+        assert_eq!(split(r#"set k "a b\n\x41\"""#), vec![b"set".to_vec(), b"k".to_vec(), b"a b\nA\"".to_vec()]);
+        assert_eq!(split(r#""\x00\xff""#), vec![vec![0x00, 0xff]]);
+    }
+
+    #[test]
+    fn single_quotes_only_take_an_escaped_quote() {
+        assert_eq!(split(r"'it\'s' '\n'"), vec![b"it's".to_vec(), b"\\n".to_vec()]);
+    }
+
+    #[test]
+    fn rejects_bad_quoting() {
+        assert!(split_args(r#"get "key"#).is_err());
+        assert!(split_args("get 'key").is_err());
+        assert!(split_args(r#"get "key"x"#).is_err());
+        assert!(split_args("get 'key'x").is_err());
+    }
+
+    #[test]
+    fn joins_continued_lines() {
+        assert_eq!(split("set k \\\nv"), vec![b"set".to_vec(), b"k".to_vec(), b"v".to_vec()]);
+        assert!(wants_continuation("set k \\"));
+        assert!(!wants_continuation("set k \\\\"));
+    }
+}
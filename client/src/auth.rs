@@ -0,0 +1,46 @@
+use anyhow::{bail, Result};
+
+use crate::connection::Connection;
+use crate::{build_array_from_cli, send_command, RespValue, UsageError};
+
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    pub user: Option<String>,
+    pub password: String,
+}
+
+/// Resolves the password from -a, then --askpass, then RUSTCACHE_AUTH.
+pub fn resolve_credentials(
+    user: Option<String>,
+    pass: Option<String>,
+    askpass: bool,
+    warn: bool,
+) -> Result<Option<Credentials>> {
+    let password = if let Some(p) = pass {
+        if warn {
+            eprintln!("Warning: Using a password with '-a' or '--pass' option on the command line interface may not be safe.");
+        }
+        Some(p)
+    } else if askpass {
+        Some(rpassword::prompt_password("Please input password: ")?)
+    } else {
+        std::env::var("RUSTCACHE_AUTH").ok().filter(|p| !p.is_empty())
+    };
+    match (password, user) {
+        (Some(password), user) => Ok(Some(Credentials { user, password })),
+        (None, Some(_)) => Err(UsageError("--user requires a password (-a, --askpass or RUSTCACHE_AUTH)".into()).into()),
+        (None, None) => Ok(None),
+    }
+}
+
+pub async fn authenticate(conn: &mut Connection, creds: &Credentials) -> Result<()> {
+    let mut args = vec!["AUTH".to_string()];
+    if let Some(user) = &creds.user {
+        args.push(user.clone());
+    }
+    args.push(creds.password.clone());
+    match send_command(conn, build_array_from_cli(&args)).await? {
+        RespValue::Error(e) => bail!("AUTH failed: {}", e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,68 @@
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
+
+use crate::args::split_args;
+use crate::connection::Connection;
+use crate::output::{print_reply, print_timing, OutputMode};
+use crate::{build_array_from_cli, reply_exit_code, send_command};
+
+/// Parses a command file: one command per line using REPL quoting rules,
+/// `#` comments and blank lines skipped, trailing backslashes joining lines.
+/// The whole file is parsed up front so a typo never leaves a batch half-applied.
+pub fn read_commands(path: &Path) -> Result<Vec<Vec<Vec<u8>>>> {
+    let text = if path.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?
+    };
+    let mut commands = Vec::new();
+    let mut pending = String::new();
+    for (lineno, line) in text.lines().enumerate() {
+        pending.push_str(line);
+        if crate::args::wants_continuation(&pending) {
+            pending.push('\n');
+            continue;
+        }
+        let trimmed = pending.trim();
+        if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            match split_args(&pending) {
+                Ok(args) => commands.push(args),
+                Err(e) => bail!("{}:{}: {}", path.display(), lineno + 1, e),
+            }
+        }
+        pending.clear();
+    }
+    if !pending.trim().is_empty() {
+        bail!("{}: file ends in the middle of a continued line", path.display());
+    }
+    Ok(commands)
+}
+
+/// Runs commands in order, printing each reply. The exit code is that of the
+/// first command that failed, so scripts still see errors from early lines.
+pub async fn run_batch(
+    conn: &mut Connection,
+    commands: &[Vec<Vec<u8>>],
+    mode: OutputMode,
+    fail_on_nil: bool,
+    timing: bool,
+) -> Result<ExitCode> {
+    let mut status = ExitCode::SUCCESS;
+    for args in commands {
+        let started = Instant::now();
+        let resp = send_command(conn, build_array_from_cli(args)).await?;
+        let elapsed = started.elapsed();
+        print_reply(&resp, mode)?;
+        if timing {
+            print_timing(elapsed, mode)?;
+        }
+        let code = reply_exit_code(&resp, fail_on_nil);
+        if status == ExitCode::SUCCESS {
+            status = code;
+        }
+    }
+    Ok(status)
+}
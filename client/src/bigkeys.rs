@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+
+use crate::connection::Connection;
+use crate::scan::scan_page;
+use crate::{build_array_from_cli, send_command, RespValue};
+
+const SCAN_COUNT: usize = 100;
+const HOTKEYS_TOP: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyReport {
+    Big,
+    Mem,
+    Hot,
+}
+
+#[derive(Default)]
+struct TypeSummary {
+    keys: u64,
+    total: u64,
+    biggest_key: String,
+    biggest: u64,
+}
+
+// Command that reports the "size" of a key of the given type, plus the unit it counts in.
+fn size_command(key_type: &str) -> Option<(&'static str, &'static str)> {
+    match key_type {
+        "string" => Some(("STRLEN", "bytes")),
+        "list" => Some(("LLEN", "items")),
+        "hash" => Some(("HLEN", "fields")),
+        "set" => Some(("SCARD", "members")),
+        "zset" => Some(("ZCARD", "members")),
+        "stream" => Some(("XLEN", "entries")),
+        _ => None,
+    }
+}
+
+async fn query_int(conn: &mut Connection, args: &[&str]) -> Result<Option<u64>> {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    match send_command(conn, build_array_from_cli(&args)).await? {
+        RespValue::Integer(i) => Ok(Some(i.max(0) as u64)),
+        RespValue::BulkString(None) => Ok(None),
+        RespValue::Error(e) => bail!("{} failed: {}", args[0], e),
+        other => bail!("unexpected {} reply: {:?}", args[0], other),
+    }
+}
+
+async fn query_type(conn: &mut Connection, key: &str) -> Result<String> {
+    let args = vec!["TYPE".to_string(), key.to_string()];
+    match send_command(conn, build_array_from_cli(&args)).await? {
+        RespValue::SimpleString(s) => Ok(s),
+        RespValue::Error(e) => bail!("TYPE failed: {}", e),
+        other => bail!("unexpected TYPE reply: {:?}", other),
+    }
+}
+
+async fn key_count(conn: &mut Connection) -> Result<u64> {
+    Ok(query_int(conn, &["DBSIZE"]).await?.unwrap_or(0))
+}
+
+fn progress(sampled: u64, total: u64) -> String {
+    let pct = if total == 0 { 100.0 } else { (sampled as f64 * 100.0 / total as f64).min(100.0) };
+    format!("[{:05.2}%]", pct)
+}
+
+/// Walks the keyspace via SCAN and reports the largest keys per type (`--bigkeys`),
+/// the keys using the most memory (`--memkeys`) or the most frequently accessed keys (`--hotkeys`).
+pub async fn run_key_report(conn: &mut Connection, report: KeyReport) -> Result<()> {
+    let total = key_count(conn).await?;
+    match report {
+        KeyReport::Hot => println!("\n# Scanning the entire keyspace to find hot keys.\n"),
+        _ => println!(
+            "\n# Scanning the entire keyspace to find biggest keys as well as\n# average sizes per key type.\n"
+        ),
+    }
+
+    let mut summaries: BTreeMap<String, TypeSummary> = BTreeMap::new();
+    let mut hot: Vec<(u64, String)> = Vec::new();
+    let mut sampled = 0u64;
+    let mut key_bytes = 0u64;
+    let mut cursor = "0".to_string();
+    loop {
+        let (next, keys) = scan_page(conn, &cursor, None, SCAN_COUNT).await?;
+        for key in keys {
+            sampled += 1;
+            key_bytes += key.len() as u64;
+            if report == KeyReport::Hot {
+                let freq = query_int(conn, &["OBJECT", "FREQ", &key]).await?.unwrap_or(0);
+                hot.push((freq, key));
+                hot.sort_by_key(|h| std::cmp::Reverse(h.0));
+                hot.truncate(HOTKEYS_TOP);
+                continue;
+            }
+            let key_type = query_type(conn, &key).await?;
+            if key_type == "none" {
+                continue;
+            }
+            let size = match report {
+                KeyReport::Mem => query_int(conn, &["MEMORY", "USAGE", &key]).await?,
+                _ => match size_command(&key_type) {
+                    Some((cmd, _)) => query_int(conn, &[cmd, &key]).await?,
+                    None => None,
+                },
+            };
+            let Some(size) = size else { continue };
+            let entry = summaries.entry(key_type.clone()).or_default();
+            entry.keys += 1;
+            entry.total += size;
+            if size > entry.biggest {
+                entry.biggest = size;
+                entry.biggest_key = key.clone();
+                let unit = unit_for(report, &key_type);
+                println!(
+                    "{} Biggest {:<6} found so far '\"{}\"' with {} {}",
+                    progress(sampled, total),
+                    key_type,
+                    key,
+                    size,
+                    unit
+                );
+            }
+        }
+        if next == "0" {
+            break;
+        }
+        cursor = next;
+    }
+
+    println!("\n-------- summary -------\n");
+    println!("Sampled {} keys in the keyspace!", sampled);
+    if report == KeyReport::Hot {
+        for (freq, key) in &hot {
+            println!("hot key found with counter: {}\tkeyname: \"{}\"", freq, key);
+        }
+        return Ok(());
+    }
+    let avg_len = if sampled == 0 { 0.0 } else { key_bytes as f64 / sampled as f64 };
+    println!("Total key length in bytes is {} (avg len {:.2})\n", key_bytes, avg_len);
+    for (key_type, s) in &summaries {
+        println!(
+            "Biggest {:>6} found '\"{}\"' has {} {}",
+            key_type,
+            s.biggest_key,
+            s.biggest,
+            unit_for(report, key_type)
+        );
+    }
+    println!();
+    for (key_type, s) in &summaries {
+        let pct = if sampled == 0 { 0.0 } else { s.keys as f64 * 100.0 / sampled as f64 };
+        let avg = if s.keys == 0 { 0.0 } else { s.total as f64 / s.keys as f64 };
+        println!(
+            "{} {}s with {} {} ({:.2}% of keys, avg size {:.2})",
+            s.keys,
+            key_type,
+            s.total,
+            unit_for(report, key_type),
+            pct,
+            avg
+        );
+    }
+    Ok(())
+}
+
+fn unit_for(report: KeyReport, key_type: &str) -> &'static str {
+    match report {
+        KeyReport::Mem => "bytes",
+        _ => size_command(key_type).map(|(_, unit)| unit).unwrap_or("units"),
+    }
+}
@@ -0,0 +1,348 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Benchmark a RustCache server, in the spirit of redis-benchmark.
+#[derive(Parser, Debug)]
+#[command(name = "rc-bench", version, about)]
+#[command(after_help = "Custom commands may use __rand_int__ (a random key-space index when -r is set) \
+and __data__ (a -d sized payload), e.g.\n  rc-bench -r 10000 -- SET user:__rand_int__ __data__")]
+struct Cli {
+    /// Server host
+    #[arg(short = 'H', long = "host", default_value = "127.0.0.1")]
+    host: String,
+
+    /// Server port
+    #[arg(short = 'p', long = "port", default_value = "9973")]
+    port: u16,
+
+    /// Password sent with AUTH on each connection
+    #[arg(short = 'a', long = "pass")]
+    password: Option<String>,
+
+    /// Number of parallel connections
+    #[arg(short = 'c', long = "clients", default_value = "50")]
+    clients: usize,
+
+    /// Total number of requests per test
+    #[arg(short = 'n', long = "requests", default_value = "100000")]
+    requests: u64,
+
+    /// Requests sent per round trip
+    #[arg(short = 'P', long = "pipeline", default_value = "1")]
+    pipeline: u64,
+
+    /// Size of the SET value in bytes
+    #[arg(short = 'd', long = "data-size", default_value = "3")]
+    data_size: usize,
+
+    /// Key-space size for __rand_int__; 0 keeps every request on one key
+    #[arg(short = 'r', long = "keyspace", default_value = "0")]
+    keyspace: u64,
+
+    /// Comma-separated list of built-in tests (set, get, incr, ping)
+    #[arg(short = 't', long = "tests", default_value = "set,get,incr")]
+    tests: String,
+
+    /// Only print the requests/second line for each test
+    #[arg(short = 'q', long = "quiet")]
+    quiet: bool,
+
+    /// Print results as CSV
+    #[arg(long = "csv")]
+    csv: bool,
+
+    /// Run this command template instead of the built-in tests
+    #[arg(trailing_var_arg = true)]
+    command: Vec<String>,
+}
+
+struct Test {
+    name: String,
+    template: Vec<String>,
+}
+
+fn builtin_test(name: &str) -> Result<Test> {
+    let template: &[&str] = match name {
+        "set" => &["SET", "key:__rand_int__", "__data__"],
+        "get" => &["GET", "key:__rand_int__"],
+        "incr" => &["INCR", "counter:__rand_int__"],
+        "ping" => &["PING"],
+        other => bail!("unknown test '{}' (expected set, get, incr or ping)", other),
+    };
+    Ok(Test {
+        name: name.to_ascii_uppercase(),
+        template: template.iter().map(|s| s.to_string()).collect(),
+    })
+}
+
+struct Workload {
+    template: Vec<String>,
+    data: Vec<u8>,
+    keyspace: u64,
+}
+
+impl Workload {
+    fn encode(&self, rng: &mut u64, out: &mut Vec<u8>) {
+        let rand = if self.keyspace > 0 {
+            Some(format!("{:012}", next_random(rng) % self.keyspace))
+        } else {
+            None
+        };
+        out.extend_from_slice(format!("*{}\r\n", self.template.len()).as_bytes());
+        for part in &self.template {
+            let arg: Vec<u8> = if part == "__data__" {
+                self.data.clone()
+            } else {
+                match &rand {
+                    Some(r) => part.replace("__rand_int__", r).into_bytes(),
+                    None => part.replace("__rand_int__", "000000000000").into_bytes(),
+                }
+            };
+            out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            out.extend_from_slice(&arg);
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+}
+
+fn next_random(state: &mut u64) -> u64 {
+    // xorshift64*: plenty for spreading keys, and keeps the tool dependency-free
+    let mut x = *state;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    *state = x;
+    x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+}
+
+/// Reads and discards one reply, returning whether it was an error.
+fn skip_reply<'a>(reader: &'a mut BufReader<TcpStream>) -> BoxFuture<'a, Result<bool>> {
+    async move {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            bail!("server closed the connection");
+        }
+        let line = line.trim_end();
+        let (prefix, rest) = line.split_at(1.min(line.len()));
+        match prefix {
+            "+" | ":" => Ok(false),
+            "-" => Ok(true),
+            "$" => {
+                let len: i64 = rest.parse().context("bad bulk length")?;
+                if len >= 0 {
+                    let mut buf = vec![0u8; len as usize + 2];
+                    reader.read_exact(&mut buf).await?;
+                }
+                Ok(false)
+            }
+            "*" => {
+                let len: i64 = rest.parse().context("bad array length")?;
+                let mut failed = false;
+                for _ in 0..len.max(0) {
+                    failed |= skip_reply(reader).await?;
+                }
+                Ok(failed)
+            }
+            _ => bail!("unexpected reply line: {}", line),
+        }
+    }
+    .boxed()
+}
+
+struct ClientResult {
+    latencies: Vec<u64>,
+    errors: u64,
+}
+
+async fn run_client(
+    addr: String,
+    password: Option<String>,
+    workload: Arc<Workload>,
+    issued: Arc<AtomicU64>,
+    total: u64,
+    pipeline: u64,
+    seed: u64,
+) -> Result<ClientResult> {
+    let stream = TcpStream::connect(&addr)
+        .await
+        .with_context(|| format!("could not connect to {}", addr))?;
+    stream.set_nodelay(true)?;
+    let mut conn = BufReader::new(stream);
+    if let Some(pass) = password {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(format!("*2\r\n$4\r\nAUTH\r\n${}\r\n{}\r\n", pass.len(), pass).as_bytes());
+        conn.get_mut().write_all(&buf).await?;
+        if skip_reply(&mut conn).await? {
+            bail!("AUTH failed");
+        }
+    }
+
+    let mut rng = seed | 1;
+    let mut result = ClientResult { latencies: Vec::new(), errors: 0 };
+    let mut buf = Vec::new();
+    loop {
+        let start = issued.fetch_add(pipeline, Ordering::Relaxed);
+        if start >= total {
+            break;
+        }
+        let batch = pipeline.min(total - start);
+        buf.clear();
+        for _ in 0..batch {
+            workload.encode(&mut rng, &mut buf);
+        }
+        let sent_at = Instant::now();
+        conn.get_mut().write_all(&buf).await?;
+        for _ in 0..batch {
+            if skip_reply(&mut conn).await? {
+                result.errors += 1;
+            }
+        }
+        // Like redis-benchmark, each request in a pipeline is charged the full round trip
+        let micros = sent_at.elapsed().as_micros() as u64;
+        result.latencies.extend(std::iter::repeat_n(micros, batch as usize));
+    }
+    Ok(result)
+}
+
+struct Report {
+    name: String,
+    elapsed: Duration,
+    latencies: Vec<u64>,
+    errors: u64,
+}
+
+impl Report {
+    fn rps(&self) -> f64 {
+        self.latencies.len() as f64 / self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    fn percentile(&self, p: f64) -> f64 {
+        if self.latencies.is_empty() {
+            return 0.0;
+        }
+        let idx = ((self.latencies.len() as f64 * p / 100.0).ceil() as usize).clamp(1, self.latencies.len());
+        self.latencies[idx - 1] as f64 / 1000.0
+    }
+
+    fn avg(&self) -> f64 {
+        if self.latencies.is_empty() {
+            return 0.0;
+        }
+        self.latencies.iter().sum::<u64>() as f64 / self.latencies.len() as f64 / 1000.0
+    }
+
+    fn print(&self, cli: &Cli) {
+        if cli.csv {
+            println!(
+                "\"{}\",\"{:.2}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{:.3}\",\"{:.3}\"",
+                self.name,
+                self.rps(),
+                self.avg(),
+                self.percentile(0.0),
+                self.percentile(50.0),
+                self.percentile(95.0),
+                self.percentile(99.0),
+                self.percentile(100.0)
+            );
+            return;
+        }
+        if cli.quiet {
+            println!("{}: {:.2} requests per second, p50={:.3} msec", self.name, self.rps(), self.percentile(50.0));
+            return;
+        }
+        println!("====== {} ======", self.name);
+        println!(
+            "  {} requests completed in {:.2} seconds",
+            self.latencies.len(),
+            self.elapsed.as_secs_f64()
+        );
+        println!("  {} parallel clients", cli.clients);
+        println!("  {} bytes payload", cli.data_size);
+        println!("  pipeline depth {}", cli.pipeline);
+        if self.errors > 0 {
+            println!("  {} error replies", self.errors);
+        }
+        println!();
+        println!("Latency (msec):");
+        println!(
+            "  avg {:.3}  min {:.3}  p50 {:.3}  p95 {:.3}  p99 {:.3}  max {:.3}",
+            self.avg(),
+            self.percentile(0.0),
+            self.percentile(50.0),
+            self.percentile(95.0),
+            self.percentile(99.0),
+            self.percentile(100.0)
+        );
+        println!("Throughput: {:.2} requests per second", self.rps());
+        println!();
+    }
+}
+
+async fn run_test(cli: &Cli, test: Test) -> Result<Report> {
+    let workload = Arc::new(Workload {
+        template: test.template,
+        data: vec![b'x'; cli.data_size],
+        keyspace: cli.keyspace,
+    });
+    let issued = Arc::new(AtomicU64::new(0));
+    let addr = format!("{}:{}", cli.host, cli.port);
+    let started = Instant::now();
+    let mut handles = Vec::with_capacity(cli.clients);
+    for i in 0..cli.clients {
+        handles.push(tokio::spawn(run_client(
+            addr.clone(),
+            cli.password.clone(),
+            workload.clone(),
+            issued.clone(),
+            cli.requests,
+            cli.pipeline,
+            0x9E37_79B9_7F4A_7C15u64.wrapping_mul(i as u64 + 1),
+        )));
+    }
+    let mut latencies = Vec::with_capacity(cli.requests as usize);
+    let mut errors = 0;
+    for handle in handles {
+        let result = handle.await??;
+        latencies.extend(result.latencies);
+        errors += result.errors;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort_unstable();
+    Ok(Report { name: test.name, elapsed, latencies, errors })
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if cli.clients == 0 || cli.pipeline == 0 {
+        bail!("--clients and --pipeline must be at least 1");
+    }
+
+    let tests = if cli.command.is_empty() {
+        cli.tests
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+            .map(builtin_test)
+            .collect::<Result<Vec<_>>>()?
+    } else {
+        vec![Test { name: cli.command.join(" "), template: cli.command.clone() }]
+    };
+
+    if cli.csv {
+        println!("\"test\",\"rps\",\"avg_latency_ms\",\"min_latency_ms\",\"p50_latency_ms\",\"p95_latency_ms\",\"p99_latency_ms\",\"max_latency_ms\"");
+    }
+    for test in tests {
+        let report = run_test(&cli, test).await?;
+        report.print(&cli);
+    }
+    Ok(())
+}
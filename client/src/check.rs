@@ -0,0 +1,152 @@
+use std::process::ExitCode;
+use std::time::Instant;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::connection::Connection;
+use crate::{build_array_from_cli, send_command, RespValue, EXIT_ERROR_REPLY};
+
+const BIG_VALUE_LEN: usize = 4 * 1024 * 1024;
+const PIPELINE_DEPTH: i64 = 1000;
+
+async fn call(conn: &mut Connection, args: &[&str]) -> Result<RespValue> {
+    send_command(conn, build_array_from_cli(args)).await
+}
+
+fn expect_ok(resp: RespValue, what: &str) -> Result<()> {
+    match resp {
+        RespValue::SimpleString(s) if s == "OK" => Ok(()),
+        other => bail!("{} replied {:?}, expected OK", what, other),
+    }
+}
+
+fn expect_int(resp: RespValue, what: &str) -> Result<i64> {
+    match resp {
+        RespValue::Integer(i) => Ok(i),
+        other => bail!("{} replied {:?}, expected an integer", what, other),
+    }
+}
+
+fn expect_error(resp: RespValue, what: &str) -> Result<()> {
+    match resp {
+        RespValue::Error(_) => Ok(()),
+        other => bail!("{} replied {:?}, expected an error", what, other),
+    }
+}
+
+async fn connectivity(conn: &mut Connection) -> Result<()> {
+    match call(conn, &["PING"]).await? {
+        RespValue::SimpleString(s) if s == "PONG" => {}
+        other => bail!("PING replied {:?}", other),
+    }
+    match call(conn, &["ECHO", "rc check"]).await? {
+        RespValue::BulkString(Some(b)) if b == b"rc check" => Ok(()),
+        other => bail!("ECHO replied {:?}", other),
+    }
+}
+
+async fn set_get(conn: &mut Connection, key: &str) -> Result<()> {
+    expect_ok(call(conn, &["SET", key, "hello"]).await?, "SET")?;
+    match call(conn, &["GET", key]).await? {
+        RespValue::BulkString(Some(b)) if b == b"hello" => {}
+        other => bail!("GET after SET replied {:?}", other),
+    }
+    ensure!(expect_int(call(conn, &["DEL", key]).await?, "DEL")? == 1, "DEL did not remove the key");
+    match call(conn, &["GET", key]).await? {
+        RespValue::BulkString(None) => Ok(()),
+        other => bail!("GET after DEL replied {:?}", other),
+    }
+}
+
+async fn ttl(conn: &mut Connection, key: &str) -> Result<()> {
+    expect_ok(call(conn, &["SET", key, "v", "EX", "100"]).await?, "SET EX")?;
+    let left = expect_int(call(conn, &["TTL", key]).await?, "TTL")?;
+    ensure!((1..=100).contains(&left), "TTL is {} right after SET EX 100", left);
+    ensure!(expect_int(call(conn, &["PERSIST", key]).await?, "PERSIST")? == 1, "PERSIST did not clear the TTL");
+    let left = expect_int(call(conn, &["TTL", key]).await?, "TTL")?;
+    ensure!(left == -1, "TTL is {} after PERSIST", left);
+    ensure!(expect_int(call(conn, &["EXPIRE", key, "100"]).await?, "EXPIRE")? == 1, "EXPIRE did not set a TTL");
+    let left = expect_int(call(conn, &["TTL", key]).await?, "TTL")?;
+    ensure!((1..=100).contains(&left), "TTL is {} after EXPIRE 100", left);
+    call(conn, &["DEL", key]).await?;
+    let left = expect_int(call(conn, &["TTL", key]).await?, "TTL")?;
+    ensure!(left == -2, "TTL of a deleted key is {}", left);
+    Ok(())
+}
+
+async fn big_value(conn: &mut Connection, key: &str) -> Result<()> {
+    // Every byte differs from its neighbours, so a dropped or repeated chunk shows.
+    let value: Vec<u8> = (0..BIG_VALUE_LEN).map(|i| (i % 251) as u8).collect();
+    let set = build_array_from_cli(&[b"SET".as_slice(), key.as_bytes(), value.as_slice()]);
+    expect_ok(send_command(conn, set).await?, "SET")?;
+    let len = expect_int(call(conn, &["STRLEN", key]).await?, "STRLEN")?;
+    ensure!(len == BIG_VALUE_LEN as i64, "STRLEN is {}, expected {}", len, BIG_VALUE_LEN);
+    match call(conn, &["GET", key]).await? {
+        RespValue::BulkString(Some(b)) if b == value => {}
+        RespValue::BulkString(Some(b)) => bail!("GET returned {} bytes that differ from the {} written", b.len(), value.len()),
+        other => bail!("GET replied {:?}", other),
+    }
+    call(conn, &["DEL", key]).await?;
+    Ok(())
+}
+
+async fn pipelining(conn: &mut Connection, key: &str) -> Result<()> {
+    let incr = build_array_from_cli(&["INCR", key]);
+    // Everything goes out before the first reply is read.
+    for _ in 0..PIPELINE_DEPTH {
+        conn.write_frame(&incr).await?;
+    }
+    for expected in 1..=PIPELINE_DEPTH {
+        let got = expect_int(conn.read_reply().await?, "INCR")?;
+        ensure!(got == expected, "pipelined INCR #{} replied {}", expected, got);
+    }
+    call(conn, &["DEL", key]).await?;
+    Ok(())
+}
+
+async fn errors(conn: &mut Connection, key: &str) -> Result<()> {
+    expect_error(call(conn, &["NOSUCHCOMMAND"]).await?, "an unknown command")?;
+    expect_error(call(conn, &["GET"]).await?, "GET with no key")?;
+    expect_ok(call(conn, &["SET", key, "not a number"]).await?, "SET")?;
+    expect_error(call(conn, &["INCR", key]).await?, "INCR of a non-integer")?;
+    expect_error(call(conn, &["EXPIRE", key, "soon"]).await?, "EXPIRE with a non-integer TTL")?;
+    call(conn, &["DEL", key]).await?;
+    // An error reply must leave the connection usable.
+    match call(conn, &["PING"]).await? {
+        RespValue::SimpleString(s) if s == "PONG" => Ok(()),
+        other => bail!("PING after the errors replied {:?}", other),
+    }
+}
+
+/// Runs a functional smoke test against the server (`--check-server`) and
+/// prints PASS or FAIL per check. Keys it writes are scoped to this process
+/// and deleted again. Exits 1 if any check failed.
+pub async fn run_check(conn: &mut Connection) -> Result<ExitCode> {
+    let prefix = format!("rc:check:{}", std::process::id());
+    let key = |name: &str| format!("{}:{}", prefix, name);
+    let (mut passed, mut failed) = (0, 0);
+    // Each result prints as soon as its check finishes.
+    macro_rules! check {
+        ($name:expr, $run:expr) => {{
+            let started = Instant::now();
+            match $run.await {
+                Ok(()) => {
+                    passed += 1;
+                    println!("PASS {:<12} ({:.1?})", $name, started.elapsed());
+                }
+                Err(e) => {
+                    failed += 1;
+                    println!("FAIL {:<12} {:#}", $name, e);
+                }
+            }
+        }};
+    }
+    check!("connectivity", connectivity(conn));
+    check!("set-get", set_get(conn, &key("string")));
+    check!("ttl", ttl(conn, &key("ttl")));
+    check!("big-value", big_value(conn, &key("big")));
+    check!("pipelining", pipelining(conn, &key("counter")));
+    check!("errors", errors(conn, &key("errors")));
+    println!("\n{} of {} checks passed", passed, passed + failed);
+    Ok(if failed == 0 { ExitCode::SUCCESS } else { ExitCode::from(EXIT_ERROR_REPLY) })
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Result};
+
+use crate::connection::{Connection, Endpoint, Target};
+use crate::session::Session;
+use crate::{build_array_from_cli, send_command, RespValue};
+
+const CLUSTER_SLOTS: u16 = 16384;
+const MAX_REDIRECTS: usize = 5;
+
+// CRC16-CCITT (XMODEM), the checksum used for cluster key slots.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Hash slot of a key, honouring `{hash-tag}` sections.
+pub fn keyslot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|&b| b == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|&b| b == b'}') {
+            Some(len) if len > 0 => &key[open + 1..open + 1 + len],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % CLUSTER_SLOTS
+}
+
+enum Redirect {
+    Moved { slot: u16, host: String, port: u16 },
+    Ask { host: String, port: u16 },
+}
+
+fn parse_redirect(reply: &RespValue) -> Option<Redirect> {
+    let RespValue::Error(msg) = reply else { return None };
+    let mut parts = msg.split_whitespace();
+    let kind = parts.next()?;
+    let slot: u16 = parts.next()?.parse().ok()?;
+    let (host, port) = parts.next()?.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']').to_string();
+    match kind {
+        "MOVED" => Some(Redirect::Moved { slot, host, port }),
+        "ASK" => Some(Redirect::Ask { host, port }),
+        _ => None,
+    }
+}
+
+/// Slot -> node map learned from MOVED redirects.
+#[derive(Default)]
+pub struct ClusterState {
+    slots: HashMap<u16, (String, u16)>,
+}
+
+fn retarget(endpoint: &Endpoint, host: &str, port: u16) -> Endpoint {
+    let tls = match &endpoint.target {
+        Target::Tcp { tls, .. } => tls.clone(),
+        Target::Unix(_) => None,
+    };
+    Endpoint {
+        target: Target::Tcp { host: host.to_string(), port, tls },
+        ..endpoint.clone()
+    }
+}
+
+fn is_current(endpoint: &Endpoint, host: &str, port: u16) -> bool {
+    matches!(&endpoint.target, Target::Tcp { host: h, port: p, .. } if h == host && *p == port)
+}
+
+async fn switch_node(
+    conn: &mut Connection,
+    endpoint: &mut Endpoint,
+    session: &Session,
+    host: &str,
+    port: u16,
+) -> Result<()> {
+    let target = retarget(endpoint, host, port);
+    let mut new_conn = target.connect().await?;
+    session.establish(&mut new_conn).await?;
+    *conn = new_conn;
+    *endpoint = target;
+    Ok(())
+}
+
+impl ClusterState {
+    /// Sends a command, routing by the key's slot when known and following
+    /// -MOVED / -ASK redirects. `conn` and `endpoint` end up on the last node used.
+    pub async fn send(
+        &mut self,
+        conn: &mut Connection,
+        endpoint: &mut Endpoint,
+        session: &Session,
+        args: &[Vec<u8>],
+    ) -> Result<RespValue> {
+        // Heuristic: the first argument is the key for the vast majority of commands.
+        if let Some(key) = args.get(1) {
+            if let Some((host, port)) = self.slots.get(&keyslot(key)).cloned() {
+                if !is_current(endpoint, &host, port) {
+                    switch_node(conn, endpoint, session, &host, port).await?;
+                }
+            }
+        }
+        let mut asking = false;
+        for _ in 0..MAX_REDIRECTS {
+            if asking {
+                send_command(conn, build_array_from_cli(&["ASKING"])).await?;
+            }
+            let reply = send_command(conn, build_array_from_cli(args)).await?;
+            match parse_redirect(&reply) {
+                None => return Ok(reply),
+                Some(Redirect::Moved { slot, host, port }) => {
+                    eprintln!("-> Redirected to slot [{}] located at {}:{}", slot, host, port);
+                    self.slots.insert(slot, (host.clone(), port));
+                    switch_node(conn, endpoint, session, &host, port).await?;
+                    asking = false;
+                }
+                Some(Redirect::Ask { host, port }) => {
+                    eprintln!("-> Asking {}:{}", host, port);
+                    switch_node(conn, endpoint, session, &host, port).await?;
+                    asking = true;
+                }
+            }
+        }
+        bail!("too many cluster redirects")
+    }
+}
@@ -0,0 +1,296 @@
+use std::borrow::Cow;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Helper};
+
+use crate::args::wants_continuation;
+use crate::connection::Connection;
+use crate::scan::scan_page;
+use crate::{build_array_from_cli, send_command, RespValue};
+
+// Fallback table used when the server does not answer COMMAND DOCS.
+const BUILTIN_COMMANDS: &[(&str, &str)] = &[
+    ("DBSIZE", ""),
+    ("DECR", "key"),
+    ("DEL", "key [key ...]"),
+    ("ECHO", "message"),
+    ("EXISTS", "key [key ...]"),
+    ("EXPIRE", "key seconds"),
+    ("FLUSHDB", ""),
+    ("GET", "key [WITHVERSION]"),
+    ("INCR", "key"),
+    ("INFO", "[section]"),
+    ("MEMORY", "USAGE key"),
+    ("MGET", "key [key ...]"),
+    ("MSET", "key value [key value ...]"),
+    ("PERSIST", "key"),
+    ("PING", "[message]"),
+    ("SCAN", "cursor [MATCH pattern] [COUNT count] [TYPE type]"),
+    ("SET", "key value [EX seconds] [MAXIDLE seconds] [IFEQ value | IFVERSION version]"),
+    ("STRLEN", "key"),
+    ("TTL", "key"),
+    ("TYPE", "key"),
+];
+
+#[derive(Debug, Clone)]
+pub struct CommandDoc {
+    pub name: String,
+    pub args: String,
+}
+
+/// The REPL's connection, parked here while it waits at the prompt so key
+/// completion can SCAN with it. None while a command runs or after it dropped.
+pub type IdleConnection = Arc<Mutex<Option<Connection>>>;
+
+// Key completion stops after this many SCAN calls or matches, whichever comes
+// first, so a TAB on a huge keyspace returns promptly with a partial list.
+const KEY_SCAN_PAGES: usize = 8;
+const KEY_SCAN_COUNT: usize = 100;
+const KEY_MATCHES: usize = 64;
+const KEY_SCAN_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct RcHelper {
+    commands: Vec<CommandDoc>,
+    idle: IdleConnection,
+}
+
+impl RcHelper {
+    pub fn new(idle: IdleConnection) -> Self {
+        let commands = BUILTIN_COMMANDS
+            .iter()
+            .map(|(name, args)| CommandDoc { name: name.to_string(), args: args.to_string() })
+            .collect();
+        Self { commands, idle }
+    }
+
+    /// Adds or replaces entries with the ones reported by the server.
+    pub fn merge(&mut self, docs: Vec<CommandDoc>) {
+        for doc in docs {
+            match self.commands.iter_mut().find(|c| c.name.eq_ignore_ascii_case(&doc.name)) {
+                Some(existing) => {
+                    if !doc.args.is_empty() {
+                        existing.args = doc.args;
+                    }
+                }
+                None => self.commands.push(doc),
+            }
+        }
+        self.commands.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    fn lookup(&self, name: &str) -> Option<&CommandDoc> {
+        self.commands.iter().find(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Keys starting with `prefix`, from a bounded SCAN over the idle
+    /// connection. Empty when there is none; a connection that fails is dropped
+    /// so the next command reconnects.
+    fn scan_keys(&self, prefix: &str) -> Vec<String> {
+        let Ok(mut idle) = self.idle.lock() else { return Vec::new() };
+        let Some(conn) = idle.as_mut() else { return Vec::new() };
+        let pattern = format!("{}*", escape_glob(prefix));
+        // The editor blocks a runtime worker while it reads a line.
+        let found = tokio::task::block_in_place(|| {
+            tokio::runtime::Handle::current().block_on(async {
+                let mut keys = Vec::new();
+                let mut cursor = "0".to_string();
+                for _ in 0..KEY_SCAN_PAGES {
+                    let page = scan_page(conn, &cursor, Some(&pattern), KEY_SCAN_COUNT);
+                    let (next, page) = tokio::time::timeout(KEY_SCAN_TIMEOUT, page).await.ok()?.ok()?;
+                    keys.extend(page);
+                    if next == "0" || keys.len() >= KEY_MATCHES {
+                        break;
+                    }
+                    cursor = next;
+                }
+                Some(keys)
+            })
+        });
+        let Some(mut keys) = found else {
+            *idle = None;
+            return Vec::new();
+        };
+        keys.sort();
+        keys.dedup();
+        keys.truncate(KEY_MATCHES);
+        keys
+    }
+}
+
+// Whether argument `index` (0 is the first after the command name) of `doc`
+// names a key, going by its synopsis: a `key` placeholder at that spot, or a
+// trailing `[key ...]` that repeats.
+fn is_key_argument(doc: &CommandDoc, index: usize) -> bool {
+    let words: Vec<&str> = doc.args.split(' ').collect();
+    let placeholder = |w: &str| w.trim_matches(|c| c == '[' || c == ']') == "key";
+    match words.get(index) {
+        Some(w) => placeholder(w),
+        None => words.ends_with(&["[key", "...]"]),
+    }
+}
+
+// Keys the argument splitter would break apart go in double quotes.
+fn quote_key(key: &str) -> Cow<'_, str> {
+    if !key.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+        return Cow::Borrowed(key);
+    }
+    Cow::Owned(format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\"")))
+}
+
+fn escape_glob(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn text(v: &RespValue) -> Option<String> {
+    match v {
+        RespValue::BulkString(Some(b)) => Some(String::from_utf8_lossy(b).to_string()),
+        RespValue::SimpleString(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+// Looks up `field` in a flat [field, value, field, value, ...] array.
+fn field<'a>(items: &'a [RespValue], name: &str) -> Option<&'a RespValue> {
+    items
+        .chunks(2)
+        .find(|pair| pair.len() == 2 && text(&pair[0]).is_some_and(|f| f.eq_ignore_ascii_case(name)))
+        .map(|pair| &pair[1])
+}
+
+fn describe_args(args: &[RespValue]) -> String {
+    let mut parts = Vec::new();
+    for arg in args {
+        let RespValue::Array(Some(spec)) = arg else { continue };
+        let name = field(spec, "token").and_then(text).or_else(|| field(spec, "name").and_then(text));
+        let Some(mut name) = name else { continue };
+        let flags: Vec<String> = match field(spec, "flags") {
+            Some(RespValue::Array(Some(f))) => f.iter().filter_map(text).collect(),
+            _ => Vec::new(),
+        };
+        if flags.iter().any(|f| f == "multiple") {
+            name = format!("{} [{} ...]", name, name);
+        }
+        if flags.iter().any(|f| f == "optional") {
+            name = format!("[{}]", name);
+        }
+        parts.push(name);
+    }
+    parts.join(" ")
+}
+
+/// Asks the server for COMMAND DOCS; returns None when unsupported.
+pub async fn fetch_command_docs(conn: &mut Connection) -> Option<Vec<CommandDoc>> {
+    let frame = build_array_from_cli(&["COMMAND".to_string(), "DOCS".to_string()]);
+    let RespValue::Array(Some(items)) = send_command(conn, frame).await.ok()? else {
+        return None;
+    };
+    let mut docs = Vec::new();
+    for pair in items.chunks(2) {
+        let [name, doc] = pair else { continue };
+        let Some(name) = text(name) else { continue };
+        let args = match doc {
+            RespValue::Array(Some(fields)) => match field(fields, "arguments") {
+                Some(RespValue::Array(Some(args))) => describe_args(args),
+                _ => String::new(),
+            },
+            _ => String::new(),
+        };
+        docs.push(CommandDoc { name: name.to_ascii_uppercase(), args });
+    }
+    Some(docs)
+}
+
+impl Completer for RcHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let prefix = &line[..pos];
+        let start = prefix.len() - prefix.trim_start().len();
+        let word = &prefix[start..];
+        if let Some((command, rest)) = word.split_once(char::is_whitespace) {
+            // Past the command name: complete keys where the synopsis has one.
+            let typed: Vec<&str> = rest.split_whitespace().collect();
+            let (index, partial) = match rest.ends_with(char::is_whitespace) || rest.trim().is_empty() {
+                true => (typed.len(), ""),
+                false => (typed.len() - 1, typed[typed.len() - 1]),
+            };
+            let Some(doc) = self.lookup(command) else { return Ok((pos, Vec::new())) };
+            if !is_key_argument(doc, index) || partial.contains(['"', '\'']) {
+                return Ok((pos, Vec::new()));
+            }
+            let candidates = self
+                .scan_keys(partial)
+                .into_iter()
+                .map(|key| Pair { replacement: format!("{} ", quote_key(&key)), display: key })
+                .collect();
+            return Ok((pos - partial.len(), candidates));
+        }
+        let lower = word.chars().all(|c| !c.is_ascii_uppercase()) && !word.is_empty();
+        let candidates = self
+            .commands
+            .iter()
+            .filter(|c| c.name.len() >= word.len() && c.name[..word.len()].eq_ignore_ascii_case(word))
+            .map(|c| {
+                let name = if lower { c.name.to_ascii_lowercase() } else { c.name.clone() };
+                Pair { display: c.name.clone(), replacement: format!("{} ", name) }
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for RcHelper {
+    type Hint = String;
+
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        let mut words = line.split_whitespace();
+        let doc = self.lookup(words.next()?)?;
+        if doc.args.is_empty() {
+            return None;
+        }
+        let typed = words.count();
+        let trailing_space = line.ends_with(char::is_whitespace);
+        // Only hint once the command name is complete, and skip args already typed.
+        if typed == 0 && !trailing_space {
+            return Some(format!(" {}", doc.args));
+        }
+        let remaining: Vec<&str> = doc.args.split(' ').skip(typed).collect();
+        if remaining.is_empty() || !trailing_space {
+            return None;
+        }
+        Some(remaining.join(" "))
+    }
+}
+
+impl Highlighter for RcHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        Cow::Owned(format!("\x1b[90m{}\x1b[0m", hint))
+    }
+}
+
+impl Validator for RcHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if wants_continuation(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
+impl Helper for RcHelper {}
@@ -0,0 +1,336 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context as _, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::trace::Traced;
+use crate::{read_resp, RespValue};
+
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub cacert: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Skip certificate and hostname verification entirely.
+    pub insecure: bool,
+    /// Server name to verify against instead of the host we dial.
+    pub sni: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Target {
+    Tcp { host: String, port: u16, tls: Option<TlsOptions> },
+    Unix(PathBuf),
+}
+
+/// Where and how to connect; kept around so a dropped connection can be re-dialled.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub target: Target,
+    /// Limit for TCP connect plus TLS handshake.
+    pub connect_timeout: Option<Duration>,
+    /// Limit for each request/response round trip.
+    pub command_timeout: Option<Duration>,
+    /// Print every byte sent and received to stderr (--trace).
+    pub trace: bool,
+}
+
+impl Endpoint {
+    pub fn label(&self) -> String {
+        match &self.target {
+            Target::Tcp { host, port, .. } => format!("{}:{}", host, port),
+            Target::Unix(path) => path.display().to_string(),
+        }
+    }
+
+    pub async fn connect(&self) -> Result<Connection> {
+        let dial = async {
+            match &self.target {
+                Target::Tcp { host, port, tls } => Connection::connect(host, *port, tls.as_ref()).await,
+                Target::Unix(path) => Connection::connect_unix(path).await,
+            }
+        };
+        let mut conn = match self.connect_timeout {
+            Some(limit) => tokio::time::timeout(limit, dial)
+                .await
+                .map_err(|_| anyhow!("connecting to {} timed out after {:?}", self.label(), limit))??,
+            None => dial.await?,
+        };
+        conn.command_timeout = self.command_timeout;
+        conn.stream.get_mut().enabled = self.trace;
+        Ok(conn)
+    }
+}
+
+pub enum Transport {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Transport::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Transport::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+            #[cfg(unix)]
+            Transport::Unix(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A server connection. The read buffer lives as long as the connection so bytes
+/// that arrive ahead of the current reply are never dropped.
+pub struct Connection {
+    stream: BufReader<Traced>,
+    command_timeout: Option<Duration>,
+}
+
+impl Connection {
+    pub async fn connect(host: &str, port: u16, tls: Option<&TlsOptions>) -> Result<Self> {
+        let tcp = TcpStream::connect((host, port))
+            .await
+            .with_context(|| format!("could not connect to {}:{}", host, port))?;
+        let transport = match tls {
+            None => Transport::Tcp(tcp),
+            Some(opts) => {
+                let config = build_tls_config(opts)?;
+                let name = opts.sni.as_deref().unwrap_or(host);
+                let server_name = ServerName::try_from(name.to_string())
+                    .map_err(|_| anyhow!("invalid TLS server name '{}'", name))?;
+                let stream = TlsConnector::from(Arc::new(config))
+                    .connect(server_name, tcp)
+                    .await
+                    .context("TLS handshake failed")?;
+                Transport::Tls(Box::new(stream))
+            }
+        };
+        Ok(Self { stream: BufReader::new(Traced { inner: transport, enabled: false }), command_timeout: None })
+    }
+
+    #[cfg(unix)]
+    pub async fn connect_unix(path: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("could not connect to {}", path.display()))?;
+        Ok(Self { stream: BufReader::new(Traced { inner: Transport::Unix(stream), enabled: false }), command_timeout: None })
+    }
+
+    #[cfg(not(unix))]
+    pub async fn connect_unix(path: &Path) -> Result<Self> {
+        bail!("unix sockets are not supported on this platform ({})", path.display())
+    }
+
+    pub async fn write_frame(&mut self, frame: &RespValue) -> Result<()> {
+        let mut buf = Vec::with_capacity(128);
+        frame.encode(&mut buf);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    pub async fn read_reply(&mut self) -> Result<RespValue> {
+        Ok(read_resp(&mut self.stream).await?)
+    }
+
+    /// Waits until reply bytes are buffered. Unlike `read_reply` this is cancel
+    /// safe, so it can race other futures without losing part of a frame.
+    pub async fn readable(&mut self) -> Result<()> {
+        if self.stream.fill_buf().await?.is_empty() {
+            bail!("connection closed by server");
+        }
+        Ok(())
+    }
+
+    /// Reads one line outside of RESP framing, without the trailing CR/LF.
+    pub async fn read_raw_line(&mut self) -> Result<Vec<u8>> {
+        let mut line = Vec::new();
+        if self.stream.read_until(b'\n', &mut line).await? == 0 {
+            bail!("connection closed by server");
+        }
+        while matches!(line.last(), Some(b'\n' | b'\r')) {
+            line.pop();
+        }
+        Ok(line)
+    }
+
+    /// Copies exactly `len` raw bytes from the connection into `out`.
+    pub async fn copy_exact<W: AsyncWrite + Unpin>(&mut self, len: u64, out: &mut W) -> Result<u64> {
+        let copied = tokio::io::copy(&mut (&mut self.stream).take(len), out).await?;
+        if copied != len {
+            bail!("connection closed after {} of {} bytes", copied, len);
+        }
+        Ok(copied)
+    }
+
+    /// Copies raw bytes into `out` until `marker` is seen; the marker itself is not written.
+    pub async fn copy_until_marker<W: AsyncWrite + Unpin>(&mut self, marker: &[u8], out: &mut W) -> Result<u64> {
+        let mut pending: Vec<u8> = Vec::with_capacity(marker.len() * 2);
+        let mut written = 0u64;
+        let mut chunk = [0u8; 16 * 1024];
+        loop {
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                bail!("connection closed before the end-of-snapshot marker");
+            }
+            pending.extend_from_slice(&chunk[..n]);
+            if let Some(pos) = pending.windows(marker.len()).position(|w| w == marker) {
+                out.write_all(&pending[..pos]).await?;
+                return Ok(written + pos as u64);
+            }
+            // Hold back enough bytes to recognise a marker split across reads.
+            let keep = marker.len().min(pending.len());
+            let flush = pending.len() - keep;
+            out.write_all(&pending[..flush]).await?;
+            written += flush as u64;
+            pending.drain(..flush);
+        }
+    }
+
+    /// One request/response round trip, bounded by the command timeout if set.
+    /// After a timeout the connection is out of sync and should be dropped.
+    pub async fn request(&mut self, frame: &RespValue) -> Result<RespValue> {
+        let limit = self.command_timeout;
+        let round_trip = async {
+            self.write_frame(frame).await?;
+            self.read_reply().await
+        };
+        match limit {
+            Some(limit) => tokio::time::timeout(limit, round_trip)
+                .await
+                .map_err(|_| anyhow!("no reply from server within {:?}", limit))?,
+            None => round_trip.await,
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate file {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    rustls_pemfile::private_key(&mut io::BufReader::new(file))
+        .with_context(|| format!("invalid key file {}", path.display()))?
+        .ok_or_else(|| anyhow!("no private key found in {}", path.display()))
+}
+
+fn build_tls_config(opts: &TlsOptions) -> Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone()).with_safe_default_protocol_versions()?;
+    let builder = if opts.insecure {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(InsecureVerifier(provider)))
+    } else {
+        let mut roots = RootCertStore::empty();
+        match &opts.cacert {
+            Some(path) => {
+                for cert in load_certs(path)? {
+                    roots.add(cert)?;
+                }
+            }
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+                roots.add_parsable_certificates(native.certs);
+            }
+        }
+        builder.with_root_certificates(roots)
+    };
+    let config = match (&opts.cert, &opts.key) {
+        (Some(cert), Some(key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => bail!("--cert and --key must be given together"),
+    };
+    Ok(config)
+}
+
+// Accepts any certificate and host name but still checks handshake signatures.
+#[derive(Debug)]
+struct InsecureVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
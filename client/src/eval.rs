@@ -0,0 +1,20 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+/// Builds `EVAL <script> <numkeys> key... arg...` from a script file and the
+/// trailing CLI words, where a lone "," separates KEYS from ARGV.
+pub fn build_eval_args(script: &Path, rest: &[String]) -> Result<Vec<Vec<u8>>> {
+    let body = std::fs::read(script).with_context(|| format!("could not read script {}", script.display()))?;
+    let (keys, argv) = match rest.iter().position(|w| w == ",") {
+        Some(i) => (&rest[..i], &rest[i + 1..]),
+        None => (rest, &[][..]),
+    };
+    let mut args = Vec::with_capacity(3 + rest.len());
+    args.push(b"EVAL".to_vec());
+    args.push(body);
+    args.push(keys.len().to_string().into_bytes());
+    args.extend(keys.iter().map(|k| k.as_bytes().to_vec()));
+    args.extend(argv.iter().map(|a| a.as_bytes().to_vec()));
+    Ok(args)
+}
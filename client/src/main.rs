@@ -1,12 +1,32 @@
 use std::io;
+use std::process::ExitCode;
 use anyhow::Result;
 use clap::{ArgAction, Parser};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
 use futures::future::BoxFuture;
 use futures::FutureExt;
-use rustyline::DefaultEditor;
-use rustyline::error::ReadlineError;
+
+use crate::connection::{Connection, Endpoint, Target, TlsOptions};
+use crate::output::{print_reply, print_timing, write_reply, OutputMode};
+
+mod args;
+mod auth;
+mod batch;
+mod bigkeys;
+mod check;
+mod cluster;
+mod completion;
+mod connection;
+mod eval;
+mod output;
+mod rdb;
+mod repl;
+mod scan;
+mod session;
+mod stat;
+mod subscribe;
+mod trace;
+mod uri;
 
 #[derive(Debug, Clone)]
 enum RespValue {
@@ -135,38 +155,19 @@ fn read_resp<'a, R: AsyncReadExt + Unpin + Send + 'a>(reader: &'a mut BufReader<
     .boxed()
 }
 
-fn build_array_from_cli(args: &[String]) -> RespValue {
+fn build_array_from_cli<S: AsRef<[u8]>>(args: &[S]) -> RespValue {
     let mut items: Vec<RespValue> = Vec::with_capacity(args.len());
     for a in args {
-        items.push(RespValue::BulkString(Some(a.as_bytes().to_vec())));
+        items.push(RespValue::BulkString(Some(a.as_ref().to_vec())));
     }
     RespValue::Array(Some(items))
 }
 
-fn format_resp(resp: &RespValue) -> String {
-    match resp {
-        RespValue::SimpleString(s) => s.clone(),
-        RespValue::Error(s) => format!("(error) {}", s),
-        RespValue::Integer(i) => i.to_string(),
-        RespValue::BulkString(None) => "(nil)".to_string(),
-        RespValue::BulkString(Some(b)) => match String::from_utf8(b.clone()) {
-            Ok(s) => s,
-            Err(_) => format!("(binary) {} bytes", b.len()),
-        },
-        RespValue::Array(None) => "(nil)".to_string(),
-        RespValue::Array(Some(items)) => {
-            let mut out = String::new();
-            for (i, it) in items.iter().enumerate() {
-                out.push_str(&format!("{}) {}\n", i + 1, format_resp(it)));
-            }
-            out.trim_end().to_string()
-        }
-    }
-}
-
 #[derive(Parser, Debug)]
 #[command(name = "rc")]
 #[command(about = "RustCache CLI (redis-cli like)", long_about = None)]
+#[command(after_help = "Exit status: 0 success, 1 error reply, 2 usage error, \
+3 connection or I/O failure, 4 nil reply with --fail-on-nil")]
 struct Cli {
     /// Server address, e.g. 127.0.0.1:9973
     #[arg(short = 'H', long = "host", default_value = "127.0.0.1")]
@@ -175,63 +176,349 @@ struct Cli {
     #[arg(short = 'p', long = "port", default_value = "9973")]
     port: u16,
 
+    /// Connection URI: rustcache://[user:pass@]host:port/db (rustcaches:// for TLS)
+    #[arg(short = 'u', long = "uri")]
+    uri: Option<String>,
+
+    /// Database number to SELECT after connecting
+    #[arg(short = 'n', long = "db")]
+    db: Option<u32>,
+
+    /// Seconds to wait for the TCP connect and TLS handshake
+    #[arg(long = "connect-timeout", value_parser = seconds)]
+    connect_timeout: Option<std::time::Duration>,
+
+    /// Seconds to wait for each reply before giving up on the connection
+    #[arg(long = "timeout", value_parser = seconds)]
+    timeout: Option<std::time::Duration>,
+
+    /// Cluster mode: follow -MOVED and -ASK redirects
+    #[arg(short = 'c', long = "cluster")]
+    cluster: bool,
+
+    /// Unix domain socket to connect to instead of host/port
+    #[arg(short = 's', long = "socket", conflicts_with = "tls")]
+    socket: Option<std::path::PathBuf>,
+
+    /// Connect using TLS
+    #[arg(long = "tls")]
+    tls: bool,
+
+    /// CA certificate bundle used to verify the server (default: system roots)
+    #[arg(long = "cacert")]
+    cacert: Option<std::path::PathBuf>,
+
+    /// Client certificate for mutual TLS
+    #[arg(long = "cert", requires = "key")]
+    cert: Option<std::path::PathBuf>,
+
+    /// Private key for the client certificate
+    #[arg(long = "key", requires = "cert")]
+    key: Option<std::path::PathBuf>,
+
+    /// Skip server certificate and hostname verification
+    #[arg(long = "insecure")]
+    insecure: bool,
+
+    /// Server name to verify instead of the host name
+    #[arg(long = "sni")]
+    sni: Option<String>,
+
+    /// Password used to AUTH after connecting (RUSTCACHE_AUTH is safer)
+    #[arg(short = 'a', long = "pass")]
+    pass: Option<String>,
+
+    /// ACL username sent along with the password
+    #[arg(long = "user")]
+    user: Option<String>,
+
+    /// Prompt for the password without echoing it
+    #[arg(long = "askpass", conflicts_with = "pass")]
+    askpass: bool,
+
+    /// Don't warn about passing the password with -a
+    #[arg(long = "no-auth-warning")]
+    no_auth_warning: bool,
+
+    /// Print replies verbatim without "(nil)" or numbering (default when stdout is not a tty)
+    #[arg(long = "raw", conflicts_with = "no_raw")]
+    raw: bool,
+
+    /// Force human-friendly formatting even when stdout is not a tty
+    #[arg(long = "no-raw")]
+    no_raw: bool,
+
+    /// Print each reply as JSON (errors as {"error": ...})
+    #[arg(long = "json", conflicts_with_all = ["raw", "no_raw", "csv"])]
+    json: bool,
+
+    /// Print each reply as a CSV record
+    #[arg(long = "csv", conflicts_with_all = ["raw", "no_raw"])]
+    csv: bool,
+
+    /// Show bulk strings as a hex+ASCII dump with an offset column
+    #[arg(long = "hexdump", conflicts_with_all = ["raw", "no_raw", "json", "csv"])]
+    hexdump: bool,
+
+    /// Write a one-shot command's reply to this file as raw bytes instead of printing it
+    #[arg(short = 'o', long = "output-file")]
+    output_file: Option<std::path::PathBuf>,
+
+    /// Run a script file with EVAL; trailing words are keys, then args after a lone ','
+    #[arg(long = "eval")]
+    eval: Option<std::path::PathBuf>,
+
+    /// Download a full snapshot from the server (via SYNC) into this file
+    #[arg(long = "rdb")]
+    rdb: Option<std::path::PathBuf>,
+
+    /// Print rolling server stats (keys, memory, clients, ops/sec, hit rate)
+    #[arg(long = "stat")]
+    stat: bool,
+
+    /// List all keys using SCAN, one per line
+    #[arg(long = "scan")]
+    scan: bool,
+
+    /// Key pattern for --scan, e.g. 'user:*'
+    #[arg(long = "pattern")]
+    pattern: Option<String>,
+
+    /// COUNT hint passed to each SCAN call in --scan mode
+    #[arg(long = "count", default_value = "100")]
+    count: usize,
+
+    /// Run a functional smoke test (SET/GET, TTLs, big values, pipelining, errors) and report pass/fail
+    #[arg(long = "check-server")]
+    check_server: bool,
+
+    /// Sample the keyspace and report the biggest keys per type
+    #[arg(long = "bigkeys")]
+    bigkeys: bool,
+
+    /// Sample the keyspace and report the keys using the most memory
+    #[arg(long = "memkeys")]
+    memkeys: bool,
+
+    /// Sample the keyspace and report the most frequently accessed keys
+    #[arg(long = "hotkeys")]
+    hotkeys: bool,
+
+    /// Seconds between samples in --stat mode
+    #[arg(short = 'i', long = "interval", default_value = "1", value_parser = seconds)]
+    interval: std::time::Duration,
+
+    /// REPL history file (default: $RUSTCACHE_HISTFILE or ~/.rc_history; /dev/null disables)
+    #[arg(long = "history-file")]
+    history_file: Option<std::path::PathBuf>,
+
+    /// Maximum number of REPL history entries kept
+    #[arg(long = "history-size", default_value = "1000")]
+    history_size: usize,
+
+    /// Skip consecutive duplicate entries in REPL history
+    #[arg(long = "history-dedup")]
+    history_dedup: bool,
+
+    /// Read the last argument of the command from stdin
+    #[arg(short = 'x', long = "stdin-arg")]
+    stdin_arg: bool,
+
+    /// Run the commands in this file (or '-' for stdin), one per line
+    #[arg(short = 'f', long = "file", conflicts_with_all = ["stdin_arg", "eval"])]
+    file: Option<std::path::PathBuf>,
+
+    /// Exit with status 4 when a one-shot command replies nil
+    #[arg(long = "fail-on-nil")]
+    fail_on_nil: bool,
+
+    /// Print the raw RESP bytes sent (>>) and received (<<) to stderr, in hex and escaped ASCII
+    #[arg(long = "trace")]
+    trace: bool,
+
+    /// Print each command's round-trip time after its reply (`:timing` toggles it in the REPL)
+    #[arg(long = "timing")]
+    timing: bool,
+
     /// Command to run non-interactively, e.g.: rc PING, rc SET k v
     #[arg(action = ArgAction::Append)]
     cmd: Vec<String>,
 }
 
-fn join_host_port(host: &str, port: u16) -> String {
-    format!("{}:{}", host, port)
+async fn send_command(conn: &mut Connection, frame: RespValue) -> Result<RespValue> {
+    conn.request(&frame).await
 }
 
-async fn send_command(stream: &mut TcpStream, frame: RespValue) -> Result<RespValue> {
-    let mut buf = Vec::with_capacity(128);
-    frame.encode(&mut buf);
-    stream.write_all(&buf).await?;
-    let mut reader = BufReader::new(stream);
-    let resp = read_resp(&mut reader).await?;
-    Ok(resp)
+// A number of seconds, for clap. Zero or negative comes out as zero, which
+// disables a timeout.
+fn seconds(value: &str) -> std::result::Result<std::time::Duration, String> {
+    match value.parse::<f64>() {
+        Ok(secs) if secs <= 0.0 => Ok(std::time::Duration::ZERO),
+        Ok(secs) => std::time::Duration::try_from_secs_f64(secs).map_err(|_| format!("{} seconds is out of range", value)),
+        Err(_) => Err(format!("'{}' is not a number of seconds", value)),
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let addr = join_host_port(&cli.host, cli.port);
-    let mut stream = TcpStream::connect(&addr).await?;
+// Exit codes for one-shot mode. Usage errors exit with 2, as clap's own do.
+const EXIT_ERROR_REPLY: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_FAILURE: u8 = 3;
+const EXIT_NIL_REPLY: u8 = 4;
 
-    if cli.cmd.is_empty() {
-        // Interactive REPL with line editing
-        println!("Connected to {}. Type commands, Ctrl+D to quit.", addr);
-        let mut editor = DefaultEditor::new()?;
-
-        loop {
-            let prompt = format!("{}> ", addr);
-            match editor.readline(&prompt) {
-                Ok(line) => {
-                    let trimmed = line.trim();
-                    if trimmed.is_empty() { continue; }
-                    if trimmed.eq_ignore_ascii_case("exit") || trimmed.eq_ignore_ascii_case("quit") {
-                        break;
-                    }
-                    let parts: Vec<String> = shell_words::split(&line).unwrap_or_else(|_| line.split_whitespace().map(|s| s.to_string()).collect());
-                    let frame = build_array_from_cli(&parts);
-                    let resp = send_command(&mut stream, frame).await?;
-                    println!("{}", format_resp(&resp));
-                }
-                Err(ReadlineError::Eof) => break,
-                Err(ReadlineError::Interrupted) => break,
-                Err(err) => {
-                    eprintln!("readline error: {}", err);
-                    break;
-                }
+/// A bad combination of arguments that clap itself can't catch.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+fn reply_exit_code(resp: &RespValue, fail_on_nil: bool) -> ExitCode {
+    match resp {
+        RespValue::Error(_) => ExitCode::from(EXIT_ERROR_REPLY),
+        RespValue::BulkString(None) | RespValue::Array(None) if fail_on_nil => ExitCode::from(EXIT_NIL_REPLY),
+        _ => ExitCode::SUCCESS,
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Cli::parse()).await {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+            if e.downcast_ref::<UsageError>().is_some() {
+                ExitCode::from(EXIT_USAGE)
+            } else {
+                ExitCode::from(EXIT_FAILURE)
             }
         }
-        Ok(())
+    }
+}
+
+async fn run(mut cli: Cli) -> Result<ExitCode> {
+    let mut db = cli.db.unwrap_or(0);
+    if let Some(raw) = cli.uri.take() {
+        let parsed = uri::parse_uri(&raw).map_err(|e| UsageError(format!("{:#}", e)))?;
+        cli.host = parsed.host;
+        cli.port = parsed.port;
+        cli.tls |= parsed.tls;
+        cli.user = cli.user.or(parsed.user);
+        cli.pass = cli.pass.or(parsed.password);
+        db = cli.db.or(parsed.db).unwrap_or(0);
+    }
+    let tls_flags = cli.cacert.is_some() || cli.cert.is_some() || cli.insecure || cli.sni.is_some();
+    if tls_flags && !cli.tls {
+        return Err(UsageError("--cacert, --cert, --key, --insecure and --sni require --tls or a rustcaches:// URI".into()).into());
+    }
+    if cli.stdin_arg && cli.cmd.is_empty() && cli.eval.is_none() {
+        return Err(UsageError("-x needs a command to append stdin to".into()).into());
+    }
+    let one_shot = cli.file.is_none() && (cli.eval.is_some() || !cli.cmd.is_empty());
+    let subscribing = cli.eval.is_none() && cli.cmd.first().is_some_and(|c| subscribe::is_subscribe_command(&[c.as_bytes().to_vec()]));
+    if cli.output_file.is_some() && (!one_shot || subscribing) {
+        return Err(UsageError("-o writes a one-shot command's reply; it can't be used with -f, the REPL or a subscription".into()).into());
+    }
+    let mode = OutputMode::detect(cli.raw, cli.no_raw, cli.json, cli.csv, cli.hexdump);
+    let creds = auth::resolve_credentials(cli.user.clone(), cli.pass.clone(), cli.askpass, !cli.no_auth_warning)?;
+    let tls = cli.tls.then(|| TlsOptions {
+        cacert: cli.cacert.clone(),
+        cert: cli.cert.clone(),
+        key: cli.key.clone(),
+        insecure: cli.insecure,
+        sni: cli.sni.clone(),
+    });
+    let target = match &cli.socket {
+        Some(path) => Target::Unix(path.clone()),
+        None => Target::Tcp { host: cli.host.clone(), port: cli.port, tls },
+    };
+    let endpoint = Endpoint {
+        target,
+        connect_timeout: cli.connect_timeout.filter(|t| !t.is_zero()),
+        command_timeout: cli.timeout.filter(|t| !t.is_zero()),
+        trace: cli.trace,
+    };
+    let mut conn = endpoint.connect().await?;
+    let session = session::Session { creds, db, in_transaction: false };
+    session.establish(&mut conn).await?;
+
+    if let Some(path) = &cli.rdb {
+        rdb::download_rdb(&mut conn, path).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if cli.stat {
+        let interval = cli.interval.max(std::time::Duration::from_millis(10));
+        stat::run_stat(&mut conn, interval).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    if cli.check_server {
+        return check::run_check(&mut conn).await;
+    }
+    if cli.scan {
+        scan::run_scan(&mut conn, cli.pattern.as_deref(), cli.count).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let report = if cli.bigkeys {
+        Some(bigkeys::KeyReport::Big)
+    } else if cli.memkeys {
+        Some(bigkeys::KeyReport::Mem)
+    } else if cli.hotkeys {
+        Some(bigkeys::KeyReport::Hot)
+    } else {
+        None
+    };
+    if let Some(report) = report {
+        bigkeys::run_key_report(&mut conn, report).await?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    if let Some(path) = &cli.file {
+        let commands = batch::read_commands(path)?;
+        return batch::run_batch(&mut conn, &commands, mode, cli.fail_on_nil, cli.timing).await;
+    }
+    if cli.cmd.is_empty() && cli.eval.is_none() {
+        // Interactive REPL with line editing
+        let history = repl::HistoryOptions::resolve(cli.history_file, cli.history_size, cli.history_dedup);
+        let opts = repl::ReplOptions { mode, history, cluster: cli.cluster, timing: cli.timing };
+        repl::run_repl(endpoint, conn, session, opts).await?;
+        Ok(ExitCode::SUCCESS)
     } else {
-        let frame = build_array_from_cli(&cli.cmd);
-        let resp = send_command(&mut stream, frame).await?;
-        println!("{}", format_resp(&resp));
-        Ok(())
+        let mut args: Vec<Vec<u8>> = match &cli.eval {
+            Some(script) => eval::build_eval_args(script, &cli.cmd)?,
+            None => cli.cmd.iter().map(|a| a.as_bytes().to_vec()).collect(),
+        };
+        if cli.stdin_arg {
+            let mut payload = Vec::new();
+            std::io::Read::read_to_end(&mut std::io::stdin(), &mut payload)?;
+            args.push(payload);
+        }
+        if subscribe::is_subscribe_command(&args) {
+            subscribe::run_subscribe(&mut conn, &args, mode).await?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        let started = std::time::Instant::now();
+        let resp = if cli.cluster {
+            let mut endpoint = endpoint;
+            cluster::ClusterState::default()
+                .send(&mut conn, &mut endpoint, &session, &args)
+                .await?
+        } else {
+            send_command(&mut conn, build_array_from_cli(&args)).await?
+        };
+        let elapsed = started.elapsed();
+        match &cli.output_file {
+            // Errors still go to the terminal rather than into the file
+            Some(path) if !matches!(resp, RespValue::Error(_)) => write_reply(&resp, path)?,
+            _ => print_reply(&resp, mode)?,
+        }
+        if cli.timing {
+            print_timing(elapsed, mode)?;
+        }
+        Ok(reply_exit_code(&resp, cli.fail_on_nil))
     }
 }
 
@@ -0,0 +1,216 @@
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::RespValue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Human-friendly replies with "(nil)", "(error)" and numbered arrays.
+    Standard,
+    /// Bulk bytes verbatim, one array element per line, no decorations.
+    Raw,
+    /// One JSON value per reply; errors become `{"error": "..."}`.
+    Json,
+    /// One CSV record per reply; nested arrays are flattened into the record.
+    Csv,
+    /// Like standard, but bulk strings are shown as a hex+ASCII dump with an offset column.
+    Hexdump,
+}
+
+impl OutputMode {
+    /// Picks raw output when requested or when stdout is not a terminal, like redis-cli.
+    pub fn detect(raw: bool, no_raw: bool, json: bool, csv: bool, hexdump: bool) -> Self {
+        if hexdump {
+            OutputMode::Hexdump
+        } else if json {
+            OutputMode::Json
+        } else if csv {
+            OutputMode::Csv
+        } else if raw {
+            OutputMode::Raw
+        } else if no_raw || io::stdout().is_terminal() {
+            OutputMode::Standard
+        } else {
+            OutputMode::Raw
+        }
+    }
+}
+
+const DUMP_WIDTH: usize = 16;
+
+/// `hexdump -C` style lines: offset, sixteen bytes in two groups of eight,
+/// the printable ones between bars, then the total length as a last offset.
+fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, chunk) in bytes.chunks(DUMP_WIDTH).enumerate() {
+        let mut hex = String::new();
+        for (j, b) in chunk.iter().enumerate() {
+            if j == DUMP_WIDTH / 2 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x} ", b));
+        }
+        let text: String = chunk.iter().map(|&b| if (0x20..=0x7e).contains(&b) { b as char } else { '.' }).collect();
+        out.push_str(&format!("{:08x}  {:<49} |{}|\n", i * DUMP_WIDTH, hex, text));
+    }
+    out.push_str(&format!("{:08x}", bytes.len()));
+    out
+}
+
+fn format_resp(resp: &RespValue, dump: bool) -> String {
+    match resp {
+        RespValue::SimpleString(s) => s.clone(),
+        RespValue::Error(s) => format!("(error) {}", s),
+        RespValue::Integer(i) => i.to_string(),
+        RespValue::BulkString(None) => "(nil)".to_string(),
+        RespValue::BulkString(Some(b)) if dump => hexdump(b),
+        RespValue::BulkString(Some(b)) => match String::from_utf8(b.clone()) {
+            Ok(s) => s,
+            Err(_) => format!("(binary) {} bytes", b.len()),
+        },
+        RespValue::Array(None) => "(nil)".to_string(),
+        RespValue::Array(Some(items)) => {
+            let mut out = String::new();
+            for (i, it) in items.iter().enumerate() {
+                let prefix = format!("{}) ", i + 1);
+                let mut item = format_resp(it, dump);
+                if dump {
+                    // Keep a dump's lines lined up under its first one
+                    item = item.replace('\n', &format!("\n{:width$}", "", width = prefix.len()));
+                }
+                out.push_str(&format!("{}{}\n", prefix, item));
+            }
+            out.trim_end().to_string()
+        }
+    }
+}
+
+fn format_raw(resp: &RespValue, out: &mut Vec<u8>) {
+    match resp {
+        RespValue::SimpleString(s) | RespValue::Error(s) => out.extend_from_slice(s.as_bytes()),
+        RespValue::Integer(i) => out.extend_from_slice(i.to_string().as_bytes()),
+        RespValue::BulkString(None) | RespValue::Array(None) => {}
+        RespValue::BulkString(Some(b)) => out.extend_from_slice(b),
+        RespValue::Array(Some(items)) => {
+            for (i, it) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(b'\n');
+                }
+                format_raw(it, out);
+            }
+        }
+    }
+}
+
+fn json_escape(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn format_json(resp: &RespValue, out: &mut String) {
+    match resp {
+        RespValue::SimpleString(s) => json_escape(s, out),
+        RespValue::Error(s) => {
+            out.push_str("{\"error\":");
+            json_escape(s, out);
+            out.push('}');
+        }
+        RespValue::Integer(i) => out.push_str(&i.to_string()),
+        RespValue::BulkString(None) | RespValue::Array(None) => out.push_str("null"),
+        RespValue::BulkString(Some(b)) => json_escape(&String::from_utf8_lossy(b), out),
+        RespValue::Array(Some(items)) => {
+            out.push('[');
+            for (i, it) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                format_json(it, out);
+            }
+            out.push(']');
+        }
+    }
+}
+
+fn csv_field(s: &str, fields: &mut Vec<String>) {
+    fields.push(format!("\"{}\"", s.replace('"', "\"\"")));
+}
+
+fn collect_csv(resp: &RespValue, fields: &mut Vec<String>) {
+    match resp {
+        RespValue::SimpleString(s) => csv_field(s, fields),
+        RespValue::Error(s) => {
+            fields.push("ERROR".to_string());
+            csv_field(s, fields);
+        }
+        RespValue::Integer(i) => fields.push(i.to_string()),
+        RespValue::BulkString(None) | RespValue::Array(None) => fields.push("NULL".to_string()),
+        RespValue::BulkString(Some(b)) => csv_field(&String::from_utf8_lossy(b), fields),
+        RespValue::Array(Some(items)) => {
+            for it in items {
+                collect_csv(it, fields);
+            }
+        }
+    }
+}
+
+pub fn render(resp: &RespValue, mode: OutputMode) -> Vec<u8> {
+    match mode {
+        OutputMode::Standard => format_resp(resp, false).into_bytes(),
+        OutputMode::Hexdump => format_resp(resp, true).into_bytes(),
+        OutputMode::Raw => {
+            let mut out = Vec::new();
+            format_raw(resp, &mut out);
+            out
+        }
+        OutputMode::Json => {
+            let mut out = String::new();
+            format_json(resp, &mut out);
+            out.into_bytes()
+        }
+        OutputMode::Csv => {
+            let mut fields = Vec::new();
+            collect_csv(resp, &mut fields);
+            fields.join(",").into_bytes()
+        }
+    }
+}
+
+/// Prints a command's round-trip time after its reply. Machine-readable
+/// modes get it on stderr so stdout stays parseable.
+pub fn print_timing(elapsed: Duration, mode: OutputMode) -> io::Result<()> {
+    let line = format!("({:.2} ms)", elapsed.as_secs_f64() * 1000.0);
+    if matches!(mode, OutputMode::Standard | OutputMode::Hexdump) {
+        let mut out = io::stdout().lock();
+        writeln!(out, "{}", line)?;
+        out.flush()
+    } else {
+        writeln!(io::stderr(), "{}", line)
+    }
+}
+
+pub fn print_reply(resp: &RespValue, mode: OutputMode) -> io::Result<()> {
+    let mut bytes = render(resp, mode);
+    bytes.push(b'\n');
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    out.write_all(&bytes)?;
+    out.flush()
+}
+
+/// Writes a reply's bytes to `path` exactly as `--raw` would print them,
+/// so binary values come out intact.
+pub fn write_reply(resp: &RespValue, path: &Path) -> io::Result<()> {
+    std::fs::write(path, render(resp, OutputMode::Raw))
+}
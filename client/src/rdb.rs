@@ -0,0 +1,43 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use tokio::io::AsyncWriteExt;
+
+use crate::build_array_from_cli;
+use crate::connection::Connection;
+
+/// Requests a full snapshot with SYNC and streams the payload into `path`.
+pub async fn download_rdb(conn: &mut Connection, path: &Path) -> Result<()> {
+    conn.write_frame(&build_array_from_cli(&["SYNC"])).await?;
+    let mut file = tokio::fs::File::create(path)
+        .await
+        .with_context(|| format!("could not create {}", path.display()))?;
+
+    let header = loop {
+        let line = conn.read_raw_line().await?;
+        match line.first() {
+            // Masters send bare newlines as keepalives while preparing the snapshot.
+            None => continue,
+            Some(b'+') => continue,
+            Some(b'-') => bail!("server refused SYNC: {}", String::from_utf8_lossy(&line[1..])),
+            Some(b'$') => break line,
+            Some(_) => bail!("unexpected reply to SYNC: {}", String::from_utf8_lossy(&line)),
+        }
+    };
+
+    let written = if let Some(mark) = header.strip_prefix(b"$EOF:") {
+        eprintln!("SYNC sent to master, writing bytes of bulk transfer until EOF marker to '{}'", path.display());
+        conn.copy_until_marker(mark, &mut file).await?
+    } else {
+        let len: u64 = std::str::from_utf8(&header[1..])
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .context("invalid snapshot length")?;
+        eprintln!("SYNC sent to master, writing {} bytes to '{}'", len, path.display());
+        conn.copy_exact(len, &mut file).await?
+    };
+    file.flush().await?;
+    file.sync_all().await?;
+    eprintln!("Transfer finished with success after {} bytes", written);
+    Ok(())
+}
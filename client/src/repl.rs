@@ -0,0 +1,213 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Config, Editor};
+
+use crate::connection::{Connection, Endpoint};
+use crate::args::split_args;
+use crate::cluster::ClusterState;
+use crate::completion::{fetch_command_docs, IdleConnection, RcHelper};
+use crate::output::{print_reply, print_timing, OutputMode};
+use crate::session::Session;
+use crate::subscribe::{is_subscribe_command, run_subscribe};
+use crate::{build_array_from_cli, send_command};
+
+pub struct HistoryOptions {
+    /// None disables the history file entirely.
+    pub path: Option<PathBuf>,
+    pub max_len: usize,
+    pub dedup: bool,
+}
+
+impl HistoryOptions {
+    /// Resolves the history file from the flag, then RUSTCACHE_HISTFILE, then ~/.rc_history.
+    /// An empty value or /dev/null disables persistence.
+    pub fn resolve(flag: Option<PathBuf>, max_len: usize, dedup: bool) -> Self {
+        let path = flag
+            .or_else(|| std::env::var_os("RUSTCACHE_HISTFILE").map(PathBuf::from))
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".rc_history")))
+            .filter(|p| !p.as_os_str().is_empty() && p.as_os_str() != "/dev/null");
+        Self { path, max_len, dedup }
+    }
+}
+
+fn is_auth_command(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|cmd| cmd.eq_ignore_ascii_case("auth") || cmd.eq_ignore_ascii_case("hello"))
+}
+
+const RECONNECT_ATTEMPTS: u32 = 8;
+const BACKOFF_START: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+// Re-dials with exponential backoff and replays AUTH/SELECT on success.
+async fn reconnect(endpoint: &Endpoint, session: &Session) -> Option<Connection> {
+    let mut delay = BACKOFF_START;
+    for attempt in 1..=RECONNECT_ATTEMPTS {
+        match endpoint.connect().await {
+            Ok(mut conn) => match session.establish(&mut conn).await {
+                Ok(()) => {
+                    eprintln!("Reconnected to {}.", endpoint.label());
+                    return Some(conn);
+                }
+                Err(e) => {
+                    // Retrying won't fix rejected credentials or a bad database.
+                    eprintln!("Reconnected but could not restore session: {}", e);
+                    return None;
+                }
+            },
+            Err(e) => {
+                eprintln!(
+                    "Reconnect attempt {}/{} failed: {}",
+                    attempt,
+                    RECONNECT_ATTEMPTS,
+                    e.root_cause()
+                );
+            }
+        }
+        if attempt < RECONNECT_ATTEMPTS {
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(BACKOFF_MAX);
+        }
+    }
+    eprintln!("Giving up for now; the next command will try again.");
+    None
+}
+
+pub struct ReplOptions {
+    pub mode: OutputMode,
+    pub history: HistoryOptions,
+    /// Follow -MOVED/-ASK redirects (-c).
+    pub cluster: bool,
+    /// Print each command's round-trip time (--timing, or `:timing` in the REPL).
+    pub timing: bool,
+}
+
+// `:timing [on|off]`, handled here rather than sent; toggles without an argument
+fn timing_command(line: &str, timing: &mut bool) -> Option<Result<(), String>> {
+    let mut words = line.split_whitespace();
+    if words.next() != Some(":timing") {
+        return None;
+    }
+    let result = match (words.next(), words.next()) {
+        (None, _) => Ok(!*timing),
+        (Some(w), None) if w.eq_ignore_ascii_case("on") => Ok(true),
+        (Some(w), None) if w.eq_ignore_ascii_case("off") => Ok(false),
+        _ => Err("usage: :timing [on|off]".to_string()),
+    };
+    Some(result.map(|on| {
+        *timing = on;
+        println!("Timing is {}.", if on { "on" } else { "off" });
+    }))
+}
+
+pub async fn run_repl(mut endpoint: Endpoint, conn: Connection, mut session: Session, opts: ReplOptions) -> Result<()> {
+    let ReplOptions { mode, history, cluster, mut timing } = opts;
+    let mut cluster = cluster.then(ClusterState::default);
+    println!("Connected to {}. Type commands, Ctrl+D to quit.", endpoint.label());
+    let mut conn = Some(conn);
+    let idle: IdleConnection = Arc::new(Mutex::new(None));
+    let mut helper = RcHelper::new(idle.clone());
+    if let Some(c) = conn.as_mut() {
+        if let Some(docs) = fetch_command_docs(c).await {
+            helper.merge(docs);
+        }
+    }
+    let config = Config::builder()
+        .max_history_size(history.max_len)?
+        .history_ignore_dups(history.dedup)?
+        .auto_add_history(false)
+        .build();
+    let mut editor: Editor<RcHelper, DefaultHistory> = Editor::with_config(config)?;
+    editor.set_helper(Some(helper));
+    if let Some(path) = &history.path {
+        // A missing file just means this is the first session.
+        let _ = editor.load_history(path);
+    }
+
+    loop {
+        let addr = endpoint.label();
+        let prompt = if conn.is_some() { session.prompt(&addr) } else { "not connected> ".to_string() };
+        // Lend the connection to key completion while the prompt is up.
+        *idle.lock().unwrap() = conn.take();
+        let read = editor.readline(&prompt);
+        conn = idle.lock().unwrap().take();
+        match read {
+            Ok(line) => {
+                let trimmed = line.trim();
+                if trimmed.is_empty() { continue; }
+                // Keep passwords out of the history file.
+                if !is_auth_command(trimmed) {
+                    editor.add_history_entry(trimmed)?;
+                }
+                if trimmed.eq_ignore_ascii_case("exit") || trimmed.eq_ignore_ascii_case("quit") {
+                    break;
+                }
+                if let Some(result) = timing_command(trimmed, &mut timing) {
+                    if let Err(e) = result {
+                        eprintln!("{}", e);
+                    }
+                    continue;
+                }
+                let parts = match split_args(&line) {
+                    Ok(parts) => parts,
+                    Err(e) => {
+                        eprintln!("Invalid argument(s): {}", e);
+                        continue;
+                    }
+                };
+                if parts.is_empty() { continue; }
+                if conn.is_none() {
+                    session.connection_reset();
+                    conn = reconnect(&endpoint, &session).await;
+                }
+                let Some(c) = conn.as_mut() else { continue };
+                let started = Instant::now();
+                let result = if is_subscribe_command(&parts) {
+                    run_subscribe(c, &parts, mode).await.map(|_| None)
+                } else if let Some(state) = cluster.as_mut() {
+                    state.send(c, &mut endpoint, &session, &parts).await.map(Some)
+                } else {
+                    send_command(c, build_array_from_cli(&parts)).await.map(Some)
+                };
+                match result {
+                    Ok(None) => {}
+                    Ok(Some(resp)) => {
+                        let elapsed = started.elapsed();
+                        session.observe(&parts, &resp);
+                        print_reply(&resp, mode)?;
+                        if timing {
+                            print_timing(elapsed, mode)?;
+                        }
+                    }
+                    Err(e) => {
+                        // The command may or may not have run, so it is not retried.
+                        eprintln!("Connection to {} lost ({}); reconnecting...", addr, e.root_cause());
+                        session.connection_reset();
+                        conn = reconnect(&endpoint, &session).await;
+                        if conn.is_some() {
+                            eprintln!("The last command may not have been executed.");
+                        }
+                    }
+                }
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(ReadlineError::Interrupted) => break,
+            Err(err) => {
+                eprintln!("readline error: {}", err);
+                break;
+            }
+        }
+    }
+    if let Some(path) = &history.path {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("could not save history to {}: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use std::io::Write;
+
+use anyhow::{bail, Result};
+
+use crate::connection::Connection;
+use crate::{build_array_from_cli, send_command, RespValue};
+
+/// Issues one SCAN call and returns the next cursor plus the keys in the page.
+pub async fn scan_page(
+    conn: &mut Connection,
+    cursor: &str,
+    pattern: Option<&str>,
+    count: usize,
+) -> Result<(String, Vec<String>)> {
+    let mut args = vec!["SCAN".to_string(), cursor.to_string()];
+    if let Some(p) = pattern {
+        args.push("MATCH".to_string());
+        args.push(p.to_string());
+    }
+    args.push("COUNT".to_string());
+    args.push(count.to_string());
+    match send_command(conn, build_array_from_cli(&args)).await? {
+        RespValue::Array(Some(parts)) if parts.len() == 2 => {
+            let next = match &parts[0] {
+                RespValue::BulkString(Some(b)) => String::from_utf8_lossy(b).to_string(),
+                other => bail!("unexpected SCAN cursor: {:?}", other),
+            };
+            let keys = match &parts[1] {
+                RespValue::Array(Some(items)) => items
+                    .iter()
+                    .filter_map(|it| match it {
+                        RespValue::BulkString(Some(b)) => Some(String::from_utf8_lossy(b).to_string()),
+                        _ => None,
+                    })
+                    .collect(),
+                _ => Vec::new(),
+            };
+            Ok((next, keys))
+        }
+        RespValue::Error(e) => bail!("SCAN failed: {}", e),
+        other => bail!("unexpected SCAN reply: {:?}", other),
+    }
+}
+
+/// Iterates SCAN cursors until the server reports 0 and prints every key on its own line.
+pub async fn run_scan(conn: &mut Connection, pattern: Option<&str>, count: usize) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut cursor = "0".to_string();
+    loop {
+        let (next, keys) = scan_page(conn, &cursor, pattern, count.max(1)).await?;
+        for key in keys {
+            writeln!(out, "{}", key)?;
+        }
+        out.flush()?;
+        if next == "0" {
+            return Ok(());
+        }
+        cursor = next;
+    }
+}
@@ -0,0 +1,84 @@
+use anyhow::{bail, Result};
+
+use crate::auth::{authenticate, Credentials};
+use crate::connection::Connection;
+use crate::{build_array_from_cli, send_command, RespValue};
+
+/// Per-connection state that has to be re-established on every new connection.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    pub creds: Option<Credentials>,
+    pub db: u32,
+    /// Inside MULTI; not replayed, since queued commands die with the connection.
+    pub in_transaction: bool,
+}
+
+impl Session {
+    pub async fn establish(&self, conn: &mut Connection) -> Result<()> {
+        if let Some(creds) = &self.creds {
+            authenticate(conn, creds).await?;
+        }
+        if self.db != 0 {
+            select_db(conn, self.db).await?;
+        }
+        Ok(())
+    }
+
+    /// REPL prompt, e.g. `127.0.0.1:9973[2](TX)> `.
+    pub fn prompt(&self, addr: &str) -> String {
+        let mut prompt = addr.to_string();
+        if self.db != 0 {
+            prompt.push_str(&format!("[{}]", self.db));
+        }
+        if self.in_transaction {
+            prompt.push_str("(TX)");
+        }
+        prompt.push_str("> ");
+        prompt
+    }
+
+    /// Called after a reconnect: anything queued in MULTI is gone.
+    pub fn connection_reset(&mut self) {
+        if self.in_transaction {
+            eprintln!("The open transaction was discarded by the reconnect.");
+            self.in_transaction = false;
+        }
+    }
+
+    /// Tracks SELECT/AUTH/MULTI so the prompt stays accurate and state can be replayed after a reconnect.
+    pub fn observe(&mut self, args: &[Vec<u8>], reply: &RespValue) {
+        if args.is_empty() {
+            return;
+        }
+        let cmd = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+        // EXEC and DISCARD close the transaction even when they fail.
+        if cmd == "exec" || cmd == "discard" {
+            self.in_transaction = false;
+            return;
+        }
+        // Inside MULTI commands only reply +QUEUED; they take effect at EXEC.
+        if matches!(reply, RespValue::Error(_)) || self.in_transaction {
+            return;
+        }
+        let text = |i: usize| String::from_utf8_lossy(&args[i]).to_string();
+        match (cmd.as_str(), args.len()) {
+            ("select", 2) => {
+                if let Ok(db) = text(1).parse() {
+                    self.db = db;
+                }
+            }
+            ("auth", 2) => self.creds = Some(Credentials { user: None, password: text(1) }),
+            ("auth", 3) => self.creds = Some(Credentials { user: Some(text(1)), password: text(2) }),
+            ("multi", 1) => self.in_transaction = true,
+            _ => {}
+        }
+    }
+}
+
+pub async fn select_db(conn: &mut Connection, db: u32) -> Result<()> {
+    let args = ["SELECT".to_string(), db.to_string()];
+    match send_command(conn, build_array_from_cli(&args)).await? {
+        RespValue::Error(e) => bail!("SELECT {} failed: {}", db, e),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+
+use crate::connection::Connection;
+use crate::{build_array_from_cli, send_command, RespValue};
+
+const HEADER_EVERY: usize = 20;
+
+fn parse_info(text: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            fields.insert(k.to_string(), v.to_string());
+        }
+    }
+    fields
+}
+
+fn field_u64(fields: &HashMap<String, String>, name: &str) -> u64 {
+    fields.get(name).and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+// Sums `keys=N` over every `dbN:keys=...,expires=...` line of the keyspace section.
+fn total_keys(fields: &HashMap<String, String>) -> u64 {
+    fields
+        .iter()
+        .filter(|(k, _)| k.starts_with("db"))
+        .filter_map(|(_, v)| {
+            v.split(',')
+                .find_map(|kv| kv.strip_prefix("keys="))
+                .and_then(|n| n.parse::<u64>().ok())
+        })
+        .sum()
+}
+
+fn human_bytes(n: u64) -> String {
+    let units = ["B", "K", "M", "G", "T"];
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}B", n)
+    } else {
+        format!("{:.2}{}", value, units[unit])
+    }
+}
+
+async fn fetch_info(conn: &mut Connection) -> Result<HashMap<String, String>> {
+    let frame = build_array_from_cli(&["INFO".to_string()]);
+    match send_command(conn, frame).await? {
+        RespValue::BulkString(Some(b)) => Ok(parse_info(&String::from_utf8_lossy(&b))),
+        RespValue::Error(e) => bail!("INFO failed: {}", e),
+        other => bail!("unexpected INFO reply: {:?}", other),
+    }
+}
+
+fn print_header() {
+    println!(
+        "{:<12} {:<10} {:<8} {:<24} {:<10} {:<8}",
+        "keys", "mem", "clients", "requests", "ops/sec", "hit rate"
+    );
+}
+
+/// Polls INFO every `interval` and prints one row per sample, like `redis-cli --stat`.
+pub async fn run_stat(conn: &mut Connection, interval: Duration) -> Result<()> {
+    let mut prev: Option<(u64, u64, u64, Instant)> = None;
+    let mut rows = 0usize;
+    loop {
+        let fields = fetch_info(conn).await?;
+        let now = Instant::now();
+        let requests = field_u64(&fields, "total_commands_processed");
+        let hits = field_u64(&fields, "keyspace_hits");
+        let misses = field_u64(&fields, "keyspace_misses");
+
+        let (delta, ops, hit_rate) = match prev {
+            Some((p_req, p_hits, p_misses, p_at)) => {
+                let delta = requests.saturating_sub(p_req);
+                let secs = now.duration_since(p_at).as_secs_f64().max(f64::EPSILON);
+                let new_hits = hits.saturating_sub(p_hits);
+                let lookups = new_hits + misses.saturating_sub(p_misses);
+                let rate = if lookups == 0 {
+                    "-".to_string()
+                } else {
+                    format!("{:.1}%", new_hits as f64 * 100.0 / lookups as f64)
+                };
+                (delta, format!("{:.0}", delta as f64 / secs), rate)
+            }
+            None => (0, "-".to_string(), "-".to_string()),
+        };
+
+        if rows.is_multiple_of(HEADER_EVERY) {
+            print_header();
+        }
+        println!(
+            "{:<12} {:<10} {:<8} {:<24} {:<10} {:<8}",
+            total_keys(&fields),
+            human_bytes(field_u64(&fields, "used_memory")),
+            field_u64(&fields, "connected_clients"),
+            format!("{} (+{})", requests, delta),
+            ops,
+            hit_rate
+        );
+        rows += 1;
+        prev = Some((requests, hits, misses, now));
+        tokio::time::sleep(interval).await;
+    }
+}
@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::connection::Connection;
+use crate::output::{print_reply, OutputMode};
+use crate::{build_array_from_cli, RespValue};
+
+const UNSUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub fn is_subscribe_command(args: &[Vec<u8>]) -> bool {
+    args.first().is_some_and(|cmd| {
+        cmd.eq_ignore_ascii_case(b"subscribe")
+            || cmd.eq_ignore_ascii_case(b"psubscribe")
+            || cmd.eq_ignore_ascii_case(b"ssubscribe")
+    })
+}
+
+// Sent in this order on exit. The server counts channels and patterns
+// together and shard channels apart, so a PUNSUBSCRIBE and a SUNSUBSCRIBE
+// confirmation with nothing remaining mean everything is gone.
+const UNSUBSCRIBES: [&str; 3] = ["UNSUBSCRIBE", "PUNSUBSCRIBE", "SUNSUBSCRIBE"];
+
+// Subscription confirmations look like ["unsubscribe", channel, remaining-count].
+fn confirmation(reply: &RespValue) -> Option<(&[u8], i64)> {
+    match reply {
+        RespValue::Array(Some(items)) if items.len() == 3 => match (&items[0], &items[2]) {
+            (RespValue::BulkString(Some(kind)), RespValue::Integer(n))
+                if UNSUBSCRIBES.iter().any(|cmd| kind.eq_ignore_ascii_case(cmd.as_bytes())) =>
+            {
+                Some((kind.as_slice(), *n))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Sends a SUBSCRIBE-family command and prints pushed messages until Ctrl+C,
+/// then unsubscribes from everything so the connection can be reused.
+pub async fn run_subscribe(conn: &mut Connection, args: &[Vec<u8>], mode: OutputMode) -> Result<()> {
+    conn.write_frame(&build_array_from_cli(args)).await?;
+    eprintln!("Reading messages... (press Ctrl-C to quit)");
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        // Only the wait for bytes races Ctrl-C; a frame once started is read
+        // whole, so the drain below never starts mid-frame.
+        tokio::select! {
+            ready = conn.readable() => {
+                ready?;
+                print_reply(&conn.read_reply().await?, mode)?;
+            }
+            _ = &mut ctrl_c => break,
+        }
+    }
+
+    for cmd in UNSUBSCRIBES {
+        conn.write_frame(&build_array_from_cli(&[cmd])).await?;
+    }
+    // Drain until the server confirms the unsubscribes, tolerating in-flight messages.
+    let (mut patterns_done, mut shards_done) = (false, false);
+    let drain = async {
+        while !(patterns_done && shards_done) {
+            let reply = conn.read_reply().await?;
+            match confirmation(&reply) {
+                Some((kind, 0)) if kind.eq_ignore_ascii_case(b"punsubscribe") => patterns_done = true,
+                Some((kind, 0)) if kind.eq_ignore_ascii_case(b"sunsubscribe") => shards_done = true,
+                _ => {}
+            }
+        }
+        Ok::<(), anyhow::Error>(())
+    };
+    match tokio::time::timeout(UNSUBSCRIBE_TIMEOUT, drain).await {
+        Ok(res) => res,
+        Err(_) => anyhow::bail!("timed out waiting for UNSUBSCRIBE confirmation"),
+    }
+}
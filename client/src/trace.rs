@@ -0,0 +1,96 @@
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::connection::Transport;
+
+const BYTES_PER_LINE: usize = 16;
+
+/// Which way traced bytes went.
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn marker(self) -> &'static str {
+        match self {
+            Direction::Sent => ">>",
+            Direction::Received => "<<",
+        }
+    }
+}
+
+/// `--trace` lines for a chunk of bytes: the direction marker, up to 16
+/// bytes in hex, then the same bytes as escaped ASCII.
+pub fn trace_lines(direction: Direction, bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .map(|chunk| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = chunk.iter().map(|b| escape(*b)).collect();
+            format!("{} {:<width$}  {}", direction.marker(), hex.join(" "), text, width = BYTES_PER_LINE * 3 - 1)
+        })
+        .collect()
+}
+
+fn escape(b: u8) -> String {
+    match b {
+        b'\r' => "\\r".to_string(),
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\\' => "\\\\".to_string(),
+        0x20..=0x7e => (b as char).to_string(),
+        _ => format!("\\x{:02x}", b),
+    }
+}
+
+fn trace(direction: Direction, bytes: &[u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    let mut err = io::stderr().lock();
+    for line in trace_lines(direction, bytes) {
+        let _ = writeln!(err, "{}", line);
+    }
+}
+
+/// A transport that, with tracing on, copies every byte it moves to stderr.
+pub struct Traced {
+    pub inner: Transport,
+    pub enabled: bool,
+}
+
+impl AsyncRead for Traced {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if this.enabled && matches!(polled, Poll::Ready(Ok(()))) {
+            trace(Direction::Received, &buf.filled()[before..]);
+        }
+        polled
+    }
+}
+
+impl AsyncWrite for Traced {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (true, Poll::Ready(Ok(n))) = (this.enabled, &polled) {
+            trace(Direction::Sent, &buf[..*n]);
+        }
+        polled
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
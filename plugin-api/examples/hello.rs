@@ -0,0 +1,79 @@
+//! Example plugin adding HELLO [name] and GETDEL-style TAKE key.
+//!
+//!     cargo build -p rustcache-plugin-api --example hello
+//!     RUSTCACHE_PLUGINS=target/debug/examples/libhello.so cargo run -p server
+
+use std::os::raw::{c_int, c_void};
+
+use rustcache_plugin_api::{CommandInfo, HostApi, Slice, ABI_VERSION, FLAG_FAST, FLAG_WRITE};
+
+#[no_mangle]
+pub extern "C" fn rustcache_plugin_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// # Safety
+/// Called once by the server with a valid API table and registrar.
+#[no_mangle]
+pub unsafe extern "C" fn rustcache_plugin_init(api: *const HostApi, registrar: *mut c_void) -> c_int {
+    let api = &*api;
+    let hello_info = CommandInfo {
+        name: c"hello".as_ptr(),
+        arity: -1,
+        flags: FLAG_FAST,
+        first_key: 0,
+        last_key: 0,
+        key_step: 0,
+    };
+    if (api.register_command)(registrar, &hello_info, hello) != 0 {
+        return -1;
+    }
+    // TAKE key: one key, which it deletes
+    let take_info = CommandInfo {
+        name: c"take".as_ptr(),
+        arity: 2,
+        flags: FLAG_WRITE | FLAG_FAST,
+        first_key: 1,
+        last_key: 1,
+        key_step: 1,
+    };
+    if (api.register_command)(registrar, &take_info, take) != 0 {
+        return -1;
+    }
+    (api.log)(Slice::new(b"hello plugin loaded"));
+    0
+}
+
+extern "C" fn hello(api: *const HostApi, call: *mut c_void, argv: *const Slice, argc: usize) {
+    let api = unsafe { &*api };
+    let args = unsafe { std::slice::from_raw_parts(argv, argc) };
+    if let Some(extra) = args.get(1) {
+        let mut error = b"ERR unexpected argument '".to_vec();
+        error.extend_from_slice(unsafe { extra.as_bytes() });
+        error.push(b'\'');
+        (api.reply_error)(call, Slice::new(&error));
+        return;
+    }
+    let name = match args.first() {
+        Some(s) => unsafe { s.as_bytes() },
+        None => b"world".as_slice(),
+    };
+    let mut reply = b"Hello, ".to_vec();
+    reply.extend_from_slice(name);
+    (api.reply_bulk)(call, Slice::new(&reply));
+}
+
+extern "C" fn take(api: *const HostApi, call: *mut c_void, argv: *const Slice, argc: usize) {
+    let api = unsafe { &*api };
+    // The server has checked the arity, so there is exactly one argument
+    debug_assert_eq!(argc, 1);
+    let key = unsafe { *argv };
+    let mut value = Slice::new(&[]);
+    if (api.get)(call, key, &mut value) == 1 {
+        // `value` stays valid until we return, even after the key is deleted
+        (api.del)(call, key);
+        (api.reply_bulk)(call, value);
+    } else {
+        (api.reply_null)(call);
+    }
+}
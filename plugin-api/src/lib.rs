@@ -0,0 +1,113 @@
+//! C ABI shared by the RustCache server and its plugins.
+//!
+//! A plugin is a dynamic library exporting two symbols:
+//!
+//! * `rustcache_plugin_abi_version() -> u32`, returning [`ABI_VERSION`];
+//! * `rustcache_plugin_init(api, registrar) -> c_int`, which registers its
+//!   commands through `api.register_command` and returns 0 on success.
+//!
+//! Each command is registered with a [`CommandInfo`] declaring its arity,
+//! flags and key positions, as the built-in commands have. The server goes
+//! by them as it does for its own: only commands flagged [`FLAG_WRITE`] are
+//! appended to the AOF, sent to replicas and committed through raft, and
+//! only they may write (a write from any other command fails the call);
+//! [`FLAG_DENYOOM`] commands are refused when over the memory budget; and
+//! the declared keys are the ones checked for slots and namespaces, so a
+//! command should touch no others.
+//!
+//! Command callbacks receive the host API, an opaque call handle, and the
+//! arguments after the command name. They read and write keys and build exactly
+//! one reply through the host API. Byte slices handed to a callback are valid
+//! only until it returns. Values are byte strings: the keyspace holds nothing
+//! else, so plugins cannot define value types of their own. Everything here is
+//! plain `repr(C)`, so plugins can be written in any language that can export
+//! C functions.
+
+use std::os::raw::{c_char, c_int, c_void};
+
+/// Bumped whenever `HostApi` changes layout; the server refuses mismatched plugins.
+pub const ABI_VERSION: u32 = 2;
+
+pub const ABI_VERSION_SYMBOL: &[u8] = b"rustcache_plugin_abi_version\0";
+pub const INIT_SYMBOL: &[u8] = b"rustcache_plugin_init\0";
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Slice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl Slice {
+    pub fn new(bytes: &[u8]) -> Self {
+        Self { ptr: bytes.as_ptr(), len: bytes.len() }
+    }
+
+    /// # Safety
+    /// `ptr` must point to `len` readable bytes that outlive `'a`.
+    pub unsafe fn as_bytes<'a>(&self) -> &'a [u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+/// Modifies the dataset.
+pub const FLAG_WRITE: u32 = 1;
+/// Only reads the dataset.
+pub const FLAG_READONLY: u32 = 1 << 1;
+/// May grow memory use; refused with OOM when the database is over budget.
+pub const FLAG_DENYOOM: u32 = 1 << 2;
+/// Server administration.
+pub const FLAG_ADMIN: u32 = 1 << 3;
+/// Runs in constant or logarithmic time.
+pub const FLAG_FAST: u32 = 1 << 4;
+
+/// What the server needs to know about a command before running it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct CommandInfo {
+    /// NUL-terminated.
+    pub name: *const c_char,
+    /// Arguments including the name; negative means at least that many.
+    pub arity: i32,
+    /// `FLAG_*` values ORed together.
+    pub flags: u32,
+    /// The key arguments, counting the name as 0: every `key_step`-th from
+    /// `first_key` to `last_key`, where a negative `last_key` counts back from
+    /// the end (-1 is the final argument). A `first_key` of 0 means no keys.
+    pub first_key: i32,
+    pub last_key: i32,
+    pub key_step: i32,
+}
+
+pub type CommandFn = extern "C" fn(api: *const HostApi, call: *mut c_void, argv: *const Slice, argc: usize);
+pub type AbiVersionFn = unsafe extern "C" fn() -> u32;
+pub type InitFn = unsafe extern "C" fn(api: *const HostApi, registrar: *mut c_void) -> c_int;
+
+/// Functions the server exposes to plugins.
+#[repr(C)]
+pub struct HostApi {
+    pub abi_version: u32,
+    /// Registers a command. Fails with -1 if the name is taken or the info is invalid.
+    pub register_command: extern "C" fn(registrar: *mut c_void, info: *const CommandInfo, func: CommandFn) -> c_int,
+    /// Returns 1 and fills `out` when the key exists, 0 otherwise.
+    pub get: extern "C" fn(call: *mut c_void, key: Slice, out: *mut Slice) -> c_int,
+    /// Ignored, failing the call, unless the command is flagged `FLAG_WRITE`.
+    pub set: extern "C" fn(call: *mut c_void, key: Slice, value: Slice),
+    /// Returns the number of keys removed (0 or 1). Like `set`, only for
+    /// commands flagged `FLAG_WRITE`.
+    pub del: extern "C" fn(call: *mut c_void, key: Slice) -> c_int,
+    /// CR and LF are sent as spaces, here and in `reply_error`, so the reply
+    /// stays on one line.
+    pub reply_simple: extern "C" fn(call: *mut c_void, s: Slice),
+    /// Include the error code (e.g. "ERR ...").
+    pub reply_error: extern "C" fn(call: *mut c_void, s: Slice),
+    pub reply_integer: extern "C" fn(call: *mut c_void, n: i64),
+    pub reply_bulk: extern "C" fn(call: *mut c_void, s: Slice),
+    pub reply_null: extern "C" fn(call: *mut c_void),
+    /// Starts an array; the next `len` replies become its elements.
+    pub reply_array: extern "C" fn(call: *mut c_void, len: usize),
+    pub log: extern "C" fn(msg: Slice),
+}
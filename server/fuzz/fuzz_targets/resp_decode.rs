@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use server::resp::read_resp;
+use tokio::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    rt.block_on(async {
+        let mut reader = BufReader::new(data);
+        // Drain every frame in the input; anything decoded must re-encode to a frame that decodes identically
+        while let Ok(value) = read_resp(&mut reader).await {
+            let mut encoded = Vec::new();
+            value.encode(&mut encoded);
+            let mut again = BufReader::new(&encoded[..]);
+            assert_eq!(read_resp(&mut again).await.unwrap(), value);
+        }
+    });
+});
@@ -0,0 +1,415 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::commands::flags;
+
+/// A user as configured by `requirepass` and `user` directives.
+#[derive(Debug, PartialEq)]
+pub(crate) struct User {
+    pub name: String,
+    enabled: bool,
+    nopass: bool,
+    passwords: Vec<String>,
+    /// Key prefix the user is confined to; applied to every key it sends and
+    /// stripped from every key it receives.
+    pub namespace: Option<String>,
+    /// `+`/`-` command rules in order; the last one matching a command
+    /// decides, and a command no rule matches is allowed.
+    commands: Vec<CommandRule>,
+    pub quota: Quota,
+}
+
+/// Per-second limits on a user's commands and traffic, shared by all of its
+/// connections (0 = unlimited).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(crate) struct Quota {
+    pub ops: u64,
+    /// Bytes of commands received.
+    pub bytes_in: u64,
+    /// Bytes of replies sent.
+    pub bytes_out: u64,
+}
+
+impl Quota {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct CommandRule {
+    allow: bool,
+    target: RuleTarget,
+}
+
+#[derive(Debug, PartialEq)]
+enum RuleTarget {
+    All,
+    /// A category, held as the command flag it stands for.
+    Flag(&'static str),
+    Command(String),
+}
+
+/// ACL categories and the command flags that put a command in them.
+const CATEGORIES: &[(&str, &str)] = &[
+    ("read", flags::READONLY),
+    ("write", flags::WRITE),
+    ("admin", flags::ADMIN),
+    ("fast", flags::FAST),
+    ("pubsub", flags::PUBSUB),
+];
+
+/// The categories (`@read` and so on) a command with `cmd_flags` is in.
+pub(crate) fn categories(cmd_flags: &[&str]) -> Vec<String> {
+    CATEGORIES
+        .iter()
+        .filter(|(_, flag)| cmd_flags.contains(flag))
+        .map(|(name, _)| format!("@{}", name))
+        .collect()
+}
+
+fn parse_command_rule(rule: &str) -> Result<CommandRule, String> {
+    let (allow, name) = match rule.split_at(1) {
+        ("+", n) => (true, n),
+        ("-", n) => (false, n),
+        _ => unreachable!("command rules start with + or -"),
+    };
+    let target = match name.strip_prefix('@') {
+        Some(c) if c.eq_ignore_ascii_case("all") => RuleTarget::All,
+        Some(c) => match CATEGORIES.iter().find(|(n, _)| n.eq_ignore_ascii_case(c)) {
+            Some(&(_, flag)) => RuleTarget::Flag(flag),
+            None => return Err(format!("unknown ACL category '{}'", c)),
+        },
+        None if name.is_empty() => return Err(format!("ACL rule '{}' needs a command", rule)),
+        None => RuleTarget::Command(name.to_ascii_lowercase()),
+    };
+    Ok(CommandRule { allow, target })
+}
+
+impl User {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: Vec::new(),
+            namespace: None,
+            commands: Vec::new(),
+            quota: Quota::default(),
+        }
+    }
+
+    /// Whether the command rules let this user run `cmd`, whose flags are
+    /// `cmd_flags`.
+    pub fn can_run(&self, cmd: &str, cmd_flags: &[&str]) -> bool {
+        self.commands
+            .iter()
+            .rev()
+            .find(|r| match &r.target {
+                RuleTarget::All => true,
+                RuleTarget::Flag(f) => cmd_flags.contains(f),
+                RuleTarget::Command(c) => c == cmd,
+            })
+            .is_none_or(|r| r.allow)
+    }
+
+    fn accepts(&self, password: &str) -> bool {
+        self.enabled && (self.nopass || self.passwords.iter().any(|p| constant_time_eq(p.as_bytes(), password.as_bytes())))
+    }
+
+    /// Whether new connections start out logged in as this user.
+    pub fn is_open(&self) -> bool {
+        self.enabled && self.nopass
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Parses a `user` rule line, Redis ACL style:
+/// `<name> [on|off] [>password ...] [nopass] [resetpass] [namespace=<prefix>]
+/// [allcommands|nocommands] [+|-<command> ...] [+|-@<category> ...]
+/// [ops-per-sec=<n>] [bytes-in-per-sec=<n>] [bytes-out-per-sec=<n>]`, with the
+/// categories `all`, `read`, `write`, `admin` and `fast`.
+pub(crate) fn parse_user(line: &str) -> Result<User, String> {
+    let mut parts = line.split_whitespace();
+    let name = match parts.next() {
+        Some(n) => n,
+        None => return Err("user needs a name".to_string()),
+    };
+    let mut user = User::new(name);
+    for rule in parts {
+        if rule.eq_ignore_ascii_case("on") {
+            user.enabled = true;
+        } else if rule.eq_ignore_ascii_case("off") {
+            user.enabled = false;
+        } else if rule.eq_ignore_ascii_case("nopass") {
+            user.nopass = true;
+            user.passwords.clear();
+        } else if rule.eq_ignore_ascii_case("resetpass") {
+            user.nopass = false;
+            user.passwords.clear();
+        } else if let Some(pw) = rule.strip_prefix('>') {
+            user.nopass = false;
+            user.passwords.push(pw.to_string());
+        } else if rule.eq_ignore_ascii_case("allcommands") {
+            user.commands.push(parse_command_rule("+@all")?);
+        } else if rule.eq_ignore_ascii_case("nocommands") {
+            user.commands.push(parse_command_rule("-@all")?);
+        } else if rule.starts_with('+') || rule.starts_with('-') {
+            user.commands.push(parse_command_rule(rule)?);
+        } else if let Some(prefix) = rule.strip_prefix("namespace=") {
+            if prefix.is_empty() {
+                return Err("namespace= needs a prefix".to_string());
+            }
+            user.namespace = Some(prefix.to_string());
+        } else if let Some((limit, value)) = rule.split_once('=').filter(|(l, _)| QUOTAS.contains(l)) {
+            let value = value.parse().map_err(|_| format!("invalid {} '{}' for user '{}'", limit, value, name))?;
+            match limit {
+                "ops-per-sec" => user.quota.ops = value,
+                "bytes-in-per-sec" => user.quota.bytes_in = value,
+                _ => user.quota.bytes_out = value,
+            }
+        } else {
+            return Err(format!("unknown ACL rule '{}' for user '{}'", rule, name));
+        }
+    }
+    Ok(user)
+}
+
+#[derive(Debug)]
+pub(crate) struct Acl {
+    users: HashMap<String, Arc<User>>,
+}
+
+impl Acl {
+    /// The `default` user is open unless `requirepass` is set or it is
+    /// redefined by a `user default ...` line.
+    pub fn new(requirepass: Option<&str>, user_lines: &[String]) -> Result<Self, String> {
+        let mut default = User::new("default");
+        default.enabled = true;
+        match requirepass {
+            Some(pw) => default.passwords.push(pw.to_string()),
+            None => default.nopass = true,
+        }
+        let mut users = HashMap::new();
+        users.insert(default.name.clone(), Arc::new(default));
+        for line in user_lines {
+            let user = parse_user(line)?;
+            users.insert(user.name.clone(), Arc::new(user));
+        }
+        Ok(Self { users })
+    }
+
+    pub fn default_user(&self) -> Option<Arc<User>> {
+        self.users.get("default").cloned()
+    }
+
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<Arc<User>> {
+        self.users.get(name).filter(|u| u.accepts(password)).cloned()
+    }
+}
+
+const QUOTAS: &[&str] = &["ops-per-sec", "bytes-in-per-sec", "bytes-out-per-sec"];
+
+/// How much of its quota each user has used lately, kept across config
+/// reloads. Each limit is a bucket holding up to one second's allowance.
+/// Commands draw on the ops and bytes-in buckets as they arrive and replies
+/// on the bytes-out bucket as they are sent; a user is throttled while any
+/// bucket is empty, so one large request or reply is served and then paid
+/// off before the next.
+#[derive(Default)]
+pub(crate) struct QuotaMeters {
+    users: Mutex<HashMap<String, Meter>>,
+}
+
+#[derive(Default)]
+struct Meter {
+    ops: Bucket,
+    bytes_in: Bucket,
+    bytes_out: Bucket,
+}
+
+#[derive(Default)]
+struct Bucket {
+    /// What is left to spend; negative while a large charge is paid off.
+    level: f64,
+    /// When `level` was last topped up; None for a bucket not used yet,
+    /// which starts full.
+    at: Option<Instant>,
+}
+
+impl Bucket {
+    fn refill(&mut self, rate: u64, now: Instant) {
+        let rate = rate as f64;
+        self.level = match self.at {
+            Some(at) => (self.level + rate * now.duration_since(at).as_secs_f64()).min(rate),
+            None => rate,
+        };
+        self.at = Some(now);
+    }
+}
+
+impl QuotaMeters {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Meter>> {
+        self.users.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Charges `user` for a command of `bytes` bytes, or returns the quota
+    /// it is over, in which case nothing is charged.
+    pub fn charge_command(&self, user: &User, bytes: usize) -> Result<(), &'static str> {
+        let quota = user.quota;
+        let now = Instant::now();
+        let mut users = self.lock();
+        let Meter { ops, bytes_in, bytes_out } = users.entry(user.name.clone()).or_default();
+        for (bucket, rate) in [(&mut *ops, quota.ops), (&mut *bytes_in, quota.bytes_in), (&mut *bytes_out, quota.bytes_out)] {
+            if rate > 0 {
+                bucket.refill(rate, now);
+            }
+        }
+        if quota.ops > 0 && ops.level < 1.0 {
+            return Err("ops-per-sec");
+        }
+        if quota.bytes_in > 0 && bytes_in.level < 0.0 {
+            return Err("bytes-in-per-sec");
+        }
+        if quota.bytes_out > 0 && bytes_out.level < 0.0 {
+            return Err("bytes-out-per-sec");
+        }
+        if quota.ops > 0 {
+            ops.level -= 1.0;
+        }
+        if quota.bytes_in > 0 {
+            bytes_in.level -= bytes as f64;
+        }
+        Ok(())
+    }
+
+    /// Charges `user` for a reply of `bytes` bytes.
+    pub fn charge_reply(&self, user: &User, bytes: usize) {
+        if user.quota.bytes_out == 0 {
+            return;
+        }
+        let mut users = self.lock();
+        let bucket = &mut users.entry(user.name.clone()).or_default().bytes_out;
+        bucket.refill(user.quota.bytes_out, Instant::now());
+        bucket.level -= bytes as f64;
+    }
+}
+
+/// Why an ACL LOG entry was written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Denial {
+    /// A failed AUTH.
+    Auth,
+    /// A command the user may not run.
+    Command,
+    /// A command refused because the user was over a quota, the object.
+    Quota,
+}
+
+impl Denial {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Auth => "auth",
+            Self::Command => "command",
+            Self::Quota => "quota",
+        }
+    }
+}
+
+// Repeats of an entry within this window bump its count instead of adding one
+const ACL_LOG_GROUPING_MS: u64 = 60_000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct AclLogEntry {
+    pub id: u64,
+    pub count: u64,
+    pub reason: Denial,
+    /// The command denied, or `AUTH`.
+    pub object: String,
+    pub username: String,
+    pub client_info: String,
+    pub created_ms: u64,
+    pub updated_ms: u64,
+}
+
+impl AclLogEntry {
+    pub fn reason(&self) -> &'static str {
+        self.reason.name()
+    }
+}
+
+/// Recent denied commands and failed AUTH attempts, newest first, for ACL
+/// LOG. Bounded by `acllog-max-len`.
+pub(crate) struct AclLog {
+    entries: Mutex<VecDeque<AclLogEntry>>,
+    next_id: AtomicU64,
+    max_len: AtomicUsize,
+}
+
+impl AclLog {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            max_len: AtomicUsize::new(max_len),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<AclLogEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.max_len.store(max_len, Ordering::Relaxed);
+        self.lock().truncate(max_len);
+    }
+
+    pub fn record(&self, reason: Denial, object: &str, username: &str, client_info: String) {
+        let now = unix_ms_now();
+        let mut entries = self.lock();
+        let repeat = entries.iter_mut().find(|e| {
+            e.reason == reason
+                && e.object == object
+                && e.username == username
+                && now.saturating_sub(e.updated_ms) < ACL_LOG_GROUPING_MS
+        });
+        if let Some(entry) = repeat {
+            entry.count += 1;
+            entry.updated_ms = now;
+            entry.client_info = client_info;
+            return;
+        }
+        entries.push_front(AclLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            count: 1,
+            reason,
+            object: object.to_string(),
+            username: username.to_string(),
+            client_info,
+            created_ms: now,
+            updated_ms: now,
+        });
+        entries.truncate(self.max_len.load(Ordering::Relaxed));
+    }
+
+    /// Up to `count` entries, newest first.
+    pub fn recent(&self, count: usize) -> Vec<AclLogEntry> {
+        self.lock().iter().take(count).cloned().collect()
+    }
+
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+}
+
+fn unix_ms_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
@@ -0,0 +1,168 @@
+//! The memory allocator, picked at build time, and what it reports.
+//!
+//! Built with the `jemalloc` or `mimalloc` feature, the server allocates
+//! through that allocator and reads its statistics; otherwise it runs on the
+//! system allocator, which keeps none, and the figures fall back to the
+//! resident set size the kernel reports. With `maxmemory-accounting process`
+//! the gap between what the allocator holds and what the datasets are
+//! estimated to take (connection buffers, queues, per-key overhead the
+//! estimate misses) is charged against `maxmemory` too, so the budget bounds
+//! the process rather than just the keys and values.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::db::Database;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("the jemalloc and mimalloc features pick the allocator; enable at most one");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// The allocator this build uses, as INFO memory names it.
+#[cfg(feature = "jemalloc")]
+pub(crate) const NAME: &str = "jemalloc";
+#[cfg(feature = "mimalloc")]
+pub(crate) const NAME: &str = "mimalloc";
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub(crate) const NAME: &str = "libc";
+
+// How often the overhead charged under process accounting is measured
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+// Linux's /proc counts in pages; 4 KiB on the platforms this is built for
+const PAGE_SIZE: usize = 4096;
+
+/// What counts against `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAccounting {
+    /// The estimated size of the keys and values stored.
+    Dataset,
+    /// Everything the allocator has handed out.
+    Process,
+}
+
+impl MemoryAccounting {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "dataset" => Some(Self::Dataset),
+            "process" => Some(Self::Process),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Dataset => "dataset",
+            Self::Process => "process",
+        }
+    }
+}
+
+/// Bytes as the allocator counts them.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct AllocatorStats {
+    /// Handed out to the server and not yet freed.
+    pub allocated: usize,
+    /// In pages holding at least one allocation.
+    pub active: usize,
+    /// Mapped in physical memory.
+    pub resident: usize,
+}
+
+impl AllocatorStats {
+    /// Active over allocated: how much of the allocator's pages are lost to
+    /// fragmentation.
+    pub fn fragmentation(&self) -> f64 {
+        if self.allocated == 0 {
+            1.0
+        } else {
+            self.active as f64 / self.allocated as f64
+        }
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub(crate) fn stats() -> AllocatorStats {
+    use tikv_jemalloc_ctl::{epoch, stats};
+    // jemalloc caches its statistics until the epoch moves on
+    let _ = epoch::advance();
+    AllocatorStats {
+        allocated: stats::allocated::read().unwrap_or(0),
+        active: stats::active::read().unwrap_or(0),
+        resident: stats::resident::read().unwrap_or(0),
+    }
+}
+
+#[cfg(feature = "mimalloc")]
+pub(crate) fn stats() -> AllocatorStats {
+    let (mut elapsed, mut user, mut system, mut faults) = (0, 0, 0, 0);
+    let (mut rss, mut peak_rss, mut commit, mut peak_commit) = (0, 0, 0, 0);
+    // SAFETY: every pointer is to a local that outlives the call
+    unsafe {
+        libmimalloc_sys::mi_process_info(
+            &mut elapsed,
+            &mut user,
+            &mut system,
+            &mut rss,
+            &mut peak_rss,
+            &mut commit,
+            &mut peak_commit,
+            &mut faults,
+        );
+    }
+    // mimalloc keeps no cheap count of live bytes; committed memory is the
+    // closest it has
+    AllocatorStats {
+        allocated: commit,
+        active: commit,
+        resident: rss,
+    }
+}
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub(crate) fn stats() -> AllocatorStats {
+    let rss = resident_set_size();
+    AllocatorStats {
+        allocated: rss,
+        active: rss,
+        resident: rss,
+    }
+}
+
+/// The process's resident set size, from /proc; 0 where there is none.
+pub(crate) fn resident_set_size() -> usize {
+    std::fs::read_to_string("/proc/self/statm")
+        .ok()
+        .and_then(|statm| statm.split_whitespace().nth(1)?.parse::<usize>().ok())
+        .map(|pages| pages * PAGE_SIZE)
+        .unwrap_or(0)
+}
+
+/// Measures how far the allocator's count is above the datasets' estimated
+/// size, for `maxmemory-accounting process`.
+pub(crate) fn sample_overhead(dbs: &[Database]) {
+    let dataset: usize = dbs.iter().map(Database::used_memory).sum();
+    let allocated = stats().allocated;
+    let shared = &dbs[0].stats;
+    shared.memory_overhead.store(allocated.saturating_sub(dataset), Ordering::Relaxed);
+    shared.sampled_dataset.store(dataset, Ordering::Relaxed);
+}
+
+/// Re-measures the overhead every second until aborted.
+pub(crate) fn start_sampler(dbs: Arc<[Database]>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(SAMPLE_INTERVAL).await;
+            sample_overhead(&dbs);
+        }
+    })
+}
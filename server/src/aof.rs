@@ -0,0 +1,632 @@
+//! Append-only file.
+//!
+//! Every successful write command is appended as RESP in the order it ran,
+//! so replaying the file rebuilds the data. Lines starting with `#` are
+//! annotations; `#TS:<unix seconds>` precedes the first command of each new
+//! second, which is what lets a replay stop at a point in time:
+//!
+//! ```text
+//! #TS:1760000000
+//! *2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n
+//! *3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n
+//! ```
+//!
+//! Relative expiries are logged as absolute ones (`PEXPIREAT`, `SET ...
+//! PXAT`), so a replay keeps the original deadlines.
+//!
+//! A compressed AOF holds one zstd frame per flush, so everything up to the
+//! last complete frame survives a crash. Offsets always count bytes of the
+//! uncompressed commands.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::client::ClientState;
+use crate::clock::Clock;
+use crate::commands::{flags, CommandSpec, Context, Middleware, Registry};
+use crate::compression::{compress_frame, is_compressed, open_decoded_frames, Compression};
+use crate::config::Settings;
+use crate::db::{Database, SnapshotRecord};
+use crate::resp::RespValue;
+use crate::snapshot::{begin_all, shared_clock};
+use crate::stats::Stats;
+
+/// When appended commands are forced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every write command, before its reply.
+    Always,
+    /// Once a second; a crash loses at most the last second of writes.
+    EverySec,
+    /// Never; the OS flushes when it likes.
+    No,
+}
+
+impl AppendFsync {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "always" => Some(Self::Always),
+            "everysec" => Some(Self::EverySec),
+            "no" => Some(Self::No),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Always => "always",
+            Self::EverySec => "everysec",
+            Self::No => "no",
+        }
+    }
+}
+
+/// Where a point-in-time replay stops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AofLimit {
+    /// Keep commands logged at or before this unix time in seconds.
+    Timestamp(u64),
+    /// Keep the commands that end within this many bytes of the file.
+    Offset(u64),
+}
+
+pub enum AofEntry {
+    /// A `#TS:` annotation: the commands after it ran at this unix time.
+    Timestamp(u64),
+    /// A command name and its arguments.
+    Command(Vec<Vec<u8>>),
+}
+
+/// Reads an append-only file entry by entry, keeping track of the offset.
+pub struct AofReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: BufRead> AofReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, offset: 0 }
+    }
+
+    /// Offset of the next entry: everything before it was read completely.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next entry, or None at the end of the file. A file ending partway
+    /// through an entry fails with `UnexpectedEof`; anything else that is not
+    /// an entry fails with `InvalidData`.
+    pub fn next_entry(&mut self) -> io::Result<Option<AofEntry>> {
+        loop {
+            let mut read = 0u64;
+            let line = match self.line(&mut read)? {
+                Some(line) => line,
+                None => return Ok(None),
+            };
+            let entry = match line.first() {
+                // Other annotations carry nothing a replay needs
+                Some(b'#') => line.strip_prefix(b"#TS:").and_then(parse_number).map(AofEntry::Timestamp),
+                Some(b'*') => {
+                    let count = parse_number(&line[1..]).ok_or_else(|| self.invalid("bad command header"))?;
+                    let mut args = Vec::with_capacity(count.min(1024) as usize);
+                    for _ in 0..count {
+                        let header = self.line(&mut read)?.ok_or_else(truncated)?;
+                        let len = header
+                            .strip_prefix(b"$")
+                            .and_then(parse_number)
+                            .ok_or_else(|| self.invalid("bad argument header"))?;
+                        let mut arg = vec![0u8; len as usize + 2];
+                        self.inner.read_exact(&mut arg)?;
+                        read += arg.len() as u64;
+                        if !arg.ends_with(b"\r\n") {
+                            return Err(self.invalid("argument not terminated by CRLF"));
+                        }
+                        arg.truncate(len as usize);
+                        args.push(arg);
+                    }
+                    if args.is_empty() {
+                        return Err(self.invalid("empty command"));
+                    }
+                    Some(AofEntry::Command(args))
+                }
+                _ => return Err(self.invalid("expected a command or an annotation")),
+            };
+            self.offset += read;
+            if let Some(entry) = entry {
+                return Ok(Some(entry));
+            }
+        }
+    }
+
+    // One CRLF-terminated line without its terminator, or None at the end
+    fn line(&mut self, read: &mut u64) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let n = self.inner.read_until(b'\n', &mut line)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        *read += n as u64;
+        if !line.ends_with(b"\r\n") {
+            return Err(truncated());
+        }
+        line.truncate(line.len() - 2);
+        Ok(Some(line))
+    }
+
+    fn invalid(&self, what: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} in entry at offset {}", what, self.offset))
+    }
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "file ends partway through an entry")
+}
+
+fn parse_number(digits: &[u8]) -> Option<u64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// What [`replay`] got through.
+#[derive(Debug, Default)]
+pub struct Replay {
+    pub commands: usize,
+    /// Length of the part of the file that was replayed: up to the end of the
+    /// last command applied, or the whole file if nothing was left out.
+    pub end: u64,
+    /// The replay stopped at its limit with more of the file left.
+    pub stopped_at_limit: bool,
+    /// The file ends partway through an entry, as after a crash mid-write.
+    pub truncated_tail: bool,
+}
+
+/// Passes each command in the file at `path` to `apply`, stopping at `limit`
+/// or before an incomplete final entry. Corruption anywhere else is an error.
+pub fn replay(path: &Path, limit: Option<AofLimit>, mut apply: impl FnMut(&[Vec<u8>])) -> io::Result<Replay> {
+    let mut reader = AofReader::new(open_decoded_frames(path)?);
+    let mut replay = Replay::default();
+    loop {
+        match reader.next_entry() {
+            Ok(None) => {
+                replay.end = reader.offset();
+                return Ok(replay);
+            }
+            Ok(Some(AofEntry::Timestamp(ts))) => {
+                if matches!(limit, Some(AofLimit::Timestamp(t)) if ts > t) {
+                    replay.stopped_at_limit = true;
+                    return Ok(replay);
+                }
+            }
+            Ok(Some(AofEntry::Command(args))) => {
+                if matches!(limit, Some(AofLimit::Offset(o)) if reader.offset() > o) {
+                    replay.stopped_at_limit = true;
+                    return Ok(replay);
+                }
+                apply(&args);
+                replay.commands += 1;
+                replay.end = reader.offset();
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                replay.truncated_tail = true;
+                return Ok(replay);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Rebuilds `dbs` from the AOF at `path`. A replay that stopped at `limit`,
+/// or before an incomplete final command, cuts the file there so new writes
+/// follow the replayed ones; for a point-in-time recovery a copy of the
+/// whole file is kept first.
+pub(crate) fn load_aof(
+    dbs: &[Database],
+    settings: &Settings,
+    registry: &Registry,
+    path: &Path,
+    limit: Option<AofLimit>,
+) -> io::Result<Replay> {
+    let (replay, failed) = replay_into(dbs, settings, registry, path, limit)?;
+    if replay.stopped_at_limit {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut kept = path.as_os_str().to_owned();
+        kept.push(format!(".before-pitr-{}", secs));
+        std::fs::copy(path, &kept)?;
+        println!(
+            "Point-in-time recovery: stopped {} at offset {}; the whole file was kept as {}",
+            path.display(),
+            replay.end,
+            Path::new(&kept).display()
+        );
+    } else if replay.truncated_tail {
+        eprintln!(
+            "{} ends with an incomplete command, as after a crash; cutting it at offset {}",
+            path.display(),
+            replay.end
+        );
+    }
+    if replay.stopped_at_limit || replay.truncated_tail {
+        truncate(path, replay.end)?;
+    }
+    println!("Replayed {} commands from {} ({} failed)", replay.commands, path.display(), failed);
+    Ok(replay)
+}
+
+/// Runs the AOF at `path` against `dbs` without touching the file. Returns
+/// the replay and how many of its commands failed.
+pub(crate) fn replay_into(
+    dbs: &[Database],
+    settings: &Settings,
+    registry: &Registry,
+    path: &Path,
+    limit: Option<AofLimit>,
+) -> io::Result<(Replay, usize)> {
+    let mut client = ClientState::internal(0);
+    let mut failed = 0usize;
+    let replay = replay(path, limit, |args| {
+        let mut frame: Vec<RespValue> = args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect();
+        let mut ctx = Context {
+            db: &dbs[client.db],
+            dbs,
+            settings,
+            registry,
+            client: &mut client,
+            block: None,
+            backing: None,
+            deadline: None,
+            proxy: None,
+            io: None,
+            keep_args: false,
+            slowlog_args: None,
+        };
+        if let RespValue::Error(e) = registry.call(&mut ctx, &mut frame) {
+            failed += 1;
+            eprintln!("AOF command {} not applied: {}", String::from_utf8_lossy(&args[0]), e);
+        }
+    })?;
+    Ok((replay, failed))
+}
+
+/// Cuts the file at `path` to its first `end` bytes of commands. A
+/// compressed file is rewritten, since its offsets are not file offsets.
+pub fn truncate(path: &Path, end: u64) -> io::Result<()> {
+    if !is_compressed(path)? {
+        return OpenOptions::new().write(true).open(path)?.set_len(end);
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let mut input = open_decoded_frames(path)?.take(end);
+    let mut out = BufWriter::new(File::create(&tmp)?);
+    let mut chunk = Vec::with_capacity(REWRITE_FRAME);
+    loop {
+        chunk.clear();
+        if (&mut input).take(REWRITE_FRAME as u64).read_to_end(&mut chunk)? == 0 {
+            break;
+        }
+        out.write_all(&compress_frame(&chunk, zstd::DEFAULT_COMPRESSION_LEVEL)?)?;
+    }
+    if input.limit() > 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than the cut"));
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+// Frames are read whole, so a rewrite keeps them a modest size
+const REWRITE_FRAME: usize = 1 << 20;
+
+/// The open append-only file.
+pub(crate) struct Aof {
+    path: PathBuf,
+    fsync: AppendFsync,
+    writer: Mutex<AofWriter>,
+    /// The same file, so an fsync does not hold up commands being logged.
+    sync: File,
+}
+
+struct AofWriter {
+    out: BufWriter<File>,
+    /// With compression, commands wait here until the next flush writes them
+    /// as one frame at this level.
+    compress: Option<i32>,
+    pending: Vec<u8>,
+    /// The database the last logged command ran in.
+    db: Option<usize>,
+    /// The unix second of the last `#TS:` annotation.
+    second: u64,
+    buf: Vec<u8>,
+}
+
+impl Aof {
+    /// Opens `path` for appending, creating it if needed. A file that already
+    /// has commands keeps the compression it was started with.
+    pub fn open(path: &Path, fsync: AppendFsync, compression: Compression) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let compress = if file.metadata()?.len() == 0 {
+            match compression {
+                Compression::Off => None,
+                Compression::Zstd(level) => Some(level),
+            }
+        } else if is_compressed(path)? {
+            Some(zstd::DEFAULT_COMPRESSION_LEVEL)
+        } else {
+            None
+        };
+        let sync = file.try_clone()?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            fsync,
+            sync,
+            writer: Mutex::new(AofWriter {
+                out: BufWriter::new(file),
+                compress,
+                pending: Vec::new(),
+                db: None,
+                second: 0,
+                buf: Vec::new(),
+            }),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, AofWriter> {
+        self.writer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn fsync(&self) -> AppendFsync {
+        self.fsync
+    }
+
+    /// Logs a command that ran in database `db`.
+    pub fn append(&self, db: usize, args: Vec<Vec<u8>>) -> io::Result<()> {
+        let mut writer = self.lock();
+        writer.log(db, args)?;
+        if self.fsync == AppendFsync::Always {
+            writer.write_pending()?;
+            writer.out.flush()?;
+            drop(writer);
+            self.sync.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Writes buffered commands to the file and, with `sync`, to disk.
+    pub fn flush(&self, sync: bool) -> io::Result<()> {
+        let mut writer = self.lock();
+        writer.write_pending()?;
+        writer.out.flush()?;
+        drop(writer);
+        if sync {
+            self.sync.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Logs the current contents of `dbs` as SET commands, so a file started
+    /// next to data loaded from elsewhere replays to the same state.
+    pub fn write_base(&self, dbs: &[Database]) -> io::Result<usize> {
+        let snapshots = begin_all(dbs)?;
+        let clock = shared_clock(dbs).reading();
+        let mut total = 0;
+        for (index, snapshot) in snapshots.into_iter().enumerate() {
+            total += snapshot.read(|record: SnapshotRecord| {
+                let mut args = vec![b"SET".to_vec(), record.key.into_bytes(), record.value];
+                if let Some(at) = record.expires_at {
+                    let ms = clock.unix_ms_of(at);
+                    args.extend([b"PXAT".to_vec(), ms.to_string().into_bytes()]);
+                }
+                self.lock().log(index, args)
+            })?;
+        }
+        self.flush(true)?;
+        Ok(total)
+    }
+}
+
+impl AofWriter {
+    fn log(&mut self, db: usize, args: Vec<Vec<u8>>) -> io::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        if now != self.second {
+            buf.extend_from_slice(format!("#TS:{}\r\n", now).as_bytes());
+            self.second = now;
+        }
+        if self.db != Some(db) {
+            encode(&[b"SELECT".to_vec(), db.to_string().into_bytes()], &mut buf);
+            self.db = Some(db);
+        }
+        encode(&args, &mut buf);
+        let res = match self.compress {
+            Some(_) => {
+                self.pending.extend_from_slice(&buf);
+                Ok(())
+            }
+            None => self.out.write_all(&buf),
+        };
+        self.buf = buf;
+        res
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        if let (Some(level), false) = (self.compress, self.pending.is_empty()) {
+            let frame = compress_frame(&self.pending, level)?;
+            self.out.write_all(&frame)?;
+            self.pending.clear();
+        }
+        Ok(())
+    }
+}
+
+fn encode(args: &[Vec<u8>], out: &mut Vec<u8>) {
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Flushes `aof` as its `appendfsync` policy asks, once a second.
+pub(crate) fn start_aof_flusher(aof: Arc<Aof>, stats: Arc<Stats>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            let file = aof.clone();
+            let flushed = stats
+                .persistence_io
+                .run(move || file.flush(file.fsync() == AppendFsync::EverySec))
+                .await
+                .and_then(|flushed| flushed);
+            if let Err(e) = &flushed {
+                eprintln!("Writing {} failed: {}", aof.path().display(), e);
+            }
+            stats.aof_written(flushed.is_ok());
+        }
+    })
+}
+
+/// Appends every successful write command to the AOF.
+pub(crate) struct AppendOnly(pub Arc<Aof>);
+
+impl Middleware for AppendOnly {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, _spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if let Some(args) = namespaced_flush(ctx, cmd) {
+            self.log(ctx, args);
+        }
+        None
+    }
+
+    fn reads_args(&self, _ctx: &Context<'_>) -> bool {
+        true
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        if let Some(args) = logged_write(ctx, cmd, spec, args, reply) {
+            self.log(ctx, args);
+        }
+    }
+}
+
+/// A namespaced FLUSHDB only empties the user's namespace, which replaying a
+/// plain FLUSHDB would not; before it runs, this is the DEL of those keys it
+/// is logged as instead.
+pub(crate) fn namespaced_flush(ctx: &Context<'_>, cmd: &str) -> Option<Vec<Vec<u8>>> {
+    let (Some(ns), "flushdb") = (ctx.client.namespace(), cmd) else {
+        return None;
+    };
+    let keys = ctx.db.keys_with_prefix(ns);
+    if keys.is_empty() {
+        return None;
+    }
+    let mut args = vec![b"DEL".to_vec()];
+    args.extend(keys.into_iter().map(String::into_bytes));
+    Some(args)
+}
+
+/// The command to log for a write that just ran, or None if it changed
+/// nothing (or was logged by [`namespaced_flush`]).
+pub(crate) fn logged_write(
+    ctx: &Context<'_>,
+    cmd: &str,
+    spec: &CommandSpec,
+    args: &[RespValue],
+    reply: &RespValue,
+) -> Option<Vec<Vec<u8>>> {
+    // A nil reply is a conditional write that did not happen
+    if !spec.has(flags::WRITE) || matches!(reply, RespValue::Error(_) | RespValue::BulkString(None)) {
+        return None;
+    }
+    if cmd == "flushdb" && ctx.client.namespace().is_some() {
+        return None;
+    }
+    Some(logged_form(ctx.db.clock(), cmd, args))
+}
+
+impl AppendOnly {
+    fn log(&self, ctx: &Context<'_>, args: Vec<Vec<u8>>) {
+        let appended = self.0.append(ctx.client.db, args);
+        if let Err(e) = &appended {
+            eprintln!("Appending to {} failed: {}", self.0.path().display(), e);
+        }
+        ctx.db.stats.aof_written(appended.is_ok());
+    }
+}
+
+// The command as logged: relative expiries become absolute
+fn logged_form(clock: &Clock, cmd: &str, args: &[RespValue]) -> Vec<Vec<u8>> {
+    let mut logged = absolute_form(clock, cmd, args);
+    if cmd == "set" {
+        // A SET that got here was applied, so its IFEQ or IFVERSION condition
+        // held; the replay writes unconditionally
+        let options = logged.split_off(3);
+        for pair in options.chunks(2) {
+            let [opt, arg] = pair else { continue };
+            if !opt.eq_ignore_ascii_case(b"IFEQ") && !opt.eq_ignore_ascii_case(b"IFVERSION") {
+                logged.extend([opt.clone(), arg.clone()]);
+            }
+        }
+    }
+    logged
+}
+
+/// The command with its relative expiries (EXPIRE, PEXPIRE, SET EX and PX)
+/// made absolute by `clock`, so it sets the same deadline whenever it runs.
+/// Everything else, SET's conditions included, is kept as it is.
+pub(crate) fn absolute_form(clock: &Clock, cmd: &str, args: &[RespValue]) -> Vec<Vec<u8>> {
+    let bytes = |v: &RespValue| match v {
+        RespValue::BulkString(Some(b)) => b.clone(),
+        RespValue::SimpleString(s) => s.clone().into_bytes(),
+        RespValue::Integer(n) => n.to_string().into_bytes(),
+        _ => Vec::new(),
+    };
+    let number = |v: &RespValue| std::str::from_utf8(&bytes(v)).ok().and_then(|s| s.parse::<i64>().ok());
+    let now = clock.unix_ms();
+    let deadline = match (cmd, args) {
+        ("expire", [_, n]) => number(n).map(|n| now.saturating_add(n.saturating_mul(1000))),
+        ("pexpire", [_, n]) => number(n).map(|n| now.saturating_add(n)),
+        ("expireat", [_, n]) => number(n).map(|n| n.saturating_mul(1000)),
+        _ => None,
+    };
+    if let Some(ms) = deadline {
+        return vec![b"PEXPIREAT".to_vec(), bytes(&args[0]), ms.to_string().into_bytes()];
+    }
+    let mut logged = vec![cmd.to_ascii_uppercase().into_bytes()];
+    logged.extend(args.iter().map(bytes));
+    if cmd == "set" {
+        let options = logged.split_off(3);
+        for pair in options.chunks(2) {
+            let [opt, arg] = pair else { continue };
+            let n = std::str::from_utf8(arg).ok().and_then(|s| s.parse::<i64>().ok()).unwrap_or(0);
+            let ms = match String::from_utf8_lossy(opt).to_ascii_uppercase().as_str() {
+                "EX" => now.saturating_add(n.saturating_mul(1000)),
+                "PX" => now.saturating_add(n),
+                "EXAT" => n.saturating_mul(1000),
+                "PXAT" => n,
+                // An idle limit counts from the last access, not from the
+                // write, and a condition is no time at all
+                _ => {
+                    logged.extend([opt.clone(), arg.clone()]);
+                    continue;
+                }
+            };
+            logged.push(b"PXAT".to_vec());
+            logged.push(ms.to_string().into_bytes());
+        }
+    }
+    logged
+}
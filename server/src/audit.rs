@@ -0,0 +1,147 @@
+//! The key access audit log.
+//!
+//! With `audit-sample` set, one in that many commands naming keys is
+//! recorded: when, which client and user, which database, the command, the
+//! keys as stored (namespace prefix included) and whether it read or wrote
+//! them. The latest `audit-max-len` records are kept for AUDIT GET, and with
+//! `audit-file` every record is also appended there as a line of JSON, for
+//! investigating who could have seen what on a shared server.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::snapshot::unix_ms_now;
+use crate::websocket::quote;
+
+#[derive(Debug, Clone)]
+pub(crate) struct AuditEntry {
+    pub id: u64,
+    pub at_ms: i64,
+    pub client_id: u64,
+    /// Empty for a connection that has not authenticated.
+    pub username: String,
+    pub client_info: String,
+    pub db: usize,
+    pub command: String,
+    pub keys: Vec<String>,
+    pub write: bool,
+    /// Whether the command failed.
+    pub error: bool,
+}
+
+impl AuditEntry {
+    pub fn access(&self) -> &'static str {
+        if self.write {
+            "write"
+        } else {
+            "read"
+        }
+    }
+
+    pub fn result(&self) -> &'static str {
+        if self.error {
+            "error"
+        } else {
+            "ok"
+        }
+    }
+
+    fn to_json(&self) -> String {
+        let mut out = format!("{{\"id\":{},\"time_ms\":{},\"client_id\":{},\"user\":", self.id, self.at_ms, self.client_id);
+        quote(&self.username, &mut out);
+        out.push_str(",\"client\":");
+        quote(&self.client_info, &mut out);
+        out.push_str(&format!(",\"db\":{},\"command\":", self.db));
+        quote(&self.command, &mut out);
+        out.push_str(",\"keys\":[");
+        for (i, key) in self.keys.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            quote(key, &mut out);
+        }
+        out.push_str(&format!("],\"access\":\"{}\",\"result\":\"{}\"}}\n", self.access(), self.result()));
+        out
+    }
+}
+
+/// Sampled key accesses, newest first, bounded by `audit-max-len`, and the
+/// file they are also written to.
+pub(crate) struct AuditLog {
+    entries: Mutex<VecDeque<AuditEntry>>,
+    next_id: AtomicU64,
+    max_len: AtomicUsize,
+    /// Commands with keys seen, for picking the sampled ones.
+    seen: AtomicU64,
+    file: Option<Mutex<LineWriter<File>>>,
+}
+
+impl AuditLog {
+    pub fn new(max_len: usize, file: Option<&Path>) -> io::Result<Self> {
+        let file = match file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+                Some(Mutex::new(LineWriter::new(file)))
+            }
+            None => None,
+        };
+        Ok(Self {
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            max_len: AtomicUsize::new(max_len),
+            seen: AtomicU64::new(0),
+            file,
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<AuditEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.max_len.store(max_len, Ordering::Relaxed);
+        self.lock().truncate(max_len);
+    }
+
+    /// Whether the next command with keys is one of the one in `rate`
+    /// recorded.
+    pub fn sampled(&self, rate: u32) -> bool {
+        rate > 0 && self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate as u64)
+    }
+
+    /// Records an access; `entry`'s id and time are filled in here.
+    pub fn record(&self, mut entry: AuditEntry) {
+        entry.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        entry.at_ms = unix_ms_now();
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = file.write_all(entry.to_json().as_bytes()) {
+                eprintln!("Writing the audit log failed: {}", e);
+            }
+        }
+        let mut entries = self.lock();
+        entries.push_front(entry);
+        entries.truncate(self.max_len.load(Ordering::Relaxed));
+    }
+
+    /// Up to `count` entries, newest first.
+    pub fn recent(&self, count: usize) -> Vec<AuditEntry> {
+        self.lock().iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+}
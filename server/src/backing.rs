@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write as _};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::OnceCell;
+
+use crate::db::Database;
+use crate::glob::glob_match;
+use crate::resp::RespValue;
+use crate::stats::Stats;
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A slower store RustCache fronts in tiered mode: misses are loaded from it
+/// and writes are forwarded to it before the client gets its reply.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BackingStore {
+    /// `GET`, `PUT` and `DELETE` on `<url>/<key>`; 404 means the key is missing.
+    Http(String),
+    /// Runs `<path> get|set|del <key>`. `get` prints the value and exits 0, or
+    /// exits 1 if the key is missing; `set` reads the value from stdin.
+    Exec(PathBuf),
+    /// Calls a registered (typically plugin) command as `<name> GET|SET|DEL key
+    /// [value]`; a nil reply to GET means the key is missing.
+    Command(String),
+}
+
+impl BackingStore {
+    /// Parses the `backing-store` directive: `http <url>`, `exec <path>` or
+    /// `command <name>`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        let (kind, target) = match value.split_once(char::is_whitespace) {
+            Some((k, t)) => (k, t.trim()),
+            None => return Err("backing-store needs a kind and a target".to_string()),
+        };
+        match kind.to_ascii_lowercase().as_str() {
+            "http" => {
+                HttpTarget::parse(target)?;
+                Ok(Self::Http(target.to_string()))
+            }
+            "exec" => Ok(Self::Exec(PathBuf::from(target))),
+            "command" => Ok(Self::Command(target.to_ascii_lowercase())),
+            other => Err(format!("unknown backing-store kind '{}'", other)),
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Self::Http(url) => format!("http {}", url),
+            Self::Exec(path) => format!("exec {}", path.display()),
+            Self::Command(name) => format!("command {}", name),
+        }
+    }
+}
+
+/// A read-only source for the keys matching `pattern`, from a `loader`
+/// directive: a GET that misses one of them loads it from `store` instead of
+/// replying nil. Nothing is ever written back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loader {
+    pub pattern: String,
+    pub store: BackingStore,
+}
+
+impl Loader {
+    /// Parses `<pattern> <kind> <target>`, the kind and target being those of
+    /// `backing-store`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        let (pattern, store) = value
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| "loader needs a key pattern, a kind and a target".to_string())?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            store: BackingStore::parse(store.trim())?,
+        })
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        format!("{} {}", self.pattern, self.store.describe())
+    }
+}
+
+/// A write a command made that has to reach the backing store.
+#[derive(Debug, Clone)]
+pub(crate) enum BackingWrite {
+    Set(String, Vec<u8>),
+    Delete(String),
+}
+
+impl BackingWrite {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Set(k, _) | Self::Delete(k) => k,
+        }
+    }
+}
+
+/// What a command did that the backing store needs to hear about, collected
+/// while it runs and acted on by the connection afterwards.
+#[derive(Debug, Default)]
+pub(crate) struct BackingOps {
+    pub misses: Vec<String>,
+    pub writes: Vec<BackingWrite>,
+}
+
+/// Runs a command in-process for [`BackingStore::Command`].
+pub(crate) type Invoke<'a> = dyn Fn(Vec<RespValue>) -> RespValue + Sync + 'a;
+
+type LoadResult = Result<Option<Vec<u8>>, String>;
+
+/// The backing store and loaders of a running server plus the loads in
+/// flight, so a burst of misses on one key costs a single backend round trip.
+pub(crate) struct Tiered {
+    store: Option<Arc<BackingStore>>,
+    loaders: Vec<(String, Arc<BackingStore>)>,
+    /// Given to keys a loader loads; None keeps them until evicted.
+    loader_ttl: Option<Duration>,
+    pub write_through: bool,
+    inflight: Mutex<HashMap<String, Arc<OnceCell<LoadResult>>>>,
+    stats: Arc<Stats>,
+}
+
+impl Tiered {
+    /// None when there is neither a backing store nor a loader.
+    pub fn new(
+        store: Option<BackingStore>,
+        loaders: &[Loader],
+        loader_ttl: Option<Duration>,
+        write_through: bool,
+        stats: Arc<Stats>,
+    ) -> Option<Self> {
+        if store.is_none() && loaders.is_empty() {
+            return None;
+        }
+        Some(Self {
+            // Loaders are read-only, so without a store there is nowhere to write
+            write_through: write_through && store.is_some(),
+            store: store.map(Arc::new),
+            loaders: loaders.iter().map(|l| (l.pattern.clone(), Arc::new(l.store.clone()))).collect(),
+            loader_ttl,
+            inflight: Mutex::new(HashMap::new()),
+            stats,
+        })
+    }
+
+    // The first loader whose pattern matches `key`, or else the backing store
+    fn source(&self, key: &str) -> Option<(&Arc<BackingStore>, bool)> {
+        match self.loaders.iter().find(|(pattern, _)| glob_match(pattern.as_bytes(), key.as_bytes())) {
+            Some((_, store)) => Some((store, true)),
+            None => self.store.as_ref().map(|store| (store, false)),
+        }
+    }
+
+    /// Loads `key`, joining a load already in flight for it if there is one.
+    /// A key nothing can load is missing.
+    pub async fn load(&self, key: &str, invoke: &Invoke<'_>) -> LoadResult {
+        let Some((store, _)) = self.source(key) else {
+            return Ok(None);
+        };
+        let cell = self
+            .inflight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        let mut fetched = false;
+        let result = cell
+            .get_or_init(|| {
+                fetched = true;
+                self.fetch(store, key, invoke)
+            })
+            .await
+            .clone();
+        let counter = if fetched { &self.stats.backing_loads } else { &self.stats.backing_loads_shared };
+        counter.fetch_add(1, Ordering::Relaxed);
+        // Whoever gets here first retires the entry so later misses load afresh
+        let mut inflight = self.inflight.lock().unwrap_or_else(|e| e.into_inner());
+        if inflight.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            inflight.remove(key);
+        }
+        result
+    }
+
+    /// Stores a value [`Tiered::load`] returned for `key` unless a write got
+    /// there first, with the loader TTL if a loader loaded it. Returns whether
+    /// it was stored.
+    pub fn store_loaded(&self, db: &Database, key: String, value: Vec<u8>) -> bool {
+        let ttl = self.loader_ttl.filter(|_| self.source(&key).is_some_and(|(_, loader)| loader));
+        let Some(ttl) = ttl else {
+            return db.insert_if_absent(key, value);
+        };
+        db.insert_if_absent(key.clone(), value) && db.expire_millis(&key, ttl.as_millis() as i64)
+    }
+
+    async fn fetch(&self, store: &Arc<BackingStore>, key: &str, invoke: &Invoke<'_>) -> LoadResult {
+        match &**store {
+            BackingStore::Command(name) => {
+                let reply = invoke(command_args(name, "GET", key, None));
+                match reply {
+                    RespValue::BulkString(v) => Ok(v),
+                    RespValue::SimpleString(s) => Ok(Some(s.into_bytes())),
+                    RespValue::Error(e) => Err(e),
+                    _ => Err(format!("unexpected reply from '{}'", name)),
+                }
+            }
+            _ => {
+                let store = store.clone();
+                let key = key.to_string();
+                tokio::task::spawn_blocking(move || load_blocking(&store, &key))
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Forwards `writes` in order, stopping at the first failure.
+    pub async fn write(&self, writes: Vec<BackingWrite>, invoke: &Invoke<'_>) -> Result<(), String> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        match &**store {
+            BackingStore::Command(name) => {
+                for write in &writes {
+                    let args = match write {
+                        BackingWrite::Set(key, value) => command_args(name, "SET", key, Some(value)),
+                        BackingWrite::Delete(key) => command_args(name, "DEL", key, None),
+                    };
+                    if let RespValue::Error(e) = invoke(args) {
+                        return Err(e);
+                    }
+                }
+                Ok(())
+            }
+            _ => {
+                let store = store.clone();
+                tokio::task::spawn_blocking(move || writes.iter().try_for_each(|w| write_blocking(&store, w)))
+                    .await
+                    .map_err(|e| e.to_string())?
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+fn command_args(name: &str, op: &str, key: &str, value: Option<&Vec<u8>>) -> Vec<RespValue> {
+    let mut args = vec![
+        RespValue::BulkString(Some(name.as_bytes().to_vec())),
+        RespValue::BulkString(Some(op.as_bytes().to_vec())),
+        RespValue::BulkString(Some(key.as_bytes().to_vec())),
+    ];
+    if let Some(v) = value {
+        args.push(RespValue::BulkString(Some(v.clone())));
+    }
+    args
+}
+
+fn load_blocking(store: &BackingStore, key: &str) -> io::Result<Option<Vec<u8>>> {
+    match store {
+        BackingStore::Http(url) => {
+            let (status, body) = HttpTarget::parse(url).map_err(io::Error::other)?.request("GET", key, None)?;
+            match status {
+                200 => Ok(Some(body)),
+                404 => Ok(None),
+                _ => Err(io::Error::other(format!("GET {} returned {}", key, status))),
+            }
+        }
+        BackingStore::Exec(path) => {
+            let out = Command::new(path).arg("get").arg(key).stdin(Stdio::null()).output()?;
+            match out.status.code() {
+                Some(0) => Ok(Some(out.stdout)),
+                Some(1) => Ok(None),
+                _ => Err(exec_error(path, &out.stderr)),
+            }
+        }
+        BackingStore::Command(_) => unreachable!("command stores run in-process"),
+    }
+}
+
+fn write_blocking(store: &BackingStore, write: &BackingWrite) -> io::Result<()> {
+    match store {
+        BackingStore::Http(url) => {
+            let target = HttpTarget::parse(url).map_err(io::Error::other)?;
+            let (status, _) = match write {
+                BackingWrite::Set(key, value) => target.request("PUT", key, Some(value))?,
+                BackingWrite::Delete(key) => target.request("DELETE", key, None)?,
+            };
+            if (200..300).contains(&status) || (status == 404 && matches!(write, BackingWrite::Delete(_))) {
+                Ok(())
+            } else {
+                Err(io::Error::other(format!("write of {} returned {}", write.key(), status)))
+            }
+        }
+        BackingStore::Exec(path) => {
+            let out = match write {
+                BackingWrite::Set(key, value) => {
+                    let mut child = Command::new(path)
+                        .arg("set")
+                        .arg(key)
+                        .stdin(Stdio::piped())
+                        .stdout(Stdio::null())
+                        .stderr(Stdio::piped())
+                        .spawn()?;
+                    if let Some(mut stdin) = child.stdin.take() {
+                        stdin.write_all(value)?;
+                    }
+                    child.wait_with_output()?
+                }
+                BackingWrite::Delete(key) => Command::new(path).arg("del").arg(key).stdin(Stdio::null()).output()?,
+            };
+            if out.status.success() {
+                Ok(())
+            } else {
+                Err(exec_error(path, &out.stderr))
+            }
+        }
+        BackingStore::Command(_) => unreachable!("command stores run in-process"),
+    }
+}
+
+fn exec_error(path: &std::path::Path, stderr: &[u8]) -> io::Error {
+    let msg = String::from_utf8_lossy(stderr);
+    io::Error::other(format!("{} failed: {}", path.display(), msg.trim()))
+}
+
+/// A plain `http://host[:port][/base]` endpoint; keys are appended as one
+/// percent-encoded path segment.
+struct HttpTarget {
+    host: String,
+    port: u16,
+    base: String,
+}
+
+impl HttpTarget {
+    fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("backing-store url '{}' must start with http://", url))?;
+        let (authority, base) = match rest.find('/') {
+            Some(i) => (&rest[..i], rest[i..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((h, p)) => (h, p.parse().map_err(|_| format!("invalid port in '{}'", url))?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("backing-store url '{}' has no host", url));
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            base: base.to_string(),
+        })
+    }
+
+    // HTTP/1.0 so the reply is never chunked and ends when the server closes
+    fn request(&self, method: &str, key: &str, body: Option<&[u8]>) -> io::Result<(u16, Vec<u8>)> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+        let body = body.unwrap_or_default();
+        let head = format!(
+            "{} {}/{} HTTP/1.0\r\nHost: {}\r\nContent-Length: {}\r\n\r\n",
+            method,
+            self.base,
+            percent_encode(key),
+            self.host,
+            body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+        let head = String::from_utf8_lossy(&response[..split]);
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP status line"))?;
+        Ok((status, response[split + 4..].to_vec()))
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
@@ -0,0 +1,145 @@
+//! Checks an append-only file, repairs it, or cuts it at a point in time.
+//!
+//! ```text
+//! rc-check-aof appendonly.aof
+//! rc-check-aof --fix appendonly.aof
+//! rc-check-aof --truncate-to-timestamp 1760000000 appendonly.aof
+//! rc-check-aof --truncate-to-offset 4096 appendonly.aof
+//! ```
+//!
+//! Without an option the file is only read: the number of commands, the
+//! last timestamp and the offset of every `#TS:` annotation are printed, so
+//! the moment before an accidental FLUSHDB can be picked. A damaged file is
+//! reported with where the damage starts and the exit status is 1; `--fix`
+//! cuts it after the last command that reads back whole. Changing the file
+//! keeps a copy of the original next to it first. The server must be
+//! stopped. Compressed files are read the same way; offsets count
+//! uncompressed bytes.
+
+use std::io;
+use std::path::Path;
+use std::process::exit;
+
+use server::aof::{replay, truncate, AofEntry, AofLimit, AofReader};
+use server::compression::open_decoded_frames;
+
+enum Action {
+    Check,
+    Fix,
+    Cut(AofLimit),
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (action, path) = match args.as_slice() {
+        [path] => (Action::Check, path),
+        [option, path] if option == "--fix" => (Action::Fix, path),
+        [option, value, path] => {
+            let value: u64 = match value.parse() {
+                Ok(v) => v,
+                Err(_) => usage(&format!("{} needs a number, not {}", option, value)),
+            };
+            match option.as_str() {
+                "--truncate-to-timestamp" => (Action::Cut(AofLimit::Timestamp(value)), path),
+                "--truncate-to-offset" => (Action::Cut(AofLimit::Offset(value)), path),
+                _ => usage(&format!("unknown option {}", option)),
+            }
+        }
+        _ => usage("expected a file"),
+    };
+    let path = Path::new(path);
+    let result = check(path).and_then(|valid_end| match (action, valid_end) {
+        (Action::Check, None) => Ok(true),
+        (Action::Check, Some(_)) => Ok(false),
+        (Action::Fix, None) => {
+            println!("Nothing to fix");
+            Ok(true)
+        }
+        (Action::Fix, Some(end)) => {
+            keep_original(path)?;
+            truncate(path, end)?;
+            println!("Cut at offset {}, after the last whole command", end);
+            Ok(true)
+        }
+        (Action::Cut(limit), None) => truncate_at(path, limit).map(|_| true),
+        (Action::Cut(_), Some(_)) => {
+            eprintln!("Repair the file with --fix first");
+            Ok(false)
+        }
+    });
+    match result {
+        Ok(true) => {}
+        Ok(false) => exit(1),
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+}
+
+fn usage(problem: &str) -> ! {
+    eprintln!("{}", problem);
+    eprintln!("usage: rc-check-aof [--fix | --truncate-to-timestamp <unix secs> | --truncate-to-offset <bytes>] <file>");
+    exit(2);
+}
+
+// Lists the timestamps in the file and reports whether it reads back whole.
+// For a damaged file, returns where its last whole command ends.
+fn check(path: &Path) -> io::Result<Option<u64>> {
+    let mut reader = AofReader::new(open_decoded_frames(path)?);
+    let mut commands = 0usize;
+    let mut last_ts = None;
+    let mut valid_end = 0;
+    let damage = loop {
+        let offset = reader.offset();
+        match reader.next_entry() {
+            Ok(None) => break None,
+            Ok(Some(AofEntry::Timestamp(ts))) => {
+                println!("offset {:>12}  timestamp {}", offset, ts);
+                last_ts = Some(ts);
+            }
+            Ok(Some(AofEntry::Command(_))) => {
+                commands += 1;
+                valid_end = reader.offset();
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                break Some(format!("incomplete command at offset {}, as after a crash mid-write", offset));
+            }
+            Err(e) => break Some(format!("damaged at offset {}: {}", offset, e)),
+        }
+    };
+    match last_ts {
+        Some(ts) => println!("{} whole commands, last timestamp {}", commands, ts),
+        None => println!("{} whole commands", commands),
+    }
+    match damage {
+        None => {
+            println!("{}: OK", path.display());
+            Ok(None)
+        }
+        Some(damage) => {
+            println!("{}: {}; the last whole command ends at offset {}", path.display(), damage, valid_end);
+            Ok(Some(valid_end))
+        }
+    }
+}
+
+fn keep_original(path: &Path) -> io::Result<()> {
+    let mut kept = path.as_os_str().to_owned();
+    kept.push(".orig");
+    std::fs::copy(path, &kept)?;
+    println!("The original was copied to {}", Path::new(&kept).display());
+    Ok(())
+}
+
+fn truncate_at(path: &Path, limit: AofLimit) -> io::Result<()> {
+    let result = replay(path, Some(limit), |_| {})?;
+    if !result.stopped_at_limit {
+        println!("Nothing after the limit; {} left as it is", path.display());
+        return Ok(());
+    }
+    keep_original(path)?;
+    truncate(path, result.end)?;
+    println!("Kept {} commands, cut at offset {}", result.commands, result.end);
+    Ok(())
+}
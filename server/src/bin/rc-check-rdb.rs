@@ -0,0 +1,55 @@
+//! Checks a snapshot or a Redis RDB file without loading it.
+//!
+//! ```text
+//! rc-check-rdb dump.rcs
+//! rc-check-rdb dump.rdb
+//! ```
+//!
+//! The kind of file is told from its first bytes, and compressed snapshots
+//! are read as they are. Every record is parsed and the checksum verified;
+//! a damaged file is reported with the offset of the first record that does
+//! not parse and the exit status is 1.
+
+use std::io::Read;
+use std::path::Path;
+use std::process::exit;
+
+use server::compression::open_decoded;
+use server::{check_rdb, check_snapshot};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let path = match args.as_slice() {
+        [path] => Path::new(path),
+        _ => {
+            eprintln!("usage: rc-check-rdb <file>");
+            exit(2);
+        }
+    };
+    let mut magic = [0u8; 5];
+    let is_rdb = open_decoded(path).and_then(|mut f| f.read_exact(&mut magic)).is_ok() && &magic == b"REDIS";
+    let result = if is_rdb {
+        check_rdb(path).map(|check| {
+            println!("Redis RDB version {}", check.version);
+            println!("{} keys, {} of types RustCache skips on import", check.keys, check.unsupported);
+            if check.unchecked {
+                println!("the file was written without a checksum");
+            }
+        })
+    } else {
+        check_snapshot(path).map(|check| {
+            println!("RustCache snapshot format version {}", check.version);
+            match check.max_db {
+                Some(db) => println!("{} keys in databases up to {}", check.keys, db),
+                None => println!("no keys"),
+            }
+        })
+    };
+    match result {
+        Ok(()) => println!("{}: OK", path.display()),
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+}
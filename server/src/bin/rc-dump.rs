@@ -0,0 +1,164 @@
+//! Summarises or dumps a snapshot, Redis RDB file or AOF without a server.
+//!
+//! ```text
+//! rc-dump dump.rcs
+//! rc-dump --top 20 appendonly.aof
+//! rc-dump --db 1 --keys 'session:*' dump.rdb
+//! ```
+//!
+//! The file is loaded into memory as a starting server would load it, and
+//! left as it is. The summary gives each database's key count by type and
+//! size, the biggest keys and how the TTLs are spread; `--keys` prints the
+//! keys matching a pattern with their TTL and value instead. Keys that have
+//! already expired are not shown. Use `--databases` for a file written by a
+//! server with more than 16.
+
+use std::path::Path;
+use std::process::exit;
+
+use server::db::Database;
+use server::offline::{detect, load};
+
+struct Options {
+    databases: usize,
+    top: usize,
+    db: Option<usize>,
+    pattern: Option<String>,
+}
+
+// Upper bounds, in seconds, of the TTL buckets in the summary
+const TTL_BUCKETS: &[(i64, &str)] = &[(60, "under 1m"), (3600, "under 1h"), (86_400, "under 1d"), (i64::MAX, "1d or more")];
+
+fn usage(problem: &str) -> ! {
+    eprintln!("rc-dump: {}", problem);
+    eprintln!("usage: rc-dump [--databases N] [--db N] [--top N | --keys PATTERN] <file>");
+    exit(2);
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options { databases: 16, top: 10, db: None, pattern: None };
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let mut number = |name: &str| -> usize {
+            match args.next().map(|v| v.parse()) {
+                Some(Ok(n)) => n,
+                _ => usage(&format!("{} needs a number", name)),
+            }
+        };
+        match arg.as_str() {
+            "--databases" => options.databases = number("--databases"),
+            "--top" => options.top = number("--top"),
+            "--db" => options.db = Some(number("--db")),
+            "--keys" => options.pattern = Some(args.next().unwrap_or_else(|| usage("--keys needs a pattern"))),
+            _ if arg.starts_with("--") => usage(&format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage("expected a single file"),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage("expected a file"));
+    let path = Path::new(&path);
+    if options.db.is_some_and(|db| db >= options.databases) {
+        usage("--db is past the number of databases");
+    }
+    let dbs = match detect(path).and_then(|kind| Ok((kind, load(path, options.databases)?))) {
+        Ok((kind, dbs)) => {
+            println!("{}: {}", path.display(), kind.name());
+            dbs
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    };
+    let selected: Vec<(usize, &Database)> = dbs
+        .iter()
+        .enumerate()
+        .filter(|(i, db)| options.db.is_none_or(|want| want == *i) && db.dbsize() > 0)
+        .collect();
+    match &options.pattern {
+        Some(pattern) => dump(&selected, pattern),
+        None => summarise(&selected, options.top),
+    }
+}
+
+// Every key of `db` matching `pattern`
+fn keys(db: &Database, pattern: Option<&str>) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, page) = db.scan(cursor, 1000, pattern);
+        keys.extend(page);
+        if next == 0 {
+            return keys;
+        }
+        cursor = next;
+    }
+}
+
+fn dump(dbs: &[(usize, &Database)], pattern: &str) {
+    for (index, db) in dbs {
+        let mut keys = keys(db, Some(pattern));
+        keys.sort();
+        for key in keys {
+            let Some(value) = db.get(&key) else { continue };
+            println!(
+                "db{} \"{}\" {} ttl={} \"{}\"",
+                index,
+                key.escape_debug(),
+                db.key_type(&key),
+                db.ttl_seconds(&key),
+                value.escape_ascii()
+            );
+        }
+    }
+}
+
+fn summarise(dbs: &[(usize, &Database)], top: usize) {
+    let mut biggest: Vec<(usize, usize, String)> = Vec::new();
+    let mut ttls = vec![0usize; TTL_BUCKETS.len()];
+    let mut persistent = 0usize;
+    let (mut total_keys, mut total_bytes) = (0usize, 0usize);
+    for (index, db) in dbs {
+        let mut types: Vec<(&'static str, usize)> = Vec::new();
+        let mut bytes = 0;
+        let keys = keys(db, None);
+        for key in &keys {
+            let Some(size) = db.memory_usage(key) else { continue };
+            bytes += size;
+            match types.iter_mut().find(|(t, _)| *t == db.key_type(key)) {
+                Some((_, n)) => *n += 1,
+                None => types.push((db.key_type(key), 1)),
+            }
+            match db.ttl_seconds(key) {
+                -1 => persistent += 1,
+                ttl => {
+                    let bucket = TTL_BUCKETS.iter().position(|(bound, _)| ttl < *bound).unwrap_or(TTL_BUCKETS.len() - 1);
+                    ttls[bucket] += 1;
+                }
+            }
+            biggest.push((size, *index, key.clone()));
+        }
+        let types: Vec<String> = types.iter().map(|(t, n)| format!("{}: {}", t, n)).collect();
+        println!("db{}: {} keys, {} bytes ({})", index, keys.len(), bytes, types.join(", "));
+        total_keys += keys.len();
+        total_bytes += bytes;
+    }
+    println!("Total: {} keys in {} databases, {} bytes", total_keys, dbs.len(), total_bytes);
+    if total_keys == 0 {
+        return;
+    }
+
+    biggest.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| (a.1, &a.2).cmp(&(b.1, &b.2))));
+    println!();
+    println!("Biggest keys:");
+    for (size, index, key) in biggest.iter().take(top) {
+        println!("  db{} \"{}\" {} bytes", index, key.escape_debug(), size);
+    }
+    println!();
+    println!("TTLs:");
+    println!("  {:<12}{}", "none", persistent);
+    for ((_, label), n) in TTL_BUCKETS.iter().zip(&ttls) {
+        println!("  {:<12}{}", label, n);
+    }
+}
@@ -0,0 +1,432 @@
+//! Replays an AOF or a MONITOR capture against a server, for capacity
+//! testing with a real workload.
+//!
+//! ```text
+//! rc-replay appendonly.aof
+//! rc-replay --host 10.0.0.5 --speed 4 --concurrency 16 monitor.log
+//! rc-replay --speed 0 --max-error-rate 0.5 appendonly.aof
+//! ```
+//!
+//! The file's kind is detected: an append-only file as the server writes
+//! it (compressed or not), or the lines `MONITOR` prints in Redis' format
+//! (`1760000000.123456 [0 127.0.0.1:50000] "SET" "k" "v"`). Commands are
+//! sent at the pace they were captured, `--speed` times faster; 0 sends them
+//! as fast as the target answers. `--concurrency` spreads them over that
+//! many connections, keeping the order of each captured client's commands
+//! (of each key's, for an AOF, which has no clients) and the database each
+//! ran in. The report gives throughput, latency, and replies and errors by
+//! command; with `--max-error-rate` the exit status is 1 when more than that
+//! percentage of replies were errors.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use server::aof::{AofEntry, AofReader};
+use server::compression::open_decoded_frames;
+use server::resp::{read_resp, RespValue};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+struct Options {
+    host: String,
+    port: u16,
+    password: Option<String>,
+    speed: f64,
+    concurrency: usize,
+    max_error_rate: Option<f64>,
+}
+
+// Commands queued per connection before reading the capture waits
+const QUEUE: usize = 1024;
+
+fn usage(problem: &str) -> ! {
+    eprintln!("rc-replay: {}", problem);
+    eprintln!(
+        "usage: rc-replay [--host HOST] [--port PORT] [--pass PASSWORD] [--speed N] [--concurrency N] [--max-error-rate PERCENT] <file>"
+    );
+    exit(2);
+}
+
+/// A command as captured.
+struct Captured {
+    /// When it ran, in unix seconds; 0 where the capture does not say.
+    at: f64,
+    db: usize,
+    /// Which connection sent it, for a capture that records that.
+    client: Option<String>,
+    args: Vec<Vec<u8>>,
+}
+
+enum Capture {
+    Aof {
+        reader: AofReader<Box<dyn BufRead + Send>>,
+        at: f64,
+        db: usize,
+    },
+    Monitor {
+        reader: Box<dyn BufRead + Send>,
+        line: usize,
+    },
+}
+
+impl Capture {
+    fn open(path: &Path) -> io::Result<Self> {
+        let mut reader = open_decoded_frames(path)?;
+        let head = reader.fill_buf()?;
+        // A capture saved from redis-cli starts with MONITOR's own OK
+        let monitor = head.first().is_some_and(u8::is_ascii_digit) || head.starts_with(b"OK\n") || head.starts_with(b"OK\r\n");
+        Ok(match head.first() {
+            _ if monitor => Capture::Monitor { reader, line: 0 },
+            None | Some(b'#') | Some(b'*') => Capture::Aof { reader: AofReader::new(reader), at: 0.0, db: 0 },
+            Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "neither an append-only file nor a MONITOR capture")),
+        })
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Capture::Aof { .. } => "append-only file",
+            Capture::Monitor { .. } => "MONITOR capture",
+        }
+    }
+
+    fn next(&mut self) -> io::Result<Option<Captured>> {
+        match self {
+            Capture::Aof { reader, at, db } => loop {
+                match reader.next_entry()? {
+                    None => return Ok(None),
+                    Some(AofEntry::Timestamp(ts)) => *at = ts as f64,
+                    // The connections select databases themselves
+                    Some(AofEntry::Command(args)) if args.len() == 2 && args[0].eq_ignore_ascii_case(b"SELECT") => {
+                        *db = std::str::from_utf8(&args[1])
+                            .ok()
+                            .and_then(|n| n.parse().ok())
+                            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "SELECT of a database that is not a number"))?;
+                    }
+                    Some(AofEntry::Command(args)) => return Ok(Some(Captured { at: *at, db: *db, client: None, args })),
+                }
+            },
+            Capture::Monitor { reader, line } => loop {
+                let mut text = String::new();
+                if reader.read_line(&mut text)? == 0 {
+                    return Ok(None);
+                }
+                *line += 1;
+                let text = text.trim_end();
+                if text.is_empty() || text == "OK" {
+                    continue;
+                }
+                return parse_monitor_line(text)
+                    .map(Some)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("line {} is not a MONITOR line", line)));
+            },
+        }
+    }
+}
+
+// `<unix secs> [<db> <client>] "<arg>" ...`
+fn parse_monitor_line(line: &str) -> Option<Captured> {
+    let (at, rest) = line.split_once(' ')?;
+    let (source, mut rest) = rest.strip_prefix('[')?.split_once("] ")?;
+    let (db, client) = source.split_once(' ')?;
+    let mut args = Vec::new();
+    while !rest.is_empty() {
+        let (arg, after) = unquote(rest)?;
+        args.push(arg);
+        rest = after.trim_start();
+    }
+    if args.is_empty() {
+        return None;
+    }
+    Some(Captured { at: at.parse().ok()?, db: db.parse().ok()?, client: Some(client.to_string()), args })
+}
+
+// One argument quoted as Redis quotes it, and what follows it
+fn unquote(text: &str) -> Option<(Vec<u8>, &str)> {
+    let bytes = text.as_bytes();
+    if bytes.first() != Some(&b'"') {
+        return None;
+    }
+    let mut out = Vec::new();
+    let mut i = 1;
+    loop {
+        match *bytes.get(i)? {
+            b'"' => return Some((out, &text[i + 1..])),
+            b'\\' => {
+                let escaped = *bytes.get(i + 1)?;
+                i += 2;
+                out.push(match escaped {
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b'x' => {
+                        let hex = std::str::from_utf8(bytes.get(i..i + 2)?).ok()?;
+                        i += 2;
+                        u8::from_str_radix(hex, 16).ok()?
+                    }
+                    other => other,
+                });
+            }
+            b => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// What one connection's replies came to.
+#[derive(Default)]
+struct Tally {
+    /// Replies and error replies, by command.
+    commands: BTreeMap<String, (u64, u64)>,
+    /// Error replies by their code (`ERR`, `WRONGTYPE`, ...).
+    errors: BTreeMap<String, u64>,
+    /// Round trips in microseconds.
+    latencies: Vec<u64>,
+}
+
+impl Tally {
+    fn count(&mut self, command: &[u8], reply: &RespValue) {
+        let counts = self.commands.entry(String::from_utf8_lossy(command).to_ascii_uppercase()).or_default();
+        counts.0 += 1;
+        if let RespValue::Error(message) = reply {
+            counts.1 += 1;
+            let code = message.split(' ').next().unwrap_or_default();
+            *self.errors.entry(code.to_string()).or_default() += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Tally) {
+        for (command, (replies, errors)) in other.commands {
+            let counts = self.commands.entry(command).or_default();
+            counts.0 += replies;
+            counts.1 += errors;
+        }
+        for (code, n) in other.errors {
+            *self.errors.entry(code).or_default() += n;
+        }
+        self.latencies.extend(other.latencies);
+    }
+
+    fn replies(&self) -> u64 {
+        self.commands.values().map(|c| c.0).sum()
+    }
+
+    fn error_replies(&self) -> u64 {
+        self.commands.values().map(|c| c.1).sum()
+    }
+}
+
+async fn request(conn: &mut BufReader<TcpStream>, args: Vec<Vec<u8>>, buf: &mut Vec<u8>) -> io::Result<RespValue> {
+    buf.clear();
+    RespValue::Array(Some(args.into_iter().map(|a| RespValue::BulkString(Some(a))).collect())).encode(buf);
+    conn.get_mut().write_all(buf).await?;
+    read_resp(conn).await
+}
+
+// Sends the commands routed to one connection, one at a time
+async fn run_connection(addr: String, password: Option<String>, mut jobs: mpsc::Receiver<Captured>) -> io::Result<Tally> {
+    let stream = TcpStream::connect(&addr)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("could not connect to {}: {}", addr, e)))?;
+    stream.set_nodelay(true)?;
+    let mut conn = BufReader::new(stream);
+    let mut buf = Vec::new();
+    if let Some(password) = password {
+        if let RespValue::Error(e) = request(&mut conn, vec![b"AUTH".to_vec(), password.into_bytes()], &mut buf).await? {
+            return Err(io::Error::other(format!("AUTH failed: {}", e)));
+        }
+    }
+    let mut tally = Tally::default();
+    let mut db = 0;
+    while let Some(job) = jobs.recv().await {
+        if job.db != db {
+            let select = vec![b"SELECT".to_vec(), job.db.to_string().into_bytes()];
+            // Carrying on in the wrong database would replay nothing like the capture
+            if let RespValue::Error(e) = request(&mut conn, select, &mut buf).await? {
+                return Err(io::Error::other(format!("SELECT {} failed: {}", job.db, e)));
+            }
+            db = job.db;
+        }
+        let name = job.args[0].clone();
+        let sent_at = Instant::now();
+        let reply = request(&mut conn, job.args, &mut buf).await?;
+        tally.latencies.push(sent_at.elapsed().as_micros() as u64);
+        tally.count(&name, &reply);
+    }
+    Ok(tally)
+}
+
+// Which connection sends a command: one per captured client, or per key
+fn route(captured: &Captured, connections: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    match (&captured.client, captured.args.get(1)) {
+        (Some(client), _) => client.hash(&mut hasher),
+        (None, Some(key)) => key.hash(&mut hasher),
+        (None, None) => return 0,
+    }
+    (hasher.finish() % connections as u64) as usize
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut options = Options {
+        host: "127.0.0.1".into(),
+        port: 9973,
+        password: None,
+        speed: 1.0,
+        concurrency: 1,
+        max_error_rate: None,
+    };
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().unwrap_or_else(|| usage(&format!("{} needs a value", name)));
+        let mut number = |name: &str| -> f64 {
+            match value(name).parse::<f64>() {
+                Ok(n) if n >= 0.0 && n.is_finite() => n,
+                _ => usage(&format!("{} needs a number", name)),
+            }
+        };
+        match arg.as_str() {
+            "--host" => options.host = value("--host"),
+            "--port" => options.port = value("--port").parse().unwrap_or_else(|_| usage("--port needs a port number")),
+            "--pass" => options.password = Some(value("--pass")),
+            "--speed" => options.speed = number("--speed"),
+            "--concurrency" => options.concurrency = number("--concurrency") as usize,
+            "--max-error-rate" => options.max_error_rate = Some(number("--max-error-rate")),
+            _ if arg.starts_with("--") => usage(&format!("unknown option {}", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => usage("expected a single file"),
+        }
+    }
+    let path = path.unwrap_or_else(|| usage("expected a file"));
+    let path = Path::new(&path);
+    if options.concurrency == 0 {
+        usage("--concurrency must be at least 1");
+    }
+    let mut capture = match Capture::open(path) {
+        Ok(capture) => capture,
+        Err(e) => {
+            eprintln!("{}: {}", path.display(), e);
+            exit(1);
+        }
+    };
+
+    let addr = format!("{}:{}", options.host, options.port);
+    let (senders, connections): (Vec<_>, Vec<JoinHandle<io::Result<Tally>>>) = (0..options.concurrency)
+        .map(|_| {
+            let (tx, rx) = mpsc::channel(QUEUE);
+            (tx, tokio::spawn(run_connection(addr.clone(), options.password.clone(), rx)))
+        })
+        .unzip();
+    let started = Instant::now();
+    let mut first_at = None;
+    let mut span = 0.0;
+    let mut failure = None;
+    loop {
+        let captured = match capture.next() {
+            Ok(Some(captured)) => captured,
+            Ok(None) => break,
+            Err(e) => {
+                failure = Some(format!("{}: {}", path.display(), e));
+                break;
+            }
+        };
+        let first = *first_at.get_or_insert(captured.at);
+        span = captured.at - first;
+        if options.speed > 0.0 && span > 0.0 {
+            let Ok(due) = Duration::try_from_secs_f64(span / options.speed) else {
+                usage(&format!("--speed {} is too slow to pace this capture", options.speed));
+            };
+            tokio::time::sleep_until((started + due).into()).await;
+        }
+        // A connection that failed has dropped its queue; its error is reported below
+        if senders[route(&captured, options.concurrency)].send(captured).await.is_err() {
+            break;
+        }
+    }
+    drop(senders);
+    let mut tally = Tally::default();
+    for connection in connections {
+        match connection.await.map_err(io::Error::other).and_then(|r| r) {
+            Ok(t) => tally.merge(t),
+            Err(e) => failure = failure.or(Some(e.to_string())),
+        }
+    }
+    let elapsed = started.elapsed();
+    if let Some(failure) = failure {
+        eprintln!("rc-replay: {}", failure);
+        exit(1);
+    }
+    if report(&capture, path, &options, &mut tally, elapsed, span) {
+        exit(1);
+    }
+}
+
+// Prints what the replay came to; true when errors were over --max-error-rate
+fn report(capture: &Capture, path: &Path, options: &Options, tally: &mut Tally, elapsed: Duration, span: f64) -> bool {
+    let replies = tally.replies();
+    let errors = tally.error_replies();
+    let secs = elapsed.as_secs_f64().max(1e-9);
+    println!(
+        "Replayed {} commands from {} ({}) in {:.2} seconds over {} connections",
+        replies,
+        path.display(),
+        capture.kind(),
+        secs,
+        options.concurrency
+    );
+    println!("  {:.2} commands per second", replies as f64 / secs);
+    if options.speed > 0.0 && span > 0.0 {
+        // Over the paced speed, the target did not keep up
+        let behind = secs - span / options.speed;
+        println!("  captured over {:.2} seconds; replayed at {:.2}x, {:.2} seconds behind the {}x pace", span, span / secs, behind.max(0.0), options.speed);
+    }
+    tally.latencies.sort_unstable();
+    if !tally.latencies.is_empty() {
+        let at = |p: f64| {
+            let idx = ((tally.latencies.len() as f64 * p / 100.0).ceil() as usize).clamp(1, tally.latencies.len());
+            tally.latencies[idx - 1] as f64 / 1000.0
+        };
+        let avg = tally.latencies.iter().sum::<u64>() as f64 / tally.latencies.len() as f64 / 1000.0;
+        println!("Latency (msec): avg {:.3}  p50 {:.3}  p99 {:.3}  max {:.3}", avg, at(50.0), at(99.0), at(100.0));
+    }
+    println!();
+    println!("{:<20} {:>10} {:>10} {:>8}", "command", "replies", "errors", "rate");
+    let rate = |errors: u64, replies: u64| if replies == 0 { 0.0 } else { errors as f64 * 100.0 / replies as f64 };
+    for (command, (replies, errors)) in &tally.commands {
+        println!("{:<20} {:>10} {:>10} {:>7.2}%", command, replies, errors, rate(*errors, *replies));
+    }
+    if !tally.errors.is_empty() {
+        println!();
+        println!("Errors by code:");
+        let mut codes: Vec<(&String, &u64)> = tally.errors.iter().collect();
+        codes.sort_by(|a, b| b.1.cmp(a.1));
+        for (code, n) in codes {
+            println!("  {:<18} {}", code, n);
+        }
+    }
+    println!();
+    let total = rate(errors, replies);
+    match capture {
+        Capture::Aof { .. } => println!("Error rate {:.2}%, against 0.00% captured: an AOF only holds commands that succeeded", total),
+        Capture::Monitor { .. } => println!("Error rate {:.2}%; MONITOR shows commands before they run, so the capture has none to compare", total),
+    }
+    match options.max_error_rate {
+        Some(max) if total > max => {
+            println!("Over the maximum error rate of {:.2}%", max);
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use tokio::io::{AsyncRead, BufReader};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+
+use crate::acl::User;
+use crate::config::Settings;
+use crate::pubsub::Subscriber;
+use crate::resp::{read_resp, RespValue};
+use crate::stats::Stats;
+
+/// Per-connection state that commands can read and change.
+pub(crate) struct ClientState {
+    /// None until the connection authenticates (or the default user is open).
+    pub user: Option<Arc<User>>,
+    /// Index of the logical database chosen with SELECT.
+    pub db: usize,
+    /// The peer's address; None for commands the server runs itself.
+    pub addr: Option<SocketAddr>,
+    /// The connection's CLIENT LIST entry; None for commands the server runs itself.
+    pub entry: Option<Arc<ClientEntry>>,
+    /// The connection's channels and patterns, once it subscribes to any.
+    pub subscriber: Option<Subscriber>,
+    /// Further replies to the command just run, written after its reply:
+    /// SUBSCRIBE confirms each channel separately.
+    pub extra_replies: Vec<RespValue>,
+    /// The port a replica says it serves clients on, from REPLCONF.
+    pub listening_port: Option<u16>,
+    /// Set by SYNC and PSYNC: the connection becomes a replication stream
+    /// once the command returns.
+    pub sync: bool,
+    /// A reply still being worked out elsewhere (a write going through the
+    /// raft log, an indirect cluster probe), which the connection waits for.
+    pub pending: Option<oneshot::Receiver<RespValue>>,
+}
+
+impl ClientState {
+    pub fn new(settings: &Settings, addr: Option<SocketAddr>) -> Self {
+        let user = settings.acl().default_user().filter(|u| u.is_open());
+        Self { user, db: 0, addr, entry: None, subscriber: None, extra_replies: Vec::new(), listening_port: None, sync: false, pending: None }
+    }
+
+    /// The client that commands the server runs itself act as.
+    pub fn internal(db: usize) -> Self {
+        Self { user: None, db, addr: None, entry: None, subscriber: None, extra_replies: Vec::new(), listening_port: None, sync: false, pending: None }
+    }
+
+    /// Who the client is, for logs: `addr=<ip:port>`.
+    pub fn info(&self) -> String {
+        match self.addr {
+            Some(a) => format!("addr={}", a),
+            None => "addr=internal".to_string(),
+        }
+    }
+
+    pub fn namespace(&self) -> Option<&str> {
+        self.user.as_ref().and_then(|u| u.namespace.as_deref())
+    }
+}
+
+/// A connection's commands, read off its socket ahead of the one running.
+///
+/// A task of its own parses frames into a queue of `client-queue-depth`
+/// frames and `client-queue-bytes` bytes, counted as the frames encode.
+/// When the queue is full the task waits instead of reading, so a client
+/// that sends faster than its commands run is held back by TCP flow control
+/// rather than having parsed frames pile up here. The task ends with the
+/// error that ended the connection, and is stopped when the queue is dropped.
+pub(crate) struct CommandQueue {
+    frames: mpsc::Receiver<(io::Result<RespValue>, usize)>,
+    // Taken off the queue by `ready`, for `next` to return
+    peeked: Option<io::Result<RespValue>>,
+    queued: Arc<QueuedBytes>,
+    reader: JoinHandle<()>,
+}
+
+// The bytes of the frames in a queue, and a wakeup for its reader as they
+// are taken
+#[derive(Default)]
+struct QueuedBytes {
+    bytes: AtomicUsize,
+    taken: Notify,
+}
+
+impl CommandQueue {
+    pub fn start<R>(reader: R, depth: usize, budget: usize, stats: Arc<Stats>) -> Self
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let (queue, frames) = mpsc::channel(depth.max(1));
+        let queued = Arc::new(QueuedBytes::default());
+        let bytes = queued.clone();
+        let reader = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            loop {
+                let frame = read_resp(&mut reader).await;
+                let last = frame.is_err();
+                let size = frame.as_ref().map_or(0, RespValue::encoded_len);
+                let mut stalled = false;
+                let mut stall = || {
+                    if !std::mem::replace(&mut stalled, true) {
+                        stats.client_queue_stalls.fetch_add(1, Ordering::Relaxed);
+                    }
+                };
+                // An empty queue takes a frame of any size
+                loop {
+                    let held = bytes.bytes.load(Ordering::Acquire);
+                    if held == 0 || held + size <= budget {
+                        break;
+                    }
+                    stall();
+                    bytes.taken.notified().await;
+                }
+                bytes.bytes.fetch_add(size, Ordering::AcqRel);
+                let queued = match queue.try_send((frame, size)) {
+                    Err(TrySendError::Full(frame)) => {
+                        stall();
+                        queue.send(frame).await.is_ok()
+                    }
+                    sent => sent.is_ok(),
+                };
+                if last || !queued {
+                    break;
+                }
+            }
+        });
+        Self { frames, peeked: None, queued, reader }
+    }
+
+    /// The next command, or the error that ended the connection
+    /// (UnexpectedEof once it closed).
+    pub async fn next(&mut self) -> io::Result<RespValue> {
+        if let Some(frame) = self.peeked.take() {
+            return frame;
+        }
+        self.recv().await
+    }
+
+    /// Waits for the next command, or the end of the connection, without
+    /// taking it; returns false for the end. Safe to cancel.
+    pub async fn ready(&mut self) -> bool {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.recv().await);
+        }
+        matches!(self.peeked, Some(Ok(_)))
+    }
+
+    async fn recv(&mut self) -> io::Result<RespValue> {
+        let Some((frame, size)) = self.frames.recv().await else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+        self.queued.bytes.fetch_sub(size, Ordering::AcqRel);
+        self.queued.taken.notify_one();
+        frame
+    }
+}
+
+impl Drop for CommandQueue {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Every open connection, for CLIENT LIST.
+pub(crate) struct ClientList {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, Arc<ClientEntry>>>,
+}
+
+/// What CLIENT LIST shows about a connection, updated after each command.
+pub(crate) struct ClientEntry {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    connected_at: Instant,
+    activity: Mutex<Activity>,
+}
+
+struct Activity {
+    at: Instant,
+    cmd: String,
+    db: usize,
+    user: Option<Arc<User>>,
+}
+
+impl ClientList {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            open: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, Arc<ClientEntry>>> {
+        self.open.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn add(&self, addr: Option<SocketAddr>) -> Arc<ClientEntry> {
+        let now = Instant::now();
+        let entry = Arc::new(ClientEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            addr,
+            connected_at: now,
+            activity: Mutex::new(Activity {
+                at: now,
+                cmd: "NULL".to_string(),
+                db: 0,
+                user: None,
+            }),
+        });
+        self.lock().insert(entry.id, entry.clone());
+        entry
+    }
+
+    pub fn remove(&self, id: u64) {
+        self.lock().remove(&id);
+    }
+
+    /// The open connections, oldest first.
+    pub fn all(&self) -> Vec<Arc<ClientEntry>> {
+        self.lock().values().cloned().collect()
+    }
+}
+
+impl ClientEntry {
+    fn activity(&self) -> std::sync::MutexGuard<'_, Activity> {
+        self.activity.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Notes that `client` just ran `cmd`.
+    pub fn ran(&self, cmd: &str, client: &ClientState) {
+        let mut activity = self.activity();
+        activity.at = Instant::now();
+        activity.cmd.clear();
+        activity.cmd.push_str(cmd);
+        activity.db = client.db;
+        if !activity.user.as_ref().zip(client.user.as_ref()).is_some_and(|(a, b)| Arc::ptr_eq(a, b)) {
+            activity.user = client.user.clone();
+        }
+    }
+
+    /// The connection as a CLIENT LIST line, without the newline.
+    pub fn describe(&self) -> String {
+        let activity = self.activity();
+        format!(
+            "id={} addr={} age={} idle={} db={} user={} cmd={}",
+            self.id,
+            self.addr.map(|a| a.to_string()).unwrap_or_default(),
+            self.connected_at.elapsed().as_secs(),
+            activity.at.elapsed().as_secs(),
+            activity.db,
+            activity.user.as_ref().map(|u| u.name.as_str()).unwrap_or("(none)"),
+            activity.cmd
+        )
+    }
+}
@@ -0,0 +1,187 @@
+//! The clock TTLs are measured against.
+//!
+//! Deadlines are held as monotonic `Instant`s, so a wall clock step does not
+//! expire keys early or late, but they arrive and leave as unix times:
+//! EXPIREAT and PXAT, TIME, snapshots, RDB files and the AOF. A server's
+//! databases share one [`Clock`], and every TTL reading or conversion goes
+//! through it, so there is a single answer to "has this key expired". The
+//! reaper and blocking timeouts sleep on it too, so a [`ManualClock`] put in
+//! its place by a test drives all of them.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::watch;
+
+/// A wait on a [`TimeSource`].
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Where a [`Clock`] gets the time from: the system's clocks, or a
+/// [`ManualClock`] that tests move by hand.
+pub trait TimeSource: Send + Sync {
+    /// The current monotonic time.
+    fn now(&self) -> Instant;
+
+    /// The wall clock in microseconds since the unix epoch.
+    fn unix_micros(&self) -> i64;
+
+    /// Resolves once this source's monotonic time reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn unix_micros(&self) -> i64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_micros() as i64).unwrap_or(0)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// A time source that stands still until [`ManualClock::advance`] moves it,
+/// so TTLs, the expiry reaper, eviction ages and blocking timeouts can be
+/// tested without real sleeps. It starts at the system's time of its creation;
+/// both its clocks move together.
+pub struct ManualClock {
+    // The monotonic and wall time, as unix microseconds
+    time: watch::Sender<(Instant, i64)>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        let (time, _) = watch::channel((Instant::now(), SystemClock.unix_micros()));
+        Self { time }
+    }
+
+    /// Moves time on by `by`, waking whatever was sleeping until then.
+    pub fn advance(&self, by: Duration) {
+        self.time.send_modify(|(now, unix)| {
+            *now += by;
+            *unix = unix.saturating_add(by.as_micros() as i64);
+        });
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for ManualClock {
+    fn now(&self) -> Instant {
+        self.time.borrow().0
+    }
+
+    fn unix_micros(&self) -> i64 {
+        self.time.borrow().1
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let mut time = self.time.subscribe();
+        Box::pin(async move {
+            // The sender lives as long as any clock using it, so this only
+            // returns early if time can no longer move
+            let _ = time.wait_for(|(now, _)| *now >= deadline).await;
+        })
+    }
+}
+
+/// The server's time source.
+#[derive(Clone)]
+pub struct Clock {
+    source: Arc<dyn TimeSource>,
+}
+
+impl Clock {
+    /// The system's monotonic and wall clocks.
+    pub fn system() -> Self {
+        Self { source: Arc::new(SystemClock) }
+    }
+
+    /// Time as `source` tells it, such as a [`ManualClock`] shared with a test.
+    pub fn from_source(source: Arc<dyn TimeSource>) -> Self {
+        Self { source }
+    }
+
+    /// The current monotonic time, which deadlines are compared to.
+    pub fn now(&self) -> Instant {
+        self.source.now()
+    }
+
+    /// The wall clock in microseconds since the unix epoch.
+    pub fn unix_micros(&self) -> i64 {
+        self.source.unix_micros()
+    }
+
+    /// The wall clock in milliseconds since the unix epoch.
+    pub fn unix_ms(&self) -> i64 {
+        self.unix_micros() / 1000
+    }
+
+    /// Resolves once this clock reaches `deadline`.
+    pub fn sleep_until(&self, deadline: Instant) -> Sleep {
+        self.source.sleep_until(deadline)
+    }
+
+    /// Resolves once `duration` has passed by this clock.
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        self.sleep_until(self.now() + duration)
+    }
+
+    /// Reads both clocks at once, for converting a batch of deadlines (a
+    /// snapshot's, say) consistently.
+    pub fn reading(&self) -> Reading {
+        Reading {
+            now: self.now(),
+            unix_ms: self.unix_ms(),
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Clock").finish_non_exhaustive()
+    }
+}
+
+/// The monotonic and wall clocks as of one moment.
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub now: Instant,
+    pub unix_ms: i64,
+}
+
+impl Reading {
+    /// The unix time in milliseconds of the deadline `at`.
+    pub fn unix_ms_of(&self, at: Instant) -> i64 {
+        if at >= self.now {
+            self.unix_ms.saturating_add(at.duration_since(self.now).as_millis() as i64)
+        } else {
+            self.unix_ms.saturating_sub(self.now.duration_since(at).as_millis() as i64)
+        }
+    }
+
+    /// The time from this reading until unix time `ms`, or None if it had
+    /// passed.
+    pub fn until_unix_ms(&self, ms: i64) -> Option<Duration> {
+        let left = ms.saturating_sub(self.unix_ms);
+        (left > 0).then(|| Duration::from_millis(left as u64))
+    }
+}
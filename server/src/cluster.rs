@@ -0,0 +1,42 @@
+use crc::{Crc, CRC_16_XMODEM};
+
+/// Hash slots keys are spread over, as in Redis Cluster.
+pub(crate) const SLOTS: u16 = 16384;
+
+// The CRC16 variant Redis Cluster hashes keys with
+static CRC16: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+/// The part of `key` that is hashed: what is between the first `{` and the
+/// next `}` if that is non-empty, otherwise the whole key. Keys sharing a
+/// hash tag, like `{user:1}:name` and `{user:1}:email`, share a slot.
+pub(crate) fn hash_tag(key: &[u8]) -> &[u8] {
+    if let Some(open) = key.iter().position(|b| *b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|b| *b == b'}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+/// The hash slot of `key`.
+pub(crate) fn key_slot(key: &[u8]) -> u16 {
+    CRC16.checksum(hash_tag(key)) % SLOTS
+}
+
+/// A slot range from `start-end`, or a single slot.
+pub(crate) fn parse_slot_range(s: &str) -> Option<(u16, u16)> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let (start, end) = (start.parse::<u16>().ok()?, end.parse::<u16>().ok()?);
+    (start <= end && end < SLOTS).then_some((start, end))
+}
+
+/// Slot ranges as `start-end` (or the slot alone), separated by `sep`.
+pub(crate) fn format_slots(ranges: &[(u16, u16)], sep: &str) -> String {
+    let ranges: Vec<String> = ranges
+        .iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}-{}", start, end) })
+        .collect();
+    ranges.join(sep)
+}
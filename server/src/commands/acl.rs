@@ -0,0 +1,118 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::flags::{ADMIN, FAST, NOAUTH};
+use super::{bulk_to_string_lossy, resp_err, resp_ok, CommandSpec, Context, Registry};
+use crate::acl::Denial;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("auth", CommandSpec::new(-2, &[NOAUTH, FAST]), auth);
+    registry.register("acl", CommandSpec::new(-2, &[]), acl);
+}
+
+fn auth(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let (user, password) = match args {
+        [password] => ("default".to_string(), bulk_to_string_lossy(password).unwrap_or_default()),
+        [user, password] => (
+            bulk_to_string_lossy(user).unwrap_or_default(),
+            bulk_to_string_lossy(password).unwrap_or_default(),
+        ),
+        _ => return CommandError::WrongArity("auth".into()).into(),
+    };
+    let acl = ctx.settings.acl();
+    if args.len() == 1 && acl.default_user().is_some_and(|u| u.is_open()) {
+        return resp_err(
+            "AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?",
+        );
+    }
+    match acl.authenticate(&user, &password) {
+        Some(u) => {
+            ctx.client.user = Some(u);
+            resp_ok()
+        }
+        None => {
+            ctx.settings.acl_log().record(Denial::Auth, "AUTH", &user, ctx.client.info());
+            CommandError::WrongPass.into()
+        }
+    }
+}
+
+fn acl(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = args.first().and_then(bulk_to_string_lossy).unwrap_or_default();
+    if sub.eq_ignore_ascii_case("whoami") {
+        if args.len() != 1 {
+            return CommandError::WrongArity("acl|whoami".into()).into();
+        }
+        return match &ctx.client.user {
+            Some(u) => RespValue::BulkString(Some(u.name.clone().into_bytes())),
+            None => RespValue::BulkString(None),
+        };
+    }
+    if sub.eq_ignore_ascii_case("log") {
+        return acl_log(ctx, &args[1..]);
+    }
+    resp_err("unknown subcommand for 'acl'")
+}
+
+// ACL LOG [count | RESET]; entries are field/value lists, newest first. It
+// names other users and their addresses, so it counts as an admin command.
+fn acl_log(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    if let Some(user) = &ctx.client.user {
+        if user.namespace.is_some() || !user.can_run("acl|log", &[ADMIN]) {
+            return CommandError::NoPerm {
+                user: user.name.clone(),
+                command: "acl|log".to_string(),
+            }
+            .into();
+        }
+    }
+    let log = ctx.settings.acl_log();
+    let count = match args {
+        [] => 10,
+        [arg] => match bulk_to_string_lossy(arg) {
+            Some(a) if a.eq_ignore_ascii_case("reset") => {
+                log.reset();
+                return resp_ok();
+            }
+            Some(a) => match a.parse::<usize>() {
+                Ok(n) => n,
+                Err(_) => return CommandError::NotInteger.into(),
+            },
+            None => return CommandError::Syntax.into(),
+        },
+        _ => return CommandError::WrongArity("acl|log".into()).into(),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+    let entries = log
+        .recent(count)
+        .into_iter()
+        .map(|e| {
+            let age = now.saturating_sub(e.created_ms) as f64 / 1000.0;
+            RespValue::Array(Some(vec![
+                bulk("count"),
+                RespValue::Integer(e.count as i64),
+                bulk("reason"),
+                bulk(e.reason()),
+                bulk("context"),
+                bulk("toplevel"),
+                bulk("object"),
+                bulk(&e.object),
+                bulk("username"),
+                bulk(&e.username),
+                bulk("age-seconds"),
+                bulk(&format!("{:.3}", age)),
+                bulk("client-info"),
+                bulk(&e.client_info),
+                bulk("entry-id"),
+                RespValue::Integer(e.id as i64),
+                bulk("timestamp-created"),
+                RespValue::Integer(e.created_ms as i64),
+                bulk("timestamp-last-updated"),
+                RespValue::Integer(e.updated_ms as i64),
+            ]))
+        })
+        .collect();
+    RespValue::Array(Some(entries))
+}
@@ -0,0 +1,113 @@
+use super::{bulk_to_bytes, bulk_to_string_lossy, resp_err, resp_ok, CommandSpec, Context, Registry};
+use crate::cluster::{format_slots, key_slot};
+use crate::error::CommandError;
+use crate::gossip::Health;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("cluster", CommandSpec::new(-2, &[]), cluster);
+}
+
+fn bulk(s: &str) -> RespValue {
+    RespValue::BulkString(Some(s.as_bytes().to_vec()))
+}
+
+// KEYSLOT works whether or not cluster mode is on, so clients can plan hash
+// tags against a single node. The rest needs the gossip started with the
+// server; PING and PINGREQ are how the nodes gossip (see gossip.rs).
+fn cluster(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    if sub == "keyslot" {
+        return match &args[1..] {
+            [key] => match bulk_to_bytes(key) {
+                Some(k) => RespValue::Integer(key_slot(&k) as i64),
+                None => resp_err("invalid key"),
+            },
+            _ => CommandError::WrongArity("cluster|keyslot".into()).into(),
+        };
+    }
+    let gossip = match ctx.db.stats.gossip.get() {
+        Some(gossip) if ctx.settings.cluster_enabled() => gossip.clone(),
+        _ => return resp_err("This instance has cluster support disabled"),
+    };
+    let Some(words) = args[1..].iter().map(bulk_to_bytes).collect::<Option<Vec<_>>>() else {
+        return CommandError::Syntax.into();
+    };
+    match (sub.as_str(), words.len()) {
+        ("myid", 0) => bulk(&gossip.id),
+        ("info", 0) => {
+            let summary = gossip.summary();
+            let info = format!(
+                "cluster_enabled:1\r\ncluster_state:{}\r\ncluster_slots_assigned:{}\r\ncluster_known_nodes:{}\r\ncluster_size:{}\r\n",
+                if summary.ok { "ok" } else { "fail" },
+                summary.slots_assigned,
+                summary.known_nodes,
+                summary.size
+            );
+            bulk(&info)
+        }
+        ("nodes", 0) => {
+            let line = |id: &str, addr: &str, flags: &str, seen: i64, incarnation: u64, link: &str, slots: &[(u16, u16)]| {
+                let port = addr.rsplit_once(':').map_or("0", |(_, p)| p);
+                format!("{} {}@{} {} - 0 {} {} {} {}\n", id, addr, port, flags, seen, incarnation, link, format_slots(slots, " ")).replace(" \n", "\n")
+            };
+            let mut out = line(&gossip.id, &gossip.addr, "myself,master", 0, gossip.incarnation(), "connected", &gossip.slots);
+            for m in gossip.members() {
+                let (flags, link) = match m.health {
+                    Health::Alive => ("master", "connected"),
+                    Health::Suspect => ("master,fail?", "connected"),
+                    Health::Failed => ("master,fail", "disconnected"),
+                };
+                out.push_str(&line(&m.id, &m.addr, flags, m.seen_ms, m.incarnation, link, &m.slots));
+            }
+            bulk(&out)
+        }
+        ("slots", 0) => {
+            let node = |id: &str, addr: &str| {
+                let (host, port) = addr.rsplit_once(':').unwrap_or((addr, "0"));
+                RespValue::Array(Some(vec![bulk(host), RespValue::Integer(port.parse().unwrap_or(0)), bulk(id)]))
+            };
+            let mut owners = vec![(gossip.slots.clone(), node(&gossip.id, &gossip.addr))];
+            for m in gossip.members().into_iter().filter(|m| m.health != Health::Failed) {
+                owners.push((m.slots.clone(), node(&m.id, &m.addr)));
+            }
+            let mut ranges: Vec<(u16, u16, RespValue)> =
+                owners.into_iter().flat_map(|(slots, node)| slots.into_iter().map(move |(start, end)| (start, end, node.clone()))).collect();
+            ranges.sort_by_key(|(start, _, _)| *start);
+            let ranges = ranges.into_iter().map(|(start, end, node)| {
+                RespValue::Array(Some(vec![RespValue::Integer(start as i64), RespValue::Integer(end as i64), node]))
+            });
+            RespValue::Array(Some(ranges.collect()))
+        }
+        ("meet", 2) => {
+            let host = String::from_utf8_lossy(&words[0]);
+            let Some(port) = std::str::from_utf8(&words[1]).ok().and_then(|p| p.parse::<u16>().ok()) else {
+                return resp_err("Invalid node address specified");
+            };
+            gossip.meet(format!("{}:{}", host, port));
+            resp_ok()
+        }
+        ("ping", _) => {
+            if !gossip.merge(&words) {
+                return resp_err("invalid gossip message");
+            }
+            RespValue::Array(Some(gossip.rumors().into_iter().map(|r| RespValue::BulkString(Some(r))).collect()))
+        }
+        ("pingreq", 1) => {
+            // Answered once the probe is: the connection waits for it
+            let target = String::from_utf8_lossy(&words[0]).into_owned();
+            let (answer, reply) = tokio::sync::oneshot::channel();
+            tokio::spawn(async move {
+                let result = match gossip.ping(&target).await {
+                    Ok(_) => resp_ok(),
+                    Err(e) => resp_err(&format!("no answer from {}: {}", target, e)),
+                };
+                let _ = answer.send(result);
+            });
+            ctx.client.pending = Some(reply);
+            resp_ok()
+        }
+        ("myid" | "info" | "nodes" | "slots" | "meet" | "pingreq", _) => CommandError::WrongArity(format!("cluster|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'cluster'"),
+    }
+}
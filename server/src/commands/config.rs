@@ -0,0 +1,57 @@
+use super::flags::ADMIN;
+use super::{bulk_to_string_lossy, resp_err, resp_ok, CommandSpec, Context, Registry};
+use crate::glob::glob_match;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("config", CommandSpec::new(-2, &[ADMIN]), config);
+}
+
+fn config(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = args.first().and_then(bulk_to_string_lossy).unwrap_or_default();
+    if sub.eq_ignore_ascii_case("reload") {
+        if args.len() != 1 {
+            return CommandError::WrongArity("config|reload".into()).into();
+        }
+        return match ctx.settings.reload() {
+            Ok(_) => resp_ok(),
+            Err(e) => resp_err(&format!("config reload failed: {}", e)),
+        };
+    }
+    if sub.eq_ignore_ascii_case("set") {
+        if args.len() < 3 || args.len().is_multiple_of(2) {
+            return CommandError::WrongArity("config|set".into()).into();
+        }
+        let pairs: Vec<(String, String)> = args[1..]
+            .chunks(2)
+            .map(|pair| {
+                let text = |v: &RespValue| bulk_to_string_lossy(v).unwrap_or_default();
+                (text(&pair[0]), text(&pair[1]))
+            })
+            .collect();
+        return match ctx.settings.set(&pairs) {
+            Ok(report) if report.restart_required.is_empty() => resp_ok(),
+            Ok(report) => resp_err(&format!(
+                "{} can only change on restart",
+                report.restart_required.join(", ")
+            )),
+            Err(e) => resp_err(&format!("CONFIG SET failed: {}", e)),
+        };
+    }
+    if sub.eq_ignore_ascii_case("get") {
+        if args.len() < 2 {
+            return CommandError::WrongArity("config|get".into()).into();
+        }
+        let patterns: Vec<String> = args[1..].iter().filter_map(bulk_to_string_lossy).collect();
+        let mut out = Vec::new();
+        for (name, value) in ctx.settings.current().directives() {
+            if patterns.iter().any(|p| glob_match(p.to_ascii_lowercase().as_bytes(), name.as_bytes())) {
+                out.push(RespValue::BulkString(Some(name.as_bytes().to_vec())));
+                out.push(RespValue::BulkString(Some(value.into_bytes())));
+            }
+        }
+        return RespValue::Array(Some(out));
+    }
+    resp_err("unknown subcommand for 'config'")
+}
@@ -0,0 +1,159 @@
+use std::sync::atomic::Ordering;
+
+use super::flags::{FAST, READONLY, WRITE};
+use super::{arg_str, bulk_to_string_lossy, parse_scan, resp_err, ttl_millis, CommandSpec, Context, Registry};
+use crate::allocator;
+use crate::backing::BackingWrite;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("del", CommandSpec::new(-2, &[WRITE]).keys(1, -1, 1), del);
+    registry.register("unlink", CommandSpec::new(-2, &[WRITE, FAST]).keys(1, -1, 1), del);
+    registry.register("exists", CommandSpec::new(-2, &[READONLY, FAST]).keys(1, -1, 1), exists);
+    registry.register("expire", CommandSpec::new(3, &[WRITE, FAST]).keys(1, 1, 1), |ctx, args| expire(ctx, args, "EX"));
+    registry.register("pexpire", CommandSpec::new(3, &[WRITE, FAST]).keys(1, 1, 1), |ctx, args| expire(ctx, args, "PX"));
+    registry.register("expireat", CommandSpec::new(3, &[WRITE, FAST]).keys(1, 1, 1), |ctx, args| expire(ctx, args, "EXAT"));
+    registry.register("pexpireat", CommandSpec::new(3, &[WRITE, FAST]).keys(1, 1, 1), |ctx, args| expire(ctx, args, "PXAT"));
+    registry.register("ttl", CommandSpec::new(2, &[READONLY, FAST]).keys(1, 1, 1), ttl);
+    registry.register("persist", CommandSpec::new(2, &[WRITE, FAST]).keys(1, 1, 1), persist);
+    registry.register("type", CommandSpec::new(2, &[READONLY, FAST]).keys(1, 1, 1), key_type);
+    registry.register("memory", CommandSpec::new(-2, &[READONLY]).keys(2, 2, 1), memory);
+    registry.register("scan", CommandSpec::new(-2, &[READONLY]), scan);
+}
+
+fn del(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let keys: Vec<_> = args.iter().filter_map(arg_str).collect();
+    for k in &keys {
+        ctx.wrote(|| BackingWrite::Delete(k.to_string()));
+    }
+    RespValue::Integer(ctx.db.del(&keys) as i64)
+}
+
+fn exists(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let keys: Vec<_> = args.iter().filter_map(arg_str).collect();
+    RespValue::Integer(ctx.db.exists(&keys) as i64)
+}
+
+// EXPIRE, PEXPIRE, EXPIREAT and PEXPIREAT, by the unit of their argument
+fn expire(ctx: &mut Context<'_>, args: &[RespValue], unit: &str) -> RespValue {
+    let key = match arg_str(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    let n: i64 = match arg_str(&args[1]).and_then(|s| s.parse().ok()) {
+        Some(v) => v,
+        None => return CommandError::NotInteger.into(),
+    };
+    let ms = ttl_millis(ctx.db.clock(), unit, n).unwrap_or(0);
+    let ok = ctx.db.expire_millis(&key, ms);
+    RespValue::Integer(if ok { 1 } else { 0 })
+}
+
+fn ttl(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match arg_str(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    RespValue::Integer(ctx.db.ttl_seconds(&key))
+}
+
+fn persist(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match bulk_to_string_lossy(&args[0]) { Some(s) => s, None => return resp_err("invalid key") };
+    // remove expiration; if key exists and had expiration, return 1 else 0
+    let existed = ctx.db.exists(std::slice::from_ref(&key)) > 0;
+    if existed {
+        let value = match ctx.db.get_checked(&key) {
+            Ok(value) => value.unwrap_or_default(),
+            Err(e) => return CommandError::from(e).into(),
+        };
+        ctx.db.set(key.clone(), value, None);
+        RespValue::Integer(1)
+    } else {
+        RespValue::Integer(0)
+    }
+}
+
+fn key_type(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match arg_str(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    RespValue::SimpleString(ctx.db.key_type(&key).to_string())
+}
+
+// MEMORY USAGE key | STATS
+fn memory(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = args.first().and_then(bulk_to_string_lossy).unwrap_or_default();
+    if sub.eq_ignore_ascii_case("stats") {
+        return memory_stats(ctx, &args[1..]);
+    }
+    if !sub.eq_ignore_ascii_case("usage") {
+        return resp_err("unknown subcommand for 'memory'");
+    }
+    if args.len() != 2 {
+        return CommandError::WrongArity("memory|usage".into()).into();
+    }
+    let key = match bulk_to_string_lossy(&args[1]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    match ctx.db.memory_usage(&key) {
+        Some(n) => RespValue::Integer(n as i64),
+        None => RespValue::BulkString(None),
+    }
+}
+
+// Field/value pairs, as Redis lays them out
+fn memory_stats(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    if !args.is_empty() {
+        return CommandError::WrongArity("memory|stats".into()).into();
+    }
+    let dataset: usize = ctx.dbs.iter().map(|db| db.used_memory()).sum();
+    let alloc = allocator::stats();
+    let rss = allocator::resident_set_size();
+    let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+    let overhead = ctx.db.stats.memory_overhead.load(Ordering::Relaxed);
+    RespValue::Array(Some(vec![
+        bulk("allocator"),
+        bulk(allocator::NAME),
+        bulk("dataset.bytes"),
+        RespValue::Integer(dataset as i64),
+        bulk("overhead.total"),
+        RespValue::Integer(overhead as i64),
+        bulk("allocator.allocated"),
+        RespValue::Integer(alloc.allocated as i64),
+        bulk("allocator.active"),
+        RespValue::Integer(alloc.active as i64),
+        bulk("allocator.resident"),
+        RespValue::Integer(alloc.resident as i64),
+        bulk("allocator-fragmentation.ratio"),
+        bulk(&format!("{:.2}", alloc.fragmentation())),
+        bulk("rss"),
+        RespValue::Integer(rss as i64),
+        bulk("fragmentation"),
+        bulk(&format!("{:.2}", rss as f64 / dataset.max(1) as f64)),
+    ]))
+}
+
+fn scan(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let (cursor, count, pattern, type_filter) = match parse_scan(args) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+    let (next, mut keys) = match ctx.db.scan_until(cursor, count, pattern.as_deref(), ctx.deadline) {
+        Some(page) => page,
+        None => return CommandError::Busy.into(),
+    };
+    if let Some(t) = type_filter {
+        keys.retain(|k| ctx.db.key_type(k) == t);
+    }
+    let keys = keys
+        .into_iter()
+        .map(|k| RespValue::BulkString(Some(k.into_bytes())))
+        .collect();
+    RespValue::Array(Some(vec![
+        RespValue::BulkString(Some(next.to_string().into_bytes())),
+        RespValue::Array(Some(keys)),
+    ]))
+}
@@ -0,0 +1,844 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use crate::acl::Denial;
+use crate::allocator::MemoryAccounting;
+use crate::audit::AuditEntry;
+use crate::backing::{BackingOps, BackingWrite};
+use crate::client::ClientState;
+use crate::cluster::key_slot;
+use crate::config::Settings;
+use crate::db::{BlockRequest, Database, EvictionPolicy, SetCondition};
+use crate::latency::SlowLog;
+use crate::error::CommandError;
+use crate::namespace;
+use crate::proxy::{self, Forward, ProxyOps};
+use crate::resp::RespValue;
+use crate::clock::Clock;
+
+mod acl;
+mod cluster;
+mod config;
+mod keys;
+mod persistence;
+mod pubsub;
+mod replication;
+mod server;
+mod spec;
+mod strings;
+
+pub(crate) use spec::{flags, CommandSpec, KeySpec};
+
+fn resp_ok() -> RespValue {
+    RespValue::SimpleString("OK".to_string())
+}
+fn resp_err(msg: &str) -> RespValue {
+    CommandError::generic(msg).into()
+}
+fn resp_pong() -> RespValue {
+    RespValue::SimpleString("PONG".to_string())
+}
+
+fn lower_ascii(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len());
+    for &b in bytes {
+        s.push((b as char).to_ascii_lowercase());
+    }
+    s
+}
+
+// An argument's bytes, borrowed from the frame. Keys that are only looked
+// up and options that are only compared are read through these; the
+// `bulk_to_*` copies are for what gets stored.
+fn arg_bytes(arg: &RespValue) -> Option<&[u8]> {
+    match arg {
+        RespValue::BulkString(Some(b)) => Some(b),
+        RespValue::SimpleString(s) => Some(s.as_bytes()),
+        _ => None,
+    }
+}
+
+// An argument as text, borrowed unless it is not valid UTF-8
+fn arg_str(arg: &RespValue) -> Option<Cow<'_, str>> {
+    arg_bytes(arg).map(String::from_utf8_lossy)
+}
+
+fn bulk_to_string_lossy(arg: &RespValue) -> Option<String> {
+    arg_str(arg).map(Cow::into_owned)
+}
+
+fn bulk_to_bytes(arg: &RespValue) -> Option<Vec<u8>> {
+    arg_bytes(arg).map(<[u8]>::to_vec)
+}
+
+// An argument's bytes moved out of the frame, which is left holding an
+// empty string; only for a handler whose arguments are not kept
+fn take_bytes(arg: &mut RespValue) -> Option<Vec<u8>> {
+    match arg {
+        RespValue::BulkString(Some(b)) => Some(std::mem::take(b)),
+        RespValue::SimpleString(s) => Some(std::mem::take(s).into_bytes()),
+        _ => None,
+    }
+}
+
+/// SET's arguments: the key, the value, the TTL from EX, PX, EXAT or PXAT,
+/// how long the key may go unread from MAXIDLE, and what the key must hold
+/// for the write to happen, from IFEQ (a value) or IFVERSION (a version from
+/// GET WITHVERSION).
+struct SetArgs {
+    key: String,
+    value: Vec<u8>,
+    ttl: Option<std::time::Duration>,
+    max_idle: Option<std::time::Duration>,
+    condition: Option<SetCondition>,
+}
+
+/// With `take`, the value and any IFEQ value are moved out of `args`
+/// rather than copied.
+fn parse_set(clock: &Clock, args: &mut [RespValue], take: bool) -> Result<SetArgs, RespValue> {
+    let bytes = |arg: &mut RespValue| if take { take_bytes(arg) } else { bulk_to_bytes(arg) };
+    if args.len() < 2 {
+        return Err(CommandError::WrongArity("set".into()).into());
+    }
+    let key = match bulk_to_string_lossy(&args[0]) {
+        Some(s) => s,
+        None => return Err(resp_err("invalid key")),
+    };
+    let value = match bytes(&mut args[1]) {
+        Some(v) => v,
+        None => return Err(resp_err("invalid value")),
+    };
+    let mut parsed = SetArgs { key, value, ttl: None, max_idle: None, condition: None };
+    for pair in args[2..].chunks_mut(2) {
+        let [opt, arg] = pair else {
+            return Err(CommandError::Syntax.into());
+        };
+        let opt = arg_bytes(opt).unwrap_or_default();
+        let is = |name: &str| opt.eq_ignore_ascii_case(name.as_bytes());
+        if let Some(unit) = ["EX", "PX", "EXAT", "PXAT"].into_iter().find(|u| is(u)).filter(|_| parsed.ttl.is_none()) {
+            let n: i64 = arg_str(arg)
+                .and_then(|n| n.parse().ok())
+                .ok_or_else(|| RespValue::from(CommandError::NotInteger))?;
+            // A time already past stores the key expired
+            let ms = ttl_millis(clock, unit, n).unwrap_or(0).max(0);
+            parsed.ttl = Some(std::time::Duration::from_millis(ms as u64));
+        } else if is("MAXIDLE") && parsed.max_idle.is_none() {
+            let secs: u64 = match arg_str(arg).and_then(|n| n.parse().ok()) {
+                Some(secs) if secs > 0 => secs,
+                _ => return Err(resp_err("invalid maxidle in 'set' command")),
+            };
+            parsed.max_idle = Some(std::time::Duration::from_secs(secs));
+        } else if is("IFEQ") && parsed.condition.is_none() {
+            parsed.condition = bytes(arg).map(SetCondition::Equals);
+        } else if is("IFVERSION") && parsed.condition.is_none() {
+            let version = arg_str(arg)
+                .and_then(|v| v.parse().ok())
+                .ok_or_else(|| RespValue::from(CommandError::NotInteger))?;
+            parsed.condition = Some(SetCondition::Version(version));
+        } else {
+            return Err(CommandError::Syntax.into());
+        }
+    }
+    Ok(parsed)
+}
+
+/// Milliseconds from now until the expiry `n` means in `unit`: EX and PX are
+/// relative seconds and milliseconds, EXAT and PXAT Unix times. Negative
+/// when the time is already past.
+fn ttl_millis(clock: &Clock, unit: &str, n: i64) -> Option<i64> {
+    match unit {
+        "EX" => Some(n.saturating_mul(1000)),
+        "PX" => Some(n),
+        "EXAT" => Some(n.saturating_mul(1000).saturating_sub(clock.unix_ms())),
+        "PXAT" => Some(n.saturating_sub(clock.unix_ms())),
+        _ => None,
+    }
+}
+
+fn parse_scan(args: &[RespValue]) -> Result<(usize, usize, Option<String>, Option<String>), RespValue> {
+    if args.is_empty() {
+        return Err(CommandError::WrongArity("scan".into()).into());
+    }
+    let cursor: usize = match bulk_to_string_lossy(&args[0]).and_then(|s| s.parse().ok()) {
+        Some(c) => c,
+        None => return Err(resp_err("invalid cursor")),
+    };
+    let mut count = 10usize;
+    let mut pattern = None;
+    let mut type_filter = None;
+    let mut i = 1usize;
+    while i < args.len() {
+        let opt = bulk_to_string_lossy(&args[i]).unwrap_or_default();
+        let val = match args.get(i + 1).and_then(bulk_to_string_lossy) {
+            Some(v) => v,
+            None => return Err(CommandError::Syntax.into()),
+        };
+        if opt.eq_ignore_ascii_case("COUNT") {
+            count = match val.parse::<usize>() {
+                Ok(c) if c > 0 => c,
+                _ => return Err(CommandError::NotInteger.into()),
+            };
+        } else if opt.eq_ignore_ascii_case("MATCH") {
+            pattern = Some(val);
+        } else if opt.eq_ignore_ascii_case("TYPE") {
+            type_filter = Some(val.to_ascii_lowercase());
+        } else {
+            return Err(CommandError::Syntax.into());
+        }
+        i += 2;
+    }
+    Ok((cursor, count, pattern, type_filter))
+}
+
+fn command_to_string(args: &[RespValue]) -> Option<String> {
+    if args.is_empty() {
+        return None;
+    }
+    match &args[0] {
+        RespValue::BulkString(Some(b)) => Some(lower_ascii(b)),
+        RespValue::SimpleString(s) => Some(s.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// Per-command view of the server handed to handlers and middleware.
+pub(crate) struct Context<'a> {
+    /// The database selected by the client.
+    pub db: &'a Database,
+    /// Every logical database, indexed as SELECT numbers them.
+    pub dbs: &'a [Database],
+    pub settings: &'a Settings,
+    pub registry: &'a Registry,
+    pub client: &'a mut ClientState,
+    /// Set by a blocking command that cannot be served yet; its reply is then
+    /// discarded and the command is retried when one of the keys is written.
+    /// Middleware admits it once: the `after` hooks wait until it is served or
+    /// times out, and retries run only the handler.
+    pub block: Option<BlockRequest>,
+    /// Present when a backing store fronts the selected database: misses and
+    /// writes recorded here are loaded or forwarded once the command returns.
+    pub backing: Option<BackingOps>,
+    /// When a cancellable command should give up with BUSY; set per command
+    /// from busy-reply-threshold.
+    pub deadline: Option<Instant>,
+    /// Present in proxy mode: commands bound for the backends are left here,
+    /// after auth and namespacing, instead of running locally.
+    pub proxy: Option<ProxyOps>,
+    /// Set by a command whose work is persistence I/O. The connection runs
+    /// it on the I/O pool and replies with its result instead of the
+    /// handler's reply.
+    pub io: Option<IoJob>,
+    /// Whether the command's arguments are read again once its handler
+    /// returns, by the caller or by middleware. When they are not, a handler
+    /// registered with [`Registry::register_taking`] moves its values out of
+    /// them instead of copying them.
+    pub keep_args: bool,
+    /// The arguments as the slowlog shows them, for a command that may have
+    /// taken its values by the time it is known to be slow.
+    pub slowlog_args: Option<Vec<String>>,
+}
+
+impl Context<'_> {
+    fn missed(&mut self, key: &str) {
+        if let Some(ops) = self.backing.as_mut() {
+            ops.misses.push(key.to_string());
+        }
+    }
+
+    // Takes a closure so nothing is copied for a backing store there isn't
+    fn wrote(&mut self, write: impl FnOnce() -> BackingWrite) {
+        if let Some(ops) = self.backing.as_mut() {
+            ops.writes.push(write());
+        }
+    }
+}
+
+pub(crate) type IoJob = Box<dyn FnOnce() -> RespValue + Send>;
+
+pub(crate) type Handler = Box<dyn Fn(&mut Context<'_>, &mut [RespValue]) -> RespValue + Send + Sync>;
+
+/// Cross-cutting hooks run around every known command (auth, metrics, slowlog,
+/// propagation). Middleware runs in registration order.
+pub(crate) trait Middleware: Send + Sync {
+    /// Returning a reply rejects the command with it; the handler is not called.
+    fn before(&self, _ctx: &mut Context<'_>, _cmd: &str, _spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        None
+    }
+
+    /// Whether `after` reads arguments other than the command's keys, which
+    /// handlers then leave in place.
+    fn reads_args(&self, _ctx: &Context<'_>) -> bool {
+        false
+    }
+
+    fn after(
+        &self,
+        _ctx: &mut Context<'_>,
+        _cmd: &str,
+        _spec: &CommandSpec,
+        _args: &[RespValue],
+        _reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+    }
+}
+
+/// Rejects everything but `no-auth` commands until the connection has a
+/// user, then whatever the user's command rules do not allow.
+struct RequireAuth;
+
+impl Middleware for RequireAuth {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if spec.has(flags::NOAUTH) {
+            return None;
+        }
+        match &ctx.client.user {
+            None => Some(CommandError::NoAuth.into()),
+            Some(user) if !user.can_run(cmd, spec.flags) => {
+                ctx.settings.acl_log().record(Denial::Command, cmd, &user.name, ctx.client.info());
+                let denied = CommandError::NoPerm {
+                    user: user.name.clone(),
+                    command: cmd.to_string(),
+                };
+                Some(denied.into())
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+/// Throttles users over their ACL quotas, and charges them for each command
+/// and reply.
+struct Quotas;
+
+impl Middleware for Quotas {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, _spec: &CommandSpec, args: &[RespValue]) -> Option<RespValue> {
+        let user = ctx.client.user.as_ref().filter(|u| !u.quota.is_unlimited())?;
+        // The command as it came in: the array header, the name, the arguments
+        let name = RespValue::BulkString(Some(cmd.as_bytes().to_vec()));
+        let header = (args.len() + 1).to_string().len() + 3;
+        let bytes = header + name.encoded_len() + args.iter().map(RespValue::encoded_len).sum::<usize>();
+        let quota = ctx.settings.quotas().charge_command(user, bytes).err()?;
+        ctx.settings.acl_log().record(Denial::Quota, quota, &user.name, ctx.client.info());
+        let throttled = CommandError::Throttled {
+            user: user.name.clone(),
+            quota,
+        };
+        Some(throttled.into())
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        _cmd: &str,
+        _spec: &CommandSpec,
+        _args: &[RespValue],
+        reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        if let Some(user) = ctx.client.user.as_ref().filter(|u| u.quota.bytes_out > 0) {
+            ctx.settings.quotas().charge_reply(user, reply.encoded_len());
+        }
+    }
+}
+
+/// Holds a connection with subscriptions to the commands that manage them,
+/// as its replies would otherwise be mixed in with the messages.
+struct SubscribedMode;
+
+// What a subscribed connection may still run
+const SUBSCRIBED_COMMANDS: &[&str] = &["subscribe", "unsubscribe", "psubscribe", "punsubscribe", "ssubscribe", "sunsubscribe", "ping", "quit", "reset"];
+
+impl Middleware for SubscribedMode {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, _spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if ctx.client.subscriber.is_none() || SUBSCRIBED_COMMANDS.contains(&cmd) {
+            return None;
+        }
+        let msg = format!("Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context", cmd);
+        Some(resp_err(&msg))
+    }
+}
+
+/// Refuses writes from clients while this server is a read-only replica;
+/// the replication stream itself is applied around the dispatcher.
+struct ReadOnlyReplica;
+
+impl Middleware for ReadOnlyReplica {
+    fn before(&self, ctx: &mut Context<'_>, _cmd: &str, spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if spec.has(flags::WRITE) && ctx.settings.read_only() {
+            Some(CommandError::ReadOnly.into())
+        } else {
+            None
+        }
+    }
+}
+
+/// With replica-serve-stale-data off, refuses commands while the link to the
+/// master is down rather than answer from data that may be out of date.
+struct StaleReplica;
+
+// What a replica cut off from its master still runs
+const STALE_COMMANDS: &[&str] = &["info", "config", "ping", "auth", "hello", "quit", "reset", "replconf", "shutdown"];
+
+impl Middleware for StaleReplica {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, _spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if ctx.settings.refuse_stale() && !ctx.db.stats.replica.link_up.load(Ordering::Relaxed) && !STALE_COMMANDS.contains(&cmd) {
+            Some(CommandError::MasterDown.into())
+        } else {
+            None
+        }
+    }
+}
+
+/// With cluster-enabled, refuses multi-key commands whose keys hash to
+/// different slots, as a cluster node would, and redirects those for a slot
+/// another node serves.
+struct SameSlot;
+
+impl Middleware for SameSlot {
+    fn before(&self, ctx: &mut Context<'_>, _cmd: &str, spec: &CommandSpec, args: &[RespValue]) -> Option<RespValue> {
+        if !ctx.settings.cluster_enabled() {
+            return None;
+        }
+        let mut slots = key_args(spec, args).into_iter().map(key_slot);
+        let first = slots.next()?;
+        if !slots.all(|s| s == first) {
+            return Some(CommandError::CrossSlot.into());
+        }
+        let addr = ctx.db.stats.gossip.get()?.owner(first)?;
+        Some(CommandError::Moved { slot: first, addr }.into())
+    }
+}
+
+// The key arguments of a command, as far as its spec knows them
+fn key_args<'a>(spec: &CommandSpec, args: &'a [RespValue]) -> Vec<&'a [u8]> {
+    if spec.keys == KeySpec::NONE {
+        return Vec::new();
+    }
+    let keys = spec.keys.positions(args.len());
+    keys.into_iter()
+        .filter_map(|i| match &args[i] {
+            RespValue::BulkString(Some(b)) => Some(b.as_slice()),
+            RespValue::SimpleString(s) => Some(s.as_bytes()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Enforces the selected database's maxmemory budget on `denyoom` commands:
+/// they evict first and are refused with OOM if the database cannot get back
+/// under its budget. Each database evicts only its own keys, so one tenant
+/// filling its database cannot push out another's.
+struct MemoryLimit;
+
+impl Middleware for MemoryLimit {
+    fn before(&self, ctx: &mut Context<'_>, _cmd: &str, spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if !spec.has(flags::DENYOOM) {
+            return None;
+        }
+        let (limit, policy) = memory_budget(ctx);
+        if ctx.db.evict_to_fit(limit, policy) {
+            None
+        } else {
+            ctx.db.stats.command_refused_for_memory();
+            Some(CommandError::Oom.into())
+        }
+    }
+
+    // Reads fault spilled values back in, so a cold tier is rebalanced after
+    // every command rather than only before writes
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        _cmd: &str,
+        _spec: &CommandSpec,
+        _args: &[RespValue],
+        _reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        if ctx.db.has_overflow() {
+            let (limit, policy) = memory_budget(ctx);
+            ctx.db.evict_to_fit(limit, policy);
+        }
+    }
+}
+
+// The selected database's budget for its keys and values: under process
+// accounting, less its share of the overhead
+fn memory_budget(ctx: &Context<'_>) -> (usize, EvictionPolicy) {
+    let (limit, policy) = ctx.settings.memory_budget(ctx.client.db);
+    if limit == 0 || ctx.settings.memory_accounting() == MemoryAccounting::Dataset {
+        return (limit, policy);
+    }
+    let overhead = ctx.db.stats.overhead_share(ctx.db.used_memory());
+    // Never down to 0, which would read as unlimited
+    (limit.saturating_sub(overhead).max(1), policy)
+}
+
+/// Counts executed commands for INFO, and notes each client's latest one
+/// for CLIENT LIST.
+struct CommandStats;
+
+impl Middleware for CommandStats {
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        _spec: &CommandSpec,
+        _args: &[RespValue],
+        _reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        ctx.db.stats.command_processed();
+        if let Some(entry) = &ctx.client.entry {
+            entry.ran(cmd, ctx.client);
+        }
+    }
+}
+
+struct Command {
+    spec: CommandSpec,
+    handler: Handler,
+    // Registered with register_taking
+    takes_args: bool,
+}
+
+/// Feeds the keys of sampled commands to the hot key tracker.
+struct HotKeyTracking;
+
+impl Middleware for HotKeyTracking {
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        _cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        _reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        let names = key_args(spec, args);
+        if !names.is_empty() {
+            ctx.db.stats.hotkeys.record(ctx.settings.hotkeys_sample(), ctx.client.db, names.into_iter());
+        }
+    }
+}
+
+/// Records a sample of the commands that name keys in the audit log, keys
+/// as stored.
+struct Audit;
+
+impl Middleware for Audit {
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        let names = key_args(spec, args);
+        if names.is_empty() || !ctx.settings.audit().sampled(ctx.settings.audit_sample()) {
+            return;
+        }
+        ctx.settings.audit().record(AuditEntry {
+            id: 0,
+            at_ms: 0,
+            client_id: ctx.client.entry.as_ref().map(|e| e.id).unwrap_or(0),
+            username: ctx.client.user.as_ref().map(|u| u.name.clone()).unwrap_or_default(),
+            client_info: ctx.client.info(),
+            db: ctx.client.db,
+            command: cmd.to_string(),
+            keys: names.into_iter().map(|k| String::from_utf8_lossy(k).into_owned()).collect(),
+            write: spec.has(flags::WRITE),
+            error: matches!(reply, RespValue::Error(_)),
+        });
+    }
+}
+
+/// Logs commands slower than latency-monitor-threshold and records them as
+/// LATENCY events; those slower than slowlog-log-slower-than go to the SLOWLOG.
+struct Watchdog;
+
+impl Middleware for Watchdog {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, _spec: &CommandSpec, args: &[RespValue]) -> Option<RespValue> {
+        // Rendered now, while the handler has yet to take the values
+        if !ctx.keep_args && ctx.settings.slowlog_threshold().is_some() {
+            ctx.slowlog_args = Some(SlowLog::logged(cmd, args));
+        }
+        None
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        _reply: &RespValue,
+        elapsed: Duration,
+    ) {
+        if ctx.settings.slowlog_threshold().is_some_and(|t| elapsed >= t) {
+            let addr = ctx.client.addr.map(|a| a.to_string()).unwrap_or_default();
+            let logged = ctx.slowlog_args.take().unwrap_or_else(|| SlowLog::logged(cmd, args));
+            ctx.settings.slowlog().record(logged, elapsed, addr);
+        }
+        if ctx.settings.latency_threshold().is_none_or(|t| elapsed < t) {
+            return;
+        }
+        let ms = elapsed.as_millis() as u64;
+        let event = if spec.has(flags::FAST) { "fast-command" } else { "command" };
+        ctx.db.stats.latency.record(event, ms);
+        eprintln!("Slow command: {} took {} ms (db {}, {})", cmd, ms, ctx.client.db, ctx.client.info());
+    }
+}
+
+pub(crate) struct Registry {
+    commands: HashMap<String, Command>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self {
+            commands: HashMap::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    /// The built-in command table and middleware.
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        strings::register(&mut registry);
+        keys::register(&mut registry);
+        server::register(&mut registry);
+        config::register(&mut registry);
+        acl::register(&mut registry);
+        persistence::register(&mut registry);
+        cluster::register(&mut registry);
+        pubsub::register(&mut registry);
+        replication::register(&mut registry);
+        registry.add_middleware(Box::new(RequireAuth));
+        registry.add_middleware(Box::new(Quotas));
+        registry.add_middleware(Box::new(SubscribedMode));
+        registry.add_middleware(Box::new(StaleReplica));
+        registry.add_middleware(Box::new(ReadOnlyReplica));
+        registry.add_middleware(Box::new(SameSlot));
+        registry.add_middleware(Box::new(MemoryLimit));
+        registry.add_middleware(Box::new(CommandStats));
+        registry.add_middleware(Box::new(HotKeyTracking));
+        registry.add_middleware(Box::new(Audit));
+        registry.add_middleware(Box::new(Watchdog));
+        registry
+    }
+
+    /// Registers `handler` under `name` (case-insensitive), replacing any previous entry.
+    pub fn register<F>(&mut self, name: &str, spec: CommandSpec, handler: F)
+    where
+        F: Fn(&mut Context<'_>, &[RespValue]) -> RespValue + Send + Sync + 'static,
+    {
+        let handler = Box::new(move |ctx: &mut Context<'_>, args: &mut [RespValue]| handler(ctx, args));
+        self.commands.insert(name.to_ascii_lowercase(), Command { spec, handler, takes_args: false });
+    }
+
+    /// Like [`register`](Self::register), for a handler that stores its
+    /// arguments: unless `ctx.keep_args` is set it may move their values out
+    /// of the frame, though never its keys, which middleware still reads.
+    pub fn register_taking<F>(&mut self, name: &str, spec: CommandSpec, handler: F)
+    where
+        F: Fn(&mut Context<'_>, &mut [RespValue]) -> RespValue + Send + Sync + 'static,
+    {
+        let handler = Box::new(handler);
+        self.commands.insert(name.to_ascii_lowercase(), Command { spec, handler, takes_args: true });
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.commands.contains_key(&name.to_ascii_lowercase())
+    }
+
+    pub fn spec(&self, name: &str) -> Option<&CommandSpec> {
+        self.commands.get(&name.to_ascii_lowercase()).map(|c| &c.spec)
+    }
+
+    /// Every command with its spec, in no particular order.
+    pub fn specs(&self) -> impl Iterator<Item = (&str, &CommandSpec)> {
+        self.commands.iter().map(|(name, c)| (name.as_str(), &c.spec))
+    }
+
+    // The command named by `frame`, if it exists and the arity fits
+    fn lookup(&self, frame: &[RespValue]) -> Result<(String, &Command), RespValue> {
+        let cmd = match command_to_string(frame) {
+            Some(c) => c,
+            None => return Err(resp_err("invalid command name")),
+        };
+        let command = match self.commands.get(&cmd) {
+            Some(c) => c,
+            None => return Err(CommandError::UnknownCommand(cmd).into()),
+        };
+        if !command.spec.arity_ok(frame.len()) {
+            return Err(CommandError::WrongArity(cmd).into());
+        }
+        Ok((cmd, command))
+    }
+
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    /// Runs a handler directly, skipping middleware and namespacing; for calls
+    /// the server makes itself, such as a command-backed store.
+    pub fn call(&self, ctx: &mut Context<'_>, frame: &mut [RespValue]) -> RespValue {
+        match self.lookup(frame) {
+            Ok((_, command)) => {
+                ctx.keep_args |= !command.takes_args;
+                (command.handler)(ctx, &mut frame[1..])
+            }
+            Err(e) => e,
+        }
+    }
+
+    /// The key arguments of a command frame, as far as its spec knows them;
+    /// none if it isn't a valid command.
+    pub(crate) fn keys<'a>(&self, frame: &'a RespValue) -> Vec<&'a [u8]> {
+        match frame {
+            RespValue::Array(Some(items)) if !items.is_empty() => match self.lookup(items) {
+                Ok((_, command)) => key_args(&command.spec, &items[1..]),
+                Err(_) => Vec::new(),
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn dispatch(&self, ctx: &mut Context<'_>, frame: &mut RespValue) -> RespValue {
+        let arr = match frame {
+            RespValue::Array(Some(items)) => items,
+            _ => return resp_err("protocol error: expected command array"),
+        };
+        if arr.is_empty() {
+            return resp_err("protocol error: empty command");
+        }
+        let (cmd, command) = match self.lookup(arr) {
+            Ok(found) => found,
+            Err(e) => return e,
+        };
+        ctx.keep_args |= !command.takes_args || self.middleware.iter().any(|m| m.reads_args(ctx));
+        let (head, args) = arr.split_first_mut().expect("checked non-empty");
+        for m in &self.middleware {
+            if let Some(reply) = m.before(ctx, &cmd, &command.spec, args) {
+                return reply;
+            }
+        }
+        // Namespaced users see their keys without the prefix; commands whose
+        // key positions are unknown are refused rather than risk a leak.
+        let user = ctx.client.user.clone();
+        let namespace = user.as_ref().and_then(|u| u.namespace.as_deref().map(|ns| (u.name.as_str(), ns)));
+        let mut rewritten;
+        let args = match namespace {
+            Some((name, ns)) => {
+                rewritten = match namespace::apply(name, ns, &cmd, &command.spec, args) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        ctx.settings.acl_log().record(Denial::Command, &cmd, name, ctx.client.info());
+                        return e;
+                    }
+                };
+                &mut rewritten[..]
+            }
+            None => args,
+        };
+        if let Some(ops) = ctx.proxy.as_mut() {
+            if !proxy::runs_locally(&cmd, &command.spec) {
+                let mut frame = Vec::with_capacity(args.len() + 1);
+                frame.push(head.clone());
+                frame.extend_from_slice(args);
+                let namespace = namespace.map(|(_, ns)| ns.to_string());
+                ops.forward = Some(Forward {
+                    cmd,
+                    spec: command.spec,
+                    frame,
+                    namespace,
+                });
+                // Replaced by the backends' reply
+                return RespValue::BulkString(None);
+            }
+        }
+        let started = Instant::now();
+        ctx.deadline = ctx.settings.busy_reply_threshold().map(|t| started + t);
+        let mut reply = (command.handler)(ctx, args);
+        let elapsed = started.elapsed();
+        if ctx.block.is_some() {
+            // Discarded; see `resume`
+            return reply;
+        }
+        if let Some((_, ns)) = namespace {
+            reply = namespace::strip_reply(ns, &cmd, reply);
+        }
+        for m in &self.middleware {
+            m.after(ctx, &cmd, &command.spec, args, &reply, elapsed);
+        }
+        reply
+    }
+
+    /// Runs a blocked command's handler again, namespaced as `dispatch` does,
+    /// once one of its keys is written. If that serves it, the `after` hooks
+    /// held back when it blocked run with the reply.
+    pub(crate) fn retry(&self, ctx: &mut Context<'_>, frame: &mut RespValue) -> RespValue {
+        self.resume(ctx, frame, None)
+    }
+
+    /// Ends a blocked command with the reply it times out with, running the
+    /// `after` hooks held back when it blocked.
+    pub(crate) fn time_out(&self, ctx: &mut Context<'_>, frame: &mut RespValue, reply: RespValue) -> RespValue {
+        self.resume(ctx, frame, Some(reply))
+    }
+
+    // The `before` hooks admitted the command when it was dispatched, and
+    // running them again would count it again in stats, quotas and the audit
+    // log, so a blocked command goes on from its handler
+    fn resume(&self, ctx: &mut Context<'_>, frame: &mut RespValue, timed_out: Option<RespValue>) -> RespValue {
+        let arr = match frame {
+            RespValue::Array(Some(items)) if !items.is_empty() => items,
+            _ => return resp_err("protocol error: expected command array"),
+        };
+        let (cmd, command) = match self.lookup(arr) {
+            Ok(found) => found,
+            Err(e) => return e,
+        };
+        let args = &mut arr[1..];
+        let user = ctx.client.user.clone();
+        let namespace = user.as_ref().and_then(|u| u.namespace.as_deref().map(|ns| (u.name.as_str(), ns)));
+        let mut rewritten;
+        let args = match namespace {
+            Some((name, ns)) => {
+                rewritten = match namespace::apply(name, ns, &cmd, &command.spec, args) {
+                    Ok(a) => a,
+                    Err(e) => return e,
+                };
+                &mut rewritten[..]
+            }
+            None => args,
+        };
+        let started = Instant::now();
+        let reply = match timed_out {
+            Some(reply) => reply,
+            None => {
+                ctx.deadline = ctx.settings.busy_reply_threshold().map(|t| started + t);
+                let reply = (command.handler)(ctx, args);
+                if ctx.block.is_some() {
+                    return reply;
+                }
+                match namespace {
+                    Some((_, ns)) => namespace::strip_reply(ns, &cmd, reply),
+                    None => reply,
+                }
+            }
+        };
+        let elapsed = started.elapsed();
+        for m in &self.middleware {
+            m.after(ctx, &cmd, &command.spec, args, &reply, elapsed);
+        }
+        reply
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use super::flags::{ADMIN, FAST};
+use super::{bulk_to_string_lossy, resp_err, resp_ok, CommandSpec, Context, Registry};
+use crate::db::Database;
+use crate::rdb::write_rdb;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+use crate::snapshot::write_snapshot;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("save", CommandSpec::new(-1, &[ADMIN]), save);
+    registry.register("bgsave", CommandSpec::new(-1, &[ADMIN]), bgsave);
+    registry.register("lastsave", CommandSpec::new(1, &[FAST]), lastsave);
+}
+
+type Writer = Box<dyn FnOnce(&[Database], &Path) -> io::Result<usize> + Send>;
+
+// The file and format a save writes: the native snapshot, or with `RDB`, a
+// Redis RDB export
+fn target(ctx: &Context<'_>, args: &[RespValue], name: &str) -> Result<(PathBuf, Writer), RespValue> {
+    let config = ctx.settings.current();
+    match args {
+        [] => match config.snapshot_file {
+            Some(p) => Ok((p, Box::new(move |dbs, path| write_snapshot(dbs, path, config.compression)))),
+            None => Err(resp_err("no dbfilename configured")),
+        },
+        [format] if bulk_to_string_lossy(format).is_some_and(|f| f.eq_ignore_ascii_case("rdb")) => {
+            match config.rdb_file {
+                Some(p) => Ok((p, Box::new(write_rdb))),
+                None => Err(resp_err("no rdbfilename configured")),
+            }
+        }
+        [_] => Err(CommandError::Syntax.into()),
+        _ => Err(CommandError::WrongArity(name.to_string()).into()),
+    }
+}
+
+fn save(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let (path, write) = match target(ctx, args, "save") {
+        Ok(t) => t,
+        Err(e) => return e,
+    };
+    let stats = ctx.db.stats.clone();
+    if stats.bgsave_in_progress.load(Ordering::SeqCst) {
+        return resp_err("Background save already in progress");
+    }
+    // The client waits for the reply, but not on a command worker
+    let dbs = ctx.dbs.to_vec();
+    ctx.io = Some(Box::new(move || {
+        let result = write(&dbs, &path);
+        stats.save_finished(result.is_ok());
+        match result {
+            Ok(_) => resp_ok(),
+            Err(e) => resp_err(&format!("snapshot failed: {}", e)),
+        }
+    }));
+    resp_ok()
+}
+
+fn bgsave(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let (path, write) = match target(ctx, args, "bgsave") {
+        Ok(t) => t,
+        Err(e) => return e,
+    };
+    let stats = ctx.db.stats.clone();
+    if stats.bgsave_in_progress.swap(true, Ordering::SeqCst) {
+        return resp_err("Background save already in progress");
+    }
+    let dbs = ctx.dbs.to_vec();
+    let io = stats.clone();
+    io.persistence_io.spawn(move || {
+        let result = write(&dbs, &path);
+        match &result {
+            Ok(keys) => println!("Background save of {} keys to {} done", keys, path.display()),
+            Err(e) => eprintln!("Background save to {} failed: {}", path.display(), e),
+        }
+        stats.save_finished(result.is_ok());
+        stats.bgsave_in_progress.store(false, Ordering::SeqCst);
+    });
+    RespValue::SimpleString("Background saving started".to_string())
+}
+
+fn lastsave(ctx: &mut Context<'_>, _args: &[RespValue]) -> RespValue {
+    RespValue::Integer(ctx.db.stats.last_save_time.load(Ordering::Relaxed) as i64)
+}
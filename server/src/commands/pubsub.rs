@@ -0,0 +1,109 @@
+use super::flags::{FAST, PUBSUB};
+use super::{arg_bytes, bulk_to_string_lossy, resp_err, CommandSpec, Context, Registry};
+use crate::client::ClientState;
+use crate::error::CommandError;
+use crate::pubsub::{Kind, Subscriber};
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("subscribe", CommandSpec::new(-2, &[PUBSUB]), |ctx, args| join(ctx, args, Kind::Channel, "subscribe"));
+    registry.register("psubscribe", CommandSpec::new(-2, &[PUBSUB]), |ctx, args| join(ctx, args, Kind::Pattern, "psubscribe"));
+    registry.register("ssubscribe", CommandSpec::new(-2, &[PUBSUB]), |ctx, args| join(ctx, args, Kind::Shard, "ssubscribe"));
+    registry.register("unsubscribe", CommandSpec::new(-1, &[PUBSUB]), |ctx, args| leave(ctx, args, Kind::Channel, "unsubscribe"));
+    registry.register("punsubscribe", CommandSpec::new(-1, &[PUBSUB]), |ctx, args| leave(ctx, args, Kind::Pattern, "punsubscribe"));
+    registry.register("sunsubscribe", CommandSpec::new(-1, &[PUBSUB]), |ctx, args| leave(ctx, args, Kind::Shard, "sunsubscribe"));
+    registry.register("publish", CommandSpec::new(3, &[PUBSUB, FAST]), publish);
+    registry.register("spublish", CommandSpec::new(3, &[PUBSUB, FAST]), spublish);
+    registry.register("pubsub", CommandSpec::new(-2, &[PUBSUB]), pubsub);
+}
+
+fn bulk(b: &[u8]) -> RespValue {
+    RespValue::BulkString(Some(b.to_vec()))
+}
+
+// What (P|S)(UN)SUBSCRIBE answers for each name: the command, the name and
+// the subscriptions the connection holds afterwards
+fn confirmation(reply: &str, name: Option<&[u8]>, count: usize) -> RespValue {
+    let name = name.map_or(RespValue::BulkString(None), bulk);
+    RespValue::Array(Some(vec![bulk(reply.as_bytes()), name, RespValue::Integer(count as i64)]))
+}
+
+// The first confirmation is the command's reply; the others follow it
+fn confirmed(client: &mut ClientState, mut replies: Vec<RespValue>) -> RespValue {
+    client.extra_replies = replies.split_off(1);
+    replies.pop().expect("one reply per name, and at least one name")
+}
+
+// (P|S)SUBSCRIBE name [name ...]
+fn join(ctx: &mut Context<'_>, args: &[RespValue], kind: Kind, reply: &str) -> RespValue {
+    let Some(names) = args.iter().map(arg_bytes).collect::<Option<Vec<_>>>() else {
+        return resp_err("invalid channel");
+    };
+    let stats = ctx.db.stats.clone();
+    let subscriber = ctx.client.subscriber.get_or_insert_with(|| Subscriber::new(stats));
+    let replies = names.into_iter().map(|name| confirmation(reply, Some(name), subscriber.subscribe(kind, name))).collect();
+    confirmed(ctx.client, replies)
+}
+
+// (P|S)UNSUBSCRIBE [name ...]; with no names, from everything of that kind
+fn leave(ctx: &mut Context<'_>, args: &[RespValue], kind: Kind, reply: &str) -> RespValue {
+    let Some(subscriber) = ctx.client.subscriber.as_mut() else {
+        return confirmation(reply, None, 0);
+    };
+    let names = match args.iter().map(|a| arg_bytes(a).map(<[u8]>::to_vec)).collect::<Option<Vec<_>>>() {
+        Some(names) if names.is_empty() => subscriber.names(kind),
+        Some(names) => names,
+        None => return resp_err("invalid channel"),
+    };
+    if names.is_empty() {
+        return confirmation(reply, None, subscriber.count(kind));
+    }
+    let replies = names.iter().map(|name| confirmation(reply, Some(name), subscriber.unsubscribe(kind, name))).collect();
+    if subscriber.is_empty() {
+        ctx.client.subscriber = None;
+    }
+    confirmed(ctx.client, replies)
+}
+
+fn publish(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    match (arg_bytes(&args[0]), arg_bytes(&args[1])) {
+        (Some(channel), Some(message)) => {
+            let received = ctx.db.stats.pubsub.publish(channel, message, ctx.settings.pubsub_limit());
+            RespValue::Integer(received as i64)
+        }
+        _ => resp_err("invalid channel or message"),
+    }
+}
+
+fn spublish(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    match (arg_bytes(&args[0]), arg_bytes(&args[1])) {
+        (Some(channel), Some(message)) => {
+            let received = ctx.db.stats.pubsub.spublish(channel, message, ctx.settings.pubsub_limit());
+            RespValue::Integer(received as i64)
+        }
+        _ => resp_err("invalid channel or message"),
+    }
+}
+
+// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT
+// | SHARDCHANNELS [pattern] | SHARDNUMSUB [channel ...]
+fn pubsub(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let hub = &ctx.db.stats.pubsub;
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    let names: Vec<&[u8]> = args[1..].iter().filter_map(arg_bytes).collect();
+    let channels = |kind| RespValue::Array(Some(hub.channels(kind, names.first().copied()).iter().map(|c| bulk(c)).collect()));
+    let numsub = |kind| {
+        let counts = hub.numsub(kind, &names);
+        let pairs = names.iter().zip(counts).flat_map(|(name, n)| [bulk(name), RespValue::Integer(n as i64)]);
+        RespValue::Array(Some(pairs.collect()))
+    };
+    match (sub.as_str(), names.len()) {
+        ("channels", 0 | 1) => channels(Kind::Channel),
+        ("shardchannels", 0 | 1) => channels(Kind::Shard),
+        ("numsub", _) => numsub(Kind::Channel),
+        ("shardnumsub", _) => numsub(Kind::Shard),
+        ("numpat", 0) => RespValue::Integer(hub.count(Kind::Pattern) as i64),
+        ("channels" | "shardchannels" | "numpat", _) => CommandError::WrongArity(format!("pubsub|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'pubsub'"),
+    }
+}
@@ -0,0 +1,107 @@
+use super::flags::ADMIN;
+use super::{arg_bytes, bulk_to_string_lossy, resp_err, resp_ok, CommandSpec, Context, Registry};
+use crate::crdt::parse_state;
+use crate::error::CommandError;
+use crate::raft::decode_entries;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("replconf", CommandSpec::new(-1, &[ADMIN]), replconf);
+    registry.register("sync", CommandSpec::new(1, &[ADMIN]), sync);
+    registry.register("psync", CommandSpec::new(3, &[ADMIN]), sync);
+    registry.register("raft", CommandSpec::new(-2, &[ADMIN]), raft);
+    registry.register("crdt", CommandSpec::new(-2, &[ADMIN]), crdt);
+}
+
+// REPLCONF listening-port <port> | capa <capability> ... | ACK <offset>
+fn replconf(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let words: Vec<String> = args.iter().map(|a| bulk_to_string_lossy(a).unwrap_or_default()).collect();
+    if !words.len().is_multiple_of(2) {
+        return CommandError::Syntax.into();
+    }
+    for pair in words.chunks(2) {
+        match pair[0].to_ascii_lowercase().as_str() {
+            "listening-port" => match pair[1].parse() {
+                Ok(port) => ctx.client.listening_port = Some(port),
+                Err(_) => return resp_err("invalid listening-port"),
+            },
+            // Acknowledgements are read off the replication stream itself
+            "capa" | "ack" | "getack" | "ip-address" => {}
+            _ => return resp_err(&format!("unrecognized REPLCONF option: {}", pair[0])),
+        }
+    }
+    resp_ok()
+}
+
+// SYNC | PSYNC <replid> <offset>. There is no backlog to continue from, so
+// both get a full resync, sent once the command returns.
+fn sync(ctx: &mut Context<'_>, _args: &[RespValue]) -> RespValue {
+    ctx.client.sync = true;
+    resp_ok()
+}
+
+// RAFT VOTE <term> <candidate> <last-index> <last-term>
+// | APPEND <term> <leader> <prev-index> <prev-term> <commit> [<term> <db> <command>] ...
+// Sent between the members of a raft group; see raft.rs.
+fn raft(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let Some(raft) = ctx.db.stats.raft.get() else {
+        return resp_err("raft mode is not enabled");
+    };
+    let Some(args) = args.iter().map(|a| arg_bytes(a).map(<[u8]>::to_vec)).collect::<Option<Vec<_>>>() else {
+        return CommandError::Syntax.into();
+    };
+    let number = |i: usize| std::str::from_utf8(&args[i]).ok().and_then(|s| s.parse::<u64>().ok());
+    let int = |n: u64| RespValue::Integer(n as i64);
+    let sub = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let reply = match (sub.as_str(), args.len()) {
+        ("vote", 5) => {
+            let (Some(term), Some(last_index), Some(last_term)) = (number(1), number(3), number(4)) else {
+                return CommandError::NotInteger.into();
+            };
+            let (term, granted) = raft.vote(term, &String::from_utf8_lossy(&args[2]), last_index, last_term);
+            RespValue::Array(Some(vec![int(term), int(granted as u64)]))
+        }
+        ("append", n) if n >= 6 => {
+            let (Some(term), Some(prev_index), Some(prev_term), Some(commit)) = (number(1), number(3), number(4), number(5)) else {
+                return CommandError::NotInteger.into();
+            };
+            let Some(entries) = decode_entries(&args[6..]) else {
+                return resp_err("invalid raft entries");
+            };
+            let (term, ok, matched) = raft.append_entries(term, &String::from_utf8_lossy(&args[2]), prev_index, prev_term, commit, entries);
+            RespValue::Array(Some(vec![int(term), int(ok as u64), int(matched)]))
+        }
+        ("vote" | "append", _) => return CommandError::WrongArity(format!("raft|{}", sub)).into(),
+        _ => return resp_err("unknown subcommand for 'raft'"),
+    };
+    // Answered once the term, vote and entries it journaled are on disk:
+    // the connection waits for it
+    ctx.client.pending = Some(raft.when_synced(reply));
+    RespValue::BulkString(None)
+}
+
+// CRDT MERGE <db> <key> <ms> <node> <has-base> <base> [<node> <incr> <decr>] ...
+// Sent between the members of an active-active group; see crdt.rs.
+fn crdt(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let Some(crdt) = ctx.db.stats.crdt.get() else {
+        return resp_err("active-active mode is not enabled");
+    };
+    let Some(args) = args.iter().map(|a| arg_bytes(a).map(<[u8]>::to_vec)).collect::<Option<Vec<_>>>() else {
+        return CommandError::Syntax.into();
+    };
+    let sub = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    match (sub.as_str(), args.len()) {
+        ("merge", n) if n >= 7 => {
+            let Some((index, db)) = std::str::from_utf8(&args[1]).ok().and_then(|s| s.parse::<usize>().ok()).and_then(|i| Some((i, ctx.dbs.get(i)?))) else {
+                return resp_err("DB index is out of range");
+            };
+            let Some(state) = parse_state(&args[3..]) else {
+                return resp_err("invalid crdt state");
+            };
+            crdt.merge(db, index, &String::from_utf8_lossy(&args[2]), state);
+            resp_ok()
+        }
+        ("merge", _) => CommandError::WrongArity(format!("crdt|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'crdt'"),
+    }
+}
@@ -0,0 +1,448 @@
+use std::time::Duration;
+
+use super::flags::{ADMIN, FAST, READONLY, WRITE};
+use super::{bulk_to_bytes, bulk_to_string_lossy, resp_err, resp_ok, resp_pong, CommandSpec, Context, Registry};
+use crate::acl::categories;
+use crate::db::dataset_digest;
+use crate::diagnostics;
+use crate::hotkeys::TRACKED_KEYS;
+use crate::info::build_info;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register("ping", CommandSpec::new(-1, &[FAST]), ping);
+    registry.register("echo", CommandSpec::new(2, &[FAST]), echo);
+    registry.register("time", CommandSpec::new(1, &[FAST]), time);
+    registry.register("info", CommandSpec::new(-1, &[]), info);
+    registry.register("dbsize", CommandSpec::new(1, &[READONLY, FAST]), dbsize);
+    registry.register("flushdb", CommandSpec::new(-1, &[WRITE]), flushdb);
+    registry.register("select", CommandSpec::new(2, &[FAST]), select);
+    registry.register("command", CommandSpec::new(-1, &[]), command);
+    registry.register("hotkeys", CommandSpec::new(-1, &[ADMIN]), hotkeys);
+    registry.register("histogram", CommandSpec::new(-1, &[ADMIN]), histogram);
+    registry.register("latency", CommandSpec::new(-2, &[ADMIN]), latency);
+    registry.register("slowlog", CommandSpec::new(-2, &[ADMIN]), slowlog);
+    registry.register("audit", CommandSpec::new(-2, &[ADMIN]), audit);
+    registry.register("warmup", CommandSpec::new(2, &[ADMIN]), warmup);
+    registry.register("client", CommandSpec::new(-2, &[ADMIN]), client);
+    registry.register("debug", CommandSpec::new(-2, &[ADMIN]).keys(2, -1, 1), debug);
+}
+
+fn ping(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    // A subscribed connection gets its pong as a message would come
+    if ctx.client.subscriber.is_some() && args.len() <= 1 {
+        let echoed = args.first().and_then(bulk_to_bytes).unwrap_or_default();
+        return RespValue::Array(Some(vec![RespValue::BulkString(Some(b"pong".to_vec())), RespValue::BulkString(Some(echoed))]));
+    }
+    if args.is_empty() {
+        resp_pong()
+    } else if args.len() == 1 {
+        match bulk_to_bytes(&args[0]) {
+            Some(b) => RespValue::BulkString(Some(b)),
+            None => CommandError::WrongArity("ping".into()).into(),
+        }
+    } else {
+        CommandError::WrongArity("ping".into()).into()
+    }
+}
+
+fn echo(_ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    match bulk_to_bytes(&args[0]) {
+        Some(b) => RespValue::BulkString(Some(b)),
+        None => RespValue::BulkString(None),
+    }
+}
+
+// The server clock's unix time, as seconds and the microseconds within it
+fn time(ctx: &mut Context<'_>, _args: &[RespValue]) -> RespValue {
+    let micros = ctx.db.clock().unix_micros();
+    RespValue::Array(Some(vec![
+        RespValue::BulkString(Some((micros / 1_000_000).to_string().into_bytes())),
+        RespValue::BulkString(Some((micros % 1_000_000).to_string().into_bytes())),
+    ]))
+}
+
+fn info(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    if args.len() > 1 {
+        return CommandError::Syntax.into();
+    }
+    let section = args.first().and_then(bulk_to_string_lossy);
+    RespValue::BulkString(Some(build_info(ctx.dbs, ctx.settings, section.as_deref()).into_bytes()))
+}
+
+fn dbsize(ctx: &mut Context<'_>, _args: &[RespValue]) -> RespValue {
+    match ctx.client.namespace() {
+        Some(ns) => RespValue::Integer(ctx.db.count_prefix(ns) as i64),
+        None => RespValue::Integer(ctx.db.dbsize() as i64),
+    }
+}
+
+fn flushdb(ctx: &mut Context<'_>, _args: &[RespValue]) -> RespValue {
+    match ctx.client.namespace() {
+        Some(ns) => ctx.db.flush_prefix(ns),
+        None => ctx.db.flushdb(),
+    }
+    resp_ok()
+}
+
+fn select(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let index = match bulk_to_string_lossy(&args[0]).and_then(|s| s.parse::<i64>().ok()) {
+        Some(i) => i,
+        None => return CommandError::NotInteger.into(),
+    };
+    if index < 0 || index as usize >= ctx.dbs.len() {
+        return resp_err("DB index is out of range");
+    }
+    ctx.client.db = index as usize;
+    resp_ok()
+}
+
+fn command(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = args.first().and_then(bulk_to_string_lossy).unwrap_or_default();
+    if args.is_empty() {
+        let mut names: Vec<&str> = ctx.registry.specs().map(|(name, _)| name).collect();
+        names.sort_unstable();
+        return RespValue::Array(Some(names.into_iter().map(|n| command_info(ctx, n)).collect()));
+    }
+    if sub.eq_ignore_ascii_case("count") {
+        if args.len() != 1 {
+            return CommandError::WrongArity("command|count".into()).into();
+        }
+        return RespValue::Integer(ctx.registry.specs().count() as i64);
+    }
+    if sub.eq_ignore_ascii_case("info") {
+        let names: Vec<String> = args[1..].iter().filter_map(bulk_to_string_lossy).collect();
+        return RespValue::Array(Some(names.iter().map(|n| command_info(ctx, n)).collect()));
+    }
+    if sub.eq_ignore_ascii_case("getkeys") {
+        if args.len() < 2 {
+            return CommandError::WrongArity("command|getkeys".into()).into();
+        }
+        let name = bulk_to_string_lossy(&args[1]).unwrap_or_default();
+        let spec = match ctx.registry.spec(&name) {
+            Some(s) => s,
+            None => return resp_err("Invalid command specified"),
+        };
+        if !spec.arity_ok(args.len() - 1) {
+            return resp_err("Invalid number of arguments specified for command");
+        }
+        let keys = spec.keys.positions(args.len() - 2);
+        if keys.is_empty() {
+            return resp_err("The command has no key arguments");
+        }
+        return RespValue::Array(Some(keys.into_iter().map(|i| args[i + 2].clone()).collect()));
+    }
+    resp_err("unknown subcommand for 'command'")
+}
+
+// `[name, arity, flags, first key, last key, step, categories]`, as Redis
+// replies to COMMAND INFO; nil for unknown commands
+fn command_info(ctx: &Context<'_>, name: &str) -> RespValue {
+    let spec = match ctx.registry.spec(name) {
+        Some(s) => s,
+        None => return RespValue::BulkString(None),
+    };
+    let cmd_flags: Vec<RespValue> = spec.flags.iter().map(|f| RespValue::SimpleString(f.to_string())).collect();
+    let keys = spec.keys;
+    RespValue::Array(Some(vec![
+        RespValue::BulkString(Some(name.to_ascii_lowercase().into_bytes())),
+        RespValue::Integer(spec.arity as i64),
+        RespValue::Array(Some(cmd_flags)),
+        RespValue::Integer(keys.first as i64),
+        RespValue::Integer(keys.last as i64),
+        RespValue::Integer(keys.step as i64),
+        RespValue::Array(Some(
+            categories(spec.flags).into_iter().map(RespValue::SimpleString).collect(),
+        )),
+    ]))
+}
+
+fn hotkeys(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let count = match args {
+        [] => 10,
+        [arg] => match bulk_to_string_lossy(arg) {
+            Some(a) if a.eq_ignore_ascii_case("reset") => {
+                ctx.db.stats.hotkeys.reset();
+                return resp_ok();
+            }
+            Some(a) => match a.parse::<usize>() {
+                Ok(n) => n.min(TRACKED_KEYS),
+                Err(_) => return CommandError::NotInteger.into(),
+            },
+            None => return CommandError::Syntax.into(),
+        },
+        _ => return CommandError::WrongArity("hotkeys".into()).into(),
+    };
+    let hot = ctx.db.stats.hotkeys.top(count);
+    RespValue::Array(Some(
+        hot.into_iter()
+            .map(|(db, key, hits)| {
+                RespValue::Array(Some(vec![
+                    RespValue::BulkString(Some(key.into_bytes())),
+                    RespValue::Integer(db as i64),
+                    RespValue::Integer(hits as i64),
+                ]))
+            })
+            .collect(),
+    ))
+}
+
+const HISTOGRAM_SAMPLES: usize = 1000;
+
+// Upper bounds of the value-size buckets, in bytes; larger values go in a last one
+const SIZE_BUCKETS: [(usize, &str); 9] = [
+    (16, "<=16"),
+    (64, "<=64"),
+    (256, "<=256"),
+    (1 << 10, "<=1k"),
+    (4 << 10, "<=4k"),
+    (16 << 10, "<=16k"),
+    (64 << 10, "<=64k"),
+    (256 << 10, "<=256k"),
+    (1 << 20, "<=1m"),
+];
+
+// Upper bounds of the TTL-remaining buckets, in seconds
+const TTL_BUCKETS: [(u64, &str); 5] = [(60, "<=1m"), (600, "<=10m"), (3600, "<=1h"), (86_400, "<=1d"), (604_800, "<=1w")];
+
+// HISTOGRAM [SAMPLES count]: how a sample of the selected database's keys
+// spreads over value sizes and time left to live, per type, alongside the
+// database's size so counts can be scaled up
+fn histogram(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let samples = match args {
+        [] => HISTOGRAM_SAMPLES,
+        [word, count] if bulk_to_string_lossy(word).is_some_and(|w| w.eq_ignore_ascii_case("samples")) => {
+            match bulk_to_string_lossy(count).and_then(|c| c.parse::<usize>().ok()) {
+                Some(n) if n > 0 => n,
+                _ => return resp_err("SAMPLES must be a positive integer"),
+            }
+        }
+        [_, _] => return CommandError::Syntax.into(),
+        _ => return CommandError::WrongArity("histogram".into()).into(),
+    };
+    let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+    let sampled = ctx.db.sample(samples);
+    let mut kinds: Vec<&str> = sampled.iter().map(|s| s.kind).collect();
+    kinds.sort_unstable();
+    kinds.dedup();
+    let buckets = |counts: Vec<(&str, usize)>| {
+        RespValue::Array(Some(counts.into_iter().flat_map(|(label, n)| [bulk(label), RespValue::Integer(n as i64)]).collect()))
+    };
+    let mut reply = vec![
+        bulk("keys"),
+        RespValue::Integer(ctx.db.dbsize() as i64),
+        bulk("sampled"),
+        RespValue::Integer(sampled.len() as i64),
+    ];
+    for kind in kinds {
+        let of_kind: Vec<_> = sampled.iter().filter(|s| s.kind == kind).collect();
+        let mut sizes: Vec<(&str, usize)> = SIZE_BUCKETS.iter().map(|(_, label)| (*label, 0)).collect();
+        sizes.push((">1m", 0));
+        let mut ttls: Vec<(&str, usize)> = vec![("none", 0)];
+        ttls.extend(TTL_BUCKETS.iter().map(|(_, label)| (*label, 0)));
+        ttls.push((">1w", 0));
+        for sample in of_kind {
+            let size = SIZE_BUCKETS.iter().position(|(max, _)| sample.value_len <= *max).unwrap_or(SIZE_BUCKETS.len());
+            sizes[size].1 += 1;
+            let ttl = match sample.ttl {
+                None => 0,
+                Some(left) => 1 + TTL_BUCKETS.iter().position(|(max, _)| left <= Duration::from_secs(*max)).unwrap_or(TTL_BUCKETS.len()),
+            };
+            ttls[ttl].1 += 1;
+        }
+        reply.push(bulk(kind));
+        reply.push(RespValue::Array(Some(vec![bulk("sizes"), buckets(sizes), bulk("ttls"), buckets(ttls)])));
+    }
+    RespValue::Array(Some(reply))
+}
+
+// SLOWLOG GET [count] | LEN | RESET
+fn slowlog(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let log = ctx.settings.slowlog();
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("get", rest) if rest.len() <= 1 => {
+            // A negative count returns every entry
+            let count = match rest.first().map(|c| bulk_to_string_lossy(c).and_then(|c| c.parse::<i64>().ok())) {
+                None => 10,
+                Some(Some(n)) => usize::try_from(n).unwrap_or(usize::MAX),
+                Some(None) => return CommandError::NotInteger.into(),
+            };
+            RespValue::Array(Some(
+                log.recent(count)
+                    .into_iter()
+                    .map(|e| {
+                        RespValue::Array(Some(vec![
+                            RespValue::Integer(e.id as i64),
+                            RespValue::Integer(e.at as i64),
+                            RespValue::Integer(e.micros as i64),
+                            RespValue::Array(Some(e.args.into_iter().map(|a| RespValue::BulkString(Some(a.into_bytes()))).collect())),
+                            RespValue::BulkString(Some(e.client_addr.into_bytes())),
+                            RespValue::BulkString(Some(Vec::new())),
+                        ]))
+                    })
+                    .collect(),
+            ))
+        }
+        ("len", []) => RespValue::Integer(log.len() as i64),
+        ("reset", []) => {
+            log.reset();
+            resp_ok()
+        }
+        ("get" | "len" | "reset", _) => CommandError::WrongArity(format!("slowlog|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'slowlog'"),
+    }
+}
+
+// AUDIT GET [count] | LEN | RESET; entries are field/value lists, newest
+// first
+fn audit(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let log = ctx.settings.audit();
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("get", rest) if rest.len() <= 1 => {
+            let count = match rest.first().map(|c| bulk_to_string_lossy(c).and_then(|c| c.parse::<i64>().ok())) {
+                None => 10,
+                Some(Some(n)) => usize::try_from(n).unwrap_or(usize::MAX),
+                Some(None) => return CommandError::NotInteger.into(),
+            };
+            let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+            RespValue::Array(Some(
+                log.recent(count)
+                    .into_iter()
+                    .map(|e| {
+                        RespValue::Array(Some(vec![
+                            bulk("entry-id"),
+                            RespValue::Integer(e.id as i64),
+                            bulk("timestamp-ms"),
+                            RespValue::Integer(e.at_ms),
+                            bulk("client-id"),
+                            RespValue::Integer(e.client_id as i64),
+                            bulk("username"),
+                            bulk(&e.username),
+                            bulk("client-info"),
+                            bulk(&e.client_info),
+                            bulk("db"),
+                            RespValue::Integer(e.db as i64),
+                            bulk("command"),
+                            bulk(&e.command),
+                            bulk("keys"),
+                            RespValue::Array(Some(e.keys.iter().map(|k| bulk(k)).collect())),
+                            bulk("access"),
+                            bulk(e.access()),
+                            bulk("result"),
+                            bulk(e.result()),
+                        ]))
+                    })
+                    .collect(),
+            ))
+        }
+        ("len", []) => RespValue::Integer(log.len() as i64),
+        ("reset", []) => {
+            log.reset();
+            resp_ok()
+        }
+        ("get" | "len" | "reset", _) => CommandError::WrongArity(format!("audit|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'audit'"),
+    }
+}
+
+fn warmup(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default();
+    if !sub.eq_ignore_ascii_case("status") {
+        return resp_err("unknown subcommand for 'warmup'");
+    }
+    let status = ctx.db.stats.warmup.status();
+    let bulk = |s: &str| RespValue::BulkString(Some(s.as_bytes().to_vec()));
+    RespValue::Array(Some(vec![
+        bulk("state"),
+        bulk(status.state.name()),
+        bulk("file"),
+        bulk(&status.file.as_ref().map(|f| f.display().to_string()).unwrap_or_default()),
+        bulk("commands"),
+        RespValue::Integer(status.commands as i64),
+        bulk("failed"),
+        RespValue::Integer(status.failed as i64),
+        bulk("loaded-keys"),
+        RespValue::Integer(status.loaded_keys as i64),
+        bulk("elapsed-ms"),
+        RespValue::Integer(status.elapsed().as_millis() as i64),
+        bulk("last-error"),
+        bulk(status.error.as_deref().unwrap_or_default()),
+    ]))
+}
+
+// CLIENT LIST | ID
+fn client(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("list", []) => {
+            let list: String = ctx.db.stats.clients.all().iter().map(|c| c.describe() + "\n").collect();
+            RespValue::BulkString(Some(list.into_bytes()))
+        }
+        ("id", []) => RespValue::Integer(ctx.client.entry.as_ref().map(|e| e.id as i64).unwrap_or(0)),
+        ("list" | "id", _) => CommandError::WrongArity(format!("client|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'client'"),
+    }
+}
+
+// DEBUG DIGEST | DIGEST-VALUE key [key ...]. Digests are 16 hex digits, all
+// zeros for an empty server or a missing key.
+fn debug(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let hex = |digest: u64| RespValue::BulkString(Some(format!("{:016x}", digest).into_bytes()));
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("digest", []) => hex(dataset_digest(ctx.dbs)),
+        ("digest-value", keys) => RespValue::Array(Some(
+            keys.iter()
+                .map(|key| hex(bulk_to_string_lossy(key).and_then(|key| ctx.db.digest_value(&key)).unwrap_or(0)))
+                .collect(),
+        )),
+        // Written out too, so the report outlives the reply
+        ("dumpstats", []) => match diagnostics::dump(ctx.dbs, ctx.settings) {
+            Ok(report) => RespValue::BulkString(Some(report.into_bytes())),
+            Err(e) => CommandError::generic(format!("writing diagnostics failed: {}", e)).into(),
+        },
+        ("digest" | "dumpstats", _) => CommandError::WrongArity(format!("debug|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'debug'"),
+    }
+}
+
+// LATENCY LATEST | HISTORY <event> | RESET [event ...]
+fn latency(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let monitor = &ctx.db.stats.latency;
+    let sub = bulk_to_string_lossy(&args[0]).unwrap_or_default().to_ascii_lowercase();
+    match (sub.as_str(), &args[1..]) {
+        ("latest", []) => RespValue::Array(Some(
+            monitor
+                .latest()
+                .into_iter()
+                .map(|(event, at, ms, max)| {
+                    RespValue::Array(Some(vec![
+                        RespValue::BulkString(Some(event.as_bytes().to_vec())),
+                        RespValue::Integer(at as i64),
+                        RespValue::Integer(ms as i64),
+                        RespValue::Integer(max as i64),
+                    ]))
+                })
+                .collect(),
+        )),
+        ("history", [event]) => {
+            let event = bulk_to_string_lossy(event).unwrap_or_default();
+            RespValue::Array(Some(
+                monitor
+                    .history(&event)
+                    .into_iter()
+                    .map(|(at, ms)| RespValue::Array(Some(vec![RespValue::Integer(at as i64), RespValue::Integer(ms as i64)])))
+                    .collect(),
+            ))
+        }
+        ("reset", events) => {
+            let events: Vec<String> = events.iter().filter_map(bulk_to_string_lossy).collect();
+            RespValue::Integer(monitor.reset(&events) as i64)
+        }
+        ("latest" | "history", _) => CommandError::WrongArity(format!("latency|{}", sub)).into(),
+        _ => resp_err("unknown subcommand for 'latency'"),
+    }
+}
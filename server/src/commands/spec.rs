@@ -0,0 +1,87 @@
+/// Command flags, named as COMMAND reports them.
+pub(crate) mod flags {
+    /// Modifies the dataset.
+    pub const WRITE: &str = "write";
+    /// Only reads the dataset.
+    pub const READONLY: &str = "readonly";
+    /// May grow memory use; refused with OOM when the database is over budget.
+    pub const DENYOOM: &str = "denyoom";
+    /// Server administration.
+    pub const ADMIN: &str = "admin";
+    /// Allowed before the connection has authenticated.
+    pub const NOAUTH: &str = "no-auth";
+    /// Runs in constant or logarithmic time.
+    pub const FAST: &str = "fast";
+    /// Publish/subscribe messaging.
+    pub const PUBSUB: &str = "pubsub";
+}
+
+/// Which arguments name keys, counting the command name as position 0:
+/// every `step`-th argument from `first` to `last`, where a negative `last`
+/// counts back from the end (-1 is the final argument).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct KeySpec {
+    pub first: usize,
+    pub last: isize,
+    pub step: usize,
+}
+
+impl KeySpec {
+    /// A command that takes no keys.
+    pub const NONE: KeySpec = KeySpec { first: 0, last: 0, step: 0 };
+
+    /// Indexes of the key arguments in `args`, which excludes the command name.
+    pub fn positions(&self, args: usize) -> Vec<usize> {
+        if self.first == 0 {
+            return Vec::new();
+        }
+        let last = if self.last < 0 {
+            args as isize + 1 + self.last
+        } else {
+            (self.last as usize).min(args) as isize
+        };
+        if last < self.first as isize {
+            return Vec::new();
+        }
+        (self.first..=last as usize).step_by(self.step.max(1)).map(|p| p - 1).collect()
+    }
+}
+
+/// What the dispatcher knows about a command before running it.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CommandSpec {
+    /// Number of arguments including the name; negative means at least that
+    /// many.
+    pub arity: i32,
+    pub flags: &'static [&'static str],
+    pub keys: KeySpec,
+}
+
+impl CommandSpec {
+    /// A command without keys.
+    pub const fn new(arity: i32, flags: &'static [&'static str]) -> Self {
+        Self {
+            arity,
+            flags,
+            keys: KeySpec::NONE,
+        }
+    }
+
+    pub const fn keys(mut self, first: usize, last: isize, step: usize) -> Self {
+        self.keys = KeySpec { first, last, step };
+        self
+    }
+
+    pub fn has(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+
+    /// Whether `args` (the command name included) has an acceptable length.
+    pub fn arity_ok(&self, args: usize) -> bool {
+        if self.arity < 0 {
+            args >= self.arity.unsigned_abs() as usize
+        } else {
+            args == self.arity as usize
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use super::flags::{DENYOOM, FAST, READONLY, WRITE};
+use super::{arg_bytes, arg_str, bulk_to_bytes, bulk_to_string_lossy, parse_set, resp_err, resp_ok, CommandSpec, Context, Registry};
+use crate::backing::BackingWrite;
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+pub(super) fn register(registry: &mut Registry) {
+    registry.register_taking("set", CommandSpec::new(-3, &[WRITE, DENYOOM]).keys(1, 1, 1), set);
+    registry.register("get", CommandSpec::new(-2, &[READONLY, FAST]).keys(1, 1, 1), get);
+    registry.register("mget", CommandSpec::new(-2, &[READONLY, FAST]).keys(1, -1, 1), mget);
+    registry.register("mset", CommandSpec::new(-3, &[WRITE, DENYOOM]).keys(1, -1, 2), mset);
+    registry.register("incr", CommandSpec::new(2, &[WRITE, DENYOOM, FAST]).keys(1, 1, 1), incr);
+    registry.register("decr", CommandSpec::new(2, &[WRITE, DENYOOM, FAST]).keys(1, 1, 1), decr);
+    registry.register("strlen", CommandSpec::new(2, &[READONLY, FAST]).keys(1, 1, 1), strlen);
+}
+
+// SET with IFEQ or IFVERSION is a compare-and-set: it writes only if the key
+// holds the given value or version, and replies nil otherwise
+fn set(ctx: &mut Context<'_>, args: &mut [RespValue]) -> RespValue {
+    let set = match parse_set(ctx.db.clock(), args, !ctx.keep_args) {
+        Ok(parsed) => parsed,
+        Err(e) => return e,
+    };
+    // The store takes the parsed key and value; they are copied only when a
+    // backing store needs the write too
+    let backed = ctx.backing.is_some().then(|| (set.key.clone(), set.value.clone()));
+    let idle = set.max_idle.map(|limit| (set.key.clone(), limit));
+    match set.condition {
+        Some(condition) => {
+            if !ctx.db.set_if(set.key, &condition, set.value, set.ttl) {
+                return RespValue::BulkString(None);
+            }
+        }
+        None => ctx.db.set(set.key, set.value, set.ttl),
+    }
+    if let Some((key, limit)) = idle {
+        ctx.db.set_idle_limit(&key, Some(limit));
+    }
+    if let Some((key, value)) = backed {
+        ctx.wrote(|| BackingWrite::Set(key, value));
+    }
+    resp_ok()
+}
+
+fn get(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match arg_str(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    let with_version = match &args[1..] {
+        [] => false,
+        [opt] if arg_bytes(opt).is_some_and(|o| o.eq_ignore_ascii_case(b"WITHVERSION")) => true,
+        _ => return CommandError::Syntax.into(),
+    };
+    if with_version {
+        // The value and its version, for a later SET ... IFVERSION
+        return match ctx.db.get_with_version(&key) {
+            Ok(Some((v, version))) => RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(v)),
+                RespValue::Integer(version as i64),
+            ])),
+            Ok(None) => {
+                ctx.missed(&key);
+                RespValue::BulkString(None)
+            }
+            Err(e) => CommandError::from(e).into(),
+        };
+    }
+    match ctx.db.get_checked(&key) {
+        Ok(Some(v)) => RespValue::BulkString(Some(v)),
+        Ok(None) => {
+            ctx.missed(&key);
+            RespValue::BulkString(None)
+        }
+        Err(e) => CommandError::from(e).into(),
+    }
+}
+
+fn mget(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let mut out: Vec<RespValue> = Vec::with_capacity(args.len());
+    for a in args {
+        let key = arg_str(a).unwrap_or_default();
+        match ctx.db.get_checked(&key) {
+            Ok(Some(v)) => out.push(RespValue::BulkString(Some(v))),
+            Ok(None) => {
+                ctx.missed(&key);
+                out.push(RespValue::BulkString(None));
+            }
+            Err(e) => return CommandError::from(e).into(),
+        }
+    }
+    RespValue::Array(Some(out))
+}
+
+fn mset(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    if !args.len().is_multiple_of(2) {
+        return CommandError::WrongArity("mset".into()).into();
+    }
+    let mut i = 0usize;
+    while i < args.len() {
+        let key = match bulk_to_string_lossy(&args[i]) { Some(s) => s, None => return resp_err("invalid key") };
+        let val = match bulk_to_bytes(&args[i + 1]) { Some(v) => v, None => return resp_err("invalid value") };
+        ctx.wrote(|| BackingWrite::Set(key.clone(), val.clone()));
+        ctx.db.set(key, val, None);
+        i += 2;
+    }
+    resp_ok()
+}
+
+fn incr(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match bulk_to_string_lossy(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    match ctx.db.incr_by(key.clone(), 1) {
+        Ok(v) => {
+            ctx.wrote(|| BackingWrite::Set(key, v.to_string().into_bytes()));
+            RespValue::Integer(v)
+        }
+        Err(m) => resp_err(&m),
+    }
+}
+
+fn decr(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match bulk_to_string_lossy(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    match ctx.db.incr_by(key.clone(), -1) {
+        Ok(v) => {
+            ctx.wrote(|| BackingWrite::Set(key, v.to_string().into_bytes()));
+            RespValue::Integer(v)
+        }
+        Err(m) => resp_err(&m),
+    }
+}
+
+fn strlen(ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let key = match arg_str(&args[0]) {
+        Some(s) => s,
+        None => return resp_err("invalid key"),
+    };
+    RespValue::Integer(ctx.db.strlen(&key) as i64)
+}
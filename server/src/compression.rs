@@ -0,0 +1,163 @@
+//! zstd compression of snapshot and AOF files.
+//!
+//! Compressed files are ordinary zstd streams with a content checksum in
+//! every frame, so `zstd -d` can unpack them for inspection. Readers tell
+//! the two kinds apart by the zstd magic number at the start of the file;
+//! neither of our plain formats can begin with it.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+
+/// How persistence files are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Off,
+    /// zstd at this level, 1 (fastest) to 22.
+    Zstd(i32),
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "no",
+            Self::Zstd(_) => "zstd",
+        }
+    }
+}
+
+/// Whether the file at `path` starts with a zstd frame.
+pub fn is_compressed(path: &Path) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path)?;
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => return Ok(false),
+            n => read += n,
+        }
+    }
+    Ok(magic == ZSTD_MAGIC)
+}
+
+/// Opens `path` for reading, decompressing it if it is compressed.
+pub fn open_decoded(path: &Path) -> io::Result<Box<dyn BufRead + Send>> {
+    let compressed = is_compressed(path)?;
+    let file = BufReader::new(File::open(path)?);
+    if compressed {
+        Ok(Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(file)?)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Opens `path` for reading like [`open_decoded`], but decompresses one
+/// whole frame at a time, so nothing from a frame is returned before its
+/// checksum is verified. A file that ends partway through a frame fails with
+/// `UnexpectedEof` at that frame. For files written a frame per flush.
+pub fn open_decoded_frames(path: &Path) -> io::Result<Box<dyn BufRead + Send>> {
+    let compressed = is_compressed(path)?;
+    let file = BufReader::new(File::open(path)?);
+    if compressed {
+        Ok(Box::new(Frames {
+            inner: file,
+            frame: Vec::new(),
+            pos: 0,
+        }))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+struct Frames<R> {
+    inner: R,
+    frame: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: BufRead> BufRead for Frames<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.frame.len() {
+            if self.inner.fill_buf()?.is_empty() {
+                break;
+            }
+            self.frame.clear();
+            self.pos = 0;
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(&mut self.inner)?.single_frame();
+            if let Err(e) = decoder.read_to_end(&mut self.frame) {
+                self.frame.clear();
+                return Err(e);
+            }
+        }
+        Ok(&self.frame[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.frame.len());
+    }
+}
+
+impl<R: BufRead> Read for Frames<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+/// Compresses `data` as one complete frame.
+pub(crate) fn compress_frame(data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+    encoder.include_checksum(true)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// A new file written through zstd or as it is.
+pub(crate) enum FileWriter {
+    Plain(BufWriter<File>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+}
+
+impl FileWriter {
+    pub fn create(path: &Path, compression: Compression) -> io::Result<Self> {
+        let out = BufWriter::new(File::create(path)?);
+        Ok(match compression {
+            Compression::Off => Self::Plain(out),
+            Compression::Zstd(level) => {
+                let mut encoder = zstd::stream::write::Encoder::new(out, level)?;
+                encoder.include_checksum(true)?;
+                Self::Zstd(encoder)
+            }
+        })
+    }
+
+    /// Ends the stream and returns the file, flushed but not synced.
+    pub fn finish(self) -> io::Result<File> {
+        let out = match self {
+            Self::Plain(out) => out,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        out.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(out) => out.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(out) => out.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
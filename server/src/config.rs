@@ -0,0 +1,1561 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::acl::{parse_user, Acl, AclLog, QuotaMeters};
+use crate::allocator::MemoryAccounting;
+use crate::aof::{AofLimit, AppendFsync};
+use crate::audit::AuditLog;
+use crate::backing::{BackingStore, Loader};
+use crate::cluster::{format_slots, parse_slot_range};
+use crate::compression::Compression;
+use crate::db::EvictionPolicy;
+use crate::events::{EventFormat, EventSink};
+use crate::ipfilter::{Cidr, IpFilter};
+use crate::latency::SlowLog;
+use crate::mirror::MirrorOnFull;
+use crate::pubsub::{QueueLimit, SlowSubscriber};
+use crate::shadow::ShadowCommands;
+use crate::sql::SqlTarget;
+use crate::tls::TlsCerts;
+use crate::webhook::Webhook;
+
+const DEFAULT_PORT: u16 = 9973;
+
+/// Settings for a server instance. `Default` binds an ephemeral loopback port,
+/// which is what embedders and tests usually want.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub addr: String,
+    pub reaper_interval: Duration,
+    /// Keys not read for this long expire; zero turns it off. `SET ...
+    /// MAXIDLE` gives a key a limit of its own.
+    pub maxidle: Duration,
+    /// Plugin libraries loaded at startup, in order.
+    pub plugins: Vec<PathBuf>,
+    /// File re-read by CONFIG RELOAD and SIGHUP.
+    pub config_file: Option<PathBuf>,
+    /// Password for the `default` user; without it (and without a `user default`
+    /// line) connections need no AUTH.
+    pub requirepass: Option<String>,
+    /// ACL user lines: `<name> [on|off] [>password ...] [nopass] [namespace=<prefix>]
+    /// [ops-per-sec=<n>] ...`; see [`crate::acl`].
+    pub users: Vec<String>,
+    /// Number of logical databases selectable with SELECT.
+    pub databases: usize,
+    /// Threads that each own a share of the key store and run the commands
+    /// whose keys all fall in it; 0 runs every command on its connection.
+    pub cores: usize,
+    /// Memory budget in bytes for each database (0 = unlimited).
+    pub maxmemory: usize,
+    /// Per-database overrides of `maxmemory`, by database index.
+    pub maxmemory_db: BTreeMap<usize, usize>,
+    pub maxmemory_policy: EvictionPolicy,
+    /// Whether the budget covers only keys and values, or also the process's
+    /// overhead as the allocator measures it.
+    pub maxmemory_accounting: MemoryAccounting,
+    /// Compact sparse shard tables and oversized values in the background.
+    pub activedefrag: bool,
+    /// Share of a core, 1 to 99 percent, defragmentation may take.
+    pub active_defrag_cycle_max: u32,
+    /// Directory for the cold tier: values evicted over maxmemory are spilled
+    /// here and faulted back in on access instead of being dropped.
+    pub overflow_dir: Option<PathBuf>,
+    /// Store a checksum with every value and refuse to serve or save one
+    /// that no longer matches it.
+    pub value_checksums: bool,
+    /// Where SAVE and BGSAVE write snapshots and startup loads them from.
+    pub snapshot_file: Option<PathBuf>,
+    /// Redis RDB file that `SAVE RDB` and `BGSAVE RDB` export to, and that
+    /// startup imports from when there is no snapshot to load.
+    pub rdb_file: Option<PathBuf>,
+    /// Log every write to `aof_file`, and rebuild the data from it at startup
+    /// in preference to a snapshot or RDB file.
+    pub appendonly: bool,
+    pub aof_file: PathBuf,
+    pub appendfsync: AppendFsync,
+    /// Replay the AOF only up to this point at startup, then cut it there.
+    /// Set from the command line for a one-off point-in-time recovery.
+    pub aof_load_until: Option<AofLimit>,
+    /// How snapshots and new AOF files are written. Loading reads either.
+    pub compression: Compression,
+    pub compression_level: i32,
+    /// Store that database 0 reads through to on misses and writes through to.
+    pub backing_store: Option<BackingStore>,
+    /// Forward writes to the backing store; off for read-only backends.
+    pub backing_write_through: bool,
+    /// Sources database 0 loads missed keys matching their patterns from,
+    /// ahead of the backing store.
+    pub loaders: Vec<Loader>,
+    /// TTL given to keys a loader loads (zero = none).
+    pub loader_ttl: Duration,
+    /// Hot key tracking samples one command in this many (0 = off).
+    pub hotkeys_sample: u32,
+    /// Entries kept in the ACL LOG of denied commands and failed AUTHs.
+    pub acllog_max_len: usize,
+    /// Commands taking at least this long are logged and recorded as LATENCY
+    /// events (zero = off).
+    pub latency_threshold: Duration,
+    /// Cancellable commands, such as a long SCAN, are aborted with BUSY once
+    /// they run this long (zero = never).
+    pub busy_reply_threshold: Duration,
+    /// Commands taking at least this many microseconds go to the SLOWLOG
+    /// (negative = off, 0 = every command).
+    pub slowlog_log_slower_than: i64,
+    /// Entries kept in the SLOWLOG.
+    pub slowlog_max_len: usize,
+    /// The key access audit records one command with keys in this many
+    /// (0 = off).
+    pub audit_sample: u32,
+    /// Entries kept for AUDIT GET.
+    pub audit_max_len: usize,
+    /// File audit records are also appended to, as JSON lines.
+    pub audit_file: Option<PathBuf>,
+    /// Refuse multi-key commands whose keys hash to different cluster slots.
+    /// Set at startup, it also makes this server a cluster node that finds
+    /// the others by gossip.
+    pub cluster_enabled: bool,
+    /// Nodes (`host:port`) to join the cluster through; one reachable node
+    /// is enough.
+    pub cluster_seeds: Vec<String>,
+    /// The hash slots this node serves, as inclusive ranges.
+    pub cluster_slots: Vec<(u16, u16)>,
+    /// How long a node may go unanswered before it is suspected.
+    pub cluster_node_timeout: Duration,
+    /// The address (`host:port`) other nodes reach this one at; the bound
+    /// address when unset.
+    pub cluster_announce_addr: Option<String>,
+    /// Backend nodes (`host:port`). When set, this server is a stateless proxy
+    /// that consistently hashes keys across them.
+    pub proxy_backends: Vec<String>,
+    /// Master (`host:port`) to replicate from.
+    pub replicaof: Option<String>,
+    /// Password, and optionally user, the replica authenticates to its
+    /// master with.
+    pub masterauth: Option<String>,
+    pub masteruser: Option<String>,
+    /// Refuse writes from clients while replicating.
+    pub replica_read_only: bool,
+    /// Keep answering with possibly stale data while the link to the master
+    /// is down; when off, commands other than INFO, CONFIG and a few
+    /// connection commands fail with MASTERDOWN until it is back.
+    pub replica_serve_stale_data: bool,
+    /// Replicas that must have acknowledged within `min_replicas_max_lag`
+    /// for writes to be accepted; 0 accepts them regardless.
+    pub min_replicas_to_write: usize,
+    pub min_replicas_max_lag: Duration,
+    /// This server's address (`host:port`) as the other members of its raft
+    /// group reach it. Setting it turns on raft mode: writes are committed
+    /// through a log replicated to a majority of the group first.
+    pub raft_node: Option<String>,
+    /// The other members of the raft group (`host:port`).
+    pub raft_peers: Vec<String>,
+    /// Where the raft term, vote and log are kept.
+    pub raft_file: PathBuf,
+    /// How long a follower waits to hear from the leader before standing
+    /// for election; each election waits between this and twice it.
+    pub raft_election_timeout: Duration,
+    /// This server's id in an active-active group. Setting it turns on
+    /// active-active mode: every member takes writes, and strings and
+    /// counters merge as CRDTs with the `crdt_peers`.
+    pub crdt_id: Option<String>,
+    /// The other members of the active-active group (`host:port`).
+    pub crdt_peers: Vec<String>,
+    /// Server (`host:port`) every write is also forwarded to; off when unset.
+    pub mirror_to: Option<String>,
+    /// Writes held for the mirror target before `mirror_on_full` applies.
+    pub mirror_queue_size: usize,
+    pub mirror_on_full: MirrorOnFull,
+    /// Messages queued for a subscriber before `pubsub_slow_subscriber`
+    /// applies.
+    pub pubsub_queue_size: usize,
+    pub pubsub_slow_subscriber: SlowSubscriber,
+    /// Commands a connection may have read ahead of the one running before
+    /// the server stops reading its socket.
+    pub client_queue_depth: usize,
+    /// Bytes of parsed commands that queue may hold; a single larger
+    /// command is still let in once the queue is empty.
+    pub client_queue_bytes: usize,
+    /// Server (`host:port`) a sample of commands is replayed against, for
+    /// comparison; off when unset.
+    pub shadow_to: Option<String>,
+    /// Share of `shadow_commands`, 0 to 100, sent to the shadow.
+    pub shadow_percent: u32,
+    pub shadow_commands: ShadowCommands,
+    /// Broker key events are published to; off when unset.
+    pub events_to: Option<EventSink>,
+    pub events_format: EventFormat,
+    /// Events held for the broker before further ones are dropped.
+    pub events_queue_size: usize,
+    /// URLs key events are POSTed to.
+    pub webhooks: Vec<Webhook>,
+    /// Key webhook bodies are signed with (HMAC-SHA256); unsigned when unset.
+    pub webhook_secret: Option<String>,
+    /// Most events in one POST, and how long a batch waits to fill.
+    pub webhook_batch_size: usize,
+    pub webhook_batch_delay: Duration,
+    /// Times a failed POST is tried again before its events are given up on.
+    pub webhook_retries: u32,
+    /// Events held for each webhook before further ones are dropped.
+    pub webhook_queue_size: usize,
+    /// Table database 0's writes are flushed to; off when unset.
+    pub write_behind: Option<SqlTarget>,
+    /// Most keys written in one transaction, and how long changes gather
+    /// before a flush.
+    pub write_behind_batch_size: usize,
+    pub write_behind_delay: Duration,
+    /// Times a failed batch is tried again before it is dead-lettered.
+    pub write_behind_retries: u32,
+    /// Keys with pending changes before changes to further keys are dropped.
+    pub write_behind_queue_size: usize,
+    /// File batches that could not be written are appended to.
+    pub write_behind_dead_letter: PathBuf,
+    /// Address of the WebSocket listener for browser clients; off when unset.
+    pub websocket_addr: Option<String>,
+    /// Address of the TLS listener; off when unset.
+    pub tls_addr: Option<String>,
+    /// PEM certificate chain and private key the TLS listener serves,
+    /// reloaded when they change.
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    /// Address of the HTTP listener for /healthz and /readyz; off when unset.
+    pub health_addr: Option<String>,
+    /// File every client command and its reply are recorded to.
+    pub record_file: Option<PathBuf>,
+    /// File diagnostic reports are appended to; the log when unset.
+    pub diagnostics_file: Option<PathBuf>,
+    /// Recording to answer commands from instead of running them.
+    pub replay_file: Option<PathBuf>,
+    /// Commands run at startup to prime the cache.
+    pub warmup_file: Option<PathBuf>,
+    /// Whether clients are served while warmup runs, rather than after it.
+    pub warmup_async: bool,
+    /// Address blocks clients and WebSocket peers may connect from (any when
+    /// empty), and blocks they may not.
+    pub ip_allow: Vec<Cidr>,
+    pub ip_deny: Vec<Cidr>,
+    /// Other origins allowed to open a WebSocket, or `*` for any; the
+    /// listener's own origin always may.
+    pub websocket_origins: Vec<String>,
+    /// Serve the admin dashboard at `/` on the WebSocket listener.
+    pub dashboard: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            addr: "127.0.0.1:0".to_string(),
+            reaper_interval: Duration::from_millis(500),
+            maxidle: Duration::ZERO,
+            plugins: Vec::new(),
+            config_file: None,
+            requirepass: None,
+            users: Vec::new(),
+            databases: 16,
+            cores: 0,
+            maxmemory: 0,
+            maxmemory_db: BTreeMap::new(),
+            maxmemory_policy: EvictionPolicy::NoEviction,
+            maxmemory_accounting: MemoryAccounting::Dataset,
+            activedefrag: false,
+            active_defrag_cycle_max: 25,
+            overflow_dir: None,
+            value_checksums: false,
+            snapshot_file: None,
+            rdb_file: None,
+            appendonly: false,
+            aof_file: PathBuf::from("appendonly.aof"),
+            appendfsync: AppendFsync::EverySec,
+            aof_load_until: None,
+            compression: Compression::Off,
+            compression_level: 3,
+            backing_store: None,
+            backing_write_through: true,
+            loaders: Vec::new(),
+            loader_ttl: Duration::ZERO,
+            hotkeys_sample: 16,
+            acllog_max_len: 128,
+            latency_threshold: Duration::from_millis(100),
+            busy_reply_threshold: Duration::from_millis(5000),
+            slowlog_log_slower_than: 10_000,
+            slowlog_max_len: 128,
+            audit_sample: 0,
+            audit_max_len: 128,
+            audit_file: None,
+            cluster_enabled: false,
+            cluster_seeds: Vec::new(),
+            cluster_slots: Vec::new(),
+            cluster_node_timeout: Duration::from_millis(15000),
+            cluster_announce_addr: None,
+            proxy_backends: Vec::new(),
+            replicaof: None,
+            masterauth: None,
+            masteruser: None,
+            replica_read_only: true,
+            replica_serve_stale_data: true,
+            min_replicas_to_write: 0,
+            min_replicas_max_lag: Duration::from_secs(10),
+            raft_node: None,
+            raft_peers: Vec::new(),
+            raft_file: PathBuf::from("raft.log"),
+            raft_election_timeout: Duration::from_millis(1000),
+            crdt_id: None,
+            crdt_peers: Vec::new(),
+            mirror_to: None,
+            mirror_queue_size: 10_000,
+            mirror_on_full: MirrorOnFull::Drop,
+            pubsub_queue_size: 10_000,
+            pubsub_slow_subscriber: SlowSubscriber::Disconnect,
+            client_queue_depth: 128,
+            client_queue_bytes: 64 * 1024 * 1024,
+            shadow_to: None,
+            shadow_percent: 10,
+            shadow_commands: ShadowCommands::Reads,
+            events_to: None,
+            events_format: EventFormat::Json,
+            events_queue_size: 10_000,
+            webhooks: Vec::new(),
+            webhook_secret: None,
+            webhook_batch_size: 100,
+            webhook_batch_delay: Duration::from_millis(100),
+            webhook_retries: 3,
+            webhook_queue_size: 10_000,
+            write_behind: None,
+            write_behind_batch_size: 500,
+            write_behind_delay: Duration::from_millis(1000),
+            write_behind_retries: 5,
+            write_behind_queue_size: 100_000,
+            write_behind_dead_letter: PathBuf::from("write-behind-dead-letter.aof"),
+            websocket_addr: None,
+            tls_addr: None,
+            tls_cert_file: None,
+            tls_key_file: None,
+            health_addr: None,
+            record_file: None,
+            diagnostics_file: None,
+            replay_file: None,
+            warmup_file: None,
+            warmup_async: false,
+            ip_allow: Vec::new(),
+            ip_deny: Vec::new(),
+            websocket_origins: Vec::new(),
+            dashboard: true,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Builds the startup configuration: defaults, then the ADDR/PORT,
+    /// RUSTCACHE_REAPER_MS and RUSTCACHE_PLUGINS environment variables, then the
+    /// config file (the given path or RUSTCACHE_CONFIG), which wins over both.
+    pub fn load(config_file: Option<PathBuf>) -> io::Result<Self> {
+        let config_file = config_file.or_else(|| std::env::var_os("RUSTCACHE_CONFIG").map(PathBuf::from));
+        let mut config = Self {
+            addr: format!("127.0.0.1:{}", DEFAULT_PORT),
+            config_file: config_file.clone(),
+            ..Self::default()
+        };
+        config.apply_env();
+        if let Some(path) = &config_file {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            config
+                .apply_file(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+        }
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(config)
+    }
+
+    /// Parses redis.conf-style `directive value` lines; `#` starts a comment line.
+    fn apply_file(&mut self, text: &str) -> Result<(), String> {
+        // loadplugin lines replace RUSTCACHE_PLUGINS rather than extending it
+        let env_plugins = std::mem::take(&mut self.plugins);
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.split_once(char::is_whitespace) {
+                Some((k, v)) => (k, v.trim()),
+                None => (line, ""),
+            };
+            self.apply_directive(&key.to_ascii_lowercase(), value)
+                .map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        if self.plugins.is_empty() {
+            self.plugins = env_plugins;
+        }
+        Ok(())
+    }
+
+    fn apply_directive(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "addr" => {
+                if value.is_empty() {
+                    return Err("addr needs a host:port value".to_string());
+                }
+                self.addr = value.to_string();
+            }
+            "port" => {
+                let port: u16 = value.parse().map_err(|_| format!("invalid port '{}'", value))?;
+                self.set_port(port);
+            }
+            "maxidle" => {
+                let secs: u64 = value.parse().map_err(|_| format!("invalid maxidle '{}'", value))?;
+                self.maxidle = Duration::from_secs(secs);
+            }
+            "reaper-ms" => {
+                let ms: u64 = match value.parse() {
+                    Ok(ms) if ms > 0 => ms,
+                    _ => return Err(format!("invalid reaper-ms '{}'", value)),
+                };
+                self.reaper_interval = Duration::from_millis(ms);
+            }
+            "requirepass" => {
+                self.requirepass = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "user" => {
+                parse_user(value)?;
+                self.users.push(value.to_string());
+            }
+            "cores" => self.cores = value.parse().map_err(|_| format!("invalid cores '{}'", value))?,
+            "databases" => {
+                self.databases = match value.parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Err(format!("invalid databases '{}'", value)),
+                };
+            }
+            "maxmemory" => self.maxmemory = parse_memory(value)?,
+            "maxmemory-db" => {
+                let (index, bytes) = match value.split_once(char::is_whitespace) {
+                    Some((i, b)) => (i, b.trim()),
+                    None => return Err("maxmemory-db needs a database index and a size".to_string()),
+                };
+                let index: usize = index.parse().map_err(|_| format!("invalid database index '{}'", index))?;
+                self.maxmemory_db.insert(index, parse_memory(bytes)?);
+            }
+            "maxmemory-policy" => {
+                self.maxmemory_policy =
+                    EvictionPolicy::from_name(value).ok_or_else(|| format!("unknown maxmemory-policy '{}'", value))?;
+            }
+            "activedefrag" => self.activedefrag = parse_yes_no(key, value)?,
+            "active-defrag-cycle-max" => {
+                self.active_defrag_cycle_max = value
+                    .parse()
+                    .ok()
+                    .filter(|p| (1..=99).contains(p))
+                    .ok_or_else(|| format!("active-defrag-cycle-max must be 1 to 99, not '{}'", value))?;
+            }
+            "maxmemory-accounting" => {
+                self.maxmemory_accounting = MemoryAccounting::from_name(value)
+                    .ok_or_else(|| format!("unknown maxmemory-accounting '{}'", value))?;
+            }
+            "dbfilename" => {
+                self.snapshot_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "rdbfilename" => {
+                self.rdb_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "appendonly" => self.appendonly = parse_yes_no(key, value)?,
+            "appendfilename" => {
+                if value.is_empty() {
+                    return Err("appendfilename needs a path".to_string());
+                }
+                self.aof_file = PathBuf::from(value);
+            }
+            "appendfsync" => {
+                self.appendfsync = AppendFsync::from_name(value)
+                    .ok_or_else(|| format!("appendfsync must be always, everysec or no, not '{}'", value))?;
+            }
+            "persistence-compression" => {
+                self.compression = match value.to_ascii_lowercase().as_str() {
+                    "no" => Compression::Off,
+                    "zstd" => Compression::Zstd(self.compression_level),
+                    _ => return Err(format!("persistence-compression must be no or zstd, not '{}'", value)),
+                };
+            }
+            "persistence-compression-level" => {
+                self.compression_level = match value.parse() {
+                    Ok(level) if (1..=22).contains(&level) => level,
+                    _ => return Err(format!("persistence-compression-level must be 1 to 22, not '{}'", value)),
+                };
+                if let Compression::Zstd(_) = self.compression {
+                    self.compression = Compression::Zstd(self.compression_level);
+                }
+            }
+            "acllog-max-len" => {
+                self.acllog_max_len = value.parse().map_err(|_| format!("invalid acllog-max-len '{}'", value))?;
+            }
+            "hotkeys-sample" => {
+                self.hotkeys_sample = value.parse().map_err(|_| format!("invalid hotkeys-sample '{}'", value))?;
+            }
+            "latency-monitor-threshold" => {
+                let ms: u64 = value.parse().map_err(|_| format!("invalid latency-monitor-threshold '{}'", value))?;
+                self.latency_threshold = Duration::from_millis(ms);
+            }
+            "busy-reply-threshold" => {
+                let ms: u64 = value.parse().map_err(|_| format!("invalid busy-reply-threshold '{}'", value))?;
+                self.busy_reply_threshold = Duration::from_millis(ms);
+            }
+            "slowlog-log-slower-than" => {
+                self.slowlog_log_slower_than =
+                    value.parse().map_err(|_| format!("invalid slowlog-log-slower-than '{}'", value))?;
+            }
+            "slowlog-max-len" => {
+                self.slowlog_max_len = value.parse().map_err(|_| format!("invalid slowlog-max-len '{}'", value))?;
+            }
+            "audit-sample" => {
+                self.audit_sample = value.parse().map_err(|_| format!("invalid audit-sample '{}'", value))?;
+            }
+            "audit-max-len" => {
+                self.audit_max_len = value.parse().map_err(|_| format!("invalid audit-max-len '{}'", value))?;
+            }
+            "audit-file" => {
+                self.audit_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "overflow-dir" => {
+                self.overflow_dir = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "value-checksums" => self.value_checksums = parse_yes_no(key, value)?,
+            "backing-store" => {
+                self.backing_store = if value.is_empty() { None } else { Some(BackingStore::parse(value)?) };
+            }
+            "backing-write-through" => self.backing_write_through = parse_yes_no(key, value)?,
+            "loader" => self.loaders.push(Loader::parse(value)?),
+            "loader-ttl" => {
+                let secs: u64 = value.parse().map_err(|_| format!("invalid loader-ttl '{}'", value))?;
+                self.loader_ttl = Duration::from_secs(secs);
+            }
+            "cluster-enabled" => self.cluster_enabled = parse_yes_no(key, value)?,
+            "cluster-seed" => {
+                if value.is_empty() {
+                    return Err("cluster-seed needs a host:port".to_string());
+                }
+                self.cluster_seeds.push(value.to_string());
+            }
+            "cluster-slots" => {
+                let range = parse_slot_range(value).ok_or_else(|| format!("invalid cluster-slots range '{}'", value))?;
+                self.cluster_slots.push(range);
+            }
+            "cluster-node-timeout" => {
+                let ms: u64 = value.parse().map_err(|_| format!("invalid cluster-node-timeout '{}'", value))?;
+                if ms == 0 {
+                    return Err("cluster-node-timeout must be positive".to_string());
+                }
+                self.cluster_node_timeout = Duration::from_millis(ms);
+            }
+            "cluster-announce-addr" => self.cluster_announce_addr = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "replicaof" | "slaveof" => {
+                self.replicaof = match value.split_whitespace().collect::<Vec<_>>()[..] {
+                    [] => None,
+                    [no, one] if no.eq_ignore_ascii_case("no") && one.eq_ignore_ascii_case("one") => None,
+                    [host, port] => {
+                        let port: u16 = port.parse().map_err(|_| format!("invalid replicaof port '{}'", port))?;
+                        Some(format!("{}:{}", host, port))
+                    }
+                    _ => return Err("replicaof needs a host and a port".to_string()),
+                };
+            }
+            "masterauth" => {
+                self.masterauth = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "masteruser" => {
+                self.masteruser = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "replica-read-only" | "slave-read-only" => self.replica_read_only = parse_yes_no(key, value)?,
+            "replica-serve-stale-data" | "slave-serve-stale-data" => self.replica_serve_stale_data = parse_yes_no(key, value)?,
+            "min-replicas-to-write" | "min-slaves-to-write" => {
+                self.min_replicas_to_write = value.parse().map_err(|_| format!("invalid min-replicas-to-write '{}'", value))?;
+            }
+            "min-replicas-max-lag" | "min-slaves-max-lag" => {
+                let secs: u64 = value.parse().map_err(|_| format!("invalid min-replicas-max-lag '{}'", value))?;
+                self.min_replicas_max_lag = Duration::from_secs(secs);
+            }
+            "raft-node" => self.raft_node = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "raft-peer" => {
+                if value.is_empty() {
+                    return Err("raft-peer needs a host:port".to_string());
+                }
+                self.raft_peers.push(value.to_string());
+            }
+            "raft-file" => self.raft_file = PathBuf::from(value),
+            "raft-election-timeout" => {
+                let ms: u64 = value.parse().map_err(|_| format!("invalid raft-election-timeout '{}'", value))?;
+                if ms == 0 {
+                    return Err("raft-election-timeout must be positive".to_string());
+                }
+                self.raft_election_timeout = Duration::from_millis(ms);
+            }
+            "crdt-id" => self.crdt_id = Some(value.to_string()).filter(|v| !v.is_empty()),
+            "crdt-peer" => {
+                if value.is_empty() {
+                    return Err("crdt-peer needs a host:port".to_string());
+                }
+                self.crdt_peers.push(value.to_string());
+            }
+            "mirror-to" => {
+                self.mirror_to = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "mirror-queue-size" => {
+                self.mirror_queue_size = value
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid mirror-queue-size '{}'", value))?;
+            }
+            "shadow-to" => {
+                self.shadow_to = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "shadow-percent" => {
+                self.shadow_percent = value
+                    .parse()
+                    .ok()
+                    .filter(|n| *n <= 100)
+                    .ok_or_else(|| format!("shadow-percent must be 0 to 100, not '{}'", value))?;
+            }
+            "shadow-commands" => {
+                self.shadow_commands = ShadowCommands::from_name(value)
+                    .ok_or_else(|| format!("shadow-commands must be reads, writes or all, not '{}'", value))?;
+            }
+            "mirror-on-full" => {
+                self.mirror_on_full = MirrorOnFull::from_name(value)
+                    .ok_or_else(|| format!("mirror-on-full must be drop or refuse, not '{}'", value))?;
+            }
+            "pubsub-queue-size" => {
+                self.pubsub_queue_size = value
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid pubsub-queue-size '{}'", value))?;
+            }
+            "client-queue-depth" => {
+                self.client_queue_depth = value
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid client-queue-depth '{}'", value))?;
+            }
+            "client-queue-bytes" => {
+                self.client_queue_bytes = Some(parse_memory(value)?)
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid client-queue-bytes '{}'", value))?;
+            }
+            "pubsub-slow-subscriber" => {
+                self.pubsub_slow_subscriber = SlowSubscriber::from_name(value).ok_or_else(|| {
+                    format!("pubsub-slow-subscriber must be drop-oldest, drop-message or disconnect, not '{}'", value)
+                })?;
+            }
+            "events-to" => {
+                self.events_to = if value.is_empty() { None } else { Some(EventSink::parse(value)?) };
+            }
+            "events-format" => {
+                self.events_format = EventFormat::from_name(value)
+                    .ok_or_else(|| format!("events-format must be json or text, not '{}'", value))?;
+            }
+            "events-queue-size" => {
+                self.events_queue_size = value
+                    .parse()
+                    .ok()
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| format!("invalid events-queue-size '{}'", value))?;
+            }
+            "webhook" => self.webhooks.push(Webhook::parse(value)?),
+            "webhook-secret" => {
+                self.webhook_secret = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "webhook-batch-size" | "webhook-queue-size" => {
+                let n = value.parse().ok().filter(|n| *n > 0).ok_or_else(|| format!("invalid {} '{}'", key, value))?;
+                if key == "webhook-batch-size" {
+                    self.webhook_batch_size = n;
+                } else {
+                    self.webhook_queue_size = n;
+                }
+            }
+            "webhook-batch-ms" => {
+                self.webhook_batch_delay = Duration::from_millis(
+                    value.parse().map_err(|_| format!("invalid webhook-batch-ms '{}'", value))?,
+                );
+            }
+            "write-behind" => {
+                self.write_behind = if value.is_empty() { None } else { Some(SqlTarget::parse(value)?) };
+            }
+            "write-behind-batch-size" | "write-behind-queue-size" => {
+                let n = value.parse().ok().filter(|n| *n > 0).ok_or_else(|| format!("invalid {} '{}'", key, value))?;
+                if key == "write-behind-batch-size" {
+                    self.write_behind_batch_size = n;
+                } else {
+                    self.write_behind_queue_size = n;
+                }
+            }
+            "write-behind-delay-ms" => {
+                self.write_behind_delay = Duration::from_millis(
+                    value.parse().map_err(|_| format!("invalid write-behind-delay-ms '{}'", value))?,
+                );
+            }
+            "write-behind-retries" => {
+                self.write_behind_retries = value.parse().map_err(|_| format!("invalid write-behind-retries '{}'", value))?;
+            }
+            "write-behind-dead-letter" => {
+                if value.is_empty() {
+                    return Err("write-behind-dead-letter needs a path".to_string());
+                }
+                self.write_behind_dead_letter = PathBuf::from(value);
+            }
+            "webhook-retries" => {
+                self.webhook_retries = value.parse().map_err(|_| format!("invalid webhook-retries '{}'", value))?;
+            }
+            "websocket-addr" => {
+                self.websocket_addr = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "tls-addr" => {
+                self.tls_addr = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "tls-cert-file" => {
+                self.tls_cert_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "tls-key-file" => {
+                self.tls_key_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "health-addr" => {
+                self.health_addr = if value.is_empty() { None } else { Some(value.to_string()) };
+            }
+            "record-file" => {
+                self.record_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "diagnostics-file" => {
+                self.diagnostics_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "replay-file" => {
+                self.replay_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "warmup-file" => {
+                self.warmup_file = if value.is_empty() { None } else { Some(PathBuf::from(value)) };
+            }
+            "warmup-async" => self.warmup_async = parse_yes_no(key, value)?,
+            "ip-allow" | "ip-deny" => {
+                let blocks = value.split_whitespace().map(Cidr::parse).collect::<Result<Vec<_>, _>>()?;
+                if key == "ip-allow" {
+                    self.ip_allow.extend(blocks);
+                } else {
+                    self.ip_deny.extend(blocks);
+                }
+            }
+            "websocket-origin" => {
+                if value.is_empty() {
+                    return Err("websocket-origin needs an origin".to_string());
+                }
+                self.websocket_origins.push(value.to_string());
+            }
+            "dashboard" => self.dashboard = parse_yes_no(key, value)?,
+            "proxy-backend" => {
+                if value.is_empty() {
+                    return Err("proxy-backend needs a host:port".to_string());
+                }
+                self.proxy_backends.push(value.to_string());
+            }
+            "loadplugin" => {
+                if value.is_empty() {
+                    return Err("loadplugin needs a path".to_string());
+                }
+                self.plugins.push(PathBuf::from(value));
+            }
+            other => return Err(format!("unknown directive '{}'", other)),
+        }
+        Ok(())
+    }
+
+    // One CONFIG SET directive. List directives are replaced rather than
+    // extended, from values separated by spaces as CONFIG GET shows them.
+    fn set_directive(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "user" | "maxmemory-db" | "webhook" | "loader" => return Err(format!("{} cannot be set at runtime; edit the config file and reload it", key)),
+            "ip-allow" => self.ip_allow.clear(),
+            "ip-deny" => self.ip_deny.clear(),
+            "websocket-origin" => self.websocket_origins.clear(),
+            "proxy-backend" => self.proxy_backends.clear(),
+            "raft-peer" => self.raft_peers.clear(),
+            "crdt-peer" => self.crdt_peers.clear(),
+            "cluster-seed" => self.cluster_seeds.clear(),
+            "cluster-slots" => self.cluster_slots.clear(),
+            "loadplugin" => self.plugins.clear(),
+            _ => return self.apply_directive(key, value),
+        }
+        value.split_whitespace().try_for_each(|v| self.apply_directive(key, v))
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.databases == 0 {
+            return Err("databases must be at least 1".to_string());
+        }
+        if let Some(index) = self.maxmemory_db.keys().find(|i| **i >= self.databases) {
+            return Err(format!("maxmemory-db index {} is out of range for {} databases", index, self.databases));
+        }
+        if !self.proxy_backends.is_empty() && self.backing_store.is_some() {
+            return Err("a proxy cannot also use a backing-store".to_string());
+        }
+        if !self.proxy_backends.is_empty() && !self.loaders.is_empty() {
+            return Err("a proxy cannot also use loaders".to_string());
+        }
+        if !self.proxy_backends.is_empty() && self.replicaof.is_some() {
+            return Err("a proxy cannot also be a replica".to_string());
+        }
+        if !self.proxy_backends.is_empty() && self.mirror_to.is_some() {
+            return Err("a proxy cannot also mirror writes".to_string());
+        }
+        if !self.proxy_backends.is_empty() && self.shadow_to.is_some() {
+            return Err("a proxy cannot also shadow commands".to_string());
+        }
+        if self.record_file.is_some() && self.replay_file.is_some() {
+            return Err("a server replaying a recording cannot also record".to_string());
+        }
+        if self.tls_addr.is_some() && (self.tls_cert_file.is_none() || self.tls_key_file.is_none()) {
+            return Err("tls-addr needs tls-cert-file and tls-key-file".to_string());
+        }
+        if let Some(node) = &self.raft_node {
+            // The raft log is where the data lives; nothing else may load it
+            if self.appendonly || self.snapshot_file.is_some() || self.rdb_file.is_some() {
+                return Err("raft mode keeps the data in raft-file; appendonly, dbfilename and rdbfilename must be off".to_string());
+            }
+            if self.replicaof.is_some() || !self.proxy_backends.is_empty() || self.backing_store.is_some() {
+                return Err("a raft member cannot also be a replica, a proxy or use a backing-store".to_string());
+            }
+            if self.raft_peers.contains(node) {
+                return Err("raft-peer lists the other members, not raft-node itself".to_string());
+            }
+        }
+        if self.cores > 0 && (self.backing_store.is_some() || !self.loaders.is_empty() || !self.proxy_backends.is_empty()) {
+            return Err("cores can't be combined with a backing-store, loaders or a proxy".to_string());
+        }
+        if self.crdt_id.is_some() && (self.raft_node.is_some() || self.replicaof.is_some() || !self.proxy_backends.is_empty()) {
+            return Err("an active-active member cannot also be a raft member, a replica or a proxy".to_string());
+        }
+        Ok(())
+    }
+
+    /// The memory budget of database `index`.
+    fn maxmemory_for(&self, index: usize) -> usize {
+        self.maxmemory_db.get(&index).copied().unwrap_or(self.maxmemory)
+    }
+
+    fn apply_env(&mut self) {
+        if let Ok(addr) = std::env::var("ADDR") {
+            self.addr = addr;
+        } else if let Some(port) = std::env::var("PORT").ok().and_then(|s| s.parse::<u16>().ok()) {
+            self.set_port(port);
+        }
+        if let Some(ms) = std::env::var("RUSTCACHE_REAPER_MS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .filter(|v| *v > 0)
+        {
+            self.reaper_interval = Duration::from_millis(ms);
+        }
+        if let Some(v) = std::env::var_os("RUSTCACHE_PLUGINS") {
+            self.plugins = std::env::split_paths(&v).filter(|p| !p.as_os_str().is_empty()).collect();
+        }
+    }
+
+    fn set_port(&mut self, port: u16) {
+        let host = self.addr.rsplit_once(':').map(|(h, _)| h).unwrap_or("127.0.0.1");
+        self.addr = format!("{}:{}", host, port);
+    }
+
+    /// Directive names and values as reported by CONFIG GET.
+    pub(crate) fn directives(&self) -> Vec<(&'static str, String)> {
+        let port = self.addr.rsplit_once(':').map(|(_, p)| p.to_string()).unwrap_or_default();
+        let plugins: Vec<String> = self.plugins.iter().map(|p| p.display().to_string()).collect();
+        let overrides: Vec<String> = self.maxmemory_db.iter().map(|(i, b)| format!("{} {}", i, b)).collect();
+        vec![
+            ("addr", self.addr.clone()),
+            ("port", port),
+            ("reaper-ms", self.reaper_interval.as_millis().to_string()),
+            ("maxidle", self.maxidle.as_secs().to_string()),
+            ("databases", self.databases.to_string()),
+            ("cores", self.cores.to_string()),
+            ("maxmemory", self.maxmemory.to_string()),
+            ("maxmemory-db", overrides.join(" ")),
+            ("maxmemory-policy", self.maxmemory_policy.name().to_string()),
+            ("maxmemory-accounting", self.maxmemory_accounting.name().to_string()),
+            ("activedefrag", yes_no(self.activedefrag)),
+            ("active-defrag-cycle-max", self.active_defrag_cycle_max.to_string()),
+            ("dbfilename", self.snapshot_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("rdbfilename", self.rdb_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("appendonly", yes_no(self.appendonly)),
+            ("appendfilename", self.aof_file.display().to_string()),
+            ("appendfsync", self.appendfsync.name().to_string()),
+            ("persistence-compression", self.compression.name().to_string()),
+            ("persistence-compression-level", self.compression_level.to_string()),
+            ("overflow-dir", self.overflow_dir.as_ref().map(|d| d.display().to_string()).unwrap_or_default()),
+            ("value-checksums", yes_no(self.value_checksums)),
+            ("backing-store", self.backing_store.as_ref().map(|b| b.describe()).unwrap_or_default()),
+            ("backing-write-through", yes_no(self.backing_write_through)),
+            ("loader", self.loaders.iter().map(Loader::describe).collect::<Vec<_>>().join(", ")),
+            ("loader-ttl", self.loader_ttl.as_secs().to_string()),
+            ("hotkeys-sample", self.hotkeys_sample.to_string()),
+            ("acllog-max-len", self.acllog_max_len.to_string()),
+            ("latency-monitor-threshold", self.latency_threshold.as_millis().to_string()),
+            ("busy-reply-threshold", self.busy_reply_threshold.as_millis().to_string()),
+            ("slowlog-log-slower-than", self.slowlog_log_slower_than.to_string()),
+            ("slowlog-max-len", self.slowlog_max_len.to_string()),
+            ("audit-sample", self.audit_sample.to_string()),
+            ("audit-max-len", self.audit_max_len.to_string()),
+            ("audit-file", self.audit_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("cluster-enabled", yes_no(self.cluster_enabled)),
+            ("cluster-seed", self.cluster_seeds.join(" ")),
+            ("cluster-slots", format_slots(&self.cluster_slots, " ")),
+            ("cluster-node-timeout", self.cluster_node_timeout.as_millis().to_string()),
+            ("cluster-announce-addr", self.cluster_announce_addr.clone().unwrap_or_default()),
+            ("proxy-backend", self.proxy_backends.join(" ")),
+            ("replicaof", self.replicaof.as_deref().map(|m| m.replacen(':', " ", 1)).unwrap_or_default()),
+            ("masteruser", self.masteruser.clone().unwrap_or_default()),
+            ("replica-read-only", yes_no(self.replica_read_only)),
+            ("replica-serve-stale-data", yes_no(self.replica_serve_stale_data)),
+            ("min-replicas-to-write", self.min_replicas_to_write.to_string()),
+            ("min-replicas-max-lag", self.min_replicas_max_lag.as_secs().to_string()),
+            ("raft-node", self.raft_node.clone().unwrap_or_default()),
+            ("raft-peer", self.raft_peers.join(" ")),
+            ("raft-file", self.raft_file.display().to_string()),
+            ("raft-election-timeout", self.raft_election_timeout.as_millis().to_string()),
+            ("crdt-id", self.crdt_id.clone().unwrap_or_default()),
+            ("crdt-peer", self.crdt_peers.join(" ")),
+            ("mirror-to", self.mirror_to.clone().unwrap_or_default()),
+            ("mirror-queue-size", self.mirror_queue_size.to_string()),
+            ("mirror-on-full", self.mirror_on_full.name().to_string()),
+            ("pubsub-queue-size", self.pubsub_queue_size.to_string()),
+            ("pubsub-slow-subscriber", self.pubsub_slow_subscriber.name().to_string()),
+            ("client-queue-depth", self.client_queue_depth.to_string()),
+            ("client-queue-bytes", self.client_queue_bytes.to_string()),
+            ("shadow-to", self.shadow_to.clone().unwrap_or_default()),
+            ("shadow-percent", self.shadow_percent.to_string()),
+            ("shadow-commands", self.shadow_commands.name().to_string()),
+            ("events-to", self.events_to.as_ref().map(|e| e.describe()).unwrap_or_default()),
+            ("events-format", self.events_format.name().to_string()),
+            ("events-queue-size", self.events_queue_size.to_string()),
+            ("webhook", self.webhooks.iter().map(Webhook::describe).collect::<Vec<_>>().join(", ")),
+            ("webhook-batch-size", self.webhook_batch_size.to_string()),
+            ("webhook-batch-ms", self.webhook_batch_delay.as_millis().to_string()),
+            ("webhook-retries", self.webhook_retries.to_string()),
+            ("webhook-queue-size", self.webhook_queue_size.to_string()),
+            ("write-behind", self.write_behind.as_ref().map(|t| t.describe()).unwrap_or_default()),
+            ("write-behind-batch-size", self.write_behind_batch_size.to_string()),
+            ("write-behind-delay-ms", self.write_behind_delay.as_millis().to_string()),
+            ("write-behind-retries", self.write_behind_retries.to_string()),
+            ("write-behind-queue-size", self.write_behind_queue_size.to_string()),
+            ("write-behind-dead-letter", self.write_behind_dead_letter.display().to_string()),
+            ("websocket-addr", self.websocket_addr.clone().unwrap_or_default()),
+            ("tls-addr", self.tls_addr.clone().unwrap_or_default()),
+            ("tls-cert-file", self.tls_cert_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("tls-key-file", self.tls_key_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("health-addr", self.health_addr.clone().unwrap_or_default()),
+            ("record-file", self.record_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("diagnostics-file", self.diagnostics_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("replay-file", self.replay_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("warmup-file", self.warmup_file.as_ref().map(|p| p.display().to_string()).unwrap_or_default()),
+            ("warmup-async", yes_no(self.warmup_async)),
+            ("ip-allow", self.ip_allow.iter().map(Cidr::to_string).collect::<Vec<_>>().join(" ")),
+            ("ip-deny", self.ip_deny.iter().map(Cidr::to_string).collect::<Vec<_>>().join(" ")),
+            ("websocket-origin", self.websocket_origins.join(" ")),
+            ("dashboard", yes_no(self.dashboard)),
+            ("loadplugin", plugins.join(" ")),
+        ]
+    }
+}
+
+fn parse_yes_no(directive: &str, value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("{} must be yes or no, not '{}'", directive, value)),
+    }
+}
+
+fn yes_no(flag: bool) -> String {
+    if flag { "yes" } else { "no" }.to_string()
+}
+
+/// Parses a redis.conf memory size: plain bytes or a k/kb/m/mb/g/gb suffix,
+/// where k is 1000 and kb is 1024.
+fn parse_memory(value: &str) -> Result<usize, String> {
+    let lower = value.to_ascii_lowercase();
+    let split = lower.find(|c: char| !c.is_ascii_digit()).unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid memory size '{}'", value)),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid memory size '{}'", value))
+}
+
+/// What a reload changed, by directive name.
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    pub applied: Vec<&'static str>,
+    pub restart_required: Vec<&'static str>,
+}
+
+/// The running configuration. Settings that may change at runtime are read
+/// from here on each use rather than captured at startup.
+pub(crate) struct Settings {
+    running: Mutex<ServerConfig>,
+    reaper_ms: AtomicU64,
+    // Shared with every database, which checks it on access
+    max_idle_ms: Arc<AtomicU64>,
+    hotkeys_sample: AtomicU32,
+    latency_ms: AtomicU64,
+    busy_ms: AtomicU64,
+    slowlog_us: AtomicI64,
+    slowlog: SlowLog,
+    // Percent of a core defragmentation may take; 0 when it is off
+    defrag_percent: AtomicU32,
+    audit_sample: AtomicU32,
+    audit: AuditLog,
+    cluster_enabled: AtomicBool,
+    read_only: AtomicBool,
+    refuse_stale: AtomicBool,
+    mirror_refuse: AtomicBool,
+    shadowing: Mutex<(u32, ShadowCommands)>,
+    pubsub_limit: Mutex<QueueLimit>,
+    client_queue_depth: AtomicUsize,
+    client_queue_bytes: AtomicUsize,
+    min_replicas: Mutex<(usize, Duration)>,
+    acl: RwLock<Arc<Acl>>,
+    acl_log: AclLog,
+    quotas: QuotaMeters,
+    ip_filter: RwLock<IpFilter>,
+    memory: RwLock<MemoryBudget>,
+    tls: Option<TlsCerts>,
+}
+
+struct MemoryBudget {
+    per_db: Vec<usize>,
+    policy: EvictionPolicy,
+    accounting: MemoryAccounting,
+}
+
+impl MemoryBudget {
+    // Sized by `databases`, which only changes on restart
+    fn new(databases: usize, config: &ServerConfig) -> Self {
+        Self {
+            per_db: (0..databases).map(|i| config.maxmemory_for(i)).collect(),
+            policy: config.maxmemory_policy,
+            accounting: config.maxmemory_accounting,
+        }
+    }
+}
+
+impl Settings {
+    pub fn new(config: ServerConfig) -> io::Result<Self> {
+        let acl = Acl::new(config.requirepass.as_deref(), &config.users)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            memory: RwLock::new(MemoryBudget::new(config.databases, &config)),
+            reaper_ms: AtomicU64::new(config.reaper_interval.as_millis() as u64),
+            max_idle_ms: Arc::new(AtomicU64::new(config.maxidle.as_millis() as u64)),
+            hotkeys_sample: AtomicU32::new(config.hotkeys_sample),
+            latency_ms: AtomicU64::new(config.latency_threshold.as_millis() as u64),
+            busy_ms: AtomicU64::new(config.busy_reply_threshold.as_millis() as u64),
+            slowlog_us: AtomicI64::new(config.slowlog_log_slower_than),
+            slowlog: SlowLog::new(config.slowlog_max_len),
+            defrag_percent: AtomicU32::new(if config.activedefrag { config.active_defrag_cycle_max } else { 0 }),
+            audit_sample: AtomicU32::new(config.audit_sample),
+            audit: AuditLog::new(config.audit_max_len, config.audit_file.as_deref())?,
+            cluster_enabled: AtomicBool::new(config.cluster_enabled),
+            read_only: AtomicBool::new(config.replicaof.is_some() && config.replica_read_only),
+            refuse_stale: AtomicBool::new(config.replicaof.is_some() && !config.replica_serve_stale_data),
+            mirror_refuse: AtomicBool::new(config.mirror_on_full == MirrorOnFull::Refuse),
+            // Nothing is sampled without a target to send it to
+            shadowing: Mutex::new((if config.shadow_to.is_some() { config.shadow_percent } else { 0 }, config.shadow_commands)),
+            pubsub_limit: Mutex::new((config.pubsub_queue_size, config.pubsub_slow_subscriber)),
+            client_queue_depth: AtomicUsize::new(config.client_queue_depth),
+            client_queue_bytes: AtomicUsize::new(config.client_queue_bytes),
+            min_replicas: Mutex::new((config.min_replicas_to_write, config.min_replicas_max_lag)),
+            acl_log: AclLog::new(config.acllog_max_len),
+            quotas: QuotaMeters::default(),
+            ip_filter: RwLock::new(IpFilter::new(&config.ip_allow, &config.ip_deny)),
+            tls: match (&config.tls_addr, &config.tls_cert_file, &config.tls_key_file) {
+                (Some(_), Some(cert), Some(key)) => Some(TlsCerts::new(cert, key)?),
+                _ => None,
+            },
+            running: Mutex::new(config),
+            acl: RwLock::new(Arc::new(acl)),
+        })
+    }
+
+    pub fn acl(&self) -> Arc<Acl> {
+        self.acl.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn acl_log(&self) -> &AclLog {
+        &self.acl_log
+    }
+
+    pub fn quotas(&self) -> &QuotaMeters {
+        &self.quotas
+    }
+
+    /// Whether `ip-allow` and `ip-deny` let a peer at `ip` connect.
+    pub fn ip_permitted(&self, ip: IpAddr) -> bool {
+        self.ip_filter.read().unwrap_or_else(|e| e.into_inner()).permits(ip)
+    }
+
+    /// The TLS listener's certificate, when there is a TLS listener.
+    pub fn tls(&self) -> Option<&TlsCerts> {
+        self.tls.as_ref()
+    }
+
+    pub fn reaper_interval(&self) -> Duration {
+        Duration::from_millis(self.reaper_ms.load(Ordering::Relaxed))
+    }
+
+    /// The live maxidle in milliseconds, for [`crate::db::Database::with_max_idle`].
+    pub fn max_idle(&self) -> Arc<AtomicU64> {
+        self.max_idle_ms.clone()
+    }
+
+    pub fn hotkeys_sample(&self) -> u32 {
+        self.hotkeys_sample.load(Ordering::Relaxed)
+    }
+
+    /// How long a command may take before it counts as slow, if tracked.
+    pub fn latency_threshold(&self) -> Option<Duration> {
+        Some(self.latency_ms.load(Ordering::Relaxed)).filter(|ms| *ms > 0).map(Duration::from_millis)
+    }
+
+    /// How long a cancellable command may run before it is aborted, if ever.
+    pub fn busy_reply_threshold(&self) -> Option<Duration> {
+        Some(self.busy_ms.load(Ordering::Relaxed)).filter(|ms| *ms > 0).map(Duration::from_millis)
+    }
+
+    /// How long a command may take before it goes to the slow log, if logged.
+    pub fn slowlog_threshold(&self) -> Option<Duration> {
+        u64::try_from(self.slowlog_us.load(Ordering::Relaxed)).ok().map(Duration::from_micros)
+    }
+
+    pub fn slowlog(&self) -> &SlowLog {
+        &self.slowlog
+    }
+
+    /// How much of each `period` defragmentation may work, if it is on.
+    pub fn defrag_budget(&self, period: Duration) -> Option<Duration> {
+        Some(self.defrag_percent.load(Ordering::Relaxed)).filter(|p| *p > 0).map(|p| period * p / 100)
+    }
+
+    /// One command with keys in how many is audited, or 0 for none.
+    pub fn audit_sample(&self) -> u32 {
+        self.audit_sample.load(Ordering::Relaxed)
+    }
+
+    pub fn audit(&self) -> &AuditLog {
+        &self.audit
+    }
+
+    pub fn cluster_enabled(&self) -> bool {
+        self.cluster_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Whether this replica refuses commands while its master link is down.
+    pub fn refuse_stale(&self) -> bool {
+        self.refuse_stale.load(Ordering::Relaxed)
+    }
+
+    /// Whether client writes are refused because this is a read-only replica.
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    /// The percentage of commands shadowed (0 when shadowing is off) and
+    /// which kinds.
+    pub fn shadowing(&self) -> (u32, ShadowCommands) {
+        *self.shadowing.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// How many replicas writes need in reach, and how recently each must
+    /// have acknowledged; None when writes need none.
+    pub fn min_replicas(&self) -> Option<(usize, Duration)> {
+        Some(*self.min_replicas.lock().unwrap_or_else(|e| e.into_inner())).filter(|(min, _)| *min > 0)
+    }
+
+    /// How many messages a subscriber may have queued, and what happens to
+    /// the next one.
+    pub fn pubsub_limit(&self) -> QueueLimit {
+        *self.pubsub_limit.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The read-ahead queue size for connections made from now on.
+    pub fn client_queue_depth(&self) -> usize {
+        self.client_queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// The read-ahead queue's byte budget for connections made from now on.
+    pub fn client_queue_bytes(&self) -> usize {
+        self.client_queue_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn mirror_on_full(&self) -> MirrorOnFull {
+        if self.mirror_refuse.load(Ordering::Relaxed) {
+            MirrorOnFull::Refuse
+        } else {
+            MirrorOnFull::Drop
+        }
+    }
+
+    /// The memory budget of database `index` (0 = unlimited) and the policy
+    /// used to stay within it.
+    pub fn memory_budget(&self, index: usize) -> (usize, EvictionPolicy) {
+        let memory = self.memory.read().unwrap_or_else(|e| e.into_inner());
+        (memory.per_db.get(index).copied().unwrap_or(0), memory.policy)
+    }
+
+    /// What counts against the memory budgets.
+    pub fn memory_accounting(&self) -> MemoryAccounting {
+        self.memory.read().unwrap_or_else(|e| e.into_inner()).accounting
+    }
+
+    pub fn current(&self) -> ServerConfig {
+        self.running.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Re-reads the config file and applies what can change without a restart.
+    /// On any parse error the running configuration is left untouched.
+    pub fn reload(&self) -> io::Result<ReloadReport> {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let path = match &running.config_file {
+            Some(p) => p.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no config file to reload")),
+        };
+        let fresh = ServerConfig::load(Some(path.clone()))?;
+        let report = self.apply(&mut running, &fresh);
+        println!(
+            "Config reloaded from {}: applied [{}], restart required for [{}]",
+            path.display(),
+            report.applied.join(", "),
+            report.restart_required.join(", ")
+        );
+        Ok(report)
+    }
+
+    /// Sets directives as CONFIG SET does, each value given as CONFIG GET
+    /// reports it; a list directive's value replaces the whole list. Nothing
+    /// changes if any of them is invalid. The config file is not touched, so
+    /// a reload goes back to what it says.
+    pub fn set(&self, directives: &[(String, String)]) -> Result<ReloadReport, String> {
+        let mut running = self.running.lock().unwrap_or_else(|e| e.into_inner());
+        let mut fresh = running.clone();
+        for (key, value) in directives {
+            fresh.set_directive(&key.to_ascii_lowercase(), value)?;
+        }
+        fresh.validate()?;
+        // Checked up front, so a pair that does not load is refused rather
+        // than only reported
+        if let (Some(_), Some(cert), Some(key)) = (&self.tls, &fresh.tls_cert_file, &fresh.tls_key_file) {
+            if fresh.tls_cert_file != running.tls_cert_file || fresh.tls_key_file != running.tls_key_file {
+                crate::tls::load(cert, key)?;
+            }
+        }
+        Ok(self.apply(&mut running, &fresh))
+    }
+
+    // Brings `running` up to `fresh` as far as that can be done live
+    fn apply(&self, running: &mut ServerConfig, fresh: &ServerConfig) -> ReloadReport {
+        let mut report = ReloadReport::default();
+        if fresh.reaper_interval != running.reaper_interval {
+            running.reaper_interval = fresh.reaper_interval;
+            self.reaper_ms.store(fresh.reaper_interval.as_millis() as u64, Ordering::Relaxed);
+            report.applied.push("reaper-ms");
+        }
+        if fresh.maxidle != running.maxidle {
+            running.maxidle = fresh.maxidle;
+            self.max_idle_ms.store(fresh.maxidle.as_millis() as u64, Ordering::Relaxed);
+            report.applied.push("maxidle");
+        }
+        if fresh.acllog_max_len != running.acllog_max_len {
+            running.acllog_max_len = fresh.acllog_max_len;
+            self.acl_log.set_max_len(fresh.acllog_max_len);
+            report.applied.push("acllog-max-len");
+        }
+        if fresh.hotkeys_sample != running.hotkeys_sample {
+            running.hotkeys_sample = fresh.hotkeys_sample;
+            self.hotkeys_sample.store(fresh.hotkeys_sample, Ordering::Relaxed);
+            report.applied.push("hotkeys-sample");
+        }
+        if fresh.latency_threshold != running.latency_threshold {
+            running.latency_threshold = fresh.latency_threshold;
+            self.latency_ms.store(fresh.latency_threshold.as_millis() as u64, Ordering::Relaxed);
+            report.applied.push("latency-monitor-threshold");
+        }
+        if fresh.busy_reply_threshold != running.busy_reply_threshold {
+            running.busy_reply_threshold = fresh.busy_reply_threshold;
+            self.busy_ms.store(fresh.busy_reply_threshold.as_millis() as u64, Ordering::Relaxed);
+            report.applied.push("busy-reply-threshold");
+        }
+        if fresh.slowlog_log_slower_than != running.slowlog_log_slower_than {
+            running.slowlog_log_slower_than = fresh.slowlog_log_slower_than;
+            self.slowlog_us.store(fresh.slowlog_log_slower_than, Ordering::Relaxed);
+            report.applied.push("slowlog-log-slower-than");
+        }
+        if fresh.slowlog_max_len != running.slowlog_max_len {
+            running.slowlog_max_len = fresh.slowlog_max_len;
+            self.slowlog.set_max_len(fresh.slowlog_max_len);
+            report.applied.push("slowlog-max-len");
+        }
+        if fresh.activedefrag != running.activedefrag || fresh.active_defrag_cycle_max != running.active_defrag_cycle_max {
+            running.activedefrag = fresh.activedefrag;
+            running.active_defrag_cycle_max = fresh.active_defrag_cycle_max;
+            let percent = if fresh.activedefrag { fresh.active_defrag_cycle_max } else { 0 };
+            self.defrag_percent.store(percent, Ordering::Relaxed);
+            report.applied.push("activedefrag");
+        }
+        if fresh.audit_sample != running.audit_sample {
+            running.audit_sample = fresh.audit_sample;
+            self.audit_sample.store(fresh.audit_sample, Ordering::Relaxed);
+            report.applied.push("audit-sample");
+        }
+        if fresh.audit_max_len != running.audit_max_len {
+            running.audit_max_len = fresh.audit_max_len;
+            self.audit.set_max_len(fresh.audit_max_len);
+            report.applied.push("audit-max-len");
+        }
+        if fresh.audit_file != running.audit_file {
+            report.restart_required.push("audit-file");
+        }
+        if fresh.cluster_enabled != running.cluster_enabled {
+            running.cluster_enabled = fresh.cluster_enabled;
+            self.cluster_enabled.store(fresh.cluster_enabled, Ordering::Relaxed);
+            report.applied.push("cluster-enabled");
+        }
+        if fresh.cluster_seeds != running.cluster_seeds
+            || fresh.cluster_slots != running.cluster_slots
+            || fresh.cluster_node_timeout != running.cluster_node_timeout
+            || fresh.cluster_announce_addr != running.cluster_announce_addr
+        {
+            report.restart_required.push("cluster-slots");
+        }
+        if fresh.requirepass != running.requirepass || fresh.users != running.users {
+            // Validated by load(); connections keep the user they authenticated as
+            if let Ok(acl) = Acl::new(fresh.requirepass.as_deref(), &fresh.users) {
+                *self.acl.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(acl);
+            }
+            running.requirepass = fresh.requirepass.clone();
+            running.users = fresh.users.clone();
+            report.applied.push("user");
+        }
+        if fresh.maxmemory != running.maxmemory
+            || fresh.maxmemory_db != running.maxmemory_db
+            || fresh.maxmemory_policy != running.maxmemory_policy
+            || fresh.maxmemory_accounting != running.maxmemory_accounting
+        {
+            if fresh.maxmemory_db.keys().all(|i| *i < running.databases) {
+                *self.memory.write().unwrap_or_else(|e| e.into_inner()) = MemoryBudget::new(running.databases, fresh);
+                running.maxmemory = fresh.maxmemory;
+                running.maxmemory_db = fresh.maxmemory_db.clone();
+                running.maxmemory_policy = fresh.maxmemory_policy;
+                running.maxmemory_accounting = fresh.maxmemory_accounting;
+                report.applied.push("maxmemory");
+            } else {
+                // Budgets for databases that only exist after a restart
+                report.restart_required.push("maxmemory");
+            }
+        }
+        if fresh.backing_store != running.backing_store || fresh.backing_write_through != running.backing_write_through {
+            report.restart_required.push("backing-store");
+        }
+        if fresh.loaders != running.loaders || fresh.loader_ttl != running.loader_ttl {
+            report.restart_required.push("loader");
+        }
+        if fresh.snapshot_file != running.snapshot_file {
+            running.snapshot_file = fresh.snapshot_file.clone();
+            report.applied.push("dbfilename");
+        }
+        if fresh.rdb_file != running.rdb_file {
+            running.rdb_file = fresh.rdb_file.clone();
+            report.applied.push("rdbfilename");
+        }
+        if fresh.appendonly != running.appendonly || fresh.aof_file != running.aof_file || fresh.appendfsync != running.appendfsync {
+            report.restart_required.push("appendonly");
+        }
+        if fresh.compression != running.compression {
+            // The next snapshot or new AOF file is written the new way
+            running.compression = fresh.compression;
+            running.compression_level = fresh.compression_level;
+            report.applied.push("persistence-compression");
+        }
+        if fresh.overflow_dir != running.overflow_dir {
+            report.restart_required.push("overflow-dir");
+        }
+        if fresh.value_checksums != running.value_checksums {
+            report.restart_required.push("value-checksums");
+        }
+        if fresh.databases != running.databases {
+            report.restart_required.push("databases");
+        }
+        if fresh.cores != running.cores {
+            report.restart_required.push("cores");
+        }
+        if fresh.addr != running.addr {
+            report.restart_required.push("addr");
+        }
+        if fresh.plugins != running.plugins {
+            report.restart_required.push("loadplugin");
+        }
+        if fresh.proxy_backends != running.proxy_backends {
+            report.restart_required.push("proxy-backend");
+        }
+        if fresh.replicaof != running.replicaof {
+            report.restart_required.push("replicaof");
+        }
+        if fresh.raft_node != running.raft_node
+            || fresh.raft_peers != running.raft_peers
+            || fresh.raft_file != running.raft_file
+            || fresh.raft_election_timeout != running.raft_election_timeout
+        {
+            report.restart_required.push("raft-node");
+        }
+        if fresh.crdt_id != running.crdt_id || fresh.crdt_peers != running.crdt_peers {
+            report.restart_required.push("crdt-id");
+        }
+        if fresh.mirror_to != running.mirror_to || fresh.mirror_queue_size != running.mirror_queue_size {
+            report.restart_required.push("mirror-to");
+        }
+        if fresh.shadow_to != running.shadow_to {
+            report.restart_required.push("shadow-to");
+        }
+        if fresh.events_to != running.events_to
+            || fresh.events_format != running.events_format
+            || fresh.events_queue_size != running.events_queue_size
+        {
+            report.restart_required.push("events-to");
+        }
+        if fresh.webhooks != running.webhooks
+            || fresh.webhook_secret != running.webhook_secret
+            || fresh.webhook_batch_size != running.webhook_batch_size
+            || fresh.webhook_batch_delay != running.webhook_batch_delay
+            || fresh.webhook_retries != running.webhook_retries
+            || fresh.webhook_queue_size != running.webhook_queue_size
+        {
+            report.restart_required.push("webhook");
+        }
+        if fresh.write_behind != running.write_behind
+            || fresh.write_behind_batch_size != running.write_behind_batch_size
+            || fresh.write_behind_delay != running.write_behind_delay
+            || fresh.write_behind_retries != running.write_behind_retries
+            || fresh.write_behind_queue_size != running.write_behind_queue_size
+            || fresh.write_behind_dead_letter != running.write_behind_dead_letter
+        {
+            report.restart_required.push("write-behind");
+        }
+        if fresh.shadow_percent != running.shadow_percent || fresh.shadow_commands != running.shadow_commands {
+            running.shadow_percent = fresh.shadow_percent;
+            running.shadow_commands = fresh.shadow_commands;
+            let percent = if running.shadow_to.is_some() { fresh.shadow_percent } else { 0 };
+            *self.shadowing.lock().unwrap_or_else(|e| e.into_inner()) = (percent, fresh.shadow_commands);
+            report.applied.push("shadow-percent");
+        }
+        if fresh.mirror_on_full != running.mirror_on_full {
+            running.mirror_on_full = fresh.mirror_on_full;
+            self.mirror_refuse.store(fresh.mirror_on_full == MirrorOnFull::Refuse, Ordering::Relaxed);
+            report.applied.push("mirror-on-full");
+        }
+        if fresh.pubsub_queue_size != running.pubsub_queue_size || fresh.pubsub_slow_subscriber != running.pubsub_slow_subscriber {
+            running.pubsub_queue_size = fresh.pubsub_queue_size;
+            running.pubsub_slow_subscriber = fresh.pubsub_slow_subscriber;
+            *self.pubsub_limit.lock().unwrap_or_else(|e| e.into_inner()) = (fresh.pubsub_queue_size, fresh.pubsub_slow_subscriber);
+            report.applied.push("pubsub-queue-size");
+        }
+        if fresh.client_queue_depth != running.client_queue_depth {
+            running.client_queue_depth = fresh.client_queue_depth;
+            self.client_queue_depth.store(fresh.client_queue_depth, Ordering::Relaxed);
+            report.applied.push("client-queue-depth");
+        }
+        if fresh.client_queue_bytes != running.client_queue_bytes {
+            running.client_queue_bytes = fresh.client_queue_bytes;
+            self.client_queue_bytes.store(fresh.client_queue_bytes, Ordering::Relaxed);
+            report.applied.push("client-queue-bytes");
+        }
+        if fresh.websocket_addr != running.websocket_addr {
+            report.restart_required.push("websocket-addr");
+        }
+        if fresh.tls_addr != running.tls_addr {
+            report.restart_required.push("tls-addr");
+        }
+        if fresh.tls_cert_file != running.tls_cert_file || fresh.tls_key_file != running.tls_key_file {
+            let replaced = match (&self.tls, &fresh.tls_cert_file, &fresh.tls_key_file) {
+                (Some(tls), Some(cert), Some(key)) => tls.replace(cert, key).map_err(|e| eprintln!("Keeping the current TLS certificate: {}", e)).is_ok(),
+                // Nothing is serving them until a restart
+                _ => true,
+            };
+            if replaced {
+                running.tls_cert_file = fresh.tls_cert_file.clone();
+                running.tls_key_file = fresh.tls_key_file.clone();
+                report.applied.push("tls-cert-file");
+            }
+        }
+        if fresh.health_addr != running.health_addr {
+            report.restart_required.push("health-addr");
+        }
+        if fresh.diagnostics_file != running.diagnostics_file {
+            running.diagnostics_file = fresh.diagnostics_file.clone();
+            report.applied.push("diagnostics-file");
+        }
+        if fresh.record_file != running.record_file || fresh.replay_file != running.replay_file {
+            report.restart_required.push("record-file");
+        }
+        if fresh.warmup_file != running.warmup_file || fresh.warmup_async != running.warmup_async {
+            report.restart_required.push("warmup-file");
+        }
+        if fresh.websocket_origins != running.websocket_origins {
+            // Checked on each new WebSocket handshake
+            running.websocket_origins = fresh.websocket_origins.clone();
+            report.applied.push("websocket-origin");
+        }
+        if fresh.dashboard != running.dashboard {
+            running.dashboard = fresh.dashboard;
+            report.applied.push("dashboard");
+        }
+        if fresh.masterauth != running.masterauth || fresh.masteruser != running.masteruser {
+            // Used from the next connection to the master on
+            running.masterauth = fresh.masterauth.clone();
+            running.masteruser = fresh.masteruser.clone();
+            report.applied.push("masterauth");
+        }
+        if fresh.replica_read_only != running.replica_read_only {
+            running.replica_read_only = fresh.replica_read_only;
+            self.read_only.store(running.replicaof.is_some() && fresh.replica_read_only, Ordering::Relaxed);
+            report.applied.push("replica-read-only");
+        }
+        if fresh.replica_serve_stale_data != running.replica_serve_stale_data {
+            running.replica_serve_stale_data = fresh.replica_serve_stale_data;
+            self.refuse_stale.store(running.replicaof.is_some() && !fresh.replica_serve_stale_data, Ordering::Relaxed);
+            report.applied.push("replica-serve-stale-data");
+        }
+        if fresh.min_replicas_to_write != running.min_replicas_to_write || fresh.min_replicas_max_lag != running.min_replicas_max_lag {
+            running.min_replicas_to_write = fresh.min_replicas_to_write;
+            running.min_replicas_max_lag = fresh.min_replicas_max_lag;
+            *self.min_replicas.lock().unwrap_or_else(|e| e.into_inner()) = (fresh.min_replicas_to_write, fresh.min_replicas_max_lag);
+            report.applied.push("min-replicas-to-write");
+        }
+        if fresh.ip_allow != running.ip_allow || fresh.ip_deny != running.ip_deny {
+            // Checked as each new connection is accepted
+            running.ip_allow = fresh.ip_allow.clone();
+            running.ip_deny = fresh.ip_deny.clone();
+            *self.ip_filter.write().unwrap_or_else(|e| e.into_inner()) = IpFilter::new(&fresh.ip_allow, &fresh.ip_deny);
+            report.applied.push("ip-allow");
+        }
+        report
+    }
+}
@@ -0,0 +1,123 @@
+//! Thread-per-core execution.
+//!
+//! With `cores` set, the server starts that many threads, each running its
+//! own single-threaded runtime, and gives each the shards of the key store
+//! whose index modulo `cores` is its number. A command whose keys all live
+//! on one core's shards is sent to that core over a channel and runs there,
+//! so routed commands never contend with each other for a shard: two of
+//! them touching the same shard run one after the other on its core. The
+//! shard locks are still taken from other threads by the expiry reaper,
+//! active defrag, eviction, SCAN and commands whose keys span cores, so a
+//! routed command can wait on those. The frame and the connection's state
+//! travel with the command and come back with the reply.
+//!
+//! Commands without keys, or with keys on several cores (MGET across
+//! shards, SCAN, FLUSHDB, ...), run on the connection's own task as they
+//! always do, as do commands from namespaced users, whose keys are
+//! rewritten after routing would have to be decided.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::client::ClientState;
+use crate::commands::{Context, IoJob, Registry};
+use crate::config::Settings;
+use crate::db::{BlockRequest, Database};
+use crate::resp::RespValue;
+
+struct Job {
+    frame: RespValue,
+    client: ClientState,
+    keep_args: bool,
+    done: oneshot::Sender<Ran>,
+}
+
+/// What running a command on a core left: its reply, the frame it ran,
+/// the connection's state, and the work the command handed back to the
+/// connection.
+pub(crate) struct Ran {
+    pub reply: RespValue,
+    pub frame: RespValue,
+    pub client: ClientState,
+    pub block: Option<BlockRequest>,
+    pub io: Option<IoJob>,
+}
+
+struct Core {
+    jobs: mpsc::UnboundedSender<Job>,
+    /// Commands run here.
+    commands: AtomicU64,
+}
+
+/// The cores and the channels to them.
+pub(crate) struct Cores {
+    cores: Vec<Core>,
+    // Taken by stop()
+    stops: Mutex<Vec<oneshot::Sender<()>>>,
+}
+
+impl Cores {
+    /// Starts `count` core threads, which run until stop().
+    pub fn start(count: usize, dbs: Arc<[Database]>, settings: Arc<Settings>, registry: Arc<Registry>) -> std::io::Result<Self> {
+        let (mut cores, mut stops) = (Vec::with_capacity(count), Vec::with_capacity(count));
+        for i in 0..count {
+            let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+            let (stop, mut stopped) = oneshot::channel::<()>();
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+            let (dbs, settings, registry) = (dbs.clone(), settings.clone(), registry.clone());
+            std::thread::Builder::new().name(format!("core-{}", i)).spawn(move || {
+                runtime.block_on(async move {
+                    loop {
+                        let job = tokio::select! {
+                            job = queue.recv() => job,
+                            _ = &mut stopped => None,
+                        };
+                        let Some(job) = job else { break };
+                        let _ = job.done.send(run(job.frame, job.client, job.keep_args, &dbs, &settings, &registry));
+                    }
+                })
+            })?;
+            cores.push(Core { jobs, commands: AtomicU64::new(0) });
+            stops.push(stop);
+        }
+        Ok(Self { cores, stops: Mutex::new(stops) })
+    }
+
+    /// Stops the core threads; commands sent to them afterwards fail.
+    pub fn stop(&self) {
+        self.stops.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+
+    /// The core that owns every key `frame` names on database `db`, if there
+    /// is one.
+    pub fn route(&self, registry: &Registry, db: &Database, frame: &RespValue) -> Option<usize> {
+        let mut cores = registry.keys(frame).into_iter().map(|key| db.shard_of(key) % self.cores.len());
+        let first = cores.next()?;
+        cores.all(|c| c == first).then_some(first)
+    }
+
+    /// Runs `frame` for `client` on core `core`; None if the core stopped.
+    /// `keep_args` is set when the connection reads the frame afterwards.
+    pub async fn run(&self, core: usize, frame: RespValue, client: ClientState, keep_args: bool) -> Option<Ran> {
+        let core = &self.cores[core];
+        let (done, ran) = oneshot::channel();
+        core.jobs.send(Job { frame, client, keep_args, done }).ok()?;
+        core.commands.fetch_add(1, Ordering::Relaxed);
+        ran.await.ok()
+    }
+
+    /// Commands each core has run.
+    pub fn commands(&self) -> Vec<u64> {
+        self.cores.iter().map(|c| c.commands.load(Ordering::Relaxed)).collect()
+    }
+}
+
+fn run(mut frame: RespValue, mut client: ClientState, keep_args: bool, dbs: &[Database], settings: &Settings, registry: &Registry) -> Ran {
+    let db = &dbs[client.db];
+    let mut ctx = Context { db, dbs, settings, registry, client: &mut client, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args, slowlog_args: None };
+    let reply = registry.dispatch(&mut ctx, &mut frame);
+    let (block, io) = (ctx.block.take(), ctx.io.take());
+    Ran { reply, frame, client, block, io }
+}
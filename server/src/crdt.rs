@@ -0,0 +1,516 @@
+//! Active-active replication: every member takes writes, and the members
+//! converge by exchanging CRDT state.
+//!
+//! With `crdt-id` set, this server is one region of a multi-master group
+//! with its `crdt-peer`s. Each key written here carries metadata that
+//! merges deterministically whatever order it arrives in:
+//!
+//! - SET, MSET, DEL and UNLINK are last-writer-wins: each write is stamped
+//!   with the server clock's time and the member's id, and the latest stamp
+//!   wins.
+//! - INCR and DECR count per member since the key was last set, as a
+//!   PN-counter: concurrent increments in different regions all add up. A
+//!   SET that wins discards the counts made against the value it replaced.
+//!
+//! A key's state is pushed to every peer after each change, with
+//! `CRDT MERGE`, and all of it again whenever a link is re-established, so
+//! a peer that restarted (the metadata lives in memory only) is brought
+//! back up to date. A deleted key's state, its tombstone, is forgotten once
+//! every peer has acknowledged it and [`TOMBSTONE_GRACE`] has passed since
+//! its stamp, so a write older than the delete that was still on its way
+//! cannot bring the key back. Other writes (expiry, FLUSHDB, SET with options) could
+//! not be merged and are refused; the tree has no set type to merge yet.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::clock::Clock;
+use crate::commands::{flags, CommandSpec, Context, Middleware};
+use crate::config::Settings;
+use crate::db::Database;
+use crate::error::CommandError;
+use crate::resp::{read_resp, RespValue};
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+// How often an idle link is checked with a PING
+const HEARTBEAT: Duration = Duration::from_secs(1);
+// Keys sent to a peer per pipelined batch
+const BATCH: usize = 512;
+/// How long after its stamp an acknowledged tombstone is kept.
+pub(crate) const TOMBSTONE_GRACE: Duration = Duration::from_secs(60);
+
+// The writes whose effects merge
+const MERGED_COMMANDS: &[&str] = &["set", "mset", "incr", "decr", "del", "unlink"];
+
+/// When a key was last set or deleted, and by which member; later stamps
+/// win, ties going to the greater member id.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Stamp {
+    pub ms: i64,
+    pub node: String,
+}
+
+/// A key's mergeable state.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct KeyState {
+    pub stamp: Stamp,
+    /// The value last set; None once deleted (or never set).
+    pub base: Option<Vec<u8>>,
+    /// Each member's increments and decrements since `stamp`.
+    pub counts: BTreeMap<String, (u64, u64)>,
+}
+
+impl KeyState {
+    /// Folds `other` in; true if anything changed.
+    pub fn merge(&mut self, other: KeyState) -> bool {
+        match other.stamp.cmp(&self.stamp) {
+            std::cmp::Ordering::Greater => {
+                *self = other;
+                true
+            }
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Equal => {
+                // Keys adopted from data already here share the zero stamp;
+                // the greater value wins so every member picks the same
+                let mut changed = false;
+                if other.base > self.base {
+                    self.base = other.base;
+                    changed = true;
+                }
+                for (node, (incr, decr)) in other.counts {
+                    let counts = self.counts.entry(node).or_default();
+                    if incr > counts.0 || decr > counts.1 {
+                        *counts = (counts.0.max(incr), counts.1.max(decr));
+                        changed = true;
+                    }
+                }
+                changed
+            }
+        }
+    }
+
+    /// The value the key holds: the base, plus the counts when it is an
+    /// integer (or absent).
+    pub fn value(&self) -> Option<Vec<u8>> {
+        if self.counts.is_empty() {
+            return self.base.clone();
+        }
+        let base: i128 = match &self.base {
+            None => 0,
+            Some(b) => match std::str::from_utf8(b).ok().and_then(|s| s.parse::<i64>().ok()) {
+                Some(n) => n as i128,
+                None => return self.base.clone(),
+            },
+        };
+        let counted: i128 = self.counts.values().map(|(incr, decr)| *incr as i128 - *decr as i128).sum();
+        let total = (base + counted).clamp(i64::MIN as i128, i64::MAX as i128);
+        Some(total.to_string().into_bytes())
+    }
+
+    /// Whether the key is deleted (or was never set) with nothing counted
+    /// since.
+    pub fn is_tombstone(&self) -> bool {
+        self.base.is_none() && self.counts.is_empty()
+    }
+}
+
+type Key = (usize, String);
+
+#[derive(Default)]
+struct Store {
+    /// Each key's state and the version it last changed at.
+    keys: HashMap<Key, (KeyState, u64)>,
+    /// The keys by the version they last changed at, for the peers to send.
+    changes: BTreeMap<u64, Key>,
+    /// The versions in `changes` whose key is a tombstone.
+    tombstones: BTreeSet<u64>,
+    version: u64,
+}
+
+/// A link to a peer, as INFO replication reports it.
+#[derive(Default)]
+struct Link {
+    up: AtomicBool,
+    /// The last version sent over it, and acknowledged.
+    sent: AtomicU64,
+}
+
+/// This member's CRDT state and its links to the peers.
+pub(crate) struct Crdt {
+    node: String,
+    peers: Vec<String>,
+    links: Vec<Link>,
+    store: Mutex<Store>,
+    clock: Clock,
+    /// Bumped when a key changes.
+    outbox: watch::Sender<u64>,
+    /// Merges from peers that changed a key here.
+    pub merged: AtomicU64,
+}
+
+/// A peer's line in INFO replication.
+pub(crate) struct PeerStatus {
+    pub addr: String,
+    pub up: bool,
+    /// Changes not yet sent to it.
+    pub unsent: usize,
+}
+
+impl Crdt {
+    pub fn new(node: &str, peers: &[String], clock: Clock) -> Self {
+        Self {
+            node: node.to_string(),
+            peers: peers.to_vec(),
+            links: peers.iter().map(|_| Link::default()).collect(),
+            store: Mutex::new(Store::default()),
+            clock,
+            outbox: watch::Sender::new(0),
+            merged: AtomicU64::new(0),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Store> {
+        self.store.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    pub fn keys(&self) -> usize {
+        self.lock().keys.len()
+    }
+
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        let store = self.lock();
+        self.peers
+            .iter()
+            .zip(&self.links)
+            .map(|(addr, link)| PeerStatus {
+                addr: addr.clone(),
+                up: link.up.load(Ordering::Relaxed),
+                unsent: store.changes.range(link.sent.load(Ordering::Relaxed) + 1..).count(),
+            })
+            .collect()
+    }
+
+    /// Starts tracking `key` from the value it already holds, unless it is
+    /// tracked.
+    fn adopt(&self, db: &Database, index: usize, key: &str) {
+        let mut store = self.lock();
+        if !store.keys.contains_key(&(index, key.to_string())) {
+            let state = KeyState { base: db.peek(key), ..KeyState::default() };
+            Self::changed(&mut store, (index, key.to_string()), state);
+        }
+    }
+
+    // Stores `state` as the key's latest, to be sent to the peers
+    fn changed(store: &mut Store, key: Key, state: KeyState) {
+        store.version += 1;
+        let version = store.version;
+        if state.is_tombstone() {
+            store.tombstones.insert(version);
+        }
+        if let Some((_, old)) = store.keys.insert(key.clone(), (state, version)) {
+            store.changes.remove(&old);
+            store.tombstones.remove(&old);
+        }
+        store.changes.insert(version, key);
+    }
+
+    /// Forgets the tombstones every peer has acknowledged whose grace has
+    /// passed. A key written again is adopted afresh, from no value.
+    fn collect(&self) {
+        let mut store = self.lock();
+        let acked = self.links.iter().map(|l| l.sent.load(Ordering::Relaxed)).min().unwrap_or(store.version);
+        let horizon = self.clock.unix_ms() - TOMBSTONE_GRACE.as_millis() as i64;
+        let expired: Vec<u64> = store
+            .tombstones
+            .range(..=acked)
+            .filter(|v| store.keys[&store.changes[v]].0.stamp.ms <= horizon)
+            .copied()
+            .collect();
+        for version in expired {
+            store.tombstones.remove(&version);
+            if let Some(key) = store.changes.remove(&version) {
+                store.keys.remove(&key);
+            }
+        }
+    }
+
+    /// Applies a local write to `key`'s state, then makes the key hold the
+    /// merged value, in case a merge landed while the write ran.
+    fn update(&self, db: &Database, index: usize, key: &str, f: impl FnOnce(&mut KeyState, &str)) {
+        let mut store = self.lock();
+        let mut state = store.keys.get(&(index, key.to_string())).map(|(s, _)| s.clone()).unwrap_or_default();
+        f(&mut state, &self.node);
+        materialize(db, key, &state);
+        Self::changed(&mut store, (index, key.to_string()), state);
+        drop(store);
+        self.outbox.send_modify(|n| *n += 1);
+    }
+
+    // A stamp later than `current`
+    fn stamp(&self, current: &Stamp) -> Stamp {
+        Stamp { ms: self.clock.unix_ms().max(current.ms + 1), node: self.node.clone() }
+    }
+
+    fn set(&self, db: &Database, index: usize, key: &str, value: Vec<u8>) {
+        self.update(db, index, key, |state, _| {
+            *state = KeyState { stamp: self.stamp(&state.stamp), base: Some(value), counts: BTreeMap::new() };
+        });
+    }
+
+    fn delete(&self, db: &Database, index: usize, key: &str) {
+        self.update(db, index, key, |state, _| {
+            *state = KeyState { stamp: self.stamp(&state.stamp), base: None, counts: BTreeMap::new() };
+        });
+    }
+
+    fn count(&self, db: &Database, index: usize, key: &str, delta: i64) {
+        self.update(db, index, key, |state, node| {
+            let counts = state.counts.entry(node.to_string()).or_default();
+            if delta >= 0 {
+                counts.0 += delta as u64;
+            } else {
+                counts.1 += delta.unsigned_abs();
+            }
+        });
+    }
+
+    /// Merges a peer's state for `key` into database `index`.
+    pub fn merge(&self, db: &Database, index: usize, key: &str, incoming: KeyState) {
+        let mut store = self.lock();
+        let mut state = store.keys.get(&(index, key.to_string())).map(|(s, _)| s.clone()).unwrap_or_default();
+        if !state.merge(incoming) {
+            return;
+        }
+        materialize(db, key, &state);
+        Self::changed(&mut store, (index, key.to_string()), state);
+        drop(store);
+        self.merged.fetch_add(1, Ordering::Relaxed);
+        self.outbox.send_modify(|n| *n += 1);
+    }
+
+    // Up to `limit` keys changed after `version`, oldest change first
+    fn changes_after(&self, version: u64, limit: usize) -> Vec<(u64, Key, KeyState)> {
+        let store = self.lock();
+        store
+            .changes
+            .range(version + 1..)
+            .take(limit)
+            .map(|(v, key)| (*v, key.clone(), store.keys[key].0.clone()))
+            .collect()
+    }
+}
+
+// Makes `key` hold the value `state` merges to
+fn materialize(db: &Database, key: &str, state: &KeyState) {
+    let value = state.value();
+    if db.peek(key) == value {
+        return;
+    }
+    match value {
+        Some(value) => db.set(key.to_string(), value, None),
+        None => {
+            db.del(&[key]);
+        }
+    }
+}
+
+/// The arguments of `CRDT MERGE` for a key's state:
+/// `<db> <key> <ms> <node> <has-base> <base> [<node> <incr> <decr>] ...`.
+fn merge_args(index: usize, key: &str, state: &KeyState) -> Vec<Vec<u8>> {
+    let mut args = vec![
+        b"CRDT".to_vec(),
+        b"MERGE".to_vec(),
+        index.to_string().into_bytes(),
+        key.as_bytes().to_vec(),
+        state.stamp.ms.to_string().into_bytes(),
+        state.stamp.node.as_bytes().to_vec(),
+        (state.base.is_some() as u8).to_string().into_bytes(),
+        state.base.clone().unwrap_or_default(),
+    ];
+    for (node, (incr, decr)) in &state.counts {
+        args.extend([node.as_bytes().to_vec(), incr.to_string().into_bytes(), decr.to_string().into_bytes()]);
+    }
+    args
+}
+
+/// A key's state from the `CRDT MERGE` arguments after `<db> <key>`.
+pub(crate) fn parse_state(args: &[Vec<u8>]) -> Option<KeyState> {
+    let text = |b: &[u8]| std::str::from_utf8(b).ok().map(str::to_string);
+    let [ms, node, has_base, base, counts @ ..] = args else { return None };
+    if !counts.len().is_multiple_of(3) {
+        return None;
+    }
+    let counts = counts
+        .chunks(3)
+        .map(|c| Some((text(&c[0])?, (text(&c[1])?.parse().ok()?, text(&c[2])?.parse().ok()?))))
+        .collect::<Option<BTreeMap<_, _>>>()?;
+    Some(KeyState {
+        stamp: Stamp { ms: text(ms)?.parse().ok()?, node: text(node)? },
+        base: (has_base.as_slice() == b"1").then(|| base.clone()),
+        counts,
+    })
+}
+
+/// Refuses writes whose effects could not be merged, and records the
+/// state of those that can.
+pub(crate) struct ActiveActive;
+
+fn written_keys<'a>(cmd: &str, args: &'a [RespValue]) -> Vec<&'a RespValue> {
+    match cmd {
+        "mset" => args.iter().step_by(2).collect(),
+        "del" | "unlink" => args.iter().collect(),
+        _ => args.iter().take(1).collect(),
+    }
+}
+
+fn text(arg: &RespValue) -> Option<String> {
+    match arg {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b.clone()).ok(),
+        _ => None,
+    }
+}
+
+impl Middleware for ActiveActive {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, spec: &CommandSpec, args: &[RespValue]) -> Option<RespValue> {
+        let crdt = ctx.db.stats.crdt.get()?;
+        if !spec.has(flags::WRITE) {
+            return None;
+        }
+        if !MERGED_COMMANDS.contains(&cmd) || (cmd == "set" && args.len() > 2) {
+            return Some(CommandError::generic(format!("'{}' can't be merged and is not allowed in active-active mode", cmd)).into());
+        }
+        if ctx.client.namespace().is_some() {
+            return Some(CommandError::generic("namespaced users can't write in active-active mode").into());
+        }
+        for key in written_keys(cmd, args).into_iter().filter_map(text) {
+            crdt.adopt(ctx.db, ctx.client.db, &key);
+        }
+        None
+    }
+
+    fn reads_args(&self, _ctx: &Context<'_>) -> bool {
+        true
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        _spec: &CommandSpec,
+        args: &[RespValue],
+        reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        let Some(crdt) = ctx.db.stats.crdt.get() else { return };
+        if matches!(reply, RespValue::Error(_)) || !MERGED_COMMANDS.contains(&cmd) {
+            return;
+        }
+        let (db, index) = (ctx.db, ctx.client.db);
+        match cmd {
+            "set" | "mset" => {
+                for pair in args.chunks(2) {
+                    if let (Some(key), [_, RespValue::BulkString(Some(value))]) = (text(&pair[0]), pair) {
+                        crdt.set(db, index, &key, value.clone());
+                    }
+                }
+            }
+            "incr" | "decr" => {
+                if let Some(key) = text(&args[0]) {
+                    crdt.count(db, index, &key, if cmd == "incr" { 1 } else { -1 });
+                }
+            }
+            _ => {
+                for key in args.iter().filter_map(text) {
+                    crdt.delete(db, index, &key);
+                }
+            }
+        }
+    }
+}
+
+/// Pushes key states to each peer until aborted.
+pub(crate) fn start_crdt(crdt: Arc<Crdt>, settings: Arc<Settings>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let config = settings.current();
+        let auth: Vec<Vec<u8>> = match &config.masterauth {
+            Some(pass) => ["AUTH".to_string()].into_iter().chain(config.masteruser.clone()).chain([pass.clone()]).map(String::into_bytes).collect(),
+            None => Vec::new(),
+        };
+        let mut links = JoinSet::new();
+        for index in 0..crdt.peers.len() {
+            let (crdt, auth) = (crdt.clone(), auth.clone());
+            links.spawn(async move {
+                let link = &crdt.links[index];
+                loop {
+                    if let Err(e) = push_to(&crdt, index, &auth).await {
+                        if link.up.swap(false, Ordering::Relaxed) {
+                            eprintln!("Active-active link to {} failed: {}", crdt.peers[index], e);
+                        }
+                    }
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            });
+        }
+        while links.join_next().await.is_some() {}
+    })
+}
+
+// Sends the peer every key's state, then each change as it happens
+async fn push_to(crdt: &Crdt, index: usize, auth: &[Vec<u8>]) -> io::Result<()> {
+    let (peer, link) = (&crdt.peers[index], &crdt.links[index]);
+    let mut outbox = crdt.outbox.subscribe();
+    let mut conn = BufReader::new(TcpStream::connect(peer).await?);
+    if !auth.is_empty() {
+        pipeline(&mut conn, &[auth.to_vec()]).await?;
+    }
+    println!("Active-active link to {} is up", peer);
+    link.up.store(true, Ordering::Relaxed);
+    // A peer that restarted lost its metadata, so everything goes again
+    link.sent.store(0, Ordering::Relaxed);
+    loop {
+        let batch = crdt.changes_after(link.sent.load(Ordering::Relaxed), BATCH);
+        let Some((last, _, _)) = batch.last() else {
+            tokio::select! {
+                _ = outbox.changed() => {}
+                _ = tokio::time::sleep(HEARTBEAT) => {
+                    pipeline(&mut conn, &[vec![b"PING".to_vec()]]).await?;
+                    crdt.collect();
+                }
+            }
+            continue;
+        };
+        let last = *last;
+        let commands: Vec<Vec<Vec<u8>>> = batch.iter().map(|(_, (db, key), state)| merge_args(*db, key, state)).collect();
+        pipeline(&mut conn, &commands).await?;
+        link.sent.store(last, Ordering::Relaxed);
+        crdt.collect();
+    }
+}
+
+// Sends `commands` at once and reads their replies; an error reply fails
+// the link
+async fn pipeline(conn: &mut BufReader<TcpStream>, commands: &[Vec<Vec<u8>>]) -> io::Result<()> {
+    let mut buf = Vec::new();
+    for args in commands {
+        RespValue::Array(Some(args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect())).encode(&mut buf);
+    }
+    conn.get_mut().write_all(&buf).await?;
+    for _ in commands {
+        if let RespValue::Error(e) = read_resp(conn).await? {
+            return Err(io::Error::other(e));
+        }
+    }
+    Ok(())
+}
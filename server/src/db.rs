@@ -1,71 +1,657 @@
-use std::sync::Arc;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
+use crc::{Crc, CRC_32_ISCSI};
 use dashmap::DashMap;
+use tokio::sync::Notify;
+
+use crate::resp::RespValue;
+
+use crate::clock::Clock;
+use crate::config::Settings;
+use crate::engine::{MapEngine, StorageEngine};
+use crate::glob::glob_match;
+use crate::overflow::{ColdTier, Spilled};
+use crate::snapshot::CRC64;
+use crate::stats::Stats;
+
+/// A stored value plus the bookkeeping eviction needs. Storage engines hold
+/// entries without looking past the value.
+pub struct Entry {
+    pub value: Vec<u8>,
+    /// Changes with every write of the key; see [`Database::get_with_version`].
+    version: u64,
+    /// Milliseconds since the database's clock epoch at the last access.
+    last_access: AtomicU64,
+    /// CRC-32C of `value`, with value-checksums on.
+    checksum: Option<u32>,
+}
+
+/// A value that no longer matches the checksum it was stored with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptValue {
+    pub key: String,
+}
+
+impl fmt::Display for CorruptValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "value of key '{}' failed its checksum", self.key)
+    }
+}
+
+impl std::error::Error for CorruptValue {}
+
+impl From<CorruptValue> for std::io::Error {
+    fn from(e: CorruptValue) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+impl From<CorruptValue> for String {
+    fn from(e: CorruptValue) -> Self {
+        e.to_string()
+    }
+}
+
+/// How a database over its memory budget makes room, named as in redis.conf.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse writes with an OOM error instead of evicting.
+    NoEviction,
+    AllKeysLru,
+    AllKeysRandom,
+    /// Only keys with a TTL are candidates.
+    VolatileLru,
+    VolatileRandom,
+    /// Evict the key closest to expiring first.
+    VolatileTtl,
+}
+
+impl Entry {
+    fn meets(&self, condition: &SetCondition) -> bool {
+        match condition {
+            SetCondition::Equals(expected) => self.value == *expected,
+            SetCondition::Version(version) => self.version == *version,
+        }
+    }
+}
+
+impl EvictionPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "noeviction" => Some(Self::NoEviction),
+            "allkeys-lru" => Some(Self::AllKeysLru),
+            "allkeys-random" => Some(Self::AllKeysRandom),
+            "volatile-lru" => Some(Self::VolatileLru),
+            "volatile-random" => Some(Self::VolatileRandom),
+            "volatile-ttl" => Some(Self::VolatileTtl),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::NoEviction => "noeviction",
+            Self::AllKeysLru => "allkeys-lru",
+            Self::AllKeysRandom => "allkeys-random",
+            Self::VolatileLru => "volatile-lru",
+            Self::VolatileRandom => "volatile-random",
+            Self::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    fn volatile_only(&self) -> bool {
+        matches!(self, Self::VolatileLru | Self::VolatileRandom | Self::VolatileTtl)
+    }
+}
+
+/// What a conditional write requires of the key's current value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SetCondition {
+    /// The key holds exactly these bytes.
+    Equals(Vec<u8>),
+    /// The key is at this version.
+    Version(u64),
+}
+
+/// A key's value and expiry as a snapshot sees them.
+pub struct SnapshotRecord {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub expires_at: Option<Instant>,
+}
+
+/// What [`Database::sample`] saw of a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySample {
+    pub kind: &'static str,
+    pub value_len: usize,
+    /// Time left before it expires; None without a TTL.
+    pub ttl: Option<Duration>,
+}
+
+// Value and expiry of a key, or None if it did not exist
+type BeforeImage = Option<(Vec<u8>, Option<Instant>)>;
+
+/// Copy-on-write bookkeeping for a snapshot in progress. Rather than fork, the
+/// first write to a key the snapshot has not copied yet saves the key's prior
+/// state here; the snapshot uses that in place of whatever the key holds now.
+#[derive(Default)]
+struct SnapshotState {
+    active: AtomicBool,
+    // Shards below this index (and the cold tier, once set) are already copied
+    visited_shards: AtomicUsize,
+    visited_cold: AtomicBool,
+    before: Mutex<HashMap<String, BeforeImage>>,
+}
+
+/// What happened to a key, as reported to the callbacks registered with
+/// [`Database::on_set`] and its siblings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyEvent {
+    Set,
+    Deleted,
+    Expired,
+    Evicted,
+}
+
+// Called with the key and the value it held before the event
+type Listener = Box<dyn Fn(&str, Option<&[u8]>) + Send + Sync>;
+
+#[derive(Default)]
+struct Listeners {
+    // Lets writes skip the lock while nothing is registered
+    any: AtomicBool,
+    registered: RwLock<Vec<(KeyEvent, Listener)>>,
+}
+
+// Keys a deadline-bound scan visits between looks at the clock
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+// Shards in the keyspace map. A SCAN page reads the whole shard its cursor
+// falls in, so there are more than the map would pick for write concurrency
+// alone, keeping a page's cost at a small fraction of the keyspace.
+const STORE_SHARDS: usize = 256;
+
+static CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+// Keys compared per eviction, as with Redis' maxmemory-samples default.
+const EVICTION_SAMPLES: usize = 5;
 
 #[derive(Clone)]
 pub struct Database {
-    pub(crate) store: Arc<DashMap<String, Vec<u8>>>,
-    pub(crate) expirations: Arc<DashMap<String, Instant>>, // key -> expiry time
+    // Entries and their deadlines
+    pub(crate) store: Arc<dyn StorageEngine>,
+    // Keys set with SET ... MAXIDLE -> how long they may go unread
+    idle_limits: Arc<DashMap<String, Duration>>,
+    // Lets accesses skip the idle check while no key has a limit of its own
+    any_idle_limits: Arc<AtomicBool>,
+    // maxidle in milliseconds, shared with the settings; 0 when off
+    max_idle_ms: Arc<AtomicU64>,
+    pub(crate) stats: Arc<Stats>,
+    pub(crate) blocked: Arc<BlockedClients>,
+    // Bytes of keys and values held, kept in step with every insert and remove
+    used_memory: Arc<AtomicUsize>,
+    // The last version handed out
+    versions: Arc<AtomicU64>,
+    clock: Clock,
+    epoch: Instant,
+    // Where evicted values go instead of being dropped, when configured
+    cold: Option<Arc<ColdTier>>,
+    // Whether values are stored with a checksum
+    checksums: bool,
+    snapshot: Arc<SnapshotState>,
+    listeners: Arc<Listeners>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Database {
     pub fn new() -> Self {
+        Self::with_stats(Arc::new(Stats::new()), Clock::system())
+    }
+
+    /// A database on its own keeping time by `clock`, such as a
+    /// [`ManualClock`](crate::clock::ManualClock) a test moves.
+    pub fn with_clock(clock: Clock) -> Self {
+        Self::with_stats(Arc::new(Stats::new()), clock)
+    }
+
+    /// A database reporting into `stats` and keeping time by `clock`, both
+    /// of which logical databases of one server share.
+    pub(crate) fn with_stats(stats: Arc<Stats>, clock: Clock) -> Self {
         Self {
-            store: Arc::new(DashMap::new()),
-            expirations: Arc::new(DashMap::new()),
+            store: Arc::new(MapEngine::new(STORE_SHARDS)),
+            idle_limits: Arc::new(DashMap::new()),
+            any_idle_limits: Arc::new(AtomicBool::new(false)),
+            max_idle_ms: Arc::new(AtomicU64::new(0)),
+            stats,
+            blocked: Arc::new(BlockedClients { clock: clock.clone(), ..BlockedClients::default() }),
+            used_memory: Arc::new(AtomicUsize::new(0)),
+            // Starting from the wall clock keeps versions rising across
+            // restarts, short of a clock step back or a million writes a second
+            versions: Arc::new(AtomicU64::new(clock.unix_micros().max(0) as u64)),
+            epoch: clock.now(),
+            clock,
+            cold: None,
+            checksums: false,
+            snapshot: Arc::new(SnapshotState::default()),
+            listeners: Arc::new(Listeners::default()),
         }
     }
 
-    fn remove_if_expired(&self, key: &str) -> bool {
-        if let Some(exp) = self.expirations.get(key) {
-            if Instant::now() >= *exp {
-                drop(exp);
-                self.expirations.remove(key);
-                self.store.remove(key);
-                return true;
+    /// Keeps entries in `engine` rather than the default [`MapEngine`]; for a
+    /// database nothing has been written to yet.
+    pub fn with_engine(mut self, engine: Arc<dyn StorageEngine>) -> Self {
+        self.store = engine;
+        self
+    }
+
+    /// Spills evicted values to a scratch log at `path` and faults them back
+    /// in on access, instead of dropping them.
+    pub(crate) fn with_overflow(mut self, path: &std::path::Path) -> std::io::Result<Self> {
+        self.cold = Some(Arc::new(ColdTier::open(path)?));
+        Ok(self)
+    }
+
+    /// Stores a CRC-32C with every value written from now on and checks it
+    /// whenever the value is read or persisted; see [`CorruptValue`].
+    pub(crate) fn with_checksums(mut self) -> Self {
+        self.checksums = true;
+        self
+    }
+
+    /// Expires keys that go unread for longer than `max_idle_ms`, a value
+    /// the settings keep current; 0 turns it off.
+    pub(crate) fn with_max_idle(mut self, max_idle_ms: Arc<AtomicU64>) -> Self {
+        self.max_idle_ms = max_idle_ms;
+        self
+    }
+
+    /// The clock TTLs are measured against.
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn blocked(&self) -> &Arc<BlockedClients> {
+        &self.blocked
+    }
+
+    /// Calls `f(key, old)` after every write that stores a value, where `old`
+    /// is the value it replaced (None for a new key).
+    ///
+    /// Callbacks run on the writing thread once the write is done, so they see
+    /// the new state and may call back into the database, but should be quick.
+    /// Clones of a database share their callbacks. FLUSHDB reports nothing.
+    pub fn on_set(&self, f: impl Fn(&str, Option<&[u8]>) + Send + Sync + 'static) {
+        self.listen(KeyEvent::Set, Box::new(f));
+    }
+
+    /// Calls `f(key, old)` after DEL (or an EXPIRE into the past) removes a
+    /// key. `old` is None if the value had been spilled to the overflow file.
+    pub fn on_delete(&self, f: impl Fn(&str, Option<&[u8]>) + Send + Sync + 'static) {
+        self.listen(KeyEvent::Deleted, Box::new(f));
+    }
+
+    /// Calls `f(key, old)` after a key is removed because its TTL ran out,
+    /// whether found on access or by the reaper.
+    pub fn on_expired(&self, f: impl Fn(&str, Option<&[u8]>) + Send + Sync + 'static) {
+        self.listen(KeyEvent::Expired, Box::new(f));
+    }
+
+    /// Calls `f(key, old)` after eviction drops a key to stay within maxmemory.
+    /// Values spilled to the overflow file are not evicted and not reported.
+    pub fn on_evicted(&self, f: impl Fn(&str, Option<&[u8]>) + Send + Sync + 'static) {
+        self.listen(KeyEvent::Evicted, Box::new(f));
+    }
+
+    fn listen(&self, event: KeyEvent, f: Listener) {
+        let mut registered = self.listeners.registered.write().unwrap_or_else(|e| e.into_inner());
+        registered.push((event, f));
+        self.listeners.any.store(true, Ordering::Release);
+    }
+
+    fn notify(&self, event: KeyEvent, key: &str, old: Option<&[u8]>) {
+        if !self.listeners.any.load(Ordering::Acquire) {
+            return;
+        }
+        let registered = self.listeners.registered.read().unwrap_or_else(|e| e.into_inner());
+        for (_, f) in registered.iter().filter(|(e, _)| *e == event) {
+            f(key, old);
+        }
+    }
+
+    // `f` of the entry of `key`, if any, read under its shard's lock
+    fn read<R>(&self, key: &str, f: impl FnOnce(&Entry) -> R) -> Option<R> {
+        let mut f = Some(f);
+        let mut out = None;
+        self.store.get(key, &mut |e| out = f.take().map(|f| f(e)));
+        out
+    }
+
+    fn clock_ms(&self) -> u64 {
+        self.clock.now().saturating_duration_since(self.epoch).as_millis() as u64
+    }
+
+    // Stores `value` and reports it as a set
+    fn insert(&self, key: String, value: Vec<u8>) {
+        self.preserve(&key);
+        if let Some(cold) = self.cold.as_ref().filter(|c| !c.is_empty()) {
+            cold.discard(&key);
+        }
+        let old = self.insert_hot(key.clone(), self.new_entry(value));
+        self.notify(KeyEvent::Set, &key, old.as_deref());
+    }
+
+    // A fresh entry for a write of `value`, under a new version
+    fn new_entry(&self, value: Vec<u8>) -> Entry {
+        let checksum = self.checksums.then(|| CRC32C.checksum(&value));
+        self.restored_entry((value, checksum))
+    }
+
+    // An entry for a value coming back from the cold tier, which keeps the
+    // checksum it was stored with
+    fn restored_entry(&self, (value, checksum): Spilled) -> Entry {
+        Entry {
+            value,
+            version: self.versions.fetch_add(1, Ordering::Relaxed) + 1,
+            last_access: AtomicU64::new(self.clock_ms()),
+            checksum,
+        }
+    }
+
+    // Fails, counting and logging it, if `value` no longer matches `checksum`
+    fn verify(&self, key: &str, value: &[u8], checksum: Option<u32>) -> Result<(), CorruptValue> {
+        if checksum.is_none_or(|c| CRC32C.checksum(value) == c) {
+            return Ok(());
+        }
+        self.stats.value_found_corrupt();
+        eprintln!("value of key '{}' failed its checksum", key);
+        Err(CorruptValue { key: key.to_string() })
+    }
+
+    fn insert_hot(&self, key: String, entry: Entry) -> Option<Vec<u8>> {
+        let size = key.len() + entry.value.len();
+        let key_len = key.len();
+        // Add before subtracting so a concurrent reader never sees an underflow
+        self.used_memory.fetch_add(size, Ordering::Relaxed);
+        let old = self.store.set(key, entry)?;
+        self.used_memory.fetch_sub(key_len + old.value.len(), Ordering::Relaxed);
+        Some(old.value)
+    }
+
+    /// Stores `value` unless `key` already holds one (a load racing a write
+    /// must not clobber it). Returns whether it was stored.
+    pub(crate) fn insert_if_absent(&self, key: String, value: Vec<u8>) -> bool {
+        self.remove_if_expired(&key);
+        self.preserve(&key);
+        let size = key.len() + value.len();
+        let mut value = Some(value);
+        let mut stored = false;
+        self.store.update(key.clone(), &mut |current| {
+            if current.is_some() {
+                return None;
+            }
+            self.used_memory.fetch_add(size, Ordering::Relaxed);
+            stored = true;
+            Some(self.new_entry(value.take()?))
+        });
+        if stored {
+            self.notify(KeyEvent::Set, &key, None);
+        }
+        stored
+    }
+
+    // Removes `key` and reports why; returns whether it existed
+    fn remove(&self, key: &str, why: KeyEvent) -> bool {
+        self.preserve(key);
+        self.store.set_expiry(key, None);
+        self.clear_idle_limit(key);
+        let spilled = self.cold.as_ref().is_some_and(|c| !c.is_empty() && c.discard(key));
+        let old = self.take_hot(key).map(|e| e.value);
+        if old.is_some() || spilled {
+            self.notify(why, key, old.as_deref());
+        }
+        old.is_some() || spilled
+    }
+
+    fn take_hot(&self, key: &str) -> Option<Entry> {
+        let (k, old) = self.store.delete(key)?;
+        self.used_memory.fetch_sub(k.len() + old.value.len(), Ordering::Relaxed);
+        Some(old)
+    }
+
+    /// Brings `key` back from the cold tier if it was spilled there.
+    fn fault_in(&self, key: &str) {
+        if let Some(cold) = self.cold.as_ref().filter(|c| !c.is_empty()) {
+            if !self.store.contains(key) {
+                cold.fault_in(key, |spilled| {
+                    self.insert_hot(key.to_string(), self.restored_entry(spilled));
+                });
             }
         }
+    }
+
+    fn remove_if_expired(&self, key: &str) -> bool {
+        if self.store.expiry(key).is_some_and(|exp| self.clock.now() >= exp) {
+            self.remove(key, KeyEvent::Expired);
+            return true;
+        }
+        if self.idle_too_long(key) {
+            self.remove(key, KeyEvent::Expired);
+            return true;
+        }
         false
     }
 
+    // Whether `key` has gone unread for longer than its own idle limit, or
+    // maxidle if it has none
+    fn idle_too_long(&self, key: &str) -> bool {
+        let global = self.max_idle_ms.load(Ordering::Relaxed);
+        let own = if self.any_idle_limits.load(Ordering::Relaxed) {
+            self.idle_limits.get(key).map(|limit| limit.as_millis() as u64)
+        } else {
+            None
+        };
+        let limit = match own {
+            Some(limit) => limit,
+            None if global > 0 => global,
+            None => return false,
+        };
+        let now = self.clock_ms();
+        self.read(key, |e| now.saturating_sub(e.last_access.load(Ordering::Relaxed)) > limit)
+            .unwrap_or(false)
+    }
+
+    fn clear_idle_limit(&self, key: &str) {
+        if self.any_idle_limits.load(Ordering::Relaxed) {
+            self.idle_limits.remove(key);
+        }
+    }
+
+    /// Expires `key` once it has gone unread for `limit`, instead of after
+    /// maxidle; None goes back to maxidle. Reads keep the key alive, and
+    /// every write of it starts the count again. A write that replaces the
+    /// value, as SET does, drops the limit along with the TTL. Returns
+    /// whether the key exists.
+    pub fn set_idle_limit(&self, key: &str, limit: Option<Duration>) -> bool {
+        self.fault_in(key);
+        if !self.store.contains(key) {
+            return false;
+        }
+        match limit {
+            Some(limit) => {
+                self.any_idle_limits.store(true, Ordering::Relaxed);
+                self.idle_limits.insert(key.to_string(), limit);
+            }
+            None => self.clear_idle_limit(key),
+        }
+        true
+    }
+
+    // Expires the keys of store shards `shards` that have gone unread too
+    // long, for the reaper
+    fn reap_idle(&self, shards: std::ops::Range<usize>) {
+        if self.max_idle_ms.load(Ordering::Relaxed) == 0 && !self.any_idle_limits.load(Ordering::Relaxed) {
+            return;
+        }
+        for shard in shards.take_while(|shard| *shard < self.store.shards()) {
+            let mut keys = Vec::new();
+            self.store.iterate(shard, &mut |key, _| {
+                keys.push(key.to_string());
+                true
+            });
+            for key in keys {
+                if self.idle_too_long(&key) {
+                    self.remove(&key, KeyEvent::Expired);
+                }
+            }
+        }
+    }
+
+    /// The value of `key`. A value that fails its checksum reads as missing;
+    /// use [`Database::get_checked`] to tell the two apart.
     pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.get_checked(key).ok().flatten()
+    }
+
+    /// The value of `key`, or an error if it no longer matches its checksum.
+    pub fn get_checked(&self, key: &str) -> Result<Option<Vec<u8>>, CorruptValue> {
+        Ok(self.get_with_version(key)?.map(|(value, _)| value))
+    }
+
+    /// Like [`Database::get`], but also returns the value's version. Each
+    /// write of a key gives it a version greater than any it had before, even
+    /// across a delete and re-create, so a client can tell whether the value
+    /// it read has changed since. Values faulted back in from the cold tier
+    /// are given a new version too. Fails like [`Database::get_checked`].
+    pub fn get_with_version(&self, key: &str) -> Result<Option<(Vec<u8>, u64)>, CorruptValue> {
+        self.fault_in(key);
         if self.remove_if_expired(key) {
-            return None;
+            self.stats.record_lookup(false);
+            return Ok(None);
         }
-        self.store.get(key).map(|v| v.clone())
+        let found = self
+            .read(key, |e| {
+                self.verify(key, &e.value, e.checksum)?;
+                e.last_access.store(self.clock_ms(), Ordering::Relaxed);
+                Ok((e.value.clone(), e.version))
+            })
+            .transpose()?;
+        self.stats.record_lookup(found.is_some());
+        Ok(found)
     }
 
     pub fn set(&self, key: String, value: Vec<u8>, ttl: Option<Duration>) {
-        self.store.insert(key.clone(), value);
-        match ttl {
-            Some(dur) => {
-                self.expirations.insert(key, Instant::now() + dur);
-            }
-            None => {
-                self.expirations.remove(&key);
-            }
+        self.insert(key.clone(), value);
+        self.blocked.signal_key_ready(&key);
+        self.clear_idle_limit(&key);
+        self.store.set_expiry(&key, ttl.map(|dur| self.clock.now() + dur));
+    }
+
+    /// Sets `key` like [`Database::set`], but only if it exists and meets
+    /// `condition`; the check and the write are one [`Database::update`], so
+    /// two racing writers cannot both succeed. Returns whether it was set,
+    /// which it is not over a value that fails its checksum.
+    pub fn set_if(&self, key: String, condition: &SetCondition, value: Vec<u8>, ttl: Option<Duration>) -> bool {
+        let written = self.update_entry(key.clone(), |current| {
+            let met = current.is_some_and(|e| e.meets(condition));
+            Ok::<_, CorruptValue>((met.then_some(value), met))
+        });
+        if !matches!(written, Ok(true)) {
+            return false;
         }
+        self.clear_idle_limit(&key);
+        self.store.set_expiry(&key, ttl.map(|dur| self.clock.now() + dur));
+        true
     }
 
-    pub fn del(&self, keys: &[String]) -> usize {
-        let mut deleted = 0usize;
-        for key in keys {
-            let _ = self.expirations.remove(key);
-            if self.store.remove(key).is_some() {
-                deleted += 1;
+    /// Reads and rewrites `key` atomically: `f` gets the current value (None
+    /// if there is none) and returns the value to store, or None to leave the
+    /// key as it is, along with a result for the caller. It runs under the
+    /// lock of the key's shard, so concurrent updates of a key apply one
+    /// after the other and none is lost; it must be quick and must not use
+    /// the database. An error from `f` leaves the key untouched. The key
+    /// keeps its TTL. A current value that fails its checksum is not passed
+    /// to `f`; the update fails with the [`CorruptValue`] instead.
+    pub fn update<T, E: From<CorruptValue>>(
+        &self,
+        key: String,
+        f: impl FnOnce(Option<&[u8]>) -> Result<(Option<Vec<u8>>, T), E>,
+    ) -> Result<T, E> {
+        self.update_entry(key, |current| f(current.map(|e| e.value.as_slice())))
+    }
+
+    fn update_entry<T, E: From<CorruptValue>>(
+        &self,
+        key: String,
+        f: impl FnOnce(Option<&Entry>) -> Result<(Option<Vec<u8>>, T), E>,
+    ) -> Result<T, E> {
+        self.fault_in(&key);
+        self.remove_if_expired(&key);
+        self.preserve(&key);
+        let key_len = key.len();
+        let mut f = Some(f);
+        let mut outcome = None;
+        let mut stored = false;
+        let old = self.store.update(key.clone(), &mut |current| {
+            let f = f.take()?;
+            if let Some(e) = current {
+                if let Err(corrupt) = self.verify(&key, &e.value, e.checksum) {
+                    outcome = Some(Err(corrupt.into()));
+                    return None;
+                }
             }
+            let (new, result) = match f(current) {
+                Ok(done) => done,
+                Err(e) => {
+                    outcome = Some(Err(e));
+                    return None;
+                }
+            };
+            outcome = Some(Ok(result));
+            let value = new?;
+            // Added before the old entry's size is subtracted, so a
+            // concurrent reader never sees an underflow
+            self.used_memory.fetch_add(key_len + value.len(), Ordering::Relaxed);
+            stored = true;
+            Some(self.new_entry(value))
+        });
+        let result = outcome.expect("the engine passes every update the current entry")?;
+        if !stored {
+            return Ok(result);
         }
-        deleted
+        let old = old.map(|old| {
+            self.used_memory.fetch_sub(key_len + old.value.len(), Ordering::Relaxed);
+            old.value
+        });
+        self.notify(KeyEvent::Set, &key, old.as_deref());
+        self.blocked.signal_key_ready(&key);
+        Ok(result)
+    }
+
+    pub fn del<K: AsRef<str>>(&self, keys: &[K]) -> usize {
+        keys.iter().filter(|key| self.remove(key.as_ref(), KeyEvent::Deleted)).count()
     }
 
-    pub fn exists(&self, keys: &[String]) -> usize {
+    pub fn exists<K: AsRef<str>>(&self, keys: &[K]) -> usize {
         let mut count = 0usize;
         for key in keys {
+            let key = key.as_ref();
+            self.fault_in(key);
             if self.remove_if_expired(key) {
                 continue;
             }
-            if self.store.contains_key(key) {
+            if self.store.contains(key) {
                 count += 1;
             }
         }
@@ -73,101 +659,804 @@ impl Database {
     }
 
     pub fn incr_by(&self, key: String, delta: i64) -> Result<i64, String> {
-        loop {
-            if self.remove_if_expired(&key) {}
-            match self.store.get(&key) {
-                None => {
-                    let new_val = delta;
-                    self.store
-                        .insert(key.clone(), new_val.to_string().into_bytes());
-                    return Ok(new_val);
-                }
-                Some(existing) => {
-                    let s = match std::str::from_utf8(&existing) {
-                        Ok(s) => s,
-                        Err(_) => return Err("value is not an integer or out of range".to_string()),
-                    };
-                    let curr: i64 = match s.parse() {
-                        Ok(i) => i,
-                        Err(_) => return Err("value is not an integer or out of range".to_string()),
-                    };
-                    drop(existing);
-                    let new_val = curr.saturating_add(delta);
-                    self.store
-                        .insert(key.clone(), new_val.to_string().into_bytes());
-                    return Ok(new_val);
-                }
-            }
-        }
+        self.update(key, |current| {
+            let curr = match current {
+                None => 0,
+                Some(value) => std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|s| s.parse::<i64>().ok())
+                    .ok_or_else(|| "value is not an integer or out of range".to_string())?,
+            };
+            let new_val = curr.saturating_add(delta);
+            Ok((Some(new_val.to_string().into_bytes()), new_val))
+        })
     }
 
     pub fn expire_seconds(&self, key: &str, seconds: i64) -> bool {
-        if !self.store.contains_key(key) {
+        self.expire_millis(key, seconds.saturating_mul(1000))
+    }
+
+    /// Sets `key` to expire in `ms` milliseconds; a negative `ms` deletes it.
+    /// Returns whether the key exists.
+    pub fn expire_millis(&self, key: &str, ms: i64) -> bool {
+        self.fault_in(key);
+        if !self.store.contains(key) {
             return false;
         }
-        if seconds < 0 {
-            self.store.remove(key);
-            self.expirations.remove(key);
+        if ms < 0 {
+            self.remove(key, KeyEvent::Deleted);
             return true;
         }
-        let when = Instant::now() + Duration::from_secs(seconds as u64);
-        self.expirations.insert(key.to_string(), when);
+        let when = self.clock.now() + Duration::from_millis(ms as u64);
+        self.preserve(key);
+        self.store.set_expiry(key, Some(when));
         true
     }
 
     pub fn ttl_seconds(&self, key: &str) -> i64 {
+        self.fault_in(key);
         if self.remove_if_expired(key) {
             return -2;
         }
-        if !self.store.contains_key(key) {
+        if !self.store.contains(key) {
             return -2;
         }
-        match self.expirations.get(key) {
+        match self.store.expiry(key) {
             None => -1,
             Some(exp) => {
-                let now = Instant::now();
-                if *exp <= now {
-                    drop(exp);
-                    self.expirations.remove(key);
-                    self.store.remove(key);
+                let now = self.clock.now();
+                if exp <= now {
+                    self.remove(key, KeyEvent::Expired);
                     -2
                 } else {
-                    let remaining = *exp - now;
+                    let remaining = exp - now;
                     remaining.as_secs() as i64
                 }
             }
         }
     }
 
+    pub fn strlen(&self, key: &str) -> usize {
+        self.fault_in(key);
+        if self.remove_if_expired(key) {
+            return 0;
+        }
+        self.read(key, |e| e.value.len()).unwrap_or(0)
+    }
+
+    pub fn key_type(&self, key: &str) -> &'static str {
+        self.fault_in(key);
+        if self.remove_if_expired(key) || !self.store.contains(key) {
+            return "none";
+        }
+        "string"
+    }
+
+    pub fn memory_usage(&self, key: &str) -> Option<usize> {
+        self.fault_in(key);
+        if self.remove_if_expired(key) {
+            return None;
+        }
+        self.read(key, |e| key.len() + e.value.len())
+    }
+
+    /// A hash of every live key with its value and expiry, the same on any
+    /// server holding the same data whatever order it was written in; 0 when
+    /// the database is empty. Expiry counts to the nearest second: deadlines
+    /// cannot be compared more finely across servers, and one set in whole
+    /// seconds is then never near a boundary, but a millisecond deadline
+    /// close to a half second can digest differently on a replica. Keys
+    /// written while the digest is taken may or may not be included. Spilled
+    /// keys are read where they are, not faulted in.
+    pub fn digest(&self) -> u64 {
+        let reading = self.clock.reading();
+        let record = |key: &str, value: &[u8]| {
+            let expires = match self.store.expiry(key) {
+                Some(at) if at <= reading.now => return 0,
+                Some(at) => (reading.unix_ms_of(at) + 500).div_euclid(1000).to_be_bytes(),
+                None => [0xff; 8],
+            };
+            digest_of(&[key.as_bytes(), value, &expires])
+        };
+        let mut digest = 0;
+        for shard in 0..self.store.shards() {
+            let mut copied = Vec::new();
+            self.store.iterate(shard, &mut |key, entry| {
+                copied.push((key.to_string(), entry.value.clone()));
+                true
+            });
+            for (key, value) in copied {
+                digest ^= record(&key, &value);
+            }
+        }
+        if let Some(cold) = &self.cold {
+            for key in cold.keys() {
+                if let Some((value, _)) = cold.locate(&key, |v| v) {
+                    digest ^= record(&key, &value);
+                }
+            }
+        }
+        digest
+    }
+
+    /// The value of `key` without counting as a read: hit and miss stats,
+    /// access times and idle limits are left alone, and a spilled value is
+    /// read where it is rather than faulted in.
+    pub fn peek(&self, key: &str) -> Option<Vec<u8>> {
+        if let Some(value) = self.read(key, |e| e.value.clone()) {
+            return Some(value);
+        }
+        self.cold.as_ref()?.locate(key, |v| v).map(|(value, _)| value)
+    }
+
+    /// A hash of the value of `key` alone, or None if there is no such key.
+    pub fn digest_value(&self, key: &str) -> Option<u64> {
+        self.fault_in(key);
+        if self.remove_if_expired(key) {
+            return None;
+        }
+        self.read(key, |e| digest_of(&[&e.value]))
+    }
+
+    /// Up to `count` live keys taken from random spots across the shards,
+    /// for judging the keyspace's make-up without reading all of it: a
+    /// database with no more than `count` keys in memory is read whole.
+    /// Values spilled to the cold tier are left out. Like eviction sampling,
+    /// it walks a shard's keys up to the spot it picks, but copies nothing.
+    pub fn sample(&self, count: usize) -> Vec<KeySample> {
+        let now = self.clock.now();
+        let shards = self.store.shards();
+        let per_shard = count.div_ceil(shards.max(1)).max(1);
+        let mut samples = Vec::with_capacity(count.min(self.store.len()));
+        let first = random_below(shards);
+        for i in 0..shards {
+            let want = per_shard.min(count - samples.len());
+            if want == 0 {
+                break;
+            }
+            let shard = (first + i) % shards;
+            let len = self.store.shard_len(shard);
+            if len == 0 {
+                continue;
+            }
+            let skip = random_below(len);
+            let mut taken = Vec::new();
+            let mut take = |key: &str, entry: &Entry| {
+                let ttl = match self.store.expiry(key) {
+                    Some(at) if at <= now => return taken.len() < want,
+                    Some(at) => Some(at - now),
+                    None => None,
+                };
+                taken.push(KeySample { kind: "string", value_len: entry.value.len(), ttl });
+                taken.len() < want
+            };
+            // From the random spot to the end of the shard, then on from its start
+            let (mut seen, mut more) = (0, true);
+            self.store.iterate(shard, &mut |key, entry| {
+                seen += 1;
+                if seen > skip {
+                    more = take(key, entry);
+                }
+                more
+            });
+            if more {
+                let mut seen = 0;
+                self.store.iterate(shard, &mut |key, entry| {
+                    seen += 1;
+                    seen <= skip && take(key, entry)
+                });
+            }
+            samples.append(&mut taken);
+        }
+        samples
+    }
+
+    /// One page of keys, from `cursor` (0 to start), and the cursor for the
+    /// next page (0 once the iteration is over).
+    ///
+    /// The cursor is a position in the keys' hash order, not an offset into
+    /// the map, so a page picks up after the last key it returned wherever
+    /// the other keys have gone meanwhile. A key present for the whole
+    /// iteration is returned exactly once, however the store grows, shrinks
+    /// or spills to the cold tier in between; one added or removed partway
+    /// may or may not be. Keys that share a hash come back in the same page,
+    /// which can then hold more than `count`. Cursors depend on the process's
+    /// hasher and mean nothing to another server or after a restart.
+    ///
+    /// Finding a page's lowest positions means hashing every key of the shard
+    /// the cursor falls in, plus every key in the cold tier, so a page costs
+    /// about a 256th of the keyspace whatever `count` is. Only the keys
+    /// returned are copied.
+    pub fn scan(&self, cursor: usize, count: usize, pattern: Option<&str>) -> (usize, Vec<String>) {
+        self.scan_until(cursor, count, pattern, None).unwrap_or_default()
+    }
+
+    /// Like [`Database::scan`], but gives up with None once `deadline` passes.
+    /// The deadline is an execution budget, so it is checked against real
+    /// time rather than the database's clock.
+    pub fn scan_until(
+        &self,
+        cursor: usize,
+        count: usize,
+        pattern: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> Option<(usize, Vec<String>)> {
+        let now = self.clock.now();
+        let count = count.max(1);
+        let shards = self.store.shards();
+        // A position's leading bits are its shard, so each shard holds one
+        // run of positions and a page reads them a shard at a time
+        let shard_bits = shards.trailing_zeros();
+        let shard_of = |pos: usize| pos.checked_shr(usize::BITS - shard_bits).unwrap_or(0);
+        let spilled: Vec<(usize, String)> = self
+            .cold
+            .as_ref()
+            .map(|c| c.keys())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|k| (self.store.position(&k), k))
+            .collect();
+        let mut keys = Vec::new();
+        let mut visited = 0usize;
+        let mut cursor = cursor;
+        loop {
+            let shard = shard_of(cursor);
+            let (mut page, bound) = {
+                // Positions first, so only the keys that make the page are copied
+                let mut positions: Vec<usize> = Vec::new();
+                self.store.iterate(shard, &mut |key, _| {
+                    let pos = self.store.position(key);
+                    if pos >= cursor {
+                        positions.push(pos);
+                    }
+                    true
+                });
+                let spilled = spilled.iter().filter(|(pos, _)| *pos >= cursor && shard_of(*pos) == shard);
+                positions.extend(spilled.clone().map(|(pos, _)| *pos));
+                // The `count - visited` lowest positions, and any that tie
+                // with the last of them, so the next cursor splits no hash
+                let want = count - visited;
+                let bound = if positions.len() > want {
+                    let (_, last, _) = positions.select_nth_unstable(want - 1);
+                    *last
+                } else {
+                    usize::MAX
+                };
+                let mut found: Vec<(usize, String)> = spilled.filter(|(pos, _)| *pos <= bound).cloned().collect();
+                self.store.iterate(shard, &mut |key, _| {
+                    let pos = self.store.position(key);
+                    if (cursor..=bound).contains(&pos) {
+                        found.push((pos, key.to_string()));
+                    }
+                    true
+                });
+                (found, bound)
+            };
+            page.sort_unstable();
+            // A key faulting in from the cold tier can be seen in both
+            page.dedup();
+            // Keys deleted since the positions were read can leave the page
+            // short, but the shard still has keys past the bound
+            let more = bound != usize::MAX;
+            let full = more || visited + page.len() >= count;
+            let last = if more { Some(bound) } else { page.last().map(|(pos, _)| *pos) };
+            for (_, key) in page {
+                visited += 1;
+                if visited.is_multiple_of(DEADLINE_CHECK_INTERVAL) && deadline.is_some_and(|d| Instant::now() >= d) {
+                    return None;
+                }
+                if self.store.expiry(&key).is_some_and(|exp| exp <= now) {
+                    continue;
+                }
+                if pattern.is_some_and(|p| !glob_match(p.as_bytes(), key.as_bytes())) {
+                    continue;
+                }
+                keys.push(key);
+            }
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return None;
+            }
+            if full {
+                // Positions past the last one returned, if any remain
+                return Some((last.and_then(|pos| pos.checked_add(1)).unwrap_or(0), keys));
+            }
+            if shard + 1 == shards {
+                return Some((0, keys));
+            }
+            cursor = (shard + 1) << (usize::BITS - shard_bits);
+        }
+    }
+
+    /// The shard of the key store `key` lives in.
+    pub(crate) fn shard_of(&self, key: &[u8]) -> usize {
+        self.store.shard_of(String::from_utf8_lossy(key).as_ref())
+    }
+
+    pub fn dbsize(&self) -> usize {
+        self.store.len() + self.spilled_keys()
+    }
+
+    pub fn count_prefix(&self, prefix: &str) -> usize {
+        let spilled = self.cold.as_ref().map(|c| c.keys()).unwrap_or_default();
+        let mut count = spilled.iter().filter(|k| k.starts_with(prefix)).count();
+        for shard in 0..self.store.shards() {
+            self.store.iterate(shard, &mut |key, _| {
+                count += usize::from(key.starts_with(prefix));
+                true
+            });
+        }
+        count
+    }
+
+    pub fn flush_prefix(&self, prefix: &str) {
+        self.del(&self.keys_with_prefix(prefix));
+    }
+
+    pub(crate) fn keys_with_prefix(&self, prefix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = self.store.keys().into_iter().filter(|k| k.starts_with(prefix)).collect();
+        if let Some(cold) = &self.cold {
+            keys.extend(cold.keys().into_iter().filter(|k| k.starts_with(prefix)));
+        }
+        keys
+    }
+
+    pub fn has_overflow(&self) -> bool {
+        self.cold.is_some()
+    }
+
+    /// Keys currently held in the cold tier.
+    pub fn spilled_keys(&self) -> usize {
+        self.cold.as_ref().map_or(0, |c| c.len())
+    }
+
+    /// Bytes of values held in the cold tier.
+    pub fn spilled_bytes(&self) -> u64 {
+        self.cold.as_ref().map_or(0, |c| c.bytes())
+    }
+
+    /// Keys held in each shard of the keyspace.
+    pub(crate) fn shard_lens(&self) -> Vec<usize> {
+        (0..self.store.shards()).map(|shard| self.store.shard_len(shard)).collect()
+    }
+
+    pub fn expires_count(&self) -> usize {
+        self.store.expiring()
+    }
+
+    /// Bytes of keys and values currently stored.
+    pub fn used_memory(&self) -> usize {
+        self.used_memory.load(Ordering::Relaxed)
+    }
+
     pub fn flushdb(&self) {
+        if self.snapshot.active.load(Ordering::SeqCst) {
+            // Keep what the running snapshot still has to copy
+            let keys = self.store.keys();
+            let spilled = self.cold.as_ref().map(|c| c.keys()).unwrap_or_default();
+            for key in keys.iter().chain(&spilled) {
+                self.preserve(key);
+            }
+        }
         self.store.clear();
-        self.expirations.clear();
+        self.idle_limits.clear();
+        self.used_memory.store(0, Ordering::Relaxed);
+        if let Some(cold) = &self.cold {
+            cold.clear();
+        }
+    }
+
+    /// Evicts keys under `policy` until memory use is within `limit` bytes
+    /// (0 means unlimited). With a cold tier, evicted values are spilled to
+    /// disk rather than dropped. Returns false if the database is still over
+    /// budget because the policy forbids eviction or no key qualifies.
+    pub fn evict_to_fit(&self, limit: usize, policy: EvictionPolicy) -> bool {
+        if limit == 0 {
+            return true;
+        }
+        while self.used_memory() > limit {
+            if policy == EvictionPolicy::NoEviction {
+                return false;
+            }
+            let victim = match self.eviction_candidate(policy) {
+                Some(k) => k,
+                None => return false,
+            };
+            let evicted = match &self.cold {
+                Some(cold) => match cold.spill(&victim, || self.take_hot(&victim).map(|e| (e.value, e.checksum))) {
+                    // Spilling resumes once the snapshot holding the tier still is done
+                    Ok(false) if cold.is_frozen() => return true,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        eprintln!("overflow spill of '{}' failed, dropping it: {}", victim, e);
+                        self.remove(&victim, KeyEvent::Evicted);
+                        true
+                    }
+                },
+                None => self.remove(&victim, KeyEvent::Evicted),
+            };
+            if evicted {
+                self.stats.evicted_keys.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+
+    // Approximated like Redis: compare a few keys from a random spot rather
+    // than keep the whole keyspace ordered.
+    fn eviction_candidate(&self, policy: EvictionPolicy) -> Option<String> {
+        let shards = self.store.shards();
+        let first = random_below(shards);
+        for i in 0..shards {
+            let shard = (first + i) % shards;
+            let len = self.store.shard_len(shard);
+            if len == 0 {
+                continue;
+            }
+            let skip = random_below(len);
+            let mut best: Option<(u64, String)> = None;
+            let mut sampled = 0;
+            // Whether to sample on
+            let mut sample = |key: &str, entry: &Entry| {
+                let expires = self.store.expiry(key);
+                if policy.volatile_only() && expires.is_none() {
+                    return true;
+                }
+                // Lower scores are evicted first
+                let score = match policy {
+                    EvictionPolicy::AllKeysLru | EvictionPolicy::VolatileLru => entry.last_access.load(Ordering::Relaxed),
+                    EvictionPolicy::VolatileTtl => {
+                        expires.map_or(u64::MAX, |at| at.saturating_duration_since(self.epoch).as_millis() as u64)
+                    }
+                    _ => 0,
+                };
+                if best.as_ref().is_none_or(|(s, _)| score < *s) {
+                    best = Some((score, key.to_string()));
+                }
+                sampled += 1;
+                sampled < EVICTION_SAMPLES
+            };
+            // From a random spot to the end of the shard, then on from its start
+            let (mut seen, mut more) = (0, true);
+            self.store.iterate(shard, &mut |key, entry| {
+                seen += 1;
+                if seen > skip {
+                    more = sample(key, entry);
+                }
+                more
+            });
+            if more {
+                let mut seen = 0;
+                self.store.iterate(shard, &mut |key, entry| {
+                    seen += 1;
+                    seen <= skip && sample(key, entry)
+                });
+            }
+            if let Some((_, key)) = best {
+                return Some(key);
+            }
+        }
+        None
+    }
+}
+
+impl Database {
+    /// Saves the state of `key` for a running snapshot that has yet to copy
+    /// it. Called before every logical write; moving values between memory
+    /// and the cold tier is not one.
+    fn preserve(&self, key: &str) {
+        let snap = &self.snapshot;
+        if !snap.active.load(Ordering::SeqCst) {
+            return;
+        }
+        let shard = self.store.shard_of(key);
+        // Whether the snapshot still has to copy the key
+        let unvisited = |spilled: bool| {
+            shard >= snap.visited_shards.load(Ordering::SeqCst)
+                || (spilled && !snap.visited_cold.load(Ordering::SeqCst))
+        };
+        let capture = |spilled: Option<Spilled>| -> Option<(BeforeImage, bool)> {
+            let was_spilled = spilled.is_some();
+            if !unvisited(was_spilled) {
+                return None;
+            }
+            let value = self.read(key, |e| e.value.clone()).or(spilled.map(|(value, _)| value));
+            Some((value.map(|v| (v, self.store.expiry(key))), was_spilled))
+        };
+        let captured = match &self.cold {
+            Some(cold) => cold.locate(key, capture),
+            None => capture(None),
+        };
+        if let Some((image, was_spilled)) = captured {
+            let mut before = snap.before.lock().unwrap_or_else(|e| e.into_inner());
+            // Rechecked under the lock: the snapshot marks what it has copied
+            // while holding it, and a finished snapshot's clear must stick
+            if snap.active.load(Ordering::SeqCst) && unvisited(was_spilled) {
+                before.entry(key.to_string()).or_insert(image);
+            }
+        }
+    }
+
+    /// Starts a point-in-time snapshot. Writes carry on while it is read;
+    /// None if this database already has one running.
+    pub fn begin_snapshot(&self) -> Option<Snapshot> {
+        let snap = &self.snapshot;
+        let mut before = snap.before.lock().unwrap_or_else(|e| e.into_inner());
+        if snap.active.load(Ordering::SeqCst) {
+            return None;
+        }
+        before.clear();
+        snap.visited_shards.store(0, Ordering::SeqCst);
+        snap.visited_cold.store(false, Ordering::SeqCst);
+        if let Some(cold) = &self.cold {
+            cold.set_frozen(true);
+        }
+        snap.active.store(true, Ordering::SeqCst);
+        Some(Snapshot { db: self.clone() })
+    }
+}
+
+/// A consistent view of one database as of `Database::begin_snapshot`.
+/// Dropping it ends the snapshot.
+pub struct Snapshot {
+    db: Database,
+}
+
+impl Snapshot {
+    /// Passes every live key to `sink`, each exactly as it was when the
+    /// snapshot began. Returns the number of records. A key faulted in from
+    /// the cold tier meanwhile may be passed twice, with the same content.
+    /// Values stored with a checksum are checked first, and the first that
+    /// fails it ends the read with a [`CorruptValue`].
+    pub fn read<E: From<CorruptValue>>(self, mut sink: impl FnMut(SnapshotRecord) -> Result<(), E>) -> Result<usize, E> {
+        let db = &self.db;
+        let snap = &db.snapshot;
+        let lock_before = || snap.before.lock().unwrap_or_else(|e| e.into_inner());
+        let mut count = 0;
+        for i in 0..db.store.shards() {
+            // Copy under the shard lock, then let writers back in before the sink runs
+            let mut copied: Vec<(SnapshotRecord, Option<u32>)> = Vec::new();
+            db.store.iterate(i, &mut |key, entry| {
+                let expires_at = db.store.expiry(key);
+                copied.push((SnapshotRecord { key: key.to_string(), value: entry.value.clone(), expires_at }, entry.checksum));
+                true
+            });
+            // Checked after copying, as a key written since was preserved first;
+            // marked visited under the same lock so no image arrives too late
+            let live: Vec<(SnapshotRecord, Option<u32>)> = {
+                let before = lock_before();
+                let live = copied.into_iter().filter(|(r, _)| !before.contains_key(&r.key)).collect();
+                snap.visited_shards.store(i + 1, Ordering::SeqCst);
+                live
+            };
+            for (record, checksum) in live {
+                db.verify(&record.key, &record.value, checksum)?;
+                sink(record)?;
+                count += 1;
+            }
+        }
+        if let Some(cold) = &db.cold {
+            let copied: Vec<(SnapshotRecord, Option<u32>)> = cold
+                .keys()
+                .into_iter()
+                .filter_map(|key| {
+                    let (value, checksum) = cold.locate(&key, |v| v)?;
+                    let expires_at = db.store.expiry(&key);
+                    Some((SnapshotRecord { key, value, expires_at }, checksum))
+                })
+                .collect();
+            let live: Vec<(SnapshotRecord, Option<u32>)> = {
+                let before = lock_before();
+                let live = copied.into_iter().filter(|(r, _)| !before.contains_key(&r.key)).collect();
+                snap.visited_cold.store(true, Ordering::SeqCst);
+                live
+            };
+            for (record, checksum) in live {
+                db.verify(&record.key, &record.value, checksum)?;
+                sink(record)?;
+                count += 1;
+            }
+        }
+        // Everything is visited, so no new before-images can appear
+        let before = std::mem::take(&mut *lock_before());
+        for (key, image) in before {
+            if let Some((value, expires_at)) = image {
+                sink(SnapshotRecord { key, value, expires_at })?;
+                count += 1;
+            }
+        }
+        Ok(count)
     }
 }
 
-pub async fn start_expiry_reaper(db: Database) {
-    let expirations = db.expirations.clone();
-    let store = db.store.clone();
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let snap = &self.db.snapshot;
+        {
+            let mut before = snap.before.lock().unwrap_or_else(|e| e.into_inner());
+            snap.active.store(false, Ordering::SeqCst);
+            before.clear();
+        }
+        if let Some(cold) = &self.db.cold {
+            // Values faulted in while frozen were copied; drop the disk copies
+            // before spilling can move anything again
+            for key in cold.keys() {
+                if self.db.store.contains(&key) {
+                    cold.discard(&key);
+                }
+            }
+            cold.set_frozen(false);
+        }
+    }
+}
+
+/// The digest of a whole server's data: each non-empty database's
+/// [`Database::digest`] tagged with its index. 0 when every database is empty.
+pub fn dataset_digest(dbs: &[Database]) -> u64 {
+    dbs.iter()
+        .enumerate()
+        .map(|(index, db)| (index as u64, db.digest()))
+        .filter(|(_, digest)| *digest != 0)
+        .fold(0, |all, (index, digest)| all ^ digest_of(&[&index.to_be_bytes(), &digest.to_be_bytes()]))
+}
+
+// CRC-64 of the length-prefixed `parts`, put through the splitmix64
+// finalizer so that digests XORed together do not cancel linearly
+fn digest_of(parts: &[&[u8]]) -> u64 {
+    let mut digest = CRC64.digest();
+    for part in parts {
+        digest.update(&(part.len() as u64).to_be_bytes());
+        digest.update(part);
+    }
+    let mut x = digest.finalize();
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+fn random_below(n: usize) -> usize {
+    // Every RandomState is freshly keyed, which is all the randomness sampling needs
+    (RandomState::new().hash_one(()) % n as u64) as usize
+}
+
+// Store shards each reaper run checks for idle keys; a full pass over the
+// keyspace takes STORE_SHARDS / IDLE_SHARDS_PER_RUN runs
+const IDLE_SHARDS_PER_RUN: usize = 16;
+
+/// Sweeps expired keys out of every database on the configured interval,
+/// along with a slice of the keys gone idle.
+pub(crate) fn start_expiry_reaper(dbs: Arc<[Database]>, settings: Arc<Settings>) -> tokio::task::JoinHandle<()> {
     tokio::spawn(async move {
-        let interval_ms: u64 = std::env::var("RUSTCACHE_REAPER_MS")
-            .ok()
-            .and_then(|s| s.parse::<u64>().ok())
-            .filter(|v| *v > 0)
-            .unwrap_or(500);
-        let interval = std::time::Duration::from_millis(interval_ms);
+        let mut idle_shard = 0;
         loop {
-            tokio::time::sleep(interval).await;
-            let now = Instant::now();
-            let mut to_remove: Vec<String> = Vec::new();
-            for entry in expirations.iter() {
-                if *entry.value() <= now {
-                    to_remove.push(entry.key().clone());
+            dbs[0].clock.sleep(settings.reaper_interval()).await;
+            for db in dbs.iter() {
+                db.reap_idle(idle_shard..idle_shard + IDLE_SHARDS_PER_RUN);
+                let now = db.clock.now();
+                for k in db.store.expired(now) {
+                    db.remove(&k, KeyEvent::Expired);
                 }
             }
-            for k in to_remove {
-                expirations.remove(&k);
-                store.remove(&k);
+            let shards = dbs.iter().map(|db| db.store.shards()).max().unwrap_or(STORE_SHARDS);
+            idle_shard = (idle_shard + IDLE_SHARDS_PER_RUN) % shards.max(1);
+        }
+    })
+}
+
+/// What a blocking command asks for when it cannot be served yet: the keys to
+/// wait on, how long (None = forever) and the reply to send on timeout.
+/// Handlers set `Context::block` and the connection retries the command each
+/// time one of the keys is signalled.
+pub struct BlockRequest {
+    pub keys: Vec<String>,
+    pub timeout: Option<Duration>,
+    pub timeout_reply: RespValue,
+}
+
+/// Clients parked on keys by blocking commands. Each key keeps a FIFO queue and
+/// a signal wakes only its head; once the head is served it passes the signal
+/// on, so waiters are served in arrival order. Writers call `signal_key_ready`
+/// for every key they create or modify.
+#[derive(Default)]
+pub struct BlockedClients {
+    queues: Mutex<HashMap<String, VecDeque<Arc<Waiter>>>>,
+    waiting: AtomicUsize,
+    next_id: AtomicU64,
+    // What timeouts are measured by
+    clock: Clock,
+}
+
+struct Waiter {
+    id: u64,
+    notify: Notify,
+}
+
+impl BlockedClients {
+    /// Joins the back of the queue for each key. Dropping the returned guard
+    /// (timeout, disconnect) leaves the queues again.
+    pub fn block(self: &Arc<Self>, keys: &[String]) -> BlockedClient {
+        let waiter = Arc::new(Waiter {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            notify: Notify::new(),
+        });
+        let mut queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+        for key in keys {
+            queues.entry(key.clone()).or_default().push_back(waiter.clone());
+        }
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        BlockedClient {
+            owner: self.clone(),
+            keys: keys.to_vec(),
+            waiter,
+        }
+    }
+
+    pub fn signal_key_ready(&self, key: &str) {
+        if self.waiting.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let queues = self.queues.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(head) = queues.get(key).and_then(|q| q.front()) {
+            head.notify.notify_one();
+        }
+    }
+
+    /// Number of clients currently blocked.
+    pub fn len(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A client's place in the blocked queues.
+pub struct BlockedClient {
+    owner: Arc<BlockedClients>,
+    keys: Vec<String>,
+    waiter: Arc<Waiter>,
+}
+
+impl BlockedClient {
+    /// Waits for one of the keys to be signalled. Returns false once
+    /// `deadline`, by the database's clock, passes.
+    pub async fn wait(&self, deadline: Option<Instant>) -> bool {
+        match deadline {
+            Some(d) => tokio::select! {
+                biased;
+                _ = self.waiter.notify.notified() => true,
+                _ = self.owner.clock.sleep_until(d) => false,
+            },
+            None => {
+                self.waiter.notify.notified().await;
+                true
             }
         }
-    });
+    }
+
+    /// Leaves the queues after the command succeeded and hands the wake-up to
+    /// the next waiter, which retries in case something is left for it.
+    pub fn served(self) {
+        let owner = self.owner.clone();
+        let keys = self.keys.clone();
+        drop(self);
+        for key in &keys {
+            owner.signal_key_ready(key);
+        }
+    }
+}
+
+impl Drop for BlockedClient {
+    fn drop(&mut self) {
+        let mut queues = self.owner.queues.lock().unwrap_or_else(|e| e.into_inner());
+        for key in &self.keys {
+            if let Some(queue) = queues.get_mut(key) {
+                queue.retain(|w| w.id != self.waiter.id);
+                if queue.is_empty() {
+                    queues.remove(key);
+                }
+            }
+        }
+        self.owner.waiting.fetch_sub(1, Ordering::Relaxed);
+    }
 }
@@ -0,0 +1,128 @@
+//! Active defragmentation: giving back memory that churn leaves behind.
+//!
+//! A shard's table keeps the capacity it grew to after most of its keys
+//! are gone, and a value's buffer can hold far more than its bytes. With
+//! `activedefrag` on, a background pass walks every database a shard at a
+//! time, rebuilding tables that are mostly empty slots and shrinking values
+//! with a lot of slack. The pass works in slices of at most
+//! `active-defrag-cycle-max` percent of a core, holding one shard's lock at a
+//! time, and starts over at most every ten seconds. INFO stats reports what
+//! it reclaimed.
+
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::task::JoinHandle;
+
+use crate::config::Settings;
+use crate::db::Database;
+use crate::engine::Compacted;
+
+// One slice of a pass runs per tick; its budget is a share of the tick
+const TICK: Duration = Duration::from_millis(100);
+// How soon a finished pass may start over
+const PASS_INTERVAL: Duration = Duration::from_secs(10);
+// Tables are rebuilt once more than three quarters of their slots are empty,
+// and values shrunk once over a quarter of their buffer is, as long as
+// either frees at least this much
+const MIN_SLACK: usize = 64;
+
+/// What defragmentation has done, as INFO reports it.
+#[derive(Default)]
+pub(crate) struct DefragStats {
+    /// Whether a pass is underway.
+    pub running: AtomicBool,
+    pub passes: AtomicU64,
+    pub reclaimed_bytes: AtomicU64,
+    /// Shard tables rebuilt smaller.
+    pub rebuilt_tables: AtomicU64,
+    /// Value buffers shrunk to fit.
+    pub shrunk_values: AtomicU64,
+}
+
+// Where a pass has got to: a database and a shard of its store
+#[derive(Default)]
+struct Cursor {
+    db: usize,
+    shard: usize,
+}
+
+/// Runs defragmentation passes while `activedefrag` is on, until aborted.
+pub(crate) fn start_defrag(dbs: Arc<[Database]>, settings: Arc<Settings>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let stats = &dbs[0].stats.defrag;
+        let mut cursor: Option<Cursor> = None;
+        let mut last_pass: Option<Instant> = None;
+        loop {
+            tokio::time::sleep(TICK).await;
+            let Some(budget) = settings.defrag_budget(TICK) else {
+                cursor = None;
+                stats.running.store(false, Ordering::Relaxed);
+                continue;
+            };
+            if cursor.is_none() && last_pass.is_none_or(|at| at.elapsed() >= PASS_INTERVAL) {
+                cursor = Some(Cursor::default());
+                stats.running.store(true, Ordering::Relaxed);
+            }
+            let Some(at) = cursor.as_mut() else {
+                continue;
+            };
+            if !step(&dbs, at, Instant::now() + budget) {
+                cursor = None;
+                last_pass = Some(Instant::now());
+                stats.passes.fetch_add(1, Ordering::Relaxed);
+                stats.running.store(false, Ordering::Relaxed);
+            }
+        }
+    })
+}
+
+// Compacts shards from `at` on until `deadline`; false once the pass is
+// through every database
+fn step(dbs: &[Database], at: &mut Cursor, deadline: Instant) -> bool {
+    while let Some(db) = dbs.get(at.db) {
+        if at.shard < db.store.shards() {
+            let done = db.store.compact(at.shard);
+            let stats = &db.stats.defrag;
+            stats.reclaimed_bytes.fetch_add(done.reclaimed_bytes as u64, Ordering::Relaxed);
+            stats.rebuilt_tables.fetch_add(done.rebuilt_tables, Ordering::Relaxed);
+            stats.shrunk_values.fetch_add(done.shrunk_values, Ordering::Relaxed);
+        } else {
+            at.db += 1;
+            at.shard = 0;
+            continue;
+        }
+        at.shard += 1;
+        if Instant::now() >= deadline {
+            return true;
+        }
+    }
+    false
+}
+
+// Rebuilds a table of `Slot`s that is mostly empty slots at the size its
+// keys need, counting what that freed into `done`; `shrink` rebuilds it and
+// returns its new capacity
+pub(crate) fn shrink_table<Slot>(len: usize, capacity: usize, done: &mut Compacted, shrink: impl FnOnce() -> usize) {
+    // Each slot also has a control byte
+    let slot = size_of::<Slot>() + 1;
+    if capacity <= len.saturating_mul(4) || (capacity - len) * slot < MIN_SLACK {
+        return;
+    }
+    let after = shrink();
+    done.rebuilt_tables += 1;
+    done.reclaimed_bytes += capacity.saturating_sub(after) * slot;
+}
+
+// Shrinks `value` to fit if over a quarter of its buffer is slack, counting
+// what that freed into `done`
+pub(crate) fn shrink_value(value: &mut Vec<u8>, done: &mut Compacted) {
+    let slack = value.capacity() - value.len();
+    if slack >= MIN_SLACK && slack > value.len() / 4 {
+        value.shrink_to_fit();
+        done.reclaimed_bytes += slack - (value.capacity() - value.len());
+        done.shrunk_values += 1;
+    }
+}
@@ -0,0 +1,105 @@
+//! A full diagnostic report for debugging a wedged instance, written on
+//! SIGUSR1 or DEBUG DUMPSTATS.
+//!
+//! The report holds what an operator would otherwise collect command by
+//! command, if the server still answered: runtime task counts, each
+//! database's size per shard, blocked and connected clients, the slow log,
+//! INFO memory, stats and persistence, and the running config (secrets left
+//! out, as CONFIG GET leaves them out). It goes to the log, or is appended
+//! to `diagnostics-file` when that is set. Building it takes each shard's
+//! read lock in turn and nothing else for long, so a server stuck on one
+//! lock still reports everything but that shard.
+
+use std::fmt::Write as _;
+use std::fs::OpenOptions;
+use std::io::{self, Write as _};
+use std::path::Path;
+
+use chrono::Local;
+
+use crate::config::Settings;
+use crate::db::Database;
+use crate::info::build_info;
+
+// Slow log entries included
+const SLOWLOG_TAIL: usize = 32;
+
+/// The report, as plain text.
+fn build_report(dbs: &[Database], settings: &Settings) -> String {
+    let mut out = String::with_capacity(8 * 1024);
+    let _ = writeln!(out, "=== RustCache diagnostics, pid {}, {} ===", std::process::id(), Local::now().format("%d %b %Y %H:%M:%S%.3f"));
+
+    let _ = writeln!(out, "\n# Runtime");
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => {
+            let metrics = runtime.metrics();
+            let _ = writeln!(out, "workers:{}", metrics.num_workers());
+            let _ = writeln!(out, "alive_tasks:{}", metrics.num_alive_tasks());
+            let _ = writeln!(out, "global_queue_depth:{}", metrics.global_queue_depth());
+        }
+        Err(_) => {
+            let _ = writeln!(out, "(not on the runtime)");
+        }
+    }
+
+    let _ = writeln!(out, "\n# Databases");
+    for (i, db) in dbs.iter().enumerate() {
+        let shards = db.shard_lens();
+        let _ = writeln!(
+            out,
+            "db{}:keys={},expires={},used_memory={},blocked_clients={},spilled_keys={}",
+            i,
+            shards.iter().sum::<usize>(),
+            db.expires_count(),
+            db.used_memory(),
+            db.blocked().len(),
+            db.spilled_keys()
+        );
+        let largest = shards.iter().max().copied().unwrap_or(0);
+        let sizes: Vec<String> = shards.iter().map(usize::to_string).collect();
+        let _ = writeln!(out, "db{}_shards:count={},largest={},sizes={}", i, shards.len(), largest, sizes.join(","));
+    }
+
+    let clients = dbs[0].stats.clients.all();
+    let _ = writeln!(out, "\n# Clients ({})", clients.len());
+    for client in clients {
+        let _ = writeln!(out, "{}", client.describe());
+    }
+
+    let slowlog = settings.slowlog();
+    let _ = writeln!(out, "\n# Slowlog (newest {} of {})", SLOWLOG_TAIL.min(slowlog.len()), slowlog.len());
+    for entry in slowlog.recent(SLOWLOG_TAIL) {
+        let _ = writeln!(out, "id={} at={} us={} client={} cmd={}", entry.id, entry.at, entry.micros, entry.client_addr, entry.args.join(" "));
+    }
+
+    for section in ["memory", "stats", "persistence", "replication"] {
+        out.push('\n');
+        out.push_str(&build_info(dbs, settings, Some(section)).replace("\r\n", "\n"));
+    }
+
+    let _ = writeln!(out, "\n# Config");
+    for (name, value) in settings.current().directives() {
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    out
+}
+
+/// Writes the report to `diagnostics-file`, or the log when that is unset,
+/// and returns it.
+pub(crate) fn dump(dbs: &[Database], settings: &Settings) -> io::Result<String> {
+    let report = build_report(dbs, settings);
+    match settings.current().diagnostics_file {
+        Some(path) => {
+            append(&path, &report)?;
+            println!("Diagnostics written to {}", path.display());
+        }
+        None => println!("{}", report),
+    }
+    Ok(report)
+}
+
+fn append(path: &Path, report: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(report.as_bytes())?;
+    file.write_all(b"\n")
+}
@@ -0,0 +1,231 @@
+//! Storage engines: where a database keeps its entries and their deadlines.
+//!
+//! [`Database`](crate::db::Database) does everything the commands see (TTLs,
+//! idle limits, the cold tier, snapshots, eviction, keyspace callbacks) in
+//! terms of the [`StorageEngine`] beneath it, so a sharded, persistent or
+//! tiered backend can take the place of the in-memory [`MapEngine`] by
+//! implementing the trait, without the command layer changing.
+
+use std::time::Instant;
+
+use dashmap::mapref::entry::Entry as MapEntry;
+use dashmap::DashMap;
+
+use crate::db::Entry;
+use crate::defrag::{shrink_table, shrink_value};
+
+/// A visitor of a shard's entries; returns false to stop early.
+pub type Visit<'a> = &'a mut dyn FnMut(&str, &Entry) -> bool;
+
+/// The key-value map beneath a database, split into shards that each lock
+/// on their own. Every method is atomic with respect to the key it touches.
+///
+/// SCAN cursors are positions in the keyspace: [`StorageEngine::position`]
+/// must put a shard's keys in one run, shard 0 first, so the shard count has
+/// to be a power of two.
+pub trait StorageEngine: Send + Sync {
+    /// Runs `f` on the entry of `key`, if there is one, while no write can
+    /// change it. Returns whether there was.
+    fn get(&self, key: &str, f: &mut dyn FnMut(&Entry)) -> bool;
+
+    fn contains(&self, key: &str) -> bool;
+
+    /// Stores `entry` under `key`, returning the entry it replaced.
+    fn set(&self, key: String, entry: Entry) -> Option<Entry>;
+
+    /// Passes the entry of `key` (None if there is none) to `f` and stores
+    /// the entry `f` returns, if any, in the same step: no other write of the
+    /// key comes in between. Returns the entry it replaced.
+    fn update(&self, key: String, f: &mut dyn FnMut(Option<&Entry>) -> Option<Entry>) -> Option<Entry>;
+
+    /// Removes `key`, returning its entry. Its deadline is left alone.
+    fn delete(&self, key: &str) -> Option<(String, Entry)>;
+
+    /// Keys stored, across every shard.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drops every entry and every deadline.
+    fn clear(&self);
+
+    fn shards(&self) -> usize;
+
+    fn shard_of(&self, key: &str) -> usize;
+
+    /// Where `key` falls in SCAN order; its leading bits are its shard.
+    fn position(&self, key: &str) -> usize;
+
+    fn shard_len(&self, shard: usize) -> usize;
+
+    /// Passes the entries of `shard` to `visit` in a stable order, under the
+    /// shard's lock, until it returns false.
+    fn iterate(&self, shard: usize, visit: Visit<'_>);
+
+    /// When `key` expires, if it has a TTL.
+    fn expiry(&self, key: &str) -> Option<Instant>;
+
+    /// Sets or, with None, clears the deadline of `key`, returning the old one.
+    fn set_expiry(&self, key: &str, at: Option<Instant>) -> Option<Instant>;
+
+    /// Keys with a deadline.
+    fn expiring(&self) -> usize;
+
+    /// Keys whose deadline is `now` or earlier.
+    fn expired(&self, now: Instant) -> Vec<String>;
+
+    /// Gives back memory `shard` no longer needs, for active defragmentation.
+    /// Engines with nothing to compact leave it alone.
+    fn compact(&self, _shard: usize) -> Compacted {
+        Compacted::default()
+    }
+
+    /// Every key of every shard, for whole-keyspace operations.
+    fn keys(&self) -> Vec<String> {
+        let mut keys = Vec::with_capacity(self.len());
+        for shard in 0..self.shards() {
+            self.iterate(shard, &mut |key, _| {
+                keys.push(key.to_string());
+                true
+            });
+        }
+        keys
+    }
+}
+
+/// What compacting a shard gave back.
+#[derive(Debug, Default)]
+pub struct Compacted {
+    pub reclaimed_bytes: usize,
+    /// Tables rebuilt smaller.
+    pub rebuilt_tables: u64,
+    /// Value buffers shrunk to fit.
+    pub shrunk_values: u64,
+}
+
+/// The default engine: entries in one DashMap, deadlines in another sharded
+/// the same way, so compacting a shard of one compacts that of the other.
+pub struct MapEngine {
+    entries: DashMap<String, Entry>,
+    deadlines: DashMap<String, Instant>,
+}
+
+impl MapEngine {
+    pub fn new(shards: usize) -> Self {
+        Self {
+            entries: DashMap::with_shard_amount(shards),
+            deadlines: DashMap::with_shard_amount(shards),
+        }
+    }
+}
+
+impl StorageEngine for MapEngine {
+    fn get(&self, key: &str, f: &mut dyn FnMut(&Entry)) -> bool {
+        self.entries.get(key).map(|e| f(&e)).is_some()
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    fn set(&self, key: String, entry: Entry) -> Option<Entry> {
+        self.entries.insert(key, entry)
+    }
+
+    fn update(&self, key: String, f: &mut dyn FnMut(Option<&Entry>) -> Option<Entry>) -> Option<Entry> {
+        match self.entries.entry(key) {
+            MapEntry::Occupied(mut slot) => {
+                let new = f(Some(slot.get()))?;
+                Some(std::mem::replace(slot.get_mut(), new))
+            }
+            MapEntry::Vacant(slot) => {
+                slot.insert(f(None)?);
+                None
+            }
+        }
+    }
+
+    fn delete(&self, key: &str) -> Option<(String, Entry)> {
+        self.entries.remove(key)
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn clear(&self) {
+        self.entries.clear();
+        self.deadlines.clear();
+    }
+
+    fn shards(&self) -> usize {
+        self.entries.shards().len()
+    }
+
+    fn shard_of(&self, key: &str) -> usize {
+        self.entries.determine_map(key)
+    }
+
+    // The hash rotated so the bits DashMap picks the shard with come first
+    fn position(&self, key: &str) -> usize {
+        self.entries.hash_usize(&key).rotate_left(7)
+    }
+
+    fn shard_len(&self, shard: usize) -> usize {
+        self.entries.shards().get(shard).map_or(0, |map| map.read().len())
+    }
+
+    fn iterate(&self, shard: usize, visit: Visit<'_>) {
+        let Some(map) = self.entries.shards().get(shard) else { return };
+        for (key, entry) in map.read().iter() {
+            if !visit(key, entry.get()) {
+                break;
+            }
+        }
+    }
+
+    fn expiry(&self, key: &str) -> Option<Instant> {
+        self.deadlines.get(key).map(|at| *at)
+    }
+
+    fn set_expiry(&self, key: &str, at: Option<Instant>) -> Option<Instant> {
+        match at {
+            Some(at) => self.deadlines.insert(key.to_string(), at),
+            None => self.deadlines.remove(key).map(|(_, at)| at),
+        }
+    }
+
+    fn expiring(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    fn expired(&self, now: Instant) -> Vec<String> {
+        self.deadlines.iter().filter(|e| *e.value() <= now).map(|e| e.key().clone()).collect()
+    }
+
+    fn compact(&self, shard: usize) -> Compacted {
+        let mut done = Compacted::default();
+        if let Some(map) = self.entries.shards().get(shard) {
+            let mut map = map.write();
+            for (_, entry) in map.iter_mut() {
+                shrink_value(&mut entry.get_mut().value, &mut done);
+            }
+            let (len, capacity) = (map.len(), map.capacity());
+            shrink_table::<(String, Entry)>(len, capacity, &mut done, || {
+                map.shrink_to_fit();
+                map.capacity()
+            });
+        }
+        if let Some(map) = self.deadlines.shards().get(shard) {
+            let mut map = map.write();
+            let (len, capacity) = (map.len(), map.capacity());
+            shrink_table::<(String, Instant)>(len, capacity, &mut done, || {
+                map.shrink_to_fit();
+                map.capacity()
+            });
+        }
+        done
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+use crate::db::CorruptValue;
+use crate::resp::RespValue;
+
+/// An error reply. Each kind carries the prefix Redis uses for it, so clients
+/// that branch on the error class (`WRONGTYPE`, `MOVED`, ...) work unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    /// `ERR` with a free-form message, for failures without their own class.
+    Generic(String),
+    Syntax,
+    NotInteger,
+    /// The command's name.
+    WrongArity(String),
+    UnknownCommand(String),
+    WrongType,
+    NoAuth,
+    WrongPass,
+    NoPerm { user: String, command: String },
+    Oom,
+    BusyKey,
+    Moved { slot: u16, addr: String },
+    Ask { slot: u16, addr: String },
+    NoScript,
+    ExecAbort,
+    Loading,
+    ReadOnly,
+    /// Refused by a replica cut off from its master, under
+    /// replica-serve-stale-data no.
+    MasterDown,
+    /// A cancellable command ran past busy-reply-threshold.
+    Busy,
+    CrossSlot,
+    /// A stored value failed its checksum; see [`CorruptValue`].
+    Corrupt(CorruptValue),
+    /// The user is over one of its ACL quotas, named here.
+    Throttled { user: String, quota: &'static str },
+    /// A write refused under min-replicas-to-write.
+    NoReplicas,
+    /// In raft mode, a command for the leader sent elsewhere; names the
+    /// leader when one is known.
+    NotLeader { leader: Option<String> },
+}
+
+impl CommandError {
+    pub fn generic(msg: impl Into<String>) -> Self {
+        Self::Generic(msg.into())
+    }
+
+    /// The error class, the first word of the reply.
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::Generic(_) | Self::Syntax | Self::NotInteger | Self::WrongArity(_) | Self::UnknownCommand(_) => "ERR",
+            Self::WrongType => "WRONGTYPE",
+            Self::NoAuth => "NOAUTH",
+            Self::WrongPass => "WRONGPASS",
+            Self::NoPerm { .. } => "NOPERM",
+            Self::Oom => "OOM",
+            Self::BusyKey => "BUSYKEY",
+            Self::Moved { .. } => "MOVED",
+            Self::Ask { .. } => "ASK",
+            Self::NoScript => "NOSCRIPT",
+            Self::ExecAbort => "EXECABORT",
+            Self::Loading => "LOADING",
+            Self::ReadOnly => "READONLY",
+            Self::MasterDown => "MASTERDOWN",
+            Self::Busy => "BUSY",
+            Self::CrossSlot => "CROSSSLOT",
+            Self::Corrupt(_) => "CORRUPT",
+            Self::Throttled { .. } => "THROTTLED",
+            Self::NoReplicas => "NOREPLICAS",
+            Self::NotLeader { .. } => "NOTLEADER",
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ", self.prefix())?;
+        match self {
+            Self::Generic(msg) => f.write_str(msg),
+            Self::Syntax => f.write_str("syntax error"),
+            Self::NotInteger => f.write_str("value is not an integer or out of range"),
+            Self::WrongArity(cmd) => write!(f, "wrong number of arguments for '{}' command", cmd),
+            Self::UnknownCommand(cmd) => write!(f, "unknown command '{}'", cmd),
+            Self::WrongType => f.write_str("Operation against a key holding the wrong kind of value"),
+            Self::NoAuth => f.write_str("Authentication required."),
+            Self::WrongPass => f.write_str("invalid username-password pair or user is disabled."),
+            Self::NoPerm { user, command } => {
+                write!(f, "User {} has no permissions to run the '{}' command", user, command)
+            }
+            Self::Oom => f.write_str("command not allowed when used memory > 'maxmemory'."),
+            Self::BusyKey => f.write_str("Target key name already exists."),
+            Self::Moved { slot, addr } | Self::Ask { slot, addr } => write!(f, "{} {}", slot, addr),
+            Self::NoScript => f.write_str("No matching script. Please use EVAL."),
+            Self::ExecAbort => f.write_str("Transaction discarded because of previous errors."),
+            Self::Loading => f.write_str("RustCache is loading the dataset in memory"),
+            Self::ReadOnly => f.write_str("You can't write against a read only replica."),
+            Self::MasterDown => f.write_str("Link with MASTER is down and replica-serve-stale-data is set to 'no'."),
+            Self::Busy => f.write_str("command aborted after exceeding busy-reply-threshold"),
+            Self::CrossSlot => f.write_str("Keys in request don't hash to the same slot"),
+            Self::Corrupt(e) => e.fmt(f),
+            Self::Throttled { user, quota } => write!(f, "User {} is over its {} quota", user, quota),
+            Self::NoReplicas => f.write_str("Not enough good replicas to write."),
+            Self::NotLeader { leader: Some(addr) } => f.write_str(addr),
+            Self::NotLeader { leader: None } => f.write_str("no leader is confirmed yet; try again"),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl From<CorruptValue> for CommandError {
+    fn from(e: CorruptValue) -> Self {
+        Self::Corrupt(e)
+    }
+}
+
+impl From<CommandError> for RespValue {
+    fn from(e: CommandError) -> Self {
+        RespValue::Error(e.to_string())
+    }
+}
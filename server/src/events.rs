@@ -0,0 +1,306 @@
+//! Publishing keyspace events to Kafka or NATS.
+//!
+//! With `events-to` set, every key that is set, deleted, expired or evicted
+//! in any database is queued as an event, and a background task publishes
+//! the queue in order: to a NATS subject, as `<subject>.<event>` so a
+//! subscriber can pick events with a wildcard, or to a Kafka topic, keyed by
+//! the cache key so one key's events stay in order on one partition.
+//! Downstream caches and pipelines then learn of invalidations without
+//! polling. `events-format` picks the payload: `json`
+//! (`{"event":"set","db":0,"key":"user:1","time_ms":1760000000000}`) or
+//! `text` (`set 0 user:1`). The queue holds at most `events-queue-size`
+//! events; while the broker is slow or unreachable it fills and further
+//! events are dropped and counted. An event whose publish was not
+//! acknowledged is sent again, so delivery is at least once. FLUSHDB
+//! publishes nothing, as it reports nothing to key listeners.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::db::{Database, KeyEvent};
+use crate::kafka::KafkaProducer;
+use crate::snapshot::unix_ms_now;
+use crate::stats::Stats;
+use crate::websocket::quote;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Events published before waiting for the broker to acknowledge them
+const BATCH: usize = 256;
+
+/// Where events go: the `events-to` directive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventSink {
+    /// A NATS server (`host:port`) and the subject events are published under.
+    Nats { addr: String, subject: String },
+    /// A Kafka bootstrap broker (`host:port`) and the topic.
+    Kafka { addr: String, topic: String },
+}
+
+impl EventSink {
+    /// Parses `nats <host:port> <subject>` or `kafka <host:port> <topic>`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        let [kind, addr, name] = value.split_whitespace().collect::<Vec<_>>()[..] else {
+            return Err("events-to needs a kind (nats or kafka), an address and a subject or topic".to_string());
+        };
+        if !addr.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            return Err(format!("events-to address '{}' must be host:port", addr));
+        }
+        let (addr, name) = (addr.to_string(), name.to_string());
+        match kind.to_ascii_lowercase().as_str() {
+            "nats" => Ok(Self::Nats { addr, subject: name }),
+            "kafka" => Ok(Self::Kafka { addr, topic: name }),
+            other => Err(format!("unknown events-to kind '{}'", other)),
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Self::Nats { addr, subject } => format!("nats {} {}", addr, subject),
+            Self::Kafka { addr, topic } => format!("kafka {} {}", addr, topic),
+        }
+    }
+}
+
+/// How an event is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    Json,
+    /// `<event> <db> <key>`.
+    Text,
+}
+
+impl EventFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "json" => Some(Self::Json),
+            "text" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Text => "text",
+        }
+    }
+}
+
+const KEY_EVENTS: [KeyEvent; 4] = [KeyEvent::Set, KeyEvent::Deleted, KeyEvent::Expired, KeyEvent::Evicted];
+
+/// An event's name in what is published: set, del, expired or evicted.
+pub(crate) fn event_name(event: KeyEvent) -> &'static str {
+    match event {
+        KeyEvent::Set => "set",
+        KeyEvent::Deleted => "del",
+        KeyEvent::Expired => "expired",
+        KeyEvent::Evicted => "evicted",
+    }
+}
+
+pub(crate) fn event_from_name(name: &str) -> Option<KeyEvent> {
+    KEY_EVENTS.into_iter().find(|e| event_name(*e).eq_ignore_ascii_case(name))
+}
+
+/// A queued event, serialized.
+#[derive(Clone)]
+pub(crate) struct Event {
+    pub name: &'static str,
+    pub key: String,
+    pub payload: Vec<u8>,
+}
+
+/// Events waiting for the broker, and how publishing is going, as INFO
+/// stats reports it. Webhooks queue their events in one each too.
+#[derive(Default)]
+pub(crate) struct EventQueue {
+    queue: Mutex<VecDeque<Event>>,
+    ready: Notify,
+    /// Zero until publishing starts.
+    capacity: AtomicUsize,
+    pub link_up: AtomicBool,
+    /// Events the broker has acknowledged.
+    pub published: AtomicU64,
+    /// Events left out because the queue was full.
+    pub dropped: AtomicU64,
+}
+
+impl EventQueue {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let queue = Self::default();
+        queue.capacity.store(capacity, Ordering::Relaxed);
+        queue
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Event>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn push(&self, event: Event) {
+        let mut queue = self.lock();
+        if queue.len() >= self.capacity.load(Ordering::Relaxed) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push_back(event);
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    /// Waits for events and adds up to `max` of them to `batch`, leaving
+    /// them queued until [`pop`](Self::pop).
+    pub async fn peek(&self, batch: &mut Vec<Event>, max: usize) {
+        loop {
+            let notified = self.ready.notified();
+            {
+                let queue = self.lock();
+                if queue.len() > batch.len() {
+                    batch.extend(queue.iter().skip(batch.len()).take(max.saturating_sub(batch.len())).cloned());
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Removes the first `n` events, once they are delivered.
+    pub fn pop(&self, n: usize) {
+        self.lock().drain(..n);
+    }
+}
+
+pub(crate) fn serialize(format: EventFormat, event: &str, db: usize, key: &str) -> Vec<u8> {
+    match format {
+        EventFormat::Json => {
+            let mut out = format!("{{\"event\":\"{}\",\"db\":{},\"key\":", event, db);
+            quote(key, &mut out);
+            out.push_str(&format!(",\"time_ms\":{}}}", unix_ms_now()));
+            out.into_bytes()
+        }
+        EventFormat::Text => format!("{} {} {}", event, db, key).into_bytes(),
+    }
+}
+
+/// Queues the key events of every database in `dbs` for `sink` and
+/// publishes them until aborted, holding up to `capacity` of them.
+pub(crate) fn start_events(sink: EventSink, format: EventFormat, dbs: &[Database], stats: Arc<Stats>, capacity: usize) -> JoinHandle<()> {
+    stats.events.capacity.store(capacity, Ordering::Relaxed);
+    let queued = stats.clone();
+    on_key_events(dbs, move |db, event, key| {
+        let name = event_name(event);
+        queued.events.push(Event {
+            name,
+            key: key.to_string(),
+            payload: serialize(format, name, db, key),
+        });
+    });
+    tokio::spawn(async move {
+        let events = &stats.events;
+        loop {
+            let published = match &sink {
+                EventSink::Nats { addr, subject } => publish_nats(addr, subject, events).await,
+                EventSink::Kafka { addr, topic } => publish_kafka(addr, topic, events).await,
+            };
+            if let Err(e) = published {
+                eprintln!("Publishing events to {} failed: {}", sink.describe(), e);
+            }
+            events.link_up.store(false, Ordering::Relaxed);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    })
+}
+
+/// Calls `f(db, event, key)` for every key event in every database of
+/// `dbs`, `db` being the database's number.
+pub(crate) fn on_key_events(dbs: &[Database], f: impl Fn(usize, KeyEvent, &str) + Send + Sync + Clone + 'static) {
+    for (index, db) in dbs.iter().enumerate() {
+        let listener = |event: KeyEvent| {
+            let f = f.clone();
+            move |key: &str, _: Option<&[u8]>| f(index, event, key)
+        };
+        db.on_set(listener(KeyEvent::Set));
+        db.on_delete(listener(KeyEvent::Deleted));
+        db.on_expired(listener(KeyEvent::Expired));
+        db.on_evicted(listener(KeyEvent::Evicted));
+    }
+}
+
+pub(crate) async fn connect(addr: &str) -> io::Result<TcpStream> {
+    tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+}
+
+// Publishes queued events over one NATS connection until it fails. NATS
+// acknowledges nothing, so each batch ends with a PING and counts as
+// delivered once the server's PONG shows it read everything before
+async fn publish_nats(addr: &str, subject: &str, events: &EventQueue) -> io::Result<()> {
+    let mut conn = BufReader::new(connect(addr).await?);
+    let mut line = String::new();
+    conn.read_line(&mut line).await?;
+    if !line.starts_with("INFO ") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("not a NATS server: {}", line.trim_end())));
+    }
+    let hello = format!(
+        "CONNECT {{\"verbose\":false,\"pedantic\":false,\"name\":\"rustcache\",\"lang\":\"rust\",\"version\":\"{}\"}}\r\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    conn.get_mut().write_all(hello.as_bytes()).await?;
+    events.link_up.store(true, Ordering::Relaxed);
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        events.peek(&mut batch, BATCH).await;
+        let mut buf = Vec::new();
+        for event in &batch {
+            buf.extend_from_slice(format!("PUB {}.{} {}\r\n", subject, event.name, event.payload.len()).as_bytes());
+            buf.extend_from_slice(&event.payload);
+            buf.extend_from_slice(b"\r\n");
+        }
+        buf.extend_from_slice(b"PING\r\n");
+        conn.get_mut().write_all(&buf).await?;
+        loop {
+            line.clear();
+            if conn.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "NATS server closed the connection"));
+            }
+            match line.trim_end() {
+                "PONG" => break,
+                "PING" => conn.get_mut().write_all(b"PONG\r\n").await?,
+                err if err.starts_with("-ERR") => return Err(io::Error::other(err.to_string())),
+                // INFO updates and +OK carry nothing to act on
+                _ => {}
+            }
+        }
+        events.pop(batch.len());
+        events.published.fetch_add(batch.len() as u64, Ordering::Relaxed);
+    }
+}
+
+// Publishes queued events to a Kafka topic until the producer fails
+async fn publish_kafka(addr: &str, topic: &str, events: &EventQueue) -> io::Result<()> {
+    let mut producer = KafkaProducer::connect(addr, topic).await?;
+    events.link_up.store(true, Ordering::Relaxed);
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        events.peek(&mut batch, BATCH).await;
+        let records: Vec<(&[u8], &[u8])> = batch.iter().map(|e| (e.key.as_bytes(), e.payload.as_slice())).collect();
+        producer.send(&records).await?;
+        events.pop(batch.len());
+        events.published.fetch_add(batch.len() as u64, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,471 @@
+//! Cluster membership and failure detection by gossip, in the style of SWIM.
+//!
+//! With cluster-enabled, each node knows the others from what they tell each
+//! other rather than from a static list: a node started with a
+//! `cluster-seed` (or sent CLUSTER MEET) introduces itself there and learns
+//! the rest of the cluster in the reply. Every `cluster-node-timeout / 5`
+//! the node probes one member in turn with `CLUSTER PING`, which carries
+//! its whole view (each member's id, address, incarnation, health and
+//! slots) and is answered with the other side's, so what one node learns
+//! spreads to all of them.
+//!
+//! A member that doesn't answer is probed indirectly through a few others
+//! (`CLUSTER PINGREQ`), so one bad link is not taken for a dead node. If
+//! none of them reach it either, it is suspected. It is marked failed once a
+//! majority of the members that aren't failed have suspected it within two
+//! node timeouts, and the failure then spreads like anything else. A member
+//! that hears it is suspected or failed refutes it by raising its
+//! incarnation, which overrides every report made about the lower one.
+//!
+//! Slot ownership travels the same way, from each node's `cluster-slots`: a
+//! keyed command for a slot another live member owns is answered with MOVED.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::cluster::{format_slots, parse_slot_range, SLOTS};
+use crate::config::ServerConfig;
+use crate::resp::{read_resp, RespValue};
+use crate::snapshot::unix_ms_now;
+
+// Members asked to probe one that didn't answer
+const FANOUT: usize = 3;
+// Bulk strings per member in a gossip message
+const FIELDS: usize = 5;
+
+/// What a node believes about a member.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Health {
+    Alive,
+    Suspect,
+    Failed,
+}
+
+impl Health {
+    pub fn name(self) -> &'static str {
+        match self {
+            Health::Alive => "alive",
+            Health::Suspect => "suspect",
+            Health::Failed => "failed",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "alive" => Some(Health::Alive),
+            "suspect" => Some(Health::Suspect),
+            "failed" => Some(Health::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// Another node of the cluster.
+#[derive(Debug, Clone)]
+pub(crate) struct Member {
+    pub id: String,
+    pub addr: String,
+    pub incarnation: u64,
+    pub health: Health,
+    pub slots: Vec<(u16, u16)>,
+    /// When it last answered a probe from here (Unix ms); 0 if never.
+    pub seen_ms: i64,
+    // The nodes that reported it suspect, and when
+    reporters: HashMap<String, i64>,
+}
+
+// A member as gossip describes it
+struct Rumor {
+    id: String,
+    addr: String,
+    incarnation: u64,
+    health: Health,
+    slots: Vec<(u16, u16)>,
+}
+
+#[derive(Default)]
+struct View {
+    incarnation: u64,
+    members: BTreeMap<String, Member>,
+    /// Addresses to introduce this node to until a member there is known.
+    seeds: Vec<String>,
+    /// Where the probe rotation is.
+    next: usize,
+}
+
+/// This node's view of the cluster.
+pub(crate) struct Gossip {
+    pub id: String,
+    pub addr: String,
+    pub slots: Vec<(u16, u16)>,
+    timeout: Duration,
+    auth: Vec<Vec<u8>>,
+    view: Mutex<View>,
+}
+
+/// The cluster as INFO cluster and CLUSTER INFO summarize it.
+pub(crate) struct Summary {
+    /// Every slot is owned by a node that isn't failed.
+    pub ok: bool,
+    pub slots_assigned: usize,
+    pub known_nodes: usize,
+    /// Nodes owning at least one slot.
+    pub size: usize,
+}
+
+impl Gossip {
+    pub fn new(config: &ServerConfig, addr: String) -> Self {
+        let auth = match &config.masterauth {
+            Some(pass) => ["AUTH".to_string()].into_iter().chain(config.masteruser.clone()).chain([pass.clone()]).map(String::into_bytes).collect(),
+            None => Vec::new(),
+        };
+        let id: String = (0..3u8).map(|i| format!("{:016x}", RandomState::new().hash_one(i))).collect();
+        Self {
+            id: id[..40].to_string(),
+            addr,
+            slots: config.cluster_slots.clone(),
+            timeout: config.cluster_node_timeout,
+            auth,
+            view: Mutex::new(View { seeds: config.cluster_seeds.clone(), ..View::default() }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, View> {
+        self.view.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // How often a member is probed, and how long one may take to answer
+    fn period(&self) -> Duration {
+        self.timeout / 5
+    }
+
+    fn probe_timeout(&self) -> Duration {
+        self.timeout / 4
+    }
+
+    pub fn incarnation(&self) -> u64 {
+        self.lock().incarnation
+    }
+
+    pub fn members(&self) -> Vec<Member> {
+        self.lock().members.values().cloned().collect()
+    }
+
+    /// Adds `addr` to the addresses this node introduces itself to.
+    pub fn meet(&self, addr: String) {
+        let mut view = self.lock();
+        if !view.seeds.contains(&addr) {
+            view.seeds.push(addr);
+        }
+    }
+
+    /// The address of the live member owning `slot`, unless it is this node
+    /// or no one claims it.
+    pub fn owner(&self, slot: u16) -> Option<String> {
+        let owns = |slots: &[(u16, u16)]| slots.iter().any(|(start, end)| (*start..=*end).contains(&slot));
+        if owns(&self.slots) {
+            return None;
+        }
+        let view = self.lock();
+        view.members.values().find(|m| m.health != Health::Failed && owns(&m.slots)).map(|m| m.addr.clone())
+    }
+
+    pub fn summary(&self) -> Summary {
+        let view = self.lock();
+        let owners: Vec<&[(u16, u16)]> =
+            [self.slots.as_slice()].into_iter().chain(view.members.values().map(|m| m.slots.as_slice())).collect();
+        let live: Vec<&[(u16, u16)]> = [self.slots.as_slice()]
+            .into_iter()
+            .chain(view.members.values().filter(|m| m.health != Health::Failed).map(|m| m.slots.as_slice()))
+            .collect();
+        let covered = |owners: &[&[(u16, u16)]]| {
+            let mut slots = vec![false; SLOTS as usize];
+            for (start, end) in owners.iter().flat_map(|o| o.iter()) {
+                slots[*start as usize..=*end as usize].iter_mut().for_each(|s| *s = true);
+            }
+            slots.into_iter().filter(|s| *s).count()
+        };
+        let assigned = covered(&owners);
+        Summary {
+            ok: covered(&live) == SLOTS as usize,
+            slots_assigned: assigned,
+            known_nodes: view.members.len() + 1,
+            size: owners.iter().filter(|o| !o.is_empty()).count(),
+        }
+    }
+
+    /// This node's view as a gossip message: itself first, then every member.
+    pub fn rumors(&self) -> Vec<Vec<u8>> {
+        let view = self.lock();
+        let mut out = Vec::new();
+        let mut push = |id: &str, addr: &str, incarnation: u64, health: Health, slots: &[(u16, u16)]| {
+            out.extend([
+                id.as_bytes().to_vec(),
+                addr.as_bytes().to_vec(),
+                incarnation.to_string().into_bytes(),
+                health.name().as_bytes().to_vec(),
+                format_slots(slots, ",").into_bytes(),
+            ]);
+        };
+        push(&self.id, &self.addr, view.incarnation, Health::Alive, &self.slots);
+        // Only this node's own suspicions count as its reports
+        for m in view.members.values() {
+            let health = match m.health {
+                Health::Suspect if !m.reporters.contains_key(&self.id) => Health::Alive,
+                health => health,
+            };
+            push(&m.id, &m.addr, m.incarnation, health, &m.slots);
+        }
+        out
+    }
+
+    /// Merges a gossip message; the first member in it is the one that sent
+    /// it. False if the message is malformed.
+    pub fn merge(&self, message: &[Vec<u8>]) -> bool {
+        let Some(rumors) = parse_rumors(message) else { return false };
+        let Some(sender) = rumors.first().map(|r| r.id.clone()) else { return false };
+        let now = unix_ms_now();
+        let mut view = self.lock();
+        for rumor in rumors {
+            self.hear(&mut view, &sender, rumor, now);
+        }
+        true
+    }
+
+    // Folds one member's description, as `reporter` gave it, into the view
+    fn hear(&self, view: &mut View, reporter: &str, rumor: Rumor, now: i64) {
+        if rumor.id == self.id {
+            // Refuted by outliving every report about this incarnation
+            if rumor.health != Health::Alive && rumor.incarnation >= view.incarnation {
+                view.incarnation = rumor.incarnation + 1;
+            }
+            return;
+        }
+        let Some(member) = view.members.get_mut(&rumor.id) else {
+            if rumor.health == Health::Failed || rumor.addr == self.addr {
+                return;
+            }
+            // A node that restarted comes back with a new id
+            view.members.retain(|_, m| !(m.addr == rumor.addr && m.health == Health::Failed));
+            println!("Cluster node {} at {} joined", rumor.id, rumor.addr);
+            let mut member = Member {
+                id: rumor.id.clone(),
+                addr: rumor.addr,
+                incarnation: rumor.incarnation,
+                health: rumor.health,
+                slots: rumor.slots,
+                seen_ms: 0,
+                reporters: HashMap::new(),
+            };
+            if rumor.health == Health::Suspect {
+                member.reporters.insert(reporter.to_string(), now);
+            }
+            view.members.insert(rumor.id, member);
+            return;
+        };
+        if rumor.incarnation < member.incarnation {
+            return;
+        }
+        if rumor.incarnation > member.incarnation {
+            if member.health == Health::Failed && rumor.health == Health::Alive {
+                println!("Cluster node {} at {} is back", member.id, rumor.addr);
+            }
+            member.incarnation = rumor.incarnation;
+            member.health = rumor.health;
+            member.reporters.clear();
+        }
+        member.addr = rumor.addr;
+        member.slots = rumor.slots;
+        if rumor.health == Health::Failed && member.health != Health::Failed {
+            println!("Cluster node {} at {} was marked failed by {}", member.id, member.addr, reporter);
+        }
+        member.health = member.health.max(rumor.health);
+        if rumor.health == Health::Suspect {
+            member.reporters.insert(reporter.to_string(), now);
+        }
+        self.judge(view, &rumor.id, now);
+    }
+
+    // Marks a suspected member failed once a majority has suspected it
+    // recently enough
+    fn judge(&self, view: &mut View, id: &str, now: i64) {
+        let members = 1 + view.members.values().filter(|m| m.health != Health::Failed).count();
+        let quorum = members / 2 + 1;
+        let fresh = 2 * self.timeout.as_millis() as i64;
+        let Some(member) = view.members.get_mut(id) else { return };
+        member.reporters.retain(|_, at| now - *at <= fresh);
+        if member.health != Health::Suspect {
+            return;
+        }
+        if member.reporters.is_empty() {
+            member.health = Health::Alive;
+        } else if member.reporters.len() >= quorum {
+            member.health = Health::Failed;
+            println!("Cluster node {} at {} failed: {} of {} nodes lost it", member.id, member.addr, member.reporters.len(), members);
+        }
+    }
+
+    // Notes that `id` answered a probe from here
+    fn acked(&self, id: &str) {
+        let now = unix_ms_now();
+        let mut view = self.lock();
+        if let Some(member) = view.members.get_mut(id) {
+            member.seen_ms = now;
+            member.reporters.remove(&self.id);
+            self.judge(&mut view, id, now);
+        }
+    }
+
+    // Notes that neither this node nor the ones it asked could reach `id`
+    fn suspect(&self, id: &str) {
+        let now = unix_ms_now();
+        let mut view = self.lock();
+        let Some(member) = view.members.get_mut(id) else { return };
+        if member.health == Health::Alive {
+            println!("Cluster node {} at {} is not answering", member.id, member.addr);
+            member.health = Health::Suspect;
+        }
+        if member.health == Health::Suspect {
+            member.reporters.insert(self.id.clone(), now);
+        }
+        self.judge(&mut view, id, now);
+    }
+
+    // The next member to probe, and seeds not yet known as members
+    fn targets(&self) -> (Option<Member>, Vec<String>) {
+        let mut view = self.lock();
+        let seeds = view.seeds.iter().filter(|s| **s != self.addr && !view.members.values().any(|m| m.addr == **s)).cloned().collect();
+        if view.members.is_empty() {
+            return (None, seeds);
+        }
+        view.next = (view.next + 1) % view.members.len();
+        (view.members.values().nth(view.next).cloned(), seeds)
+    }
+
+    // Members that may probe `id` on this node's behalf
+    fn helpers(&self, id: &str) -> Vec<String> {
+        let view = self.lock();
+        let helpers = view.members.values().filter(|m| m.id != id && m.health == Health::Alive);
+        helpers.take(FANOUT).map(|m| m.addr.clone()).collect()
+    }
+
+    /// Exchanges views with the node at `addr`; returns its id.
+    pub async fn ping(&self, addr: &str) -> io::Result<String> {
+        let mut command = vec![b"CLUSTER".to_vec(), b"PING".to_vec()];
+        command.extend(self.rumors());
+        let reply = self.request(addr, command, self.probe_timeout()).await?;
+        let reply = match reply {
+            RespValue::Array(Some(items)) => items
+                .into_iter()
+                .map(|i| match i {
+                    RespValue::BulkString(Some(b)) => Some(b),
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        };
+        let Some(reply) = reply.filter(|r| self.merge(r)) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "malformed gossip reply"));
+        };
+        Ok(String::from_utf8_lossy(&reply[0]).into_owned())
+    }
+
+    // Probes a member directly, then through others
+    async fn probe(self: Arc<Self>, member: Member) {
+        if self.ping(&member.addr).await.is_ok_and(|id| id == member.id) {
+            self.acked(&member.id);
+            return;
+        }
+        let mut asks = JoinSet::new();
+        for helper in self.helpers(&member.id) {
+            let (gossip, target) = (self.clone(), member.addr.clone());
+            asks.spawn(async move {
+                let command = vec![b"CLUSTER".to_vec(), b"PINGREQ".to_vec(), target.into_bytes()];
+                gossip.request(&helper, command, 2 * gossip.probe_timeout()).await
+            });
+        }
+        while let Some(reply) = asks.join_next().await {
+            if matches!(reply, Ok(Ok(RespValue::SimpleString(_)))) {
+                self.acked(&member.id);
+                return;
+            }
+        }
+        self.suspect(&member.id);
+    }
+
+    // Sends `command` to `addr` on a new connection, after AUTH when
+    // masterauth is set, and returns the reply
+    async fn request(&self, addr: &str, command: Vec<Vec<u8>>, timeout: Duration) -> io::Result<RespValue> {
+        let exchange = async {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await?);
+            let mut buf = Vec::new();
+            let commands = [self.auth.clone(), command];
+            let commands: Vec<&Vec<Vec<u8>>> = commands.iter().filter(|c| !c.is_empty()).collect();
+            for args in &commands {
+                RespValue::Array(Some(args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect())).encode(&mut buf);
+            }
+            conn.get_mut().write_all(&buf).await?;
+            let mut reply = RespValue::BulkString(None);
+            for _ in &commands {
+                reply = read_resp(&mut conn).await?;
+                if let RespValue::Error(e) = reply {
+                    return Err(io::Error::other(e));
+                }
+            }
+            Ok(reply)
+        };
+        tokio::time::timeout(timeout, exchange).await.map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer"))?
+    }
+}
+
+fn parse_rumors(message: &[Vec<u8>]) -> Option<Vec<Rumor>> {
+    if message.is_empty() || !message.len().is_multiple_of(FIELDS) {
+        return None;
+    }
+    let text = |b: &[u8]| std::str::from_utf8(b).ok().map(str::to_string);
+    message
+        .chunks(FIELDS)
+        .map(|f| {
+            let slots = text(&f[4])?;
+            Some(Rumor {
+                id: text(&f[0])?,
+                addr: text(&f[1])?,
+                incarnation: text(&f[2])?.parse().ok()?,
+                health: Health::from_name(&text(&f[3])?)?,
+                slots: slots.split(',').filter(|s| !s.is_empty()).map(parse_slot_range).collect::<Option<_>>()?,
+            })
+        })
+        .collect()
+}
+
+/// Probes a member every period, and introduces this node to its seeds,
+/// until aborted.
+pub(crate) fn start_gossip(gossip: Arc<Gossip>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut probes = JoinSet::new();
+        let mut tick = tokio::time::interval(gossip.period());
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            while probes.try_join_next().is_some() {}
+            let (member, seeds) = gossip.targets();
+            for seed in seeds {
+                let gossip = gossip.clone();
+                probes.spawn(async move {
+                    let _ = gossip.ping(&seed).await;
+                });
+            }
+            if let Some(member) = member {
+                probes.spawn(gossip.clone().probe(member));
+            }
+        }
+    })
+}
@@ -0,0 +1,62 @@
+//! Liveness and readiness probes over plain HTTP, for orchestrators that
+//! would otherwise have to speak RESP and parse INFO.
+//!
+//! `GET /healthz` answers 200 whenever the server is accepting connections.
+//! `GET /readyz` answers 200 when it can serve its data as expected and 503
+//! otherwise, with one line per problem in the body: a replica still loading
+//! its first full sync or cut off from its master, a warmup still running,
+//! or a failed save or AOF write.
+
+use std::io;
+use std::sync::atomic::Ordering;
+
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+use crate::config::Settings;
+use crate::db::Database;
+use crate::websocket::{read_request, respond};
+
+/// Why the server is not ready; empty when it is.
+pub(crate) fn problems(dbs: &[Database], settings: &Settings) -> Vec<String> {
+    let stats = &dbs[0].stats;
+    let mut problems = Vec::new();
+    if let Some(master) = settings.current().replicaof {
+        let replica = &stats.replica;
+        if replica.sync_in_progress.load(Ordering::Relaxed) {
+            problems.push(format!("loading: full sync from {} in progress", master));
+        } else if !replica.link_up.load(Ordering::Relaxed) {
+            problems.push(format!("replication: link to {} is down", master));
+        }
+    }
+    if stats.warmup.running() {
+        problems.push("loading: warmup in progress".to_string());
+    }
+    if !stats.last_save_ok.load(Ordering::Relaxed) {
+        problems.push("persistence: the last save failed".to_string());
+    }
+    if !stats.aof_last_write_ok.load(Ordering::Relaxed) {
+        problems.push("persistence: the last AOF write failed".to_string());
+    }
+    problems
+}
+
+/// Answers one probe on `stream` and closes it.
+pub(crate) async fn serve(stream: TcpStream, dbs: &[Database], settings: &Settings) -> io::Result<()> {
+    let (read_half, mut writer) = stream.into_split();
+    let request = read_request(&mut BufReader::new(read_half)).await?;
+    let mut words = request.first().map(|line| line.split(' ')).into_iter().flatten();
+    let (method, path) = (words.next().unwrap_or_default(), words.next().unwrap_or_default());
+    let path = path.split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("GET", "/healthz") => respond(&mut writer, "200 OK", "text/plain", "ok\n").await,
+        ("GET", "/readyz") => match problems(dbs, settings) {
+            problems if problems.is_empty() => respond(&mut writer, "200 OK", "text/plain", "ready\n").await,
+            problems => {
+                let body: String = problems.iter().map(|p| format!("{}\n", p)).collect();
+                respond(&mut writer, "503 Service Unavailable", "text/plain", &body).await
+            }
+        },
+        _ => respond(&mut writer, "404 Not Found", "text/plain", "try /healthz or /readyz\n").await,
+    }
+}
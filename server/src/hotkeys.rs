@@ -0,0 +1,111 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 4096;
+/// Keys kept as top-N candidates; HOTKEYS reports at most this many.
+pub(crate) const TRACKED_KEYS: usize = 64;
+// Counts are halved after this many recorded accesses so the ranking follows
+// current traffic rather than all-time totals
+const AGING_PERIOD: u64 = 16 * SKETCH_WIDTH as u64;
+
+/// Sampled access frequencies: a count-min sketch estimates how often each
+/// key is accessed, and the keys with the highest estimates are kept as the
+/// hot key candidates.
+pub(crate) struct HotKeys {
+    seen: AtomicU64,
+    inner: Mutex<Tracker>,
+}
+
+struct Tracker {
+    hashers: [RandomState; SKETCH_DEPTH],
+    counters: Vec<u32>,
+    recorded: u64,
+    /// (db, key) -> estimated accesses
+    top: HashMap<(usize, String), u64>,
+}
+
+impl HotKeys {
+    pub fn new() -> Self {
+        Self {
+            seen: AtomicU64::new(0),
+            inner: Mutex::new(Tracker {
+                hashers: std::array::from_fn(|_| RandomState::new()),
+                counters: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+                recorded: 0,
+                top: HashMap::new(),
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Tracker> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Counts one access to `keys` in database `db` if it falls in the sample
+    /// of one in `rate`; each sampled access stands for `rate` of them.
+    pub fn record<'a>(&self, rate: u32, db: usize, keys: impl Iterator<Item = &'a [u8]>) {
+        if rate == 0 || !self.seen.fetch_add(1, Ordering::Relaxed).is_multiple_of(rate as u64) {
+            return;
+        }
+        let mut tracker = self.lock();
+        for key in keys {
+            tracker.add(db, key, rate);
+        }
+    }
+
+    /// Up to `count` of the most accessed keys as (db, key, estimated accesses),
+    /// hottest first.
+    pub fn top(&self, count: usize) -> Vec<(usize, String, u64)> {
+        let tracker = self.lock();
+        let mut hot: Vec<(usize, String, u64)> =
+            tracker.top.iter().map(|((db, key), n)| (*db, key.clone(), *n)).collect();
+        hot.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+        hot.truncate(count);
+        hot
+    }
+
+    pub fn reset(&self) {
+        let mut tracker = self.lock();
+        tracker.counters.fill(0);
+        tracker.top.clear();
+        tracker.recorded = 0;
+    }
+}
+
+impl Tracker {
+    fn add(&mut self, db: usize, key: &[u8], weight: u32) {
+        let mut estimate = u32::MAX;
+        for row in 0..SKETCH_DEPTH {
+            let slot = row * SKETCH_WIDTH + (self.hashers[row].hash_one((db, key)) as usize % SKETCH_WIDTH);
+            let counter = &mut self.counters[slot];
+            *counter = counter.saturating_add(weight);
+            estimate = estimate.min(*counter);
+        }
+        let estimate = estimate as u64;
+        let name = (db, String::from_utf8_lossy(key).into_owned());
+        if let Some(n) = self.top.get_mut(&name) {
+            *n = estimate;
+        } else if self.top.len() < TRACKED_KEYS {
+            self.top.insert(name, estimate);
+        } else if let Some((coldest, n)) = self.top.iter().min_by_key(|(_, n)| **n).map(|(k, n)| (k.clone(), *n)) {
+            if estimate > n {
+                self.top.remove(&coldest);
+                self.top.insert(name, estimate);
+            }
+        }
+
+        self.recorded += 1;
+        if self.recorded.is_multiple_of(AGING_PERIOD) {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            for n in self.top.values_mut() {
+                *n /= 2;
+            }
+        }
+    }
+}
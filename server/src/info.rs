@@ -0,0 +1,286 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+
+use crate::allocator;
+use crate::config::Settings;
+use crate::db::Database;
+use crate::pubsub::Kind;
+use crate::snapshot::unix_ms_now;
+
+const SECTIONS: &[&str] = &["server", "clients", "memory", "persistence", "stats", "replication", "hotkeys", "cluster", "keyspace"];
+const INFO_HOTKEYS: usize = 10;
+
+fn write_section(dbs: &[Database], settings: &Settings, name: &str, out: &mut String) {
+    let stats = &dbs[0].stats;
+    match name {
+        "server" => {
+            let _ = write!(out, "# Server\r\n");
+            let _ = write!(out, "rustcache_version:{}\r\n", env!("CARGO_PKG_VERSION"));
+            let _ = write!(out, "process_id:{}\r\n", std::process::id());
+            let uptime = stats.started_at.elapsed().as_secs();
+            let _ = write!(out, "uptime_in_seconds:{}\r\n", uptime);
+            let _ = write!(out, "uptime_in_days:{}\r\n", uptime / 86_400);
+            if let Some(cores) = stats.cores.get() {
+                let commands = cores.commands();
+                let _ = write!(out, "cores:{}\r\n", commands.len());
+                for (i, n) in commands.iter().enumerate() {
+                    let _ = write!(out, "core{}:commands={}\r\n", i, n);
+                }
+            }
+        }
+        "clients" => {
+            let _ = write!(out, "# Clients\r\n");
+            let clients = stats.connected_clients.load(Ordering::Relaxed);
+            let _ = write!(out, "connected_clients:{}\r\n", clients);
+        }
+        "memory" => {
+            let _ = write!(out, "# Memory\r\n");
+            let used: usize = dbs.iter().map(|db| db.used_memory()).sum();
+            let config = settings.current();
+            let _ = write!(out, "used_memory:{}\r\n", used);
+            let rss = allocator::resident_set_size();
+            let _ = write!(out, "used_memory_rss:{}\r\n", rss);
+            let _ = write!(out, "mem_fragmentation_ratio:{:.2}\r\n", rss as f64 / used.max(1) as f64);
+            let alloc = allocator::stats();
+            let _ = write!(out, "mem_allocator:{}\r\n", allocator::NAME);
+            let _ = write!(out, "allocator_allocated:{}\r\n", alloc.allocated);
+            let _ = write!(out, "allocator_active:{}\r\n", alloc.active);
+            let _ = write!(out, "allocator_resident:{}\r\n", alloc.resident);
+            let _ = write!(out, "allocator_frag_ratio:{:.2}\r\n", alloc.fragmentation());
+            let _ = write!(out, "maxmemory:{}\r\n", config.maxmemory);
+            let _ = write!(out, "maxmemory_policy:{}\r\n", config.maxmemory_policy.name());
+            let _ = write!(out, "maxmemory_accounting:{}\r\n", config.maxmemory_accounting.name());
+            let defragging = stats.defrag.running.load(Ordering::Relaxed);
+            let _ = write!(out, "active_defrag_running:{}\r\n", defragging as u8);
+            let (hits, misses) = stats.reply_buffers.hits_and_misses();
+            let _ = write!(out, "reply_buffers_idle:{}\r\n", stats.reply_buffers.idle());
+            let _ = write!(out, "reply_buffers_reused:{}\r\n", hits);
+            let _ = write!(out, "reply_buffers_allocated:{}\r\n", misses);
+            if config.overflow_dir.is_some() {
+                let keys: usize = dbs.iter().map(|db| db.spilled_keys()).sum();
+                let bytes: u64 = dbs.iter().map(|db| db.spilled_bytes()).sum();
+                let _ = write!(out, "overflow_keys:{}\r\n", keys);
+                let _ = write!(out, "overflow_bytes:{}\r\n", bytes);
+            }
+        }
+        "persistence" => {
+            let _ = write!(out, "# Persistence\r\n");
+            let in_progress = stats.bgsave_in_progress.load(Ordering::Relaxed);
+            let ok = stats.last_save_ok.load(Ordering::Relaxed);
+            let _ = write!(out, "rdb_bgsave_in_progress:{}\r\n", in_progress as u8);
+            let _ = write!(out, "rdb_last_save_time:{}\r\n", stats.last_save_time.load(Ordering::Relaxed));
+            let _ = write!(out, "rdb_last_bgsave_status:{}\r\n", if ok { "ok" } else { "err" });
+            let _ = write!(out, "aof_enabled:{}\r\n", settings.current().appendonly as u8);
+            let aof_ok = stats.aof_last_write_ok.load(Ordering::Relaxed);
+            let _ = write!(out, "aof_last_write_status:{}\r\n", if aof_ok { "ok" } else { "err" });
+            let (queued, running, completed) = stats.persistence_io.counts();
+            let _ = write!(out, "io_jobs_queued:{}\r\n", queued);
+            let _ = write!(out, "io_jobs_running:{}\r\n", running);
+            let _ = write!(out, "io_jobs_completed:{}\r\n", completed);
+            if let Some(target) = &settings.current().write_behind {
+                let queue = &stats.write_behind;
+                let up = queue.link_up.load(Ordering::Relaxed);
+                let _ = write!(out, "write_behind_target:{}\r\n", target.describe());
+                let _ = write!(out, "write_behind_link_status:{}\r\n", if up { "up" } else { "down" });
+                let _ = write!(out, "write_behind_pending:{}\r\n", queue.len());
+                let _ = write!(out, "write_behind_written:{}\r\n", queue.written.load(Ordering::Relaxed));
+                let _ = write!(out, "write_behind_retries:{}\r\n", queue.retries.load(Ordering::Relaxed));
+                let _ = write!(out, "write_behind_dead_lettered:{}\r\n", queue.dead_lettered.load(Ordering::Relaxed));
+                let _ = write!(out, "write_behind_dropped:{}\r\n", queue.dropped.load(Ordering::Relaxed));
+            }
+        }
+        "stats" => {
+            let _ = write!(out, "# Stats\r\n");
+            let conns = stats.total_connections_received.load(Ordering::Relaxed);
+            let cmds = stats.total_commands_processed.load(Ordering::Relaxed);
+            let _ = write!(out, "total_connections_received:{}\r\n", conns);
+            let rejected = stats.rejected_connections_ip.load(Ordering::Relaxed);
+            let _ = write!(out, "rejected_connections_ip:{}\r\n", rejected);
+            let _ = write!(out, "total_commands_processed:{}\r\n", cmds);
+            let hits = stats.keyspace_hits.load(Ordering::Relaxed);
+            let misses = stats.keyspace_misses.load(Ordering::Relaxed);
+            let _ = write!(out, "keyspace_hits:{}\r\n", hits);
+            let _ = write!(out, "keyspace_misses:{}\r\n", misses);
+            let evicted = stats.evicted_keys.load(Ordering::Relaxed);
+            let _ = write!(out, "evicted_keys:{}\r\n", evicted);
+            let refused = stats.oom_rejected_commands.load(Ordering::Relaxed);
+            let _ = write!(out, "oom_rejected_commands:{}\r\n", refused);
+            let corrupt = stats.corrupt_values.load(Ordering::Relaxed);
+            let _ = write!(out, "corrupt_values:{}\r\n", corrupt);
+            let stalls = stats.client_queue_stalls.load(Ordering::Relaxed);
+            let _ = write!(out, "client_queue_stalls:{}\r\n", stalls);
+            let _ = write!(out, "pubsub_channels:{}\r\n", stats.pubsub.count(Kind::Channel));
+            let _ = write!(out, "pubsub_patterns:{}\r\n", stats.pubsub.count(Kind::Pattern));
+            let _ = write!(out, "pubsub_shardchannels:{}\r\n", stats.pubsub.count(Kind::Shard));
+            let _ = write!(out, "pubsub_dropped_messages:{}\r\n", stats.pubsub.dropped.load(Ordering::Relaxed));
+            let _ = write!(out, "pubsub_slow_disconnects:{}\r\n", stats.pubsub.disconnected.load(Ordering::Relaxed));
+            if let Some(tls) = settings.tls() {
+                let _ = write!(out, "tls_cert_reloads:{}\r\n", tls.reloads.load(Ordering::Relaxed));
+                let _ = write!(out, "tls_cert_reload_errors:{}\r\n", tls.reload_errors.load(Ordering::Relaxed));
+            }
+            let current = settings.current();
+            if current.backing_store.is_some() || !current.loaders.is_empty() {
+                let _ = write!(out, "backing_loads:{}\r\n", stats.backing_loads.load(Ordering::Relaxed));
+                let _ = write!(out, "backing_loads_shared:{}\r\n", stats.backing_loads_shared.load(Ordering::Relaxed));
+            }
+            let defrag = &stats.defrag;
+            let _ = write!(out, "defrag_passes:{}\r\n", defrag.passes.load(Ordering::Relaxed));
+            let _ = write!(out, "defrag_reclaimed_bytes:{}\r\n", defrag.reclaimed_bytes.load(Ordering::Relaxed));
+            let _ = write!(out, "defrag_rebuilt_tables:{}\r\n", defrag.rebuilt_tables.load(Ordering::Relaxed));
+            let _ = write!(out, "defrag_shrunk_values:{}\r\n", defrag.shrunk_values.load(Ordering::Relaxed));
+            if let Some(target) = &settings.current().shadow_to {
+                let shadow = &stats.shadow;
+                let up = shadow.link_up.load(Ordering::Relaxed);
+                let (primary_us, shadow_us) = shadow.mean_latencies();
+                let _ = write!(out, "shadow_target:{}\r\n", target);
+                let _ = write!(out, "shadow_link_status:{}\r\n", if up { "up" } else { "down" });
+                let _ = write!(out, "shadow_sent:{}\r\n", shadow.sent.load(Ordering::Relaxed));
+                let _ = write!(out, "shadow_dropped:{}\r\n", shadow.dropped.load(Ordering::Relaxed));
+                let _ = write!(out, "shadow_only_errors:{}\r\n", shadow.shadow_only_errors.load(Ordering::Relaxed));
+                let _ = write!(out, "shadow_primary_only_errors:{}\r\n", shadow.primary_only_errors.load(Ordering::Relaxed));
+                let _ = write!(out, "shadow_primary_avg_us:{}\r\n", primary_us);
+                let _ = write!(out, "shadow_avg_us:{}\r\n", shadow_us);
+                let _ = write!(out, "shadow_latency_delta_us:{}\r\n", shadow_us as i64 - primary_us as i64);
+            }
+            if let Some(sink) = &settings.current().events_to {
+                let events = &stats.events;
+                let up = events.link_up.load(Ordering::Relaxed);
+                let _ = write!(out, "events_target:{}\r\n", sink.describe());
+                let _ = write!(out, "events_link_status:{}\r\n", if up { "up" } else { "down" });
+                let _ = write!(out, "events_queue_len:{}\r\n", events.len());
+                let _ = write!(out, "events_published:{}\r\n", events.published.load(Ordering::Relaxed));
+                let _ = write!(out, "events_dropped:{}\r\n", events.dropped.load(Ordering::Relaxed));
+            }
+            let hooks = settings.current().webhooks.len();
+            if hooks > 0 {
+                let webhooks = &stats.webhooks;
+                let _ = write!(out, "webhooks:{}\r\n", hooks);
+                let _ = write!(out, "webhook_queue_len:{}\r\n", webhooks.queued());
+                let _ = write!(out, "webhook_delivered:{}\r\n", webhooks.delivered.load(Ordering::Relaxed));
+                let _ = write!(out, "webhook_failed:{}\r\n", webhooks.failed.load(Ordering::Relaxed));
+                let _ = write!(out, "webhook_retries:{}\r\n", webhooks.retries.load(Ordering::Relaxed));
+                let _ = write!(out, "webhook_dropped:{}\r\n", webhooks.dropped());
+            }
+        }
+        "replication" => {
+            let _ = write!(out, "# Replication\r\n");
+            let config = settings.current();
+            match &config.replicaof {
+                None => {
+                    let replicas = &stats.replicas;
+                    let _ = write!(out, "role:master\r\n");
+                    let list = replicas.list();
+                    let _ = write!(out, "connected_slaves:{}\r\n", list.len());
+                    if let Some((_, max_lag)) = settings.min_replicas() {
+                        let _ = write!(out, "min_slaves_good_slaves:{}\r\n", replicas.good(max_lag));
+                    }
+                    for (i, r) in list.iter().enumerate() {
+                        let state = if r.online { "online" } else { "wait_bgsave" };
+                        let _ = write!(out, "slave{}:ip={},port={},state={},offset={},lag={}\r\n", i, r.ip, r.port, state, r.offset, r.lag);
+                    }
+                    let _ = write!(out, "master_replid:{}\r\n", replicas.replid);
+                    let _ = write!(out, "master_repl_offset:{}\r\n", replicas.offset.load(Ordering::Relaxed));
+                }
+                Some(master) => {
+                    let replica = &stats.replica;
+                    let (host, port) = master.rsplit_once(':').unwrap_or((master, ""));
+                    let up = replica.link_up.load(Ordering::Relaxed);
+                    let last_io = replica.last_io_ms.load(Ordering::Relaxed);
+                    let _ = write!(out, "role:slave\r\n");
+                    let _ = write!(out, "master_host:{}\r\n", host);
+                    let _ = write!(out, "master_port:{}\r\n", port);
+                    let _ = write!(out, "master_link_status:{}\r\n", if up { "up" } else { "down" });
+                    if last_io > 0 {
+                        let _ = write!(out, "master_last_io_seconds_ago:{}\r\n", (unix_ms_now() - last_io) / 1000);
+                    }
+                    let syncing = replica.sync_in_progress.load(Ordering::Relaxed);
+                    let _ = write!(out, "master_sync_in_progress:{}\r\n", syncing as u8);
+                    let _ = write!(out, "slave_repl_offset:{}\r\n", replica.offset.load(Ordering::Relaxed));
+                    let _ = write!(out, "slave_read_only:{}\r\n", config.replica_read_only as u8);
+                    let _ = write!(out, "repl_full_syncs:{}\r\n", replica.full_syncs.load(Ordering::Relaxed));
+                    let skipped = replica.skipped_commands.load(Ordering::Relaxed);
+                    let _ = write!(out, "repl_skipped_commands:{}\r\n", skipped);
+                }
+            }
+            if let Some(raft) = stats.raft.get() {
+                let status = raft.status();
+                let _ = write!(out, "raft_role:{}\r\n", status.role.name());
+                let _ = write!(out, "raft_term:{}\r\n", status.term);
+                let _ = write!(out, "raft_leader:{}\r\n", status.leader.unwrap_or_default());
+                let _ = write!(out, "raft_peers:{}\r\n", status.peers);
+                let _ = write!(out, "raft_log_length:{}\r\n", status.log_length);
+                let _ = write!(out, "raft_commit_index:{}\r\n", status.commit);
+                let _ = write!(out, "raft_last_applied:{}\r\n", status.applied);
+            }
+            if let Some(crdt) = stats.crdt.get() {
+                let _ = write!(out, "crdt_id:{}\r\n", crdt.node());
+                let _ = write!(out, "crdt_keys:{}\r\n", crdt.keys());
+                let _ = write!(out, "crdt_merges:{}\r\n", crdt.merged.load(Ordering::Relaxed));
+                for (i, peer) in crdt.peers().iter().enumerate() {
+                    let link = if peer.up { "up" } else { "down" };
+                    let _ = write!(out, "crdt_peer{}:addr={},link={},unsent={}\r\n", i, peer.addr, link, peer.unsent);
+                }
+            }
+            if let Some(target) = &config.mirror_to {
+                let mirror = &stats.mirror;
+                let up = mirror.link_up.load(Ordering::Relaxed);
+                let _ = write!(out, "mirror_target:{}\r\n", target);
+                let _ = write!(out, "mirror_link_status:{}\r\n", if up { "up" } else { "down" });
+                let _ = write!(out, "mirror_queue_len:{}\r\n", mirror.len());
+                let _ = write!(out, "mirror_lag_ms:{}\r\n", mirror.lag().as_millis());
+                let _ = write!(out, "mirror_forwarded:{}\r\n", mirror.forwarded.load(Ordering::Relaxed));
+                let _ = write!(out, "mirror_dropped:{}\r\n", mirror.dropped.load(Ordering::Relaxed));
+                let _ = write!(out, "mirror_refused:{}\r\n", mirror.refused.load(Ordering::Relaxed));
+                let _ = write!(out, "mirror_errors:{}\r\n", mirror.errors.load(Ordering::Relaxed));
+            }
+        }
+        "hotkeys" => {
+            let _ = write!(out, "# Hotkeys\r\n");
+            let _ = write!(out, "hotkeys_sample:{}\r\n", settings.hotkeys_sample());
+            for (i, (db, key, hits)) in stats.hotkeys.top(INFO_HOTKEYS).into_iter().enumerate() {
+                let _ = write!(out, "hotkey{}:db={},key={},hits={}\r\n", i, db, key.escape_debug(), hits);
+            }
+        }
+        "cluster" => {
+            let _ = write!(out, "# Cluster\r\n");
+            let _ = write!(out, "cluster_enabled:{}\r\n", settings.cluster_enabled() as u8);
+            if let Some(gossip) = stats.gossip.get() {
+                let summary = gossip.summary();
+                let _ = write!(out, "cluster_state:{}\r\n", if summary.ok { "ok" } else { "fail" });
+                let _ = write!(out, "cluster_slots_assigned:{}\r\n", summary.slots_assigned);
+                let _ = write!(out, "cluster_known_nodes:{}\r\n", summary.known_nodes);
+                let _ = write!(out, "cluster_size:{}\r\n", summary.size);
+            }
+        }
+        "keyspace" => {
+            let _ = write!(out, "# Keyspace\r\n");
+            for (i, db) in dbs.iter().enumerate() {
+                let keys = db.dbsize();
+                if keys > 0 {
+                    let _ = write!(out, "db{}:keys={},expires={}\r\n", i, keys, db.expires_count());
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+pub fn build_info(dbs: &[Database], settings: &Settings, section: Option<&str>) -> String {
+    let mut out = String::with_capacity(512);
+    let wanted: Vec<&str> = match section {
+        None => SECTIONS.to_vec(),
+        Some(s) if s.eq_ignore_ascii_case("all") || s.eq_ignore_ascii_case("default") => SECTIONS.to_vec(),
+        Some(s) => SECTIONS
+            .iter()
+            .copied()
+            .filter(|name| name.eq_ignore_ascii_case(s))
+            .collect(),
+    };
+    for (i, name) in wanted.iter().enumerate() {
+        if i > 0 {
+            out.push_str("\r\n");
+        }
+        write_section(dbs, settings, name, &mut out);
+    }
+    out
+}
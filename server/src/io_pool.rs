@@ -0,0 +1,107 @@
+//! Threads for persistence I/O.
+//!
+//! Saves, AOF fsyncs and bulk loads run here instead of on the async workers
+//! serving commands, so a slow disk or a big file delays only the client
+//! that asked for it. The threads start with the first job. Jobs run in the
+//! order they were queued, `IO_THREADS` at a time, and INFO persistence
+//! reports how many are waiting, running and done.
+
+use std::io;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Enough for an fsync to go ahead while a save is being written
+const IO_THREADS: usize = 2;
+
+// Jobs mark themselves finished, so a waiter never sees its own job counted
+// as still running
+type Job = Box<dyn FnOnce(&Counts) + Send>;
+
+#[derive(Default)]
+pub(crate) struct IoPool {
+    jobs: OnceLock<Sender<Job>>,
+    counts: Arc<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    queued: AtomicUsize,
+    running: AtomicUsize,
+    completed: AtomicU64,
+}
+
+impl IoPool {
+    /// Queues `job` and returns at once.
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        self.queue(Box::new(move |counts| {
+            job();
+            counts.finished();
+        }));
+    }
+
+    /// Runs `job` on the pool and waits for its result without holding up
+    /// the async worker. Fails if the job panicked.
+    pub async fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> io::Result<T> {
+        let (done, result) = tokio::sync::oneshot::channel();
+        self.queue(Box::new(move |counts| {
+            let value = job();
+            counts.finished();
+            let _ = done.send(value);
+        }));
+        result.await.map_err(|_| io::Error::other("persistence job panicked"))
+    }
+
+    /// Jobs waiting for a thread, jobs running and jobs finished.
+    pub fn counts(&self) -> (usize, usize, u64) {
+        let counts = &self.counts;
+        (
+            counts.queued.load(Ordering::Relaxed),
+            counts.running.load(Ordering::Relaxed),
+            counts.completed.load(Ordering::Relaxed),
+        )
+    }
+
+    fn queue(&self, job: Job) {
+        self.counts.queued.fetch_add(1, Ordering::Relaxed);
+        // The workers only stop once the sender is dropped with the pool
+        let _ = self.jobs.get_or_init(|| self.start()).send(job);
+    }
+
+    fn start(&self) -> Sender<Job> {
+        let (jobs, queue) = channel();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..IO_THREADS {
+            let (queue, counts) = (queue.clone(), self.counts.clone());
+            std::thread::Builder::new()
+                .name(format!("rustcache-io-{}", i))
+                .spawn(move || work(&queue, &counts))
+                .expect("spawning a persistence I/O thread");
+        }
+        jobs
+    }
+}
+
+fn work(queue: &Mutex<Receiver<Job>>, counts: &Counts) {
+    loop {
+        let job = match queue.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        counts.queued.fetch_sub(1, Ordering::Relaxed);
+        counts.running.fetch_add(1, Ordering::Relaxed);
+        // A panicking job drops its result sender, which fails its waiter
+        if catch_unwind(AssertUnwindSafe(|| job(counts))).is_err() {
+            eprintln!("persistence I/O job panicked");
+            counts.finished();
+        }
+    }
+}
+
+impl Counts {
+    fn finished(&self) {
+        self.running.fetch_sub(1, Ordering::Relaxed);
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+}
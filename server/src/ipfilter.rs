@@ -0,0 +1,83 @@
+//! Allow and deny lists of client addresses.
+//!
+//! Checked as each client or WebSocket connection is accepted, before a byte
+//! of it is read, so an unwelcome peer costs an accept and a close. A peer
+//! on the deny list is refused; with an allow list, so is any peer not on
+//! it. Connections already open when the lists change are left alone.
+
+use std::fmt;
+use std::net::IpAddr;
+
+/// An address block, `10.0.0.0/8` or `fd00::/8`; a bare address is a block
+/// of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid address block '{}'", text);
+        let (addr, prefix) = match text.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (text, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        // Host bits are cleared, so 10.1.2.3/8 reads back as 10.0.0.0/8
+        let network = match addr {
+            IpAddr::V4(v4) => IpAddr::V4((u32::from(v4) & mask(prefix, 32) as u32).into()),
+            IpAddr::V6(v6) => IpAddr::V6((u128::from(v6) & mask(prefix, 128)).into()),
+        };
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => u32::from(ip) & mask(self.prefix, 32) as u32 == u32::from(net),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => u128::from(ip) & mask(self.prefix, 128) == u128::from(net),
+            _ => false,
+        }
+    }
+}
+
+// The top `prefix` of `bits` bits set
+fn mask(prefix: u8, bits: u32) -> u128 {
+    match prefix {
+        0 => 0,
+        p => (u128::MAX << (128 - p as u32)) >> (128 - bits),
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// The `ip-allow` and `ip-deny` lists.
+#[derive(Debug, Default)]
+pub(crate) struct IpFilter {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpFilter {
+    pub fn new(allow: &[Cidr], deny: &[Cidr]) -> Self {
+        Self {
+            allow: allow.to_vec(),
+            deny: deny.to_vec(),
+        }
+    }
+
+    /// Whether a peer at `ip` may connect.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(ip)) && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+    }
+}
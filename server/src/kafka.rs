@@ -0,0 +1,340 @@
+//! A minimal Kafka producer, for publishing key events.
+//!
+//! It speaks just enough of the Kafka protocol to append records to one
+//! topic: Metadata (v1) to learn the topic's partitions and their leaders
+//! from a bootstrap broker, and Produce (v3, `acks=all`) with v2 record
+//! batches, uncompressed. Records are spread over partitions by their key,
+//! with the murmur2 hash the Java client uses, so the same key always lands
+//! on the same partition whichever producer wrote it. There is no SASL or
+//! TLS. Any error ends the producer; the caller connects a new one, which
+//! refreshes the metadata, and sends the records again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crc::{Crc, CRC_32_ISCSI};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::events::connect;
+
+static CRC32C: Crc<u32> = Crc::<u32>::new(&CRC_32_ISCSI);
+
+const PRODUCE: i16 = 0;
+const METADATA: i16 = 3;
+const CLIENT_ID: &str = "rustcache";
+// How long a broker may wait for the in-sync replicas before it fails a produce
+const PRODUCE_TIMEOUT: Duration = Duration::from_secs(10);
+// Longest response read, so a peer that is not a broker cannot make us allocate
+const MAX_RESPONSE: usize = 64 << 20;
+
+/// A record's key and value.
+pub(crate) type Record<'a> = (&'a [u8], &'a [u8]);
+
+/// Appends records to one topic.
+pub(crate) struct KafkaProducer {
+    topic: String,
+    /// The leader of each partition, by partition number.
+    leaders: Vec<i32>,
+    /// Broker addresses by node id.
+    brokers: HashMap<i32, String>,
+    /// Open connections by node id.
+    conns: HashMap<i32, BufReader<TcpStream>>,
+    correlation: i32,
+}
+
+impl KafkaProducer {
+    /// Looks up `topic` through the bootstrap broker at `addr`.
+    pub async fn connect(addr: &str, topic: &str) -> io::Result<Self> {
+        let mut conn = BufReader::new(connect(addr).await?);
+        let mut body = Vec::new();
+        put_i32(&mut body, 1);
+        put_str(&mut body, topic);
+        let response = request(&mut conn, METADATA, 1, 0, &body).await?;
+        let mut r = Decoder::new(&response);
+        let mut brokers = HashMap::new();
+        for _ in 0..r.count()? {
+            let node = r.i32()?;
+            let host = r.string()?;
+            let port = r.i32()?;
+            r.nullable_string()?;
+            brokers.insert(node, format!("{}:{}", host, port));
+        }
+        r.i32()?;
+        let mut leaders = BTreeMap::new();
+        for _ in 0..r.count()? {
+            let error = r.i16()?;
+            let name = r.string()?;
+            r.i8()?;
+            if error != 0 {
+                return Err(kafka_error(&format!("topic {}", name), error));
+            }
+            for _ in 0..r.count()? {
+                let error = r.i16()?;
+                let partition = r.i32()?;
+                let leader = r.i32()?;
+                for _ in 0..2 {
+                    for _ in 0..r.count()? {
+                        r.i32()?;
+                    }
+                }
+                if error != 0 {
+                    return Err(kafka_error(&format!("partition {}-{}", name, partition), error));
+                }
+                leaders.insert(partition, leader);
+            }
+        }
+        // Partitions are numbered from zero, so the map's values in order are by number
+        if leaders.is_empty() || leaders.keys().copied().ne(0..leaders.len() as i32) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("no partitions found for topic {}", topic)));
+        }
+        Ok(Self {
+            topic: topic.to_string(),
+            leaders: leaders.into_values().collect(),
+            brokers,
+            conns: HashMap::new(),
+            correlation: 1,
+        })
+    }
+
+    /// Appends `records`, as (key, value) pairs, and returns once every
+    /// partition leader has acknowledged them.
+    pub async fn send(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        let mut by_leader: BTreeMap<i32, BTreeMap<i32, Vec<Record>>> = BTreeMap::new();
+        for &(key, value) in records {
+            let partition = (murmur2(key) & 0x7fff_ffff) % self.leaders.len() as u32;
+            let leader = self.leaders[partition as usize];
+            by_leader.entry(leader).or_default().entry(partition as i32).or_default().push((key, value));
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+        for (leader, partitions) in by_leader {
+            let mut body = Vec::new();
+            put_i16(&mut body, -1);
+            put_i16(&mut body, -1);
+            put_i32(&mut body, PRODUCE_TIMEOUT.as_millis() as i32);
+            put_i32(&mut body, 1);
+            put_str(&mut body, &self.topic);
+            put_i32(&mut body, partitions.len() as i32);
+            for (partition, records) in &partitions {
+                put_i32(&mut body, *partition);
+                let batch = record_batch(records, now);
+                put_i32(&mut body, batch.len() as i32);
+                body.extend_from_slice(&batch);
+            }
+            let correlation = self.correlation;
+            self.correlation = self.correlation.wrapping_add(1);
+            let conn = self.conn(leader).await?;
+            let response = request(conn, PRODUCE, 3, correlation, &body).await?;
+            let mut r = Decoder::new(&response);
+            for _ in 0..r.count()? {
+                let name = r.string()?;
+                for _ in 0..r.count()? {
+                    let partition = r.i32()?;
+                    let error = r.i16()?;
+                    r.i64()?;
+                    r.i64()?;
+                    if error != 0 {
+                        return Err(kafka_error(&format!("producing to {}-{}", name, partition), error));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn conn(&mut self, node: i32) -> io::Result<&mut BufReader<TcpStream>> {
+        if !self.conns.contains_key(&node) {
+            let addr = self
+                .brokers
+                .get(&node)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no address for broker {}", node)))?;
+            let conn = BufReader::new(connect(addr).await?);
+            self.conns.insert(node, conn);
+        }
+        Ok(self.conns.get_mut(&node).expect("connected above"))
+    }
+}
+
+fn kafka_error(what: &str, code: i16) -> io::Error {
+    // The codes a misconfigured topic or a broker failover most often return
+    let name = match code {
+        3 => "unknown topic or partition",
+        5 => "leader not available",
+        6 => "not leader for partition",
+        7 => "request timed out",
+        19 => "not enough replicas",
+        20 => "not enough replicas after append",
+        29 => "topic authorization failed",
+        _ => "error",
+    };
+    io::Error::other(format!("{}: {} (Kafka error {})", what, name, code))
+}
+
+// Sends one request and reads its response, returning the body after the
+// correlation id
+async fn request(conn: &mut BufReader<TcpStream>, api_key: i16, version: i16, correlation: i32, body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut header = Vec::with_capacity(14 + CLIENT_ID.len());
+    put_i16(&mut header, api_key);
+    put_i16(&mut header, version);
+    put_i32(&mut header, correlation);
+    put_str(&mut header, CLIENT_ID);
+    let mut out = Vec::with_capacity(4 + header.len() + body.len());
+    put_i32(&mut out, (header.len() + body.len()) as i32);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(body);
+    conn.get_mut().write_all(&out).await?;
+    let len = conn.read_i32().await?;
+    if !(4..=MAX_RESPONSE as i32).contains(&len) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("bad Kafka response length {}", len)));
+    }
+    let mut response = vec![0; len as usize];
+    conn.read_exact(&mut response).await?;
+    let replied = i32::from_be_bytes(response[..4].try_into().expect("four bytes"));
+    if replied != correlation {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Kafka response out of order"));
+    }
+    response.drain(..4);
+    Ok(response)
+}
+
+// A v2 record batch holding `records`, all stamped `timestamp`
+fn record_batch(records: &[Record], timestamp: i64) -> Vec<u8> {
+    // From attributes on, the part the CRC covers
+    let mut tail = Vec::new();
+    put_i16(&mut tail, 0);
+    put_i32(&mut tail, records.len() as i32 - 1);
+    put_i64(&mut tail, timestamp);
+    put_i64(&mut tail, timestamp);
+    // No producer id, epoch or sequence: not idempotent
+    put_i64(&mut tail, -1);
+    put_i16(&mut tail, -1);
+    put_i32(&mut tail, -1);
+    put_i32(&mut tail, records.len() as i32);
+    for (offset, (key, value)) in records.iter().enumerate() {
+        let mut record = vec![0];
+        put_varint(&mut record, 0);
+        put_varint(&mut record, offset as i64);
+        put_varint(&mut record, key.len() as i64);
+        record.extend_from_slice(key);
+        put_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        put_varint(&mut record, 0);
+        put_varint(&mut tail, record.len() as i64);
+        tail.extend_from_slice(&record);
+    }
+    let mut batch = Vec::with_capacity(21 + tail.len());
+    put_i64(&mut batch, 0);
+    // Length of everything after this field
+    put_i32(&mut batch, (4 + 1 + 4 + tail.len()) as i32);
+    put_i32(&mut batch, -1);
+    batch.push(2);
+    batch.extend_from_slice(&CRC32C.checksum(&tail).to_be_bytes());
+    batch.extend_from_slice(&tail);
+    batch
+}
+
+/// Kafka's default partitioner hash: murmur2 with its seed, as the Java
+/// client computes it.
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut h = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().expect("four bytes"));
+        k = k.wrapping_mul(M);
+        k ^= k >> 24;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M) ^ k;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, &b) in rest.iter().enumerate().rev() {
+            h ^= (b as u32) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^ (h >> 15)
+}
+
+fn put_i16(out: &mut Vec<u8>, n: i16) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_i32(out: &mut Vec<u8>, n: i32) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_i64(out: &mut Vec<u8>, n: i64) {
+    out.extend_from_slice(&n.to_be_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_i16(out, s.len() as i16);
+    out.extend_from_slice(s.as_bytes());
+}
+
+// A zigzag varint, as record fields are written
+fn put_varint(out: &mut Vec<u8>, n: i64) {
+    let mut v = ((n << 1) ^ (n >> 63)) as u64;
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated Kafka response"));
+        }
+        let (head, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn i8(&mut self) -> io::Result<i8> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().expect("two bytes")))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().expect("four bytes")))
+    }
+
+    fn i64(&mut self) -> io::Result<i64> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().expect("eight bytes")))
+    }
+
+    // An array length; null arrays count as empty
+    fn count(&mut self) -> io::Result<usize> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    fn nullable_string(&mut self) -> io::Result<Option<String>> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        Ok(self.nullable_string()?.unwrap_or_default())
+    }
+}
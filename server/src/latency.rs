@@ -0,0 +1,175 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::resp::RespValue;
+
+/// Samples kept per event, as with Redis' latency monitor.
+const HISTORY_LEN: usize = 160;
+// Slow log entries abbreviate long commands, as Redis' do
+const SLOWLOG_MAX_ARGS: usize = 32;
+const SLOWLOG_MAX_ARG_LEN: usize = 128;
+
+/// Commands over the latency threshold, grouped into named events like Redis'
+/// LATENCY: `command` for ordinary commands and `fast-command` for ones
+/// flagged fast. Samples within the same second are merged into their maximum.
+pub(crate) struct LatencyMonitor {
+    events: Mutex<HashMap<&'static str, EventHistory>>,
+}
+
+#[derive(Default)]
+struct EventHistory {
+    /// (unix seconds, milliseconds), oldest first.
+    samples: VecDeque<(u64, u64)>,
+    max_ms: u64,
+}
+
+impl LatencyMonitor {
+    pub fn new() -> Self {
+        Self {
+            events: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<&'static str, EventHistory>> {
+        self.events.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn record(&self, event: &'static str, ms: u64) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut events = self.lock();
+        let history = events.entry(event).or_default();
+        history.max_ms = history.max_ms.max(ms);
+        match history.samples.back_mut() {
+            Some((at, worst)) if *at == now => *worst = (*worst).max(ms),
+            _ => {
+                if history.samples.len() == HISTORY_LEN {
+                    history.samples.pop_front();
+                }
+                history.samples.push_back((now, ms));
+            }
+        }
+    }
+
+    /// Every event as (name, time of the latest sample, its latency, the
+    /// highest latency seen), sorted by name.
+    pub fn latest(&self) -> Vec<(&'static str, u64, u64, u64)> {
+        let events = self.lock();
+        let mut latest: Vec<_> = events
+            .iter()
+            .filter_map(|(name, h)| h.samples.back().map(|(at, ms)| (*name, *at, *ms, h.max_ms)))
+            .collect();
+        latest.sort_by_key(|e| e.0);
+        latest
+    }
+
+    /// The samples of `event` as (unix seconds, milliseconds), oldest first.
+    pub fn history(&self, event: &str) -> Vec<(u64, u64)> {
+        self.lock().get(event).map(|h| h.samples.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// Clears the named events, or all of them when `names` is empty.
+    /// Returns how many had samples.
+    pub fn reset(&self, names: &[String]) -> usize {
+        let mut events = self.lock();
+        if names.is_empty() {
+            let cleared = events.len();
+            events.clear();
+            return cleared;
+        }
+        names.iter().filter(|n| events.remove(n.as_str()).is_some()).count()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct SlowLogEntry {
+    pub id: u64,
+    /// Unix time in seconds the command finished.
+    pub at: u64,
+    pub micros: u64,
+    /// The command name and its arguments, abbreviated.
+    pub args: Vec<String>,
+    pub client_addr: String,
+}
+
+/// Commands slower than `slowlog-log-slower-than`, newest first, for SLOWLOG.
+/// Bounded by `slowlog-max-len`.
+pub(crate) struct SlowLog {
+    entries: Mutex<VecDeque<SlowLogEntry>>,
+    next_id: AtomicU64,
+    max_len: AtomicUsize,
+}
+
+impl SlowLog {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            entries: Mutex::new(VecDeque::new()),
+            next_id: AtomicU64::new(0),
+            max_len: AtomicUsize::new(max_len),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<SlowLogEntry>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn set_max_len(&self, max_len: usize) {
+        self.max_len.store(max_len, Ordering::Relaxed);
+        self.lock().truncate(max_len);
+    }
+
+    /// A command as an entry shows it, long arguments and argument lists
+    /// cut short.
+    pub fn logged(cmd: &str, args: &[RespValue]) -> Vec<String> {
+        let mut logged = vec![cmd.to_string()];
+        for (i, arg) in args.iter().enumerate() {
+            if i + 2 == SLOWLOG_MAX_ARGS && args.len() > i + 1 {
+                logged.push(format!("... ({} more arguments)", args.len() - i));
+                break;
+            }
+            let bytes = match arg {
+                RespValue::BulkString(Some(b)) => b.as_slice(),
+                RespValue::SimpleString(s) => s.as_bytes(),
+                _ => &[],
+            };
+            logged.push(if bytes.len() > SLOWLOG_MAX_ARG_LEN {
+                format!(
+                    "{}... ({} more bytes)",
+                    String::from_utf8_lossy(&bytes[..SLOWLOG_MAX_ARG_LEN]),
+                    bytes.len() - SLOWLOG_MAX_ARG_LEN
+                )
+            } else {
+                String::from_utf8_lossy(bytes).into_owned()
+            });
+        }
+        logged
+    }
+
+    /// Records a command, `logged` as [`logged`](Self::logged) made it.
+    pub fn record(&self, logged: Vec<String>, elapsed: Duration, client_addr: String) {
+        let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut entries = self.lock();
+        entries.push_front(SlowLogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            at,
+            micros: elapsed.as_micros() as u64,
+            args: logged,
+            client_addr,
+        });
+        entries.truncate(self.max_len.load(Ordering::Relaxed));
+    }
+
+    /// Up to `count` entries, newest first.
+    pub fn recent(&self, count: usize) -> Vec<SlowLogEntry> {
+        self.lock().iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+}
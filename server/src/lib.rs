@@ -0,0 +1,73 @@
+//! Library surface of the RustCache server, shared by the binary, tests and fuzz targets.
+//!
+//! Embed a server with [`server::run_server`]; it binds the configured address and
+//! returns a [`server::ServerHandle`] for its bound address, store and shutdown.
+
+pub mod resp;
+pub mod db;
+pub mod engine;
+pub mod server;
+pub mod config;
+pub mod error;
+pub mod aof;
+pub mod clock;
+pub mod compression;
+pub mod offline;
+mod stats;
+mod acl;
+mod allocator;
+mod audit;
+mod backing;
+mod client;
+mod cluster;
+mod namespace;
+mod overflow;
+mod commands;
+mod cores;
+mod defrag;
+mod diagnostics;
+mod events;
+mod info;
+mod ipfilter;
+mod io_pool;
+mod glob;
+mod gossip;
+mod health;
+mod hotkeys;
+mod kafka;
+mod latency;
+mod mirror;
+mod plugins;
+mod pool;
+mod proxy;
+mod pubsub;
+mod raft;
+mod crdt;
+mod crypto;
+mod rdb;
+mod recording;
+mod shadow;
+mod replica;
+mod replicas;
+mod snapshot;
+mod sql;
+mod tls;
+mod warmup;
+mod webhook;
+mod write_behind;
+mod websocket;
+
+pub use crate::allocator::MemoryAccounting;
+pub use crate::backing::{BackingStore, Loader};
+pub use crate::config::{ReloadReport, ServerConfig};
+pub use crate::error::CommandError;
+pub use crate::events::{EventFormat, EventSink};
+pub use crate::ipfilter::Cidr;
+pub use crate::mirror::MirrorOnFull;
+pub use crate::pubsub::SlowSubscriber;
+pub use crate::shadow::ShadowCommands;
+pub use crate::webhook::Webhook;
+pub use crate::rdb::{check_rdb, RdbCheck};
+pub use crate::server::{run_server, run_server_with_clock, ServerHandle};
+pub use crate::snapshot::{check_snapshot, SnapshotCheck};
+pub use crate::sql::{SqlKind, SqlTarget};
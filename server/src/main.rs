@@ -1,85 +1,112 @@
-use tokio::io::{self, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io;
 use tokio::signal;
 
-mod resp;
-mod db;
-mod commands;
 mod banner;
+mod memtest;
 
-use crate::resp::read_resp;
-use crate::db::{Database, start_expiry_reaper};
-use crate::commands::process_command;
+use server::aof::AofLimit;
+use server::{run_server, ServerConfig};
 use crate::banner::build_banner;
 use chrono::Local;
 
-async fn handle_client(stream: TcpStream, db: Database) -> io::Result<()> {
-    let (reader_half, mut writer_half) = stream.into_split();
-    let mut reader = BufReader::new(reader_half);
-    loop {
-        match read_resp(&mut reader).await {
-            Ok(frame) => {
-                let response = process_command(&db, frame);
-                let mut buf = Vec::with_capacity(128);
-                response.encode(&mut buf);
-                if let Err(e) = writer_half.write_all(&buf).await {
-                    eprintln!("write error: {}", e);
-                    break;
-                }
-            }
-            Err(e) => {
-                if e.kind() != io::ErrorKind::UnexpectedEof {
-                    eprintln!("connection error: {}", e);
-                }
-                break;
-            }
-        }
-    }
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> io::Result<()> {
     let _ = dotenvy::dotenv();
-    let addr = match std::env::var("ADDR").ok() {
-        Some(a) => a,
-        None => {
-            let port: u16 = std::env::var("PORT")
-                .ok()
-                .and_then(|s| s.parse::<u16>().ok())
-                .unwrap_or(9973);
-            format!("127.0.0.1:{}", port)
+    let Args { config_file, aof_load_until, test_memory } = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: server [--aof-load-until-timestamp <unix secs> | --aof-load-until-offset <bytes>] [config file]");
+            eprintln!("       server --test-memory <megabytes>");
+            std::process::exit(1);
+        }
+    };
+    if let Some(megabytes) = test_memory {
+        std::process::exit(if memtest::run(megabytes) { 0 } else { 1 });
+    }
+    let mut config = match ServerConfig::load(config_file) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Failed to load configuration: {}", e);
+            std::process::exit(1);
         }
     };
-    let listener = TcpListener::bind(&addr).await?;
-    // Determine the bound address to display the correct port
-    let local_addr = listener.local_addr()?;
-    println!("{}", build_banner(local_addr));
+    config.aof_load_until = aof_load_until;
+    let handle = match run_server(config).await {
+        Ok(h) => h,
+        Err(e) => {
+            eprintln!("Failed to start server: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("{}", build_banner(handle.local_addr()));
     let now = Local::now();
     let ts = now.format("%d %b %Y %H:%M:%S%.3f");
     println!("{}:M {} * Server initialized", std::process::id(), ts);
 
-    let db = Database::new();
-    start_expiry_reaper(db.clone()).await;
-
-    loop {
-        tokio::select! {
-            res = listener.accept() => {
-                let (socket, peer) = res?;
-                let db_clone = db.clone();
-                println!("connection from {}", peer);
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, db_clone).await {
-                        eprintln!("client error: {}", e);
+    #[cfg(unix)]
+    {
+        let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())?;
+        let mut usr1 = signal::unix::signal(signal::unix::SignalKind::user_defined1())?;
+        loop {
+            tokio::select! {
+                _ = signal::ctrl_c() => break,
+                _ = hangup.recv() => {
+                    if let Err(e) = handle.reload_config() {
+                        eprintln!("Config reload failed: {}", e);
                     }
-                });
-            }
-            _ = signal::ctrl_c() => {
-                println!("Shutting down on Ctrl+C");
-                break;
+                }
+                _ = usr1.recv() => {
+                    if let Err(e) = handle.dump_diagnostics() {
+                        eprintln!("Writing diagnostics failed: {}", e);
+                    }
+                }
             }
         }
     }
-
+    #[cfg(not(unix))]
+    signal::ctrl_c().await?;
+    println!("Shutting down on Ctrl+C");
+    handle.shutdown().await;
     Ok(())
 }
+
+struct Args {
+    config_file: Option<std::path::PathBuf>,
+    /// Where to stop replaying the AOF, for a point-in-time recovery.
+    aof_load_until: Option<AofLimit>,
+    /// Megabytes to test instead of starting the server.
+    test_memory: Option<usize>,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut config_file = None;
+    let mut limit = None;
+    let mut test_memory = None;
+    while let Some(arg) = args.next() {
+        let make: fn(u64) -> AofLimit = match arg.as_str() {
+            "--test-memory" => {
+                let value = args.next().ok_or("--test-memory needs a size in megabytes")?;
+                match value.parse::<usize>() {
+                    Ok(mb) if mb > 0 => test_memory = Some(mb),
+                    _ => return Err(format!("--test-memory needs a number of megabytes, not {}", value)),
+                }
+                continue;
+            }
+            "--aof-load-until-timestamp" => AofLimit::Timestamp,
+            "--aof-load-until-offset" => AofLimit::Offset,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if config_file.is_none() => {
+                config_file = Some(std::path::PathBuf::from(arg));
+                continue;
+            }
+            _ => return Err(format!("unexpected argument {}", arg)),
+        };
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        let value = value.parse().map_err(|_| format!("{} needs a number, not {}", arg, value))?;
+        if limit.replace(make(value)).is_some() {
+            return Err("only one --aof-load-until option may be given".into());
+        }
+    }
+    Ok(Args { config_file, aof_load_until: limit, test_memory })
+}
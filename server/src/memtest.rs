@@ -0,0 +1,73 @@
+use std::io::Write;
+use std::time::Instant;
+
+const WORDS_PER_MB: usize = 1024 * 1024 / 8;
+
+// The word a pattern puts at `index`, so it can be rebuilt when reading back.
+type Pattern = fn(usize) -> u64;
+
+// Each pattern catches something the others can miss: the address pattern
+// aliased addresses, the solid and alternating ones stuck and coupled bits,
+// the random one anything data-dependent.
+const PATTERNS: &[(&str, Pattern)] = &[
+    ("address", |i| i as u64),
+    ("zeros", |_| 0),
+    ("ones", |_| u64::MAX),
+    ("checkerboard", |i| if i % 2 == 0 { 0xAAAA_AAAA_AAAA_AAAA } else { 0x5555_5555_5555_5555 }),
+    ("inverse checkerboard", |i| if i % 2 == 0 { 0x5555_5555_5555_5555 } else { 0xAAAA_AAAA_AAAA_AAAA }),
+    ("random", |i| {
+        // splitmix64 of the index: random-looking, yet recomputable
+        let mut z = (i as u64).wrapping_add(1).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }),
+];
+
+/// `--test-memory <MB>`: fills that much memory with each pattern in turn and
+/// reads it back, to find bad RAM before a host goes into production. Returns
+/// whether every word read back as written.
+pub fn run(megabytes: usize) -> bool {
+    let words = megabytes.saturating_mul(WORDS_PER_MB);
+    let mut memory: Vec<u64> = Vec::new();
+    if memory.try_reserve_exact(words).is_err() {
+        eprintln!("Could not allocate {} MB to test.", megabytes);
+        return false;
+    }
+    memory.resize(words, 0);
+    println!("Testing {} MB of memory with {} patterns.", megabytes, PATTERNS.len());
+    let started = Instant::now();
+    let mut bad = 0usize;
+    for (name, pattern) in PATTERNS {
+        print!("  {:<22}", name);
+        let _ = std::io::stdout().flush();
+        let ptr = memory.as_mut_ptr();
+        // Volatile so the compiler cannot keep the pattern in registers and
+        // skip the round trip through RAM.
+        for i in 0..words {
+            unsafe { ptr.add(i).write_volatile(pattern(i)) };
+        }
+        let mut mismatches = 0usize;
+        for i in 0..words {
+            let got = unsafe { ptr.add(i).read_volatile() };
+            let want = pattern(i);
+            if got != want {
+                if mismatches == 0 {
+                    print!("\n    word {} (byte offset {:#x}) read {:#018x}, wrote {:#018x}", i, i * 8, got, want);
+                }
+                mismatches += 1;
+            }
+        }
+        match mismatches {
+            0 => println!("ok"),
+            n => println!("\n    {} bad words", n),
+        }
+        bad += mismatches;
+    }
+    if bad == 0 {
+        println!("Your memory passed the test in {:.1?}.", started.elapsed());
+    } else {
+        println!("{} words read back wrong: this host's memory is unreliable.", bad);
+    }
+    bad == 0
+}
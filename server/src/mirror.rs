@@ -0,0 +1,231 @@
+//! Dual-write forwarding, for moving traffic to a new instance.
+//!
+//! With `mirror-to` set, every write is applied here as usual and then
+//! queued for the target, another RESP server (Redis or RustCache), which a
+//! background task feeds in order. Writes are logged the way the AOF logs
+//! them, so relative expiries arrive as absolute deadlines. The queue holds
+//! at most `mirror-queue-size` commands; while the target is slow or
+//! unreachable it fills, and `mirror-on-full` decides what gives: `drop`
+//! keeps serving writes and discards what does not fit (the target then
+//! needs a fresh copy), `refuse` fails writes until the queue drains, so
+//! the two never diverge. A command whose reply was lost with the
+//! connection is sent again, so delivery is at least once.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::aof::{logged_write, namespaced_flush};
+use crate::commands::{flags, CommandSpec, Context, Middleware};
+use crate::error::CommandError;
+use crate::resp::{read_resp, RespValue};
+use crate::stats::Stats;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Commands sent to the target before waiting for their replies
+const BATCH: usize = 128;
+
+/// What happens to writes once the mirror queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorOnFull {
+    /// Serve the write and leave it out of the mirror.
+    Drop,
+    /// Fail the write until the target catches up.
+    Refuse,
+}
+
+impl MirrorOnFull {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "drop" => Some(Self::Drop),
+            "refuse" => Some(Self::Refuse),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Drop => "drop",
+            Self::Refuse => "refuse",
+        }
+    }
+}
+
+/// Writes waiting for the target, and how forwarding is going, as INFO
+/// replication reports it.
+#[derive(Default)]
+pub(crate) struct MirrorQueue {
+    queue: Mutex<VecDeque<Queued>>,
+    ready: Notify,
+    /// Zero until forwarding starts.
+    capacity: AtomicUsize,
+    pub link_up: AtomicBool,
+    /// Commands the target has replied to.
+    pub forwarded: AtomicU64,
+    /// Writes left out because the queue was full.
+    pub dropped: AtomicU64,
+    /// Writes failed because the queue was full.
+    pub refused: AtomicU64,
+    /// Forwarded commands the target answered with an error.
+    pub errors: AtomicU64,
+}
+
+struct Queued {
+    db: usize,
+    args: Vec<Vec<u8>>,
+    at: Instant,
+}
+
+impl MirrorQueue {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Queued>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity.load(Ordering::Relaxed)
+    }
+
+    /// How long the oldest waiting write has waited.
+    pub fn lag(&self) -> Duration {
+        self.lock().front().map(|q| q.at.elapsed()).unwrap_or_default()
+    }
+
+    // Queues a write that ran in database `db`, or counts it dropped
+    fn push(&self, db: usize, args: Vec<Vec<u8>>) {
+        let mut queue = self.lock();
+        if queue.len() >= self.capacity.load(Ordering::Relaxed) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push_back(Queued {
+            db,
+            args,
+            at: Instant::now(),
+        });
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    // Waits for writes and returns up to a batch of them, still queued
+    async fn peek(&self, batch: &mut Vec<(usize, Vec<Vec<u8>>)>) {
+        loop {
+            let notified = self.ready.notified();
+            {
+                let queue = self.lock();
+                if !queue.is_empty() {
+                    batch.extend(queue.iter().take(BATCH).map(|q| (q.db, q.args.clone())));
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    // Removes the first `n` writes, which the target has answered
+    fn pop(&self, n: usize) {
+        self.lock().drain(..n);
+        self.forwarded.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+/// Queues every successful write for the mirror target, and refuses writes
+/// while the queue is full under `mirror-on-full refuse`.
+pub(crate) struct Mirroring;
+
+impl Middleware for Mirroring {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        let mirror = &ctx.db.stats.mirror;
+        if spec.has(flags::WRITE) && ctx.settings.mirror_on_full() == MirrorOnFull::Refuse && mirror.is_full() {
+            mirror.refused.fetch_add(1, Ordering::Relaxed);
+            return Some(CommandError::generic("mirror queue is full; writes are refused until it drains").into());
+        }
+        if let Some(args) = namespaced_flush(ctx, cmd) {
+            mirror.push(ctx.client.db, args);
+        }
+        None
+    }
+
+    fn reads_args(&self, _ctx: &Context<'_>) -> bool {
+        true
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        if let Some(args) = logged_write(ctx, cmd, spec, args, reply) {
+            ctx.db.stats.mirror.push(ctx.client.db, args);
+        }
+    }
+}
+
+/// Forwards queued writes to `target` (`host:port`) until aborted, holding
+/// up to `capacity` of them.
+pub(crate) fn start_mirror(target: String, stats: Arc<Stats>, capacity: usize) -> JoinHandle<()> {
+    stats.mirror.capacity.store(capacity, Ordering::Relaxed);
+    tokio::spawn(async move {
+        let mirror = &stats.mirror;
+        loop {
+            if let Err(e) = forward(&target, mirror).await {
+                eprintln!("Mirroring to {} failed: {}", target, e);
+            }
+            mirror.link_up.store(false, Ordering::Relaxed);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    })
+}
+
+// Sends queued writes over one connection until it fails
+async fn forward(target: &str, mirror: &MirrorQueue) -> io::Result<()> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+    let mut conn = BufReader::new(stream);
+    mirror.link_up.store(true, Ordering::Relaxed);
+    let mut selected = 0;
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        mirror.peek(&mut batch).await;
+        let mut buf = Vec::new();
+        let mut selects = 0;
+        for (db, args) in &batch {
+            if *db != selected {
+                encode(&[b"SELECT".to_vec(), db.to_string().into_bytes()], &mut buf);
+                selected = *db;
+                selects += 1;
+            }
+            encode(args, &mut buf);
+        }
+        conn.get_mut().write_all(&buf).await?;
+        for _ in 0..batch.len() + selects {
+            if let RespValue::Error(e) = read_resp(&mut conn).await? {
+                mirror.errors.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Mirror target {} refused a write: {}", target, e);
+            }
+        }
+        mirror.pop(batch.len());
+    }
+}
+
+fn encode(args: &[Vec<u8>], out: &mut Vec<u8>) {
+    let frame = args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect();
+    RespValue::Array(Some(frame)).encode(out);
+}
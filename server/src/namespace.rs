@@ -0,0 +1,93 @@
+use crate::commands::{flags, CommandSpec};
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+fn prefixed(ns: &str, arg: &RespValue) -> RespValue {
+    let key: &[u8] = match arg {
+        RespValue::BulkString(Some(b)) => b,
+        RespValue::SimpleString(s) => s.as_bytes(),
+        other => return other.clone(),
+    };
+    let mut out = Vec::with_capacity(ns.len() + key.len());
+    out.extend_from_slice(ns.as_bytes());
+    out.extend_from_slice(key);
+    RespValue::BulkString(Some(out))
+}
+
+fn escape_glob(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Rewrites the arguments of `cmd` so every key lives under `ns`.
+pub(crate) fn apply(
+    user: &str,
+    ns: &str,
+    cmd: &str,
+    spec: &CommandSpec,
+    args: &[RespValue],
+) -> Result<Vec<RespValue>, RespValue> {
+    // Tenants get no say over the server itself, nor channels, which every
+    // tenant shares
+    if spec.has(flags::ADMIN) || spec.has(flags::PUBSUB) {
+        return Err(CommandError::NoPerm {
+            user: user.to_string(),
+            command: cmd.to_string(),
+        }
+        .into());
+    }
+    let keys = spec.keys;
+    let mut out = args.to_vec();
+    // SCAN has no key arguments, but its MATCH pattern is scoped and the keys
+    // it returns are stripped
+    if cmd == "scan" {
+        let scope = escape_glob(ns);
+        let mut matched = false;
+        let mut i = 1;
+        while i + 1 < out.len() {
+            let is_match = matches!(&out[i], RespValue::BulkString(Some(b)) if b.eq_ignore_ascii_case(b"match"));
+            if is_match {
+                out[i + 1] = prefixed(&scope, &out[i + 1]);
+                matched = true;
+            }
+            i += 2;
+        }
+        if !matched {
+            out.push(RespValue::BulkString(Some(b"MATCH".to_vec())));
+            out.push(RespValue::BulkString(Some(format!("{}*", scope).into_bytes())));
+        }
+        return Ok(out);
+    }
+    for i in keys.positions(out.len()) {
+        out[i] = prefixed(ns, &out[i]);
+    }
+    Ok(out)
+}
+
+/// Removes `ns` from keys in replies that return key names.
+pub(crate) fn strip_reply(ns: &str, cmd: &str, reply: RespValue) -> RespValue {
+    if cmd != "scan" {
+        return reply;
+    }
+    match reply {
+        RespValue::Array(Some(mut parts)) if parts.len() == 2 => {
+            if let RespValue::Array(Some(keys)) = &mut parts[1] {
+                for key in keys.iter_mut() {
+                    if let RespValue::BulkString(Some(b)) = key {
+                        if b.starts_with(ns.as_bytes()) {
+                            b.drain(..ns.len());
+                        }
+                    }
+                }
+            }
+            RespValue::Array(Some(parts))
+        }
+        other => other,
+    }
+}
@@ -0,0 +1,70 @@
+//! Reading persistence files without a running server, for the command line
+//! tools.
+
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::aof::replay_into;
+use crate::commands::Registry;
+use crate::compression::open_decoded;
+use crate::config::{ServerConfig, Settings};
+use crate::db::Database;
+use crate::rdb::load_rdb;
+use crate::snapshot::load_snapshot;
+
+/// The kinds of file a server persists to or imports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Snapshot,
+    Rdb,
+    Aof,
+}
+
+impl FileKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Snapshot => "RustCache snapshot",
+            Self::Rdb => "Redis RDB file",
+            Self::Aof => "append-only file",
+        }
+    }
+}
+
+/// Tells what kind of file `path` is from its first bytes, compressed or not.
+pub fn detect(path: &Path) -> io::Result<FileKind> {
+    let mut start = Vec::new();
+    open_decoded(path)?.take(6).read_to_end(&mut start)?;
+    if start.starts_with(b"RCSNAP") {
+        Ok(FileKind::Snapshot)
+    } else if start.starts_with(b"REDIS") {
+        Ok(FileKind::Rdb)
+    } else if start.first().is_some_and(|b| *b == b'*' || *b == b'#') {
+        Ok(FileKind::Aof)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "not a snapshot, RDB file or AOF"))
+    }
+}
+
+/// Loads the file at `path` into `databases` fresh databases, as a server
+/// starting from it would, but leaving the file as it is. Keys that have
+/// expired are not loaded.
+pub fn load(path: &Path, databases: usize) -> io::Result<Vec<Database>> {
+    let dbs: Vec<Database> = (0..databases).map(|_| Database::new()).collect();
+    match detect(path)? {
+        FileKind::Snapshot => {
+            load_snapshot(&dbs, path)?;
+        }
+        FileKind::Rdb => {
+            load_rdb(&dbs, path)?;
+        }
+        FileKind::Aof => {
+            let config = ServerConfig {
+                databases,
+                ..ServerConfig::default()
+            };
+            let settings = Settings::new(config)?;
+            replay_into(&dbs, &settings, &Registry::with_builtins(), path, None)?;
+        }
+    }
+    Ok(dbs)
+}
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Rewrite the log once this much of it is dead and dead records outweigh live ones
+const COMPACT_MIN_DEAD: u64 = 4 * 1024 * 1024;
+
+/// A spilled value and the checksum it was stored with.
+pub(crate) type Spilled = (Vec<u8>, Option<u32>);
+
+/// Disk tier for values spilled out of a database over its memory budget.
+/// Values live in an append-only scratch log (`[key len][value len][key][value]`
+/// records) with an in-memory index; the file is truncated on startup, since
+/// the cache itself is not persistent. A value's checksum, when it has one,
+/// stays in the index and comes back with it, so damage on disk is caught
+/// when the value is next read.
+pub(crate) struct ColdTier {
+    log: Mutex<ColdLog>,
+    keys: AtomicUsize,
+    // While a snapshot runs nothing moves between memory and disk
+    frozen: AtomicBool,
+}
+
+struct ColdLog {
+    path: PathBuf,
+    file: File,
+    end: u64,
+    live: u64,
+    dead: u64,
+    // key -> (offset of the value, value length, value checksum)
+    index: HashMap<String, (u64, u64, Option<u32>)>,
+}
+
+impl ColdTier {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self {
+            log: Mutex::new(ColdLog {
+                path: path.to_path_buf(),
+                file,
+                end: 0,
+                live: 0,
+                dead: 0,
+                index: HashMap::new(),
+            }),
+            keys: AtomicUsize::new(0),
+            frozen: AtomicBool::new(false),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ColdLog> {
+        self.log.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of values held on disk.
+    pub fn bytes(&self) -> u64 {
+        self.lock().live
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.lock().index.keys().cloned().collect()
+    }
+
+    /// Stops (or resumes) spilling and faulting. Taking the lock first waits
+    /// out a move already in progress.
+    pub fn set_frozen(&self, frozen: bool) {
+        let _log = self.lock();
+        self.frozen.store(frozen, Ordering::SeqCst);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    /// Calls `f` with the spilled value of `key` and its checksum (if any)
+    /// while holding the log lock, so nothing moves between memory and disk
+    /// meanwhile.
+    pub fn locate<R>(&self, key: &str, f: impl FnOnce(Option<Spilled>) -> R) -> R {
+        let mut log = self.lock();
+        let value = match log.read(key) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("overflow read of '{}' failed: {}", key, e);
+                None
+            }
+        };
+        f(value)
+    }
+
+    /// Moves the value `take` removes from memory onto disk. Both happen under
+    /// the log lock, so a reader missing in memory always finds it here.
+    /// Returns false without calling `take` while frozen.
+    pub fn spill(&self, key: &str, take: impl FnOnce() -> Option<Spilled>) -> io::Result<bool> {
+        let mut log = self.lock();
+        if self.is_frozen() {
+            return Ok(false);
+        }
+        let (value, checksum) = match take() {
+            Some(spilled) => spilled,
+            None => return Ok(false),
+        };
+        log.append(key, &value, checksum)?;
+        self.keys.store(log.index.len(), Ordering::Relaxed);
+        Ok(true)
+    }
+
+    /// Moves `key` back into memory through `restore`, if it is on disk.
+    /// While frozen the value is copied and stays on disk too.
+    pub fn fault_in(&self, key: &str, restore: impl FnOnce(Spilled)) {
+        let mut log = self.lock();
+        let taken = if self.is_frozen() { log.read(key) } else { log.take(key) };
+        match taken {
+            Ok(Some(spilled)) => restore(spilled),
+            Ok(None) => {}
+            Err(e) => eprintln!("overflow read of '{}' failed, dropping it: {}", key, e),
+        }
+        self.keys.store(log.index.len(), Ordering::Relaxed);
+    }
+
+    /// Forgets `key`; returns whether it was on disk.
+    pub fn discard(&self, key: &str) -> bool {
+        let mut log = self.lock();
+        let found = log.forget(key);
+        self.keys.store(log.index.len(), Ordering::Relaxed);
+        found
+    }
+
+    pub fn clear(&self) {
+        let mut log = self.lock();
+        log.index.clear();
+        log.live = 0;
+        log.dead = 0;
+        log.end = 0;
+        if let Err(e) = log.file.set_len(0) {
+            eprintln!("overflow truncate of {} failed: {}", log.path.display(), e);
+        }
+        self.keys.store(0, Ordering::Relaxed);
+    }
+}
+
+impl ColdLog {
+    fn append(&mut self, key: &str, value: &[u8], checksum: Option<u32>) -> io::Result<()> {
+        // First, as forgetting may compact the file and move `end`
+        self.forget(key);
+        let mut record = Vec::with_capacity(16 + key.len() + value.len());
+        record.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        record.extend_from_slice(key.as_bytes());
+        record.extend_from_slice(value);
+        self.file.seek(SeekFrom::Start(self.end))?;
+        self.file.write_all(&record)?;
+        let offset = self.end + 16 + key.len() as u64;
+        self.end += record.len() as u64;
+        self.index.insert(key.to_string(), (offset, value.len() as u64, checksum));
+        self.live += value.len() as u64;
+        Ok(())
+    }
+
+    fn read(&mut self, key: &str) -> io::Result<Option<Spilled>> {
+        let (offset, len, checksum) = match self.index.get(key) {
+            Some(&loc) => loc,
+            None => return Ok(None),
+        };
+        let mut value = vec![0u8; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut value)?;
+        Ok(Some((value, checksum)))
+    }
+
+    fn take(&mut self, key: &str) -> io::Result<Option<Spilled>> {
+        let read = self.read(key);
+        // After reading, as forgetting may compact the file
+        self.forget(key);
+        read
+    }
+
+    fn forget(&mut self, key: &str) -> bool {
+        match self.index.remove(key) {
+            Some((_, len, _)) => {
+                self.live -= len;
+                self.dead += len;
+                if self.dead >= COMPACT_MIN_DEAD && self.dead > self.live {
+                    if let Err(e) = self.compact() {
+                        eprintln!("overflow compaction of {} failed: {}", self.path.display(), e);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Copies live values into a fresh file and swaps it in
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("compact");
+        let mut tmp = ColdLog {
+            path: self.path.clone(),
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&tmp_path)?,
+            end: 0,
+            live: 0,
+            dead: 0,
+            index: HashMap::with_capacity(self.index.len()),
+        };
+        let keys: Vec<String> = self.index.keys().cloned().collect();
+        for key in keys {
+            let (offset, len, checksum) = self.index[&key];
+            let mut value = vec![0u8; len as usize];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut value)?;
+            tmp.append(&key, &value, checksum)?;
+        }
+        std::fs::rename(&tmp_path, &self.path)?;
+        *self = tmp;
+        Ok(())
+    }
+}
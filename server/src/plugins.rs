@@ -0,0 +1,323 @@
+use std::ffi::CStr;
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use libloading::Library;
+use rustcache_plugin_api::{
+    AbiVersionFn, CommandFn, CommandInfo, HostApi, InitFn, Slice, ABI_VERSION, ABI_VERSION_SYMBOL, FLAG_ADMIN,
+    FLAG_DENYOOM, FLAG_FAST, FLAG_READONLY, FLAG_WRITE, INIT_SYMBOL,
+};
+
+use crate::commands::{flags, CommandSpec, Context, Registry};
+use crate::db::{Database, EvictionPolicy};
+use crate::error::CommandError;
+use crate::resp::RespValue;
+
+static HOST_API: HostApi = HostApi {
+    abi_version: ABI_VERSION,
+    register_command,
+    get: host_get,
+    set: host_set,
+    del: host_del,
+    reply_simple,
+    reply_error,
+    reply_integer,
+    reply_bulk,
+    reply_null,
+    reply_array,
+    log: host_log,
+};
+
+/// Loads each plugin library and lets it register its commands. Any failure
+/// aborts startup rather than running with a partially loaded plugin set.
+pub(crate) fn load_plugins(registry: &mut Registry, paths: &[PathBuf]) -> io::Result<()> {
+    for path in paths {
+        match load_plugin(registry, path) {
+            Ok(commands) => println!("Loaded plugin {} ({})", path.display(), commands.join(", ")),
+            Err(e) => return Err(io::Error::other(format!("plugin {}: {}", path.display(), e))),
+        }
+    }
+    Ok(())
+}
+
+struct Registrar<'a> {
+    registry: &'a mut Registry,
+    library: Arc<Library>,
+    added: Vec<String>,
+    error: Option<String>,
+}
+
+fn load_plugin(registry: &mut Registry, path: &Path) -> Result<Vec<String>, String> {
+    // Safety: loading runs the library's initializers; plugins are trusted by configuration.
+    let library = Arc::new(unsafe { Library::new(path) }.map_err(|e| e.to_string())?);
+    let version = unsafe { library.get::<AbiVersionFn>(ABI_VERSION_SYMBOL) }.map_err(|e| e.to_string())?;
+    let version = unsafe { version() };
+    if version != ABI_VERSION {
+        return Err(format!("built for plugin ABI {}, server provides {}", version, ABI_VERSION));
+    }
+    let init: InitFn = *unsafe { library.get::<InitFn>(INIT_SYMBOL) }.map_err(|e| e.to_string())?;
+
+    let mut registrar = Registrar {
+        registry,
+        library: library.clone(),
+        added: Vec::new(),
+        error: None,
+    };
+    let rc = unsafe { init(&HOST_API, &mut registrar as *mut Registrar as *mut c_void) };
+    if rc != 0 {
+        return Err(registrar.error.unwrap_or_else(|| format!("init returned {}", rc)));
+    }
+    Ok(registrar.added)
+}
+
+const PLUGIN_FLAGS: [(u32, &str); 5] = [
+    (FLAG_WRITE, flags::WRITE),
+    (FLAG_READONLY, flags::READONLY),
+    (FLAG_DENYOOM, flags::DENYOOM),
+    (FLAG_ADMIN, flags::ADMIN),
+    (FLAG_FAST, flags::FAST),
+];
+
+// The spec a plugin declared for a command, checked the way the built-in
+// table would be
+fn plugin_spec(info: &CommandInfo) -> Result<CommandSpec, String> {
+    if info.arity == 0 {
+        return Err("arity must not be 0".to_string());
+    }
+    let known = PLUGIN_FLAGS.iter().fold(0, |all, (bit, _)| all | bit);
+    if info.flags & !known != 0 {
+        return Err(format!("unknown flags {:#x}", info.flags & !known));
+    }
+    if info.flags & FLAG_WRITE != 0 && info.flags & FLAG_READONLY != 0 {
+        return Err("a command cannot be both write and readonly".to_string());
+    }
+    let names: Vec<&'static str> =
+        PLUGIN_FLAGS.iter().filter(|(bit, _)| info.flags & bit != 0).map(|(_, name)| *name).collect();
+    // Specs hold static flag lists; a plugin's are made once, at startup, and
+    // live as long as the server
+    let spec = CommandSpec::new(info.arity, Box::leak(names.into_boxed_slice()));
+    match (info.first_key, info.key_step) {
+        (0, _) => Ok(spec),
+        (first, step) if first > 0 && step > 0 => {
+            if info.last_key >= 0 && info.last_key < first {
+                return Err("last_key comes before first_key".to_string());
+            }
+            Ok(spec.keys(first as usize, info.last_key as isize, step as usize))
+        }
+        _ => Err("key positions must be positive".to_string()),
+    }
+}
+
+extern "C" fn register_command(registrar: *mut c_void, info: *const CommandInfo, func: CommandFn) -> c_int {
+    let registrar = unsafe { &mut *(registrar as *mut Registrar) };
+    let Some(info) = (unsafe { info.as_ref() }) else {
+        return -1;
+    };
+    if info.name.is_null() {
+        return -1;
+    }
+    let name = match unsafe { CStr::from_ptr(info.name) }.to_str() {
+        Ok(n) if !n.is_empty() && !n.contains(|c: char| c.is_whitespace()) => n.to_ascii_lowercase(),
+        _ => {
+            registrar.error = Some("invalid command name".to_string());
+            return -1;
+        }
+    };
+    if registrar.registry.contains(&name) {
+        registrar.error = Some(format!("command '{}' is already registered", name));
+        return -1;
+    }
+    let spec = match plugin_spec(info) {
+        Ok(spec) => spec,
+        Err(e) => {
+            registrar.error = Some(format!("command '{}': {}", name, e));
+            return -1;
+        }
+    };
+    let library = registrar.library.clone();
+    registrar.registry.register(&name, spec, move |ctx, args| {
+        // The handler owns a reference so the code behind `func` stays mapped
+        let _loaded = &library;
+        call_plugin(func, spec.has(flags::WRITE), ctx, args)
+    });
+    registrar.added.push(name);
+    0
+}
+
+struct Call<'a> {
+    db: &'a Database,
+    // Whether the command was registered as a write; writes from any other
+    // would skip the AOF and replicas, so they fail the call instead
+    may_write: bool,
+    wrote_undeclared: bool,
+    budget: (usize, EvictionPolicy),
+    // Set when a write was dropped for want of memory; the command then fails
+    // with OOM whatever the plugin replied
+    refused: bool,
+    reply: ReplyBuilder,
+    // Values returned by `get`, kept alive until the callback returns
+    values: Vec<Vec<u8>>,
+}
+
+fn call_plugin(func: CommandFn, may_write: bool, ctx: &mut Context<'_>, args: &[RespValue]) -> RespValue {
+    let argv: Vec<Slice> = args
+        .iter()
+        .map(|a| match a {
+            RespValue::BulkString(Some(b)) => Slice::new(b),
+            RespValue::SimpleString(s) => Slice::new(s.as_bytes()),
+            _ => Slice::new(&[]),
+        })
+        .collect();
+    let mut call = Call {
+        db: ctx.db,
+        may_write,
+        wrote_undeclared: false,
+        budget: ctx.settings.memory_budget(ctx.client.db),
+        refused: false,
+        reply: ReplyBuilder::default(),
+        values: Vec::new(),
+    };
+    func(&HOST_API, &mut call as *mut Call as *mut c_void, argv.as_ptr(), argv.len());
+    if call.wrote_undeclared {
+        return CommandError::generic("plugin command wrote without the write flag").into();
+    }
+    if call.refused {
+        ctx.db.stats.command_refused_for_memory();
+        return CommandError::Oom.into();
+    }
+    call.reply.finish()
+}
+
+unsafe fn call_mut<'a>(call: *mut c_void) -> &'a mut Call<'a> {
+    &mut *(call as *mut Call)
+}
+
+fn key_string(key: Slice) -> String {
+    String::from_utf8_lossy(unsafe { key.as_bytes() }).to_string()
+}
+
+extern "C" fn host_get(call: *mut c_void, key: Slice, out: *mut Slice) -> c_int {
+    let call = unsafe { call_mut(call) };
+    match call.db.get(&key_string(key)) {
+        Some(v) => {
+            call.values.push(v);
+            let v = call.values.last().map(|v| Slice::new(v)).unwrap_or(Slice::new(&[]));
+            unsafe { *out = v };
+            1
+        }
+        None => 0,
+    }
+}
+
+extern "C" fn host_set(call: *mut c_void, key: Slice, value: Slice) {
+    let call = unsafe { call_mut(call) };
+    if !call.may_write {
+        call.wrote_undeclared = true;
+        return;
+    }
+    // A command not flagged denyoom can still store values, so each write
+    // is held to the memory budget as it happens
+    let (limit, policy) = call.budget;
+    if call.refused || !call.db.evict_to_fit(limit, policy) {
+        call.refused = true;
+        return;
+    }
+    let value = unsafe { value.as_bytes() }.to_vec();
+    call.db.set(key_string(key), value, None);
+}
+
+extern "C" fn host_del(call: *mut c_void, key: Slice) -> c_int {
+    let call = unsafe { call_mut(call) };
+    if !call.may_write {
+        call.wrote_undeclared = true;
+        return 0;
+    }
+    call.db.del(&[key_string(key)]) as c_int
+}
+
+// Simple strings and errors end at the first CR LF, so a line break in the
+// plugin's text would end the reply early and send the rest as frames of its
+// own; as Redis does, each becomes a space
+fn one_line(s: Slice) -> String {
+    String::from_utf8_lossy(unsafe { s.as_bytes() }).replace(['\r', '\n'], " ")
+}
+
+extern "C" fn reply_simple(call: *mut c_void, s: Slice) {
+    unsafe { call_mut(call) }.reply.push(RespValue::SimpleString(one_line(s)));
+}
+
+extern "C" fn reply_error(call: *mut c_void, s: Slice) {
+    unsafe { call_mut(call) }.reply.push(RespValue::Error(one_line(s)));
+}
+
+extern "C" fn reply_integer(call: *mut c_void, n: i64) {
+    unsafe { call_mut(call) }.reply.push(RespValue::Integer(n));
+}
+
+extern "C" fn reply_bulk(call: *mut c_void, s: Slice) {
+    let bytes = unsafe { s.as_bytes() }.to_vec();
+    unsafe { call_mut(call) }.reply.push(RespValue::BulkString(Some(bytes)));
+}
+
+extern "C" fn reply_null(call: *mut c_void) {
+    unsafe { call_mut(call) }.reply.push(RespValue::BulkString(None));
+}
+
+extern "C" fn reply_array(call: *mut c_void, len: usize) {
+    unsafe { call_mut(call) }.reply.start_array(len);
+}
+
+extern "C" fn host_log(msg: Slice) {
+    println!("plugin: {}", String::from_utf8_lossy(unsafe { msg.as_bytes() }));
+}
+
+/// Assembles the streamed reply calls into a single value. Anything sent after
+/// the reply is complete is ignored.
+#[derive(Default)]
+struct ReplyBuilder {
+    open: Vec<(Vec<RespValue>, usize)>,
+    done: Option<RespValue>,
+}
+
+impl ReplyBuilder {
+    fn start_array(&mut self, len: usize) {
+        if self.done.is_some() {
+            return;
+        }
+        if len == 0 {
+            self.push(RespValue::Array(Some(Vec::new())));
+        } else {
+            self.open.push((Vec::with_capacity(len.min(1024)), len));
+        }
+    }
+
+    fn push(&mut self, mut value: RespValue) {
+        loop {
+            match self.open.last_mut() {
+                None => {
+                    if self.done.is_none() {
+                        self.done = Some(value);
+                    }
+                    return;
+                }
+                Some((items, len)) => {
+                    items.push(value);
+                    if items.len() < *len {
+                        return;
+                    }
+                    let (items, _) = self.open.pop().unwrap_or_default();
+                    value = RespValue::Array(Some(items));
+                }
+            }
+        }
+    }
+
+    fn finish(self) -> RespValue {
+        match self.done {
+            Some(v) => v,
+            None => CommandError::generic("plugin command did not send a complete reply").into(),
+        }
+    }
+}
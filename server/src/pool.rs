@@ -0,0 +1,97 @@
+//! Reusable reply buffers.
+//!
+//! A connection takes a buffer when it opens, encodes every reply into it and
+//! hands it back when it closes, so neither each reply nor each new connection
+//! costs an allocation once the pool is warm. Buffers a huge reply stretched
+//! are shrunk before they are reused, so one big GET does not pin its size
+//! for the life of the process.
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+// Idle buffers kept for later connections; past this they are freed
+const MAX_POOLED: usize = 1024;
+// Capacity a buffer is allowed to keep between replies
+const KEEP_CAPACITY: usize = 16 * 1024;
+// Capacity of a newly allocated buffer
+const INITIAL_CAPACITY: usize = 1024;
+
+#[derive(Default)]
+pub(crate) struct BufferPool {
+    free: Mutex<Vec<Vec<u8>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BufferPool {
+    /// An empty buffer, from the pool if one is idle.
+    pub fn take(&self) -> PooledBuffer<'_> {
+        let reused = self.free.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let buf = match reused {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(INITIAL_CAPACITY)
+            }
+        };
+        PooledBuffer { buf, pool: self }
+    }
+
+    fn give_back(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        buf.shrink_to(KEEP_CAPACITY);
+        let mut free = self.free.lock().unwrap_or_else(|e| e.into_inner());
+        if free.len() < MAX_POOLED {
+            free.push(buf);
+        }
+    }
+
+    /// Buffers waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// Takes served from the pool, and those that had to allocate.
+    pub fn hits_and_misses(&self) -> (u64, u64) {
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed))
+    }
+}
+
+/// A buffer on loan from a [`BufferPool`], returned to it on drop.
+pub(crate) struct PooledBuffer<'a> {
+    buf: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl PooledBuffer<'_> {
+    /// Empties the buffer for the next reply, giving back memory a large one
+    /// took.
+    pub fn reset(&mut self) {
+        self.buf.clear();
+        self.buf.shrink_to(KEEP_CAPACITY);
+    }
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.give_back(std::mem::take(&mut self.buf));
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::join_all;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use crate::cluster::hash_tag;
+use crate::commands::{CommandSpec, KeySpec};
+use crate::error::CommandError;
+use crate::namespace;
+use crate::resp::{read_resp, RespValue};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Points each backend gets on the ring; more even out the key spread
+const POINTS_PER_NODE: usize = 160;
+// Multi-key commands split across backends when their keys land on several
+const FAN_OUT: &[&str] = &["mget", "mset", "del", "exists"];
+// Keyless commands sent to every backend, their replies combined
+const BROADCAST: &[&str] = &["dbsize", "flushdb"];
+
+/// A command bound for the backends, left by the dispatcher in proxy mode.
+pub(crate) struct Forward {
+    pub cmd: String,
+    pub spec: CommandSpec,
+    /// The command as sent: name first, keys already namespaced.
+    pub frame: Vec<RespValue>,
+    /// The namespace to strip from the reply.
+    pub namespace: Option<String>,
+}
+
+/// Present in a command's context in proxy mode.
+#[derive(Default)]
+pub(crate) struct ProxyOps {
+    pub forward: Option<Forward>,
+}
+
+/// Whether the proxy answers `cmd` itself: keyless commands about the
+/// connection or the proxy (PING, AUTH, SELECT, INFO, ...) rather than data.
+pub(crate) fn runs_locally(cmd: &str, spec: &CommandSpec) -> bool {
+    spec.keys == KeySpec::NONE && !BROADCAST.contains(&cmd) && cmd != "scan"
+}
+
+/// Consistent hash ring over the backend nodes: each node owns the arcs
+/// ending at its points, so adding or removing one only moves the keys on
+/// its own arcs. Keys are placed by their hash tag, so `{tag}` keys share a
+/// node just as they share a cluster slot.
+pub(crate) struct Ring {
+    nodes: Vec<String>,
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    pub fn new(nodes: Vec<String>) -> Self {
+        let mut points: Vec<(u64, usize)> = nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| (0..POINTS_PER_NODE).map(move |p| (ring_hash(format!("{}-{}", node, p).as_bytes()), i)))
+            .collect();
+        points.sort_unstable();
+        Self { nodes, points }
+    }
+
+    /// Index of the node owning `key`.
+    pub fn node_for(&self, key: &[u8]) -> usize {
+        let h = ring_hash(hash_tag(key));
+        let at = self.points.partition_point(|(p, _)| *p < h);
+        self.points[at % self.points.len()].1
+    }
+}
+
+// FNV-1a with a murmur3 finalizer: stable across processes and platforms, so
+// every proxy in front of the same backends places keys alike
+fn ring_hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+struct Backend {
+    conn: BufReader<TcpStream>,
+    /// The database last selected on this connection.
+    db: usize,
+}
+
+impl Backend {
+    async fn connect(addr: &str) -> io::Result<Self> {
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+        Ok(Self {
+            conn: BufReader::new(stream),
+            db: 0,
+        })
+    }
+
+    async fn call(&mut self, db: usize, frame: Vec<RespValue>) -> io::Result<RespValue> {
+        let mut buf = Vec::new();
+        let select = self.db != db;
+        if select {
+            let select = vec![bulk(b"SELECT"), bulk(db.to_string().as_bytes())];
+            RespValue::Array(Some(select)).encode(&mut buf);
+        }
+        RespValue::Array(Some(frame)).encode(&mut buf);
+        self.conn.get_mut().write_all(&buf).await?;
+        if select {
+            if let RespValue::Error(e) = read_resp(&mut self.conn).await? {
+                // Still read the command's own reply to stay in step
+                read_resp(&mut self.conn).await?;
+                return Ok(RespValue::Error(e));
+            }
+            self.db = db;
+        }
+        read_resp(&mut self.conn).await
+    }
+}
+
+/// One client's connections to the backends, opened on first use and
+/// reopened after a failure.
+pub(crate) struct ProxyClient {
+    ring: Arc<Ring>,
+    backends: Vec<Option<Backend>>,
+}
+
+impl ProxyClient {
+    pub fn new(ring: Arc<Ring>) -> Self {
+        let backends = ring.nodes.iter().map(|_| None).collect();
+        Self { ring, backends }
+    }
+
+    /// Sends `fwd` to the backends owning its keys and combines their replies.
+    pub async fn forward(&mut self, db: usize, fwd: Forward) -> RespValue {
+        let reply = self.route(db, &fwd).await;
+        match &fwd.namespace {
+            Some(ns) => namespace::strip_reply(ns, &fwd.cmd, reply),
+            None => reply,
+        }
+    }
+
+    async fn route(&mut self, db: usize, fwd: &Forward) -> RespValue {
+        let keys = fwd.spec.keys;
+        if keys == KeySpec::NONE {
+            // A tenant's DBSIZE or FLUSHDB would count or flush everyone's keys
+            if !BROADCAST.contains(&fwd.cmd.as_str()) || fwd.namespace.is_some() {
+                return CommandError::generic(format!("'{}' is not supported in proxy mode", fwd.cmd)).into();
+            }
+            let all = (0..self.ring.nodes.len()).map(|n| (n, fwd.frame.clone())).collect();
+            let replies = self.call_many(db, all).await;
+            return combine_sum(&fwd.cmd, replies);
+        }
+        // Key positions within the frame, grouped by owning node
+        let mut groups: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for p in keys.positions(fwd.frame.len() - 1) {
+            let key = match &fwd.frame[p + 1] {
+                RespValue::BulkString(Some(b)) => b.as_slice(),
+                RespValue::SimpleString(s) => s.as_bytes(),
+                _ => return CommandError::Syntax.into(),
+            };
+            groups.entry(self.ring.node_for(key)).or_default().push(p + 1);
+        }
+        if groups.len() <= 1 {
+            let node = groups.into_keys().next().unwrap_or(0);
+            return self.call_many(db, vec![(node, fwd.frame.clone())]).await.remove(0);
+        }
+        if !FAN_OUT.contains(&fwd.cmd.as_str()) {
+            return CommandError::CrossSlot.into();
+        }
+        // Each node gets the command with just its keys (and, for MSET, values)
+        let step = keys.step.max(1);
+        let calls = groups
+            .iter()
+            .map(|(node, positions)| {
+                let mut frame = vec![fwd.frame[0].clone()];
+                for p in positions {
+                    frame.extend_from_slice(&fwd.frame[*p..*p + step]);
+                }
+                (*node, frame)
+            })
+            .collect();
+        let replies = self.call_many(db, calls).await;
+        match fwd.cmd.as_str() {
+            "mget" => {
+                let mut values = vec![RespValue::BulkString(None); keys.positions(fwd.frame.len() - 1).len()];
+                for (positions, reply) in groups.values().zip(replies) {
+                    let parts = match reply {
+                        RespValue::Array(Some(parts)) if parts.len() == positions.len() => parts,
+                        RespValue::Error(e) => return RespValue::Error(e),
+                        _ => return CommandError::generic("unexpected MGET reply from backend").into(),
+                    };
+                    // MGET keys are every argument, so position p is value p - 1
+                    for (p, value) in positions.iter().zip(parts) {
+                        values[p - 1] = value;
+                    }
+                }
+                RespValue::Array(Some(values))
+            }
+            "mset" => replies
+                .into_iter()
+                .find(|r| matches!(r, RespValue::Error(_)))
+                .unwrap_or_else(|| RespValue::SimpleString("OK".to_string())),
+            _ => combine_sum(&fwd.cmd, replies),
+        }
+    }
+
+    // Sends each frame to its node, all at once, and returns the replies in
+    // order. A failed backend connection is dropped and reported as an error.
+    async fn call_many(&mut self, db: usize, calls: Vec<(usize, Vec<RespValue>)>) -> Vec<RespValue> {
+        for (node, _) in &calls {
+            if self.backends[*node].is_none() {
+                match Backend::connect(&self.ring.nodes[*node]).await {
+                    Ok(b) => self.backends[*node] = Some(b),
+                    Err(e) => eprintln!("proxy: backend {} unavailable: {}", self.ring.nodes[*node], e),
+                }
+            }
+        }
+        let mut frames: Vec<Option<Vec<RespValue>>> = self.backends.iter().map(|_| None).collect();
+        let order: Vec<usize> = calls.iter().map(|(n, _)| *n).collect();
+        for (node, frame) in calls {
+            frames[node] = Some(frame);
+        }
+        let nodes = &self.ring.nodes;
+        let pending = self.backends.iter_mut().zip(frames).enumerate().filter_map(|(node, (slot, frame))| {
+            let frame = frame?;
+            Some(async move {
+                let result = match slot.as_mut() {
+                    Some(backend) => backend.call(db, frame).await,
+                    None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected")),
+                };
+                let reply = match result {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        *slot = None;
+                        CommandError::generic(format!("backend {} unavailable: {}", nodes[node], e)).into()
+                    }
+                };
+                (node, reply)
+            })
+        });
+        let mut replies: BTreeMap<usize, RespValue> = join_all(pending).await.into_iter().collect();
+        order.iter().map(|n| replies.remove(n).unwrap_or(RespValue::BulkString(None))).collect()
+    }
+}
+
+// Integer replies add up (DEL, EXISTS, DBSIZE); anything else must be the
+// same OK from every backend (FLUSHDB). The first error wins.
+fn combine_sum(cmd: &str, replies: Vec<RespValue>) -> RespValue {
+    if let Some(e) = replies.iter().find(|r| matches!(r, RespValue::Error(_))) {
+        return e.clone();
+    }
+    let counts: Option<Vec<i64>> = replies
+        .iter()
+        .map(|r| match r {
+            RespValue::Integer(n) => Some(*n),
+            _ => None,
+        })
+        .collect();
+    if let Some(counts) = counts {
+        return RespValue::Integer(counts.iter().sum());
+    }
+    match replies.into_iter().next() {
+        Some(first) => first,
+        None => CommandError::generic(format!("no backend answered '{}'", cmd)).into(),
+    }
+}
+
+fn bulk(b: &[u8]) -> RespValue {
+    RespValue::BulkString(Some(b.to_vec()))
+}
@@ -0,0 +1,291 @@
+//! Publish/subscribe messaging between clients.
+//!
+//! Subscribers join channels by name (SUBSCRIBE), by glob pattern
+//! (PSUBSCRIBE) or as shard channels (SSUBSCRIBE), and every message
+//! PUBLISHed or SPUBLISHed to a channel is pushed to each connection
+//! subscribed to it. Channels exist only while someone is subscribed, and
+//! messages to a channel nobody is on are dropped, as in Redis. The PUBSUB
+//! command reports which channels have subscribers and how many.
+//!
+//! Each subscriber's messages wait in a queue of `pubsub-queue-size` until
+//! its connection writes them. A subscriber that stops reading fills its
+//! queue, and `pubsub-slow-subscriber` decides what gives: `drop-oldest`
+//! makes room by discarding its oldest message, `drop-message` discards the
+//! new one, and `disconnect` closes the connection, as Redis does past its
+//! pubsub output buffer limit. Either way one stalled client holds at most
+//! a queue's worth of messages; INFO stats counts what was dropped.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+
+use crate::glob::glob_match;
+use crate::resp::RespValue;
+use crate::stats::Stats;
+
+/// How a subscription names what it listens to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Channel,
+    Pattern,
+    Shard,
+}
+
+impl Kind {
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What happens to a message for a subscriber whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowSubscriber {
+    /// Discard the subscriber's oldest queued message to make room.
+    DropOldest,
+    /// Discard the new message.
+    DropMessage,
+    /// Close the subscriber's connection.
+    Disconnect,
+}
+
+impl SlowSubscriber {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "drop-oldest" => Some(Self::DropOldest),
+            "drop-message" => Some(Self::DropMessage),
+            "disconnect" => Some(Self::Disconnect),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DropOldest => "drop-oldest",
+            Self::DropMessage => "drop-message",
+            Self::Disconnect => "disconnect",
+        }
+    }
+}
+
+/// How many messages a subscriber may have queued, and what happens past
+/// that.
+pub(crate) type QueueLimit = (usize, SlowSubscriber);
+
+// Messages waiting for one connection
+#[derive(Default)]
+struct Mailbox {
+    queue: Mutex<VecDeque<RespValue>>,
+    ready: Notify,
+    /// Set when the subscriber is to be disconnected for falling behind.
+    closed: AtomicBool,
+}
+
+impl Mailbox {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<RespValue>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Subscribers by id, per channel or pattern
+type Subscribed = HashMap<Vec<u8>, HashMap<u64, Arc<Mailbox>>>;
+
+fn bulk(b: &[u8]) -> RespValue {
+    RespValue::BulkString(Some(b.to_vec()))
+}
+
+/// Every subscription on the server.
+#[derive(Default)]
+pub(crate) struct PubSub {
+    next_id: AtomicU64,
+    /// Indexed by [`Kind`].
+    subscribed: Mutex<[Subscribed; 3]>,
+    /// Messages discarded because a subscriber's queue was full.
+    pub dropped: AtomicU64,
+    /// Subscribers disconnected because their queue was full.
+    pub disconnected: AtomicU64,
+}
+
+impl PubSub {
+    fn lock(&self) -> std::sync::MutexGuard<'_, [Subscribed; 3]> {
+        self.subscribed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of the patterns
+    /// it matches, returning how many had it queued.
+    pub fn publish(&self, channel: &[u8], message: &[u8], limit: QueueLimit) -> usize {
+        let subscribed = self.lock();
+        let mut received = 0;
+        if let Some(subs) = subscribed[Kind::Channel.index()].get(channel) {
+            let frame = RespValue::Array(Some(vec![bulk(b"message"), bulk(channel), bulk(message)]));
+            received += self.deliver(subs, &frame, limit);
+        }
+        for (pattern, subs) in &subscribed[Kind::Pattern.index()] {
+            if glob_match(pattern, channel) {
+                let frame = RespValue::Array(Some(vec![bulk(b"pmessage"), bulk(pattern), bulk(channel), bulk(message)]));
+                received += self.deliver(subs, &frame, limit);
+            }
+        }
+        received
+    }
+
+    /// Sends `message` to the subscribers of the shard channel `channel`.
+    pub fn spublish(&self, channel: &[u8], message: &[u8], limit: QueueLimit) -> usize {
+        let subscribed = self.lock();
+        let Some(subs) = subscribed[Kind::Shard.index()].get(channel) else { return 0 };
+        self.deliver(subs, &RespValue::Array(Some(vec![bulk(b"smessage"), bulk(channel), bulk(message)])), limit)
+    }
+
+    // Queues `frame` for each of `subs`, returning how many took it
+    fn deliver(&self, subs: &HashMap<u64, Arc<Mailbox>>, frame: &RespValue, (size, policy): QueueLimit) -> usize {
+        let mut received = 0;
+        for mailbox in subs.values() {
+            if mailbox.closed.load(Ordering::Relaxed) {
+                continue;
+            }
+            let mut queue = mailbox.lock();
+            if queue.len() >= size {
+                match policy {
+                    SlowSubscriber::DropOldest => {
+                        queue.pop_front();
+                    }
+                    SlowSubscriber::DropMessage => {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    SlowSubscriber::Disconnect => {
+                        // What it had queued goes now rather than when the connection closes
+                        queue.clear();
+                        mailbox.closed.store(true, Ordering::Relaxed);
+                        mailbox.ready.notify_one();
+                        self.disconnected.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            queue.push_back(frame.clone());
+            drop(queue);
+            mailbox.ready.notify_one();
+            received += 1;
+        }
+        received
+    }
+
+    /// The channels (or shard channels) with subscribers, sorted, limited to
+    /// those matching `pattern` if given.
+    pub fn channels(&self, kind: Kind, pattern: Option<&[u8]>) -> Vec<Vec<u8>> {
+        let mut names: Vec<Vec<u8>> = self.lock()[kind.index()]
+            .keys()
+            .filter(|name| pattern.is_none_or(|p| glob_match(p, name)))
+            .cloned()
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// How many connections are subscribed to each of `names`.
+    pub fn numsub(&self, kind: Kind, names: &[&[u8]]) -> Vec<usize> {
+        let subscribed = self.lock();
+        names.iter().map(|n| subscribed[kind.index()].get(*n).map_or(0, HashMap::len)).collect()
+    }
+
+    /// How many channels, patterns or shard channels have subscribers.
+    pub fn count(&self, kind: Kind) -> usize {
+        self.lock()[kind.index()].len()
+    }
+}
+
+/// A connection's subscriptions and the messages waiting for it. Dropping it
+/// leaves every channel and pattern.
+pub(crate) struct Subscriber {
+    id: u64,
+    mailbox: Arc<Mailbox>,
+    /// What the connection is subscribed to, indexed by [`Kind`].
+    names: [BTreeSet<Vec<u8>>; 3],
+    stats: Arc<Stats>,
+}
+
+impl Subscriber {
+    pub fn new(stats: Arc<Stats>) -> Self {
+        let id = stats.pubsub.next_id.fetch_add(1, Ordering::Relaxed);
+        Self { id, mailbox: Arc::default(), names: Default::default(), stats }
+    }
+
+    /// Subscribes to `name`, returning the subscription count SUBSCRIBE
+    /// replies with.
+    pub fn subscribe(&mut self, kind: Kind, name: &[u8]) -> usize {
+        if self.names[kind.index()].insert(name.to_vec()) {
+            let mut subscribed = self.stats.pubsub.lock();
+            subscribed[kind.index()].entry(name.to_vec()).or_default().insert(self.id, self.mailbox.clone());
+        }
+        self.count(kind)
+    }
+
+    /// Unsubscribes from `name`, returning the subscription count left.
+    pub fn unsubscribe(&mut self, kind: Kind, name: &[u8]) -> usize {
+        if self.names[kind.index()].remove(name) {
+            self.leave(kind, name);
+        }
+        self.count(kind)
+    }
+
+    // Takes the connection off `name`'s subscribers, and the name off the
+    // list once it has none
+    fn leave(&self, kind: Kind, name: &[u8]) {
+        let mut subscribed = self.stats.pubsub.lock();
+        let by_name = &mut subscribed[kind.index()];
+        if let Some(subs) = by_name.get_mut(name) {
+            subs.remove(&self.id);
+            if subs.is_empty() {
+                by_name.remove(name);
+            }
+        }
+    }
+
+    /// What the connection is subscribed to as `kind`, sorted.
+    pub fn names(&self, kind: Kind) -> Vec<Vec<u8>> {
+        self.names[kind.index()].iter().cloned().collect()
+    }
+
+    /// The subscriptions (P)SUBSCRIBE replies count: channels and patterns
+    /// count together, shard channels on their own.
+    pub fn count(&self, kind: Kind) -> usize {
+        match kind {
+            Kind::Channel | Kind::Pattern => self.names[Kind::Channel.index()].len() + self.names[Kind::Pattern.index()].len(),
+            Kind::Shard => self.names[Kind::Shard.index()].len(),
+        }
+    }
+
+    /// Whether the connection is no longer subscribed to anything.
+    pub fn is_empty(&self) -> bool {
+        self.names.iter().all(BTreeSet::is_empty)
+    }
+
+    /// The next message for the connection, or None once it is to be
+    /// disconnected for falling behind. Nothing is lost if the wait is
+    /// cancelled.
+    pub async fn recv(&mut self) -> Option<RespValue> {
+        loop {
+            let ready = self.mailbox.ready.notified();
+            if self.mailbox.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            if let Some(message) = self.mailbox.lock().pop_front() {
+                return Some(message);
+            }
+            ready.await;
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for kind in [Kind::Channel, Kind::Pattern, Kind::Shard] {
+            for name in &self.names[kind.index()] {
+                self.leave(kind, name);
+            }
+        }
+    }
+}
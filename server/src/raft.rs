@@ -0,0 +1,902 @@
+//! Raft consensus mode: writes are committed through a replicated log
+//! before they are acknowledged.
+//!
+//! With `raft-node` set, this server is one member of a group with the
+//! `raft-peer`s it names, and writes are no longer applied where they
+//! arrive. The elected leader appends each write to its log and replicates
+//! it to the other members; once a majority hold it the write is committed,
+//! and every member applies the committed writes in log order. The client's
+//! reply is the result of that application, so a SET IFEQ the leader
+//! acknowledges is held by a majority and seen by every later leader: lock
+//! and metadata workloads get linearizable writes, for a round trip to the
+//! followers and an fsync.
+//!
+//! Members talk over the ordinary client port with the internal RAFT
+//! command, authenticating with masterauth (and masteruser). Followers
+//! refuse reads and writes with NOTLEADER, naming the leader when they know
+//! it. The leader serves reads while a majority has acknowledged it within
+//! the election timeout; followers that heard from a leader that recently
+//! do not vote for anyone else, so no other leader can exist meanwhile.
+//!
+//! The term, vote and log are kept in `raft-file` and replayed on restart.
+//! One task writes the file, in the order the records were made, and syncs
+//! whatever has queued up since its last fsync on the persistence I/O
+//! threads; a member answers RAFT requests, and the leader counts its own
+//! copy of an entry, only once the records behind them are on disk.
+//! There are no snapshots: the log holds every write since the group was
+//! formed, and the data lives only there, so raft mode excludes dbfilename,
+//! rdbfilename and appendonly. Writes with a relative TTL (SET EX or PX,
+//! EXPIRE, PEXPIRE) are logged with the Unix time the leader computed from
+//! it, as in the AOF, so every member and every replay expires the key at
+//! the same moment.
+//!
+//! Committed writes are applied below the middleware. ACL checks, quotas,
+//! stats and the audit log saw each one when the leader's client sent it,
+//! and running the chain again would propose it again; the raft log stands
+//! in for the AOF and replication, which raft mode excludes.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::hash::BuildHasher;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::aof::{absolute_form, truncate, AofEntry, AofReader};
+use crate::client::ClientState;
+use crate::commands::{flags, CommandSpec, Context, Middleware, Registry};
+use crate::config::Settings;
+use crate::db::Database;
+use crate::error::CommandError;
+use crate::io_pool::IoPool;
+use crate::resp::{read_resp, RespValue};
+
+// Entries sent to a peer in one AppendEntries
+const BATCH: usize = 256;
+
+/// A proposed write's reply: the result of applying it once committed.
+pub(crate) type Committed = oneshot::Receiver<RespValue>;
+
+/// A write in the log. A new leader's first entry is a no-op with no args.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Entry {
+    pub term: u64,
+    pub db: usize,
+    pub args: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl Role {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Follower => "follower",
+            Self::Candidate => "candidate",
+            Self::Leader => "leader",
+        }
+    }
+}
+
+/// What the leader knows of each peer, and a candidate of its votes.
+#[derive(Default)]
+struct Peer {
+    /// The next entry to send it.
+    next: u64,
+    /// The last entry it is known to hold.
+    matched: u64,
+    /// When the last AppendEntries it accepted was sent.
+    acked: Option<Instant>,
+    /// The term it was last asked for its vote in, and whether it gave it.
+    asked: u64,
+    granted: bool,
+}
+
+fn number(b: &[u8]) -> Option<u64> {
+    std::str::from_utf8(b).ok()?.parse().ok()
+}
+
+fn encode(args: &[Vec<u8>], out: &mut Vec<u8>) {
+    let frame = RespValue::Array(Some(args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect()));
+    frame.encode(out);
+}
+
+// The term, vote and log, as RESP records appended to `raft-file`:
+// TERM <term> <vote>, ENTRY <term> <db> <arg>... and TRUNCATE <length>.
+// Records are queued here, under the state lock, and written in that order
+// by `write_journal`.
+struct Journal {
+    path: PathBuf,
+    queue: mpsc::UnboundedSender<Queued>,
+    /// Sequence number of the next write.
+    next: u64,
+    /// Set once a write fails; the file no longer follows the log, so the
+    /// member takes no further part.
+    failed: bool,
+}
+
+// Records waiting for the writer, with the length of the log once they are
+// on disk and the reply, if any, to send then
+struct Queued {
+    seq: u64,
+    records: Vec<u8>,
+    length: u64,
+    reply: Option<(oneshot::Sender<RespValue>, RespValue)>,
+}
+
+/// What a journal held when it was opened.
+type Restored = (u64, Option<String>, Vec<Entry>);
+
+impl Journal {
+    fn open(path: &Path) -> io::Result<(Self, Restored, JournalWriter)> {
+        let (mut term, mut vote, mut log) = (0, None, Vec::new());
+        if path.exists() {
+            let mut reader = AofReader::new(io::BufReader::new(File::open(path)?));
+            let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), what));
+            loop {
+                let record = match reader.next_entry() {
+                    Ok(Some(AofEntry::Command(record))) => record,
+                    Ok(Some(AofEntry::Timestamp(_))) => continue,
+                    Ok(None) => break,
+                    // A record cut short by a crash was never acknowledged
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                        truncate(path, reader.offset())?;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                };
+                match (record[0].as_slice(), &record[1..]) {
+                    (b"TERM", [t, v]) => {
+                        term = number(t).ok_or_else(|| invalid("bad term"))?;
+                        vote = (!v.is_empty()).then(|| String::from_utf8_lossy(v).into_owned());
+                    }
+                    (b"ENTRY", [t, db, args @ ..]) => log.push(Entry {
+                        term: number(t).ok_or_else(|| invalid("bad entry term"))?,
+                        db: number(db).ok_or_else(|| invalid("bad entry database"))? as usize,
+                        args: args.to_vec(),
+                    }),
+                    (b"TRUNCATE", [len]) => log.truncate(number(len).ok_or_else(|| invalid("bad truncation"))? as usize),
+                    _ => return Err(invalid("unknown record")),
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (queue, queued) = mpsc::unbounded_channel();
+        let journal = Self { path: path.to_path_buf(), queue, next: 0, failed: false };
+        Ok((journal, (term, vote, log), (Arc::new(file), queued)))
+    }
+
+    // Queues `records`, after which the log on disk is `length` entries
+    // long, and `reply` to be sent once they are written; the sequence
+    // number of the write
+    fn write(&mut self, records: &[Vec<Vec<u8>>], length: u64, reply: Option<(oneshot::Sender<RespValue>, RespValue)>) -> u64 {
+        let mut buf = Vec::new();
+        for record in records {
+            encode(record, &mut buf);
+        }
+        let seq = self.next;
+        self.next += 1;
+        // The writer only stops with the member
+        let _ = self.queue.send(Queued { seq, records: buf, length, reply });
+        seq
+    }
+}
+
+// The file and the writes queued for it, until `start_raft` hands them to
+// `write_journal`
+type JournalWriter = (Arc<File>, mpsc::UnboundedReceiver<Queued>);
+
+struct State {
+    term: u64,
+    voted_for: Option<String>,
+    /// Entry `i` is at `log[i - 1]`.
+    log: Vec<Entry>,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<String>,
+    /// When a follower last heard from the leader or gave its vote, or a
+    /// candidate stood for election.
+    heard: Instant,
+    /// This term's election timeout, randomized so members rarely stand at
+    /// once.
+    timeout: Duration,
+    peers: HashMap<String, Peer>,
+    /// Clients waiting on the entry at each index, with the term it was
+    /// appended in.
+    waiters: BTreeMap<u64, (u64, oneshot::Sender<RespValue>)>,
+    journal: Journal,
+    /// How many entries at the start of the log are on disk.
+    durable: u64,
+    /// The latest truncation's write and length: writes before it leave
+    /// entries on disk that the log no longer has.
+    truncated: (u64, u64),
+}
+
+impl State {
+    // The index and term of the last entry
+    fn last(&self) -> (u64, u64) {
+        (self.log.len() as u64, self.log.last().map_or(0, |e| e.term))
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index {
+            0 => Some(0),
+            i => self.log.get(i as usize - 1).map(|e| e.term),
+        }
+    }
+
+    fn save_term(&mut self) {
+        let record = vec![b"TERM".to_vec(), self.term.to_string().into_bytes(), self.voted_for.clone().unwrap_or_default().into_bytes()];
+        let length = self.last().0;
+        self.journal.write(&[record], length, None);
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    // Adopts `term` if it is newer, forgetting the vote, and follows
+    fn follow(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_term();
+        }
+        if self.role == Role::Leader {
+            // Whether they commit is up to the next leader
+            for (_, (_, waiter)) in std::mem::take(&mut self.waiters) {
+                let _ = waiter.send(CommandError::generic("leadership was lost; the write may or may not have been applied").into());
+            }
+        }
+        self.role = Role::Follower;
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) {
+        let records: Vec<Vec<Vec<u8>>> = entries
+            .iter()
+            .map(|e| {
+                let mut record = vec![b"ENTRY".to_vec(), e.term.to_string().into_bytes(), e.db.to_string().into_bytes()];
+                record.extend(e.args.iter().cloned());
+                record
+            })
+            .collect();
+        self.log.extend(entries);
+        let length = self.last().0;
+        self.journal.write(&records, length, None);
+    }
+
+    // Drops the entries after `len`, which a new leader does not have
+    fn truncate(&mut self, len: u64) {
+        self.log.truncate(len as usize);
+        let seq = self.journal.write(&[vec![b"TRUNCATE".to_vec(), len.to_string().into_bytes()]], len, None);
+        self.truncated = (seq, len);
+        self.durable = self.durable.min(len);
+        for (_, (_, waiter)) in self.waiters.split_off(&(len + 1)) {
+            let _ = waiter.send(CommandError::generic("the write was overwritten by a new leader").into());
+        }
+    }
+
+    // The writer has synced write `seq`, after which the log on disk is
+    // `length` entries long
+    fn synced(&mut self, seq: u64, length: u64) {
+        let (truncation, truncated_to) = self.truncated;
+        self.durable = if seq >= truncation { length } else { length.min(truncated_to) };
+    }
+
+    // Commits what a majority holds, if it is from this term; true if the
+    // commit index moved
+    fn advance_commit(&mut self) -> bool {
+        if self.role != Role::Leader {
+            return false;
+        }
+        // This member holds an entry once it is on its disk
+        let own = self.durable.min(self.last().0);
+        let mut matched: Vec<u64> = self.peers.values().map(|p| p.matched).chain([own]).collect();
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let held = matched[self.quorum() - 1];
+        if held > self.commit && self.term_at(held) == Some(self.term) {
+            self.commit = held;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn lead(&mut self, node: &str) {
+        self.role = Role::Leader;
+        self.leader = Some(node.to_string());
+        let next = self.last().0 + 1;
+        for peer in self.peers.values_mut() {
+            *peer = Peer { next, ..Peer::default() };
+        }
+        let term = self.term;
+        self.append(vec![Entry { term, db: 0, args: Vec::new() }]);
+        println!("Raft: leading in term {}", term);
+    }
+}
+
+fn random_timeout(base: Duration) -> Duration {
+    let spread = RandomState::new().hash_one(0u8) % 1000;
+    base + base * spread as u32 / 1000
+}
+
+/// A member's view of the group, as INFO replication reports it.
+pub(crate) struct RaftStatus {
+    pub role: Role,
+    pub term: u64,
+    pub leader: Option<String>,
+    pub log_length: u64,
+    pub commit: u64,
+    pub applied: u64,
+    pub peers: usize,
+}
+
+/// This member of the group.
+pub(crate) struct Raft {
+    node: String,
+    election_timeout: Duration,
+    state: Mutex<State>,
+    /// Bumped when the peers have something to be sent.
+    outbox: watch::Sender<u64>,
+    /// Woken when the commit index moves.
+    committed: Notify,
+    journal_writer: Mutex<Option<JournalWriter>>,
+}
+
+/// A message for a peer: a vote request while standing for election,
+/// AppendEntries while leading.
+enum Request {
+    Vote { term: u64, last_index: u64, last_term: u64 },
+    Append { term: u64, prev_index: u64, prev_term: u64, commit: u64, entries: Vec<Entry>, sent: Instant },
+}
+
+impl Raft {
+    /// Restores the member `node` from `path`, a follower until it hears
+    /// from a leader or stands for election.
+    pub fn open(node: &str, peers: &[String], path: &Path, election_timeout: Duration) -> io::Result<Self> {
+        let (journal, (term, voted_for, log), writer) = Journal::open(path)?;
+        let log_length = log.len() as u64;
+        if !log.is_empty() {
+            println!("Raft: restored {} entries of term {} from {}", log.len(), term, path.display());
+        }
+        let state = State {
+            term,
+            voted_for,
+            log,
+            commit: 0,
+            applied: 0,
+            role: Role::Follower,
+            leader: None,
+            heard: Instant::now(),
+            timeout: random_timeout(election_timeout),
+            peers: peers.iter().map(|p| (p.clone(), Peer::default())).collect(),
+            waiters: BTreeMap::new(),
+            journal,
+            durable: log_length,
+            truncated: (0, 0),
+        };
+        Ok(Self {
+            node: node.to_string(),
+            election_timeout,
+            state: Mutex::new(state),
+            outbox: watch::Sender::new(0),
+            committed: Notify::new(),
+            journal_writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wake_peers(&self) {
+        self.outbox.send_modify(|n| *n += 1);
+    }
+
+    /// `reply`, sent once everything journaled so far is on disk: a RAFT
+    /// request is answered only when what it changed would survive a crash.
+    pub fn when_synced(&self, reply: RespValue) -> Committed {
+        let (waiter, synced) = oneshot::channel();
+        let mut state = self.lock();
+        let length = state.last().0;
+        state.journal.write(&[], length, Some((waiter, reply)));
+        synced
+    }
+
+    pub fn status(&self) -> RaftStatus {
+        let state = self.lock();
+        RaftStatus {
+            role: state.role,
+            term: state.term,
+            leader: state.leader.clone(),
+            log_length: state.last().0,
+            commit: state.commit,
+            applied: state.applied,
+            peers: state.peers.len(),
+        }
+    }
+
+    /// Appends a write to the log as leader; the reply arrives once it is
+    /// committed and applied.
+    pub fn propose(&self, db: usize, args: Vec<Vec<u8>>) -> Result<Committed, CommandError> {
+        let mut state = self.lock();
+        if state.journal.failed {
+            return Err(CommandError::generic("the raft log could not be written"));
+        }
+        if state.role != Role::Leader {
+            return Err(CommandError::NotLeader { leader: state.leader.clone() });
+        }
+        let term = state.term;
+        state.append(vec![Entry { term, db, args }]);
+        let (waiter, committed) = oneshot::channel();
+        let index = state.last().0;
+        state.waiters.insert(index, (term, waiter));
+        drop(state);
+        self.wake_peers();
+        Ok(committed)
+    }
+
+    /// Whether a read may be served here: only by a leader that has applied
+    /// an entry of its own term and still holds its lease.
+    pub fn check_read(&self) -> Result<(), CommandError> {
+        let state = self.lock();
+        if state.role != Role::Leader {
+            return Err(CommandError::NotLeader { leader: state.leader.clone() });
+        }
+        let confirmed = 1 + state.peers.values().filter(|p| p.acked.is_some_and(|t| t.elapsed() < self.election_timeout)).count();
+        if state.term_at(state.applied) != Some(state.term) || confirmed < state.quorum() {
+            return Err(CommandError::NotLeader { leader: None });
+        }
+        Ok(())
+    }
+
+    /// Stands for election once the leader has been silent for the
+    /// election timeout.
+    fn tick(&self) {
+        let mut state = self.lock();
+        if state.role == Role::Leader || state.heard.elapsed() < state.timeout || state.journal.failed {
+            return;
+        }
+        state.term += 1;
+        state.voted_for = Some(self.node.clone());
+        state.save_term();
+        state.role = Role::Candidate;
+        state.leader = None;
+        state.heard = Instant::now();
+        state.timeout = random_timeout(self.election_timeout);
+        if state.quorum() == 1 {
+            state.lead(&self.node);
+        }
+        drop(state);
+        self.wake_peers();
+    }
+
+    // What to send `peer` now, if anything
+    fn request_for(&self, peer: &str) -> Option<Request> {
+        let mut state = self.lock();
+        let (term, (last_index, last_term), commit) = (state.term, state.last(), state.commit);
+        match state.role {
+            Role::Follower => None,
+            Role::Candidate => {
+                let p = state.peers.get_mut(peer)?;
+                if p.asked == term {
+                    return None;
+                }
+                *p = Peer { asked: term, ..Peer::default() };
+                Some(Request::Vote { term, last_index, last_term })
+            }
+            Role::Leader => {
+                let prev_index = state.peers.get(peer)?.next - 1;
+                let prev_term = state.term_at(prev_index).unwrap_or(0);
+                let entries = state.log[prev_index as usize..].iter().take(BATCH).cloned().collect();
+                Some(Request::Append { term, prev_index, prev_term, commit, entries, sent: Instant::now() })
+            }
+        }
+    }
+
+    // Whether a leader has more of its log to send `peer` straight away
+    fn behind(&self, peer: &str) -> bool {
+        let state = self.lock();
+        state.role == Role::Leader && state.peers.get(peer).is_some_and(|p| p.next <= state.last().0)
+    }
+
+    fn on_reply(&self, peer: &str, request: Request, reply: &[i64]) {
+        let mut state = self.lock();
+        let (reply_term, ok) = match reply {
+            [term, ok, ..] => (*term as u64, *ok == 1),
+            _ => return,
+        };
+        if reply_term > state.term {
+            state.follow(reply_term);
+            state.leader = None;
+            return;
+        }
+        match request {
+            Request::Vote { term, .. } => {
+                if state.role != Role::Candidate || state.term != term || !ok {
+                    return;
+                }
+                if let Some(p) = state.peers.get_mut(peer) {
+                    p.granted = true;
+                }
+                let votes = 1 + state.peers.values().filter(|p| p.asked == term && p.granted).count();
+                if votes >= state.quorum() {
+                    state.lead(&self.node);
+                    drop(state);
+                    self.wake_peers();
+                }
+            }
+            Request::Append { term, sent, .. } => {
+                if state.role != Role::Leader || state.term != term {
+                    return;
+                }
+                let matched = reply.get(2).copied().unwrap_or(0).max(0) as u64;
+                let Some(p) = state.peers.get_mut(peer) else { return };
+                if ok {
+                    p.matched = p.matched.max(matched);
+                    p.next = p.matched + 1;
+                    p.acked = Some(sent);
+                    if state.advance_commit() {
+                        self.committed.notify_one();
+                    }
+                } else {
+                    // The peer's hint: where its log could agree with ours
+                    p.next = (p.next - 1).min(matched + 1).max(1);
+                }
+            }
+        }
+    }
+
+    /// RequestVote from `candidate`: the current term, and whether the vote
+    /// was given.
+    pub fn vote(&self, term: u64, candidate: &str, last_index: u64, last_term: u64) -> (u64, bool) {
+        let mut state = self.lock();
+        // A member that heard from the leader lately does not help unseat it
+        let sticky = match state.role {
+            Role::Leader => true,
+            Role::Follower => state.leader.is_some() && state.heard.elapsed() < self.election_timeout,
+            Role::Candidate => false,
+        };
+        if term < state.term || sticky || state.journal.failed {
+            return (state.term, false);
+        }
+        if term > state.term {
+            state.follow(term);
+            state.leader = None;
+        }
+        let (our_index, our_term) = state.last();
+        let up_to_date = (last_term, last_index) >= (our_term, our_index);
+        let free = state.voted_for.as_deref().is_none_or(|v| v == candidate);
+        if !up_to_date || !free {
+            return (state.term, false);
+        }
+        state.voted_for = Some(candidate.to_string());
+        state.save_term();
+        state.heard = Instant::now();
+        (state.term, true)
+    }
+
+    /// AppendEntries from `leader`: the current term, whether the entries
+    /// were taken, and the last entry known to match the leader's log (or,
+    /// refused, where to try from).
+    pub fn append_entries(&self, term: u64, leader: &str, prev_index: u64, prev_term: u64, commit: u64, entries: Vec<Entry>) -> (u64, bool, u64) {
+        let mut state = self.lock();
+        if term < state.term || state.journal.failed {
+            return (state.term, false, 0);
+        }
+        if term > state.term || state.role != Role::Follower {
+            state.follow(term);
+        }
+        state.leader = Some(leader.to_string());
+        state.heard = Instant::now();
+        let (last, _) = state.last();
+        if state.term_at(prev_index) != Some(prev_term) {
+            return (term, false, last.min(prev_index.saturating_sub(1)));
+        }
+        let matched = prev_index + entries.len() as u64;
+        // Entries already held are skipped; from the first that differs, the
+        // leader's replace ours
+        let skip = entries.iter().zip(prev_index + 1..).take_while(|(e, i)| state.term_at(*i) == Some(e.term)).count();
+        if skip < entries.len() {
+            let from = prev_index + skip as u64;
+            if from < last {
+                state.truncate(from);
+            }
+            state.append(entries[skip..].to_vec());
+        }
+        if commit.min(matched) > state.commit {
+            state.commit = commit.min(matched);
+            self.committed.notify_one();
+        }
+        (term, true, matched)
+    }
+
+    // The committed entries after `after`, with the clients waiting on them
+    fn committed_after(&self, after: u64) -> Vec<(u64, Entry, Option<oneshot::Sender<RespValue>>)> {
+        let mut state = self.lock();
+        (after + 1..=state.commit)
+            .map(|index| {
+                let entry = state.log[index as usize - 1].clone();
+                let waiter = state.waiters.remove(&index).and_then(|(term, w)| (term == entry.term).then_some(w));
+                (index, entry, waiter)
+            })
+            .collect()
+    }
+}
+
+/// Sends writes through the log instead of running them, and holds reads
+/// to the leader.
+pub(crate) struct Consensus;
+
+impl Middleware for Consensus {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, spec: &CommandSpec, args: &[RespValue]) -> Option<RespValue> {
+        let raft = ctx.db.stats.raft.get()?;
+        if spec.has(flags::READONLY) {
+            return raft.check_read().err().map(Into::into);
+        }
+        if !spec.has(flags::WRITE) {
+            return None;
+        }
+        if ctx.client.namespace().is_some() {
+            return Some(CommandError::generic("namespaced users can't write in raft mode").into());
+        }
+        if !args.iter().all(|arg| matches!(arg, RespValue::BulkString(Some(_)))) {
+            return Some(CommandError::generic("invalid argument").into());
+        }
+        // Deadlines go in the log as times, not durations, so a member that
+        // applies the write late, or replays it after a restart, sets the same one
+        let entry = absolute_form(ctx.db.clock(), cmd, args);
+        match raft.propose(ctx.client.db, entry) {
+            Ok(committed) => {
+                // The connection waits for the real reply
+                ctx.client.pending = Some(committed);
+                Some(RespValue::BulkString(None))
+            }
+            Err(e) => Some(e.into()),
+        }
+    }
+}
+
+/// Runs the member until aborted: elections, replication to each peer,
+/// and applying what commits.
+pub(crate) fn start_raft(raft: Arc<Raft>, dbs: Arc<[Database]>, settings: Arc<Settings>, registry: Arc<Registry>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tasks = JoinSet::new();
+        let peers: Vec<String> = raft.lock().peers.keys().cloned().collect();
+        let config = settings.current();
+        let auth: Vec<String> = match &config.masterauth {
+            Some(pass) => ["AUTH".to_string()].into_iter().chain(config.masteruser.clone()).chain([pass.clone()]).collect(),
+            None => Vec::new(),
+        };
+        for peer in peers {
+            tasks.spawn(replicate_to(raft.clone(), peer, auth.clone()));
+        }
+        if let Some(writer) = raft.journal_writer.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            tasks.spawn(write_journal(raft.clone(), writer, dbs.clone()));
+        }
+        tasks.spawn(apply_committed(raft.clone(), dbs, settings, registry));
+        let mut ticks = tokio::time::interval(raft.election_timeout / 10);
+        loop {
+            ticks.tick().await;
+            raft.tick();
+        }
+    })
+}
+
+// Writes queued journal records in order, syncing each batch that queued up
+// during the last fsync on the persistence I/O threads, then counts what is
+// durable and sends the replies that waited on it
+async fn write_journal(raft: Arc<Raft>, (file, mut queued): JournalWriter, dbs: Arc<[Database]>) {
+    let io: &IoPool = &dbs[0].stats.persistence_io;
+    let mut failed = false;
+    while let Some(first) = queued.recv().await {
+        let mut batch = vec![first];
+        while let Ok(more) = queued.try_recv() {
+            batch.push(more);
+        }
+        let records: Vec<u8> = batch.iter().flat_map(|q| q.records.iter().copied()).collect();
+        if !failed && !records.is_empty() {
+            let file = file.clone();
+            let written = io.run(move || (&*file).write_all(&records).and_then(|()| file.sync_data())).await.and_then(|r| r);
+            if let Err(e) = written {
+                let mut state = raft.lock();
+                eprintln!("Raft: writing {} failed: {}; this member stops taking part", state.journal.path.display(), e);
+                state.journal.failed = true;
+                for (_, (_, waiter)) in std::mem::take(&mut state.waiters) {
+                    let _ = waiter.send(CommandError::generic("the raft log could not be written").into());
+                }
+                failed = true;
+            }
+        }
+        if failed {
+            for (waiter, _) in batch.into_iter().filter_map(|q| q.reply) {
+                let _ = waiter.send(CommandError::generic("the raft log could not be written").into());
+            }
+            continue;
+        }
+        let last = batch.last().expect("batch starts with one write");
+        let mut state = raft.lock();
+        state.synced(last.seq, last.length);
+        if state.advance_commit() {
+            raft.committed.notify_one();
+        }
+        drop(state);
+        for (waiter, reply) in batch.into_iter().filter_map(|q| q.reply) {
+            let _ = waiter.send(reply);
+        }
+    }
+}
+
+// Sends `peer` what the member's role calls for: its vote request, or the
+// log from where it is up to, at least every heartbeat
+async fn replicate_to(raft: Arc<Raft>, peer: String, auth: Vec<String>) {
+    let mut outbox = raft.outbox.subscribe();
+    let heartbeat = raft.election_timeout / 5;
+    let mut conn = None;
+    let mut up = true;
+    loop {
+        if let Some(request) = raft.request_for(&peer) {
+            let args = request_args(&raft.node, &request);
+            match exchange(&mut conn, &peer, &auth, &args, raft.election_timeout).await {
+                Ok(reply) => {
+                    if !up {
+                        println!("Raft: reached {}", peer);
+                        up = true;
+                    }
+                    raft.on_reply(&peer, request, &reply);
+                    if raft.behind(&peer) {
+                        continue;
+                    }
+                }
+                Err(e) => {
+                    if up {
+                        eprintln!("Raft: lost {}: {}", peer, e);
+                        up = false;
+                    }
+                    conn = None;
+                }
+            }
+        }
+        tokio::select! {
+            _ = outbox.changed() => {}
+            _ = tokio::time::sleep(heartbeat) => {}
+        }
+    }
+}
+
+fn request_args(node: &str, request: &Request) -> Vec<Vec<u8>> {
+    let mut args: Vec<String> = Vec::new();
+    match request {
+        Request::Vote { term, last_index, last_term } => {
+            args.extend(["VOTE".to_string(), term.to_string(), node.to_string(), last_index.to_string(), last_term.to_string()]);
+        }
+        Request::Append { term, prev_index, prev_term, commit, .. } => {
+            args.extend(["APPEND".to_string(), term.to_string(), node.to_string(), prev_index.to_string(), prev_term.to_string(), commit.to_string()]);
+        }
+    }
+    let mut args: Vec<Vec<u8>> = ["RAFT".to_string()].into_iter().chain(args).map(String::into_bytes).collect();
+    if let Request::Append { entries, .. } = request {
+        for entry in entries {
+            args.push(entry.term.to_string().into_bytes());
+            args.push(entry.db.to_string().into_bytes());
+            let mut payload = Vec::new();
+            if !entry.args.is_empty() {
+                encode(&entry.args, &mut payload);
+            }
+            args.push(payload);
+        }
+    }
+    args
+}
+
+/// The entries of a RAFT APPEND: term, database and RESP-encoded command
+/// for each, the command empty for a no-op.
+pub(crate) fn decode_entries(args: &[Vec<u8>]) -> Option<Vec<Entry>> {
+    if !args.len().is_multiple_of(3) {
+        return None;
+    }
+    args.chunks(3)
+        .map(|chunk| {
+            let command = match chunk[2].as_slice() {
+                [] => Vec::new(),
+                payload => match AofReader::new(payload).next_entry() {
+                    Ok(Some(AofEntry::Command(args))) => args,
+                    _ => return None,
+                },
+            };
+            Some(Entry { term: number(&chunk[0])?, db: number(&chunk[1])? as usize, args: command })
+        })
+        .collect()
+}
+
+// One request and its reply over the connection to `peer`, connecting (and
+// authenticating) first if need be
+async fn exchange(
+    conn: &mut Option<BufReader<TcpStream>>,
+    peer: &str,
+    auth: &[String],
+    args: &[Vec<u8>],
+    timeout: Duration,
+) -> io::Result<Vec<i64>> {
+    let run = async {
+        if conn.is_none() {
+            let mut stream = BufReader::new(TcpStream::connect(peer).await?);
+            if !auth.is_empty() {
+                let auth: Vec<Vec<u8>> = auth.iter().map(|a| a.as_bytes().to_vec()).collect();
+                call(&mut stream, &auth).await?;
+            }
+            *conn = Some(stream);
+        }
+        let stream = conn.as_mut().expect("connected above");
+        match call(stream, args).await? {
+            RespValue::Array(Some(items)) => items
+                .into_iter()
+                .map(|i| match i {
+                    RespValue::Integer(n) => Ok(n),
+                    other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", other))),
+                })
+                .collect(),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", other))),
+        }
+    };
+    tokio::time::timeout(timeout, run).await.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+async fn call(stream: &mut BufReader<TcpStream>, args: &[Vec<u8>]) -> io::Result<RespValue> {
+    let mut buf = Vec::new();
+    encode(args, &mut buf);
+    stream.get_mut().write_all(&buf).await?;
+    match read_resp(stream).await? {
+        RespValue::Error(e) => Err(io::Error::other(e)),
+        reply => Ok(reply),
+    }
+}
+
+// Applies committed entries in log order, answering the clients that
+// proposed them
+async fn apply_committed(raft: Arc<Raft>, dbs: Arc<[Database]>, settings: Arc<Settings>, registry: Arc<Registry>) {
+    let mut applied = 0;
+    loop {
+        raft.committed.notified().await;
+        loop {
+            let batch = raft.committed_after(applied);
+            let Some((last, _, _)) = batch.last() else { break };
+            applied = *last;
+            for (_, entry, waiter) in batch {
+                let reply = apply(&entry, &dbs, &settings, &registry);
+                if let Some(waiter) = waiter {
+                    let _ = waiter.send(reply);
+                }
+            }
+            raft.lock().applied = applied;
+        }
+    }
+}
+
+// Runs the entry's command with `Registry::call`, below the middleware; see
+// the module doc
+fn apply(entry: &Entry, dbs: &[Database], settings: &Settings, registry: &Registry) -> RespValue {
+    if entry.args.is_empty() {
+        return RespValue::SimpleString("OK".into());
+    }
+    let Some(db) = dbs.get(entry.db) else {
+        return CommandError::generic("no such database").into();
+    };
+    let mut frame: Vec<RespValue> = entry.args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect();
+    let mut internal = ClientState::internal(entry.db);
+    let mut ctx = Context { db, dbs, settings, registry, client: &mut internal, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args: false, slowlog_args: None };
+    registry.call(&mut ctx, &mut frame)
+}
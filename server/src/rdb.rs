@@ -0,0 +1,545 @@
+//! Redis RDB files, for migrating datasets between Redis and RustCache.
+//!
+//! Imports understand RDB versions up to 12 (Redis 7.4). Strings and their
+//! TTLs are loaded; values of the types RustCache does not have (lists,
+//! sets, hashes, sorted sets, streams and module types) are parsed, skipped
+//! and counted. Exports are version 9, which Redis 5.0 and later can load.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crc::Digest;
+
+use crate::db::{Database, SnapshotRecord};
+use crate::snapshot::{begin_all, shared_clock, unix_ms_now, ChecksumWriter, CRC64};
+
+const MAX_VERSION: u32 = 12;
+const EXPORT_VERSION: u32 = 9;
+
+const OP_SLOT_INFO: u8 = 0xF4;
+const OP_FUNCTION2: u8 = 0xF5;
+const OP_MODULE_AUX: u8 = 0xF7;
+const OP_IDLE: u8 = 0xF8;
+const OP_FREQ: u8 = 0xF9;
+const OP_AUX: u8 = 0xFA;
+const OP_RESIZEDB: u8 = 0xFB;
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_EXPIRETIME: u8 = 0xFD;
+const OP_SELECTDB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// What an import did.
+#[derive(Debug, Default)]
+pub(crate) struct RdbImport {
+    pub loaded: usize,
+    /// Keys of unsupported types, keys that are not UTF-8, and keys that
+    /// expired before the import.
+    pub skipped: usize,
+}
+
+/// Loads the strings of a Redis RDB file into `dbs`. The checksum is checked
+/// at the end, so a corrupt file may leave some keys loaded; startup fails
+/// on it either way.
+pub(crate) fn load_rdb(dbs: &[Database], path: &Path) -> io::Result<RdbImport> {
+    read_rdb(dbs, BufReader::new(File::open(path)?))
+}
+
+/// Loads an RDB payload from `input`, as [`load_rdb`] does from a file.
+pub(crate) fn read_rdb(dbs: &[Database], input: impl Read) -> io::Result<RdbImport> {
+    let clock = shared_clock(dbs).reading();
+    let mut import = RdbImport::default();
+    parse_rdb(input, |entry| {
+        let value = match entry.value {
+            Some(v) => v,
+            None => {
+                import.skipped += 1;
+                return Ok(());
+            }
+        };
+        let target = dbs
+            .get(entry.db)
+            .ok_or_else(|| invalid(format!("RDB file uses database {}, which is not configured", entry.db)))?;
+        let key = match String::from_utf8(entry.key) {
+            Ok(k) => k,
+            Err(_) => {
+                import.skipped += 1;
+                return Ok(());
+            }
+        };
+        let ttl = match entry.expires_ms.map(|at| clock.until_unix_ms(at)) {
+            None => None,
+            Some(None) => {
+                import.skipped += 1;
+                return Ok(());
+            }
+            Some(ttl) => ttl,
+        };
+        target.set(key, value, ttl);
+        import.loaded += 1;
+        Ok(())
+    })?;
+    Ok(import)
+}
+
+/// What [`check_rdb`] found in a sound RDB file.
+#[derive(Debug)]
+pub struct RdbCheck {
+    pub version: u32,
+    /// Keys of every type.
+    pub keys: usize,
+    /// Keys of types RustCache would skip on import.
+    pub unsupported: usize,
+    /// The file says its checksum was turned off, so it was not verified.
+    pub unchecked: bool,
+}
+
+/// Parses the Redis RDB file at `path` without loading it. A damaged file
+/// fails with the offset of the first entry that does not parse.
+pub fn check_rdb(path: &Path) -> io::Result<RdbCheck> {
+    let mut check = RdbCheck {
+        version: 0,
+        keys: 0,
+        unsupported: 0,
+        unchecked: false,
+    };
+    let (version, unchecked) = parse_rdb(BufReader::new(File::open(path)?), |entry| {
+        check.keys += 1;
+        check.unsupported += entry.value.is_none() as usize;
+        Ok(())
+    })?;
+    check.version = version;
+    check.unchecked = unchecked;
+    Ok(check)
+}
+
+struct RdbEntry {
+    db: usize,
+    key: Vec<u8>,
+    /// None for the types RustCache does not have.
+    value: Option<Vec<u8>>,
+    expires_ms: Option<i64>,
+}
+
+// Passes every key in an RDB payload to `each`, then checks the checksum.
+// Returns the version and whether the checksum was turned off.
+fn parse_rdb(input: impl Read, mut each: impl FnMut(RdbEntry) -> io::Result<()>) -> io::Result<(u32, bool)> {
+    let mut input = RdbReader {
+        inner: input,
+        digest: CRC64.digest(),
+        offset: 0,
+    };
+    let mut header = [0u8; 9];
+    input.read_exact(&mut header).map_err(|_| invalid("not a Redis RDB file"))?;
+    if &header[..5] != b"REDIS" {
+        return Err(invalid("not a Redis RDB file"));
+    }
+    let version: u32 = std::str::from_utf8(&header[5..])
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid("malformed RDB version"))?;
+    if version == 0 || version > MAX_VERSION {
+        return Err(invalid(format!("RDB version {} is not supported", version)));
+    }
+
+    let mut db = 0usize;
+    let mut expires_ms: Option<i64> = None;
+    loop {
+        let start = input.offset;
+        let done = parse_entry(&mut input, &mut db, &mut expires_ms, &mut each).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(format!("RDB file is truncated in the entry at offset {}", start)),
+            kind => io::Error::new(kind, format!("{} in the entry at offset {}", e, start)),
+        })?;
+        if done {
+            break;
+        }
+    }
+    // Version 5 added the checksum; zero means the writer had it turned off
+    let mut unchecked = true;
+    if version >= 5 {
+        let computed = input.digest.clone().finalize();
+        let stored = u64::from_le_bytes(
+            input
+                .array()
+                .map_err(|_| invalid("RDB file is truncated before its checksum"))?,
+        );
+        if stored != 0 && stored != computed {
+            return Err(invalid("RDB checksum mismatch, the file is corrupt"));
+        }
+        unchecked = stored == 0;
+    }
+    Ok((version, unchecked))
+}
+
+// Reads one opcode and what goes with it; true at the end of the file
+fn parse_entry<R: Read>(
+    input: &mut RdbReader<R>,
+    db: &mut usize,
+    expires_ms: &mut Option<i64>,
+    each: &mut impl FnMut(RdbEntry) -> io::Result<()>,
+) -> io::Result<bool> {
+    match input.u8()? {
+        OP_EOF => return Ok(true),
+        OP_SELECTDB => *db = input.length()? as usize,
+        OP_EXPIRETIME_MS => *expires_ms = Some(i64::from_le_bytes(input.array()?)),
+        OP_EXPIRETIME => *expires_ms = Some(u32::from_le_bytes(input.array()?) as i64 * 1000),
+        OP_RESIZEDB => {
+            input.length()?;
+            input.length()?;
+        }
+        OP_AUX => {
+            input.string()?;
+            input.string()?;
+        }
+        OP_MODULE_AUX => {
+            // Module id, then when it loads (as an opcode and value)
+            for _ in 0..3 {
+                input.length()?;
+            }
+            input.skip_module_value()?;
+        }
+        OP_IDLE => {
+            input.length()?;
+        }
+        OP_FREQ => {
+            input.u8()?;
+        }
+        OP_FUNCTION2 => {
+            input.string()?;
+        }
+        OP_SLOT_INFO => {
+            for _ in 0..3 {
+                input.length()?;
+            }
+        }
+        kind => {
+            let key = input.string()?;
+            let value = if kind == TYPE_STRING {
+                Some(input.string()?)
+            } else {
+                input.skip_value(kind)?;
+                None
+            };
+            each(RdbEntry {
+                db: *db,
+                key,
+                value,
+                expires_ms: expires_ms.take(),
+            })?;
+        }
+    }
+    Ok(false)
+}
+
+/// Writes every database to `path` as a Redis RDB file, from a consistent
+/// snapshot, via a temporary file renamed into place. Returns the number of
+/// keys written.
+pub(crate) fn write_rdb(dbs: &[Database], path: &Path) -> io::Result<usize> {
+    let tmp = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    let total = encode_rdb(dbs, &mut file)?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(total)
+}
+
+/// Writes every database to `out` as an RDB payload, from a consistent
+/// snapshot, as a full resync sends it. Returns the number of keys written.
+pub(crate) fn encode_rdb(dbs: &[Database], out: impl Write) -> io::Result<usize> {
+    let snapshots = begin_all(dbs)?;
+    let mut out = ChecksumWriter::new(out);
+    write!(out, "REDIS{:04}", EXPORT_VERSION)?;
+    out.write_all(&[OP_AUX])?;
+    write_string(&mut out, b"redis-bits")?;
+    write_string(&mut out, b"64")?;
+    out.write_all(&[OP_AUX])?;
+    write_string(&mut out, b"ctime")?;
+    write_string(&mut out, (unix_ms_now() / 1000).to_string().as_bytes())?;
+
+    let clock = shared_clock(dbs).reading();
+    let mut total = 0;
+    for (index, snapshot) in snapshots.into_iter().enumerate() {
+        let mut selected = false;
+        total += snapshot.read(|record: SnapshotRecord| {
+            if !selected {
+                out.write_all(&[OP_SELECTDB])?;
+                write_length(&mut out, index as u64)?;
+                selected = true;
+            }
+            if let Some(at) = record.expires_at {
+                let ms = clock.unix_ms_of(at);
+                out.write_all(&[OP_EXPIRETIME_MS])?;
+                out.write_all(&ms.to_le_bytes())?;
+            }
+            out.write_all(&[TYPE_STRING])?;
+            write_string(&mut out, record.key.as_bytes())?;
+            write_string(&mut out, &record.value)
+        })?;
+    }
+    out.write_all(&[OP_EOF])?;
+    let (mut inner, checksum) = out.finish();
+    inner.write_all(&checksum.to_le_bytes())?;
+    Ok(total)
+}
+
+fn write_length(out: &mut impl Write, len: u64) -> io::Result<()> {
+    if len < 1 << 6 {
+        out.write_all(&[len as u8])
+    } else if len < 1 << 14 {
+        out.write_all(&[0x40 | (len >> 8) as u8, len as u8])
+    } else if len <= u32::MAX as u64 {
+        out.write_all(&[0x80])?;
+        out.write_all(&(len as u32).to_be_bytes())
+    } else {
+        out.write_all(&[0x81])?;
+        out.write_all(&len.to_be_bytes())
+    }
+}
+
+fn write_string(out: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    write_length(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+// Reads RDB primitives, checksumming everything read
+struct RdbReader<R> {
+    inner: R,
+    digest: Digest<'static, u64>,
+    offset: u64,
+}
+
+impl<R: Read> RdbReader<R> {
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read_exact(buf)?;
+        self.digest.update(buf);
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0u8; N];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn bytes(&mut self, len: u64) -> io::Result<Vec<u8>> {
+        // Grown as data arrives, so a corrupt length cannot allocate it all up front
+        let mut buf = Vec::new();
+        (&mut self.inner).take(len).read_to_end(&mut buf)?;
+        if buf.len() as u64 != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        self.digest.update(&buf);
+        self.offset += len;
+        Ok(buf)
+    }
+
+    // A length, or with the flag set, the kind of special string encoding
+    fn raw_length(&mut self) -> io::Result<(u64, bool)> {
+        let first = self.u8()?;
+        Ok(match first >> 6 {
+            0 => ((first & 0x3f) as u64, false),
+            1 => ((((first & 0x3f) as u64) << 8) | self.u8()? as u64, false),
+            2 => match first {
+                0x80 => (u32::from_be_bytes(self.array()?) as u64, false),
+                0x81 => (u64::from_be_bytes(self.array()?), false),
+                _ => return Err(invalid(format!("malformed RDB length byte {:#x}", first))),
+            },
+            _ => ((first & 0x3f) as u64, true),
+        })
+    }
+
+    fn length(&mut self) -> io::Result<u64> {
+        match self.raw_length()? {
+            (len, false) => Ok(len),
+            _ => Err(invalid("RDB string encoding where a length was expected")),
+        }
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        match self.raw_length()? {
+            (len, false) => self.bytes(len),
+            (0, true) => Ok((self.u8()? as i8).to_string().into_bytes()),
+            (1, true) => Ok(i16::from_le_bytes(self.array()?).to_string().into_bytes()),
+            (2, true) => Ok(i32::from_le_bytes(self.array()?).to_string().into_bytes()),
+            (3, true) => {
+                let compressed = self.length()?;
+                let len = self.length()?;
+                let data = self.bytes(compressed)?;
+                lzf_decompress(&data, len as usize)
+            }
+            (kind, true) => Err(invalid(format!("unknown RDB string encoding {}", kind))),
+        }
+    }
+
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        self.bytes(len).map(drop)
+    }
+
+    // Parses past a value of a type RustCache does not store
+    fn skip_value(&mut self, kind: u8) -> io::Result<()> {
+        match kind {
+            // List, set
+            1 | 2 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                }
+            }
+            // Sorted set with scores as strings; 253-255 mean NaN and infinities
+            3 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    let len = self.u8()?;
+                    if len < 253 {
+                        self.skip(len as u64)?;
+                    }
+                }
+            }
+            // Hash
+            4 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.string()?;
+                }
+            }
+            // Sorted set with binary scores
+            5 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                    self.skip(8)?;
+                }
+            }
+            // Module value
+            7 => {
+                self.length()?;
+                self.skip_module_value()?;
+            }
+            // Encodings packed into a single string: zipmap, ziplists, intset, listpacks
+            9..=13 | 16 | 17 | 20 => {
+                self.string()?;
+            }
+            // Quicklist of ziplists
+            14 => {
+                for _ in 0..self.length()? {
+                    self.string()?;
+                }
+            }
+            // Quicklist of listpacks, each with its container kind
+            18 => {
+                for _ in 0..self.length()? {
+                    self.length()?;
+                    self.string()?;
+                }
+            }
+            15 | 19 | 21 => self.skip_stream(kind)?,
+            other => return Err(invalid(format!("unsupported RDB value type {}", other))),
+        }
+        Ok(())
+    }
+
+    fn skip_stream(&mut self, kind: u8) -> io::Result<()> {
+        for _ in 0..self.length()? {
+            self.string()?;
+            self.string()?;
+        }
+        // Length and last id, then first id, max deleted id and entries added
+        let fields = if kind >= 19 { 8 } else { 3 };
+        for _ in 0..fields {
+            self.length()?;
+        }
+        for _ in 0..self.length()? {
+            self.string()?;
+            self.length()?;
+            self.length()?;
+            if kind >= 19 {
+                self.length()?;
+            }
+            // Pending entries: raw id, delivery time, delivery count
+            for _ in 0..self.length()? {
+                self.skip(16 + 8)?;
+                self.length()?;
+            }
+            for _ in 0..self.length()? {
+                self.string()?;
+                // Seen time, then active time from version 3
+                self.skip(if kind >= 21 { 16 } else { 8 })?;
+                for _ in 0..self.length()? {
+                    self.skip(16)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Module data is a list of typed fields ending with opcode 0
+    fn skip_module_value(&mut self) -> io::Result<()> {
+        loop {
+            match self.length()? {
+                0 => return Ok(()),
+                1 | 2 => {
+                    self.length()?;
+                }
+                3 => self.skip(4)?,
+                4 => self.skip(8)?,
+                5 => {
+                    self.string()?;
+                }
+                other => return Err(invalid(format!("unknown RDB module opcode {}", other))),
+            }
+        }
+    }
+}
+
+// The longest back reference, 3 bytes, expands to 264, so nothing
+// decompresses to more than this many times its compressed size
+const LZF_MAX_RATIO: usize = 88;
+
+fn lzf_decompress(input: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let corrupt = || invalid("corrupt LZF compressed string in RDB file");
+    // The stated length comes from the file; a corrupt one must not allocate
+    let mut out = Vec::with_capacity(len.min(input.len().saturating_mul(LZF_MAX_RATIO)));
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let literal = input.get(i..i + ctrl + 1).ok_or_else(corrupt)?;
+            if out.len() + literal.len() > len {
+                return Err(corrupt());
+            }
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(corrupt)? as usize;
+                i += 1;
+            }
+            run += 2;
+            let back = ((ctrl & 0x1f) << 8) + *input.get(i).ok_or_else(corrupt)? as usize + 1;
+            i += 1;
+            let start = out.len().checked_sub(back).ok_or_else(corrupt)?;
+            if out.len() + run > len {
+                return Err(corrupt());
+            }
+            // Byte by byte, since a run may overlap the bytes it produces
+            for k in 0..run {
+                out.push(out[start + k]);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
@@ -0,0 +1,111 @@
+//! Recording client traffic, and answering from a recording.
+//!
+//! With `record-file` set, every command a client sends is appended to the
+//! file with the reply it got, both as RESP, in the order they were
+//! answered. With `replay-file` set the server runs nothing: each command
+//! is answered with the reply recorded for the same command, so a client
+//! library's tests get RustCache's replies without a live dataset. Command
+//! names match whatever their case. A command recorded more than once gets
+//! its replies in recorded order, counted per connection so that every
+//! connection replays from the start, and the last one again once they run
+//! out; one never recorded is answered with an error.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use tokio::io::BufReader;
+
+use crate::error::CommandError;
+use crate::resp::{read_resp, RespValue};
+
+/// Appends commands and their replies to the `record-file`.
+pub(crate) struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /// Starts a recording at `path`, replacing any there.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Ok(Self { file: Mutex::new(file) })
+    }
+
+    pub fn record(&self, command: &RespValue, reply: &RespValue) {
+        let mut buf = Vec::new();
+        command.encode(&mut buf);
+        reply.encode(&mut buf);
+        // One write per pair, so pairs from different connections never interleave
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(&buf) {
+            eprintln!("Recording a command failed: {}", e);
+        }
+    }
+}
+
+/// The replies of a `replay-file`, by command.
+pub(crate) struct Recording {
+    replies: HashMap<Vec<u8>, Vec<RespValue>>,
+}
+
+/// How far a connection has replayed each command.
+pub(crate) type Replayed = HashMap<Vec<u8>, usize>;
+
+impl Recording {
+    pub async fn load(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        let mut reader = BufReader::new(&data[..]);
+        let mut replies: HashMap<Vec<u8>, Vec<RespValue>> = HashMap::new();
+        let mut pairs = 0;
+        loop {
+            let command = match read_resp(&mut reader).await {
+                Ok(command) => command,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            };
+            let reply = read_resp(&mut reader).await.map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: command {} has no reply: {}", path.display(), pairs + 1, e))
+            })?;
+            replies.entry(key(&command)).or_default().push(reply);
+            pairs += 1;
+        }
+        println!("Replaying {} recorded replies from {}", pairs, path.display());
+        Ok(Self { replies })
+    }
+
+    /// The reply recorded for `command`, the next one for this connection.
+    pub fn reply(&self, command: &RespValue, replayed: &mut Replayed) -> RespValue {
+        let key = key(command);
+        let Some(replies) = self.replies.get(&key) else {
+            return CommandError::generic("no reply recorded for this command").into();
+        };
+        let seen = replayed.entry(key).or_default();
+        let reply = replies[(*seen).min(replies.len() - 1)].clone();
+        *seen += 1;
+        reply
+    }
+}
+
+// A command as it is looked up: encoded, with its name lowercased
+fn key(command: &RespValue) -> Vec<u8> {
+    let normalized = match command {
+        RespValue::Array(Some(items)) if !items.is_empty() => {
+            let mut items = items.clone();
+            if let RespValue::BulkString(Some(name)) = &mut items[0] {
+                name.make_ascii_lowercase();
+            }
+            RespValue::Array(Some(items))
+        }
+        other => other.clone(),
+    };
+    let mut buf = Vec::new();
+    normalized.encode(&mut buf);
+    buf
+}
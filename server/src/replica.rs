@@ -0,0 +1,340 @@
+//! Replicating from a Redis (or RustCache-compatible) master, so RustCache
+//! can shadow an existing deployment during a migration.
+//!
+//! The replica connects, authenticates with masterauth, and asks for a
+//! partial resync with PSYNC when it has replicated before, falling back to
+//! the full resync the master chooses otherwise: the RDB payload replaces
+//! every database, then the master's command stream is applied as it
+//! arrives. Commands RustCache does not have (lists, hashes, ...) are skipped
+//! and counted. The link is retried every second after a failure.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::client::ClientState;
+use crate::commands::{Context, Registry};
+use crate::config::Settings;
+use crate::db::Database;
+use crate::rdb::read_rdb;
+use crate::resp::{read_resp, RespValue};
+use crate::snapshot::unix_ms_now;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+type MasterReader = BufReader<OwnedReadHalf>;
+
+/// The replica side of the link, as INFO replication reports it.
+#[derive(Default)]
+pub(crate) struct ReplicaStatus {
+    pub link_up: AtomicBool,
+    pub sync_in_progress: AtomicBool,
+    /// Bytes of the master's replication stream applied so far.
+    pub offset: AtomicI64,
+    /// Unix time in milliseconds of the last data from the master.
+    pub last_io_ms: AtomicI64,
+    pub full_syncs: AtomicU64,
+    /// Replicated commands RustCache could not apply.
+    pub skipped_commands: AtomicU64,
+}
+
+/// Replicates from `master` (`host:port`) into `dbs` until aborted.
+pub(crate) fn start_replication(
+    master: String,
+    dbs: Arc<[Database]>,
+    settings: Arc<Settings>,
+    registry: Arc<Registry>,
+    listening_port: u16,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut link = Link {
+            master,
+            dbs,
+            settings,
+            registry,
+            listening_port,
+            replid: None,
+        };
+        loop {
+            if let Err(e) = link.run().await {
+                eprintln!("Replication from {} failed: {}", link.master, e);
+            }
+            link.status().link_up.store(false, Ordering::Relaxed);
+            link.status().sync_in_progress.store(false, Ordering::Relaxed);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    })
+}
+
+struct Link {
+    master: String,
+    dbs: Arc<[Database]>,
+    settings: Arc<Settings>,
+    registry: Arc<Registry>,
+    listening_port: u16,
+    /// The master's replication id once a sync has completed; with the
+    /// offset, what a partial resync continues from.
+    replid: Option<String>,
+}
+
+impl Link {
+    fn status(&self) -> &ReplicaStatus {
+        &self.dbs[0].stats.replica
+    }
+
+    async fn run(&mut self) -> io::Result<()> {
+        let stream = TcpStream::connect(&self.master).await?;
+        let (read_half, write_half) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+        let writer = Arc::new(Mutex::new(write_half));
+
+        let config = self.settings.current();
+        if let Some(pass) = &config.masterauth {
+            let mut auth = vec!["AUTH"];
+            auth.extend(config.masteruser.as_deref());
+            auth.push(pass);
+            expect_ok(&mut reader, &writer, &auth).await?;
+        }
+        let port = self.listening_port.to_string();
+        handshake(&mut reader, &writer, &["PING"]).await?;
+        expect_ok(&mut reader, &writer, &["REPLCONF", "listening-port", &port]).await?;
+        expect_ok(&mut reader, &writer, &["REPLCONF", "capa", "psync2"]).await?;
+
+        let offset = self.status().offset.load(Ordering::Relaxed);
+        let psync = match &self.replid {
+            Some(id) => vec![id.clone(), (offset + 1).to_string()],
+            None => vec!["?".to_string(), "-1".to_string()],
+        };
+        let reply = match handshake(&mut reader, &writer, &["PSYNC", &psync[0], &psync[1]]).await? {
+            RespValue::SimpleString(s) => s,
+            other => return Err(protocol(format!("unexpected PSYNC reply {:?}", other))),
+        };
+        let mut words = reply.split_whitespace();
+        match words.next() {
+            Some("FULLRESYNC") => {
+                let replid = words.next().ok_or_else(|| protocol("FULLRESYNC without a replication id"))?;
+                let offset: i64 = words
+                    .next()
+                    .and_then(|o| o.parse().ok())
+                    .ok_or_else(|| protocol("FULLRESYNC without an offset"))?;
+                self.replid = None;
+                self.full_sync(&mut reader).await?;
+                self.replid = Some(replid.to_string());
+                self.status().offset.store(offset, Ordering::Relaxed);
+            }
+            Some("CONTINUE") => {
+                // A master that failed over continues under its new id
+                if let Some(id) = words.next() {
+                    self.replid = Some(id.to_string());
+                }
+                println!("Partial resync with {} from offset {}", self.master, offset);
+            }
+            _ => return Err(protocol(format!("unexpected PSYNC reply '{}'", reply))),
+        }
+        self.status().link_up.store(true, Ordering::Relaxed);
+        self.stream(reader, writer).await
+    }
+
+    // Reads the RDB payload and replaces every database with it
+    async fn full_sync(&self, reader: &mut MasterReader) -> io::Result<()> {
+        self.status().sync_in_progress.store(true, Ordering::Relaxed);
+        // The master sends newlines to keep the link alive while it saves
+        let mut line: Vec<u8> = Vec::new();
+        while line.iter().all(|b| b.is_ascii_whitespace()) {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+        let header = String::from_utf8_lossy(&line);
+        let len: u64 = header
+            .trim()
+            .strip_prefix('$')
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| protocol(format!("unexpected RDB header '{}'", header.trim())))?;
+        // Grown as the payload arrives, so a garbled length cannot allocate it all up front
+        let mut payload = Vec::new();
+        if (&mut *reader).take(len).read_to_end(&mut payload).await? as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let dbs = self.dbs.clone();
+        let import = self.dbs[0]
+            .stats
+            .persistence_io
+            .run(move || {
+                for db in dbs.iter() {
+                    db.flushdb();
+                }
+                read_rdb(&dbs, &payload[..])
+            })
+            .await??;
+        self.status().full_syncs.fetch_add(1, Ordering::Relaxed);
+        self.status().sync_in_progress.store(false, Ordering::Relaxed);
+        println!(
+            "Full resync with {}: loaded {} keys ({} skipped)",
+            self.master, import.loaded, import.skipped
+        );
+        Ok(())
+    }
+
+    // Applies the command stream, acknowledging the offset every second
+    async fn stream(
+        &self,
+        mut reader: MasterReader,
+        writer: Arc<Mutex<OwnedWriteHalf>>,
+    ) -> io::Result<()> {
+        let ack_writer = writer.clone();
+        let dbs = self.dbs.clone();
+        let _acks = AbortOnDrop(tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(ACK_INTERVAL).await;
+                let offset = dbs[0].stats.replica.offset.load(Ordering::Relaxed);
+                if send_ack(&ack_writer, offset).await.is_err() {
+                    break;
+                }
+            }
+        }));
+        let mut db = 0usize;
+        let result = loop {
+            let frame = match read_resp(&mut reader).await {
+                Ok(f) => f,
+                Err(e) => break Err(e),
+            };
+            self.status().last_io_ms.store(unix_ms_now(), Ordering::Relaxed);
+            let mut encoded = Vec::new();
+            frame.encode(&mut encoded);
+            let mut args = match frame {
+                RespValue::Array(Some(args)) if !args.is_empty() => args,
+                _ => break Err(protocol("replication stream sent a non-command")),
+            };
+            let name = match &args[0] {
+                RespValue::BulkString(Some(b)) => String::from_utf8_lossy(b).to_ascii_lowercase(),
+                _ => String::new(),
+            };
+            match name.as_str() {
+                "replconf" => {
+                    // GETACK wants the offset before the GETACK itself
+                    let offset = self.status().offset.load(Ordering::Relaxed);
+                    if let Err(e) = send_ack(&writer, offset).await {
+                        break Err(e);
+                    }
+                }
+                "select" => match arg_str(&args, 1).and_then(|n| n.parse::<usize>().ok()) {
+                    Some(n) if n < self.dbs.len() => db = n,
+                    _ => {
+                        eprintln!("Replication selected a database this server does not have; skipping its writes");
+                        db = usize::MAX;
+                    }
+                },
+                "flushall" => {
+                    for d in self.dbs.iter() {
+                        d.flushdb();
+                    }
+                }
+                // Transactions are applied command by command
+                "ping" | "multi" | "exec" => {}
+                _ => self.apply(db, &mut args),
+            }
+            self.status().offset.fetch_add(encoded.len() as i64, Ordering::Relaxed);
+        };
+        result
+    }
+
+    fn apply(&self, db: usize, args: &mut [RespValue]) {
+        let target = match self.dbs.get(db) {
+            Some(d) => d,
+            None => {
+                self.status().skipped_commands.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let mut internal = ClientState::internal(db);
+        let mut ctx = Context {
+            db: target,
+            dbs: &self.dbs,
+            settings: &self.settings,
+            registry: &self.registry,
+            client: &mut internal,
+            block: None,
+            backing: None,
+            deadline: None,
+            proxy: None,
+            io: None,
+            keep_args: false,
+            slowlog_args: None,
+        };
+        if let RespValue::Error(e) = self.registry.call(&mut ctx, args) {
+            self.status().skipped_commands.fetch_add(1, Ordering::Relaxed);
+            eprintln!("Replicated {} not applied: {}", arg_str(args, 0).unwrap_or_default(), e);
+        }
+    }
+}
+
+// Stops the acknowledging task with the link, including when replication
+// itself is aborted
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn protocol(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn arg_str(args: &[RespValue], i: usize) -> Option<String> {
+    match args.get(i)? {
+        RespValue::BulkString(Some(b)) => Some(String::from_utf8_lossy(b).into_owned()),
+        RespValue::SimpleString(s) => Some(s.clone()),
+        _ => None,
+    }
+}
+
+async fn send(writer: &Mutex<OwnedWriteHalf>, args: &[&str]) -> io::Result<()> {
+    let frame = RespValue::Array(Some(
+        args.iter()
+            .map(|a| RespValue::BulkString(Some(a.as_bytes().to_vec())))
+            .collect(),
+    ));
+    let mut buf = Vec::new();
+    frame.encode(&mut buf);
+    writer.lock().await.write_all(&buf).await
+}
+
+async fn send_ack(writer: &Mutex<OwnedWriteHalf>, offset: i64) -> io::Result<()> {
+    send(writer, &["REPLCONF", "ACK", &offset.to_string()]).await
+}
+
+// Sends a handshake command; an error reply fails the attempt
+async fn handshake(
+    reader: &mut MasterReader,
+    writer: &Mutex<OwnedWriteHalf>,
+    args: &[&str],
+) -> io::Result<RespValue> {
+    send(writer, args).await?;
+    match read_resp(reader).await? {
+        RespValue::Error(e) => Err(io::Error::other(format!("master refused {}: {}", args[0], e))),
+        reply => Ok(reply),
+    }
+}
+
+async fn expect_ok(
+    reader: &mut MasterReader,
+    writer: &Mutex<OwnedWriteHalf>,
+    args: &[&str],
+) -> io::Result<()> {
+    match handshake(reader, writer, args).await? {
+        RespValue::SimpleString(s) if s == "OK" => Ok(()),
+        other => Err(protocol(format!("unexpected {} reply {:?}", args[0], other))),
+    }
+}
@@ -0,0 +1,292 @@
+//! Replicas of this server: the master side of replication.
+//!
+//! A replica (RustCache with `replicaof`, or Redis) connects, optionally
+//! sends REPLCONF listening-port, then SYNC or PSYNC, and its connection
+//! becomes a replication stream. There is no backlog to continue from, so
+//! every sync is a full resync: `+FULLRESYNC <replid> <offset>`, an RDB of
+//! every database, then each write as it runs, logged as the AOF logs it.
+//! Replicas acknowledge the offset they have applied with REPLCONF ACK.
+//!
+//! With `min-replicas-to-write` set, writes are refused with NOREPLICAS
+//! unless at least that many replicas have acknowledged within
+//! `min-replicas-max-lag` seconds, so a master cut off from its replicas
+//! stops taking writes a failover would lose.
+
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::aof::{logged_write, namespaced_flush};
+use crate::client::CommandQueue;
+use crate::commands::{flags, CommandSpec, Context, Middleware};
+use crate::db::Database;
+use crate::error::CommandError;
+use crate::rdb::encode_rdb;
+use crate::resp::RespValue;
+use crate::snapshot::unix_ms_now;
+
+// Commands a replica may fall behind by before it is dropped, to resync
+const FEED_CAPACITY: usize = 100_000;
+
+fn encode(args: &[Vec<u8>], out: &mut Vec<u8>) {
+    let frame = RespValue::Array(Some(args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect()));
+    frame.encode(out);
+}
+
+/// A connected replica, as INFO replication lists it.
+struct Replica {
+    addr: Option<SocketAddr>,
+    /// The port it says it serves clients on.
+    listening_port: Option<u16>,
+    feed: mpsc::Sender<Arc<Vec<u8>>>,
+    /// False while its full resync is being sent.
+    online: bool,
+    /// The offset it last acknowledged, and when (Unix ms).
+    ack_offset: i64,
+    ack_ms: i64,
+}
+
+#[derive(Default)]
+struct Streams {
+    next_id: u64,
+    replicas: BTreeMap<u64, Replica>,
+    /// The database the stream last selected.
+    db: Option<usize>,
+}
+
+/// This server's replicas and its replication stream.
+pub(crate) struct Replicas {
+    pub replid: String,
+    /// Bytes of the replication stream produced so far.
+    pub offset: AtomicI64,
+    // Replicas attached, so writes skip the lock when there are none
+    attached: AtomicUsize,
+    streams: Mutex<Streams>,
+}
+
+impl Default for Replicas {
+    fn default() -> Self {
+        let replid: String = (0..3u8).map(|i| format!("{:016x}", RandomState::new().hash_one(i))).collect();
+        Self {
+            replid: replid[..40].to_string(),
+            offset: AtomicI64::new(0),
+            attached: AtomicUsize::new(0),
+            streams: Mutex::new(Streams::default()),
+        }
+    }
+}
+
+/// A replica's line in INFO replication.
+pub(crate) struct ReplicaInfo {
+    pub ip: String,
+    pub port: u16,
+    pub online: bool,
+    pub offset: i64,
+    /// Seconds since its last acknowledgement.
+    pub lag: i64,
+}
+
+impl Replicas {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Streams> {
+        self.streams.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Whether any replica is attached.
+    fn attached(&self) -> bool {
+        self.attached.load(Ordering::Relaxed) > 0
+    }
+
+    /// Sends a write that ran on database `db` to every replica.
+    fn propagate(&self, db: usize, args: &[Vec<u8>]) {
+        if self.attached.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut streams = self.lock();
+        let mut bytes = Vec::new();
+        if streams.db != Some(db) {
+            encode(&[b"SELECT".to_vec(), db.to_string().into_bytes()], &mut bytes);
+            streams.db = Some(db);
+        }
+        encode(args, &mut bytes);
+        self.offset.fetch_add(bytes.len() as i64, Ordering::Relaxed);
+        let bytes = Arc::new(bytes);
+        streams.replicas.retain(|_, replica| match replica.feed.try_send(bytes.clone()) {
+            Ok(()) => true,
+            Err(_) => {
+                eprintln!("Dropping replica {:?}, which fell {} commands behind", replica.addr, FEED_CAPACITY);
+                false
+            }
+        });
+        self.attached.store(streams.replicas.len(), Ordering::Relaxed);
+    }
+
+    // Starts a stream for a new replica, at the current offset
+    fn attach(&self, addr: Option<SocketAddr>, listening_port: Option<u16>) -> (u64, i64, mpsc::Receiver<Arc<Vec<u8>>>) {
+        let (feed, stream) = mpsc::channel(FEED_CAPACITY);
+        let mut streams = self.lock();
+        let id = streams.next_id;
+        streams.next_id += 1;
+        let replica = Replica { addr, listening_port, feed, online: false, ack_offset: 0, ack_ms: unix_ms_now() };
+        streams.replicas.insert(id, replica);
+        // The new stream starts with a SELECT
+        streams.db = None;
+        self.attached.store(streams.replicas.len(), Ordering::Relaxed);
+        (id, self.offset.load(Ordering::Relaxed), stream)
+    }
+
+    fn detach(&self, id: u64) {
+        let mut streams = self.lock();
+        streams.replicas.remove(&id);
+        self.attached.store(streams.replicas.len(), Ordering::Relaxed);
+    }
+
+    // Notes an acknowledgement, or that the resync was sent when `offset`
+    // is None
+    fn acked(&self, id: u64, offset: Option<i64>) {
+        if let Some(replica) = self.lock().replicas.get_mut(&id) {
+            replica.online = true;
+            replica.ack_offset = offset.unwrap_or(replica.ack_offset);
+            replica.ack_ms = unix_ms_now();
+        }
+    }
+
+    /// Online replicas that acknowledged within `max_lag`.
+    pub fn good(&self, max_lag: Duration) -> usize {
+        let now = unix_ms_now();
+        self.lock().replicas.values().filter(|r| r.online && now - r.ack_ms <= max_lag.as_millis() as i64).count()
+    }
+
+    pub fn list(&self) -> Vec<ReplicaInfo> {
+        let now = unix_ms_now();
+        self.lock()
+            .replicas
+            .values()
+            .map(|r| ReplicaInfo {
+                ip: r.addr.map(|a| a.ip().to_string()).unwrap_or_default(),
+                port: r.listening_port.or(r.addr.map(|a| a.port())).unwrap_or(0),
+                online: r.online,
+                offset: r.ack_offset,
+                lag: (now - r.ack_ms) / 1000,
+            })
+            .collect()
+    }
+}
+
+/// Refuses writes while too few replicas are in reach, and streams the
+/// writes that run to the replicas there are.
+pub(crate) struct Replication;
+
+impl Middleware for Replication {
+    fn before(&self, ctx: &mut Context<'_>, cmd: &str, spec: &CommandSpec, _args: &[RespValue]) -> Option<RespValue> {
+        if !spec.has(flags::WRITE) {
+            return None;
+        }
+        if let Some((min, max_lag)) = ctx.settings.min_replicas() {
+            if ctx.db.stats.replicas.good(max_lag) < min {
+                return Some(CommandError::NoReplicas.into());
+            }
+        }
+        if let Some(args) = namespaced_flush(ctx, cmd) {
+            ctx.db.stats.replicas.propagate(ctx.client.db, &args);
+        }
+        None
+    }
+
+    fn reads_args(&self, ctx: &Context<'_>) -> bool {
+        ctx.db.stats.replicas.attached()
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        reply: &RespValue,
+        _elapsed: Duration,
+    ) {
+        // Unkept arguments mean no replica was attached when the command
+        // started; one attaching since gets the write in its sync
+        if !ctx.keep_args || !ctx.db.stats.replicas.attached() {
+            return;
+        }
+        if let Some(args) = logged_write(ctx, cmd, spec, args, reply) {
+            ctx.db.stats.replicas.propagate(ctx.client.db, &args);
+        }
+    }
+}
+
+/// Serves a connection that sent SYNC or PSYNC as a replica until it
+/// closes: the full resync, then the stream, reading its acknowledgements.
+pub(crate) async fn serve_replica<W>(
+    commands: &mut CommandQueue,
+    writer: &mut W,
+    dbs: Arc<[Database]>,
+    addr: Option<SocketAddr>,
+    listening_port: Option<u16>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let replicas = &dbs[0].stats.replicas;
+    // Attached first, so writes made while the snapshot is taken are streamed
+    let (id, offset, mut stream) = replicas.attach(addr, listening_port);
+    let served = async {
+        let snapshot = dbs.clone();
+        let rdb = tokio::task::spawn_blocking(move || {
+            let mut rdb = Vec::new();
+            encode_rdb(&snapshot, &mut rdb).map(|_| rdb)
+        })
+        .await
+        .map_err(io::Error::other)??;
+        writer.write_all(format!("+FULLRESYNC {} {}\r\n${}\r\n", replicas.replid, offset, rdb.len()).as_bytes()).await?;
+        writer.write_all(&rdb).await?;
+        replicas.acked(id, None);
+        println!("Full resync of replica {:?} sent at offset {}", addr, offset);
+        let streaming = async {
+            // Ends when the replica is dropped for falling behind
+            while let Some(bytes) = stream.recv().await {
+                writer.write_all(&bytes).await?;
+            }
+            Ok(())
+        };
+        let acknowledging = async {
+            loop {
+                if let Some(acked) = read_ack(commands).await? {
+                    replicas.acked(id, Some(acked));
+                }
+            }
+        };
+        tokio::select! {
+            res = streaming => res,
+            res = acknowledging => res,
+        }
+    };
+    let result = served.await;
+    replicas.detach(id);
+    result
+}
+
+// Reads what the replica sends, returning the offset of a REPLCONF ACK
+async fn read_ack(commands: &mut CommandQueue) -> io::Result<Option<i64>> {
+    let args = match commands.next().await? {
+        RespValue::Array(Some(args)) => args,
+        _ => return Ok(None),
+    };
+    let word = |i: usize| match args.get(i) {
+        Some(RespValue::BulkString(Some(b))) => String::from_utf8_lossy(b).into_owned(),
+        _ => String::new(),
+    };
+    if word(0).eq_ignore_ascii_case("replconf") && word(1).eq_ignore_ascii_case("ack") {
+        return Ok(word(2).parse().ok());
+    }
+    Ok(None)
+}
@@ -1,9 +1,24 @@
-use tokio::io::{self, AsyncReadExt, BufReader};
+use tokio::io::{self, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::io::AsyncBufReadExt;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 
-#[derive(Debug, Clone)]
+/// Largest bulk string accepted from a peer, matching Redis' proto-max-bulk-len default.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Longest header or simple-string line accepted before the CRLF.
+pub const MAX_LINE_LEN: usize = 64 * 1024;
+/// Deepest array nesting accepted. Commands are flat arrays, so this is generous.
+pub const MAX_DEPTH: usize = 32;
+
+/// Bulk strings at least this long are written to the peer straight from the
+/// value rather than copied into the reply buffer first.
+pub const STREAM_THRESHOLD: usize = 64 * 1024;
+
+// Lengths come straight off the wire, so never reserve more than this up front;
+// buffers grow only as the peer actually sends data.
+const MAX_PREALLOC: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(String),
     Error(String),
@@ -14,6 +29,25 @@ pub enum RespValue {
 
 impl RespValue {
     pub fn encode(&self, out: &mut Vec<u8>) {
+        self.encode_split(out, usize::MAX, &mut Vec::new());
+    }
+
+    /// The size of the encoded value, without encoding it.
+    pub fn encoded_len(&self) -> usize {
+        let digits = |n: usize| n.to_string().len();
+        match self {
+            RespValue::SimpleString(s) | RespValue::Error(s) => s.len() + 3,
+            RespValue::Integer(i) => i.to_string().len() + 3,
+            RespValue::BulkString(None) | RespValue::Array(None) => 5,
+            RespValue::BulkString(Some(bytes)) => digits(bytes.len()) + bytes.len() + 5,
+            RespValue::Array(Some(values)) => digits(values.len()) + 3 + values.iter().map(Self::encoded_len).sum::<usize>(),
+        }
+    }
+
+    // Encodes into `out`, except that bulk strings of `threshold` bytes or
+    // more are left out and listed in `large` with the offset in `out` each
+    // belongs at
+    fn encode_split<'a>(&'a self, out: &mut Vec<u8>, threshold: usize, large: &mut Vec<(usize, &'a [u8])>) {
         match self {
             RespValue::SimpleString(s) => {
                 out.extend_from_slice(b"+");
@@ -36,7 +70,11 @@ impl RespValue {
                     out.extend_from_slice(b"$");
                     out.extend_from_slice(bytes.len().to_string().as_bytes());
                     out.extend_from_slice(b"\r\n");
-                    out.extend_from_slice(bytes);
+                    if bytes.len() >= threshold {
+                        large.push((out.len(), bytes));
+                    } else {
+                        out.extend_from_slice(bytes);
+                    }
                     out.extend_from_slice(b"\r\n");
                 }
             },
@@ -47,7 +85,7 @@ impl RespValue {
                     out.extend_from_slice(values.len().to_string().as_bytes());
                     out.extend_from_slice(b"\r\n");
                     for v in values {
-                        v.encode(out);
+                        v.encode_split(out, threshold, large);
                     }
                 }
             },
@@ -55,12 +93,34 @@ impl RespValue {
     }
 }
 
+/// Writes `value` to `writer`, encoding through `buf` (cleared first). Bulk
+/// strings of [`STREAM_THRESHOLD`] bytes or more go to the writer from the
+/// value itself rather than being copied into `buf`. That saves one copy of a
+/// large reply, not all of them: a command such as GET still copies the value
+/// out of the store into the reply, since the shard stays locked only while
+/// it is read.
+pub async fn write_resp<W: AsyncWrite + Unpin>(writer: &mut W, value: &RespValue, buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    let mut large = Vec::new();
+    value.encode_split(buf, STREAM_THRESHOLD, &mut large);
+    let mut written = 0;
+    for (at, bytes) in large {
+        writer.write_all(&buf[written..at]).await?;
+        writer.write_all(bytes).await?;
+        written = at;
+    }
+    writer.write_all(&buf[written..]).await
+}
+
 async fn read_crlf_line<R: AsyncReadExt + Unpin>(reader: &mut BufReader<R>) -> io::Result<Vec<u8>> {
     let mut buf = Vec::with_capacity(64);
-    reader.read_until(b'\n', &mut buf).await?;
+    (&mut *reader).take(MAX_LINE_LEN as u64 + 2).read_until(b'\n', &mut buf).await?;
     if buf.is_empty() {
         return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "EOF"));
     }
+    if buf.len() > MAX_LINE_LEN + 1 && buf[buf.len() - 1] != b'\n' {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
     if buf.len() < 2 || buf[buf.len() - 2] != b'\r' || buf[buf.len() - 1] != b'\n' {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "line missing CRLF"));
     }
@@ -69,8 +129,11 @@ async fn read_crlf_line<R: AsyncReadExt + Unpin>(reader: &mut BufReader<R>) -> i
 }
 
 async fn read_exact_crlf<R: AsyncReadExt + Unpin>(reader: &mut BufReader<R>, len: usize) -> io::Result<Vec<u8>> {
-    let mut data = vec![0u8; len];
-    reader.read_exact(&mut data).await?;
+    let mut data = Vec::with_capacity(len.min(MAX_PREALLOC));
+    (&mut *reader).take(len as u64).read_to_end(&mut data).await?;
+    if data.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "bulk string truncated"));
+    }
     let mut crlf = [0u8; 2];
     reader.read_exact(&mut crlf).await?;
     if crlf != [b'\r', b'\n'] {
@@ -80,6 +143,10 @@ async fn read_exact_crlf<R: AsyncReadExt + Unpin>(reader: &mut BufReader<R>, len
 }
 
 pub fn read_resp<'a, R: AsyncReadExt + Unpin + Send + 'a>(reader: &'a mut BufReader<R>) -> BoxFuture<'a, io::Result<RespValue>> {
+    read_resp_nested(reader, 0)
+}
+
+fn read_resp_nested<'a, R: AsyncReadExt + Unpin + Send + 'a>(reader: &'a mut BufReader<R>, depth: usize) -> BoxFuture<'a, io::Result<RespValue>> {
     async move {
         let mut prefix = [0u8; 1];
         reader.read_exact(&mut prefix).await?;
@@ -104,6 +171,8 @@ pub fn read_resp<'a, R: AsyncReadExt + Unpin + Send + 'a>(reader: &'a mut BufRea
                 let len: isize = s.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid bulk length"))?;
                 if len < 0 {
                     Ok(RespValue::BulkString(None))
+                } else if len as usize > MAX_BULK_LEN {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bulk length"))
                 } else {
                     let data = read_exact_crlf(reader, len as usize).await?;
                     Ok(RespValue::BulkString(Some(data)))
@@ -115,10 +184,12 @@ pub fn read_resp<'a, R: AsyncReadExt + Unpin + Send + 'a>(reader: &'a mut BufRea
                 let len: isize = s.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid array length"))?;
                 if len < 0 {
                     Ok(RespValue::Array(None))
+                } else if depth >= MAX_DEPTH {
+                    Err(io::Error::new(io::ErrorKind::InvalidData, "arrays nested too deeply"))
                 } else {
-                    let mut items = Vec::with_capacity(len as usize);
+                    let mut items = Vec::with_capacity((len as usize).min(MAX_PREALLOC / std::mem::size_of::<RespValue>()));
                     for _ in 0..len {
-                        let v = read_resp(reader).await?;
+                        let v = read_resp_nested(reader, depth + 1).await?;
                         items.push(v);
                     }
                     Ok(RespValue::Array(Some(items)))
@@ -0,0 +1,721 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::allocator;
+use crate::aof::{load_aof, start_aof_flusher, Aof, AppendOnly};
+use crate::backing::{BackingOps, Tiered};
+use crate::clock::Clock;
+use crate::client::{ClientState, CommandQueue};
+use crate::commands::{Context, Registry};
+use crate::config::{ReloadReport, ServerConfig, Settings};
+use crate::db::{start_expiry_reaper, BlockRequest, Database};
+use crate::defrag::start_defrag;
+use crate::error::CommandError;
+use crate::events::start_events;
+use crate::mirror::{start_mirror, Mirroring};
+use crate::cores::Cores;
+use crate::crdt::{start_crdt, ActiveActive, Crdt};
+use crate::gossip::{start_gossip, Gossip};
+use crate::raft::{start_raft, Consensus, Raft};
+use crate::replicas::{serve_replica, Replication};
+use crate::shadow::{start_shadow, Shadowing};
+use crate::stats::Stats;
+use crate::plugins::load_plugins;
+use crate::proxy::{ProxyClient, ProxyOps, Ring};
+use crate::resp::{write_resp, RespValue};
+use crate::rdb::load_rdb;
+use crate::recording::{Recorder, Recording, Replayed};
+use crate::replica::start_replication;
+use crate::snapshot::load_snapshot;
+use crate::warmup::warm_up;
+use crate::webhook::start_webhooks;
+use crate::write_behind::start_write_behind;
+use crate::health;
+use crate::tls::{self, start_tls_watcher};
+use crate::diagnostics;
+use crate::websocket;
+
+// Bytes in flight between a WebSocket and its client session
+const WEBSOCKET_BUFFER: usize = 64 * 1024;
+
+/// A running server. Dropping the handle stops it too; `shutdown` additionally
+/// waits until the listener and every client connection are closed.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    websocket_addr: Option<SocketAddr>,
+    tls_addr: Option<SocketAddr>,
+    health_addr: Option<SocketAddr>,
+    dbs: Arc<[Database]>,
+    settings: Arc<Settings>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Where the WebSocket listener is bound, if `websocket-addr` is set.
+    pub fn websocket_addr(&self) -> Option<SocketAddr> {
+        self.websocket_addr
+    }
+
+    /// Where the TLS listener is bound, if `tls-addr` is set.
+    pub fn tls_addr(&self) -> Option<SocketAddr> {
+        self.tls_addr
+    }
+
+    /// Where the health probe listener is bound, if `health-addr` is set.
+    pub fn health_addr(&self) -> Option<SocketAddr> {
+        self.health_addr
+    }
+
+    /// Database 0, the one clients start in.
+    pub fn db(&self) -> &Database {
+        &self.dbs[0]
+    }
+
+    /// The logical database SELECT calls `index`.
+    pub fn database(&self, index: usize) -> Option<&Database> {
+        self.dbs.get(index)
+    }
+
+    /// Writes a diagnostic report to the log or `diagnostics-file`, as
+    /// SIGUSR1 does.
+    pub fn dump_diagnostics(&self) -> io::Result<()> {
+        diagnostics::dump(&self.dbs, &self.settings).map(drop)
+    }
+
+    /// Re-reads the config file; see [`ReloadReport`] for what took effect.
+    pub fn reload_config(&self) -> io::Result<ReloadReport> {
+        self.settings.reload()
+    }
+
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        let _ = self.task.await;
+    }
+}
+
+/// Binds `config.addr` and serves clients on the current tokio runtime.
+pub async fn run_server(config: ServerConfig) -> io::Result<ServerHandle> {
+    run_server_with_clock(config, Clock::system()).await
+}
+
+/// Like [`run_server`], keeping time by `clock`: with a
+/// [`ManualClock`](crate::clock::ManualClock) behind it, keys expire only as
+/// a test moves it on.
+pub async fn run_server_with_clock(config: ServerConfig, clock: Clock) -> io::Result<ServerHandle> {
+    let mut registry = Registry::with_builtins();
+    load_plugins(&mut registry, &config.plugins)?;
+    let listener = TcpListener::bind(&config.addr).await?;
+    let local_addr = listener.local_addr()?;
+    let ws_listener = match &config.websocket_addr {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let websocket_addr = ws_listener.as_ref().map(|l| l.local_addr()).transpose()?;
+    if let Some(addr) = websocket_addr {
+        println!("Accepting WebSocket clients on {}", addr);
+    }
+    let tls_listener = match &config.tls_addr {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let tls_addr = tls_listener.as_ref().map(|l| l.local_addr()).transpose()?;
+    if let Some(addr) = tls_addr {
+        println!("Accepting TLS clients on {}", addr);
+    }
+    let health_listener = match &config.health_addr {
+        Some(addr) => Some(TcpListener::bind(addr).await?),
+        None => None,
+    };
+    let health_addr = health_listener.as_ref().map(|l| l.local_addr()).transpose()?;
+    if let Some(addr) = health_addr {
+        println!("Answering health probes on {}", addr);
+    }
+    let stats = Arc::new(Stats::new());
+    if let Some(dir) = &config.overflow_dir {
+        std::fs::create_dir_all(dir)?;
+    }
+    let settings = Arc::new(Settings::new(config.clone())?);
+    let dbs = (0..config.databases)
+        .map(|i| {
+            let mut db = Database::with_stats(stats.clone(), clock.clone()).with_max_idle(settings.max_idle());
+            if config.value_checksums {
+                db = db.with_checksums();
+            }
+            match &config.overflow_dir {
+                Some(dir) => db.with_overflow(&dir.join(format!("db{}.overflow", i))),
+                None => Ok(db),
+            }
+        })
+        .collect::<io::Result<Arc<[Database]>>>()?;
+    // Loading a big file is disk-bound; the runtime's workers stay free
+    let (mut registry, aof) = {
+        let (dbs, settings, config) = (dbs.clone(), settings.clone(), config.clone());
+        stats.persistence_io.run(move || load_data(registry, &dbs, &settings, &config)).await??
+    };
+    let raft = match &config.raft_node {
+        Some(node) => {
+            println!("Raft member {} of a group with {}", node, config.raft_peers.join(", "));
+            let raft = Arc::new(Raft::open(node, &config.raft_peers, &config.raft_file, config.raft_election_timeout)?);
+            let _ = stats.raft.set(raft.clone());
+            registry.add_middleware(Box::new(Consensus));
+            Some(raft)
+        }
+        None => None,
+    };
+    let crdt = config.crdt_id.as_ref().map(|id| {
+        println!("Active-active member {} with {}", id, config.crdt_peers.join(", "));
+        let crdt = Arc::new(Crdt::new(id, &config.crdt_peers, clock.clone()));
+        let _ = stats.crdt.set(crdt.clone());
+        registry.add_middleware(Box::new(ActiveActive));
+        crdt
+    });
+    // Added after loading, so replaying the AOF is not forwarded
+    registry.add_middleware(Box::new(Replication));
+    let mirror = config.mirror_to.clone().map(|target| {
+        println!("Mirroring writes to {}", target);
+        registry.add_middleware(Box::new(Mirroring));
+        start_mirror(target, stats.clone(), config.mirror_queue_size)
+    });
+    let shadow = config.shadow_to.clone().map(|target| {
+        println!("Shadowing {}% of {} to {}", config.shadow_percent, config.shadow_commands.name(), target);
+        registry.add_middleware(Box::new(Shadowing));
+        start_shadow(target, stats.clone())
+    });
+    // Registered after loading too, so the loaded keys are not published
+    let events = config.events_to.clone().map(|sink| {
+        println!("Publishing key events to {}", sink.describe());
+        start_events(sink, config.events_format, &dbs, stats.clone(), config.events_queue_size)
+    });
+    let webhooks = (!config.webhooks.is_empty()).then(|| {
+        for hook in &config.webhooks {
+            println!("Posting key events to webhook {}", hook.describe());
+        }
+        start_webhooks(&config, &dbs, stats.clone())
+    });
+    let write_behind = config.write_behind.clone().map(|target| {
+        println!("Writing database 0 behind to {}", target.describe());
+        start_write_behind(target, &config, &dbs[0], stats.clone())
+    });
+    let registry = Arc::new(registry);
+    if config.cores > 0 {
+        println!("Running single-key commands on {} cores", config.cores);
+        let _ = stats.cores.set(Arc::new(Cores::start(config.cores, dbs.clone(), settings.clone(), registry.clone())?));
+    }
+    for loader in &config.loaders {
+        println!("Loading missed keys matching {} from {}", loader.pattern, loader.store.describe());
+    }
+    let loader_ttl = Some(config.loader_ttl).filter(|ttl| !ttl.is_zero());
+    let tiered = Tiered::new(config.backing_store.clone(), &config.loaders, loader_ttl, config.backing_write_through, stats.clone()).map(Arc::new);
+    let ring = if config.proxy_backends.is_empty() {
+        None
+    } else {
+        println!("Proxying to {} backends: {}", config.proxy_backends.len(), config.proxy_backends.join(", "));
+        Some(Arc::new(Ring::new(config.proxy_backends.clone())))
+    };
+    let recorder = match &config.record_file {
+        Some(path) => {
+            println!("Recording commands and replies to {}", path.display());
+            Some(Arc::new(Recorder::create(path)?))
+        }
+        None => None,
+    };
+    let replay = match &config.replay_file {
+        Some(path) => Some(Arc::new(Recording::load(path).await?)),
+        None => None,
+    };
+    let (shutdown, mut stop) = watch::channel(false);
+
+    let reaper = start_expiry_reaper(dbs.clone(), settings.clone());
+    // Measured once up front, so process accounting applies from the first write
+    allocator::sample_overhead(&dbs);
+    let sampler = allocator::start_sampler(dbs.clone());
+    let defrag = start_defrag(dbs.clone(), settings.clone());
+    let tls_watcher = settings.tls().map(|_| start_tls_watcher(settings.clone()));
+    let aof_flusher = aof.clone().map(|aof| start_aof_flusher(aof, stats.clone()));
+    let replication = settings.current().replicaof.map(|master| {
+        println!("Replicating from {}", master);
+        start_replication(master, dbs.clone(), settings.clone(), registry.clone(), local_addr.port())
+    });
+    let raft = raft.map(|raft| start_raft(raft, dbs.clone(), settings.clone(), registry.clone()));
+    let crdt = crdt.map(|crdt| start_crdt(crdt, settings.clone()));
+    let gossip = config.cluster_enabled.then(|| {
+        let addr = config.cluster_announce_addr.clone().unwrap_or_else(|| local_addr.to_string());
+        let gossip = Arc::new(Gossip::new(&config, addr));
+        println!("Cluster node {} at {}", gossip.id, gossip.addr);
+        let _ = stats.gossip.set(gossip.clone());
+        start_gossip(gossip)
+    });
+    let shared = Shared {
+        dbs: dbs.clone(),
+        settings: settings.clone(),
+        registry,
+        tiered,
+        ring,
+        recorder,
+        replay,
+    };
+    let warmup = match config.warmup_file.clone() {
+        Some(path) => {
+            stats.warmup.begin(&path);
+            println!("Warming up from {}", path.display());
+            let shared = shared.clone();
+            let run = async move { warm_up(&path, &shared.dbs, &shared.settings, &shared.registry, shared.tiered.as_deref()).await };
+            if config.warmup_async {
+                Some(tokio::spawn(run))
+            } else {
+                run.await;
+                None
+            }
+        }
+        None => None,
+    };
+    let task = tokio::spawn(async move {
+        let mut clients = JoinSet::new();
+        loop {
+            tokio::select! {
+                res = listener.accept() => {
+                    let (socket, peer) = match res {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            eprintln!("accept error: {}", e);
+                            continue;
+                        }
+                    };
+                    if !shared.settings.ip_permitted(peer.ip()) {
+                        stats.connection_rejected();
+                        continue;
+                    }
+                    let shared = shared.clone();
+                    println!("connection from {}", peer);
+                    clients.spawn(async move {
+                        let (reader, writer) = socket.into_split();
+                        if let Err(e) = handle_client(reader, writer, Some(peer), shared).await {
+                            eprintln!("client error: {}", e);
+                        }
+                    });
+                }
+                Some(res) = accept_on(ws_listener.as_ref()) => {
+                    let (socket, peer) = match res {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            eprintln!("accept error: {}", e);
+                            continue;
+                        }
+                    };
+                    if !shared.settings.ip_permitted(peer.ip()) {
+                        stats.connection_rejected();
+                        continue;
+                    }
+                    let shared = shared.clone();
+                    println!("WebSocket connection from {}", peer);
+                    clients.spawn(async move {
+                        if let Err(e) = handle_websocket(socket, peer, shared).await {
+                            eprintln!("WebSocket client error: {}", e);
+                        }
+                    });
+                }
+                Some(res) = accept_on(tls_listener.as_ref()) => {
+                    let (socket, peer) = match res {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            eprintln!("accept error: {}", e);
+                            continue;
+                        }
+                    };
+                    if !shared.settings.ip_permitted(peer.ip()) {
+                        stats.connection_rejected();
+                        continue;
+                    }
+                    let shared = shared.clone();
+                    println!("TLS connection from {}", peer);
+                    clients.spawn(async move {
+                        let Some(certs) = shared.settings.tls() else { return };
+                        let stream = match tls::accept(socket, certs).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                eprintln!("TLS handshake with {} failed: {}", peer, e);
+                                return;
+                            }
+                        };
+                        let (reader, writer) = tokio::io::split(stream);
+                        if let Err(e) = handle_client(reader, writer, Some(peer), shared).await {
+                            eprintln!("TLS client error: {}", e);
+                        }
+                    });
+                }
+                Some(res) = accept_on(health_listener.as_ref()) => {
+                    let socket = match res {
+                        Ok((socket, _)) => socket,
+                        Err(e) => {
+                            eprintln!("accept error: {}", e);
+                            continue;
+                        }
+                    };
+                    let shared = shared.clone();
+                    clients.spawn(async move {
+                        if let Err(e) = health::serve(socket, &shared.dbs, &shared.settings).await {
+                            eprintln!("health probe error: {}", e);
+                        }
+                    });
+                }
+                Some(_) = clients.join_next(), if !clients.is_empty() => {}
+                // Fires on an explicit shutdown and when the handle is dropped
+                _ = stop.changed() => break,
+            }
+        }
+        reaper.abort();
+        sampler.abort();
+        defrag.abort();
+        if let Some(tls_watcher) = tls_watcher {
+            tls_watcher.abort();
+        }
+        if let Some(warmup) = warmup {
+            warmup.abort();
+        }
+        if let Some(replication) = replication {
+            replication.abort();
+        }
+        if let Some(raft) = raft {
+            raft.abort();
+        }
+        if let Some(crdt) = crdt {
+            crdt.abort();
+        }
+        if let Some(gossip) = gossip {
+            gossip.abort();
+        }
+        if let Some(cores) = stats.cores.get() {
+            cores.stop();
+        }
+        if let Some(mirror) = mirror {
+            mirror.abort();
+        }
+        if let Some(shadow) = shadow {
+            shadow.abort();
+        }
+        if let Some(events) = events {
+            events.abort();
+        }
+        if let Some(webhooks) = webhooks {
+            webhooks.abort();
+        }
+        clients.shutdown().await;
+        // After the clients, so their last writes are flushed too
+        if let Some(write_behind) = write_behind {
+            write_behind.stop().await;
+        }
+        if let Some(flusher) = aof_flusher {
+            flusher.abort();
+        }
+        if let Some(aof) = aof {
+            let file = aof.clone();
+            if let Err(e) = stats.persistence_io.run(move || file.flush(true)).await.and_then(|flushed| flushed) {
+                eprintln!("Writing {} failed: {}", aof.path().display(), e);
+            }
+        }
+    });
+
+    Ok(ServerHandle {
+        local_addr,
+        websocket_addr,
+        tls_addr,
+        health_addr,
+        dbs,
+        settings,
+        shutdown,
+        task,
+    })
+}
+
+/// Loads the AOF, snapshot or RDB file `config` names into `dbs` and opens
+/// the AOF for appending, logging through `registry`. Runs on the
+/// persistence I/O pool.
+fn load_data(
+    mut registry: Registry,
+    dbs: &[Database],
+    settings: &Arc<Settings>,
+    config: &ServerConfig,
+) -> io::Result<(Registry, Option<Arc<Aof>>)> {
+    let aof_exists = config.appendonly && config.aof_file.exists();
+    if aof_exists {
+        load_aof(dbs, settings, &registry, &config.aof_file, config.aof_load_until)
+            .map_err(|e| io::Error::new(e.kind(), format!("loading {}: {}", config.aof_file.display(), e)))?;
+    } else if let Some(path) = config.snapshot_file.as_ref().filter(|p| p.exists()) {
+        let loaded = load_snapshot(dbs, path)
+            .map_err(|e| io::Error::new(e.kind(), format!("loading {}: {}", path.display(), e)))?;
+        println!("Loaded {} keys from {}", loaded, path.display());
+    } else if let Some(path) = config.rdb_file.as_ref().filter(|p| p.exists()) {
+        let import = load_rdb(dbs, path)
+            .map_err(|e| io::Error::new(e.kind(), format!("importing {}: {}", path.display(), e)))?;
+        println!(
+            "Imported {} keys from {} ({} skipped)",
+            import.loaded,
+            path.display(),
+            import.skipped
+        );
+    }
+    let aof = if config.appendonly {
+        let aof = Arc::new(Aof::open(&config.aof_file, config.appendfsync, config.compression)?);
+        if !aof_exists {
+            // Whatever was loaded from a snapshot starts the new file
+            let logged = aof.write_base(dbs)?;
+            println!("Started {} with {} keys", config.aof_file.display(), logged);
+        }
+        registry.add_middleware(Box::new(AppendOnly(aof.clone())));
+        Some(aof)
+    } else {
+        None
+    };
+    Ok((registry, aof))
+}
+
+/// What every client session shares.
+#[derive(Clone)]
+struct Shared {
+    dbs: Arc<[Database]>,
+    settings: Arc<Settings>,
+    registry: Arc<Registry>,
+    tiered: Option<Arc<Tiered>>,
+    ring: Option<Arc<Ring>>,
+    recorder: Option<Arc<Recorder>>,
+    replay: Option<Arc<Recording>>,
+}
+
+// Never resolves when there is no such listener
+async fn accept_on(listener: Option<&TcpListener>) -> Option<io::Result<(TcpStream, SocketAddr)>> {
+    match listener {
+        Some(l) => Some(l.accept().await),
+        None => std::future::pending().await,
+    }
+}
+
+/// Runs a client session behind a WebSocket: the session reads and writes
+/// RESP over an in-memory pipe, and the socket relays it as messages.
+async fn handle_websocket(socket: TcpStream, peer: SocketAddr, shared: Shared) -> io::Result<()> {
+    let ws = match websocket::accept(socket, &shared.settings).await? {
+        Some(ws) => ws,
+        None => return Ok(()),
+    };
+    let (session, relayed) = io::duplex(WEBSOCKET_BUFFER);
+    let (reader, writer) = io::split(session);
+    let (res, ()) = tokio::join!(handle_client(reader, writer, Some(peer), shared), ws.relay(relayed));
+    res
+}
+
+async fn handle_client<R, W>(reader_half: R, mut writer_half: W, addr: Option<SocketAddr>, shared: Shared) -> io::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let Shared { dbs, settings, registry, tiered, ring, recorder, replay } = shared;
+    let mut proxy = ring.map(ProxyClient::new);
+    let mut client = ClientState::new(&settings, addr);
+    let stats = dbs[0].stats.clone();
+    let mut commands = CommandQueue::start(reader_half, settings.client_queue_depth(), settings.client_queue_bytes(), stats.clone());
+    let entry = stats.client_connected(addr);
+    client.entry = Some(entry.clone());
+    let mut buf = stats.reply_buffers.take();
+    let mut replayed = Replayed::new();
+    loop {
+        // A subscribed connection is sent its messages while it has no
+        // command waiting
+        if let Some(subscriber) = client.subscriber.as_mut() {
+            tokio::select! {
+                message = subscriber.recv() => {
+                    let Some(message) = message else {
+                        eprintln!("Disconnecting {}: its pub/sub queue is full", client.info());
+                        break;
+                    };
+                    let written = write_resp(&mut writer_half, &message, &mut buf).await;
+                    buf.reset();
+                    if let Err(e) = written {
+                        eprintln!("write error: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+                // Waiting only, so the command is taken below
+                _ = commands.ready() => {}
+            }
+        }
+        match commands.next().await {
+            Ok(mut frame) => {
+                let response = if let Some(replay) = &replay {
+                    replay.reply(&frame, &mut replayed)
+                } else {
+                    let db = &dbs[client.db];
+                    // Only database 0 is tiered; the others stay purely in memory
+                    let tiered = tiered.as_deref().filter(|_| client.db == 0);
+                    // Namespaced keys are rewritten after routing would be decided
+                    let core = stats.cores.get().filter(|_| client.namespace().is_none()).and_then(|cores| Some((cores, cores.route(&registry, db, &frame)?)));
+                    let (mut response, backing, forward, io, block) = match core {
+                        Some((cores, core)) => {
+                            let placeholder = ClientState::internal(client.db);
+                            // The frame goes to the core and comes back with the reply
+                            let sent = std::mem::replace(&mut frame, RespValue::Array(None));
+                            let Some(ran) = cores.run(core, sent, std::mem::replace(&mut client, placeholder), recorder.is_some()).await else {
+                                break;
+                            };
+                            (frame, client) = (ran.frame, ran.client);
+                            (ran.reply, None, None, ran.io, ran.block)
+                        }
+                        None => {
+                            let backing = tiered.map(|_| BackingOps::default());
+                            let proxy_ops = proxy.as_ref().map(|_| ProxyOps::default());
+                            // Recorded afterwards, or run again once the backing store answers
+                            let keep_args = recorder.is_some() || backing.is_some();
+                            let mut ctx = Context { db, dbs: &dbs, settings: &settings, registry: &registry, client: &mut client, block: None, backing, deadline: None, proxy: proxy_ops, io: None, keep_args, slowlog_args: None };
+                            let response = registry.dispatch(&mut ctx, &mut frame);
+                            (response, ctx.backing.take(), ctx.proxy.take().and_then(|ops| ops.forward), ctx.io.take(), ctx.block.take())
+                        }
+                    };
+                    if let (Some(proxy), Some(forward)) = (proxy.as_mut(), forward) {
+                        response = proxy.forward(client.db, forward).await;
+                    } else if let Some(job) = io {
+                        response = stats.persistence_io.run(job).await.unwrap_or_else(|e| CommandError::generic(e.to_string()).into());
+                    } else if let Some(block) = block {
+                        response = match serve_blocked(&mut commands, &dbs, &settings, &mut client, &registry, &mut frame, block).await {
+                            Some(r) => r,
+                            None => break,
+                        };
+                    } else if let (Some(tiered), Some(ops)) = (tiered, backing) {
+                        if !ops.misses.is_empty() || !ops.writes.is_empty() {
+                            response = serve_tiered(tiered, &dbs, &settings, &mut client, &registry, &mut frame, ops, response).await;
+                        }
+                    }
+                    // A write sent through the raft log replies once it is applied
+                    if let Some(pending) = client.pending.take() {
+                        response = pending.await.unwrap_or_else(|_| CommandError::generic("the server stopped before the command finished").into());
+                    }
+                    response
+                };
+                if let Some(recorder) = &recorder {
+                    recorder.record(&frame, &response);
+                }
+                // SYNC and PSYNC hand the connection over to replication
+                if client.sync {
+                    if let Err(e) = serve_replica(&mut commands, &mut writer_half, dbs.clone(), addr, client.listening_port).await {
+                        eprintln!("replica {} disconnected: {}", client.info(), e);
+                    }
+                    break;
+                }
+                let mut written = write_resp(&mut writer_half, &response, &mut buf).await;
+                buf.reset();
+                for reply in std::mem::take(&mut client.extra_replies) {
+                    if written.is_ok() {
+                        written = write_resp(&mut writer_half, &reply, &mut buf).await;
+                        buf.reset();
+                    }
+                }
+                if let Err(e) = written {
+                    eprintln!("write error: {}", e);
+                    break;
+                }
+            }
+            Err(e) => {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    eprintln!("connection error: {}", e);
+                }
+                break;
+            }
+        }
+    }
+    stats.client_disconnected(entry.id);
+    Ok(())
+}
+
+/// Parks the client until the blocked command succeeds or times out. Returns
+/// None if the client disconnects while waiting.
+async fn serve_blocked(
+    commands: &mut CommandQueue,
+    dbs: &[Database],
+    settings: &Settings,
+    client: &mut ClientState,
+    registry: &Registry,
+    frame: &mut RespValue,
+    block: BlockRequest,
+) -> Option<RespValue> {
+    // Blocking commands never change the selected database
+    let db = &dbs[client.db];
+    let deadline = block.timeout.map(|t| db.clock().now() + t);
+    let blocked = db.blocked().block(&block.keys);
+    let mut watch_disconnect = true;
+    loop {
+        // Retry before sleeping: a write may have landed between the handler's
+        // check and joining the queue, and its signal would be lost otherwise.
+        let mut ctx = Context { db, dbs, settings, registry, client: &mut *client, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args: true, slowlog_args: None };
+        let response = registry.retry(&mut ctx, frame);
+        if ctx.block.is_none() {
+            blocked.served();
+            return Some(response);
+        }
+        tokio::select! {
+            woken = blocked.wait(deadline) => {
+                if !woken {
+                    let mut ctx = Context { db, dbs, settings, registry, client, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args: true, slowlog_args: None };
+                    return Some(registry.time_out(&mut ctx, frame, block.timeout_reply));
+                }
+            }
+            open = commands.ready(), if watch_disconnect => {
+                if !open {
+                    return None;
+                }
+                // Pipelined commands wait their turn; stop polling so we don't spin on them
+                watch_disconnect = false;
+            }
+        }
+    }
+}
+
+/// Finishes a command against the backing store: forwards its writes, then
+/// loads what it missed and runs it again so the reply includes them.
+#[allow(clippy::too_many_arguments)]
+async fn serve_tiered(
+    tiered: &Tiered,
+    dbs: &[Database],
+    settings: &Settings,
+    client: &mut ClientState,
+    registry: &Registry,
+    frame: &mut RespValue,
+    ops: BackingOps,
+    response: RespValue,
+) -> RespValue {
+    let db = &dbs[0];
+    let invoke = |mut args: Vec<RespValue>| {
+        let mut internal = ClientState::internal(0);
+        let mut ctx = Context { db, dbs, settings, registry, client: &mut internal, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args: false, slowlog_args: None };
+        registry.call(&mut ctx, &mut args)
+    };
+    if tiered.write_through && !ops.writes.is_empty() {
+        let keys: Vec<String> = ops.writes.iter().map(|w| w.key().to_string()).collect();
+        if let Err(e) = tiered.write(ops.writes, &invoke).await {
+            // The store is the source of truth; drop what it didn't accept
+            db.del(&keys);
+            return CommandError::generic(format!("backing store: {}", e)).into();
+        }
+    }
+    if ops.misses.is_empty() {
+        return response;
+    }
+    for key in ops.misses {
+        match tiered.load(&key, &invoke).await {
+            Ok(Some(value)) => {
+                tiered.store_loaded(db, key, value);
+            }
+            Ok(None) => {}
+            Err(e) => return CommandError::generic(format!("backing store: {}", e)).into(),
+        }
+    }
+    let mut ctx = Context { db, dbs, settings, registry, client, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args: true, slowlog_args: None };
+    registry.dispatch(&mut ctx, frame)
+}
@@ -0,0 +1,233 @@
+//! Shadow traffic, for trying a new version or configuration on production
+//! load.
+//!
+//! With `shadow-to` set, `shadow-percent` of the commands clients send here
+//! (reads, writes or both, per `shadow-commands`) are sent again to the
+//! shadow target after they have been answered. The target's replies are
+//! discarded; what is kept is how long it took next to how long this server
+//! took, and how often it failed where this server did not or the other way
+//! round. Shadowing never holds up a client: commands wait in a bounded
+//! queue and are dropped, and counted, when it is full.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::commands::{flags, CommandSpec, Context, Middleware};
+use crate::resp::{read_resp, RespValue};
+use crate::stats::Stats;
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+// Commands waiting for the shadow before new ones are dropped
+const QUEUE_LIMIT: usize = 10_000;
+
+/// Which commands are shadowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShadowCommands {
+    Reads,
+    Writes,
+    All,
+}
+
+impl ShadowCommands {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "reads" => Some(Self::Reads),
+            "writes" => Some(Self::Writes),
+            "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Reads => "reads",
+            Self::Writes => "writes",
+            Self::All => "all",
+        }
+    }
+
+    fn covers(&self, spec: &CommandSpec) -> bool {
+        if spec.has(flags::ADMIN) {
+            return false;
+        }
+        match self {
+            Self::Reads => spec.has(flags::READONLY),
+            Self::Writes => spec.has(flags::WRITE),
+            Self::All => spec.has(flags::READONLY) || spec.has(flags::WRITE),
+        }
+    }
+}
+
+/// Commands waiting for the shadow, and how it compares, as INFO stats
+/// reports it.
+#[derive(Default)]
+pub(crate) struct ShadowQueue {
+    queue: Mutex<VecDeque<Shadowed>>,
+    ready: Notify,
+    /// Commands considered for shadowing, for spreading the sample evenly.
+    seen: AtomicU64,
+    pub link_up: AtomicBool,
+    /// Commands the shadow has answered.
+    pub sent: AtomicU64,
+    /// Commands left out because the queue was full.
+    pub dropped: AtomicU64,
+    /// Commands the shadow failed while this server did not.
+    pub shadow_only_errors: AtomicU64,
+    /// Commands this server failed while the shadow did not.
+    pub primary_only_errors: AtomicU64,
+    /// Total time, in microseconds, the shadowed commands took here and on
+    /// the shadow.
+    pub primary_us: AtomicU64,
+    pub shadow_us: AtomicU64,
+}
+
+struct Shadowed {
+    db: usize,
+    args: Vec<Vec<u8>>,
+    failed: bool,
+    took: Duration,
+}
+
+impl ShadowQueue {
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Shadowed>> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Mean time a shadowed command took here and on the shadow, in
+    /// microseconds.
+    pub fn mean_latencies(&self) -> (u64, u64) {
+        let sent = self.sent.load(Ordering::Relaxed).max(1);
+        (
+            self.primary_us.load(Ordering::Relaxed) / sent,
+            self.shadow_us.load(Ordering::Relaxed) / sent,
+        )
+    }
+
+    // Whether this command is in the `percent` sampled; every 100 in a row
+    // have exactly `percent` chosen
+    fn sampled(&self, percent: u32) -> bool {
+        let n = self.seen.fetch_add(1, Ordering::Relaxed);
+        let percent = percent as u64;
+        n * percent / 100 != (n + 1) * percent / 100
+    }
+
+    fn push(&self, command: Shadowed) {
+        let mut queue = self.lock();
+        if queue.len() >= QUEUE_LIMIT {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push_back(command);
+        drop(queue);
+        self.ready.notify_one();
+    }
+
+    async fn next(&self) -> Shadowed {
+        loop {
+            let notified = self.ready.notified();
+            if let Some(command) = self.lock().pop_front() {
+                return command;
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Queues the sampled share of clients' commands for the shadow target.
+pub(crate) struct Shadowing;
+
+impl Middleware for Shadowing {
+    fn reads_args(&self, ctx: &Context<'_>) -> bool {
+        ctx.settings.shadowing().0 > 0
+    }
+
+    fn after(
+        &self,
+        ctx: &mut Context<'_>,
+        cmd: &str,
+        spec: &CommandSpec,
+        args: &[RespValue],
+        reply: &RespValue,
+        elapsed: Duration,
+    ) {
+        let (percent, commands) = ctx.settings.shadowing();
+        let shadow = &ctx.db.stats.shadow;
+        // Shadowing was off when the command started, so its values may be gone
+        if percent == 0 || !ctx.keep_args || !commands.covers(spec) || !shadow.sampled(percent) {
+            return;
+        }
+        let mut frame = vec![cmd.to_ascii_uppercase().into_bytes()];
+        frame.extend(args.iter().map(|a| match a {
+            RespValue::BulkString(Some(b)) => b.clone(),
+            RespValue::SimpleString(s) => s.clone().into_bytes(),
+            RespValue::Integer(n) => n.to_string().into_bytes(),
+            _ => Vec::new(),
+        }));
+        shadow.push(Shadowed {
+            db: ctx.client.db,
+            args: frame,
+            failed: matches!(reply, RespValue::Error(_)),
+            took: elapsed,
+        });
+    }
+}
+
+/// Replays queued commands against `target` (`host:port`) until aborted.
+pub(crate) fn start_shadow(target: String, stats: Arc<Stats>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let shadow = &stats.shadow;
+        loop {
+            if let Err(e) = replay(&target, shadow).await {
+                eprintln!("Shadowing to {} failed: {}", target, e);
+            }
+            shadow.link_up.store(false, Ordering::Relaxed);
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    })
+}
+
+// Sends queued commands one at a time, so each is timed on its own, until
+// the connection fails. The command in hand when it does is lost.
+async fn replay(target: &str, shadow: &ShadowQueue) -> io::Result<()> {
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(target))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+    let mut conn = BufReader::new(stream);
+    shadow.link_up.store(true, Ordering::Relaxed);
+    let mut selected = 0;
+    loop {
+        let command = shadow.next().await;
+        if command.db != selected {
+            send(&mut conn, &[b"SELECT".to_vec(), command.db.to_string().into_bytes()]).await?;
+            selected = command.db;
+        }
+        let start = Instant::now();
+        let failed = matches!(send(&mut conn, &command.args).await?, RespValue::Error(_));
+        let took = start.elapsed();
+        match (command.failed, failed) {
+            (false, true) => shadow.shadow_only_errors.fetch_add(1, Ordering::Relaxed),
+            (true, false) => shadow.primary_only_errors.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        shadow.primary_us.fetch_add(command.took.as_micros() as u64, Ordering::Relaxed);
+        shadow.shadow_us.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+        shadow.sent.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+async fn send(conn: &mut BufReader<TcpStream>, args: &[Vec<u8>]) -> io::Result<RespValue> {
+    let frame = args.iter().map(|a| RespValue::BulkString(Some(a.clone()))).collect();
+    let mut buf = Vec::new();
+    RespValue::Array(Some(frame)).encode(&mut buf);
+    conn.get_mut().write_all(&buf).await?;
+    read_resp(conn).await
+}
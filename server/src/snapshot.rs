@@ -0,0 +1,323 @@
+//! Snapshot files.
+//!
+//! A snapshot is the magic bytes `RCSNAP`, a little-endian `u16` format
+//! version, a sequence of records and an end marker:
+//!
+//! ```text
+//! record: [u8 type][u32 payload len][payload]
+//! end:    [u8 0xFF][u64 CRC-64 of every byte before it, including the 0xFF]
+//! ```
+//!
+//! Every record carries its length, so a reader skips record types it does
+//! not know; new kinds of data can be added without bumping the version.
+//! The version only changes when old readers could no longer make sense of
+//! the file.
+//!
+//! With `persistence-compression zstd` all of that is written through zstd;
+//! loading detects either kind.
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crc::{Crc, Digest, CRC_64_REDIS};
+
+use crate::clock::Clock;
+use crate::compression::{open_decoded, Compression, FileWriter};
+use crate::db::{Database, Snapshot, SnapshotRecord};
+
+const MAGIC: &[u8] = b"RCSNAP";
+const FORMAT_VERSION: u16 = 1;
+
+/// `[u32 db][u32 key len][key][u32 value len][value][i64 expiry in unix ms, or -1]`
+const RECORD_STRING: u8 = 0x01;
+const RECORD_END: u8 = 0xFF;
+
+pub(crate) static CRC64: Crc<u64> = Crc::<u64>::new(&CRC_64_REDIS);
+
+pub(crate) fn unix_ms_now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Keeps a CRC-64 (the Jones polynomial Redis uses) of everything written
+/// through it.
+pub(crate) struct ChecksumWriter<W> {
+    inner: W,
+    digest: Digest<'static, u64>,
+}
+
+impl<W> ChecksumWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            digest: CRC64.digest(),
+        }
+    }
+
+    /// Returns the writer and the checksum of what went through it.
+    pub fn finish(self) -> (W, u64) {
+        (self.inner, self.digest.finalize())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn write_record(out: &mut impl Write, kind: u8, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&[kind])?;
+    out.write_all(&(payload.len() as u32).to_le_bytes())?;
+    out.write_all(payload)
+}
+
+/// Begins a snapshot of every database. They all begin before any is read,
+/// so the databases agree on the moment.
+pub(crate) fn begin_all(dbs: &[Database]) -> io::Result<Vec<Snapshot>> {
+    dbs.iter()
+        .map(|db| db.begin_snapshot())
+        .collect::<Option<_>>()
+        .ok_or_else(|| io::Error::other("a snapshot is already in progress"))
+}
+
+/// The clock the databases of one server share.
+pub(crate) fn shared_clock(dbs: &[Database]) -> Clock {
+    dbs.first().map(|db| db.clock().clone()).unwrap_or_default()
+}
+
+/// Writes a point-in-time copy of every database to `path`, via a temporary
+/// file renamed into place. Writes keep being served throughout. Returns the
+/// number of keys written.
+pub(crate) fn write_snapshot(dbs: &[Database], path: &Path, compression: Compression) -> io::Result<usize> {
+    let snapshots = begin_all(dbs)?;
+    let tmp = path.with_extension("tmp");
+    let mut out = ChecksumWriter::new(FileWriter::create(&tmp, compression)?);
+    out.write_all(MAGIC)?;
+    out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    let clock = shared_clock(dbs).reading();
+    let mut payload = Vec::new();
+    let mut total = 0;
+    for (index, snapshot) in snapshots.into_iter().enumerate() {
+        total += snapshot.read(|record: SnapshotRecord| {
+            let expires_ms = match record.expires_at {
+                Some(at) => clock.unix_ms_of(at),
+                None => -1,
+            };
+            payload.clear();
+            payload.extend_from_slice(&(index as u32).to_le_bytes());
+            payload.extend_from_slice(&(record.key.len() as u32).to_le_bytes());
+            payload.extend_from_slice(record.key.as_bytes());
+            payload.extend_from_slice(&(record.value.len() as u32).to_le_bytes());
+            payload.extend_from_slice(&record.value);
+            payload.extend_from_slice(&expires_ms.to_le_bytes());
+            write_record(&mut out, RECORD_STRING, &payload)
+        })?;
+    }
+    out.write_all(&[RECORD_END])?;
+    let (mut inner, checksum) = out.finish();
+    inner.write_all(&checksum.to_le_bytes())?;
+    inner.finish()?.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(total)
+}
+
+/// Loads a snapshot written by `write_snapshot`, skipping keys that expired
+/// in the meantime. The checksum is verified before anything is loaded, so
+/// a corrupt file leaves the databases untouched. Returns the number of keys
+/// loaded.
+pub(crate) fn load_snapshot(dbs: &[Database], path: &Path) -> io::Result<usize> {
+    verify_checksum(open_decoded(path)?)?;
+    let clock = shared_clock(dbs).reading();
+    let mut loaded = 0;
+    read_records(open_decoded(path)?, |record| {
+        let db = dbs
+            .get(record.db)
+            .ok_or_else(|| invalid("snapshot has more databases than configured"))?;
+        let ttl = match record.expires_ms {
+            -1 => None,
+            at => match clock.until_unix_ms(at) {
+                Some(ttl) => Some(ttl),
+                None => return Ok(()),
+            },
+        };
+        db.set(record.key, record.value, ttl);
+        loaded += 1;
+        Ok(())
+    })?;
+    Ok(loaded)
+}
+
+/// What [`check_snapshot`] found in a sound snapshot.
+#[derive(Debug)]
+pub struct SnapshotCheck {
+    pub version: u16,
+    pub keys: usize,
+    /// The highest database a key is stored in.
+    pub max_db: Option<usize>,
+}
+
+/// Reads the snapshot at `path` without loading it: every record is parsed,
+/// then the checksum verified. A damaged file fails with the offset of the
+/// first record that does not parse; offsets of a compressed snapshot count
+/// uncompressed bytes.
+pub fn check_snapshot(path: &Path) -> io::Result<SnapshotCheck> {
+    let mut keys = 0;
+    let mut max_db = None;
+    let version = read_records(open_decoded(path)?, |record| {
+        keys += 1;
+        max_db = max_db.max(Some(record.db));
+        Ok(())
+    })?;
+    verify_checksum(open_decoded(path)?)?;
+    Ok(SnapshotCheck { version, keys, max_db })
+}
+
+struct StringRecord {
+    db: usize,
+    key: String,
+    value: Vec<u8>,
+    /// Unix ms, or -1 for none.
+    expires_ms: i64,
+}
+
+// Parses the header and passes every string record to `each`, skipping
+// record types from newer versions. Returns the format version.
+fn read_records(input: impl Read, mut each: impl FnMut(StringRecord) -> io::Result<()>) -> io::Result<u16> {
+    let mut input = Counting { inner: input, read: 0 };
+    let mut header = [0u8; MAGIC.len() + 2];
+    input.read_exact(&mut header).map_err(|_| invalid("not a RustCache snapshot"))?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(invalid("not a RustCache snapshot"));
+    }
+    let version = u16::from_le_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]]);
+    if version > FORMAT_VERSION {
+        return Err(invalid(format!(
+            "snapshot format version {} is newer than the supported {}",
+            version, FORMAT_VERSION
+        )));
+    }
+
+    loop {
+        let start = input.read;
+        let done = match read_record(&mut input) {
+            Ok(None) => Ok(true),
+            Ok(Some(record)) => each(record).map(|_| false),
+            Err(e) => Err(e),
+        }
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(format!("snapshot is truncated in the record at offset {}", start)),
+            kind => io::Error::new(kind, format!("{} in the record at offset {}", e, start)),
+        })?;
+        if done {
+            return Ok(version);
+        }
+    }
+}
+
+// The next string record, or None at the end marker
+fn read_record(input: &mut impl Read) -> io::Result<Option<StringRecord>> {
+    loop {
+        let mut kind = [0u8; 1];
+        input.read_exact(&mut kind)?;
+        if kind[0] == RECORD_END {
+            return Ok(None);
+        }
+        let len = read_u32(input)? as u64;
+        if kind[0] != RECORD_STRING {
+            // Written by a newer version; skip it
+            let skipped = io::copy(&mut input.take(len), &mut io::sink())?;
+            if skipped != len {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            continue;
+        }
+        let mut payload = Vec::new();
+        if input.take(len).read_to_end(&mut payload)? as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut record = payload.as_slice();
+        let db = read_u32(&mut record)? as usize;
+        let key = String::from_utf8(read_chunk(&mut record)?).map_err(|_| invalid("snapshot key is not UTF-8"))?;
+        let value = read_chunk(&mut record)?;
+        let mut expiry = [0u8; 8];
+        record.read_exact(&mut expiry).map_err(|_| invalid("record is shorter than its contents"))?;
+        return Ok(Some(StringRecord {
+            db,
+            key,
+            value,
+            expires_ms: i64::from_le_bytes(expiry),
+        }));
+    }
+}
+
+// Counts the bytes read through it, for error offsets
+struct Counting<R> {
+    inner: R,
+    read: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        Ok(n)
+    }
+}
+
+// Checks the trailing CRC-64 against the rest of the file. The length of a
+// compressed file is not known up front, so the last 8 bytes read are held
+// back from the digest until more arrive.
+fn verify_checksum(mut input: impl Read) -> io::Result<()> {
+    let mut digest = CRC64.digest();
+    let mut buf = vec![0u8; 64 * 1024];
+    let (mut held, mut total) = (0usize, 0u64);
+    loop {
+        let n = input.read(&mut buf[held..])?;
+        if n == 0 {
+            break;
+        }
+        held += n;
+        total += n as u64;
+        if held > 8 {
+            digest.update(&buf[..held - 8]);
+            buf.copy_within(held - 8..held, 0);
+            held = 8;
+        }
+    }
+    if total < (MAGIC.len() + 2 + 1 + 8) as u64 {
+        return Err(invalid("snapshot is truncated"));
+    }
+    let stored: [u8; 8] = buf[..8].try_into().unwrap();
+    if digest.finalize() != u64::from_le_bytes(stored) {
+        return Err(invalid("snapshot checksum mismatch, the file is corrupt"));
+    }
+    Ok(())
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut word = [0u8; 4];
+    input.read_exact(&mut word)?;
+    Ok(u32::from_le_bytes(word))
+}
+
+// A length-prefixed chunk of a record's payload
+fn read_chunk(record: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = read_u32(record).map_err(|_| invalid("record is shorter than its contents"))? as usize;
+    if len > record.len() {
+        return Err(invalid("record is shorter than its contents"));
+    }
+    let (chunk, rest) = record.split_at(len);
+    *record = rest;
+    Ok(chunk.to_vec())
+}
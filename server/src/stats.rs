@@ -0,0 +1,188 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::client::{ClientEntry, ClientList};
+use crate::cores::Cores;
+use crate::crdt::Crdt;
+use crate::gossip::Gossip;
+
+use crate::defrag::DefragStats;
+use crate::warmup::Warmup;
+use crate::hotkeys::HotKeys;
+use crate::io_pool::IoPool;
+use crate::latency::LatencyMonitor;
+use crate::events::EventQueue;
+use crate::mirror::MirrorQueue;
+use crate::shadow::ShadowQueue;
+use crate::pool::BufferPool;
+use crate::pubsub::PubSub;
+use crate::replica::ReplicaStatus;
+use crate::raft::Raft;
+use crate::replicas::Replicas;
+use crate::webhook::WebhookStats;
+use crate::write_behind::WriteBehindQueue;
+
+pub struct Stats {
+    pub(crate) started_at: Instant,
+    pub(crate) connected_clients: AtomicUsize,
+    pub(crate) total_connections_received: AtomicU64,
+    /// Connections closed on accept by `ip-allow` or `ip-deny`.
+    pub(crate) rejected_connections_ip: AtomicU64,
+    pub(crate) total_commands_processed: AtomicU64,
+    pub(crate) keyspace_hits: AtomicU64,
+    pub(crate) keyspace_misses: AtomicU64,
+    pub(crate) evicted_keys: AtomicU64,
+    /// Writes refused with OOM because their database was over budget.
+    pub(crate) oom_rejected_commands: AtomicU64,
+    /// Values found not to match their checksum, on read or save.
+    pub(crate) corrupt_values: AtomicU64,
+    /// Times a connection's read-ahead queue filled up and the server
+    /// stopped reading its socket until a command finished.
+    pub(crate) client_queue_stalls: AtomicU64,
+    /// Misses loaded from the backing store or a loader, and misses that
+    /// joined a load of the same key already in flight.
+    pub(crate) backing_loads: AtomicU64,
+    pub(crate) backing_loads_shared: AtomicU64,
+    pub(crate) bgsave_in_progress: AtomicBool,
+    pub(crate) last_save_ok: AtomicBool,
+    /// Whether the last append to or flush of the AOF worked.
+    pub(crate) aof_last_write_ok: AtomicBool,
+    /// Unix time of the last successful save.
+    pub(crate) last_save_time: AtomicU64,
+    pub(crate) hotkeys: HotKeys,
+    pub(crate) latency: LatencyMonitor,
+    pub(crate) replica: ReplicaStatus,
+    /// This server's own replicas.
+    pub(crate) replicas: Replicas,
+    /// This member of the raft group, in raft mode.
+    pub(crate) raft: OnceLock<Arc<Raft>>,
+    /// This member's CRDT state, in active-active mode.
+    pub(crate) crdt: OnceLock<Arc<Crdt>>,
+    /// This node's view of the cluster, when it started with cluster-enabled.
+    pub(crate) gossip: OnceLock<Arc<Gossip>>,
+    /// The core threads, when commands run thread-per-core.
+    pub(crate) cores: OnceLock<Arc<Cores>>,
+    pub(crate) mirror: MirrorQueue,
+    pub(crate) shadow: ShadowQueue,
+    pub(crate) events: EventQueue,
+    pub(crate) webhooks: WebhookStats,
+    pub(crate) write_behind: WriteBehindQueue,
+    pub(crate) clients: ClientList,
+    pub(crate) pubsub: PubSub,
+    pub(crate) reply_buffers: BufferPool,
+    /// Where saves, AOF fsyncs and loads run.
+    pub(crate) persistence_io: IoPool,
+    /// Bytes the allocator held beyond the datasets' estimated size, and
+    /// that size, when last measured.
+    pub(crate) memory_overhead: AtomicUsize,
+    pub(crate) sampled_dataset: AtomicUsize,
+    pub(crate) defrag: DefragStats,
+    pub(crate) warmup: Warmup,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            connected_clients: AtomicUsize::new(0),
+            total_connections_received: AtomicU64::new(0),
+            rejected_connections_ip: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+            oom_rejected_commands: AtomicU64::new(0),
+            corrupt_values: AtomicU64::new(0),
+            client_queue_stalls: AtomicU64::new(0),
+            backing_loads: AtomicU64::new(0),
+            backing_loads_shared: AtomicU64::new(0),
+            bgsave_in_progress: AtomicBool::new(false),
+            last_save_ok: AtomicBool::new(true),
+            aof_last_write_ok: AtomicBool::new(true),
+            last_save_time: AtomicU64::new(unix_now()),
+            hotkeys: HotKeys::new(),
+            latency: LatencyMonitor::new(),
+            replica: ReplicaStatus::default(),
+            replicas: Replicas::default(),
+            raft: OnceLock::new(),
+            crdt: OnceLock::new(),
+            gossip: OnceLock::new(),
+            cores: OnceLock::new(),
+            mirror: MirrorQueue::default(),
+            events: EventQueue::default(),
+            webhooks: WebhookStats::default(),
+            write_behind: WriteBehindQueue::default(),
+            shadow: ShadowQueue::default(),
+            clients: ClientList::new(),
+            pubsub: PubSub::default(),
+            reply_buffers: BufferPool::default(),
+            persistence_io: IoPool::default(),
+            memory_overhead: AtomicUsize::new(0),
+            sampled_dataset: AtomicUsize::new(0),
+            defrag: DefragStats::default(),
+            warmup: Warmup::default(),
+        }
+    }
+
+    pub(crate) fn client_connected(&self, addr: Option<SocketAddr>) -> Arc<ClientEntry> {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.total_connections_received.fetch_add(1, Ordering::Relaxed);
+        self.clients.add(addr)
+    }
+
+    pub(crate) fn connection_rejected(&self) {
+        self.rejected_connections_ip.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self, id: u64) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+        self.clients.remove(id);
+    }
+
+    pub fn command_processed(&self) {
+        self.total_commands_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn command_refused_for_memory(&self) {
+        self.oom_rejected_commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn value_found_corrupt(&self) {
+        self.corrupt_values.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn aof_written(&self, ok: bool) {
+        self.aof_last_write_ok.store(ok, Ordering::Relaxed);
+    }
+
+    pub fn save_finished(&self, ok: bool) {
+        self.last_save_ok.store(ok, Ordering::Relaxed);
+        if ok {
+            self.last_save_time.store(unix_now(), Ordering::Relaxed);
+        }
+    }
+
+    /// The part of the measured overhead charged to a database holding
+    /// `used` bytes: each pays in proportion to its share of the data.
+    pub(crate) fn overhead_share(&self, used: usize) -> usize {
+        let overhead = self.memory_overhead.load(Ordering::Relaxed);
+        match self.sampled_dataset.load(Ordering::Relaxed) {
+            0 => overhead,
+            dataset => (overhead as u128 * used.min(dataset) as u128 / dataset as u128) as usize,
+        }
+    }
+
+    pub fn record_lookup(&self, hit: bool) {
+        if hit {
+            self.keyspace_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.keyspace_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
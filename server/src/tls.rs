@@ -0,0 +1,149 @@
+//! TLS listener certificates, reloaded while the server runs.
+//!
+//! With `tls-addr` set, clients there speak RESP over TLS using the
+//! certificate chain in `tls-cert-file` and the private key in
+//! `tls-key-file`, both PEM. The files are checked every second and loaded
+//! again when either changes, so certificates an ACME client or an internal
+//! issuer renews rotate in without a restart; `CONFIG SET tls-cert-file` and
+//! `tls-key-file` switch to other files the same way. New connections get
+//! the new certificate and connections already open keep their session. A
+//! pair that does not load (say, a key written before its certificate) is
+//! reported and the old one kept until the files change again.
+
+use std::fs;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::ServerConfig as RustlsConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::Settings;
+
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// Longest a client may take over its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// What a file looked like when last loaded, to notice it being replaced
+type Stamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> Stamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Reads a certificate chain and its private key into a server config.
+pub(crate) fn load(cert_file: &Path, key_file: &Path) -> Result<Arc<RustlsConfig>, String> {
+    let read = |path: &Path| fs::read(path).map_err(|e| format!("cannot read {}: {}", path.display(), e));
+    let certs = rustls_pemfile::certs(&mut BufReader::new(&read(cert_file)?[..]))
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(|e| format!("{}: {}", cert_file.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{} holds no certificate", cert_file.display()));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(&read(key_file)?[..]))
+        .map_err(|e| format!("{}: {}", key_file.display(), e))?
+        .ok_or_else(|| format!("{} holds no private key", key_file.display()))?;
+    let config = RustlsConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{} and {} do not make a usable pair: {}", cert_file.display(), key_file.display(), e))?;
+    Ok(Arc::new(config))
+}
+
+struct Loaded {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    stamps: (Stamp, Stamp),
+}
+
+/// The certificate the TLS listener hands out, swapped whole on reload.
+pub(crate) struct TlsCerts {
+    config: RwLock<Arc<RustlsConfig>>,
+    loaded: Mutex<Loaded>,
+    /// Times a changed certificate was put in service, and times one failed
+    /// to load.
+    pub reloads: AtomicU64,
+    pub reload_errors: AtomicU64,
+}
+
+impl TlsCerts {
+    pub fn new(cert_file: &Path, key_file: &Path) -> io::Result<Self> {
+        let stamps = (stamp(cert_file), stamp(key_file));
+        let config = load(cert_file, key_file).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Self {
+            config: RwLock::new(config),
+            loaded: Mutex::new(Loaded { cert_file: cert_file.to_path_buf(), key_file: key_file.to_path_buf(), stamps }),
+            reloads: AtomicU64::new(0),
+            reload_errors: AtomicU64::new(0),
+        })
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.config.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+
+    /// Serves `cert_file` and `key_file` from now on, if they load.
+    pub fn replace(&self, cert_file: &Path, key_file: &Path) -> Result<(), String> {
+        let mut loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+        // Stamped first: a file replaced while it is read is loaded again
+        let stamps = (stamp(cert_file), stamp(key_file));
+        *loaded = Loaded { cert_file: cert_file.to_path_buf(), key_file: key_file.to_path_buf(), stamps };
+        match load(cert_file, key_file) {
+            Ok(config) => {
+                *self.config.write().unwrap_or_else(|e| e.into_inner()) = config;
+                self.reloads.fetch_add(1, Ordering::Relaxed);
+                println!("TLS certificate loaded from {}", cert_file.display());
+                Ok(())
+            }
+            Err(e) => {
+                self.reload_errors.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    // Loads the files again if either changed since they were last read
+    fn reload_if_changed(&self) {
+        let (cert_file, key_file) = {
+            let loaded = self.loaded.lock().unwrap_or_else(|e| e.into_inner());
+            if (stamp(&loaded.cert_file), stamp(&loaded.key_file)) == loaded.stamps {
+                return;
+            }
+            (loaded.cert_file.clone(), loaded.key_file.clone())
+        };
+        if let Err(e) = self.replace(&cert_file, &key_file) {
+            eprintln!("Keeping the current TLS certificate: {}", e);
+        }
+    }
+}
+
+/// Reloads the TLS certificate whenever its files change.
+pub(crate) fn start_tls_watcher(settings: Arc<Settings>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut tick = tokio::time::interval(WATCH_INTERVAL);
+        tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            if let Some(tls) = settings.tls() {
+                tls.reload_if_changed();
+            }
+        }
+    })
+}
+
+/// Completes the TLS handshake on a new connection with the current
+/// certificate.
+pub(crate) async fn accept(socket: TcpStream, certs: &TlsCerts) -> io::Result<TlsStream<TcpStream>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, certs.acceptor().accept(socket))
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")))
+}
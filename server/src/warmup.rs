@@ -0,0 +1,297 @@
+//! Priming the cache from a preload file at startup.
+//!
+//! With `warmup-file` set, the server runs the file's commands before it
+//! accepts connections, or alongside serving them with `warmup-async`, so a
+//! restart does not begin at a 0% hit rate. The file has one command per
+//! line, written as for redis-cli: arguments separated by spaces, quoted
+//! with `"..."` (with `\n`, `\"`, `\xHH` and the like) or `'...'` when they
+//! hold spaces. Blank lines and lines starting with `#` are skipped:
+//!
+//! ```text
+//! # sessions
+//! SET session:41 "{\"user\":7}" EX 3600
+//! SELECT 1
+//! SET greeting 'hello world'
+//! ```
+//!
+//! The commands run as the server's own, like a replayed AOF: they are not
+//! logged or passed on to replicas. In tiered mode, a read of a key that is
+//! not in memory loads it from the backing store, so a file of `GET` lines
+//! primes the hottest keys. Warmup never evicts; it stops once a database
+//! reaches its maxmemory. WARMUP STATUS reports how far it got.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use crate::backing::{BackingOps, Tiered};
+use crate::client::ClientState;
+use crate::commands::{Context, Registry};
+use crate::config::Settings;
+use crate::db::Database;
+use crate::resp::RespValue;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum WarmupState {
+    /// No `warmup-file` is set.
+    #[default]
+    Off,
+    Running,
+    Done,
+    /// A database reached its maxmemory before the end of the file.
+    Full,
+    /// The file could not be read.
+    Failed,
+}
+
+impl WarmupState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Full => "stopped-at-maxmemory",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+/// How far warmup has got, as WARMUP STATUS reports it.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WarmupStatus {
+    pub state: WarmupState,
+    pub file: Option<PathBuf>,
+    /// Commands run, including those that failed.
+    pub commands: u64,
+    pub failed: u64,
+    /// Keys loaded from the backing store.
+    pub loaded_keys: u64,
+    pub started: Option<Instant>,
+    pub finished: Option<Instant>,
+    /// Why warmup failed or a command was refused, the latest first.
+    pub error: Option<String>,
+}
+
+impl WarmupStatus {
+    pub fn elapsed(&self) -> Duration {
+        match (self.started, self.finished) {
+            (Some(started), Some(finished)) => finished - started,
+            (Some(started), None) => started.elapsed(),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Warmup {
+    status: Mutex<WarmupStatus>,
+}
+
+impl Warmup {
+    pub fn status(&self) -> WarmupStatus {
+        self.lock().clone()
+    }
+
+    pub fn running(&self) -> bool {
+        self.lock().state == WarmupState::Running
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WarmupStatus> {
+        self.status.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Marks warmup from `path` as underway, before a warmup run in the
+    /// background has started.
+    pub fn begin(&self, path: &Path) {
+        *self.lock() = WarmupStatus {
+            state: WarmupState::Running,
+            file: Some(path.to_path_buf()),
+            started: Some(Instant::now()),
+            ..WarmupStatus::default()
+        };
+    }
+
+    fn finish(&self, state: WarmupState, error: Option<String>) {
+        let mut status = self.lock();
+        status.state = state;
+        status.finished = Some(Instant::now());
+        if error.is_some() {
+            status.error = error;
+        }
+    }
+}
+
+/// Runs the commands of `path` against `dbs`; see the module docs. The
+/// outcome is left in the databases' warmup status, which [`Warmup::begin`]
+/// must have started.
+pub(crate) async fn warm_up(path: &Path, dbs: &[Database], settings: &Settings, registry: &Registry, tiered: Option<&Tiered>) {
+    let warmup = &dbs[0].stats.warmup;
+    let (state, error) = match run(path, dbs, settings, registry, tiered, warmup).await {
+        Ok(state) => (state, None),
+        Err(e) => (WarmupState::Failed, Some(e.to_string())),
+    };
+    warmup.finish(state, error);
+    let status = warmup.status();
+    println!(
+        "Warmup from {} {} after {} commands ({} failed, {} keys loaded from the backing store) in {} ms",
+        path.display(),
+        state.name(),
+        status.commands,
+        status.failed,
+        status.loaded_keys,
+        status.elapsed().as_millis()
+    );
+}
+
+async fn run(
+    path: &Path,
+    dbs: &[Database],
+    settings: &Settings,
+    registry: &Registry,
+    tiered: Option<&Tiered>,
+    warmup: &Warmup,
+) -> io::Result<WarmupState> {
+    let file = File::open(path)
+        .await
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    let mut reader = BufReader::new(file);
+    let mut client = ClientState::internal(0);
+    let mut line = Vec::new();
+    let mut number = 0;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).await? == 0 {
+            return Ok(WarmupState::Done);
+        }
+        number += 1;
+        let text = line.trim_ascii();
+        if text.is_empty() || text.starts_with(b"#") {
+            continue;
+        }
+        let args = split_line(text).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{} line {}: unbalanced quotes", path.display(), number))
+        })?;
+        let (limit, _) = settings.memory_budget(client.db);
+        if limit > 0 && dbs[client.db].used_memory() >= limit {
+            return Ok(WarmupState::Full);
+        }
+        let mut frame: Vec<RespValue> = args.into_iter().map(|a| RespValue::BulkString(Some(a))).collect();
+        // Only database 0 is tiered, as for clients
+        let tiered = tiered.filter(|_| client.db == 0);
+        let db = &dbs[client.db];
+        let mut ctx = Context {
+            db,
+            dbs,
+            settings,
+            registry,
+            client: &mut client,
+            block: None,
+            backing: tiered.map(|_| BackingOps::default()),
+            deadline: None,
+            proxy: None,
+            io: None,
+            keep_args: false,
+            slowlog_args: None,
+        };
+        let reply = registry.call(&mut ctx, &mut frame);
+        let misses = ctx.backing.take().map(|ops| ops.misses).unwrap_or_default();
+        let mut loaded = 0;
+        let mut error = match reply {
+            RespValue::Error(e) => Some(e),
+            _ => None,
+        };
+        if let Some(tiered) = tiered {
+            let invoke = |mut args: Vec<RespValue>| {
+                let mut internal = ClientState::internal(0);
+                let mut ctx = Context { db, dbs, settings, registry, client: &mut internal, block: None, backing: None, deadline: None, proxy: None, io: None, keep_args: false, slowlog_args: None };
+                registry.call(&mut ctx, &mut args)
+            };
+            for key in misses {
+                match tiered.load(&key, &invoke).await {
+                    Ok(Some(value)) => loaded += tiered.store_loaded(db, key, value) as u64,
+                    Ok(None) => {}
+                    Err(e) => error = Some(format!("backing store: {}", e)),
+                }
+            }
+        }
+        let mut status = warmup.lock();
+        status.commands += 1;
+        status.loaded_keys += loaded;
+        if let Some(e) = error {
+            status.failed += 1;
+            status.error = Some(format!("line {}: {}", number, e));
+        }
+    }
+}
+
+// The arguments of one line, split and unquoted as redis-cli does; None if
+// a quote is left open
+fn split_line(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i >= line.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        match line[i] {
+            b'"' => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let escaped = *line.get(i + 1)?;
+                            i += 1;
+                            arg.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'a' => 0x07,
+                                b'b' => 0x08,
+                                b'x' => {
+                                    let hex = std::str::from_utf8(line.get(i + 1..i + 3)?).ok()?;
+                                    i += 2;
+                                    u8::from_str_radix(hex, 16).ok()?
+                                }
+                                other => other,
+                            });
+                        }
+                        b => arg.push(b),
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            b'\'' => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'\'' => break,
+                        b'\\' if line.get(i + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            i += 1;
+                        }
+                        b => arg.push(b),
+                    }
+                    i += 1;
+                }
+                i += 1;
+            }
+            _ => {
+                while line.get(i).is_some_and(|b| !b.is_ascii_whitespace()) {
+                    arg.push(line[i]);
+                    i += 1;
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
@@ -0,0 +1,248 @@
+//! HTTP webhooks for key events.
+//!
+//! Each `webhook` directive names a URL, the events it wants (set, del,
+//! expired, evicted, or all) and a glob its keys must match. Matching events
+//! are queued per webhook and POSTed in batches, as a JSON array of the
+//! objects `events-format json` publishes:
+//!
+//! ```text
+//! [{"event":"del","db":0,"key":"user:1","time_ms":1760000000000}]
+//! ```
+//!
+//! A batch is sent once it holds `webhook-batch-size` events or
+//! `webhook-batch-ms` after its first. With `webhook-secret` set, each POST
+//! carries `X-RustCache-Signature: sha256=<hex>`, the HMAC-SHA256 of the
+//! body under the secret, so receivers can tell it came from this server.
+//! A batch that fails to connect, times out or gets a 5xx or 429 is retried
+//! up to `webhook-retries` times, waiting twice as long each time; after
+//! that, or on any other status, it is given up on and counted. Only plain
+//! `http://` URLs are supported; put a TLS proxy in front for HTTPS.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::config::ServerConfig;
+use crate::crypto::hmac_sha256;
+use crate::db::{Database, KeyEvent};
+use crate::events::{connect, event_from_name, event_name, on_key_events, serialize, Event, EventFormat, EventQueue};
+use crate::glob::glob_match;
+use crate::stats::Stats;
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One `webhook` directive: where to POST, and which events.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    /// Events sent; all of them when empty.
+    pub events: Vec<KeyEvent>,
+    /// Glob the keys of sent events match.
+    pub pattern: String,
+}
+
+impl Webhook {
+    /// Parses `<url> [events] [pattern]`, events being a comma-separated
+    /// list or `all`.
+    pub(crate) fn parse(value: &str) -> Result<Self, String> {
+        let words: Vec<&str> = value.split_whitespace().collect();
+        let (url, events, pattern) = match words[..] {
+            [url] => (url, "all", "*"),
+            [url, events] => (url, events, "*"),
+            [url, events, pattern] => (url, events, pattern),
+            _ => return Err("webhook needs a URL, and optionally events and a key pattern".to_string()),
+        };
+        split_url(url)?;
+        let events = if events.eq_ignore_ascii_case("all") {
+            Vec::new()
+        } else {
+            events
+                .split(',')
+                .map(|name| event_from_name(name).ok_or_else(|| format!("unknown webhook event '{}'", name)))
+                .collect::<Result<_, _>>()?
+        };
+        Ok(Self { url: url.to_string(), events, pattern: pattern.to_string() })
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        let events = if self.events.is_empty() {
+            "all".to_string()
+        } else {
+            self.events.iter().map(|e| event_name(*e)).collect::<Vec<_>>().join(",")
+        };
+        format!("{} {} {}", self.url, events, self.pattern)
+    }
+
+    fn wants(&self, event: KeyEvent, key: &str) -> bool {
+        (self.events.is_empty() || self.events.contains(&event)) && glob_match(self.pattern.as_bytes(), key.as_bytes())
+    }
+}
+
+// The `host:port` to connect to and the path of an http:// URL
+fn split_url(url: &str) -> Result<(String, &str), String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(format!("webhook URL '{}' must start with http:// (put a TLS proxy in front for HTTPS)", url));
+    };
+    let (authority, path) = rest.find('/').map_or((rest, "/"), |i| (&rest[..i], &rest[i..]));
+    if authority.is_empty() {
+        return Err(format!("webhook URL '{}' has no host", url));
+    }
+    let addr = match authority.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_err() => return Err(format!("webhook URL '{}' has a bad port", url)),
+        Some(_) => authority.to_string(),
+        None => format!("{}:80", authority),
+    };
+    Ok((addr, path))
+}
+
+/// How webhook delivery is going, as INFO stats reports it.
+#[derive(Default)]
+pub(crate) struct WebhookStats {
+    /// The queue of each webhook.
+    pub queues: Mutex<Vec<Arc<EventQueue>>>,
+    /// Events in batches the receiver accepted.
+    pub delivered: AtomicU64,
+    /// Events in batches given up on.
+    pub failed: AtomicU64,
+    /// Batches sent again after a failure.
+    pub retries: AtomicU64,
+}
+
+impl WebhookStats {
+    fn queues(&self) -> Vec<Arc<EventQueue>> {
+        self.queues.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queues().iter().map(|q| q.len()).sum()
+    }
+
+    /// Events left out because a webhook's queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.queues().iter().map(|q| q.dropped.load(Ordering::Relaxed)).sum()
+    }
+}
+
+/// Queues the matching key events of `dbs` for each of the config's
+/// webhooks and delivers them until aborted.
+pub(crate) fn start_webhooks(config: &ServerConfig, dbs: &[Database], stats: Arc<Stats>) -> JoinHandle<()> {
+    let mut hooks = JoinSet::new();
+    for hook in config.webhooks.iter().cloned() {
+        let queue = Arc::new(EventQueue::with_capacity(config.webhook_queue_size));
+        stats.webhooks.queues.lock().unwrap_or_else(|e| e.into_inner()).push(queue.clone());
+        let (queued, wanted) = (queue.clone(), hook.clone());
+        on_key_events(dbs, move |db, event, key| {
+            if wanted.wants(event, key) {
+                let name = event_name(event);
+                queued.push(Event {
+                    name,
+                    key: key.to_string(),
+                    payload: serialize(EventFormat::Json, name, db, key),
+                });
+            }
+        });
+        let delivery = Delivery {
+            secret: config.webhook_secret.clone(),
+            batch_size: config.webhook_batch_size,
+            batch_delay: config.webhook_batch_delay,
+            retries: config.webhook_retries,
+        };
+        hooks.spawn(deliver(hook, queue, delivery, stats.clone()));
+    }
+    // Aborting this task drops the set, which aborts every webhook's task
+    tokio::spawn(async move { while hooks.join_next().await.is_some() {} })
+}
+
+struct Delivery {
+    secret: Option<String>,
+    batch_size: usize,
+    batch_delay: Duration,
+    retries: u32,
+}
+
+async fn deliver(hook: Webhook, queue: Arc<EventQueue>, delivery: Delivery, stats: Arc<Stats>) {
+    let webhooks = &stats.webhooks;
+    let mut batch = Vec::new();
+    loop {
+        batch.clear();
+        queue.peek(&mut batch, delivery.batch_size).await;
+        let deadline = tokio::time::Instant::now() + delivery.batch_delay;
+        while batch.len() < delivery.batch_size {
+            if tokio::time::timeout_at(deadline, queue.peek(&mut batch, delivery.batch_size)).await.is_err() {
+                break;
+            }
+        }
+        let mut body = Vec::from(*b"[");
+        for (i, event) in batch.iter().enumerate() {
+            if i > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(&event.payload);
+        }
+        body.push(b']');
+        let mut attempt = 0;
+        loop {
+            let outcome = match post(&hook.url, &body, delivery.secret.as_deref()).await {
+                Ok(status) if (200..300).contains(&status) => Ok(()),
+                Ok(status) => Err((format!("HTTP status {}", status), status == 429 || status >= 500)),
+                Err(e) => Err((e.to_string(), true)),
+            };
+            match outcome {
+                Ok(()) => {
+                    webhooks.delivered.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    break;
+                }
+                Err((_, true)) if attempt < delivery.retries => {
+                    webhooks.retries.fetch_add(1, Ordering::Relaxed);
+                    tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt.min(10))).await;
+                    attempt += 1;
+                }
+                Err((e, _)) => {
+                    eprintln!("Webhook {} failed, dropping {} events: {}", hook.url, batch.len(), e);
+                    webhooks.failed.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+        queue.pop(batch.len());
+    }
+}
+
+// POSTs `body` to `url` and returns the response's status
+async fn post(url: &str, body: &[u8], secret: Option<&str>) -> io::Result<u16> {
+    let (addr, path) = split_url(url).map_err(io::Error::other)?;
+    let exchange = async {
+        let mut conn = BufReader::new(connect(&addr).await?);
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: rustcache/{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            path,
+            addr,
+            env!("CARGO_PKG_VERSION"),
+            body.len()
+        );
+        if let Some(secret) = secret {
+            let signature: String = hmac_sha256(secret.as_bytes(), body).iter().map(|b| format!("{:02x}", b)).collect();
+            request.push_str(&format!("X-RustCache-Signature: sha256={}\r\n", signature));
+        }
+        request.push_str("\r\n");
+        let mut out = request.into_bytes();
+        out.extend_from_slice(body);
+        conn.get_mut().write_all(&out).await?;
+        let mut status = String::new();
+        conn.read_line(&mut status).await?;
+        status
+            .split_whitespace()
+            .nth(1)
+            .filter(|_| status.starts_with("HTTP/1."))
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad HTTP status line '{}'", status.trim_end())))
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))?
+}
@@ -0,0 +1,478 @@
+//! WebSocket endpoint for browser dashboards and Electron apps, which cannot
+//! open plain TCP connections.
+//!
+//! A WebSocket connection is an ordinary client session: its messages feed
+//! the same command loop a TCP client gets, and every reply or push that
+//! session writes goes back as a message. Text messages are JSON command
+//! envelopes, an array of strings such as `["SET", "k", "v"]`; binary
+//! messages carry RESP. Replies are JSON text unless the client asked for the
+//! `resp` subprotocol, in which case each one is a binary message of RESP.
+//!
+//! The same listener serves the admin dashboard at `/`: a static page that
+//! opens a WebSocket back to it, so it sees exactly what its user may see
+//! and asks for credentials when the server wants AUTH.
+
+use std::fmt;
+use std::io;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::config::Settings;
+use crate::crypto::{base64, sha1};
+use crate::resp::{read_resp, RespValue};
+
+// RFC 6455 section 1.3: appended to the client's key before hashing
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_REQUEST: u64 = 8 * 1024;
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Json,
+    Resp,
+}
+
+/// An upgraded connection, ready to relay messages.
+pub(crate) struct WebSocket {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    encoding: Encoding,
+}
+
+/// Reads the HTTP upgrade request on `stream` and completes the handshake.
+/// Returns None after answering a request that is not a WebSocket upgrade
+/// (serving the dashboard, if that is what was asked for), or comes from
+/// another origin that `websocket-origin` does not allow.
+pub(crate) async fn accept(stream: TcpStream, settings: &Settings) -> io::Result<Option<WebSocket>> {
+    let (read_half, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+    let request = read_request(&mut reader).await?;
+    let request_line = request.first().map(String::as_str).unwrap_or_default();
+    let header = |name: &str| {
+        request
+            .iter()
+            .skip(1)
+            .filter_map(|line| line.split_once(':'))
+            .find(|(n, _)| n.trim().eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    };
+    let has_token = |name: &str, token: &str| {
+        header(name).is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    };
+    let key = match header("sec-websocket-key") {
+        Some(key)
+            if request_line.starts_with("GET ")
+                && has_token("upgrade", "websocket")
+                && has_token("connection", "upgrade")
+                && header("sec-websocket-version") == Some("13") =>
+        {
+            key
+        }
+        _ => {
+            let path = request_line.split(' ').nth(1).unwrap_or_default();
+            let path = path.split('?').next().unwrap_or_default();
+            if request_line.starts_with("GET ") && path == "/" && settings.current().dashboard {
+                respond(&mut writer, "200 OK", "text/html; charset=utf-8", DASHBOARD).await?;
+            } else {
+                respond(&mut writer, "400 Bad Request", "text/plain", "expected a WebSocket upgrade\n").await?;
+            }
+            return Ok(None);
+        }
+    };
+    // Browsers always send Origin; other clients are not subject to the check.
+    // The dashboard's own origin, this listener, is always allowed; any other
+    // page only when listed, or when `*` is, so a site the user happens to
+    // visit cannot drive their server.
+    let origins = settings.current().websocket_origins;
+    if let Some(origin) = header("origin") {
+        let same_origin = header("host").is_some_and(|host| {
+            ["http://", "https://"].iter().any(|scheme| origin.eq_ignore_ascii_case(&format!("{}{}", scheme, host)))
+        });
+        if !same_origin && !origins.iter().any(|o| o == "*" || o.eq_ignore_ascii_case(origin)) {
+            respond(&mut writer, "403 Forbidden", "text/plain", "origin not allowed\n").await?;
+            return Ok(None);
+        }
+    }
+    let (encoding, protocol) = if has_token("sec-websocket-protocol", "resp") {
+        (Encoding::Resp, "Sec-WebSocket-Protocol: resp\r\n")
+    } else if has_token("sec-websocket-protocol", "json") {
+        (Encoding::Json, "Sec-WebSocket-Protocol: json\r\n")
+    } else {
+        (Encoding::Json, "")
+    };
+    let accept = base64(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+        accept, protocol
+    );
+    writer.write_all(response.as_bytes()).await?;
+    Ok(Some(WebSocket { reader, writer, encoding }))
+}
+
+// The request line and headers, up to the blank line ending them
+pub(crate) async fn read_request(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut limited = reader.take(MAX_REQUEST);
+    loop {
+        let mut line = Vec::new();
+        if limited.read_until(b'\n', &mut line).await? == 0 || !line.ends_with(b"\n") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "incomplete or oversized HTTP request"));
+        }
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        lines.push(line);
+    }
+}
+
+// Answers a plain HTTP request and closes the connection
+pub(crate) async fn respond(writer: &mut OwnedWriteHalf, status: &str, content_type: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nX-Frame-Options: DENY\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    writer.write_all(response.as_bytes()).await
+}
+
+// Why the connection is being closed, for the close frame
+#[derive(Debug)]
+struct Violation {
+    code: u16,
+    reason: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn violation(code: u16, reason: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Violation { code, reason: reason.into() })
+}
+
+impl WebSocket {
+    /// Relays messages between the client and `session`, whose other end runs
+    /// the client's command loop, until either side closes.
+    pub async fn relay(self, session: DuplexStream) {
+        let (session_reader, session_writer) = tokio::io::split(session);
+        let writer = Mutex::new(self.writer);
+        let mut reader = self.reader;
+        let closing = tokio::select! {
+            res = inbound(&mut reader, &writer, session_writer) => res.err(),
+            res = outbound(session_reader, &writer, self.encoding) => res.err(),
+        };
+        let (code, reason) = match closing.as_ref().and_then(|e| e.get_ref()).and_then(|e| e.downcast_ref::<Violation>()) {
+            Some(v) => (v.code, v.reason.as_str()),
+            None => (CLOSE_NORMAL, ""),
+        };
+        let _ = send_close(&writer, code, reason).await;
+    }
+}
+
+// Client messages to the session; pings are answered here
+async fn inbound(
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &Mutex<OwnedWriteHalf>,
+    mut session: WriteHalf<DuplexStream>,
+) -> io::Result<()> {
+    // The opcode and payload of a fragmented message so far
+    let mut message: Option<(u8, Vec<u8>)> = None;
+    loop {
+        let (fin, opcode, payload) = read_frame(reader).await?;
+        match opcode {
+            OP_PING => send(writer, OP_PONG, &payload).await?,
+            OP_PONG => {}
+            OP_CLOSE => return Ok(()),
+            OP_TEXT | OP_BINARY if message.is_none() => message = Some((opcode, payload)),
+            OP_CONTINUATION if message.is_some() => {
+                if let Some((_, data)) = message.as_mut() {
+                    if data.len() + payload.len() > MAX_MESSAGE {
+                        return Err(violation(CLOSE_TOO_BIG, "message too big"));
+                    }
+                    data.extend_from_slice(&payload);
+                }
+            }
+            _ => return Err(violation(CLOSE_PROTOCOL_ERROR, format!("unexpected opcode {:#x}", opcode))),
+        }
+        if !fin || opcode >= OP_CLOSE {
+            continue;
+        }
+        if let Some((opcode, data)) = message.take() {
+            if opcode == OP_BINARY {
+                session.write_all(&data).await?;
+                continue;
+            }
+            let args = parse_envelope(&data).map_err(|e| violation(CLOSE_INVALID_PAYLOAD, e))?;
+            let mut buf = Vec::with_capacity(data.len() + 16);
+            RespValue::Array(Some(args.into_iter().map(|a| RespValue::BulkString(Some(a))).collect())).encode(&mut buf);
+            session.write_all(&buf).await?;
+        }
+    }
+}
+
+// The session's replies and pushes to the client, one message each
+async fn outbound(
+    session: ReadHalf<DuplexStream>,
+    writer: &Mutex<OwnedWriteHalf>,
+    encoding: Encoding,
+) -> io::Result<()> {
+    let mut session = BufReader::new(session);
+    loop {
+        let reply = match read_resp(&mut session).await {
+            Ok(reply) => reply,
+            // The session ended, e.g. after QUIT
+            Err(_) => return Ok(()),
+        };
+        if encoding == Encoding::Resp {
+            let mut buf = Vec::new();
+            reply.encode(&mut buf);
+            send(writer, OP_BINARY, &buf).await?;
+        } else {
+            let mut json = String::new();
+            to_json(&reply, &mut json);
+            send(writer, OP_TEXT, json.as_bytes()).await?;
+        }
+    }
+}
+
+async fn read_frame(reader: &mut BufReader<OwnedReadHalf>) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let fin = head[0] & 0x80 != 0;
+    let opcode = head[0] & 0x0f;
+    if head[1] & 0x80 == 0 {
+        return Err(violation(CLOSE_PROTOCOL_ERROR, "client frames must be masked"));
+    }
+    let len = match head[1] & 0x7f {
+        126 => reader.read_u16().await? as u64,
+        127 => reader.read_u64().await?,
+        n => n as u64,
+    };
+    if opcode >= OP_CLOSE && (len > 125 || !fin) {
+        return Err(violation(CLOSE_PROTOCOL_ERROR, "invalid control frame"));
+    }
+    if len > MAX_MESSAGE as u64 {
+        return Err(violation(CLOSE_TOO_BIG, "message too big"));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+    Ok((fin, opcode, payload))
+}
+
+async fn send(writer: &Mutex<OwnedWriteHalf>, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.lock().await.write_all(&frame).await
+}
+
+async fn send_close(writer: &Mutex<OwnedWriteHalf>, code: u16, reason: &str) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    // Control frames carry at most 125 bytes
+    let mut end = reason.len().min(123);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    send(writer, OP_CLOSE, &payload).await?;
+    writer.lock().await.shutdown().await
+}
+
+/// Parses a JSON command envelope: an array of strings (numbers are taken
+/// as written), such as `["EXPIRE", "k", 60]`.
+fn parse_envelope(text: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut json = Json { text, at: 0 };
+    json.expect(b'[')?;
+    let mut args = Vec::new();
+    if json.peek() == Some(b']') {
+        json.at += 1;
+    } else {
+        loop {
+            args.push(json.argument()?);
+            match json.next() {
+                Some(b',') => {}
+                Some(b']') => break,
+                _ => return Err("expected ',' or ']' in command envelope".to_string()),
+            }
+        }
+    }
+    if json.peek().is_some() {
+        return Err("trailing data after command envelope".to_string());
+    }
+    if args.is_empty() {
+        return Err("empty command envelope".to_string());
+    }
+    Ok(args)
+}
+
+struct Json<'a> {
+    text: &'a [u8],
+    at: usize,
+}
+
+impl Json<'_> {
+    // The next byte that is not whitespace, without consuming it
+    fn peek(&mut self) -> Option<u8> {
+        while self.text.get(self.at).is_some_and(|b| b.is_ascii_whitespace()) {
+            self.at += 1;
+        }
+        self.text.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.at += 1;
+        Some(b)
+    }
+
+    fn expect(&mut self, b: u8) -> Result<(), String> {
+        match self.next() {
+            Some(got) if got == b => Ok(()),
+            _ => Err(format!("expected '{}' in command envelope", b as char)),
+        }
+    }
+
+    fn argument(&mut self) -> Result<Vec<u8>, String> {
+        match self.peek() {
+            Some(b'"') => {
+                self.at += 1;
+                self.string()
+            }
+            Some(b) if b == b'-' || b.is_ascii_digit() => {
+                let start = self.at;
+                while self.text.get(self.at).is_some_and(|b| b"+-.eE".contains(b) || b.is_ascii_digit()) {
+                    self.at += 1;
+                }
+                Ok(self.text[start..self.at].to_vec())
+            }
+            _ => Err("command arguments must be strings or numbers".to_string()),
+        }
+    }
+
+    // The rest of a string whose opening quote was consumed
+    fn string(&mut self) -> Result<Vec<u8>, String> {
+        let mut out = Vec::new();
+        loop {
+            let b = *self.text.get(self.at).ok_or("unterminated string in command envelope")?;
+            self.at += 1;
+            match b {
+                b'"' => return Ok(out),
+                b'\\' => {
+                    let escaped = *self.text.get(self.at).ok_or("unterminated string in command envelope")?;
+                    self.at += 1;
+                    match escaped {
+                        b'"' | b'\\' | b'/' => out.push(escaped),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let mut c = self.hex4()?;
+                            // A high surrogate pairs with the \u escape after it
+                            if (0xd800..0xdc00).contains(&c) && self.text[self.at..].starts_with(b"\\u") {
+                                self.at += 2;
+                                let low = self.hex4()?;
+                                c = 0x10000 + ((c - 0xd800) << 10) + low.wrapping_sub(0xdc00);
+                            }
+                            let c = char::from_u32(c).ok_or("invalid \\u escape in command envelope")?;
+                            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => return Err("invalid escape in command envelope".to_string()),
+                    }
+                }
+                _ => out.push(b),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.at..self.at + 4).ok_or("invalid \\u escape in command envelope")?;
+        self.at += 4;
+        std::str::from_utf8(digits)
+            .ok()
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| "invalid \\u escape in command envelope".to_string())
+    }
+}
+
+/// A reply as JSON: strings, numbers, null and arrays, with errors as
+/// `{"error": "..."}`. Binary values are decoded as UTF-8, lossily.
+fn to_json(value: &RespValue, out: &mut String) {
+    match value {
+        RespValue::SimpleString(s) => quote(s, out),
+        RespValue::Error(e) => {
+            out.push_str("{\"error\":");
+            quote(e, out);
+            out.push('}');
+        }
+        RespValue::Integer(n) => out.push_str(&n.to_string()),
+        RespValue::BulkString(Some(b)) => quote(&String::from_utf8_lossy(b), out),
+        RespValue::BulkString(None) | RespValue::Array(None) => out.push_str("null"),
+        RespValue::Array(Some(items)) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                to_json(item, out);
+            }
+            out.push(']');
+        }
+    }
+}
+
+pub(crate) fn quote(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
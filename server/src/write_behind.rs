@@ -0,0 +1,301 @@
+//! Write-behind persistence to a SQL table.
+//!
+//! With `write-behind` set, every write to database 0 is recorded as its
+//! key's latest change, and a background worker flushes the changes to a
+//! PostgreSQL or MySQL table of (key, value, updated_at) every
+//! `write-behind-delay-ms`, in transactions of up to
+//! `write-behind-batch-size` keys: a key set holds its value, a deleted key
+//! is deleted. Writes to a key between flushes coalesce into one row write.
+//! Keys that expire or are evicted stay in the table, which is the system of
+//! record. A batch that fails is retried `write-behind-retries` times,
+//! waiting twice as long each time, then appended to the
+//! `write-behind-dead-letter` file as SET and DEL commands, in AOF format,
+//! for an operator to inspect or replay. A change to a new key that finds
+//! `write-behind-queue-size` keys already pending is dropped and counted,
+//! and the table then needs a fresh copy. Shutting down flushes what is
+//! pending, with a single attempt per batch.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::OpenOptions;
+use std::io::{self, Write as _};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::config::ServerConfig;
+use crate::db::Database;
+use crate::resp::RespValue;
+use crate::snapshot::unix_ms_now;
+use crate::sql::{Change, SqlConn, SqlTarget};
+use crate::stats::Stats;
+
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+// Longest a connection and one batch may take before the batch counts as failed
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+// Longest shutdown waits for the last flush
+const STOP_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Pending {
+    writes: HashMap<String, Write>,
+    /// Keys in the order they first changed since their last flush.
+    order: VecDeque<String>,
+}
+
+// A key's latest change, not yet flushed
+struct Write {
+    deleted: bool,
+    /// The value, once it is known. A set key's value is read when flushed,
+    /// unless the key expires or is evicted first.
+    value: Option<Vec<u8>>,
+    time_ms: i64,
+}
+
+/// Changes waiting for the table, and how flushing is going, as INFO
+/// persistence reports it.
+#[derive(Default)]
+pub(crate) struct WriteBehindQueue {
+    pending: Mutex<Pending>,
+    ready: Notify,
+    stop: Notify,
+    stopping: AtomicBool,
+    /// Zero until write-behind starts.
+    capacity: AtomicUsize,
+    dead_letter: Mutex<Option<PathBuf>>,
+    pub link_up: AtomicBool,
+    /// Changes committed to the table.
+    pub written: AtomicU64,
+    /// Batches tried again after a failure.
+    pub retries: AtomicU64,
+    /// Changes appended to the dead-letter file.
+    pub dead_lettered: AtomicU64,
+    /// Changes left out because the queue was full.
+    pub dropped: AtomicU64,
+}
+
+impl WriteBehindQueue {
+    fn lock(&self) -> std::sync::MutexGuard<'_, Pending> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().order.len()
+    }
+
+    // Records that `key` was set, or deleted
+    fn push(&self, key: &str, deleted: bool) {
+        let write = Write { deleted, value: None, time_ms: unix_ms_now() };
+        let mut pending = self.lock();
+        if let Some(queued) = pending.writes.get_mut(key) {
+            *queued = write;
+            return;
+        }
+        if pending.order.len() >= self.capacity.load(Ordering::Relaxed) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        pending.order.push_back(key.to_string());
+        pending.writes.insert(key.to_string(), write);
+        drop(pending);
+        self.ready.notify_one();
+    }
+
+    // Keeps the value of a pending set key that is going away, so the
+    // flush still has it
+    fn capture(&self, key: &str, value: Option<&[u8]>) {
+        if let Some(write) = self.lock().writes.get_mut(key).filter(|w| !w.deleted && w.value.is_none()) {
+            write.value = value.map(<[u8]>::to_vec);
+        }
+    }
+
+    // Up to `n` of the longest-pending changes, no longer pending. A set key
+    // whose value can no longer be found (FLUSHDB reports nothing) is left out
+    fn take(&self, n: usize, db: &Database) -> Vec<Change> {
+        let mut pending = self.lock();
+        let count = n.min(pending.order.len());
+        let keys: Vec<String> = pending.order.drain(..count).collect();
+        let writes: Vec<(String, Write)> = keys.into_iter().filter_map(|k| pending.writes.remove(&k).map(|w| (k, w))).collect();
+        drop(pending);
+        writes
+            .into_iter()
+            .filter_map(|(key, write)| {
+                let value = match write {
+                    Write { deleted: true, .. } => None,
+                    Write { value: Some(value), .. } => Some(value),
+                    Write { value: None, .. } => Some(db.peek(&key)?),
+                };
+                Some(Change { key, value, time_ms: write.time_ms })
+            })
+            .collect()
+    }
+
+    // Waits until a change is pending, then `delay` more for others to
+    // join it; returns true at once when write-behind is stopping
+    async fn wait(&self, delay: Duration) -> bool {
+        loop {
+            let notified = self.ready.notified();
+            if self.stopping.load(Ordering::Acquire) {
+                return true;
+            }
+            if self.len() > 0 {
+                break;
+            }
+            tokio::select! {
+                _ = notified => {}
+                _ = self.stop.notified() => {}
+            }
+        }
+        let stop = self.stop.notified();
+        if self.stopping.load(Ordering::Acquire) {
+            return true;
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => false,
+            _ = stop => true,
+        }
+    }
+
+    // Appends `changes` to the dead-letter file as commands
+    fn dead_letter(&self, changes: &[Change]) {
+        let path = self.dead_letter.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(path) = path.as_deref() else { return };
+        let mut buf = Vec::new();
+        for change in changes {
+            let args = match &change.value {
+                Some(value) => vec![b"SET".to_vec(), change.key.as_bytes().to_vec(), value.clone()],
+                None => vec![b"DEL".to_vec(), change.key.as_bytes().to_vec()],
+            };
+            RespValue::Array(Some(args.into_iter().map(|a| RespValue::BulkString(Some(a))).collect())).encode(&mut buf);
+        }
+        // Held under the lock, so batches never interleave
+        match append(path, &buf) {
+            Ok(()) => {
+                self.dead_lettered.fetch_add(changes.len() as u64, Ordering::Relaxed);
+            }
+            Err(e) => eprintln!("Losing {} write-behind changes, as writing {} failed: {}", changes.len(), path.display(), e),
+        }
+    }
+}
+
+fn append(path: &Path, buf: &[u8]) -> io::Result<()> {
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(buf)
+}
+
+/// The running worker; see [`start_write_behind`].
+pub(crate) struct WriteBehind {
+    stats: Arc<Stats>,
+    task: JoinHandle<()>,
+}
+
+impl WriteBehind {
+    /// Flushes what is pending and stops the worker.
+    pub async fn stop(mut self) {
+        let queue = &self.stats.write_behind;
+        queue.stopping.store(true, Ordering::Release);
+        queue.stop.notify_waiters();
+        if tokio::time::timeout(STOP_TIMEOUT, &mut self.task).await.is_err() {
+            self.task.abort();
+            eprintln!("Write-behind did not finish flushing in time; {} changes are lost", queue.len());
+        }
+    }
+}
+
+/// Records the changes to `db` and flushes them to the config's
+/// `write-behind` table until stopped.
+pub(crate) fn start_write_behind(target: SqlTarget, config: &ServerConfig, db: &Database, stats: Arc<Stats>) -> WriteBehind {
+    let queue = &stats.write_behind;
+    queue.capacity.store(config.write_behind_queue_size, Ordering::Relaxed);
+    *queue.dead_letter.lock().unwrap_or_else(|e| e.into_inner()) = Some(config.write_behind_dead_letter.clone());
+    // The listeners belong to the database, so they cannot hold it to read
+    // values; the flush reads them
+    let set = stats.clone();
+    db.on_set(move |key, _| set.write_behind.push(key, false));
+    let deleted = stats.clone();
+    db.on_delete(move |key, _| deleted.write_behind.push(key, true));
+    let expired = stats.clone();
+    db.on_expired(move |key, old| expired.write_behind.capture(key, old));
+    let evicted = stats.clone();
+    db.on_evicted(move |key, old| evicted.write_behind.capture(key, old));
+    let flush = Flush {
+        db: db.clone(),
+        target,
+        batch_size: config.write_behind_batch_size,
+        delay: config.write_behind_delay,
+        retries: config.write_behind_retries,
+    };
+    let task = tokio::spawn(flush.run(stats.clone()));
+    WriteBehind { stats, task }
+}
+
+struct Flush {
+    db: Database,
+    target: SqlTarget,
+    batch_size: usize,
+    delay: Duration,
+    retries: u32,
+}
+
+impl Flush {
+    async fn run(self, stats: Arc<Stats>) {
+        let queue = &stats.write_behind;
+        let mut conn = None;
+        loop {
+            let stopping = queue.wait(self.delay).await;
+            // Full batches go out at once; a partial one waits for more, unless stopping
+            loop {
+                let batch = queue.take(self.batch_size, &self.db);
+                if !batch.is_empty() {
+                    self.write(&mut conn, &batch, queue).await;
+                }
+                let left = queue.len();
+                if left == 0 || (!stopping && left < self.batch_size) {
+                    break;
+                }
+            }
+            if stopping {
+                return;
+            }
+        }
+    }
+
+    // Writes one batch, retrying unless stopping, and dead-letters it if
+    // that fails
+    async fn write(&self, conn: &mut Option<SqlConn>, batch: &[Change], queue: &WriteBehindQueue) {
+        let mut attempt = 0;
+        loop {
+            let applied = tokio::time::timeout(FLUSH_TIMEOUT, async {
+                if conn.is_none() {
+                    *conn = Some(SqlConn::connect(&self.target).await?);
+                    queue.link_up.store(true, Ordering::Relaxed);
+                }
+                conn.as_mut().expect("connected above").apply(&self.target.table, batch).await
+            })
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+            match applied {
+                Ok(()) => {
+                    queue.written.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    return;
+                }
+                Err(e) => {
+                    // A failed transaction may be left open; a new connection starts clean
+                    *conn = None;
+                    queue.link_up.store(false, Ordering::Relaxed);
+                    if attempt < self.retries && !queue.stopping.load(Ordering::Acquire) {
+                        queue.retries.fetch_add(1, Ordering::Relaxed);
+                        tokio::time::sleep(RETRY_DELAY * 2u32.pow(attempt.min(10))).await;
+                        attempt += 1;
+                        continue;
+                    }
+                    eprintln!("Write-behind to {} failed, dead-lettering {} changes: {}", self.target.describe(), batch.len(), e);
+                    queue.dead_letter(batch);
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,178 @@
+mod common;
+
+use server::aof::{replay, AofLimit};
+use server::compression::Compression;
+use server::resp::RespValue;
+use server::{run_server, ServerConfig};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+use common::request;
+
+fn aof_config(name: &str) -> ServerConfig {
+    let path = std::env::temp_dir().join(format!("rustcache-{}-{}.aof", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    ServerConfig {
+        databases: 2,
+        appendonly: true,
+        aof_file: path,
+        ..ServerConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn writes_are_replayed_on_restart() {
+    let config = aof_config("replay");
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "plain", "v"]).await;
+    request(&mut conn, &["SET", "ttl", "v", "EX", "100"]).await;
+    request(&mut conn, &["SET", "expiring", "v"]).await;
+    request(&mut conn, &["EXPIRE", "expiring", "100"]).await;
+    request(&mut conn, &["INCR", "counter"]).await;
+    request(&mut conn, &["INCR", "counter"]).await;
+    request(&mut conn, &["DEL", "plain"]).await;
+    request(&mut conn, &["SET", "cas", "a"]).await;
+    request(&mut conn, &["SET", "cas", "b", "IFEQ", "a", "EX", "100"]).await;
+    assert_eq!(request(&mut conn, &["SET", "cas", "c", "IFEQ", "a"]).await, RespValue::BulkString(None));
+    request(&mut conn, &["SELECT", "1"]).await;
+    request(&mut conn, &["SET", "other-db", "w"]).await;
+    // Failed writes are not logged
+    assert!(matches!(request(&mut conn, &["INCR", "other-db"]).await, RespValue::Error(_)));
+    handle.shutdown().await;
+
+    // A crash partway through a write leaves an incomplete command, cut on load
+    let complete = std::fs::metadata(&config.aof_file).unwrap().len();
+    let mut file = std::fs::OpenOptions::new().append(true).open(&config.aof_file).unwrap();
+    std::io::Write::write_all(&mut file, b"*3\r\n$3\r\nSET\r\n$4\r\nhalf").unwrap();
+    drop(file);
+
+    let handle = run_server(config.clone()).await.unwrap();
+    assert_eq!(handle.db().get("plain"), None);
+    assert!(handle.db().ttl_seconds("ttl") > 90);
+    assert!(handle.db().ttl_seconds("expiring") > 90);
+    assert_eq!(handle.db().get("counter"), Some(b"2".to_vec()));
+    assert_eq!(handle.db().get("cas"), Some(b"b".to_vec()));
+    assert!(handle.db().ttl_seconds("cas") > 90);
+    assert_eq!(handle.database(1).unwrap().get("other-db"), Some(b"w".to_vec()));
+    assert_eq!(std::fs::metadata(&config.aof_file).unwrap().len(), complete);
+    handle.shutdown().await;
+    std::fs::remove_file(&config.aof_file).unwrap();
+}
+
+#[tokio::test]
+async fn flushdb_is_rolled_back_by_replaying_up_to_an_offset() {
+    let config = aof_config("pitr");
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "a", "1"]).await;
+    request(&mut conn, &["SET", "b", "2"]).await;
+    handle.shutdown().await;
+    let before_incident = std::fs::metadata(&config.aof_file).unwrap().len();
+
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["FLUSHDB"]).await;
+    request(&mut conn, &["SET", "after", "x"]).await;
+    handle.shutdown().await;
+    let whole = std::fs::read(&config.aof_file).unwrap();
+
+    let recovery = ServerConfig {
+        aof_load_until: Some(AofLimit::Offset(before_incident)),
+        ..config.clone()
+    };
+    let handle = run_server(recovery).await.unwrap();
+    assert_eq!(handle.db().get("a"), Some(b"1".to_vec()));
+    assert_eq!(handle.db().get("b"), Some(b"2".to_vec()));
+    assert_eq!(handle.db().get("after"), None);
+    handle.shutdown().await;
+
+    // The file now ends where the replay stopped; the original is kept aside
+    assert_eq!(std::fs::read(&config.aof_file).unwrap(), whole[..before_incident as usize]);
+    let dir = config.aof_file.parent().unwrap();
+    let prefix = format!("{}.before-pitr-", config.aof_file.file_name().unwrap().to_string_lossy());
+    let kept = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .find(|p| p.file_name().unwrap().to_string_lossy().starts_with(&prefix))
+        .expect("the whole file is kept");
+    assert_eq!(std::fs::read(&kept).unwrap(), whole);
+    std::fs::remove_file(&kept).unwrap();
+    std::fs::remove_file(&config.aof_file).unwrap();
+}
+
+#[tokio::test]
+async fn compressed_aof_survives_a_cut_frame_and_point_in_time_recovery() {
+    let config = ServerConfig {
+        compression: Compression::Zstd(3),
+        ..aof_config("zstd")
+    };
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "a", "1"]).await;
+    request(&mut conn, &["SET", "b", "2"]).await;
+    handle.shutdown().await;
+    let file = std::fs::read(&config.aof_file).unwrap();
+    assert_eq!(file[..4], [0x28, 0xB5, 0x2F, 0xFD]);
+    let before_incident = replay(&config.aof_file, None, |_| {}).unwrap().end;
+
+    // Later writes land in frames of their own; a crash cuts the first short
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["FLUSHDB"]).await;
+    request(&mut conn, &["SET", "c", "3"]).await;
+    handle.shutdown().await;
+    let whole = std::fs::read(&config.aof_file).unwrap();
+    std::fs::write(&config.aof_file, &whole[..file.len() + 8]).unwrap();
+    let handle = run_server(config.clone()).await.unwrap();
+    assert_eq!(handle.db().get("a"), Some(b"1".to_vec()));
+    assert_eq!(handle.db().get("c"), None);
+    handle.shutdown().await;
+    std::fs::write(&config.aof_file, &whole).unwrap();
+
+    let recovery = ServerConfig {
+        aof_load_until: Some(AofLimit::Offset(before_incident)),
+        ..config.clone()
+    };
+    let handle = run_server(recovery).await.unwrap();
+    assert_eq!(handle.db().get("a"), Some(b"1".to_vec()));
+    assert_eq!(handle.db().get("c"), None);
+    handle.shutdown().await;
+    assert_eq!(replay(&config.aof_file, None, |_| {}).unwrap().end, before_incident);
+    let dir = config.aof_file.parent().unwrap();
+    let prefix = format!("{}.before-pitr-", config.aof_file.file_name().unwrap().to_string_lossy());
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.file_name().unwrap().to_string_lossy().starts_with(&prefix) {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+    std::fs::remove_file(&config.aof_file).unwrap();
+}
+
+#[test]
+fn replay_stops_at_a_timestamp() {
+    let path = std::env::temp_dir().join(format!("rustcache-timestamps-{}.aof", std::process::id()));
+    let set = |k: &str| format!("*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$1\r\nv\r\n", k.len(), k);
+    let file = format!(
+        "#TS:100\r\n{}{}#TS:105\r\n{}#TS:110\r\n*1\r\n$7\r\nFLUSHDB\r\n",
+        set("a"),
+        set("b"),
+        set("c")
+    );
+    std::fs::write(&path, &file).unwrap();
+
+    let mut seen = Vec::new();
+    let result = replay(&path, Some(AofLimit::Timestamp(105)), |args| seen.push(args[1].clone())).unwrap();
+    assert_eq!(seen, [b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    assert!(result.stopped_at_limit);
+    assert_eq!(result.end as usize, file.find("#TS:110").unwrap());
+
+    let result = replay(&path, None, |_| {}).unwrap();
+    assert_eq!((result.commands, result.stopped_at_limit, result.end as usize), (4, false, file.len()));
+
+    std::fs::write(&path, "#TS:100\r\n*1\r\n%3\r\nbad\r\n").unwrap();
+    let err = replay(&path, None, |_| {}).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,63 @@
+use std::time::{Duration, Instant};
+
+use server::db::Database;
+
+fn soon() -> Option<Instant> {
+    Some(Instant::now() + Duration::from_millis(50))
+}
+
+fn keys(names: &[&str]) -> Vec<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+#[tokio::test]
+async fn waiters_are_woken_in_arrival_order() {
+    let db = Database::new();
+    let first = db.blocked().block(&keys(&["jobs"]));
+    let second = db.blocked().block(&keys(&["jobs"]));
+    assert_eq!(db.blocked().len(), 2);
+
+    db.blocked().signal_key_ready("jobs");
+    assert!(first.wait(soon()).await);
+    assert!(!second.wait(soon()).await, "only the head is woken");
+
+    // Serving the head passes the signal down the queue
+    first.served();
+    assert!(second.wait(soon()).await);
+    drop(second);
+    assert!(db.blocked().is_empty());
+}
+
+#[tokio::test]
+async fn writes_signal_blocked_keys() {
+    let db = Database::new();
+    let waiter = db.blocked().block(&keys(&["a", "b"]));
+    assert!(!waiter.wait(soon()).await);
+
+    db.set("b".into(), b"1".to_vec(), None);
+    assert!(waiter.wait(soon()).await);
+
+    db.incr_by("a".into(), 1).unwrap();
+    assert!(waiter.wait(soon()).await);
+}
+
+#[tokio::test]
+async fn a_signal_before_waiting_is_not_lost() {
+    let db = Database::new();
+    let waiter = db.blocked().block(&keys(&["k"]));
+    db.set("k".into(), b"v".to_vec(), None);
+    assert!(waiter.wait(soon()).await);
+}
+
+#[tokio::test]
+async fn dropped_waiters_leave_the_queue() {
+    let db = Database::new();
+    let gone = db.blocked().block(&keys(&["k"]));
+    let next = db.blocked().block(&keys(&["k"]));
+    drop(gone);
+
+    db.blocked().signal_key_ready("k");
+    assert!(next.wait(soon()).await);
+    drop(next);
+    assert!(db.blocked().is_empty());
+}
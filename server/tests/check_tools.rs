@@ -0,0 +1,217 @@
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use server::compression::Compression;
+use server::resp::{read_resp, RespValue};
+use server::{check_rdb, check_snapshot, run_server, ServerConfig};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+fn temp(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rustcache-check-{}-{}", std::process::id(), name))
+}
+
+fn run(tool: &str, args: &[&str], file: &Path) -> Output {
+    Command::new(tool).args(args).arg(file).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn set(key: &str, value: &str) -> String {
+    format!("*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n${}\r\n{}\r\n", key.len(), key, value.len(), value)
+}
+
+#[test]
+fn check_aof_reports_damage_and_fixes_it() {
+    let tool = env!("CARGO_BIN_EXE_rc-check-aof");
+    let path = temp("damaged.aof");
+    let whole = format!("#TS:100\r\n{}{}", set("a", "1"), set("b", "2"));
+    std::fs::write(&path, &whole).unwrap();
+    let output = run(tool, &[], &path);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("2 whole commands, last timestamp 100"), "{}", stdout(&output));
+
+    // A crash mid-write leaves half a command
+    std::fs::write(&path, format!("{}*3\r\n$3\r\nSET\r\n$1\r\nc", whole)).unwrap();
+    let output = run(tool, &[], &path);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stdout(&output).contains(&format!("incomplete command at offset {}", whole.len())), "{}", stdout(&output));
+
+    // Garbage in the middle is reported where it starts
+    let damaged = format!("#TS:100\r\n{}garbage\r\n{}", set("a", "1"), set("b", "2"));
+    std::fs::write(&path, &damaged).unwrap();
+    let output = run(tool, &[], &path);
+    assert_eq!(output.status.code(), Some(1));
+    let at = damaged.find("garbage").unwrap();
+    assert!(stdout(&output).contains(&format!("damaged at offset {}", at)), "{}", stdout(&output));
+    assert_eq!(run(tool, &["--truncate-to-offset", "10"], &path).status.code(), Some(1));
+
+    let output = run(tool, &["--fix"], &path);
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), damaged[..at]);
+    let mut orig = path.clone().into_os_string();
+    orig.push(".orig");
+    assert_eq!(std::fs::read_to_string(&orig).unwrap(), damaged);
+    assert!(run(tool, &[], &path).status.success());
+    std::fs::remove_file(&orig).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+async fn save(config: ServerConfig, format: &[&str]) {
+    let handle = run_server(config).await.unwrap();
+    handle.db().set("greeting".into(), b"hello".to_vec(), None);
+    handle.database(1).unwrap().set("other".into(), b"x".to_vec(), None);
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let mut frame = Vec::new();
+    let args = [&["SAVE"], format].concat();
+    RespValue::Array(Some(args.iter().map(|a| RespValue::BulkString(Some(a.as_bytes().to_vec()))).collect())).encode(&mut frame);
+    conn.get_mut().write_all(&frame).await.unwrap();
+    assert_eq!(read_resp(&mut conn).await.unwrap(), RespValue::SimpleString("OK".into()));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn check_rdb_reads_snapshots_and_rdb_files() {
+    let tool = env!("CARGO_BIN_EXE_rc-check-rdb");
+    let snapshot = temp("dump.rcs");
+    let rdb = temp("dump.rdb");
+    let config = ServerConfig {
+        databases: 2,
+        snapshot_file: Some(snapshot.clone()),
+        rdb_file: Some(rdb.clone()),
+        compression: Compression::Zstd(3),
+        ..ServerConfig::default()
+    };
+    save(config.clone(), &[]).await;
+    save(config, &["RDB"]).await;
+
+    let check = check_snapshot(&snapshot).unwrap();
+    assert_eq!((check.keys, check.max_db), (2, Some(1)));
+    let output = run(tool, &[], &snapshot);
+    assert!(output.status.success());
+    assert!(stdout(&output).contains("2 keys in databases up to 1"), "{}", stdout(&output));
+    let check = check_rdb(&rdb).unwrap();
+    assert_eq!((check.version, check.keys, check.unsupported, check.unchecked), (9, 2, 0, false));
+    assert!(run(tool, &[], &rdb).status.success());
+
+    // A length that runs past the end of the file points at its record
+    let mut file = std::fs::read(&rdb).unwrap();
+    let at = file.windows(5).position(|w| w == b"hello").unwrap();
+    file[at - 1] = 0x3f;
+    std::fs::write(&rdb, &file).unwrap();
+    let err = check_rdb(&rdb).unwrap_err();
+    assert!(err.to_string().contains("in the entry at offset"), "{}", err);
+    let output = run(tool, &[], &rdb);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("offset"));
+
+    // A flipped byte that still parses is caught by the checksum
+    let mut file = std::fs::read(&rdb).unwrap();
+    file[at - 1] = 5;
+    file[at] ^= 1;
+    std::fs::write(&rdb, &file).unwrap();
+    assert!(check_rdb(&rdb).unwrap_err().to_string().contains("checksum mismatch"));
+
+    // An LZF string claiming 2^62 bytes is corrupt, not an allocation
+    let mut file = b"REDIS0009\x00\x01k\xc3\x01\x81".to_vec();
+    file.extend_from_slice(&(1u64 << 62).to_be_bytes());
+    file.push(0x00);
+    std::fs::write(&rdb, &file).unwrap();
+    let err = check_rdb(&rdb).unwrap_err();
+    assert!(err.to_string().contains("corrupt LZF"), "{}", err);
+    assert_eq!(run(tool, &[], &rdb).status.code(), Some(1));
+
+    std::fs::write(&snapshot, b"RCSNAP\x01\x00\x01\xff\xff\xff\x7f").unwrap();
+    let err = check_snapshot(&snapshot).unwrap_err();
+    assert!(err.to_string().contains("truncated in the record at offset 8"), "{}", err);
+    std::fs::remove_file(&snapshot).unwrap();
+    std::fs::remove_file(&rdb).unwrap();
+}
+
+#[tokio::test]
+async fn rc_dump_summarises_and_dumps_files_offline() {
+    let tool = env!("CARGO_BIN_EXE_rc-dump");
+    let snapshot = temp("summary.rcs");
+    let config = ServerConfig {
+        databases: 2,
+        snapshot_file: Some(snapshot.clone()),
+        ..ServerConfig::default()
+    };
+    save(config, &[]).await;
+    let output = run(tool, &["--databases", "2"], &snapshot);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let summary = stdout(&output);
+    assert!(summary.contains("db0: 1 keys"), "{}", summary);
+    assert!(summary.contains("(string: 1)"), "{}", summary);
+    assert!(summary.contains("Total: 2 keys in 2 databases"), "{}", summary);
+    assert!(summary.contains("db0 \"greeting\""), "{}", summary);
+    assert!(summary.contains("none        2"), "{}", summary);
+    let output = run(tool, &["--databases", "2", "--db", "1", "--keys", "*"], &snapshot);
+    assert_eq!(stdout(&output).lines().nth(1), Some("db1 \"other\" string ttl=-1 \"x\""));
+
+    let aof = temp("summary.aof");
+    let expire = "*3\r\n$6\r\nEXPIRE\r\n$1\r\na\r\n$3\r\n600\r\n";
+    std::fs::write(&aof, format!("{}{}{}", set("a", "1\n"), set("b", "22"), expire)).unwrap();
+    let output = run(tool, &["--keys", "a*"], &aof);
+    let out = stdout(&output);
+    assert!(out.starts_with(&format!("{}: append-only file\n", aof.display())), "{}", out);
+    assert!(out.contains("db0 \"a\" string ttl=59"), "{}", out);
+    assert!(out.contains("\"1\\n\"\n"), "{}", out);
+    assert!(!out.contains("\"b\""), "{}", out);
+    assert!(stdout(&run(tool, &[], &aof)).contains("under 1h    1"));
+
+    std::fs::write(&aof, b"not a dump").unwrap();
+    assert_eq!(run(tool, &[], &aof).status.code(), Some(1));
+    assert_eq!(run(tool, &["--top"], &aof).status.code(), Some(2));
+    std::fs::remove_file(&snapshot).unwrap();
+    std::fs::remove_file(&aof).unwrap();
+}
+
+#[tokio::test]
+async fn rc_replay_sends_a_capture_to_a_server_and_counts_its_errors() {
+    let tool = env!("CARGO_BIN_EXE_rc-replay");
+    let handle = run_server(ServerConfig { databases: 2, ..ServerConfig::default() }).await.unwrap();
+    let port = handle.local_addr().port().to_string();
+    let replay = |args: Vec<String>, file: PathBuf| tokio::task::spawn_blocking(move || {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        run(tool, &args, &file)
+    });
+
+    let aof = temp("replay.aof");
+    let incr = "*2\r\n$4\r\nINCR\r\n$1\r\na\r\n";
+    let select = "*2\r\n$6\r\nSELECT\r\n$1\r\n1\r\n";
+    std::fs::write(&aof, format!("#TS:100\r\n{}{}{}#TS:101\r\n{}{}", set("a", "1"), incr, set("b", "x"), select, set("c", "2"))).unwrap();
+    let args = vec!["--port".to_string(), port.clone(), "--speed".into(), "0".into(), "--concurrency".into(), "2".into()];
+    let output = replay(args, aof.clone()).await.unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let out = stdout(&output);
+    assert!(out.contains("Replayed 4 commands"), "{}", out);
+    assert!(out.contains("Error rate 0.00%, against 0.00% captured"), "{}", out);
+    assert_eq!(handle.db().get("a"), Some(b"2".to_vec()));
+    assert_eq!(handle.database(1).unwrap().get("c"), Some(b"2".to_vec()));
+    assert_eq!(handle.db().get("c"), None);
+
+    let monitor = temp("replay.monitor");
+    let capture = "OK\n\
+        1760000000.000100 [0 127.0.0.1:5000] \"SET\" \"quoted\" \"say \\\"hi\\\"\\n\"\n\
+        1760000000.000200 [0 127.0.0.1:5001] \"INCR\" \"b\"\n\
+        1760000000.000300 [1 127.0.0.1:5000] \"GET\" \"c\"\n";
+    std::fs::write(&monitor, capture).unwrap();
+    let args = vec!["--port".to_string(), port.clone(), "--max-error-rate".into(), "10".into()];
+    let output = replay(args, monitor.clone()).await.unwrap();
+    let out = stdout(&output);
+    // INCR on "x" fails: one error in three replies is over the maximum
+    assert_eq!(output.status.code(), Some(1), "{}", out);
+    assert!(out.contains("MONITOR capture"), "{}", out);
+    assert!(out.contains("Error rate 33.33%"), "{}", out);
+    assert!(out.contains("INCR                          1          1  100.00%"), "{}", out);
+    assert_eq!(handle.db().get("quoted"), Some(b"say \"hi\"\n".to_vec()));
+
+    std::fs::write(&monitor, "12 not a monitor line\n").unwrap();
+    assert_eq!(replay(vec!["--port".to_string(), port], monitor.clone()).await.unwrap().status.code(), Some(1));
+    handle.shutdown().await;
+    std::fs::remove_file(&aof).unwrap();
+    std::fs::remove_file(&monitor).unwrap();
+}
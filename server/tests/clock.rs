@@ -0,0 +1,102 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use server::clock::{Clock, ManualClock};
+use server::db::Database;
+use server::resp::RespValue;
+use server::{run_server_with_clock, ServerConfig};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+use common::request;
+
+fn manual() -> (Arc<ManualClock>, Clock) {
+    let time = Arc::new(ManualClock::new());
+    (time.clone(), Clock::from_source(time))
+}
+
+#[test]
+fn ttls_and_idle_limits_run_on_the_clock_given() {
+    let (time, clock) = manual();
+    let db = Database::with_clock(clock);
+    db.set("k".into(), b"v".to_vec(), Some(Duration::from_secs(10)));
+    db.set("idle".into(), b"v".to_vec(), None);
+    assert!(db.set_idle_limit("idle", Some(Duration::from_secs(5))));
+
+    time.advance(Duration::from_secs(4));
+    assert_eq!(db.ttl_seconds("k"), 6);
+    assert_eq!(db.get("idle"), Some(b"v".to_vec()), "reading it starts its idle time again");
+    time.advance(Duration::from_secs(5));
+    assert_eq!(db.ttl_seconds("k"), 1);
+    assert_eq!(db.get("idle"), Some(b"v".to_vec()));
+    time.advance(Duration::from_secs(6));
+    assert_eq!(db.get("k"), None);
+    assert_eq!(db.get("idle"), None);
+}
+
+#[tokio::test]
+async fn blocking_timeouts_wait_for_the_clock_to_move() {
+    let (time, clock) = manual();
+    let db = Database::with_clock(clock.clone());
+    let waiter = db.blocked().block(&["jobs".to_string()]);
+    let deadline = Some(clock.now() + Duration::from_secs(1));
+
+    // However long it really takes, the clock has not moved
+    let waiting = tokio::time::timeout(Duration::from_millis(50), waiter.wait(deadline)).await;
+    assert!(waiting.is_err());
+    let timed_out = tokio::spawn(async move { waiter.wait(deadline).await });
+    tokio::task::yield_now().await;
+    time.advance(Duration::from_secs(2));
+    assert!(!timed_out.await.unwrap());
+}
+
+#[tokio::test]
+async fn the_reaper_and_time_follow_a_servers_clock() {
+    let (time, clock) = manual();
+    let handle = run_server_with_clock(ServerConfig::default(), clock.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert_eq!(request(&mut conn, &["SET", "k", "v", "PX", "1500"]).await, RespValue::SimpleString("OK".into()));
+    let seconds = |reply: RespValue| match reply {
+        RespValue::Array(Some(parts)) => match &parts[0] {
+            RespValue::BulkString(Some(s)) => String::from_utf8_lossy(s).parse::<i64>().unwrap(),
+            other => panic!("unexpected TIME part {:?}", other),
+        },
+        other => panic!("unexpected TIME reply {:?}", other),
+    };
+    let before = seconds(request(&mut conn, &["TIME"]).await);
+
+    // Nothing expires while the clock stands still
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(handle.db().expires_count(), 1);
+
+    time.advance(Duration::from_secs(60));
+    assert_eq!(seconds(request(&mut conn, &["TIME"]).await) - before, 60);
+    // The reaper wakes as the clock passes its interval, without the key being read
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while handle.db().expires_count() > 0 {
+        assert!(tokio::time::Instant::now() < deadline, "the reaper never ran");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(handle.db().dbsize(), 0);
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn command_time_limits_are_real_time_whatever_the_clock_says() {
+    let (time, clock) = manual();
+    let handle = run_server_with_clock(ServerConfig::default(), clock).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    for i in 0..100 {
+        handle.db().set(format!("key:{}", i), b"v".to_vec(), None);
+    }
+
+    // Well past busy-reply-threshold by the clock, yet no time has gone by
+    time.advance(Duration::from_secs(60));
+    match request(&mut conn, &["SCAN", "0", "COUNT", "10"]).await {
+        RespValue::Array(Some(parts)) => assert_eq!(parts.len(), 2),
+        other => panic!("unexpected SCAN reply {:?}", other),
+    }
+    handle.shutdown().await;
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use std::time::Duration;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig, ServerHandle};
+use tokio::net::TcpListener;
+
+use common::request_once;
+
+async fn text(handle: &ServerHandle, args: &[&str]) -> String {
+    match request_once(handle, args).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+// Waits for `check` to hold of what CLUSTER `sub` says on `node`
+async fn eventually(node: &ServerHandle, sub: &str, check: impl Fn(&str) -> bool) {
+    for _ in 0..200 {
+        if check(&text(node, &["CLUSTER", sub]).await) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("CLUSTER {} never got there: {}", sub, text(node, &["CLUSTER", sub]).await);
+}
+
+#[tokio::test]
+async fn nodes_find_each_other_share_slots_and_agree_on_failures() {
+    let mut listeners = Vec::new();
+    for _ in 0..3 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners.iter().map(|l| l.local_addr().unwrap().to_string()).collect();
+    drop(listeners);
+    let slots = [(0, 5460), (5461, 10922), (10923, 16383)];
+    let mut nodes = Vec::new();
+    for i in 0..3 {
+        let config = ServerConfig {
+            addr: addrs[i].clone(),
+            cluster_enabled: true,
+            // The second joins through the first; the third is introduced later
+            cluster_seeds: if i == 1 { vec![addrs[0].clone()] } else { Vec::new() },
+            cluster_slots: vec![slots[i]],
+            cluster_node_timeout: Duration::from_millis(400),
+            ..ServerConfig::default()
+        };
+        nodes.push(run_server(config).await.unwrap());
+    }
+    let third_port = addrs[2].rsplit_once(':').unwrap().1;
+    assert_eq!(request_once(&nodes[0], &["CLUSTER", "MEET", "127.0.0.1", third_port]).await, RespValue::SimpleString("OK".into()));
+    for node in &nodes {
+        eventually(node, "INFO", |info| info.contains("cluster_known_nodes:3\r\n") && info.contains("cluster_state:ok\r\n")).await;
+    }
+
+    // A key is served by the node owning its slot, and redirected elsewhere
+    let mut owned = None;
+    for i in 0.. {
+        let key = format!("key{}", i);
+        if let RespValue::Integer(slot) = request_once(&nodes[0], &["CLUSTER", "KEYSLOT", &key]).await {
+            if (5461..=10922).contains(&slot) {
+                owned = Some((key, slot));
+                break;
+            }
+        }
+    }
+    let (key, slot) = owned.unwrap();
+    assert_eq!(
+        request_once(&nodes[0], &["GET", &key]).await,
+        RespValue::Error(format!("MOVED {} {}", slot, addrs[1]))
+    );
+    assert_eq!(request_once(&nodes[1], &["GET", &key]).await, RespValue::BulkString(None));
+    match request_once(&nodes[2], &["CLUSTER", "SLOTS"]).await {
+        RespValue::Array(Some(ranges)) => assert_eq!(ranges.len(), 3),
+        other => panic!("unexpected CLUSTER SLOTS reply {:?}", other),
+    }
+
+    // Once the third is gone, the other two agree it failed
+    let third = nodes.pop().unwrap();
+    third.shutdown().await;
+    for node in &nodes {
+        eventually(node, "NODES", |nodes| nodes.lines().any(|l| l.contains(addrs[2].as_str()) && l.contains("master,fail "))).await;
+        eventually(node, "INFO", |info| info.contains("cluster_state:fail\r\n")).await;
+    }
+    for node in nodes {
+        node.shutdown().await;
+    }
+}
@@ -0,0 +1,57 @@
+//! Client helpers the integration tests share. Each test crate uses only
+//! some of them.
+#![allow(dead_code)]
+
+use std::time::Duration;
+
+use server::resp::{read_resp, RespValue};
+use server::ServerHandle;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+pub type Conn = BufReader<TcpStream>;
+
+// Long enough for anything a test runs; a reply that never comes fails the
+// test rather than hanging it
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `args` as a command frame, encoded.
+pub fn frame(args: &[&str]) -> Vec<u8> {
+    let frame = RespValue::Array(Some(
+        args.iter()
+            .map(|a| RespValue::BulkString(Some(a.as_bytes().to_vec())))
+            .collect(),
+    ));
+    let mut buf = Vec::new();
+    frame.encode(&mut buf);
+    buf
+}
+
+pub fn bulk(s: &str) -> RespValue {
+    RespValue::BulkString(Some(s.as_bytes().to_vec()))
+}
+
+pub async fn connect(handle: &ServerHandle) -> Conn {
+    BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap())
+}
+
+/// Sends a command without waiting for its reply.
+pub async fn send<S: AsyncRead + AsyncWrite + Unpin + Send>(conn: &mut BufReader<S>, args: &[&str]) {
+    conn.get_mut().write_all(&frame(args)).await.unwrap();
+}
+
+/// The next reply on `conn`.
+pub async fn reply<S: AsyncRead + AsyncWrite + Unpin + Send>(conn: &mut BufReader<S>) -> RespValue {
+    tokio::time::timeout(REPLY_TIMEOUT, read_resp(conn)).await.unwrap().unwrap()
+}
+
+/// Sends a command and waits for its reply.
+pub async fn request<S: AsyncRead + AsyncWrite + Unpin + Send>(conn: &mut BufReader<S>, args: &[&str]) -> RespValue {
+    send(conn, args).await;
+    reply(conn).await
+}
+
+/// Sends a command on a connection of its own.
+pub async fn request_once(handle: &ServerHandle, args: &[&str]) -> RespValue {
+    request(&mut connect(handle).await, args).await
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+
+use common::{bulk, request};
+
+#[tokio::test]
+async fn single_key_commands_run_on_the_core_owning_the_key() {
+    let server = run_server(ServerConfig { cores: 4, ..ServerConfig::default() }).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+
+    for i in 0..64 {
+        let key = format!("key{}", i);
+        assert_eq!(request(&mut conn, &["SET", &key, &i.to_string()]).await, RespValue::SimpleString("OK".into()));
+        assert_eq!(request(&mut conn, &["INCR", &key]).await, RespValue::Integer(i + 1));
+    }
+    // Connection state goes with the command and comes back
+    assert_eq!(request(&mut conn, &["SELECT", "2"]).await, RespValue::SimpleString("OK".into()));
+    request(&mut conn, &["SET", "key0", "elsewhere"]).await;
+    assert_eq!(request(&mut conn, &["GET", "key0"]).await, bulk("elsewhere"));
+    assert_eq!(request(&mut conn, &["DBSIZE"]).await, RespValue::Integer(1));
+    request(&mut conn, &["SELECT", "0"]).await;
+
+    // Keys on several cores, and keyless commands, run on the connection
+    assert_eq!(request(&mut conn, &["MGET", "key1", "key2", "key3"]).await, RespValue::Array(Some(vec![bulk("2"), bulk("3"), bulk("4")])));
+    assert_eq!(request(&mut conn, &["DBSIZE"]).await, RespValue::Integer(64));
+
+    let info = match request(&mut conn, &["INFO", "server"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("cores:4\r\n"), "{}", info);
+    let counts: Vec<u64> = (0..4)
+        .map(|i| {
+            let prefix = format!("core{}:commands=", i);
+            info.lines().find_map(|l| l.strip_prefix(prefix.as_str())).unwrap().parse().unwrap()
+        })
+        .collect();
+    assert!(counts.iter().all(|n| *n > 0), "{:?}", counts);
+    assert!(counts.iter().sum::<u64>() >= 130, "{:?}", counts);
+    server.shutdown().await;
+}
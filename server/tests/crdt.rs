@@ -0,0 +1,166 @@
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use server::clock::{Clock, ManualClock};
+use server::resp::{read_resp, RespValue};
+use server::{run_server, run_server_with_clock, ServerConfig, ServerHandle};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+use common::{bulk, request_once};
+
+// Waits for `key` to read `expected` on every member
+async fn converged(members: &[&ServerHandle], key: &str, expected: RespValue) {
+    for _ in 0..200 {
+        let mut all = true;
+        for m in members {
+            all &= request_once(m, &["GET", key]).await == expected;
+        }
+        if all {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("{} never converged on {:?}", key, expected);
+}
+
+fn crdt_keys(info: &RespValue) -> usize {
+    let RespValue::BulkString(Some(b)) = info else { panic!("unexpected INFO reply {:?}", info) };
+    let info = String::from_utf8_lossy(b);
+    info.lines().find_map(|l| l.strip_prefix("crdt_keys:")).unwrap().parse().unwrap()
+}
+
+// The next CRDT MERGE the fake peer was sent, as text
+async fn next_merge(merges: &mut mpsc::UnboundedReceiver<Vec<String>>) -> Vec<String> {
+    loop {
+        let args = tokio::time::timeout(Duration::from_secs(5), merges.recv()).await.unwrap().unwrap();
+        if args[0] == "CRDT" {
+            return args;
+        }
+    }
+}
+
+#[tokio::test]
+async fn regions_take_writes_and_converge() {
+    let mut listeners = Vec::new();
+    for _ in 0..2 {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    let addrs: Vec<String> = listeners.iter().map(|l| l.local_addr().unwrap().to_string()).collect();
+    drop(listeners);
+    let mut members = Vec::new();
+    for (i, id) in ["eu", "us"].into_iter().enumerate() {
+        let config = ServerConfig {
+            addr: addrs[i].clone(),
+            crdt_id: Some(id.to_string()),
+            crdt_peers: vec![addrs[1 - i].clone()],
+            ..ServerConfig::default()
+        };
+        members.push(run_server(config).await.unwrap());
+    }
+    let (eu, us) = (&members[0], &members[1]);
+
+    assert_eq!(request_once(eu, &["SET", "greeting", "hello"]).await, RespValue::SimpleString("OK".into()));
+    converged(&[eu, us], "greeting", bulk("hello")).await;
+
+    // Increments made in both regions all count
+    for _ in 0..3 {
+        request_once(eu, &["INCR", "visits"]).await;
+    }
+    for _ in 0..2 {
+        request_once(us, &["INCR", "visits"]).await;
+    }
+    request_once(us, &["DECR", "visits"]).await;
+    converged(&[eu, us], "visits", bulk("4")).await;
+
+    // The later write wins, wherever it was made
+    request_once(eu, &["SET", "owner", "eu"]).await;
+    tokio::time::sleep(Duration::from_millis(5)).await;
+    request_once(us, &["SET", "owner", "us"]).await;
+    converged(&[eu, us], "owner", bulk("us")).await;
+
+    request_once(us, &["DEL", "greeting"]).await;
+    converged(&[eu, us], "greeting", RespValue::BulkString(None)).await;
+
+    // Writes that could not be merged are refused
+    assert!(matches!(request_once(eu, &["EXPIRE", "owner", "10"]).await, RespValue::Error(e) if e.contains("active-active")));
+    assert!(matches!(request_once(eu, &["SET", "owner", "x", "EX", "10"]).await, RespValue::Error(_)));
+    let info = match request_once(us, &["INFO", "replication"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("crdt_id:us\r\n"), "{}", info);
+    assert!(info.contains("link=up"), "{}", info);
+    for member in members {
+        member.shutdown().await;
+    }
+}
+
+#[tokio::test]
+async fn stamps_come_from_the_clock_and_acknowledged_tombstones_are_forgotten() {
+    // A peer that takes every merge, acknowledging it
+    let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer_addr = peer.local_addr().unwrap().to_string();
+    let (sent, mut merges) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut conn = BufReader::new(peer.accept().await.unwrap().0);
+        while let Ok(RespValue::Array(Some(args))) = read_resp(&mut conn).await {
+            let args = args
+                .iter()
+                .map(|a| match a {
+                    RespValue::BulkString(Some(b)) => String::from_utf8_lossy(b).to_string(),
+                    other => panic!("unexpected argument {:?}", other),
+                })
+                .collect();
+            let _ = sent.send(args);
+            conn.get_mut().write_all(b"+OK\r\n").await.unwrap();
+        }
+    });
+
+    let time = Arc::new(ManualClock::new());
+    let clock = Clock::from_source(time.clone());
+    // A day ahead of the system's, so the stamps show which clock they read
+    time.advance(Duration::from_secs(86_400));
+    let config = ServerConfig {
+        addr: "127.0.0.1:0".into(),
+        crdt_id: Some("eu".into()),
+        crdt_peers: vec![peer_addr],
+        ..ServerConfig::default()
+    };
+    let handle = run_server_with_clock(config, clock.clone()).await.unwrap();
+
+    request_once(&handle, &["SET", "kept", "v"]).await;
+    let merge = next_merge(&mut merges).await;
+    assert_eq!(&merge[3..6], ["kept", &clock.unix_ms().to_string(), "eu"]);
+    request_once(&handle, &["SET", "gone", "v"]).await;
+    next_merge(&mut merges).await;
+    request_once(&handle, &["DEL", "gone"]).await;
+    let merge = next_merge(&mut merges).await;
+    assert_eq!((merge[3].as_str(), merge[6].as_str()), ("gone", "0"));
+
+    // Acknowledged, but kept for its grace in case an older write is on its way
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert_eq!(crdt_keys(&request_once(&handle, &["INFO", "replication"]).await), 2);
+
+    // Past the minute of grace
+    time.advance(Duration::from_secs(61));
+    let mut keys = 2;
+    for _ in 0..100 {
+        keys = crdt_keys(&request_once(&handle, &["INFO", "replication"]).await);
+        if keys == 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(keys, 1, "the tombstone was never forgotten");
+
+    // The key can be written again, starting afresh
+    request_once(&handle, &["SET", "gone", "back"]).await;
+    let merge = next_merge(&mut merges).await;
+    assert_eq!(&merge[3..8], ["gone", &clock.unix_ms().to_string(), "eu", "1", "back"]);
+    assert_eq!(request_once(&handle, &["GET", "gone"]).await, bulk("back"));
+    handle.shutdown().await;
+}
@@ -0,0 +1,1480 @@
+mod common;
+
+use server::compression::Compression;
+use server::db::KeyEvent;
+use server::resp::{read_resp, RespValue};
+use server::{run_server, ServerConfig};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use common::{bulk, request};
+
+#[tokio::test]
+async fn serves_commands_on_an_ephemeral_port() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    assert_ne!(handle.local_addr().port(), 0);
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert_eq!(request(&mut conn, &["SET", "greeting", "hello"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["GET", "greeting"]).await, bulk("hello"));
+
+    // The store is shared with the handle, so tests can seed or inspect it directly
+    handle.db().set("seeded".into(), b"1".to_vec(), None);
+    assert_eq!(request(&mut conn, &["INCR", "seeded"]).await, RespValue::Integer(2));
+    assert_eq!(handle.db().get("greeting"), Some(b"hello".to_vec()));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn instances_are_isolated() {
+    let a = run_server(ServerConfig::default()).await.unwrap();
+    let b = run_server(ServerConfig::default()).await.unwrap();
+    a.db().set("only-in-a".into(), b"x".to_vec(), None);
+
+    let mut conn = BufReader::new(TcpStream::connect(b.local_addr()).await.unwrap());
+    assert_eq!(request(&mut conn, &["EXISTS", "only-in-a"]).await, RespValue::Integer(0));
+
+    a.shutdown().await;
+    b.shutdown().await;
+}
+
+#[tokio::test]
+async fn shutdown_closes_listener_and_clients() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let addr = handle.local_addr();
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(request(&mut conn, &["PING"]).await, RespValue::SimpleString("PONG".into()));
+
+    handle.shutdown().await;
+
+    assert!(read_resp(&mut conn).await.is_err());
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn config_reload_applies_live_settings() {
+    let path = std::env::temp_dir().join(format!("rustcache-reload-{}.conf", std::process::id()));
+    std::fs::write(&path, "reaper-ms 500\n").unwrap();
+    let config = ServerConfig {
+        config_file: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+
+    std::fs::write(&path, "reaper-ms 20\nport 1\n").unwrap();
+    let report = handle.reload_config().unwrap();
+    assert_eq!(report.applied, vec!["reaper-ms"]);
+    assert_eq!(report.restart_required, vec!["addr"]);
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert_eq!(
+        request(&mut conn, &["CONFIG", "GET", "reaper-ms"]).await,
+        RespValue::Array(Some(vec![bulk("reaper-ms"), bulk("20")]))
+    );
+
+    std::fs::write(&path, "no-such-directive yes\n").unwrap();
+    assert!(matches!(request(&mut conn, &["CONFIG", "RELOAD"]).await, RespValue::Error(_)));
+
+    std::fs::remove_file(&path).unwrap();
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn ip_lists_set_at_runtime_refuse_new_connections() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let mut admin = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let ok = RespValue::SimpleString("OK".into());
+    // Whether a new connection is served
+    let admitted = || async {
+        let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        conn.get_mut().write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        read_resp(&mut conn).await.is_ok()
+    };
+
+    assert_eq!(request(&mut admin, &["CONFIG", "SET", "ip-deny", "127.0.0.0/8"]).await, ok);
+    assert!(!admitted().await);
+    // Open connections are left alone
+    assert_eq!(
+        request(&mut admin, &["CONFIG", "GET", "ip-deny"]).await,
+        RespValue::Array(Some(vec![bulk("ip-deny"), bulk("127.0.0.0/8")]))
+    );
+
+    assert_eq!(request(&mut admin, &["CONFIG", "SET", "ip-deny", "", "ip-allow", "10.1.2.3/8"]).await, ok);
+    assert!(!admitted().await);
+    assert_eq!(request(&mut admin, &["CONFIG", "SET", "ip-allow", "10.0.0.0/8 127.0.0.1 ::1"]).await, ok);
+    assert!(admitted().await);
+    assert_eq!(
+        request(&mut admin, &["CONFIG", "GET", "ip-*"]).await,
+        RespValue::Array(Some(vec![bulk("ip-allow"), bulk("10.0.0.0/8 127.0.0.1/32 ::1/128"), bulk("ip-deny"), bulk("")]))
+    );
+
+    // Nothing changes when a value is invalid or needs a restart
+    let err = request(&mut admin, &["CONFIG", "SET", "ip-allow", "10.0.0.0/33"]).await;
+    assert!(matches!(&err, RespValue::Error(e) if e.contains("invalid address block '10.0.0.0/33'")), "{:?}", err);
+    let err = request(&mut admin, &["CONFIG", "SET", "port", "1"]).await;
+    assert!(matches!(&err, RespValue::Error(e) if e.contains("addr can only change on restart")), "{:?}", err);
+    assert!(admitted().await);
+
+    let info = match request(&mut admin, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("rejected_connections_ip:2\r\n"), "{}", info);
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn namespaced_users_only_see_their_own_keys() {
+    let config = ServerConfig {
+        requirepass: Some("admin".into()),
+        users: vec!["tenant on >pw namespace=t1:".into()],
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    handle.db().set("other".into(), b"x".to_vec(), None);
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert!(matches!(request(&mut conn, &["GET", "k"]).await, RespValue::Error(e) if e.starts_with("NOAUTH")));
+    assert_eq!(request(&mut conn, &["AUTH", "tenant", "pw"]).await, RespValue::SimpleString("OK".into()));
+
+    assert_eq!(request(&mut conn, &["SET", "k", "v"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(handle.db().get("t1:k"), Some(b"v".to_vec()));
+    assert_eq!(request(&mut conn, &["GET", "other"]).await, RespValue::BulkString(None));
+    assert_eq!(
+        request(&mut conn, &["SCAN", "0", "COUNT", "100"]).await,
+        RespValue::Array(Some(vec![bulk("0"), RespValue::Array(Some(vec![bulk("k")]))]))
+    );
+    assert_eq!(request(&mut conn, &["DBSIZE"]).await, RespValue::Integer(1));
+    assert!(matches!(request(&mut conn, &["CONFIG", "GET", "*"]).await, RespValue::Error(e) if e.starts_with("NOPERM")));
+
+    assert_eq!(request(&mut conn, &["FLUSHDB"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(handle.db().get("other"), Some(b"x".to_vec()));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn sampled_key_accesses_are_audited() {
+    let path = std::env::temp_dir().join(format!("rustcache-audit-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        requirepass: Some("admin".into()),
+        users: vec!["tenant on >pw namespace=t1:".into()],
+        audit_sample: 1,
+        audit_file: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut tenant = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut tenant, &["AUTH", "tenant", "pw"]).await;
+    request(&mut tenant, &["SET", "k", "v"]).await;
+    request(&mut tenant, &["GET", "k"]).await;
+    request(&mut tenant, &["INCR", "k"]).await;
+    // Commands without keys are not audited
+    request(&mut tenant, &["PING"]).await;
+
+    let mut admin = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut admin, &["AUTH", "admin"]).await;
+    let entries = match request(&mut admin, &["AUDIT", "GET"]).await {
+        RespValue::Array(Some(entries)) => entries,
+        other => panic!("unexpected AUDIT GET reply {:?}", other),
+    };
+    assert_eq!(entries.len(), 3);
+    let RespValue::Array(Some(newest)) = &entries[0] else {
+        panic!("unexpected entry {:?}", entries[0]);
+    };
+    let field = |name: &str| newest.iter().position(|f| *f == bulk(name)).map(|i| newest[i + 1].clone()).unwrap();
+    assert_eq!(field("username"), bulk("tenant"));
+    assert_eq!(field("db"), RespValue::Integer(0));
+    assert_eq!(field("command"), bulk("incr"));
+    assert_eq!(field("keys"), RespValue::Array(Some(vec![bulk("t1:k")])));
+    assert_eq!(field("access"), bulk("write"));
+    assert_eq!(field("result"), bulk("error"));
+
+    let lines: Vec<String> = std::fs::read_to_string(&path).unwrap().lines().map(String::from).collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].contains(r#""user":"tenant""#), "{}", lines[1]);
+    assert!(lines[1].contains(r#""command":"get","keys":["t1:k"],"access":"read","result":"ok""#), "{}", lines[1]);
+
+    // One in two from here on
+    assert_eq!(request(&mut admin, &["CONFIG", "SET", "audit-sample", "2"]).await, RespValue::SimpleString("OK".into()));
+    for _ in 0..4 {
+        request(&mut tenant, &["GET", "k"]).await;
+    }
+    assert_eq!(request(&mut admin, &["AUDIT", "LEN"]).await, RespValue::Integer(5));
+    assert_eq!(request(&mut admin, &["AUDIT", "RESET"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut admin, &["AUDIT", "LEN"]).await, RespValue::Integer(0));
+
+    handle.shutdown().await;
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn recorded_sessions_are_replayed_without_running_commands() {
+    let path = std::env::temp_dir().join(format!("rustcache-record-{}.resp", std::process::id()));
+    let handle = run_server(ServerConfig { record_file: Some(path.clone()), ..ServerConfig::default() }).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "counter", "1"]).await;
+    assert_eq!(request(&mut conn, &["INCR", "counter"]).await, RespValue::Integer(2));
+    assert_eq!(request(&mut conn, &["INCR", "counter"]).await, RespValue::Integer(3));
+    assert_eq!(request(&mut conn, &["GET", "counter"]).await, bulk("3"));
+    handle.shutdown().await;
+
+    let handle = run_server(ServerConfig { replay_file: Some(path.clone()), ..ServerConfig::default() }).await.unwrap();
+    for _ in 0..2 {
+        // Each connection replays from the start
+        let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        assert_eq!(request(&mut conn, &["get", "counter"]).await, bulk("3"));
+        assert_eq!(request(&mut conn, &["INCR", "counter"]).await, RespValue::Integer(2));
+        assert_eq!(request(&mut conn, &["INCR", "counter"]).await, RespValue::Integer(3));
+        // Past the recorded replies the last one repeats
+        assert_eq!(request(&mut conn, &["INCR", "counter"]).await, RespValue::Integer(3));
+        assert!(matches!(request(&mut conn, &["GET", "other"]).await, RespValue::Error(_)));
+    }
+    // Nothing ran against the dataset
+    assert_eq!(handle.db().get("counter"), None);
+    handle.shutdown().await;
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn warmup_file_primes_the_cache_before_clients_connect() {
+    let path = std::env::temp_dir().join(format!("rustcache-warmup-{}.txt", std::process::id()));
+    let file = "# primed at startup\n\
+        SET greeting \"hello \\\"world\\\"\\n\" EX 600\n\
+        \n\
+        set single 'a b'\n\
+        INCR greeting\n\
+        SELECT 1\n\
+        SET other \\x41\n";
+    std::fs::write(&path, file).unwrap();
+    let config = ServerConfig { databases: 2, warmup_file: Some(path.clone()), ..ServerConfig::default() };
+    let handle = run_server(config).await.unwrap();
+    assert_eq!(handle.db().get("greeting"), Some(b"hello \"world\"\n".to_vec()));
+    assert!(handle.db().ttl_seconds("greeting") > 590);
+    assert_eq!(handle.db().get("single"), Some(b"a b".to_vec()));
+    assert_eq!(handle.database(1).unwrap().get("other"), Some(b"\\x41".to_vec()));
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let RespValue::Array(Some(status)) = request(&mut conn, &["WARMUP", "STATUS"]).await else { panic!() };
+    assert_eq!(status[1], bulk("done"));
+    assert_eq!(status[5], RespValue::Integer(5));
+    assert_eq!(status[7], RespValue::Integer(1));
+    assert!(matches!(&status[13], RespValue::BulkString(Some(e)) if e.starts_with(b"line 5: ")), "{:?}", status[13]);
+    handle.shutdown().await;
+
+    // Warmup stops at maxmemory rather than evict what it loaded
+    let lines: String = (0..100).map(|i| format!("SET key:{} {}\n", i, "x".repeat(100))).collect();
+    std::fs::write(&path, lines).unwrap();
+    let config = ServerConfig { maxmemory: 2000, warmup_file: Some(path.clone()), ..ServerConfig::default() };
+    let handle = run_server(config).await.unwrap();
+    assert!(handle.db().dbsize() < 100);
+    assert!(handle.db().get("key:0").is_some());
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let RespValue::Array(Some(status)) = request(&mut conn, &["WARMUP", "STATUS"]).await else { panic!() };
+    assert_eq!(status[1], bulk("stopped-at-maxmemory"));
+    handle.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn command_table_checks_arity_and_acl_rules() {
+    let config = ServerConfig {
+        users: vec!["reader on >pw nocommands +@read +ping".into()],
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    for args in [&["GET"][..], &["STRLEN", "a", "b"], &["MGET"], &["EXPIRE", "k"]] {
+        let expected = format!("ERR wrong number of arguments for '{}' command", args[0].to_ascii_lowercase());
+        assert_eq!(request(&mut conn, args).await, RespValue::Error(expected));
+    }
+
+    assert_eq!(
+        request(&mut conn, &["COMMAND", "INFO", "mset", "nope"]).await,
+        RespValue::Array(Some(vec![
+            RespValue::Array(Some(vec![
+                bulk("mset"),
+                RespValue::Integer(-3),
+                RespValue::Array(Some(vec![
+                    RespValue::SimpleString("write".into()),
+                    RespValue::SimpleString("denyoom".into()),
+                ])),
+                RespValue::Integer(1),
+                RespValue::Integer(-1),
+                RespValue::Integer(2),
+                RespValue::Array(Some(vec![RespValue::SimpleString("@write".into())])),
+            ])),
+            RespValue::BulkString(None),
+        ]))
+    );
+    assert_eq!(
+        request(&mut conn, &["COMMAND", "GETKEYS", "MSET", "a", "1", "b", "2"]).await,
+        RespValue::Array(Some(vec![bulk("a"), bulk("b")]))
+    );
+
+    assert_eq!(request(&mut conn, &["AUTH", "reader", "pw"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["GET", "k"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["PING"]).await, RespValue::SimpleString("PONG".into()));
+    assert!(matches!(request(&mut conn, &["SET", "k", "v"]).await, RespValue::Error(e) if e.starts_with("NOPERM")));
+    assert!(matches!(request(&mut conn, &["ECHO", "x"]).await, RespValue::Error(e) if e.starts_with("NOPERM")));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn acl_log_records_denied_commands_and_failed_auth() {
+    let config = ServerConfig {
+        requirepass: Some("admin".into()),
+        users: vec!["reader on >pw nocommands +@read".into()],
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let local = conn.get_ref().local_addr().unwrap();
+    assert!(matches!(request(&mut conn, &["AUTH", "reader", "nope"]).await, RespValue::Error(e) if e.starts_with("WRONGPASS")));
+    request(&mut conn, &["AUTH", "reader", "pw"]).await;
+    request(&mut conn, &["SET", "k", "v"]).await;
+    request(&mut conn, &["SET", "k", "v"]).await;
+    assert!(matches!(request(&mut conn, &["ACL", "LOG"]).await, RespValue::Error(e) if e.starts_with("NOPERM")));
+
+    request(&mut conn, &["AUTH", "admin"]).await;
+    let entries = match request(&mut conn, &["ACL", "LOG"]).await {
+        RespValue::Array(Some(entries)) => entries,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    let field = |entry: &RespValue, name: &str| match entry {
+        RespValue::Array(Some(fields)) => {
+            let at = fields.iter().position(|f| *f == bulk(name)).unwrap();
+            fields[at + 1].clone()
+        }
+        other => panic!("unexpected entry {:?}", other),
+    };
+    // Newest first; the repeated SET is counted on one entry
+    assert_eq!(entries.len(), 3);
+    assert_eq!(field(&entries[0], "object"), bulk("acl"));
+    assert_eq!(field(&entries[1], "reason"), bulk("command"));
+    assert_eq!(field(&entries[1], "object"), bulk("set"));
+    assert_eq!(field(&entries[1], "count"), RespValue::Integer(2));
+    assert_eq!(field(&entries[2], "reason"), bulk("auth"));
+    assert_eq!(field(&entries[2], "username"), bulk("reader"));
+    assert_eq!(field(&entries[2], "client-info"), bulk(&format!("addr={}", local)));
+
+    assert_eq!(request(&mut conn, &["ACL", "LOG", "RESET"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["ACL", "LOG"]).await, RespValue::Array(Some(vec![])));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn users_over_their_quotas_are_throttled() {
+    let config = ServerConfig {
+        users: vec![
+            "tenant on >pw ops-per-sec=5".into(),
+            "reader on >pw bytes-out-per-sec=1000".into(),
+            "admin on >pw".into(),
+        ],
+        requirepass: Some("admin".into()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut tenant = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut tenant, &["AUTH", "tenant", "pw"]).await;
+    // A second's worth, then nothing until the bucket refills
+    for i in 0..5 {
+        assert_eq!(request(&mut tenant, &["SET", &format!("k{}", i), "v"]).await, RespValue::SimpleString("OK".into()));
+    }
+    for _ in 0..3 {
+        match request(&mut tenant, &["GET", "k0"]).await {
+            RespValue::Error(e) => assert_eq!(e, "THROTTLED User tenant is over its ops-per-sec quota"),
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+    tokio::time::sleep(std::time::Duration::from_millis(450)).await;
+    assert_eq!(request(&mut tenant, &["GET", "k0"]).await, bulk("v"));
+
+    // A big reply is sent, then paid off before the next
+    let mut reader = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut reader, &["AUTH", "reader", "pw"]).await;
+    handle.db().set("big".to_string(), vec![b'x'; 1500], None);
+    assert!(matches!(request(&mut reader, &["GET", "big"]).await, RespValue::BulkString(Some(v)) if v.len() == 1500));
+    assert!(matches!(request(&mut reader, &["GET", "k0"]).await, RespValue::Error(e) if e.contains("bytes-out-per-sec")));
+
+    let mut admin = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut admin, &["AUTH", "admin", "pw"]).await;
+    let entries = match request(&mut admin, &["ACL", "LOG"]).await {
+        RespValue::Array(Some(entries)) => entries,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    let field = |entry: &RespValue, name: &str| match entry {
+        RespValue::Array(Some(fields)) => {
+            let at = fields.iter().position(|f| *f == bulk(name)).unwrap();
+            fields[at + 1].clone()
+        }
+        other => panic!("unexpected entry {:?}", other),
+    };
+    assert_eq!(entries.len(), 2);
+    assert_eq!(field(&entries[0], "reason"), bulk("quota"));
+    assert_eq!(field(&entries[0], "object"), bulk("bytes-out-per-sec"));
+    assert_eq!(field(&entries[0], "username"), bulk("reader"));
+    assert_eq!(field(&entries[1], "object"), bulk("ops-per-sec"));
+    assert_eq!(field(&entries[1], "username"), bulk("tenant"));
+    assert_eq!(field(&entries[1], "count"), RespValue::Integer(3));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn hotkeys_reports_the_most_accessed_keys() {
+    let config = ServerConfig {
+        hotkeys_sample: 1,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    for _ in 0..50 {
+        request(&mut conn, &["GET", "hot"]).await;
+    }
+    for _ in 0..5 {
+        request(&mut conn, &["MGET", "warm", "hot"]).await;
+    }
+    request(&mut conn, &["SELECT", "3"]).await;
+    request(&mut conn, &["SET", "cold", "v"]).await;
+
+    let entry = |key: &str, db: i64, hits: i64| {
+        RespValue::Array(Some(vec![bulk(key), RespValue::Integer(db), RespValue::Integer(hits)]))
+    };
+    assert_eq!(
+        request(&mut conn, &["HOTKEYS", "3"]).await,
+        RespValue::Array(Some(vec![entry("hot", 0, 55), entry("warm", 0, 5), entry("cold", 3, 1)]))
+    );
+    let info = request(&mut conn, &["INFO", "hotkeys"]).await;
+    assert!(matches!(info, RespValue::BulkString(Some(b)) if String::from_utf8_lossy(&b).contains("hotkey0:db=0,key=hot,hits=55")));
+    assert_eq!(request(&mut conn, &["HOTKEYS", "RESET"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["HOTKEYS"]).await, RespValue::Array(Some(vec![])));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn histogram_buckets_sampled_keys_by_size_and_ttl() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    for i in 0..10 {
+        request(&mut conn, &["SET", &format!("small{}", i), "v"]).await;
+    }
+    let big = "x".repeat(2000);
+    for i in 0..5 {
+        request(&mut conn, &["SET", &format!("big{}", i), &big, "EX", "120"]).await;
+    }
+
+    let counts = |pairs: &[(&str, i64)]| {
+        RespValue::Array(Some(pairs.iter().flat_map(|(label, n)| [bulk(label), RespValue::Integer(*n)]).collect()))
+    };
+    // Small enough to be read whole
+    let sizes = counts(&[
+        ("<=16", 10), ("<=64", 0), ("<=256", 0), ("<=1k", 0), ("<=4k", 5),
+        ("<=16k", 0), ("<=64k", 0), ("<=256k", 0), ("<=1m", 0), (">1m", 0),
+    ]);
+    let ttls = counts(&[("none", 10), ("<=1m", 0), ("<=10m", 5), ("<=1h", 0), ("<=1d", 0), ("<=1w", 0), (">1w", 0)]);
+    assert_eq!(
+        request(&mut conn, &["HISTOGRAM"]).await,
+        RespValue::Array(Some(vec![
+            bulk("keys"),
+            RespValue::Integer(15),
+            bulk("sampled"),
+            RespValue::Integer(15),
+            bulk("string"),
+            RespValue::Array(Some(vec![bulk("sizes"), sizes, bulk("ttls"), ttls])),
+        ]))
+    );
+    match request(&mut conn, &["HISTOGRAM", "SAMPLES", "4"]).await {
+        RespValue::Array(Some(reply)) => assert_eq!(reply[3], RespValue::Integer(4)),
+        other => panic!("unexpected HISTOGRAM reply {:?}", other),
+    }
+    assert!(matches!(request(&mut conn, &["HISTOGRAM", "SAMPLES", "0"]).await, RespValue::Error(_)));
+
+    request(&mut conn, &["SELECT", "1"]).await;
+    assert_eq!(
+        request(&mut conn, &["HISTOGRAM"]).await,
+        RespValue::Array(Some(vec![bulk("keys"), RespValue::Integer(0), bulk("sampled"), RespValue::Integer(0)]))
+    );
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn slow_commands_are_recorded_and_long_scans_aborted() {
+    let config = ServerConfig {
+        latency_threshold: std::time::Duration::from_millis(1),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    for i in 0..200_000 {
+        handle.db().set(format!("key:{}", i), b"v".to_vec(), None);
+    }
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+
+    // A short page finishes within the default budget; one over the whole
+    // keyspace cannot finish in a millisecond
+    assert!(matches!(request(&mut conn, &["SCAN", "0", "COUNT", "10"]).await, RespValue::Array(_)));
+    let ok = RespValue::SimpleString("OK".into());
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "busy-reply-threshold", "1"]).await, ok);
+    let reply = request(&mut conn, &["SCAN", "0", "COUNT", "1000000", "MATCH", "*a*b*c*"]).await;
+    assert!(matches!(reply, RespValue::Error(e) if e.starts_with("BUSY ")));
+
+    let latest = match request(&mut conn, &["LATENCY", "LATEST"]).await {
+        RespValue::Array(Some(events)) => events,
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert!(matches!(&latest[..], [RespValue::Array(Some(e))] if e[0] == bulk("command")));
+    assert!(matches!(request(&mut conn, &["LATENCY", "HISTORY", "command"]).await, RespValue::Array(Some(h)) if !h.is_empty()));
+    assert_eq!(request(&mut conn, &["LATENCY", "RESET"]).await, RespValue::Integer(1));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn the_slowlog_shows_values_set_moved_into_the_store() {
+    let config = ServerConfig {
+        slowlog_log_slower_than: 0,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let long = "x".repeat(200);
+
+    assert_eq!(request(&mut conn, &["SET", "k", &long, "IFVERSION", "0"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["SET", "k", &long]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["GET", "k"]).await, bulk(&long));
+    let entries = match request(&mut conn, &["SLOWLOG", "GET", "3"]).await {
+        RespValue::Array(Some(entries)) => entries,
+        other => panic!("unexpected SLOWLOG reply {:?}", other),
+    };
+    let shown = format!("{}... (72 more bytes)", &long[..128]);
+    for entry in &entries[1..] {
+        match entry {
+            RespValue::Array(Some(fields)) => match &fields[3] {
+                RespValue::Array(Some(args)) => assert_eq!(args[2], bulk(&shown)),
+                other => panic!("unexpected arguments {:?}", other),
+            },
+            other => panic!("unexpected entry {:?}", other),
+        }
+    }
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn cluster_mode_refuses_cross_slot_commands() {
+    let config = ServerConfig {
+        cluster_enabled: true,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+
+    assert_eq!(request(&mut conn, &["CLUSTER", "KEYSLOT", "foo"]).await, RespValue::Integer(12182));
+    assert_eq!(request(&mut conn, &["CLUSTER", "KEYSLOT", "123456789"]).await, RespValue::Integer(12739));
+    // Only the first non-empty {tag} is hashed
+    let slot = request(&mut conn, &["CLUSTER", "KEYSLOT", "bar"]).await;
+    assert_eq!(request(&mut conn, &["CLUSTER", "KEYSLOT", "foo{bar}{zap}"]).await, slot);
+    let tagged = request(&mut conn, &["CLUSTER", "KEYSLOT", "{user1}:a"]).await;
+    assert_eq!(request(&mut conn, &["CLUSTER", "KEYSLOT", "{user1}:b"]).await, tagged);
+    assert_ne!(request(&mut conn, &["CLUSTER", "KEYSLOT", "foo{}{bar}"]).await, slot);
+
+    assert_eq!(request(&mut conn, &["MSET", "{user1}:a", "1", "{user1}:b", "2"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(
+        request(&mut conn, &["MGET", "{user1}:a", "{user1}:b"]).await,
+        RespValue::Array(Some(vec![bulk("1"), bulk("2")]))
+    );
+    assert!(matches!(request(&mut conn, &["MGET", "a", "b"]).await, RespValue::Error(e) if e.starts_with("CROSSSLOT ")));
+    assert!(matches!(request(&mut conn, &["DEL", "{user1}:a", "other"]).await, RespValue::Error(e) if e.starts_with("CROSSSLOT ")));
+    assert_eq!(request(&mut conn, &["GET", "{user1}:a"]).await, bulk("1"));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn proxy_mode_shards_keys_across_backends() {
+    let a = run_server(ServerConfig::default()).await.unwrap();
+    let b = run_server(ServerConfig::default()).await.unwrap();
+    let config = ServerConfig {
+        proxy_backends: vec![a.local_addr().to_string(), b.local_addr().to_string()],
+        ..ServerConfig::default()
+    };
+    let proxy = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(proxy.local_addr()).await.unwrap());
+
+    let keys: Vec<String> = (0..20).map(|i| format!("key:{}", i)).collect();
+    let mut mset = vec!["MSET".to_string()];
+    for k in &keys {
+        mset.push(k.clone());
+        mset.push(format!("value of {}", k));
+    }
+    let mset: Vec<&str> = mset.iter().map(String::as_str).collect();
+    assert_eq!(request(&mut conn, &mset).await, RespValue::SimpleString("OK".into()));
+
+    // Both backends hold a share and the proxy itself holds nothing
+    assert!(a.db().dbsize() > 0 && b.db().dbsize() > 0);
+    assert_eq!(a.db().dbsize() + b.db().dbsize(), 20);
+    assert_eq!(proxy.db().dbsize(), 0);
+    assert_eq!(request(&mut conn, &["DBSIZE"]).await, RespValue::Integer(20));
+
+    let mut mget = vec!["MGET"];
+    mget.extend(keys.iter().map(String::as_str));
+    mget.push("missing");
+    let mut expected: Vec<RespValue> = keys.iter().map(|k| bulk(&format!("value of {}", k))).collect();
+    expected.push(RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &mget).await, RespValue::Array(Some(expected)));
+    assert_eq!(request(&mut conn, &["INCR", "key:3:hits"]).await, RespValue::Integer(1));
+    assert_eq!(request(&mut conn, &["EXISTS", "key:1", "key:2", "key:3", "nope"]).await, RespValue::Integer(3));
+    assert_eq!(request(&mut conn, &["DEL", "key:1", "key:2", "key:3"]).await, RespValue::Integer(3));
+
+    // Hash-tagged keys share a backend; SELECT carries over to the backends
+    assert_eq!(request(&mut conn, &["SELECT", "2"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["MSET", "{u}:a", "1", "{u}:b", "2"]).await, RespValue::SimpleString("OK".into()));
+    let a2 = a.database(2).unwrap().dbsize();
+    let b2 = b.database(2).unwrap().dbsize();
+    assert!((a2, b2) == (2, 0) || (a2, b2) == (0, 2));
+    assert!(matches!(request(&mut conn, &["SCAN", "0"]).await, RespValue::Error(e) if e.contains("proxy mode")));
+    assert_eq!(request(&mut conn, &["PING"]).await, RespValue::SimpleString("PONG".into()));
+
+    proxy.shutdown().await;
+    a.shutdown().await;
+    b.shutdown().await;
+}
+
+#[tokio::test]
+async fn databases_evict_within_their_own_budget() {
+    let mut maxmemory_db = std::collections::BTreeMap::new();
+    maxmemory_db.insert(1, 1000);
+    let config = ServerConfig {
+        databases: 2,
+        maxmemory_db,
+        maxmemory_policy: server::db::EvictionPolicy::AllKeysLru,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let value = "x".repeat(100);
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    for i in 0..50 {
+        request(&mut conn, &["SET", &format!("other:{}", i), &value]).await;
+    }
+    assert_eq!(request(&mut conn, &["SELECT", "1"]).await, RespValue::SimpleString("OK".into()));
+    for i in 0..50 {
+        request(&mut conn, &["SET", &format!("tenant:{}", i), &value]).await;
+    }
+
+    // Database 1 stays near its budget while database 0 is untouched
+    let tenant = handle.database(1).unwrap();
+    assert!(tenant.used_memory() <= 1000 + 108, "used {}", tenant.used_memory());
+    assert!(tenant.dbsize() < 50);
+    assert_eq!(handle.db().dbsize(), 50);
+    assert!(matches!(request(&mut conn, &["SELECT", "2"]).await, RespValue::Error(e) if e.contains("out of range")));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn process_accounting_charges_the_overhead_to_the_budget() {
+    let config = ServerConfig {
+        maxmemory: 1 << 20,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let ok = RespValue::SimpleString("OK".into());
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert_eq!(request(&mut conn, &["SET", "a", "1"]).await, ok);
+    assert_eq!(request(&mut conn, &["SET", "b", "1"]).await, ok);
+    let info = match request(&mut conn, &["INFO", "memory"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    for field in ["mem_allocator:", "allocator_allocated:", "allocator_frag_ratio:", "used_memory_rss:", "maxmemory_accounting:dataset"] {
+        assert!(info.contains(field), "{}", info);
+    }
+    let stats = request(&mut conn, &["MEMORY", "STATS"]).await;
+    assert!(matches!(&stats, RespValue::Array(Some(fields)) if fields.contains(&bulk("allocator.allocated"))), "{:?}", stats);
+
+    // The process takes well over a megabyte, so its overhead alone fills the budget
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "maxmemory-accounting", "process"]).await, ok);
+    assert!(matches!(request(&mut conn, &["SET", "c", "1"]).await, RespValue::Error(e) if e.starts_with("OOM")));
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "maxmemory-accounting", "dataset"]).await, ok);
+    assert_eq!(request(&mut conn, &["SET", "c", "1"]).await, ok);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn active_defrag_compacts_sparse_shards_and_oversized_values() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let db = handle.db();
+    for i in 0..20_000 {
+        db.set(format!("churn:{}", i), b"x".to_vec(), None);
+    }
+    let churned: Vec<String> = (0..20_000).map(|i| format!("churn:{}", i)).collect();
+    db.del(&churned);
+    let mut roomy = Vec::with_capacity(4096);
+    roomy.extend_from_slice(b"small");
+    db.set("roomy".into(), roomy, None);
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let ok = RespValue::SimpleString("OK".into());
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "activedefrag", "yes"]).await, ok);
+    let mut info = String::new();
+    for _ in 0..100 {
+        info = match request(&mut conn, &["INFO", "stats"]).await {
+            RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+            other => panic!("unexpected INFO reply {:?}", other),
+        };
+        if info.contains("defrag_passes:1\r\n") {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(info.contains("defrag_passes:1\r\n"), "{}", info);
+    assert!(info.contains("defrag_shrunk_values:1\r\n"), "{}", info);
+    assert!(!info.contains("defrag_rebuilt_tables:0\r\n"), "{}", info);
+    let reclaimed: u64 = info
+        .lines()
+        .find_map(|l| l.strip_prefix("defrag_reclaimed_bytes:"))
+        .and_then(|n| n.parse().ok())
+        .unwrap();
+    assert!(reclaimed > 4000, "{}", info);
+    assert_eq!(request(&mut conn, &["GET", "roomy"]).await, bulk("small"));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn keys_left_unread_expire_after_their_idle_limit() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let ok = RespValue::SimpleString("OK".into());
+    assert_eq!(request(&mut conn, &["SET", "unread", "v", "MAXIDLE", "1"]).await, ok);
+    assert_eq!(request(&mut conn, &["SET", "read", "v", "maxidle", "1", "EX", "60"]).await, ok);
+    request(&mut conn, &["SET", "reset", "v", "MAXIDLE", "1"]).await;
+    // A plain SET drops the limit, as it does a TTL
+    request(&mut conn, &["SET", "reset", "w"]).await;
+    request(&mut conn, &["SET", "plain", "v"]).await;
+    assert!(matches!(request(&mut conn, &["SET", "k", "v", "MAXIDLE", "0"]).await, RespValue::Error(_)));
+
+    for _ in 0..4 {
+        tokio::time::sleep(std::time::Duration::from_millis(400)).await;
+        assert_eq!(request(&mut conn, &["GET", "read"]).await, bulk("v"));
+    }
+    assert_eq!(request(&mut conn, &["GET", "unread"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["EXISTS", "reset", "plain"]).await, RespValue::Integer(2));
+
+    // maxidle covers every key without a limit of its own
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "maxidle", "1"]).await, ok);
+    tokio::time::sleep(std::time::Duration::from_millis(1200)).await;
+    assert_eq!(request(&mut conn, &["EXISTS", "reset", "plain"]).await, RespValue::Integer(0));
+    assert_eq!(request(&mut conn, &["GET", "read"]).await, RespValue::BulkString(None));
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn key_event_callbacks_see_the_old_value() {
+    let config = ServerConfig {
+        maxmemory: 250,
+        maxmemory_policy: server::db::EvictionPolicy::AllKeysLru,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let events = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+    let db = handle.db();
+    for event in [KeyEvent::Set, KeyEvent::Deleted, KeyEvent::Expired, KeyEvent::Evicted] {
+        let events = events.clone();
+        let record = move |key: &str, old: Option<&[u8]>| {
+            let old = old.map(|v| String::from_utf8_lossy(v).into_owned());
+            events.lock().unwrap().push((event, key.to_string(), old));
+        };
+        match event {
+            KeyEvent::Set => db.on_set(record),
+            KeyEvent::Deleted => db.on_delete(record),
+            KeyEvent::Expired => db.on_expired(record),
+            KeyEvent::Evicted => db.on_evicted(record),
+        }
+    }
+    let take = || std::mem::take(&mut *events.lock().unwrap());
+
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "k", "1"]).await;
+    request(&mut conn, &["INCR", "k"]).await;
+    request(&mut conn, &["DEL", "k", "missing"]).await;
+    assert_eq!(
+        take(),
+        vec![
+            (KeyEvent::Set, "k".to_string(), None),
+            (KeyEvent::Set, "k".to_string(), Some("1".to_string())),
+            (KeyEvent::Deleted, "k".to_string(), Some("2".to_string())),
+        ]
+    );
+
+    db.set("short".into(), b"v".to_vec(), Some(std::time::Duration::from_millis(10)));
+    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    assert_eq!(request(&mut conn, &["GET", "short"]).await, RespValue::BulkString(None));
+    assert_eq!(take()[1..], [(KeyEvent::Expired, "short".to_string(), Some("v".to_string()))]);
+
+    let value = "x".repeat(100);
+    // The fourth write evicts one of the first three to make room
+    for key in ["a", "b", "c", "d"] {
+        request(&mut conn, &["SET", key, &value]).await;
+    }
+    let evicted: Vec<_> = take().into_iter().filter(|e| e.0 == KeyEvent::Evicted).collect();
+    assert_eq!(evicted.len(), 1);
+    assert!(["a", "b", "c"].contains(&evicted[0].1.as_str()));
+    assert_eq!(evicted[0].2, Some(value));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn noeviction_refuses_writes_over_budget() {
+    let config = ServerConfig {
+        maxmemory: 100,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+
+    let value = "x".repeat(200);
+    assert_eq!(request(&mut conn, &["SET", "big", &value]).await, RespValue::SimpleString("OK".into()));
+    assert!(matches!(request(&mut conn, &["SET", "more", "v"]).await, RespValue::Error(e) if e.starts_with("OOM")));
+    assert!(matches!(request(&mut conn, &["INCR", "n"]).await, RespValue::Error(e) if e.starts_with("OOM")));
+    // Reads go on being served while writes are shed
+    assert_eq!(request(&mut conn, &["GET", "big"]).await, bulk(&value));
+    let info = match request(&mut conn, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(text)) => String::from_utf8(text).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("oom_rejected_commands:2\r\n"), "{}", info);
+    assert_eq!(request(&mut conn, &["DEL", "big"]).await, RespValue::Integer(1));
+    assert_eq!(request(&mut conn, &["SET", "more", "v"]).await, RespValue::SimpleString("OK".into()));
+
+    handle.shutdown().await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn tiered_mode_reads_and_writes_through() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("rustcache-backing-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("origin"), "from-store").unwrap();
+    // One file per key; every load is logged so the test can count them
+    let script = dir.join("store.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\ncd \"$(dirname \"$0\")\"\ncase $1 in\n\
+         get) echo \"$2\" >> loads.log; sleep 0.2; [ -f \"$2\" ] || exit 1; cat \"$2\" ;;\n\
+         set) cat > \"$2\" ;;\n\
+         del) rm -f \"$2\" ;;\nesac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = ServerConfig {
+        backing_store: Some(server::BackingStore::Exec(script)),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let addr = handle.local_addr();
+
+    // Concurrent misses on one key share a single load
+    let gets = (0..5).map(|_| {
+        tokio::spawn(async move {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            request(&mut conn, &["GET", "origin"]).await
+        })
+    });
+    for get in gets.collect::<Vec<_>>() {
+        assert_eq!(get.await.unwrap(), bulk("from-store"));
+    }
+    assert_eq!(std::fs::read_to_string(dir.join("loads.log")).unwrap().lines().count(), 1);
+
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(request(&mut conn, &["GET", "absent"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["SET", "fresh", "v1"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(std::fs::read_to_string(dir.join("fresh")).unwrap(), "v1");
+    assert_eq!(request(&mut conn, &["DEL", "origin"]).await, RespValue::Integer(1));
+    assert!(!dir.join("origin").exists());
+
+    handle.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn loaders_fill_matching_misses_with_one_load_per_key() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = std::env::temp_dir().join(format!("rustcache-loader-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("load.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\ncd \"$(dirname \"$0\")\"\necho \"$1 $2\" >> loads.log\nsleep 0.2\n\
+         [ \"$2\" = user:missing ] && exit 1\nprintf 'loaded %s' \"$2\"\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let config = ServerConfig {
+        loaders: vec![server::Loader { pattern: "user:*".to_string(), store: server::BackingStore::Exec(script) }],
+        loader_ttl: std::time::Duration::from_secs(60),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let addr = handle.local_addr();
+
+    // A stampede of misses on one key costs a single load
+    let gets = (0..50).map(|_| {
+        tokio::spawn(async move {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            request(&mut conn, &["GET", "user:1"]).await
+        })
+    });
+    for get in gets.collect::<Vec<_>>() {
+        assert_eq!(get.await.unwrap(), bulk("loaded user:1"));
+    }
+    let loads = || std::fs::read_to_string(dir.join("loads.log")).unwrap();
+    assert_eq!(loads(), "get user:1\n");
+
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    assert!(matches!(request(&mut conn, &["TTL", "user:1"]).await, RespValue::Integer(1..=60)));
+    let info = match request(&mut conn, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("backing_loads:1\r\n"), "{}", info);
+    assert!(info.contains("backing_loads_shared:49\r\n"), "{}", info);
+
+    // Keys no loader matches, or that the loader lacks, stay missing; writes
+    // are never sent to a loader
+    assert_eq!(request(&mut conn, &["GET", "session:1"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["GET", "user:missing"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["SET", "user:2", "local"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["GET", "user:2"]).await, bulk("local"));
+    assert_eq!(request(&mut conn, &["TTL", "user:2"]).await, RespValue::Integer(-1));
+    assert_eq!(loads(), "get user:1\nget user:missing\n");
+
+    handle.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn overflow_tier_spills_and_faults_values_back_in() {
+    let dir = std::env::temp_dir().join(format!("rustcache-overflow-{}", std::process::id()));
+    let config = ServerConfig {
+        maxmemory: 1000,
+        maxmemory_policy: server::db::EvictionPolicy::AllKeysLru,
+        overflow_dir: Some(dir.clone()),
+        snapshot_file: Some(dir.join("dump.rcs")),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+
+    for i in 0..50 {
+        let value = format!("{:0>100}", i);
+        request(&mut conn, &["SET", &format!("k{}", i), &value]).await;
+    }
+    let db = handle.db();
+    assert!(db.spilled_keys() > 0);
+    assert!(db.used_memory() <= 1000 + 103, "used {}", db.used_memory());
+    assert_eq!(request(&mut conn, &["DBSIZE"]).await, RespValue::Integer(50));
+
+    // Snapshots include spilled keys
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+    let restored = run_server(ServerConfig {
+        snapshot_file: Some(dir.join("dump.rcs")),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    assert_eq!(restored.db().dbsize(), 50);
+    restored.shutdown().await;
+
+    // Every key is still readable, the early ones from disk
+    for i in 0..50 {
+        assert_eq!(request(&mut conn, &["GET", &format!("k{}", i)]).await, bulk(&format!("{:0>100}", i)));
+    }
+    assert_eq!(request(&mut conn, &["DEL", "k0", "k1", "k49"]).await, RespValue::Integer(3));
+    assert_eq!(request(&mut conn, &["DBSIZE"]).await, RespValue::Integer(47));
+
+    handle.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn save_is_loaded_on_restart() {
+    let path = std::env::temp_dir().join(format!("rustcache-save-{}.rcs", std::process::id()));
+    let config = ServerConfig {
+        databases: 2,
+        snapshot_file: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "plain", "v"]).await;
+    request(&mut conn, &["SET", "ttl", "v", "EX", "100"]).await;
+    request(&mut conn, &["SELECT", "1"]).await;
+    request(&mut conn, &["SET", "other-db", "w"]).await;
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+    handle.shutdown().await;
+
+    let handle = run_server(config).await.unwrap();
+    assert_eq!(handle.db().get("plain"), Some(b"v".to_vec()));
+    assert!(handle.db().ttl_seconds("ttl") > 90);
+    assert_eq!(handle.database(1).unwrap().get("other-db"), Some(b"w".to_vec()));
+    handle.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn saves_run_on_the_persistence_io_threads() {
+    let path = std::env::temp_dir().join(format!("rustcache-io-{}.rcs", std::process::id()));
+    let missing = std::env::temp_dir().join("rustcache-no-such-dir").join("dump.rdb");
+    let config = ServerConfig {
+        snapshot_file: Some(path.clone()),
+        rdb_file: Some(missing),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let completed = |info: RespValue| {
+        let RespValue::BulkString(Some(text)) = info else { panic!("unexpected INFO reply") };
+        let text = String::from_utf8(text).unwrap();
+        let line = text.lines().find(|l| l.starts_with("io_jobs_completed:")).unwrap();
+        line["io_jobs_completed:".len()..].parse::<u64>().unwrap()
+    };
+    // Starting up loads, or finds nothing to load, on the same threads
+    let before = completed(request(&mut conn, &["INFO", "persistence"]).await);
+    assert!(before >= 1, "{}", before);
+
+    request(&mut conn, &["SET", "k", "v"]).await;
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+    assert!(path.exists());
+    assert_eq!(completed(request(&mut conn, &["INFO", "persistence"]).await), before + 1);
+
+    // A save that fails reports why to the client that asked
+    match request(&mut conn, &["SAVE", "RDB"]).await {
+        RespValue::Error(e) => assert!(e.starts_with("ERR snapshot failed"), "{}", e),
+        other => panic!("unexpected SAVE reply {:?}", other),
+    }
+    let info = request(&mut conn, &["INFO", "persistence"]).await;
+    let RespValue::BulkString(Some(text)) = &info else { panic!("unexpected INFO reply") };
+    let text = String::from_utf8_lossy(text);
+    assert!(text.contains("io_jobs_queued:0\r\n"), "{}", text);
+    assert!(text.contains("io_jobs_running:0\r\n"), "{}", text);
+    assert_eq!(completed(info), before + 2);
+    handle.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn compressed_snapshots_load_whatever_the_setting() {
+    let path = std::env::temp_dir().join(format!("rustcache-zstd-{}.rcs", std::process::id()));
+    let config = ServerConfig {
+        snapshot_file: Some(path.clone()),
+        compression: Compression::Zstd(3),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config.clone()).await.unwrap();
+    let text = "the quick brown fox jumps over the lazy dog ".repeat(100);
+    for i in 0..50 {
+        handle.db().set(format!("doc:{}", i), text.as_bytes().to_vec(), None);
+    }
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+    handle.shutdown().await;
+    let saved = std::fs::read(&path).unwrap();
+    assert_eq!(saved[..4], [0x28, 0xB5, 0x2F, 0xFD]);
+    assert!(saved.len() < text.len() * 50 / 20, "{} bytes", saved.len());
+
+    let handle = run_server(ServerConfig { compression: Compression::Off, ..config }).await.unwrap();
+    assert_eq!(handle.db().get("doc:49"), Some(text.into_bytes()));
+    handle.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn snapshot_skips_unknown_records_and_rejects_corruption() {
+    let path = std::env::temp_dir().join(format!("rustcache-format-{}.rcs", std::process::id()));
+    let config = ServerConfig {
+        snapshot_file: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "kept", "value"]).await;
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+    handle.shutdown().await;
+
+    // A record type from some future version goes in before the end marker
+    let saved = std::fs::read(&path).unwrap();
+    let mut file = saved[..saved.len() - 9].to_vec();
+    file.push(0x42);
+    file.extend_from_slice(&3u32.to_le_bytes());
+    file.extend_from_slice(b"new");
+    file.push(0xFF);
+    let crc = crc::Crc::<u64>::new(&crc::CRC_64_REDIS).checksum(&file);
+    file.extend_from_slice(&crc.to_le_bytes());
+    std::fs::write(&path, &file).unwrap();
+    let handle = run_server(config.clone()).await.unwrap();
+    assert_eq!(handle.db().get("kept"), Some(b"value".to_vec()));
+    handle.shutdown().await;
+
+    let at = file.windows(5).position(|w| w == b"value").unwrap();
+    file[at] ^= 1;
+    std::fs::write(&path, &file).unwrap();
+    let err = run_server(config).await.err().expect("corrupt snapshot must not load");
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn rdb_files_are_imported_and_exported() {
+    let path = std::env::temp_dir().join(format!("rustcache-import-{}.rdb", std::process::id()));
+    let future_ms = (std::time::SystemTime::now() + std::time::Duration::from_secs(100))
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    // What a Redis 7 server would write, by hand
+    let mut rdb = b"REDIS0011".to_vec();
+    rdb.extend_from_slice(b"\xfa\x0aredis-bits\xc0\x40");
+    rdb.extend_from_slice(b"\xfe\x00\xfb\x04\x01");
+    rdb.extend_from_slice(b"\x00\x03int\xc1\x39\x30");
+    rdb.extend_from_slice(b"\x00\x04long\xc3\x05\x14\x00a\xe0\x0a\x00");
+    rdb.push(0xfc);
+    rdb.extend_from_slice(&future_ms.to_le_bytes());
+    rdb.extend_from_slice(b"\x00\x03ttl\x01v");
+    rdb.extend_from_slice(b"\xfd\x01\x00\x00\x00\x00\x04gone\x01v");
+    rdb.extend_from_slice(b"\x01\x04list\x02\x01a\x01b");
+    rdb.extend_from_slice(b"\xfe\x01\x00\x05other\x01w");
+    rdb.push(0xff);
+    let crc = crc::Crc::<u64>::new(&crc::CRC_64_REDIS).checksum(&rdb);
+    rdb.extend_from_slice(&crc.to_le_bytes());
+    std::fs::write(&path, &rdb).unwrap();
+
+    let config = ServerConfig {
+        databases: 2,
+        rdb_file: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config.clone()).await.unwrap();
+    assert_eq!(handle.db().get("int"), Some(b"12345".to_vec()));
+    assert_eq!(handle.db().get("long"), Some(vec![b'a'; 20]));
+    assert!(handle.db().ttl_seconds("ttl") > 90);
+    assert_eq!(handle.db().get("gone"), None);
+    assert_eq!(handle.db().get("list"), None);
+    assert_eq!(handle.database(1).unwrap().get("other"), Some(b"w".to_vec()));
+
+    // Exporting and importing again gives back the same data
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    request(&mut conn, &["SET", "added", "x"]).await;
+    assert_eq!(request(&mut conn, &["SAVE", "RDB"]).await, RespValue::SimpleString("OK".into()));
+    handle.shutdown().await;
+    assert!(std::fs::read(&path).unwrap().starts_with(b"REDIS0009"));
+    let handle = run_server(config).await.unwrap();
+    assert_eq!(handle.db().dbsize(), 4);
+    assert_eq!(handle.db().get("long"), Some(vec![b'a'; 20]));
+    assert!(handle.db().ttl_seconds("ttl") > 90);
+    assert_eq!(handle.database(1).unwrap().get("other"), Some(b"w".to_vec()));
+    handle.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn time_and_absolute_expiries_follow_the_server_clock() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let (secs, micros) = match request(&mut conn, &["TIME"]).await {
+        RespValue::Array(Some(parts)) => match &parts[..] {
+            [RespValue::BulkString(Some(s)), RespValue::BulkString(Some(us))] => (
+                String::from_utf8_lossy(s).parse::<i64>().unwrap(),
+                String::from_utf8_lossy(us).parse::<i64>().unwrap(),
+            ),
+            other => panic!("unexpected TIME reply {:?}", other),
+        },
+        other => panic!("unexpected TIME reply {:?}", other),
+    };
+    assert!((0..1_000_000).contains(&micros));
+    assert!((0..=1).contains(&(handle.db().clock().unix_ms() / 1000 - secs)));
+
+    let at = (secs + 100).to_string();
+    assert_eq!(request(&mut conn, &["SET", "k", "v", "EXAT", &at]).await, RespValue::SimpleString("OK".into()));
+    assert!((98..=100).contains(&handle.db().ttl_seconds("k")));
+    assert_eq!(request(&mut conn, &["PEXPIREAT", "k", &((secs - 1) * 1000).to_string()]).await, RespValue::Integer(1));
+    assert_eq!(request(&mut conn, &["EXISTS", "k"]).await, RespValue::Integer(0));
+    assert!(matches!(request(&mut conn, &["TIME", "extra"]).await, RespValue::Error(_)));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn set_ifeq_only_writes_over_the_expected_value() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let addr = handle.local_addr();
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    let ok = RespValue::SimpleString("OK".into());
+    assert_eq!(request(&mut conn, &["SET", "k", "v1", "IFEQ", "v1"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["EXISTS", "k"]).await, RespValue::Integer(0));
+    assert_eq!(request(&mut conn, &["SET", "k", "v1"]).await, ok);
+    assert_eq!(request(&mut conn, &["SET", "k", "v2", "IFEQ", "v0"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["SET", "k", "v2", "IFEQ", "v1", "EX", "100"]).await, ok);
+    assert_eq!(request(&mut conn, &["GET", "k"]).await, bulk("v2"));
+    assert!((99..=100).contains(&handle.db().ttl_seconds("k")));
+    assert_eq!(request(&mut conn, &["SET", "k", "v3", "IFEQ"]).await, RespValue::Error("ERR syntax error".into()));
+    assert_eq!(
+        request(&mut conn, &["SET", "k", "v3", "IFEQ", "v2", "IFEQ", "v2"]).await,
+        RespValue::Error("ERR syntax error".into())
+    );
+
+    // Clients racing to increment through compare-and-set lose no updates
+    request(&mut conn, &["SET", "counter", "0"]).await;
+    let mut tasks = Vec::new();
+    for _ in 0..4 {
+        tasks.push(tokio::spawn(async move {
+            let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+            let mut done = 0;
+            while done < 25 {
+                let current = match request(&mut conn, &["GET", "counter"]).await {
+                    RespValue::BulkString(Some(v)) => String::from_utf8(v).unwrap(),
+                    other => panic!("unexpected reply {:?}", other),
+                };
+                let next = (current.parse::<i64>().unwrap() + 1).to_string();
+                if request(&mut conn, &["SET", "counter", &next, "IFEQ", &current]).await != RespValue::BulkString(None) {
+                    done += 1;
+                }
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert_eq!(request(&mut conn, &["GET", "counter"]).await, bulk("100"));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn versions_rise_with_every_write_and_guard_set() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let versioned = |reply: RespValue| match reply {
+        RespValue::Array(Some(parts)) => match &parts[..] {
+            [RespValue::BulkString(Some(v)), RespValue::Integer(version)] => (v.clone(), *version),
+            other => panic!("unexpected reply {:?}", other),
+        },
+        other => panic!("unexpected reply {:?}", other),
+    };
+    assert_eq!(request(&mut conn, &["GET", "k", "WITHVERSION"]).await, RespValue::BulkString(None));
+    request(&mut conn, &["SET", "k", "a"]).await;
+    let (value, first) = versioned(request(&mut conn, &["GET", "k", "WITHVERSION"]).await);
+    assert_eq!(value, b"a");
+    // Reads leave the version alone; rewriting the same value does not
+    assert_eq!(versioned(request(&mut conn, &["GET", "k", "withversion"]).await).1, first);
+    request(&mut conn, &["SET", "k", "a"]).await;
+    let (_, second) = versioned(request(&mut conn, &["GET", "k", "WITHVERSION"]).await);
+    assert!(second > first);
+    request(&mut conn, &["INCR", "n"]).await;
+    request(&mut conn, &["DEL", "k"]).await;
+    request(&mut conn, &["SET", "k", "a"]).await;
+    let (_, third) = versioned(request(&mut conn, &["GET", "k", "WITHVERSION"]).await);
+    assert!(third > second);
+
+    let stale = first.to_string();
+    let current = third.to_string();
+    assert_eq!(request(&mut conn, &["SET", "k", "b", "IFVERSION", &stale]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["SET", "k", "b", "IFVERSION", &current]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["SET", "k", "c", "IFVERSION", &current]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut conn, &["GET", "k"]).await, bulk("b"));
+    assert_eq!(
+        request(&mut conn, &["SET", "k", "c", "IFVERSION", &current, "IFEQ", "b"]).await,
+        RespValue::Error("ERR syntax error".into())
+    );
+    assert_eq!(request(&mut conn, &["GET", "k", "EXTRA"]).await, RespValue::Error("ERR syntax error".into()));
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn connections_reuse_pooled_reply_buffers() {
+    let handle = run_server(ServerConfig::default()).await.unwrap();
+    let info_field = |info: RespValue, name: &str| -> u64 {
+        let RespValue::BulkString(Some(text)) = info else { panic!("unexpected INFO reply") };
+        let text = String::from_utf8(text).unwrap();
+        let line = text.lines().find(|l| l.starts_with(&format!("{}:", name))).unwrap_or_else(|| panic!("{} in {}", name, text));
+        line.split_once(':').unwrap().1.parse().unwrap()
+    };
+    // A large reply goes through the buffer without it staying large
+    handle.db().set("big".into(), vec![b'x'; 1 << 20], None);
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    assert!(matches!(request(&mut conn, &["GET", "big"]).await, RespValue::BulkString(Some(v)) if v.len() == 1 << 20));
+    drop(conn);
+
+    // Its buffer returns to the pool once the server sees it close
+    let mut reused = 0;
+    for _ in 0..50 {
+        let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+        reused = info_field(request(&mut conn, &["INFO", "memory"]).await, "reply_buffers_reused");
+        if reused > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert!(reused > 0);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn debug_digest_matches_servers_holding_the_same_data() {
+    let path = std::env::temp_dir().join(format!("rustcache-digest-{}.rcs", std::process::id()));
+    let config = ServerConfig {
+        databases: 2,
+        snapshot_file: Some(path.clone()),
+        ..ServerConfig::default()
+    };
+    let deadline = (std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 1000).to_string();
+    let writes: [&[&str]; 5] = [
+        &["SET", "a", "1"],
+        &["SET", "b", "2"],
+        &["EXPIREAT", "b", &deadline],
+        &["SELECT", "1"],
+        &["SET", "a", "2"],
+    ];
+    let first = run_server(config.clone()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(first.local_addr()).await.unwrap());
+    let empty = request(&mut conn, &["DEBUG", "DIGEST"]).await;
+    assert_eq!(empty, bulk("0000000000000000"));
+    for write in writes {
+        request(&mut conn, write).await;
+    }
+    let digest = request(&mut conn, &["DEBUG", "DIGEST"]).await;
+    assert_ne!(digest, empty);
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+    let values = request(&mut conn, &["DEBUG", "DIGEST-VALUE", "a", "missing"]).await;
+
+    // The same data written in another order digests the same
+    let second = run_server(ServerConfig::default()).await.unwrap();
+    let mut other = BufReader::new(TcpStream::connect(second.local_addr()).await.unwrap());
+    for write in [writes[3], writes[4], &["SELECT", "0"], writes[1], writes[2], writes[0]] {
+        request(&mut other, write).await;
+    }
+    assert_eq!(request(&mut other, &["DEBUG", "DIGEST"]).await, digest);
+    assert_eq!(request(&mut other, &["SELECT", "1"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut other, &["DEBUG", "DIGEST-VALUE", "a", "missing"]).await, values);
+    match &values {
+        RespValue::Array(Some(digests)) => assert_eq!(digests[1], bulk("0000000000000000")),
+        other => panic!("unexpected DIGEST-VALUE reply {:?}", other),
+    }
+
+    // Values and TTLs both count
+    request(&mut other, &["SET", "a", "3"]).await;
+    assert_ne!(request(&mut other, &["DEBUG", "DIGEST-VALUE", "a"]).await, values);
+    request(&mut other, &["SET", "a", "2"]).await;
+    assert_eq!(request(&mut other, &["DEBUG", "DIGEST"]).await, digest);
+    request(&mut other, &["SELECT", "0"]).await;
+    request(&mut other, &["PERSIST", "b"]).await;
+    assert_ne!(request(&mut other, &["DEBUG", "DIGEST"]).await, digest);
+    request(&mut other, &["EXPIREAT", "b", &deadline]).await;
+    assert_eq!(request(&mut other, &["DEBUG", "DIGEST"]).await, digest);
+    second.shutdown().await;
+
+    // As does a server restored from a snapshot
+    first.shutdown().await;
+    let restored = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(restored.local_addr()).await.unwrap());
+    assert_eq!(request(&mut conn, &["DEBUG", "DIGEST"]).await, digest);
+    restored.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn diagnostics_are_dumped_on_request() {
+    let path = std::env::temp_dir().join(format!("rustcache-diagnostics-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        diagnostics_file: Some(path.clone()),
+        masterauth: Some("hidden".to_string()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    for i in 0..100 {
+        request(&mut conn, &["SET", &format!("key:{}", i), "v"]).await;
+    }
+
+    let report = match request(&mut conn, &["DEBUG", "DUMPSTATS"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected DEBUG DUMPSTATS reply {:?}", other),
+    };
+    for section in ["# Runtime", "# Databases", "# Clients (1)", "# Slowlog", "# Memory", "# Stats", "# Config"] {
+        assert!(report.contains(section), "no {} in {}", section, report);
+    }
+    assert!(report.contains("alive_tasks:"));
+    assert!(report.contains("db0:keys=100,"));
+    assert!(!report.contains("hidden"));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), format!("{}\n", report));
+
+    // What SIGUSR1 runs appends another
+    handle.dump_diagnostics().unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap().matches("=== RustCache diagnostics").count(), 2);
+    handle.shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn value_checksums_catch_damaged_values() {
+    let dir = std::env::temp_dir().join(format!("rustcache-checksums-{}", std::process::id()));
+    let config = ServerConfig {
+        maxmemory: 1000,
+        maxmemory_policy: server::db::EvictionPolicy::AllKeysLru,
+        overflow_dir: Some(dir.clone()),
+        snapshot_file: Some(dir.join("dump.rcs")),
+        value_checksums: true,
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    let value = |i: usize| format!("value-{:0>94}", i);
+    for i in 0..50 {
+        request(&mut conn, &["SET", &format!("k{}", i), &value(i)]).await;
+    }
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+
+    // Damage a value where it was spilled to disk
+    let path = dir.join("db0.overflow");
+    let mut file = std::fs::read(&path).unwrap();
+    let damaged = (0..50).find(|i| file.windows(100).any(|w| w == value(*i).as_bytes())).unwrap();
+    let at = file.windows(100).position(|w| w == value(damaged).as_bytes()).unwrap();
+    file[at + 99] ^= 1;
+    std::fs::write(&path, &file).unwrap();
+    let key = format!("k{}", damaged);
+
+    let err = format!("CORRUPT value of key '{}' failed its checksum", key);
+    match request(&mut conn, &["SAVE"]).await {
+        RespValue::Error(e) => assert!(e.contains("failed its checksum"), "{}", e),
+        other => panic!("unexpected SAVE reply {:?}", other),
+    }
+    assert_eq!(request(&mut conn, &["GET", &key]).await, RespValue::Error(err.clone()));
+    assert_eq!(request(&mut conn, &["MGET", "k49", &key]).await, RespValue::Error(err));
+    assert_eq!(request(&mut conn, &["SET", &key, "x", "IFEQ", "x"]).await, RespValue::BulkString(None));
+    assert_eq!(handle.db().get(&key), None);
+    let info = match request(&mut conn, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("corrupt_values:5\r\n"), "{}", info);
+
+    // Overwriting the value clears it
+    request(&mut conn, &["SET", &key, "fresh"]).await;
+    assert_eq!(request(&mut conn, &["GET", &key]).await, bulk("fresh"));
+    assert_eq!(request(&mut conn, &["SAVE"]).await, RespValue::SimpleString("OK".into()));
+
+    handle.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use server::db::{Database, Entry};
+use server::engine::{StorageEngine, Visit};
+
+// Two shards of ordered maps behind one lock each, with a count of the
+// writes that reach them, to show the database does all its work through
+// the engine it is given
+#[derive(Default)]
+struct OrderedEngine {
+    shards: [Mutex<BTreeMap<String, Entry>>; 2],
+    deadlines: Mutex<BTreeMap<String, Instant>>,
+    writes: AtomicUsize,
+}
+
+impl OrderedEngine {
+    fn shard(&self, key: &str) -> std::sync::MutexGuard<'_, BTreeMap<String, Entry>> {
+        self.shards[self.shard_of(key)].lock().unwrap()
+    }
+}
+
+impl StorageEngine for OrderedEngine {
+    fn get(&self, key: &str, f: &mut dyn FnMut(&Entry)) -> bool {
+        self.shard(key).get(key).map(f).is_some()
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.shard(key).contains_key(key)
+    }
+
+    fn set(&self, key: String, entry: Entry) -> Option<Entry> {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.shard(&key).insert(key, entry)
+    }
+
+    fn update(&self, key: String, f: &mut dyn FnMut(Option<&Entry>) -> Option<Entry>) -> Option<Entry> {
+        let mut shard = self.shard(&key);
+        let new = f(shard.get(&key))?;
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        shard.insert(key, new)
+    }
+
+    fn delete(&self, key: &str) -> Option<(String, Entry)> {
+        self.shard(key).remove_entry(key)
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().unwrap().len()).sum()
+    }
+
+    fn clear(&self) {
+        self.shards.iter().for_each(|s| s.lock().unwrap().clear());
+        self.deadlines.lock().unwrap().clear();
+    }
+
+    fn shards(&self) -> usize {
+        2
+    }
+
+    fn shard_of(&self, key: &str) -> usize {
+        usize::from(key.as_bytes().first().is_some_and(|b| *b >= b'n'))
+    }
+
+    fn position(&self, key: &str) -> usize {
+        let mut tail = [0u8; 8];
+        for (to, from) in tail[1..].iter_mut().zip(key.bytes()) {
+            *to = from;
+        }
+        (self.shard_of(key) << (usize::BITS - 1)) | (usize::from_be_bytes(tail) >> 1)
+    }
+
+    fn shard_len(&self, shard: usize) -> usize {
+        self.shards[shard].lock().unwrap().len()
+    }
+
+    fn iterate(&self, shard: usize, visit: Visit<'_>) {
+        for (key, entry) in self.shards[shard].lock().unwrap().iter() {
+            if !visit(key, entry) {
+                break;
+            }
+        }
+    }
+
+    fn expiry(&self, key: &str) -> Option<Instant> {
+        self.deadlines.lock().unwrap().get(key).copied()
+    }
+
+    fn set_expiry(&self, key: &str, at: Option<Instant>) -> Option<Instant> {
+        let mut deadlines = self.deadlines.lock().unwrap();
+        match at {
+            Some(at) => deadlines.insert(key.to_string(), at),
+            None => deadlines.remove(key),
+        }
+    }
+
+    fn expiring(&self) -> usize {
+        self.deadlines.lock().unwrap().len()
+    }
+
+    fn expired(&self, now: Instant) -> Vec<String> {
+        self.deadlines.lock().unwrap().iter().filter(|(_, at)| **at <= now).map(|(k, _)| k.clone()).collect()
+    }
+}
+
+#[test]
+fn a_database_runs_on_the_engine_it_is_given() {
+    let engine = Arc::new(OrderedEngine::default());
+    let db = Database::new().with_engine(engine.clone());
+
+    db.set("apple".into(), b"1".to_vec(), None);
+    db.set("pear".into(), b"2".to_vec(), Some(Duration::from_secs(100)));
+    assert_eq!(db.incr_by("apple".into(), 41), Ok(42));
+    assert_eq!(db.get("apple"), Some(b"42".to_vec()));
+    assert_eq!(engine.writes.load(Ordering::Relaxed), 3);
+    assert_eq!(engine.shard_len(0), 1);
+    assert_eq!(engine.shard_len(1), 1);
+
+    assert_eq!(db.dbsize(), 2);
+    assert_eq!(db.expires_count(), 1);
+    assert!((99..=100).contains(&db.ttl_seconds("pear")));
+    assert!(db.expire_millis("apple", 0));
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(db.get("apple"), None);
+
+    for key in ["kiwi", "lime", "mango", "olive", "plum"] {
+        db.set(key.into(), b"v".to_vec(), None);
+    }
+    let mut seen = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let (next, keys) = db.scan(cursor, 2, None);
+        seen.extend(keys);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    seen.sort();
+    assert_eq!(seen, ["kiwi", "lime", "mango", "olive", "pear", "plum"]);
+
+    assert_eq!(db.del(&["kiwi", "nothing"]), 1);
+    db.flushdb();
+    assert!(engine.is_empty());
+    assert_eq!(engine.expiring(), 0);
+}
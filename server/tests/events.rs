@@ -0,0 +1,288 @@
+mod common;
+
+use std::time::Duration;
+
+use crc::{Crc, CRC_32_ISCSI};
+use server::db::KeyEvent;
+use server::resp::RespValue;
+use server::{run_server, EventFormat, EventSink, ServerConfig, Webhook};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use common::{connect, request};
+
+async fn stats_when(conn: &mut BufReader<TcpStream>, done: &str) -> String {
+    for _ in 0..100 {
+        let info = match request(conn, &["INFO", "stats"]).await {
+            RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+            other => panic!("unexpected INFO reply {:?}", other),
+        };
+        if info.contains(done) {
+            return info;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("INFO stats never showed {}", done);
+}
+
+// A NATS server that sends the subject and payload of every PUB it gets
+async fn fake_nats() -> (String, mpsc::UnboundedReceiver<(String, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = BufReader::new(stream);
+        conn.get_mut().write_all(b"INFO {\"server_id\":\"fake\",\"max_payload\":1048576}\r\n").await.unwrap();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if conn.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words[..] {
+                ["PUB", subject, len] => {
+                    let mut payload = vec![0; len.parse::<usize>().unwrap() + 2];
+                    conn.read_exact(&mut payload).await.unwrap();
+                    payload.truncate(payload.len() - 2);
+                    let _ = tx.send((subject.to_string(), String::from_utf8(payload).unwrap()));
+                }
+                ["PING"] => conn.get_mut().write_all(b"PONG\r\n").await.unwrap(),
+                _ => {}
+            }
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn key_events_are_published_to_a_nats_subject() {
+    let (nats, mut published) = fake_nats().await;
+    let server = run_server(ServerConfig {
+        events_to: Some(EventSink::Nats { addr: nats.clone(), subject: "cache.keys".to_string() }),
+        reaper_interval: Duration::from_millis(20),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    request(&mut conn, &["SET", "user:1", "x"]).await;
+    request(&mut conn, &["DEL", "user:1"]).await;
+    request(&mut conn, &["SELECT", "2"]).await;
+    request(&mut conn, &["SET", "say \"hi\"", "x", "PX", "10"]).await;
+
+    let mut events = Vec::new();
+    while events.len() < 4 {
+        let event = tokio::time::timeout(Duration::from_secs(5), published.recv()).await.unwrap().unwrap();
+        events.push(event);
+    }
+    let subjects: Vec<&str> = events.iter().map(|(s, _)| s.as_str()).collect();
+    assert_eq!(subjects, ["cache.keys.set", "cache.keys.del", "cache.keys.set", "cache.keys.expired"]);
+    assert!(events[0].1.starts_with("{\"event\":\"set\",\"db\":0,\"key\":\"user:1\",\"time_ms\":"), "{}", events[0].1);
+    assert!(events[3].1.starts_with("{\"event\":\"expired\",\"db\":2,\"key\":\"say \\\"hi\\\"\","), "{}", events[3].1);
+
+    let info = stats_when(&mut conn, "events_published:4\r\n").await;
+    assert!(info.contains(&format!("events_target:nats {} cache.keys\r\n", nats)), "{}", info);
+    assert!(info.contains("events_link_status:up\r\n"), "{}", info);
+    assert!(info.contains("events_queue_len:0\r\n"), "{}", info);
+    assert!(info.contains("events_dropped:0\r\n"), "{}", info);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn events_beyond_the_queue_size_are_dropped_while_the_broker_is_down() {
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+    let server = run_server(ServerConfig {
+        events_to: Some(EventSink::Nats { addr: closed, subject: "keys".to_string() }),
+        events_queue_size: 2,
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    for key in ["a", "b", "c"] {
+        assert_eq!(request(&mut conn, &["SET", key, "1"]).await, RespValue::SimpleString("OK".into()));
+    }
+    let info = stats_when(&mut conn, "events_dropped:1\r\n").await;
+    assert!(info.contains("events_link_status:down\r\n"), "{}", info);
+    assert!(info.contains("events_queue_len:2\r\n"), "{}", info);
+    server.shutdown().await;
+}
+
+// A one-partition Kafka broker that answers Metadata and Produce, sending
+// the record batch of every produce
+async fn fake_kafka() -> (String, mpsc::UnboundedReceiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                loop {
+                    let Ok(len) = stream.read_i32().await else { return };
+                    let mut req = vec![0; len as usize];
+                    stream.read_exact(&mut req).await.unwrap();
+                    let api_key = i16::from_be_bytes([req[0], req[1]]);
+                    let correlation = &req[4..8];
+                    let client_len = i16::from_be_bytes([req[8], req[9]]) as usize;
+                    let body = &req[10 + client_len..];
+                    let mut resp = correlation.to_vec();
+                    match api_key {
+                        3 => {
+                            // One broker, this one; one topic with one partition it leads
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                            resp.extend_from_slice(&9i16.to_be_bytes());
+                            resp.extend_from_slice(b"127.0.0.1");
+                            resp.extend_from_slice(&(addr.port() as i32).to_be_bytes());
+                            resp.extend_from_slice(&(-1i16).to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&0i16.to_be_bytes());
+                            resp.extend_from_slice(&6i16.to_be_bytes());
+                            resp.extend_from_slice(b"events");
+                            resp.push(0);
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&0i16.to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                        }
+                        0 => {
+                            // transactional id, acks, timeout, one topic "events", one partition
+                            let acks = i16::from_be_bytes([body[2], body[3]]);
+                            assert_eq!(acks, -1);
+                            let batch_at = 2 + 2 + 4 + 4 + 2 + 6 + 4 + 4 + 4;
+                            let _ = tx.send(body[batch_at..].to_vec());
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&6i16.to_be_bytes());
+                            resp.extend_from_slice(b"events");
+                            resp.extend_from_slice(&1i32.to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                            resp.extend_from_slice(&0i16.to_be_bytes());
+                            resp.extend_from_slice(&0i64.to_be_bytes());
+                            resp.extend_from_slice(&(-1i64).to_be_bytes());
+                            resp.extend_from_slice(&0i32.to_be_bytes());
+                        }
+                        other => panic!("unexpected Kafka request {}", other),
+                    }
+                    let mut framed = (resp.len() as i32).to_be_bytes().to_vec();
+                    framed.extend_from_slice(&resp);
+                    stream.write_all(&framed).await.unwrap();
+                }
+            });
+        }
+    });
+    (addr.to_string(), rx)
+}
+
+#[tokio::test]
+async fn key_events_are_produced_to_a_kafka_topic() {
+    let (kafka, mut batches) = fake_kafka().await;
+    let server = run_server(ServerConfig {
+        events_to: Some(EventSink::Kafka { addr: kafka, topic: "events".to_string() }),
+        events_format: EventFormat::Text,
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    request(&mut conn, &["SET", "user:1", "x"]).await;
+
+    let batch = tokio::time::timeout(Duration::from_secs(5), batches.recv()).await.unwrap().unwrap();
+    // A v2 record batch whose CRC-32C covers everything from the attributes on
+    assert_eq!(batch[16], 2);
+    let crc = u32::from_be_bytes(batch[17..21].try_into().unwrap());
+    assert_eq!(crc, Crc::<u32>::new(&CRC_32_ISCSI).checksum(&batch[21..]));
+    let records = &batch[21..];
+    let text = String::from_utf8_lossy(records);
+    assert!(text.contains("user:1"), "{:?}", text);
+    assert!(text.contains("set 0 user:1"), "{:?}", text);
+    stats_when(&mut conn, "events_published:1\r\n").await;
+    server.shutdown().await;
+}
+
+// An HTTP server answering each request with the next of `statuses`, then
+// 200, and sending the signature header and body of each
+async fn fake_webhook(statuses: &'static [u16]) -> (String, mpsc::UnboundedReceiver<(Option<String>, String)>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for served in 0.. {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut conn = BufReader::new(stream);
+            let (mut length, mut signature) = (0, None);
+            let mut line = String::new();
+            conn.read_line(&mut line).await.unwrap();
+            assert!(line.starts_with("POST /hook HTTP/1.1"), "{}", line);
+            loop {
+                line.clear();
+                conn.read_line(&mut line).await.unwrap();
+                let Some((name, value)) = line.trim_end().split_once(": ") else { break };
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => length = value.parse().unwrap(),
+                    "x-rustcache-signature" => signature = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; length];
+            conn.read_exact(&mut body).await.unwrap();
+            let status = statuses.get(served).copied().unwrap_or(200);
+            let _ = tx.send((signature, String::from_utf8(body).unwrap()));
+            let reply = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status);
+            conn.get_mut().write_all(reply.as_bytes()).await.unwrap();
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn matching_key_events_are_posted_to_webhooks_in_signed_batches() {
+    let (hook, mut posted) = fake_webhook(&[503]).await;
+    let server = run_server(ServerConfig {
+        webhooks: vec![Webhook {
+            url: format!("http://{}/hook", hook),
+            events: vec![KeyEvent::Set, KeyEvent::Deleted],
+            pattern: "user:*".to_string(),
+        }],
+        webhook_secret: Some("s3cret".to_string()),
+        webhook_batch_delay: Duration::from_millis(200),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    request(&mut conn, &["SET", "user:1", "x"]).await;
+    request(&mut conn, &["SET", "order:1", "x"]).await;
+    request(&mut conn, &["DEL", "user:1"]).await;
+
+    // The first attempt is refused with a 503 and the batch sent again
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), posted.recv()).await.unwrap().unwrap();
+        let signature = signature.unwrap();
+        let hex = signature.strip_prefix("sha256=").unwrap();
+        assert!(hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()), "{}", signature);
+        bodies.push(body);
+    }
+    assert_eq!(bodies[0], bodies[1]);
+    let events: Vec<&str> = bodies[0].split("},{").collect();
+    assert_eq!(events.len(), 2, "{}", bodies[0]);
+    assert!(events[0].starts_with("[{\"event\":\"set\",\"db\":0,\"key\":\"user:1\""), "{}", bodies[0]);
+    assert!(events[1].starts_with("\"event\":\"del\",\"db\":0,\"key\":\"user:1\""), "{}", bodies[0]);
+
+    let info = stats_when(&mut conn, "webhook_delivered:2\r\n").await;
+    assert!(info.contains("webhooks:1\r\n"), "{}", info);
+    assert!(info.contains("webhook_retries:1\r\n"), "{}", info);
+    assert!(info.contains("webhook_failed:0\r\n"), "{}", info);
+    assert!(info.contains("webhook_queue_len:0\r\n"), "{}", info);
+    server.shutdown().await;
+}
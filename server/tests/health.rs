@@ -0,0 +1,55 @@
+use server::resp::{read_resp, RespValue};
+use server::{run_server, ServerConfig, ServerHandle};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+async fn probe(handle: &ServerHandle, path: &str) -> (String, String) {
+    let mut conn = TcpStream::connect(handle.health_addr().unwrap()).await.unwrap();
+    conn.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (head.lines().next().unwrap().to_string(), body.to_string())
+}
+
+#[tokio::test]
+async fn probes_report_liveness_and_readiness() {
+    let dir = std::env::temp_dir().join(format!("rustcache-health-{}", std::process::id()));
+    let config = ServerConfig {
+        health_addr: Some("127.0.0.1:0".into()),
+        snapshot_file: Some(dir.join("dump.rcs")),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    assert_eq!(probe(&handle, "/healthz").await, ("HTTP/1.1 200 OK".into(), "ok\n".into()));
+    assert_eq!(probe(&handle, "/readyz").await, ("HTTP/1.1 200 OK".into(), "ready\n".into()));
+    assert_eq!(probe(&handle, "/metrics").await.0, "HTTP/1.1 404 Not Found");
+
+    // The snapshot's directory does not exist, so saving fails
+    let mut conn = BufReader::new(TcpStream::connect(handle.local_addr()).await.unwrap());
+    conn.get_mut().write_all(b"*1\r\n$4\r\nSAVE\r\n").await.unwrap();
+    assert!(matches!(read_resp(&mut conn).await.unwrap(), RespValue::Error(_)));
+    let (status, body) = probe(&handle, "/readyz").await;
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    assert_eq!(body, "persistence: the last save failed\n");
+    assert_eq!(probe(&handle, "/healthz").await.0, "HTTP/1.1 200 OK");
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn replicas_are_not_ready_without_their_master() {
+    // A master that accepts the connection but never answers the handshake
+    let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let master_addr = master.local_addr().unwrap().to_string();
+    let config = ServerConfig {
+        health_addr: Some("127.0.0.1:0".into()),
+        replicaof: Some(master_addr.clone()),
+        ..ServerConfig::default()
+    };
+    let handle = run_server(config).await.unwrap();
+    let (status, body) = probe(&handle, "/readyz").await;
+    assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+    assert_eq!(body, format!("replication: link to {} is down\n", master_addr));
+    assert_eq!(probe(&handle, "/healthz").await.0, "HTTP/1.1 200 OK");
+    handle.shutdown().await;
+}
@@ -0,0 +1,174 @@
+mod common;
+
+use std::time::Duration;
+
+use server::resp::RespValue;
+use server::{run_server, MirrorOnFull, ServerConfig, ShadowCommands};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+
+use common::{connect, request};
+
+async fn info(conn: &mut BufReader<TcpStream>) -> String {
+    match request(conn, &["INFO", "replication"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    }
+}
+
+// An address nothing is listening on
+async fn closed_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn writes_are_forwarded_to_the_mirror_target() {
+    let target = run_server(ServerConfig::default()).await.unwrap();
+    let source = run_server(ServerConfig {
+        mirror_to: Some(target.local_addr().to_string()),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&source).await;
+    request(&mut conn, &["SET", "a", "1"]).await;
+    request(&mut conn, &["INCR", "a"]).await;
+    request(&mut conn, &["SET", "b", "x"]).await;
+    request(&mut conn, &["EXPIRE", "b", "100"]).await;
+    request(&mut conn, &["SET", "gone", "x"]).await;
+    request(&mut conn, &["DEL", "gone"]).await;
+    // A conditional write that did not happen is not forwarded
+    request(&mut conn, &["SET", "a", "99", "NX"]).await;
+    request(&mut conn, &["SELECT", "1"]).await;
+    request(&mut conn, &["SET", "other", "y"]).await;
+
+    // Forwarded once the target has replied to all seven writes
+    let mut waited = 0;
+    let info = loop {
+        let info = info(&mut conn).await;
+        if info.contains("mirror_forwarded:7\r\n") {
+            break info;
+        }
+        assert!(waited < 100, "the mirror never caught up: {}", info);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        waited += 1;
+    };
+    assert_eq!(target.db().get("a"), Some(b"2".to_vec()));
+    assert!(target.db().ttl_seconds("b") > 90);
+    assert_eq!(target.db().get("gone"), None);
+    assert_eq!(target.database(1).unwrap().get("other"), Some(b"y".to_vec()));
+    assert!(info.contains(&format!("mirror_target:{}\r\n", target.local_addr())), "{}", info);
+    assert!(info.contains("mirror_link_status:up\r\n"), "{}", info);
+    assert!(info.contains("mirror_queue_len:0\r\n"), "{}", info);
+    assert!(info.contains("mirror_dropped:0\r\n"), "{}", info);
+
+    source.shutdown().await;
+    target.shutdown().await;
+}
+
+#[tokio::test]
+async fn a_full_mirror_queue_drops_or_refuses_writes() {
+    for policy in [MirrorOnFull::Drop, MirrorOnFull::Refuse] {
+        let source = run_server(ServerConfig {
+            mirror_to: Some(closed_port().await),
+            mirror_queue_size: 2,
+            mirror_on_full: policy,
+            ..ServerConfig::default()
+        })
+        .await
+        .unwrap();
+        let mut conn = connect(&source).await;
+        for key in ["a", "b"] {
+            assert_eq!(request(&mut conn, &["SET", key, "1"]).await, RespValue::SimpleString("OK".into()));
+        }
+        let third = request(&mut conn, &["SET", "c", "1"]).await;
+        // Reads are served either way
+        assert_eq!(request(&mut conn, &["GET", "a"]).await, RespValue::BulkString(Some(b"1".to_vec())));
+        let info = info(&mut conn).await;
+        assert!(info.contains("mirror_link_status:down\r\n"), "{}", info);
+        assert!(info.contains("mirror_queue_len:2\r\n"), "{}", info);
+        match policy {
+            MirrorOnFull::Drop => {
+                assert_eq!(third, RespValue::SimpleString("OK".into()));
+                assert_eq!(source.db().get("c"), Some(b"1".to_vec()));
+                assert!(info.contains("mirror_dropped:1\r\n"), "{}", info);
+            }
+            MirrorOnFull::Refuse => {
+                assert!(matches!(&third, RespValue::Error(e) if e.contains("mirror queue is full")), "{:?}", third);
+                assert_eq!(source.db().get("c"), None);
+                assert!(info.contains("mirror_refused:1\r\n"), "{}", info);
+            }
+        }
+        source.shutdown().await;
+    }
+}
+
+async fn stats_when(conn: &mut BufReader<TcpStream>, done: &str) -> String {
+    for _ in 0..100 {
+        let info = match request(conn, &["INFO", "stats"]).await {
+            RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+            other => panic!("unexpected INFO reply {:?}", other),
+        };
+        if info.contains(done) {
+            return info;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("INFO stats never showed {}", done.trim());
+}
+
+#[tokio::test]
+async fn shadowed_commands_are_compared_with_the_shadow() {
+    let target = run_server(ServerConfig::default()).await.unwrap();
+    target.db().set("n".to_string(), b"not a number".to_vec(), None);
+    let source = run_server(ServerConfig {
+        shadow_to: Some(target.local_addr().to_string()),
+        shadow_percent: 100,
+        shadow_commands: ShadowCommands::All,
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    source.db().set("t".to_string(), b"not a number".to_vec(), None);
+    let mut conn = connect(&source).await;
+    request(&mut conn, &["SET", "a", "1"]).await;
+    request(&mut conn, &["GET", "a"]).await;
+    // Fails on the shadow only, then here only
+    request(&mut conn, &["INCR", "n"]).await;
+    request(&mut conn, &["INCR", "t"]).await;
+    // Connection commands are not shadowed, keyless reads are
+    request(&mut conn, &["PING"]).await;
+    request(&mut conn, &["DBSIZE"]).await;
+    request(&mut conn, &["SELECT", "1"]).await;
+    request(&mut conn, &["SET", "other", "y"]).await;
+
+    let info = stats_when(&mut conn, "shadow_sent:6\r\n").await;
+    assert!(info.contains("shadow_link_status:up\r\n"), "{}", info);
+    assert!(info.contains("shadow_only_errors:1\r\n"), "{}", info);
+    assert!(info.contains("shadow_primary_only_errors:1\r\n"), "{}", info);
+    assert!(info.contains("shadow_dropped:0\r\n"), "{}", info);
+    assert!(info.contains("shadow_latency_delta_us:"), "{}", info);
+    assert_eq!(target.db().get("a"), Some(b"1".to_vec()));
+    assert_eq!(target.database(1).unwrap().get("other"), Some(b"y".to_vec()));
+    source.shutdown().await;
+
+    // Half of the writes, and none of the reads
+    let source = run_server(ServerConfig {
+        shadow_to: Some(target.local_addr().to_string()),
+        shadow_percent: 50,
+        shadow_commands: ShadowCommands::Writes,
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&source).await;
+    for i in 0..4 {
+        request(&mut conn, &["SET", &format!("w{}", i), "v"]).await;
+        request(&mut conn, &["GET", "a"]).await;
+    }
+    stats_when(&mut conn, "shadow_sent:2\r\n").await;
+    assert_eq!((0..4).filter(|i| target.db().get(&format!("w{}", i)).is_some()).count(), 2);
+    source.shutdown().await;
+    target.shutdown().await;
+}
@@ -0,0 +1,122 @@
+mod common;
+
+use std::time::Duration;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+use common::{frame, reply, request, Conn};
+
+async fn info_field(conn: &mut Conn, field: &str) -> String {
+    let RespValue::BulkString(Some(info)) = request(conn, &["INFO", "stats"]).await else {
+        panic!("INFO replies with a bulk string");
+    };
+    let info = String::from_utf8(info).unwrap();
+    let prefix = format!("{}:", field);
+    info.lines().find_map(|l| l.strip_prefix(&prefix)).unwrap_or_else(|| panic!("no {} in INFO", field)).to_string()
+}
+
+#[tokio::test]
+async fn a_client_that_outpaces_its_replies_is_held_back_not_buffered() {
+    let config = ServerConfig { client_queue_depth: 2, ..ServerConfig::default() };
+    let server = run_server(config).await.unwrap();
+    let mut flood = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+    let mut watch = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+
+    let value = "x".repeat(64 * 1024);
+    assert_eq!(request(&mut flood, &["SET", "big", &value]).await, RespValue::SimpleString("OK".into()));
+    // Far more reply than the socket buffers hold, sent without reading any
+    let gets = 400;
+    let mut pipeline = Vec::new();
+    for _ in 0..gets {
+        pipeline.extend(frame(&["GET", "big"]));
+    }
+    pipeline.extend(frame(&["PING"]));
+    flood.get_mut().write_all(&pipeline).await.unwrap();
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while info_field(&mut watch, "client_queue_stalls").await == "0" {
+        assert!(tokio::time::Instant::now() < deadline, "the queue never filled");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Reading the replies lets the rest through, all of them and in order
+    for _ in 0..gets {
+        assert_eq!(reply(&mut flood).await, RespValue::BulkString(Some(value.clone().into_bytes())));
+    }
+    assert_eq!(reply(&mut flood).await, RespValue::SimpleString("PONG".into()));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn large_commands_fill_the_queue_by_bytes() {
+    let config = ServerConfig { client_queue_depth: 1000, client_queue_bytes: 128 * 1024, ..ServerConfig::default() };
+    let server = run_server(config).await.unwrap();
+    let mut flood = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+    let mut watch = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+
+    let value = "x".repeat(64 * 1024);
+    assert_eq!(request(&mut flood, &["SET", "big", &value]).await, RespValue::SimpleString("OK".into()));
+    // The GETs hold the connection up on its replies while the SETs queue
+    // behind them, a few frames but far more bytes than the budget
+    let (gets, sets) = (400, 8);
+    let mut pipeline = Vec::new();
+    for _ in 0..gets {
+        pipeline.extend(frame(&["GET", "big"]));
+    }
+    for i in 0..sets {
+        pipeline.extend(frame(&["SET", &format!("copy:{}", i), &value]));
+    }
+    let writer = tokio::spawn(async move {
+        flood.get_mut().write_all(&pipeline).await.unwrap();
+        flood
+    });
+
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while info_field(&mut watch, "client_queue_stalls").await == "0" {
+        assert!(tokio::time::Instant::now() < deadline, "the queue never filled");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut flood = writer.await.unwrap();
+    for _ in 0..gets {
+        assert_eq!(reply(&mut flood).await, RespValue::BulkString(Some(value.clone().into_bytes())));
+    }
+    for _ in 0..sets {
+        assert_eq!(reply(&mut flood).await, RespValue::SimpleString("OK".into()));
+    }
+    assert_eq!(server.db().dbsize(), 1 + sets);
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn client_queue_depth_is_validated_and_reported() {
+    let server = run_server(ServerConfig::default()).await.unwrap();
+    let mut conn = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+    assert_eq!(
+        request(&mut conn, &["CONFIG", "GET", "client-queue-depth"]).await,
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"client-queue-depth".to_vec())),
+            RespValue::BulkString(Some(b"128".to_vec())),
+        ]))
+    );
+    assert!(matches!(request(&mut conn, &["CONFIG", "SET", "client-queue-depth", "0"]).await, RespValue::Error(_)));
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "client-queue-depth", "16"]).await, RespValue::SimpleString("OK".into()));
+    assert!(matches!(request(&mut conn, &["CONFIG", "SET", "client-queue-bytes", "0"]).await, RespValue::Error(_)));
+    assert_eq!(request(&mut conn, &["CONFIG", "SET", "client-queue-bytes", "1mb"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(
+        request(&mut conn, &["CONFIG", "GET", "client-queue-bytes"]).await,
+        RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"client-queue-bytes".to_vec())),
+            RespValue::BulkString(Some(b"1048576".to_vec())),
+        ]))
+    );
+
+    // Connections made since use the new depth; this one keeps working
+    let mut fresh = BufReader::new(TcpStream::connect(server.local_addr()).await.unwrap());
+    assert_eq!(request(&mut fresh, &["PING"]).await, RespValue::SimpleString("PONG".into()));
+    assert_eq!(request(&mut conn, &["PING"]).await, RespValue::SimpleString("PONG".into()));
+    server.shutdown().await;
+}
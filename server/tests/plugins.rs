@@ -0,0 +1,51 @@
+mod common;
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig};
+
+use common::{bulk, connect, request};
+
+// Builds the hello example plugin into a target directory of the test's own,
+// so the build does not wait on the lock the running `cargo test` holds
+fn build_hello() -> PathBuf {
+    let target = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("plugins");
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "-p", "rustcache-plugin-api", "--example", "hello", "--target-dir"])
+        .arg(&target)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/.."))
+        .status()
+        .unwrap();
+    assert!(status.success(), "building the hello plugin failed");
+    let name = format!("{}hello{}", std::env::consts::DLL_PREFIX, std::env::consts::DLL_SUFFIX);
+    target.join("debug").join("examples").join(name)
+}
+
+#[tokio::test]
+async fn the_example_plugin_loads_from_the_config_and_serves_its_commands() {
+    let library = build_hello();
+    let path = std::env::temp_dir().join(format!("rustcache-plugins-{}.conf", std::process::id()));
+    std::fs::write(&path, format!("addr 127.0.0.1:0\nloadplugin {}\n", library.display())).unwrap();
+    let config = ServerConfig::load(Some(path.clone())).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let handle = run_server(config).await.unwrap();
+    let mut conn = connect(&handle).await;
+
+    assert_eq!(request(&mut conn, &["HELLO"]).await, bulk("Hello, world"));
+    assert_eq!(request(&mut conn, &["hello", "plugins"]).await, bulk("Hello, plugins"));
+    assert_eq!(request(&mut conn, &["SET", "k", "v"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut conn, &["TAKE", "k"]).await, bulk("v"));
+    assert_eq!(request(&mut conn, &["TAKE", "k"]).await, RespValue::BulkString(None));
+    assert!(matches!(request(&mut conn, &["TAKE"]).await, RespValue::Error(e) if e.contains("wrong number")));
+
+    // A line break in a plugin's error cannot end the reply and smuggle in one of its own
+    assert_eq!(
+        request(&mut conn, &["HELLO", "you", "x\r\n+OK"]).await,
+        RespValue::Error("ERR unexpected argument 'x  +OK'".into())
+    );
+    assert_eq!(request(&mut conn, &["PING"]).await, RespValue::SimpleString("PONG".into()));
+
+    handle.shutdown().await;
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use std::time::Duration;
+
+use server::resp::{read_resp, RespValue};
+use server::{run_server, ServerConfig, SlowSubscriber};
+
+use common::{bulk, connect, reply, request, send, Conn};
+
+fn frame(parts: Vec<RespValue>) -> RespValue {
+    RespValue::Array(Some(parts))
+}
+
+#[tokio::test]
+async fn messages_reach_channel_and_pattern_subscribers() {
+    let server = run_server(ServerConfig::default()).await.unwrap();
+    let mut subscriber = connect(&server).await;
+    let mut publisher = connect(&server).await;
+
+    send(&mut subscriber, &["SUBSCRIBE", "news", "sport"]).await;
+    assert_eq!(reply(&mut subscriber).await, frame(vec![bulk("subscribe"), bulk("news"), RespValue::Integer(1)]));
+    assert_eq!(reply(&mut subscriber).await, frame(vec![bulk("subscribe"), bulk("sport"), RespValue::Integer(2)]));
+    assert_eq!(
+        request(&mut subscriber, &["PSUBSCRIBE", "new*"]).await,
+        frame(vec![bulk("psubscribe"), bulk("new*"), RespValue::Integer(3)])
+    );
+
+    assert_eq!(request(&mut publisher, &["PUBLISH", "news", "hello"]).await, RespValue::Integer(2));
+    assert_eq!(reply(&mut subscriber).await, frame(vec![bulk("message"), bulk("news"), bulk("hello")]));
+    assert_eq!(reply(&mut subscriber).await, frame(vec![bulk("pmessage"), bulk("new*"), bulk("news"), bulk("hello")]));
+    assert_eq!(request(&mut publisher, &["PUBLISH", "weather", "rain"]).await, RespValue::Integer(0));
+
+    // Only subscription commands run until the connection leaves every channel
+    assert!(matches!(request(&mut subscriber, &["GET", "k"]).await, RespValue::Error(e) if e.contains("only (P|S)SUBSCRIBE")));
+    assert_eq!(request(&mut subscriber, &["PING"]).await, frame(vec![bulk("pong"), bulk("")]));
+    send(&mut subscriber, &["UNSUBSCRIBE"]).await;
+    assert_eq!(reply(&mut subscriber).await, frame(vec![bulk("unsubscribe"), bulk("news"), RespValue::Integer(2)]));
+    assert_eq!(reply(&mut subscriber).await, frame(vec![bulk("unsubscribe"), bulk("sport"), RespValue::Integer(1)]));
+    assert_eq!(
+        request(&mut subscriber, &["PUNSUBSCRIBE", "new*"]).await,
+        frame(vec![bulk("punsubscribe"), bulk("new*"), RespValue::Integer(0)])
+    );
+    assert_eq!(request(&mut subscriber, &["GET", "k"]).await, RespValue::BulkString(None));
+    assert_eq!(request(&mut publisher, &["PUBLISH", "news", "again"]).await, RespValue::Integer(0));
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn pubsub_reports_channels_and_subscriber_counts() {
+    let server = run_server(ServerConfig::default()).await.unwrap();
+    let mut first = connect(&server).await;
+    let mut second = connect(&server).await;
+    let mut admin = connect(&server).await;
+
+    send(&mut first, &["SUBSCRIBE", "orders.eu", "orders.us"]).await;
+    reply(&mut first).await;
+    reply(&mut first).await;
+    request(&mut second, &["SUBSCRIBE", "orders.eu"]).await;
+    request(&mut second, &["PSUBSCRIBE", "orders.*"]).await;
+    request(&mut second, &["SSUBSCRIBE", "cart"]).await;
+
+    assert_eq!(
+        request(&mut admin, &["PUBSUB", "CHANNELS"]).await,
+        frame(vec![bulk("orders.eu"), bulk("orders.us")])
+    );
+    assert_eq!(request(&mut admin, &["PUBSUB", "CHANNELS", "*.us"]).await, frame(vec![bulk("orders.us")]));
+    assert_eq!(
+        request(&mut admin, &["PUBSUB", "NUMSUB", "orders.eu", "orders.us", "none"]).await,
+        frame(vec![
+            bulk("orders.eu"),
+            RespValue::Integer(2),
+            bulk("orders.us"),
+            RespValue::Integer(1),
+            bulk("none"),
+            RespValue::Integer(0),
+        ])
+    );
+    assert_eq!(request(&mut admin, &["PUBSUB", "NUMPAT"]).await, RespValue::Integer(1));
+    assert_eq!(request(&mut admin, &["PUBSUB", "SHARDCHANNELS"]).await, frame(vec![bulk("cart")]));
+    assert_eq!(
+        request(&mut admin, &["PUBSUB", "SHARDNUMSUB", "cart"]).await,
+        frame(vec![bulk("cart"), RespValue::Integer(1)])
+    );
+    assert_eq!(request(&mut admin, &["SPUBLISH", "cart", "added"]).await, RespValue::Integer(1));
+    assert_eq!(reply(&mut second).await, frame(vec![bulk("smessage"), bulk("cart"), bulk("added")]));
+
+    // A closed connection leaves its channels
+    drop(second);
+    let mut left = false;
+    for _ in 0..50 {
+        if request(&mut admin, &["PUBSUB", "NUMPAT"]).await == RespValue::Integer(0) {
+            left = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(left, "the closed subscriber's pattern is still listed");
+    assert_eq!(
+        request(&mut admin, &["PUBSUB", "NUMSUB", "orders.eu"]).await,
+        frame(vec![bulk("orders.eu"), RespValue::Integer(1)])
+    );
+    let info = match request(&mut admin, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("pubsub_channels:2\r\n"), "{}", info);
+    assert!(info.contains("pubsub_shardchannels:0\r\n"), "{}", info);
+    server.shutdown().await;
+}
+
+async fn stat(conn: &mut Conn, field: &str) -> u64 {
+    let info = match request(conn, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    let prefix = format!("{}:", field);
+    info.lines().find_map(|l| l.strip_prefix(prefix.as_str())).unwrap().parse().unwrap()
+}
+
+#[tokio::test]
+async fn a_subscriber_that_stops_reading_is_held_to_its_queue() {
+    let server = run_server(ServerConfig {
+        pubsub_queue_size: 4,
+        pubsub_slow_subscriber: SlowSubscriber::DropOldest,
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut stalled = connect(&server).await;
+    let mut publisher = connect(&server).await;
+    request(&mut stalled, &["SUBSCRIBE", "feed"]).await;
+
+    // Messages go on being queued, the oldest making way, once the socket
+    // backs up
+    let message = "x".repeat(1 << 20);
+    let mut published = 0;
+    while stat(&mut publisher, "pubsub_dropped_messages").await == 0 {
+        assert_eq!(request(&mut publisher, &["PUBLISH", "feed", &message]).await, RespValue::Integer(1));
+        published += 1;
+        assert!(published < 500, "nothing was ever dropped");
+    }
+
+    assert_eq!(request(&mut publisher, &["CONFIG", "SET", "pubsub-slow-subscriber", "drop-message"]).await, RespValue::SimpleString("OK".into()));
+    let dropped = stat(&mut publisher, "pubsub_dropped_messages").await;
+    assert_eq!(request(&mut publisher, &["PUBLISH", "feed", &message]).await, RespValue::Integer(0));
+    assert_eq!(stat(&mut publisher, "pubsub_dropped_messages").await, dropped + 1);
+
+    assert_eq!(request(&mut publisher, &["CONFIG", "SET", "pubsub-slow-subscriber", "disconnect"]).await, RespValue::SimpleString("OK".into()));
+    assert_eq!(request(&mut publisher, &["PUBLISH", "feed", &message]).await, RespValue::Integer(0));
+    assert_eq!(stat(&mut publisher, "pubsub_slow_disconnects").await, 1);
+    // What was already written arrives, then the connection closes
+    let closed = tokio::time::timeout(Duration::from_secs(5), async {
+        while read_resp(&mut stalled).await.is_ok() {}
+    })
+    .await;
+    assert!(closed.is_ok(), "the slow subscriber was never disconnected");
+    server.shutdown().await;
+}
@@ -0,0 +1,135 @@
+mod common;
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig, ServerHandle};
+use tokio::net::TcpListener;
+
+use common::request_once;
+
+// Addresses for the members, picked before any of them starts
+async fn free_addrs(n: usize) -> Vec<String> {
+    let mut listeners = Vec::new();
+    for _ in 0..n {
+        listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+    }
+    listeners.iter().map(|l| l.local_addr().unwrap().to_string()).collect()
+}
+
+fn raft_file(addr: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rustcache-raft-{}-{}.log", std::process::id(), addr.replace(':', "-")))
+}
+
+async fn member(addrs: &[String], i: usize) -> ServerHandle {
+    let config = ServerConfig {
+        addr: addrs[i].clone(),
+        raft_node: Some(addrs[i].clone()),
+        raft_peers: addrs.iter().filter(|a| **a != addrs[i]).cloned().collect(),
+        raft_file: raft_file(&addrs[i]),
+        raft_election_timeout: Duration::from_millis(200),
+        ..ServerConfig::default()
+    };
+    run_server(config).await.unwrap()
+}
+
+async fn role(handle: &ServerHandle) -> String {
+    let info = match request_once(handle, &["INFO", "replication"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    info.lines().find_map(|l| l.strip_prefix("raft_role:")).unwrap().to_string()
+}
+
+async fn io_jobs(handle: &ServerHandle) -> u64 {
+    let info = match request_once(handle, &["INFO", "persistence"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    info.lines().find_map(|l| l.strip_prefix("io_jobs_completed:")).unwrap().parse().unwrap()
+}
+
+// Waits for one of `members` to lead and serve reads
+async fn leader(members: &[&ServerHandle]) -> usize {
+    for _ in 0..200 {
+        for (i, m) in members.iter().enumerate() {
+            if role(m).await == "leader" && !matches!(request_once(m, &["GET", "probe"]).await, RespValue::Error(_)) {
+                return i;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    panic!("no leader was elected");
+}
+
+#[tokio::test]
+async fn writes_commit_through_the_leader_and_survive_its_loss() {
+    let addrs = free_addrs(3).await;
+    for addr in &addrs {
+        let _ = std::fs::remove_file(raft_file(addr));
+    }
+    let mut members = Vec::new();
+    for i in 0..3 {
+        members.push(Some(member(&addrs, i).await));
+    }
+    let all: Vec<&ServerHandle> = members.iter().flatten().collect();
+    let first = leader(&all).await;
+
+    let jobs_before = io_jobs(all[first]).await;
+    assert_eq!(request_once(all[first], &["INCR", "fence"]).await, RespValue::Integer(1));
+    assert_eq!(request_once(all[first], &["INCR", "fence"]).await, RespValue::Integer(2));
+    assert_eq!(request_once(all[first], &["GET", "fence"]).await, RespValue::BulkString(Some(b"2".to_vec())));
+    // The log is synced on the persistence I/O threads, not the workers
+    assert!(io_jobs(all[first]).await >= jobs_before + 2);
+
+    // Followers send clients to the leader
+    let follower = (first + 1) % 3;
+    match request_once(all[follower], &["INCR", "fence"]).await {
+        RespValue::Error(e) => assert_eq!(e, format!("NOTLEADER {}", addrs[first])),
+        other => panic!("a follower took a write: {:?}", other),
+    }
+    // ...and apply what commits
+    let mut applied = false;
+    for _ in 0..100 {
+        if all[follower].db().get("fence") == Some(b"2".to_vec()) {
+            applied = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(applied, "the committed write never reached the follower");
+
+    // The remaining two elect a leader that has every acknowledged write
+    members[first].take().unwrap().shutdown().await;
+    let rest: Vec<&ServerHandle> = members.iter().flatten().collect();
+    let second = leader(&rest).await;
+    assert_eq!(request_once(rest[second], &["GET", "fence"]).await, RespValue::BulkString(Some(b"2".to_vec())));
+    assert_eq!(request_once(rest[second], &["INCR", "fence"]).await, RespValue::Integer(3));
+    // A lease goes in the log with its deadline, and a condition still holds
+    let ok = RespValue::SimpleString("OK".into());
+    assert_eq!(request_once(rest[second], &["SET", "lease", "me", "PX", "300"]).await, ok);
+    assert_eq!(request_once(rest[second], &["SET", "lease", "you", "IFEQ", "nobody"]).await, RespValue::BulkString(None));
+    tokio::time::sleep(Duration::from_millis(400)).await;
+
+    // A restarted member rebuilds its data from its log and the leader
+    let restarted = member(&addrs, first).await;
+    let mut caught_up = false;
+    for _ in 0..100 {
+        if restarted.db().get("fence") == Some(b"3".to_vec()) {
+            caught_up = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(caught_up, "the restarted member never caught up");
+    assert_eq!(restarted.db().get("lease"), None, "replaying the log renewed an expired lease");
+
+    restarted.shutdown().await;
+    for m in members.into_iter().flatten() {
+        m.shutdown().await;
+    }
+    for addr in &addrs {
+        let _ = std::fs::remove_file(raft_file(addr));
+    }
+}
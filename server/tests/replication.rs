@@ -0,0 +1,213 @@
+mod common;
+
+use std::time::Duration;
+
+use server::resp::{read_resp, RespValue};
+use server::{run_server, ServerConfig};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use common::{frame, request_once};
+
+const REPLID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+
+fn command_name(cmd: &RespValue) -> Vec<String> {
+    match cmd {
+        RespValue::Array(Some(args)) => args
+            .iter()
+            .map(|a| match a {
+                RespValue::BulkString(Some(b)) => String::from_utf8_lossy(b).into_owned(),
+                _ => String::new(),
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Answers the replica's handshake as a Redis master would, up to and
+// including PSYNC, whose arguments are returned
+async fn accept_replica(listener: &TcpListener) -> (BufReader<TcpStream>, Vec<String>) {
+    let (socket, _) = listener.accept().await.unwrap();
+    let mut conn = BufReader::new(socket);
+    loop {
+        let cmd = command_name(&read_resp(&mut conn).await.unwrap());
+        let reply: &[u8] = match cmd[0].to_ascii_uppercase().as_str() {
+            "AUTH" => {
+                assert_eq!(cmd[1..], ["secret"]);
+                b"+OK\r\n"
+            }
+            "PING" => b"+PONG\r\n",
+            "REPLCONF" => b"+OK\r\n",
+            "PSYNC" => return (conn, cmd[1..].to_vec()),
+            other => panic!("unexpected handshake command {}", other),
+        };
+        conn.get_mut().write_all(reply).await.unwrap();
+    }
+}
+
+// Reads what the replica sends until it acknowledges `offset`
+async fn wait_for_ack(conn: &mut BufReader<TcpStream>, offset: usize) {
+    loop {
+        let cmd = command_name(&read_resp(conn).await.unwrap());
+        assert_eq!(cmd[..2], ["REPLCONF", "ACK"]);
+        if cmd[2] == offset.to_string() {
+            return;
+        }
+    }
+}
+
+#[tokio::test]
+async fn replicates_from_a_redis_master() {
+    let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = ServerConfig {
+        replicaof: Some(master.local_addr().unwrap().to_string()),
+        masterauth: Some("secret".into()),
+        ..ServerConfig::default()
+    };
+    let replica = run_server(config).await.unwrap();
+
+    // A garbled payload length fails the sync rather than the replica
+    let (mut conn, _) = accept_replica(&master).await;
+    let garbled = format!("+FULLRESYNC {} 100\r\n${}\r\nREDIS", REPLID, 1u64 << 62);
+    conn.get_mut().write_all(garbled.as_bytes()).await.unwrap();
+    drop(conn);
+
+    let (mut conn, psync) = accept_replica(&master).await;
+    assert_eq!(psync, ["?", "-1"]);
+    let mut rdb = b"REDIS0011\xfe\x00\x00\x06seeded\x01v\x01\x04list\x02\x01a\x01b\xff".to_vec();
+    rdb.extend_from_slice(&[0; 8]);
+    let mut payload = format!("+FULLRESYNC {} 100\r\n\n\n${}\r\n", REPLID, rdb.len()).into_bytes();
+    payload.extend_from_slice(&rdb);
+    conn.get_mut().write_all(&payload).await.unwrap();
+
+    let future_ms = (std::time::SystemTime::now() + Duration::from_secs(100))
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis()
+        .to_string();
+    let stream = [
+        frame(&["SELECT", "0"]),
+        frame(&["SET", "a", "1"]),
+        frame(&["SET", "b", "2", "PXAT", &future_ms]),
+        frame(&["HSET", "h", "f", "v"]),
+        frame(&["MULTI"]),
+        frame(&["INCR", "a"]),
+        frame(&["EXEC"]),
+        frame(&["DEL", "seeded"]),
+        frame(&["SELECT", "1"]),
+        frame(&["SET", "other", "x"]),
+        frame(&["PING"]),
+    ]
+    .concat();
+    conn.get_mut().write_all(&stream).await.unwrap();
+    conn.get_mut().write_all(&frame(&["REPLCONF", "GETACK", "*"])).await.unwrap();
+    wait_for_ack(&mut conn, 100 + stream.len()).await;
+
+    assert_eq!(replica.db().get("a"), Some(b"2".to_vec()));
+    assert!(replica.db().ttl_seconds("b") > 90);
+    assert_eq!(replica.db().get("seeded"), None);
+    assert_eq!(replica.database(1).unwrap().get("other"), Some(b"x".to_vec()));
+    assert!(matches!(request_once(&replica, &["SET", "a", "3"]).await, RespValue::Error(e) if e.starts_with("READONLY ")));
+    let info = match request_once(&replica, &["INFO", "replication"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("role:slave\r\n"), "{}", info);
+    assert!(info.contains("master_link_status:up\r\n"), "{}", info);
+    assert!(info.contains("repl_skipped_commands:1\r\n"), "{}", info);
+
+    // After the link drops the replica continues where it left off
+    let offset = 100 + stream.len() + frame(&["REPLCONF", "GETACK", "*"]).len();
+    drop(conn);
+    let (mut conn, psync) = accept_replica(&master).await;
+    assert_eq!(psync, [REPLID.to_string(), (offset + 1).to_string()]);
+    conn.get_mut().write_all(b"+CONTINUE\r\n").await.unwrap();
+    let more = frame(&["SET", "c", "3"]);
+    conn.get_mut().write_all(&more).await.unwrap();
+    conn.get_mut().write_all(&frame(&["REPLCONF", "GETACK", "*"])).await.unwrap();
+    wait_for_ack(&mut conn, offset + more.len()).await;
+    assert_eq!(replica.database(1).unwrap().get("c"), None);
+    assert_eq!(replica.db().get("c"), Some(b"3".to_vec()));
+
+    replica.shutdown().await;
+}
+
+#[tokio::test]
+async fn a_master_takes_writes_only_with_enough_replicas() {
+    let master = run_server(ServerConfig { min_replicas_to_write: 1, ..ServerConfig::default() }).await.unwrap();
+    match request_once(&master, &["SET", "k", "before"]).await {
+        RespValue::Error(e) => assert!(e.starts_with("NOREPLICAS"), "{}", e),
+        other => panic!("write taken without a replica: {:?}", other),
+    }
+
+    let replica = run_server(ServerConfig { replicaof: Some(master.local_addr().to_string()), ..ServerConfig::default() })
+        .await
+        .unwrap();
+    let mut accepted = false;
+    for _ in 0..100 {
+        if request_once(&master, &["SET", "k", "after"]).await == RespValue::SimpleString("OK".into()) {
+            accepted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(accepted, "writes were still refused with a replica connected");
+
+    // The write reaches the replica over the stream
+    let mut replicated = false;
+    for _ in 0..100 {
+        if request_once(&replica, &["GET", "k"]).await == RespValue::BulkString(Some(b"after".to_vec())) {
+            replicated = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(replicated, "the write never reached the replica");
+    let info = match request_once(&master, &["INFO", "replication"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    assert!(info.contains("connected_slaves:1\r\n"), "{}", info);
+    assert!(info.contains("min_slaves_good_slaves:1\r\n"), "{}", info);
+
+    // Without its replica, the master stops taking writes once the lag runs out
+    replica.shutdown().await;
+    assert_eq!(
+        request_once(&master, &["CONFIG", "SET", "min-replicas-max-lag", "0"]).await,
+        RespValue::SimpleString("OK".into())
+    );
+    let mut refused = false;
+    for _ in 0..100 {
+        if matches!(request_once(&master, &["SET", "k", "late"]).await, RespValue::Error(e) if e.starts_with("NOREPLICAS")) {
+            refused = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(refused, "writes were still taken after the replica left");
+    master.shutdown().await;
+}
+
+#[tokio::test]
+async fn a_replica_without_its_master_can_refuse_stale_reads() {
+    // A master that never answers the handshake
+    let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let config = ServerConfig {
+        replicaof: Some(master.local_addr().unwrap().to_string()),
+        replica_serve_stale_data: false,
+        ..ServerConfig::default()
+    };
+    let replica = run_server(config).await.unwrap();
+
+    match request_once(&replica, &["GET", "k"]).await {
+        RespValue::Error(e) => assert!(e.starts_with("MASTERDOWN "), "{}", e),
+        other => panic!("stale read served: {:?}", other),
+    }
+    assert!(matches!(request_once(&replica, &["INFO", "replication"]).await, RespValue::BulkString(Some(_))));
+    assert_eq!(
+        request_once(&replica, &["CONFIG", "SET", "replica-serve-stale-data", "yes"]).await,
+        RespValue::SimpleString("OK".into())
+    );
+    assert_eq!(request_once(&replica, &["GET", "k"]).await, RespValue::BulkString(None));
+    replica.shutdown().await;
+}
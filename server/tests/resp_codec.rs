@@ -0,0 +1,130 @@
+use proptest::prelude::*;
+use server::resp::{read_resp, write_resp, RespValue, MAX_BULK_LEN, MAX_DEPTH, MAX_LINE_LEN, STREAM_THRESHOLD};
+use server::CommandError;
+use tokio::io::BufReader;
+
+fn decode(bytes: &[u8]) -> std::io::Result<RespValue> {
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    rt.block_on(async {
+        let mut reader = BufReader::new(bytes);
+        read_resp(&mut reader).await
+    })
+}
+
+fn encode(value: &RespValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    value.encode(&mut out);
+    out
+}
+
+fn resp_value() -> impl Strategy<Value = RespValue> {
+    let leaf = prop_oneof![
+        "[^\r\n]{0,32}".prop_map(RespValue::SimpleString),
+        "[^\r\n]{0,32}".prop_map(RespValue::Error),
+        any::<i64>().prop_map(RespValue::Integer),
+        proptest::option::of(proptest::collection::vec(any::<u8>(), 0..64)).prop_map(RespValue::BulkString),
+    ];
+    leaf.prop_recursive(4, 64, 8, |inner| {
+        proptest::option::of(proptest::collection::vec(inner, 0..8)).prop_map(RespValue::Array)
+    })
+}
+
+proptest! {
+    #[test]
+    fn decode_inverts_encode(value in resp_value()) {
+        prop_assert_eq!(decode(&encode(&value)).unwrap(), value);
+    }
+
+    #[test]
+    fn truncated_frames_are_errors(value in resp_value(), cut in any::<prop::sample::Index>()) {
+        let bytes = encode(&value);
+        let cut = cut.index(bytes.len());
+        prop_assert!(decode(&bytes[..cut]).is_err());
+    }
+
+    #[test]
+    fn arbitrary_bytes_never_panic(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let _ = decode(&bytes);
+    }
+
+    #[test]
+    fn arbitrary_headers_never_panic(prefix in prop::sample::select(vec![b'$', b'*', b':', b'+', b'-']), len in any::<i64>()) {
+        let mut bytes = vec![prefix];
+        bytes.extend_from_slice(format!("{}\r\n", len).as_bytes());
+        let _ = decode(&bytes);
+    }
+}
+
+#[test]
+fn huge_bulk_length_is_rejected_without_allocating() {
+    let frame = format!("${}\r\n", MAX_BULK_LEN + 1);
+    assert!(decode(frame.as_bytes()).is_err());
+    // Within the limit but unbacked by data: must fail on EOF, not reserve 512MB first
+    let frame = format!("${}\r\nabc", MAX_BULK_LEN);
+    assert_eq!(decode(frame.as_bytes()).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+}
+
+#[test]
+fn huge_array_length_is_rejected_without_allocating() {
+    let frame = format!("*{}\r\n", i64::MAX);
+    assert!(decode(frame.as_bytes()).is_err());
+}
+
+#[test]
+fn deep_nesting_is_rejected() {
+    let frame = "*1\r\n".repeat(MAX_DEPTH + 1) + ":1\r\n";
+    assert!(decode(frame.as_bytes()).is_err());
+    let frame = "*1\r\n".repeat(MAX_DEPTH) + ":1\r\n";
+    assert!(decode(frame.as_bytes()).is_ok());
+}
+
+#[test]
+fn overlong_lines_are_rejected() {
+    let mut frame = vec![b'+'];
+    frame.extend(std::iter::repeat_n(b'a', MAX_LINE_LEN + 1));
+    frame.extend_from_slice(b"\r\n");
+    assert!(decode(&frame).is_err());
+    let mut frame = vec![b'+'];
+    frame.extend(std::iter::repeat_n(b'a', MAX_LINE_LEN));
+    frame.extend_from_slice(b"\r\n");
+    assert!(decode(&frame).is_ok());
+}
+
+#[test]
+fn command_errors_encode_with_their_class_prefix() {
+    let moved = CommandError::Moved {
+        slot: 3999,
+        addr: "127.0.0.1:6381".into(),
+    };
+    assert_eq!(encode(&moved.into()), b"-MOVED 3999 127.0.0.1:6381\r\n");
+    assert_eq!(encode(&CommandError::WrongType.into())[..11], *b"-WRONGTYPE ");
+    assert_eq!(encode(&CommandError::Syntax.into()), b"-ERR syntax error\r\n");
+    assert_eq!(CommandError::ExecAbort.prefix(), "EXECABORT");
+}
+
+#[test]
+fn streamed_replies_match_the_buffered_encoding() {
+    let large: Vec<u8> = (0..STREAM_THRESHOLD * 3 + 17).map(|i| i as u8).collect();
+    let value = RespValue::Array(Some(vec![
+        RespValue::BulkString(Some(b"small".to_vec())),
+        RespValue::BulkString(Some(large.clone())),
+        RespValue::Integer(7),
+        RespValue::BulkString(Some(large)),
+    ]));
+    let expected = encode(&value);
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let written = rt.block_on(async {
+        // A pipe far smaller than the reply, drained as it fills
+        let (mut client, server) = tokio::io::duplex(4096);
+        let writer = async move {
+            let mut server = server;
+            write_resp(&mut server, &value, &mut Vec::new()).await.unwrap();
+        };
+        let mut written = Vec::new();
+        let reader = tokio::io::AsyncReadExt::read_to_end(&mut client, &mut written);
+        let (_, read) = tokio::join!(writer, reader);
+        read.unwrap();
+        written
+    });
+    assert_eq!(written, expected);
+}
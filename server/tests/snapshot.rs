@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use server::db::{CorruptValue, Database, SnapshotRecord};
+
+fn contents(records: Vec<SnapshotRecord>) -> HashMap<String, Vec<u8>> {
+    records.into_iter().map(|r| (r.key, r.value)).collect()
+}
+
+fn read_all(db: &Database) -> Vec<SnapshotRecord> {
+    let mut records = Vec::new();
+    db.begin_snapshot()
+        .unwrap()
+        .read(|r| {
+            records.push(r);
+            Ok::<_, CorruptValue>(())
+        })
+        .unwrap();
+    records
+}
+
+#[test]
+fn snapshot_ignores_writes_made_after_it_began() {
+    let db = Database::new();
+    for i in 0..1000 {
+        db.set(format!("k{}", i), b"old".to_vec(), None);
+    }
+    db.set("ttl".into(), b"t".to_vec(), Some(Duration::from_secs(60)));
+
+    let snapshot = db.begin_snapshot().unwrap();
+    assert!(db.begin_snapshot().is_none(), "one snapshot at a time");
+    db.set("k1".into(), b"new".to_vec(), None);
+    db.del(&["k2".to_string()]);
+    db.set("created".into(), b"x".to_vec(), None);
+    db.expire_seconds("k3", 10);
+    db.incr_by("k4".into(), 1).unwrap_err();
+
+    let mut records = Vec::new();
+    snapshot
+        .read(|r| {
+            records.push(r);
+            Ok::<_, CorruptValue>(())
+        })
+        .unwrap();
+    assert!(records.iter().any(|r| r.key == "ttl" && r.expires_at.is_some()));
+    assert!(records.iter().any(|r| r.key == "k3" && r.expires_at.is_none()));
+    let seen = contents(records);
+    assert_eq!(seen.len(), 1001);
+    assert_eq!(seen["k1"], b"old");
+    assert_eq!(seen["k2"], b"old");
+    assert!(!seen.contains_key("created"));
+
+    // Once finished, later snapshots see the writes
+    let seen = contents(read_all(&db));
+    assert_eq!(seen["k1"], b"new");
+    assert!(!seen.contains_key("k2"));
+}
+
+#[test]
+fn snapshot_is_consistent_under_concurrent_writes() {
+    let db = Database::new();
+    for i in 0..20_000 {
+        db.set(format!("k{}", i), b"0".to_vec(), None);
+    }
+    let snapshot = db.begin_snapshot().unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writers: Vec<_> = (0..4)
+        .map(|t| {
+            let (db, stop) = (db.clone(), stop.clone());
+            std::thread::spawn(move || {
+                let mut i = t;
+                while !stop.load(Ordering::Relaxed) {
+                    let key = format!("k{}", i % 20_000);
+                    match i % 3 {
+                        0 => db.set(key, b"1".to_vec(), None),
+                        1 => drop(db.del(&[key])),
+                        _ => db.set(format!("new{}", i), b"1".to_vec(), None),
+                    }
+                    i += 4;
+                }
+            })
+        })
+        .collect();
+
+    let mut records = Vec::new();
+    snapshot
+        .read(|r| {
+            records.push(r);
+            Ok::<_, CorruptValue>(())
+        })
+        .unwrap();
+    stop.store(true, Ordering::Relaxed);
+    for w in writers {
+        w.join().unwrap();
+    }
+
+    assert_eq!(records.len(), 20_000, "every key exactly once");
+    let seen = contents(records);
+    assert_eq!(seen.len(), 20_000);
+    assert!(seen.values().all(|v| v == b"0"));
+}
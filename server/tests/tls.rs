@@ -0,0 +1,133 @@
+mod common;
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig, ServerHandle};
+use tokio::io::BufReader;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, Error, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+use common::request;
+
+type Conn = BufReader<TlsStream<TcpStream>>;
+
+// Trusts any certificate, so the test can look at which one it was given
+#[derive(Debug)]
+struct AnyCertificate(CryptoProvider);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+// Connects over TLS and returns the connection with the certificate served
+async fn connect(handle: &ServerHandle) -> (Conn, Vec<u8>) {
+    let provider = Arc::new(ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate((*provider).clone())))
+        .with_no_client_auth();
+    let socket = TcpStream::connect(handle.tls_addr().unwrap()).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap();
+    let served = stream.get_ref().1.peer_certificates().unwrap()[0].to_vec();
+    (BufReader::new(stream), served)
+}
+
+// Writes a new self-signed certificate and its key to `dir`, returning the
+// certificate as DER
+fn issue(dir: &Path, name: &str) -> Vec<u8> {
+    let issued = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    std::fs::write(dir.join(format!("{}.key", name)), issued.signing_key.serialize_pem()).unwrap();
+    std::fs::write(dir.join(format!("{}.pem", name)), issued.cert.pem()).unwrap();
+    issued.cert.der().to_vec()
+}
+
+fn temp_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rustcache-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn certificates_rotate_without_dropping_connections() {
+    let dir = temp_dir();
+    let first = issue(&dir, "server");
+    let server = run_server(ServerConfig {
+        tls_addr: Some("127.0.0.1:0".to_string()),
+        tls_cert_file: Some(dir.join("server.pem")),
+        tls_key_file: Some(dir.join("server.key")),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let (mut open, served) = connect(&server).await;
+    assert_eq!(served, first);
+    assert_eq!(request(&mut open, &["SET", "k", "v"]).await, RespValue::SimpleString("OK".into()));
+
+    // The files are renewed in place and picked up by the watcher
+    let renewed = issue(&dir, "server");
+    let mut rotated = false;
+    for _ in 0..50 {
+        if connect(&server).await.1 == renewed {
+            rotated = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(rotated, "the renewed certificate was never served");
+    assert_eq!(request(&mut open, &["GET", "k"]).await, RespValue::BulkString(Some(b"v".to_vec())));
+
+    // CONFIG SET switches files at once, and refuses ones that do not load
+    let other = issue(&dir, "other");
+    let missing = dir.join("missing.pem");
+    let reply = request(&mut open, &["CONFIG", "SET", "tls-cert-file", missing.to_str().unwrap()]).await;
+    assert!(matches!(reply, RespValue::Error(_)), "{:?}", reply);
+    assert_eq!(connect(&server).await.1, renewed);
+    let (cert, key) = (dir.join("other.pem"), dir.join("other.key"));
+    let reply = request(&mut open, &["CONFIG", "SET", "tls-cert-file", cert.to_str().unwrap(), "tls-key-file", key.to_str().unwrap()]).await;
+    assert_eq!(reply, RespValue::SimpleString("OK".into()));
+    assert_eq!(connect(&server).await.1, other);
+
+    let info = match request(&mut open, &["INFO", "stats"]).await {
+        RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+        other => panic!("unexpected INFO reply {:?}", other),
+    };
+    let reloads: u64 = info.lines().find_map(|l| l.strip_prefix("tls_cert_reloads:")).unwrap().parse().unwrap();
+    assert!(reloads >= 2, "{}", info);
+
+    server.shutdown().await;
+    std::fs::remove_dir_all(&dir).unwrap();
+}
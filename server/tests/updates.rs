@@ -0,0 +1,51 @@
+use std::time::Duration;
+
+use server::db::{CorruptValue, Database};
+
+#[test]
+fn concurrent_increments_are_not_lost() {
+    let db = Database::new();
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for _ in 0..5000 {
+                    db.incr_by("counter".into(), 1).unwrap();
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(db.get("counter"), Some(b"20000".to_vec()));
+    assert_eq!(db.used_memory(), "counter".len() + "20000".len());
+}
+
+#[test]
+fn updates_see_the_current_value_and_keep_the_ttl() {
+    let db = Database::new();
+    db.set("k".into(), b"abc".to_vec(), Some(Duration::from_secs(100)));
+    let appended = db.update("k".into(), |current| {
+        let mut value = current.unwrap_or_default().to_vec();
+        value.extend_from_slice(b"def");
+        let len = value.len();
+        Ok::<_, CorruptValue>((Some(value), len))
+    });
+    assert_eq!(appended, Ok(6));
+    assert_eq!(db.get("k"), Some(b"abcdef".to_vec()));
+    assert!(db.ttl_seconds("k") > 90);
+
+    // Declining to write or failing leaves the key alone
+    assert_eq!(db.update("k".into(), |_| Ok::<_, CorruptValue>((None, "kept"))), Ok("kept"));
+    assert_eq!(db.update("k".into(), |_| Err::<(Option<Vec<u8>>, ()), _>(String::from("no"))), Err("no".into()));
+    assert_eq!(db.get("k"), Some(b"abcdef".to_vec()));
+    assert_eq!(db.update("missing".into(), |current| Ok::<_, CorruptValue>((None, current.is_none()))), Ok(true));
+    assert_eq!(db.dbsize(), 1);
+
+    // A key that has expired reads as absent
+    db.set("gone".into(), b"1".to_vec(), Some(Duration::from_millis(1)));
+    std::thread::sleep(Duration::from_millis(5));
+    assert_eq!(db.incr_by("gone".into(), 5), Ok(5));
+    assert_eq!(db.ttl_seconds("gone"), -1);
+}
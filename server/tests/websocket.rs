@@ -0,0 +1,184 @@
+use server::{run_server, ServerConfig, ServerHandle};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+// The example key and accept value from RFC 6455
+const KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+const ACCEPT: &str = "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=";
+
+async fn websocket_server(origins: &[&str]) -> ServerHandle {
+    let config = ServerConfig {
+        websocket_addr: Some("127.0.0.1:0".into()),
+        websocket_origins: origins.iter().map(|o| o.to_string()).collect(),
+        slowlog_log_slower_than: 0,
+        ..ServerConfig::default()
+    };
+    run_server(config).await.unwrap()
+}
+
+// Sends the upgrade request and returns the status line and headers
+async fn upgrade(handle: &ServerHandle, extra: &str) -> (BufReader<TcpStream>, Vec<String>) {
+    let stream = TcpStream::connect(handle.websocket_addr().unwrap()).await.unwrap();
+    let mut conn = BufReader::new(stream);
+    let request = format!(
+        "GET / HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+        KEY, extra
+    );
+    conn.get_mut().write_all(request.as_bytes()).await.unwrap();
+    let mut response = Vec::new();
+    loop {
+        let mut line = String::new();
+        conn.read_line(&mut line).await.unwrap();
+        if line.trim().is_empty() {
+            return (conn, response);
+        }
+        response.push(line.trim().to_string());
+    }
+}
+
+async fn send_frame(conn: &mut BufReader<TcpStream>, opcode: u8, payload: &[u8]) {
+    let mask = [0x12, 0x34, 0x56, 0x78];
+    let mut frame = vec![0x80 | opcode];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    conn.get_mut().write_all(&frame).await.unwrap();
+}
+
+async fn read_frame(conn: &mut BufReader<TcpStream>) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    conn.read_exact(&mut head).await.unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames are not masked");
+    let len = match head[1] & 0x7f {
+        126 => conn.read_u16().await.unwrap() as usize,
+        127 => conn.read_u64().await.unwrap() as usize,
+        n => n as usize,
+    };
+    let mut payload = vec![0u8; len];
+    conn.read_exact(&mut payload).await.unwrap();
+    (head[0] & 0x0f, payload)
+}
+
+async fn json(conn: &mut BufReader<TcpStream>, envelope: &str) -> String {
+    send_frame(conn, 0x1, envelope.as_bytes()).await;
+    let (opcode, payload) = read_frame(conn).await;
+    assert_eq!(opcode, 0x1);
+    String::from_utf8(payload).unwrap()
+}
+
+#[tokio::test]
+async fn websocket_clients_send_json_or_resp_commands() {
+    let handle = websocket_server(&["http://localhost:8080"]).await;
+    let (mut conn, response) = upgrade(&handle, "Origin: http://localhost:8080\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+    assert!(response.contains(&format!("Sec-WebSocket-Accept: {}", ACCEPT)), "{:?}", response);
+
+    assert_eq!(json(&mut conn, r#"["SET", "greeting", "héllo \"you\""]"#).await, r#""OK""#);
+    assert_eq!(json(&mut conn, r#"["GET","greeting"]"#).await, "\"h\u{e9}llo \\\"you\\\"\"");
+    assert_eq!(json(&mut conn, r#"["EXPIRE", "greeting", 60]"#).await, "1");
+    assert_eq!(json(&mut conn, r#"["MGET", "greeting", "missing"]"#).await, "[\"h\u{e9}llo \\\"you\\\"\",null]");
+    assert!(json(&mut conn, r#"["NOSUCHCOMMAND"]"#).await.starts_with(r#"{"error":"ERR "#));
+
+    send_frame(&mut conn, 0x9, b"are you there").await;
+    assert_eq!(read_frame(&mut conn).await, (0xa, b"are you there".to_vec()));
+
+    // A message that is not an envelope closes the connection as invalid
+    send_frame(&mut conn, 0x1, b"{\"cmd\": \"GET\"}").await;
+    let (opcode, payload) = read_frame(&mut conn).await;
+    assert_eq!(opcode, 0x8);
+    assert_eq!(u16::from_be_bytes([payload[0], payload[1]]), 1007);
+
+    // With the resp subprotocol, replies are binary RESP
+    let (mut conn, response) = upgrade(&handle, "Sec-WebSocket-Protocol: resp\r\n").await;
+    assert!(response.contains(&"Sec-WebSocket-Protocol: resp".to_string()), "{:?}", response);
+    send_frame(&mut conn, 0x2, b"*2\r\n$3\r\nGET\r\n$8\r\ngreeting\r\n").await;
+    let (opcode, payload) = read_frame(&mut conn).await;
+    assert_eq!(opcode, 0x2);
+    assert_eq!(payload, "$12\r\nh\u{e9}llo \"you\"\r\n".as_bytes());
+    send_frame(&mut conn, 0x8, &1000u16.to_be_bytes()).await;
+    assert_eq!(read_frame(&mut conn).await.0, 0x8);
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn websocket_origins_can_be_restricted() {
+    let handle = websocket_server(&["https://dashboard.example"]).await;
+    let (_, response) = upgrade(&handle, "Origin: https://evil.example\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 403 Forbidden");
+    let (mut conn, response) = upgrade(&handle, "Origin: https://dashboard.example\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+    assert_eq!(json(&mut conn, r#"["PING"]"#).await, r#""PONG""#);
+
+    // The listener's own origin is the dashboard's, and always allowed
+    let (_, response) = upgrade(&handle, "Origin: http://localhost\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+    let addr = handle.websocket_addr().unwrap();
+
+    // With none listed only the listener's own origin, and clients that send
+    // none, may connect; * lets any page in
+    let same_origin_only = websocket_server(&[]).await;
+    let (_, response) = upgrade(&same_origin_only, "Origin: https://evil.example\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 403 Forbidden");
+    let (_, response) = upgrade(&same_origin_only, "Origin: http://localhost\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+    let (_, response) = upgrade(&same_origin_only, "").await;
+    assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+    same_origin_only.shutdown().await;
+    let any = websocket_server(&["*"]).await;
+    let (_, response) = upgrade(&any, "Origin: https://evil.example\r\n").await;
+    assert_eq!(response[0], "HTTP/1.1 101 Switching Protocols");
+    any.shutdown().await;
+
+    // A plain HTTP request for anything but the dashboard is not an upgrade
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    conn.get_mut().write_all(b"GET /favicon.ico HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut status = String::new();
+    conn.read_line(&mut status).await.unwrap();
+    assert_eq!(status.trim(), "HTTP/1.1 400 Bad Request");
+
+    // Nor is a request with no request line at all
+    let mut conn = BufReader::new(TcpStream::connect(addr).await.unwrap());
+    conn.get_mut().write_all(b"\r\n").await.unwrap();
+    let mut status = String::new();
+    conn.read_line(&mut status).await.unwrap();
+    assert_eq!(status.trim(), "HTTP/1.1 400 Bad Request");
+
+    handle.shutdown().await;
+}
+
+#[tokio::test]
+async fn dashboard_page_and_the_commands_it_uses() {
+    let handle = websocket_server(&[]).await;
+    let addr = handle.websocket_addr().unwrap();
+    let mut conn = TcpStream::connect(addr).await.unwrap();
+    conn.write_all(format!("GET /?tab=keys HTTP/1.1\r\nHost: {}\r\n\r\n", addr).as_bytes()).await.unwrap();
+    let mut page = String::new();
+    conn.read_to_string(&mut page).await.unwrap();
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "{}", page);
+    assert!(page.contains("Content-Type: text/html"));
+    assert!(page.contains("<title>RustCache</title>"));
+
+    let (mut conn, _) = upgrade(&handle, "").await;
+    assert_eq!(json(&mut conn, r#"["SET", "k", "v"]"#).await, r#""OK""#);
+    let clients = json(&mut conn, r#"["CLIENT", "LIST"]"#).await;
+    assert!(clients.contains(" db=0 user=default cmd=set\\n"), "{}", clients);
+    let id = json(&mut conn, r#"["CLIENT", "ID"]"#).await;
+    assert!(clients.starts_with(&format!("\"id={} ", id)), "{} {}", id, clients);
+
+    // Every command is slow with slowlog-log-slower-than 0
+    let slowlog = json(&mut conn, r#"["SLOWLOG", "GET", 2]"#).await;
+    assert!(slowlog.contains(r#"["client","ID"]"#), "{}", slowlog);
+    assert!(slowlog.contains(r#"["client","LIST"]"#), "{}", slowlog);
+    assert!(!slowlog.contains(r#"["set","k","v"]"#), "{}", slowlog);
+    assert_ne!(json(&mut conn, r#"["SLOWLOG", "LEN"]"#).await, "0");
+    assert_eq!(json(&mut conn, r#"["SLOWLOG", "RESET"]"#).await, r#""OK""#);
+    assert_eq!(json(&mut conn, r#"["SLOWLOG", "LEN"]"#).await, "1");
+
+    handle.shutdown().await;
+}
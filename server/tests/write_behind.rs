@@ -0,0 +1,230 @@
+mod common;
+
+use std::time::Duration;
+
+use server::resp::RespValue;
+use server::{run_server, ServerConfig, SqlKind, SqlTarget};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use common::{connect, request};
+
+async fn persistence_when(conn: &mut BufReader<TcpStream>, done: &str) -> String {
+    for _ in 0..100 {
+        let info = match request(conn, &["INFO", "persistence"]).await {
+            RespValue::BulkString(Some(b)) => String::from_utf8(b).unwrap(),
+            other => panic!("unexpected INFO reply {:?}", other),
+        };
+        if info.contains(done) {
+            return info;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("INFO persistence never showed {}", done);
+}
+
+fn target(kind: SqlKind, addr: String) -> SqlTarget {
+    SqlTarget {
+        kind,
+        addr,
+        user: "cache".to_string(),
+        password: Some("secret".to_string()),
+        database: "app".to_string(),
+        table: "cache_entries".to_string(),
+    }
+}
+
+// A PostgreSQL server that asks for an md5 password, and sends, per Sync,
+// the statements executed: the first word of each one's query and its
+// parameters
+type Statement = (String, Vec<Vec<u8>>);
+
+async fn fake_postgres() -> (String, mpsc::UnboundedReceiver<Vec<Statement>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let len = stream.read_i32().await.unwrap();
+                let mut startup = vec![0; len as usize - 4];
+                stream.read_exact(&mut startup).await.unwrap();
+                stream.write_all(b"R\0\0\0\x0c\0\0\0\x05\x01\x02\x03\x04").await.unwrap();
+                // md5(md5(password + user) in hex + salt), for cache/secret
+                let mut password = vec![0; 5 + 36];
+                stream.read_exact(&mut password).await.unwrap();
+                assert_eq!(&password[..], b"p\0\0\0\x28md5730ea935559d8042706f39daf09dfaff\0");
+                stream.write_all(b"R\0\0\0\x08\0\0\0\0Z\0\0\0\x05I").await.unwrap();
+                let (mut query, mut executed) = (String::new(), Vec::new());
+                loop {
+                    let Ok(tag) = stream.read_u8().await else { return };
+                    let len = stream.read_i32().await.unwrap();
+                    let mut body = vec![0; len as usize - 4];
+                    stream.read_exact(&mut body).await.unwrap();
+                    match tag {
+                        b'P' => {
+                            let text = body.split(|b| *b == 0).nth(1).unwrap();
+                            query = String::from_utf8_lossy(text).split_whitespace().next().unwrap().to_string();
+                            stream.write_all(b"1\0\0\0\x04").await.unwrap();
+                        }
+                        b'B' => {
+                            // Unnamed portal and statement, then the parameter formats
+                            let formats = i16::from_be_bytes([body[2], body[3]]) as usize;
+                            let mut at = 4 + formats * 2 + 2;
+                            let mut params = Vec::new();
+                            while params.len() < formats {
+                                let len = i32::from_be_bytes(body[at..at + 4].try_into().unwrap()) as usize;
+                                params.push(body[at + 4..at + 4 + len].to_vec());
+                                at += 4 + len;
+                            }
+                            executed.push((query.clone(), params));
+                            stream.write_all(b"2\0\0\0\x04").await.unwrap();
+                        }
+                        b'E' => stream.write_all(b"C\0\0\0\x0bINSERT\0").await.unwrap(),
+                        b'S' => {
+                            let _ = tx.send(std::mem::take(&mut executed));
+                            stream.write_all(b"Z\0\0\0\x05I").await.unwrap();
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn coalesced_writes_are_flushed_to_postgres_in_one_transaction() {
+    let (postgres, mut flushed) = fake_postgres().await;
+    let server = run_server(ServerConfig {
+        write_behind: Some(target(SqlKind::Postgres, postgres)),
+        write_behind_delay: Duration::from_millis(200),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    request(&mut conn, &["SET", "a", "1"]).await;
+    request(&mut conn, &["SET", "a", "2"]).await;
+    request(&mut conn, &["SET", "b", "x"]).await;
+    request(&mut conn, &["DEL", "b"]).await;
+
+    let batch = tokio::time::timeout(Duration::from_secs(5), flushed.recv()).await.unwrap().unwrap();
+    assert_eq!(batch.len(), 2);
+    assert_eq!(batch[0].0, "INSERT");
+    assert_eq!(batch[0].1[..2], [b"a".to_vec(), b"2".to_vec()]);
+    assert_eq!(batch[1], ("DELETE".to_string(), vec![b"b".to_vec()]));
+    let info = persistence_when(&mut conn, "write_behind_written:2").await;
+    assert!(info.contains("write_behind_link_status:up"));
+    assert!(info.contains("write_behind_pending:0"));
+    assert!(!info.contains("secret"));
+
+    // Shutdown flushes what is pending without waiting out the delay
+    request(&mut conn, &["SET", "c", "3"]).await;
+    server.shutdown().await;
+    let batch = flushed.recv().await.unwrap();
+    assert_eq!(batch[0].1[..2], [b"c".to_vec(), b"3".to_vec()]);
+}
+
+// A MySQL server that accepts any mysql_native_password login and sends
+// every query it gets
+async fn fake_mysql() -> (String, mpsc::UnboundedReceiver<String>) {
+    async fn read_packet(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await.ok()?;
+        let mut payload = vec![0; u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize];
+        stream.read_exact(&mut payload).await.ok()?;
+        Some(payload)
+    }
+    async fn write_packet(stream: &mut TcpStream, seq: u8, payload: &[u8]) {
+        let mut packet = (payload.len() as u32).to_le_bytes();
+        packet[3] = seq;
+        stream.write_all(&[&packet[..], payload].concat()).await.unwrap();
+    }
+    const OK: &[u8] = &[0, 0, 0, 2, 0, 0, 0];
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = vec![10];
+        handshake.extend_from_slice(b"8.0.0-fake\0");
+        handshake.extend_from_slice(&[1, 0, 0, 0]);
+        handshake.extend_from_slice(b"abcdefgh\0");
+        handshake.extend_from_slice(&[0xff, 0xf7, 45, 2, 0, 0xff, 0x81, 21]);
+        handshake.extend_from_slice(&[0; 10]);
+        handshake.extend_from_slice(b"ijklmnopqrst\0mysql_native_password\0");
+        write_packet(&mut stream, 0, &handshake).await;
+        read_packet(&mut stream).await.unwrap();
+        write_packet(&mut stream, 2, OK).await;
+        while let Some(query) = read_packet(&mut stream).await {
+            let _ = tx.send(String::from_utf8_lossy(&query[1..]).into_owned());
+            write_packet(&mut stream, 1, OK).await;
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn writes_are_flushed_to_mysql_in_a_transaction() {
+    let (mysql, mut queries) = fake_mysql().await;
+    let server = run_server(ServerConfig {
+        write_behind: Some(target(SqlKind::Mysql, mysql)),
+        write_behind_delay: Duration::from_millis(100),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    request(&mut conn, &["SET", "k", "v"]).await;
+    request(&mut conn, &["SET", "gone", "x"]).await;
+    request(&mut conn, &["DEL", "gone"]).await;
+
+    let mut got = Vec::new();
+    while got.len() < 4 {
+        got.push(tokio::time::timeout(Duration::from_secs(5), queries.recv()).await.unwrap().unwrap());
+    }
+    assert_eq!(got[0], "START TRANSACTION");
+    assert!(got[1].starts_with("INSERT INTO cache_entries (`key`, `value`, `updated_at`) VALUES (X'6b', X'76', FROM_UNIXTIME("), "{}", got[1]);
+    assert!(got[1].ends_with("ON DUPLICATE KEY UPDATE `value` = VALUES(`value`), `updated_at` = VALUES(`updated_at`)"));
+    assert_eq!(got[2], "DELETE FROM cache_entries WHERE `key` IN (X'676f6e65')");
+    assert_eq!(got[3], "COMMIT");
+    persistence_when(&mut conn, "write_behind_written:2").await;
+    server.shutdown().await;
+}
+
+#[tokio::test]
+async fn batches_that_cannot_be_written_are_dead_lettered() {
+    // Nothing listens on a port just released
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().to_string();
+    let path = std::env::temp_dir().join(format!("rustcache-dead-letter-{}.aof", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let server = run_server(ServerConfig {
+        write_behind: Some(target(SqlKind::Postgres, closed)),
+        write_behind_delay: Duration::from_millis(50),
+        write_behind_retries: 1,
+        write_behind_dead_letter: path.clone(),
+        ..ServerConfig::default()
+    })
+    .await
+    .unwrap();
+    let mut conn = connect(&server).await;
+    request(&mut conn, &["SET", "k", "v"]).await;
+    request(&mut conn, &["SET", "old", "x"]).await;
+    request(&mut conn, &["DEL", "old"]).await;
+
+    let info = persistence_when(&mut conn, "write_behind_dead_lettered:2").await;
+    assert!(info.contains("write_behind_link_status:down"));
+    assert!(info.contains("write_behind_retries:1"));
+    assert_eq!(
+        std::fs::read(&path).unwrap(),
+        b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nDEL\r\n$3\r\nold\r\n"
+    );
+    server.shutdown().await;
+    let _ = std::fs::remove_file(&path);
+}